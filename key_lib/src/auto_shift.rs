@@ -0,0 +1,104 @@
+use embassy_time::{Duration, Instant};
+
+use crate::scan_codes::KeyCodes;
+
+/// Returns true if the code types a character that shift changes, like
+/// letters, numbers and punctuation
+pub fn is_shiftable(code: KeyCodes) -> bool {
+    let code = code as u8;
+    (KeyCodes::KeyboardAa as u8..=KeyCodes::Keyboard0CloseParens as u8).contains(&code)
+        || (KeyCodes::KeyboardDashUnderscore as u8..=KeyCodes::KeyboardSlashQuestion as u8)
+            .contains(&code)
+}
+
+/// Code an auto shift key should send in the current report
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum AutoShiftOutput {
+    None,
+    /// Key was released before the timeout. Sent for a single report
+    Tap,
+    /// Key was held past the timeout. Sent shifted for a single report so
+    /// holding the key doesn't repeat it
+    Shifted,
+}
+
+/// Resolves an auto shift key into a tap or a shifted tap
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum AutoShift {
+    Idle,
+    // Pressed at the instant, waiting to see if the key is held past the
+    // timeout
+    Pressed(Instant),
+    // Shifted code was sent, waiting for the key to be released
+    Shifted,
+}
+
+impl AutoShift {
+    pub const fn default() -> Self {
+        Self::Idle
+    }
+
+    /// Returns true if the key is being held or waiting to be resolved
+    pub fn is_active(&self) -> bool {
+        *self != Self::Idle
+    }
+
+    /// Advances the state with the key's pressed status and returns the
+    /// code the key should send
+    pub fn update(&mut self, pressed: bool, now: Instant, timeout: Duration) -> AutoShiftOutput {
+        let (state, output) = match (*self, pressed) {
+            (Self::Idle, true) => (Self::Pressed(now), AutoShiftOutput::None),
+            (Self::Pressed(time), true) if now.saturating_duration_since(time) >= timeout => {
+                (Self::Shifted, AutoShiftOutput::Shifted)
+            }
+            (Self::Pressed(time), true) => (Self::Pressed(time), AutoShiftOutput::None),
+            (Self::Pressed(_), false) => (Self::Idle, AutoShiftOutput::Tap),
+            (Self::Shifted, true) => (Self::Shifted, AutoShiftOutput::None),
+            (Self::Idle | Self::Shifted, false) => (Self::Idle, AutoShiftOutput::None),
+        };
+        *self = state;
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_millis(200);
+
+    #[test]
+    fn quick_press_taps_on_release() {
+        let mut shift = AutoShift::default();
+        let start = Instant::from_millis(1000);
+        assert_eq!(shift.update(true, start, TIMEOUT), AutoShiftOutput::None);
+        let release = start + Duration::from_millis(50);
+        assert_eq!(shift.update(false, release, TIMEOUT), AutoShiftOutput::Tap);
+        assert!(!shift.is_active());
+    }
+
+    #[test]
+    fn held_key_is_shifted_once() {
+        let mut shift = AutoShift::default();
+        let start = Instant::from_millis(1000);
+        shift.update(true, start, TIMEOUT);
+        assert_eq!(
+            shift.update(true, start + TIMEOUT, TIMEOUT),
+            AutoShiftOutput::Shifted
+        );
+        // Holding it longer doesn't repeat the code
+        let later = start + TIMEOUT * 3;
+        assert_eq!(shift.update(true, later, TIMEOUT), AutoShiftOutput::None);
+        assert_eq!(shift.update(false, later, TIMEOUT), AutoShiftOutput::None);
+        assert!(!shift.is_active());
+    }
+
+    #[test]
+    fn only_characters_are_shiftable() {
+        assert!(is_shiftable(KeyCodes::KeyboardAa));
+        assert!(is_shiftable(KeyCodes::Keyboard1Exclamation));
+        assert!(is_shiftable(KeyCodes::KeyboardSlashQuestion));
+        assert!(!is_shiftable(KeyCodes::KeyboardSpacebar));
+        assert!(!is_shiftable(KeyCodes::KeyboardLeftShift));
+    }
+}
@@ -0,0 +1,322 @@
+//! Backups of the whole keyboard as a single blob. HidRequest::ExportConfig
+//! writes the keymap of every config and the settings in storage, and
+//! HidRequest::ImportConfig restores them.
+//!
+//! A blob starts with a [BackupHeader] holding the format version, the
+//! board's counts and the length and crc of the records after it. Each record
+//! is a [RecordKind], the config it belongs to, the layer or SettingId and the
+//! length of its data as a le u16, followed by the data in its storage
+//! format. Imports are checked in full before anything is written to flash.
+//! Secrets like the pairing key aren't exported
+
+use defmt::Format;
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, mutex::Mutex};
+use heapless::Vec;
+use num_enum::TryFromPrimitive;
+use sequential_storage::map::Value;
+
+use crate::{
+    NUM_CONFIGS, NUM_KEYS, NUM_LAYERS,
+    codes::{MAX_SERIAL_LENGTH, ScanCodeLayerStorage},
+    dfu::Crc32,
+    error::KeyLibError,
+    storage::{GLOBAL_SETTINGS, MAX_SETTING_LEN, SettingId, StorageItem, StorageKey},
+};
+
+pub const BACKUP_VERSION: u8 = 1;
+pub const BACKUP_HEADER_LEN: usize = 12;
+
+const RECORD_HEADER_LEN: usize = 5;
+const MAX_LAYER_LEN: usize = NUM_KEYS * MAX_SERIAL_LENGTH;
+
+const CONFIG_SETTINGS: usize = SettingId::ALL.len() - GLOBAL_SETTINGS;
+
+/// Length of the largest body a blob can have
+pub const MAX_BACKUP_LEN: usize = NUM_CONFIGS
+    * (NUM_LAYERS * (RECORD_HEADER_LEN + MAX_LAYER_LEN)
+        + CONFIG_SETTINGS * (RECORD_HEADER_LEN + MAX_SETTING_LEN))
+    + GLOBAL_SETTINGS * (RECORD_HEADER_LEN + MAX_SETTING_LEN);
+
+/// Body of the blob being exported or imported. Kept in a static since it's
+/// too large for the com task's stack
+pub static BACKUP_BODY: Mutex<CriticalSectionRawMutex, Vec<u8, MAX_BACKUP_LEN>> =
+    Mutex::new(Vec::new());
+
+/// Status byte sent in response to a HidRequest::ImportConfig
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Format)]
+pub enum ImportStatus {
+    Ok = 0,
+    /// The blob was made by firmware with another format version
+    Version = 1,
+    /// The blob was made for a board with other counts
+    Board = 2,
+    TooLarge = 3,
+    Checksum = 4,
+    /// A record couldn't be deserialized or is out of range
+    Invalid = 5,
+}
+
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Format, TryFromPrimitive)]
+pub enum RecordKind {
+    Layer = 0,
+    Setting = 1,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Format)]
+pub struct BackupHeader {
+    pub version: u8,
+    pub configs: u8,
+    pub keys: u8,
+    pub layers: u8,
+    pub len: u32,
+    pub crc: u32,
+}
+
+impl BackupHeader {
+    /// Returns the header of a blob made by this firmware with the body
+    pub fn new(body: &[u8]) -> Self {
+        let mut crc = Crc32::default();
+        crc.update(body);
+        Self {
+            version: BACKUP_VERSION,
+            configs: NUM_CONFIGS as u8,
+            keys: NUM_KEYS as u8,
+            layers: NUM_LAYERS as u8,
+            len: body.len() as u32,
+            crc: crc.finish(),
+        }
+    }
+
+    pub fn from_bytes(buf: &[u8; BACKUP_HEADER_LEN]) -> Self {
+        Self {
+            version: buf[0],
+            configs: buf[1],
+            keys: buf[2],
+            layers: buf[3],
+            len: u32::from_le_bytes([buf[4], buf[5], buf[6], buf[7]]),
+            crc: u32::from_le_bytes([buf[8], buf[9], buf[10], buf[11]]),
+        }
+    }
+
+    pub fn to_bytes(&self) -> [u8; BACKUP_HEADER_LEN] {
+        let mut buf = [0u8; BACKUP_HEADER_LEN];
+        buf[0] = self.version;
+        buf[1] = self.configs;
+        buf[2] = self.keys;
+        buf[3] = self.layers;
+        buf[4..8].copy_from_slice(&self.len.to_le_bytes());
+        buf[8..12].copy_from_slice(&self.crc.to_le_bytes());
+        buf
+    }
+
+    /// Checks the header was made for this board by firmware that shares
+    /// the format. The body is checked with [validate]
+    pub fn check(&self) -> Result<(), ImportStatus> {
+        if self.version != BACKUP_VERSION {
+            return Err(ImportStatus::Version);
+        }
+        if (self.configs, self.keys, self.layers)
+            != (NUM_CONFIGS as u8, NUM_KEYS as u8, NUM_LAYERS as u8)
+        {
+            return Err(ImportStatus::Board);
+        }
+        if self.len as usize > MAX_BACKUP_LEN {
+            return Err(ImportStatus::TooLarge);
+        }
+        Ok(())
+    }
+}
+
+/// A record of a blob. Every record maps to a single storage item
+pub enum BackupRecord {
+    Layer {
+        config_num: usize,
+        layer: usize,
+        codes: ScanCodeLayerStorage<NUM_KEYS>,
+    },
+    Setting {
+        config_num: usize,
+        key: StorageKey,
+        item: StorageItem,
+    },
+}
+
+/// Appends a record of the item to the body. id is the layer of a keymap
+/// record or the SettingId of a setting
+pub fn push_record(
+    body: &mut Vec<u8, MAX_BACKUP_LEN>,
+    kind: RecordKind,
+    config_num: usize,
+    id: u8,
+    item: &StorageItem,
+) -> Result<(), KeyLibError> {
+    let start = body.len();
+    let max_len = match kind {
+        RecordKind::Layer => MAX_LAYER_LEN,
+        RecordKind::Setting => MAX_SETTING_LEN,
+    };
+    body.resize(start + RECORD_HEADER_LEN + max_len, 0)
+        .map_err(|_| KeyLibError::Serialization)?;
+    let len = item.serialize_into(&mut body[start + RECORD_HEADER_LEN..])?;
+    body[start] = kind as u8;
+    body[start + 1] = config_num as u8;
+    body[start + 2] = id;
+    body[start + 3..start + RECORD_HEADER_LEN].copy_from_slice(&(len as u16).to_le_bytes());
+    body.truncate(start + RECORD_HEADER_LEN + len);
+    Ok(())
+}
+
+/// Iterator over the records of a body. Stops after the first invalid record
+pub struct Records<'a> {
+    body: &'a [u8],
+}
+
+impl<'a> Records<'a> {
+    pub fn new(body: &'a [u8]) -> Self {
+        Self { body }
+    }
+
+    fn parse(&mut self) -> Option<BackupRecord> {
+        let header = self.body.get(..RECORD_HEADER_LEN)?;
+        let kind = RecordKind::try_from(header[0]).ok()?;
+        let config_num = header[1] as usize;
+        let id = header[2];
+        let len = u16::from_le_bytes([header[3], header[4]]) as usize;
+        let data = self.body.get(RECORD_HEADER_LEN..RECORD_HEADER_LEN + len)?;
+        self.body = &self.body[RECORD_HEADER_LEN + len..];
+        match kind {
+            RecordKind::Layer => {
+                let layer = id as usize;
+                if config_num >= NUM_CONFIGS || layer >= NUM_LAYERS {
+                    return None;
+                }
+                let (codes, _) = ScanCodeLayerStorage::deserialize_from(data).ok()?;
+                Some(BackupRecord::Layer {
+                    config_num,
+                    layer,
+                    codes,
+                })
+            }
+            RecordKind::Setting => {
+                let setting = SettingId::try_from(id).ok()?;
+                let key = setting.storage_key(config_num)?;
+                let item = setting.deserialize(data).ok()?;
+                Some(BackupRecord::Setting {
+                    config_num,
+                    key,
+                    item,
+                })
+            }
+        }
+    }
+}
+
+impl Iterator for Records<'_> {
+    type Item = Result<BackupRecord, ImportStatus>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.body.is_empty() {
+            return None;
+        }
+        match self.parse() {
+            Some(record) => Some(Ok(record)),
+            None => {
+                self.body = &[];
+                Some(Err(ImportStatus::Invalid))
+            }
+        }
+    }
+}
+
+/// Checks the header and that the body matches its crc and only holds valid
+/// records
+pub fn validate(header: &BackupHeader, body: &[u8]) -> Result<(), ImportStatus> {
+    header.check()?;
+    if body.len() != header.len as usize {
+        return Err(ImportStatus::TooLarge);
+    }
+    if BackupHeader::new(body).crc != header.crc {
+        return Err(ImportStatus::Checksum);
+    }
+    Records::new(body).try_for_each(|record| record.map(|_| ()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        codes::ScanCodeBehavior,
+        scan_codes::KeyCodes,
+        settings::{STANDALONE_LAYER_SERIAL_LENGTH, StandaloneLayer},
+    };
+
+    fn body() -> Vec<u8, MAX_BACKUP_LEN> {
+        let mut codes = ScanCodeLayerStorage::<NUM_KEYS>::default();
+        codes.codes[0] = ScanCodeBehavior::Single(KeyCodes::KeyboardAa);
+        let mut body = Vec::new();
+        push_record(&mut body, RecordKind::Layer, 1, 2, &StorageItem::Key(codes)).unwrap();
+        let layer = StandaloneLayer::default();
+        push_record(
+            &mut body,
+            RecordKind::Setting,
+            0,
+            SettingId::StandaloneLayer as u8,
+            &StorageItem::StandaloneLayer(layer),
+        )
+        .unwrap();
+        body
+    }
+
+    #[test]
+    fn blob_round_trips() {
+        let body = body();
+        let header = BackupHeader::from_bytes(&BackupHeader::new(&body).to_bytes());
+        assert_eq!(validate(&header, &body), Ok(()));
+        let mut records = Records::new(&body);
+        match records.next() {
+            Some(Ok(BackupRecord::Layer {
+                config_num: 1,
+                layer: 2,
+                codes,
+            })) => assert_eq!(
+                codes.codes[0],
+                ScanCodeBehavior::Single(KeyCodes::KeyboardAa)
+            ),
+            _ => panic!("Expected a layer record"),
+        }
+        match records.next() {
+            Some(Ok(BackupRecord::Setting {
+                config_num: 0,
+                item: StorageItem::StandaloneLayer(_),
+                ..
+            })) => {}
+            _ => panic!("Expected a setting record"),
+        }
+        assert!(records.next().is_none());
+        assert_eq!(
+            body.len(),
+            2 * RECORD_HEADER_LEN + 2 * NUM_KEYS + STANDALONE_LAYER_SERIAL_LENGTH
+        );
+    }
+
+    #[test]
+    fn blob_is_checked_before_records() {
+        let mut body = body();
+        let mut header = BackupHeader::new(&body);
+        header.version += 1;
+        assert_eq!(validate(&header, &body), Err(ImportStatus::Version));
+        let mut header = BackupHeader::new(&body);
+        header.layers += 1;
+        assert_eq!(validate(&header, &body), Err(ImportStatus::Board));
+        let header = BackupHeader::new(&body);
+        body[RECORD_HEADER_LEN + 1] ^= 1;
+        assert_eq!(validate(&header, &body), Err(ImportStatus::Checksum));
+        // A layer past the board's layers with a matching crc
+        body[RECORD_HEADER_LEN + 1] ^= 1;
+        body[2] = NUM_LAYERS as u8;
+        let header = BackupHeader::new(&body);
+        assert_eq!(validate(&header, &body), Err(ImportStatus::Invalid));
+    }
+}
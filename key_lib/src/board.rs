@@ -0,0 +1,306 @@
+use defmt::{Format, info, warn};
+use embassy_time::Duration;
+use heapless::String;
+use num_enum::TryFromPrimitive;
+use sequential_storage::map::{SerializationError, Value};
+
+use crate::scan::set_scan_interval;
+use crate::storage::{StorageItem, StorageKey, get_item};
+
+pub const SIDE_SERIAL_LENGTH: usize = 1;
+/// Longest product string that can be stored in a [UsbIdentity]
+pub const MAX_PRODUCT_LEN: usize = 24;
+pub const USB_IDENTITY_SERIAL_LENGTH: usize = 5 + MAX_PRODUCT_LEN;
+pub const REPORT_RATE_SERIAL_LENGTH: usize = 6;
+/// Longest wait between scans. Slower scans would add noticeable latency
+pub const MAX_SCAN_INTERVAL_US: u16 = 10_000;
+
+/// Half of a split board
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Format, TryFromPrimitive)]
+pub enum Side {
+    Left = 0,
+    Right = 1,
+}
+
+impl<'a> Value<'a> for Side {
+    fn serialize_into(&self, buffer: &mut [u8]) -> Result<usize, SerializationError> {
+        if buffer.len() < SIDE_SERIAL_LENGTH {
+            return Err(SerializationError::BufferTooSmall);
+        }
+        buffer[0] = *self as u8;
+        Ok(SIDE_SERIAL_LENGTH)
+    }
+
+    fn deserialize_from(buffer: &'a [u8]) -> Result<(Self, usize), SerializationError>
+    where
+        Self: Sized,
+    {
+        let side = buffer.first().ok_or(SerializationError::BufferTooSmall)?;
+        let side = Side::try_from(*side).map_err(|_| SerializationError::InvalidFormat)?;
+        Ok((side, SIDE_SERIAL_LENGTH))
+    }
+}
+
+/// Settings that differ between the halves of a split board. Lets a single
+/// firmware image configure itself for either half at boot
+pub struct BoardConfig<const N: usize> {
+    pub side: Side,
+    pub vid: u16,
+    pub pid: u16,
+    pub product: &'static str,
+    /// Scan index of each key's sensor
+    pub order: [usize; N],
+}
+
+impl<const N: usize> BoardConfig<N> {
+    /// Returns the key index of each sensor in the order they're scanned
+    pub const fn scan_order(&self) -> [usize; N] {
+        let mut scan_order = [0usize; N];
+        let mut key = 0;
+        while key < N {
+            scan_order[self.order[key]] = key;
+            key += 1;
+        }
+        scan_order
+    }
+}
+
+/// Returns the side stored with SettingId::Side. Falls back to strapped,
+/// the side read from the board's strap pin, if no side was stored
+pub async fn load_side(strapped: Side) -> Side {
+    let side = match get_item(StorageKey::Side).await {
+        Some(StorageItem::Side(side)) => side,
+        _ => strapped,
+    };
+    info!("Running as {} half", side);
+    side
+}
+
+/// Usb ids and product string the board enumerates with. A vid or pid of 0
+/// and an empty product use the board's defaults, so the stored identity
+/// can't leave the board without a usable identity
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct UsbIdentity {
+    pub vid: u16,
+    pub pid: u16,
+    pub product: String<MAX_PRODUCT_LEN>,
+}
+
+impl UsbIdentity {
+    pub const fn default() -> Self {
+        Self {
+            vid: 0,
+            pid: 0,
+            product: String::new(),
+        }
+    }
+}
+
+impl<'a> Value<'a> for UsbIdentity {
+    fn serialize_into(&self, buffer: &mut [u8]) -> Result<usize, SerializationError> {
+        let len = 5 + self.product.len();
+        if buffer.len() < len {
+            return Err(SerializationError::BufferTooSmall);
+        }
+        buffer[0..2].copy_from_slice(&self.vid.to_le_bytes());
+        buffer[2..4].copy_from_slice(&self.pid.to_le_bytes());
+        buffer[4] = self.product.len() as u8;
+        buffer[5..len].copy_from_slice(self.product.as_bytes());
+        Ok(len)
+    }
+
+    fn deserialize_from(buffer: &'a [u8]) -> Result<(Self, usize), SerializationError>
+    where
+        Self: Sized,
+    {
+        if buffer.len() < 5 {
+            return Err(SerializationError::BufferTooSmall);
+        }
+        let product_len = buffer[4] as usize;
+        if product_len > MAX_PRODUCT_LEN {
+            return Err(SerializationError::InvalidFormat);
+        }
+        let product = buffer
+            .get(5..5 + product_len)
+            .ok_or(SerializationError::BufferTooSmall)?;
+        let product =
+            core::str::from_utf8(product).map_err(|_| SerializationError::InvalidFormat)?;
+        Ok((
+            Self {
+                vid: u16::from_le_bytes([buffer[0], buffer[1]]),
+                pid: u16::from_le_bytes([buffer[2], buffer[3]]),
+                product: String::try_from(product)
+                    .map_err(|_| SerializationError::InvalidFormat)?,
+            },
+            5 + product_len,
+        ))
+    }
+}
+
+/// Returns the usb identity stored with SettingId::UsbIdentity. Fields that
+/// weren't set are filled in from the board's defaults. Storage has to be
+/// running, so this should be called before the usb builder is created
+pub async fn load_usb_identity(vid: u16, pid: u16, product: &str) -> UsbIdentity {
+    let stored = match get_item(StorageKey::UsbIdentity).await {
+        Some(StorageItem::UsbIdentity(identity)) => identity,
+        _ => UsbIdentity::default(),
+    };
+    let mut identity = UsbIdentity {
+        vid: if stored.vid == 0 { vid } else { stored.vid },
+        pid: if stored.pid == 0 { pid } else { stored.pid },
+        product: stored.product,
+    };
+    if identity.product.is_empty() {
+        // Defaults longer than the stored limit are cut at a char boundary
+        for c in product.chars() {
+            if identity.product.push(c).is_err() {
+                break;
+            }
+        }
+    }
+    info!(
+        "Usb identity {:04x}:{:04x} {}",
+        identity.vid,
+        identity.pid,
+        identity.product.as_str()
+    );
+    identity
+}
+
+/// How often the host polls the report endpoints and how long the key loop
+/// waits between scans. Faster rates lower latency at the cost of power
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Format)]
+pub struct ReportRate {
+    /// Time between polls in us. Full speed boards poll every whole ms up to
+    /// 255ms. High speed boards can also poll every 125, 250 or 500us, where
+    /// 125us is 8kHz
+    pub poll_interval_us: u32,
+    pub scan_interval_us: u16,
+}
+
+impl ReportRate {
+    pub const fn default() -> Self {
+        Self {
+            poll_interval_us: 1000,
+            scan_interval_us: 5,
+        }
+    }
+
+    pub fn scan_interval(&self) -> Duration {
+        Duration::from_micros(self.scan_interval_us as u64)
+    }
+
+    /// Returns the bInterval of the report endpoints, or None if the board
+    /// can't poll at the rate. High speed endpoints are polled every
+    /// 2^(bInterval - 1) microframes of 125us
+    pub fn b_interval(&self, high_speed: bool) -> Option<u8> {
+        let us = self.poll_interval_us;
+        if high_speed {
+            return match us {
+                125 => Some(1),
+                250 => Some(2),
+                500 => Some(3),
+                1000 => Some(4),
+                _ => None,
+            };
+        }
+        if !us.is_multiple_of(1000) {
+            return None;
+        }
+        u8::try_from(us / 1000).ok().filter(|&ms| ms != 0)
+    }
+}
+
+impl<'a> Value<'a> for ReportRate {
+    fn serialize_into(&self, buffer: &mut [u8]) -> Result<usize, SerializationError> {
+        if buffer.len() < REPORT_RATE_SERIAL_LENGTH {
+            return Err(SerializationError::BufferTooSmall);
+        }
+        buffer[0..4].copy_from_slice(&self.poll_interval_us.to_le_bytes());
+        buffer[4..6].copy_from_slice(&self.scan_interval_us.to_le_bytes());
+        Ok(REPORT_RATE_SERIAL_LENGTH)
+    }
+
+    fn deserialize_from(buffer: &'a [u8]) -> Result<(Self, usize), SerializationError>
+    where
+        Self: Sized,
+    {
+        if buffer.len() < REPORT_RATE_SERIAL_LENGTH {
+            return Err(SerializationError::BufferTooSmall);
+        }
+        let rate = Self {
+            poll_interval_us: u32::from_le_bytes([buffer[0], buffer[1], buffer[2], buffer[3]]),
+            scan_interval_us: u16::from_le_bytes([buffer[4], buffer[5]]),
+        };
+        // Rates no board can poll at are rejected, full speed boards fall
+        // back to 1ms for the rates only high speed boards reach
+        if rate.b_interval(true).is_none() && rate.b_interval(false).is_none() {
+            return Err(SerializationError::InvalidFormat);
+        }
+        if rate.scan_interval_us > MAX_SCAN_INTERVAL_US {
+            return Err(SerializationError::InvalidFormat);
+        }
+        Ok((rate, REPORT_RATE_SERIAL_LENGTH))
+    }
+}
+
+/// Loads the rate stored with SettingId::ReportRate and starts scanning at
+/// it. Returns the bInterval of the report endpoints, which falls back to 1ms
+/// if the board can't poll at the stored rate. Storage has to be running, so
+/// this should be called before the usb builder is created
+pub async fn load_report_rate(high_speed: bool) -> u8 {
+    let rate = match get_item(StorageKey::ReportRate).await {
+        Some(StorageItem::ReportRate(rate)) => rate,
+        _ => ReportRate::default(),
+    };
+    set_scan_interval(rate.scan_interval());
+    match rate.b_interval(high_speed) {
+        Some(b_interval) => b_interval,
+        None => {
+            warn!(
+                "Can't poll every {}us, polling every 1ms",
+                rate.poll_interval_us
+            );
+            ReportRate::default().b_interval(high_speed).unwrap_or(1)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rate(poll_interval_us: u32) -> ReportRate {
+        ReportRate {
+            poll_interval_us,
+            ..ReportRate::default()
+        }
+    }
+
+    #[test]
+    fn polling_rates_map_to_the_bus_speed() {
+        assert_eq!(rate(125).b_interval(true), Some(1));
+        assert_eq!(rate(125).b_interval(false), None);
+        assert_eq!(rate(1000).b_interval(true), Some(4));
+        assert_eq!(rate(1000).b_interval(false), Some(1));
+        assert_eq!(rate(8000).b_interval(false), Some(8));
+        assert_eq!(rate(1500).b_interval(false), None);
+        assert_eq!(rate(256_000).b_interval(false), None);
+    }
+
+    #[test]
+    fn unreachable_rates_are_rejected() {
+        let mut buf = [0u8; REPORT_RATE_SERIAL_LENGTH];
+        rate(125).serialize_into(&mut buf).unwrap();
+        assert_eq!(ReportRate::deserialize_from(&buf).unwrap().0, rate(125));
+        rate(300).serialize_into(&mut buf).unwrap();
+        assert!(ReportRate::deserialize_from(&buf).is_err());
+        let slow_scan = ReportRate {
+            scan_interval_us: MAX_SCAN_INTERVAL_US + 1,
+            ..ReportRate::default()
+        };
+        slow_scan.serialize_into(&mut buf).unwrap();
+        assert!(ReportRate::deserialize_from(&buf).is_err());
+    }
+}
@@ -0,0 +1,21 @@
+//! Boot protocol fallback for BIOSes and KVMs that don't parse the nkro
+//! report. Boards expose a boot keyboard interface with
+//! [KeyboardReportBoot](crate::descriptor::KeyboardReportBoot) next to the
+//! nkro one, and send key reports to it instead once the host switches it to
+//! the boot protocol with SET_PROTOCOL. Hosts start in the report protocol
+//! after every bus reset
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+static BOOT_PROTOCOL: AtomicBool = AtomicBool::new(false);
+
+/// Should be called from the boot interface's request handler when the host
+/// sets its protocol, and with false on a usb bus reset
+pub fn set_boot_protocol(boot: bool) {
+    BOOT_PROTOCOL.store(boot, Ordering::Relaxed);
+}
+
+/// Returns true if key reports should be sent to the boot interface
+pub fn boot_protocol() -> bool {
+    BOOT_PROTOCOL.load(Ordering::Relaxed)
+}
@@ -0,0 +1,30 @@
+//! Rebooting into the bootloader so new firmware can be flashed without
+//! opening the case to find the reset button. ScanCodeBehavior::Bootloader
+//! and HidRequest::Bootloader request it with [request_bootloader], and
+//! boards reboot into the RP2040's BOOTSEL mode or the nRF52's DFU bootloader
+//! once [wait_for_bootloader] returns
+
+use defmt::info;
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+use embassy_time::{Duration, Timer};
+
+use crate::storage::flush_saves;
+
+// Time storage gets to write the settings flushed before the reboot
+const FLUSH_DELAY: Duration = Duration::from_millis(500);
+
+static BOOTLOADER: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Asks the board to reboot into its bootloader
+pub fn request_bootloader() {
+    BOOTLOADER.signal(());
+}
+
+/// Waits for a reboot to be requested and writes the settings still waiting
+/// on the autosave delay, so they aren't lost with the reboot
+pub async fn wait_for_bootloader() {
+    BOOTLOADER.wait().await;
+    info!("Rebooting into the bootloader");
+    flush_saves().await;
+    Timer::after(FLUSH_DELAY).await;
+}
@@ -3,7 +3,7 @@ use core::mem;
 use num_enum::TryFromPrimitive;
 use sequential_storage::map::{SerializationError, Value};
 
-use crate::scan_codes::KeyCodes;
+use crate::{NUM_LAYERS, host_switch::MAX_HOSTS, scan_codes::KeyCodes, settings::MouseAxis};
 
 /// Wrapper around ScanCode to allow different fuctionalites when pressed
 /// such as sending multiple keys
@@ -20,12 +20,100 @@ pub enum ScanCodeBehavior {
         combined_code: KeyCodes,
     } = 3,
     ChangeConfig(u8) = 4,
+    // Flips the direction of a mouse axis for the current config
+    ToggleMouseInvert(MouseAxis) = 5,
+    // Activates partial_layer once the key is pressed past partial_travel and
+    // full_layer once it's pressed past full_travel. Travel is from 0 to 100
+    AnalogLayer {
+        partial_layer: u8,
+        full_layer: u8,
+        partial_travel: u8,
+        full_travel: u8,
+    } = 6,
+    // Sends first_code once the key is pressed past first_travel and
+    // second_code along with it once it's pressed past second_travel
+    DualStage {
+        first_code: KeyCodes,
+        second_code: KeyCodes,
+        first_travel: u8,
+        second_travel: u8,
+    } = 7,
+    // Sends tap when tapped once, double_tap while the key is pressed again
+    // within term and hold while the first press is held past term. Term is
+    // in units of 10ms
+    TapDance {
+        tap: KeyCodes,
+        double_tap: KeyCodes,
+        hold: KeyCodes,
+        term: u8,
+    } = 8,
+    // Sends swapped_code instead of normal_code while any modifier in mods is
+    // held. Mods uses the bit layout of the report's modifier byte
+    ModSwap {
+        normal_code: KeyCodes,
+        swapped_code: KeyCodes,
+        mods: u8,
+    } = 9,
+    // Sends mac_code instead of normal_code while the host is running macOS.
+    // Used to swap gui and alt so the layout matches the host
+    OsSwap {
+        normal_code: KeyCodes,
+        mac_code: KeyCodes,
+    } = 10,
+    // Sends fast_code instead of slow_code if the key was pressed faster than
+    // threshold, e.g. to send a capital letter on a hard press. Velocity is in
+    // percent of travel per 10ms and is decided once when the key actuates
+    Velocity {
+        slow_code: KeyCodes,
+        fast_code: KeyCodes,
+        threshold: u8,
+    } = 11,
+    // Switches a wireless keyboard to the dongle of another host. The host
+    // is an index into the stored host pairings
+    SwitchHost(u8) = 12,
+    // Reboots the board into its bootloader to flash new firmware. See
+    // [crate::bootloader]
+    Bootloader = 13,
+    // Locks the keyboard while the key and the key at other_index are held
+    // together. Nothing is sent while locked, until the same chord unlocks it
+    LockKeyboard {
+        other_index: usize,
+    } = 14,
+    // Starts recording a dynamic macro, or stops the running recording. A
+    // recording started with save is written to storage. See
+    // [crate::dynamic_macro]
+    RecordMacro {
+        save: bool,
+    } = 15,
+    // Plays the recorded dynamic macro
+    PlayMacro = 16,
+    // Tap dance that also sends triple_tap when the key is pressed a third
+    // time within term of the second tap. See TapDance
+    TripleTapDance {
+        tap: KeyCodes,
+        double_tap: KeyCodes,
+        triple_tap: KeyCodes,
+        hold: KeyCodes,
+        term: u8,
+    } = 17,
 }
 
+/// Modifier mask for the left and right shift and gui keys
+pub const SHIFT_GUI_MODS: u8 = 0b1010_1010;
+
 impl ScanCodeBehavior {
     pub const fn default() -> Self {
         Self::Single(KeyCodes::Undefined)
     }
+
+    /// Escape that sends backtick while shift or gui is held
+    pub const fn grave_escape() -> Self {
+        Self::ModSwap {
+            normal_code: KeyCodes::KeyboardEscape,
+            swapped_code: KeyCodes::KeyboardBacktickTilde,
+            mods: SHIFT_GUI_MODS,
+        }
+    }
 }
 
 #[derive(Debug, Eq, PartialEq, TryFromPrimitive)]
@@ -36,6 +124,19 @@ pub enum HidScanCodeType {
     Triple = 2,
     CombinedKey = 3,
     ChangeConfig = 4,
+    ToggleMouseInvert = 5,
+    AnalogLayer = 6,
+    DualStage = 7,
+    TapDance = 8,
+    ModSwap = 9,
+    OsSwap = 10,
+    Velocity = 11,
+    SwitchHost = 12,
+    Bootloader = 13,
+    LockKeyboard = 14,
+    RecordMacro = 15,
+    PlayMacro = 16,
+    TripleTapDance = 17,
 }
 impl HidScanCodeType {
     pub fn get_len(&self) -> usize {
@@ -45,6 +146,19 @@ impl HidScanCodeType {
             Self::Triple => TRIPLE_SERIAL_LENGTH,
             Self::CombinedKey => COMBINED_KEY_SERIAL_LENGTH,
             Self::ChangeConfig => CHANGE_CONFIG_SERIAL_LENGTH,
+            Self::ToggleMouseInvert => TOGGLE_MOUSE_INVERT_SERIAL_LENGTH,
+            Self::AnalogLayer => ANALOG_LAYER_SERIAL_LENGTH,
+            Self::DualStage => DUAL_STAGE_SERIAL_LENGTH,
+            Self::TapDance => TAP_DANCE_SERIAL_LENGTH,
+            Self::ModSwap => MOD_SWAP_SERIAL_LENGTH,
+            Self::OsSwap => OS_SWAP_SERIAL_LENGTH,
+            Self::Velocity => VELOCITY_SERIAL_LENGTH,
+            Self::SwitchHost => SWITCH_HOST_SERIAL_LENGTH,
+            Self::Bootloader => BOOTLOADER_SERIAL_LENGTH,
+            Self::LockKeyboard => LOCK_KEYBOARD_SERIAL_LENGTH,
+            Self::RecordMacro => RECORD_MACRO_SERIAL_LENGTH,
+            Self::PlayMacro => PLAY_MACRO_SERIAL_LENGTH,
+            Self::TripleTapDance => TRIPLE_TAP_DANCE_SERIAL_LENGTH,
         }
     }
 }
@@ -67,6 +181,19 @@ pub const MAX_SERIAL_LENGTH: usize = max_len(&[
     TRIPLE_SERIAL_LENGTH,
     COMBINED_KEY_SERIAL_LENGTH,
     CHANGE_CONFIG_SERIAL_LENGTH,
+    TOGGLE_MOUSE_INVERT_SERIAL_LENGTH,
+    ANALOG_LAYER_SERIAL_LENGTH,
+    DUAL_STAGE_SERIAL_LENGTH,
+    TAP_DANCE_SERIAL_LENGTH,
+    MOD_SWAP_SERIAL_LENGTH,
+    OS_SWAP_SERIAL_LENGTH,
+    VELOCITY_SERIAL_LENGTH,
+    SWITCH_HOST_SERIAL_LENGTH,
+    BOOTLOADER_SERIAL_LENGTH,
+    LOCK_KEYBOARD_SERIAL_LENGTH,
+    RECORD_MACRO_SERIAL_LENGTH,
+    PLAY_MACRO_SERIAL_LENGTH,
+    TRIPLE_TAP_DANCE_SERIAL_LENGTH,
 ]);
 
 const SINGLE_SERIAL_LENGTH: usize = 2;
@@ -74,6 +201,19 @@ const DOUBLE_SERIAL_LENGTH: usize = 3;
 const TRIPLE_SERIAL_LENGTH: usize = 4;
 const COMBINED_KEY_SERIAL_LENGTH: usize = 4;
 const CHANGE_CONFIG_SERIAL_LENGTH: usize = 2;
+const TOGGLE_MOUSE_INVERT_SERIAL_LENGTH: usize = 2;
+const ANALOG_LAYER_SERIAL_LENGTH: usize = 5;
+const DUAL_STAGE_SERIAL_LENGTH: usize = 5;
+const TAP_DANCE_SERIAL_LENGTH: usize = 5;
+const MOD_SWAP_SERIAL_LENGTH: usize = 4;
+const OS_SWAP_SERIAL_LENGTH: usize = 3;
+const VELOCITY_SERIAL_LENGTH: usize = 4;
+const SWITCH_HOST_SERIAL_LENGTH: usize = 2;
+const BOOTLOADER_SERIAL_LENGTH: usize = 1;
+const LOCK_KEYBOARD_SERIAL_LENGTH: usize = 2;
+const RECORD_MACRO_SERIAL_LENGTH: usize = 2;
+const PLAY_MACRO_SERIAL_LENGTH: usize = 1;
+const TRIPLE_TAP_DANCE_SERIAL_LENGTH: usize = 6;
 
 impl ScanCodeBehavior {
     pub fn into_buffer_len(&self) -> usize {
@@ -83,6 +223,19 @@ impl ScanCodeBehavior {
             ScanCodeBehavior::Triple(_, _, _) => TRIPLE_SERIAL_LENGTH,
             ScanCodeBehavior::CombinedKey { .. } => COMBINED_KEY_SERIAL_LENGTH,
             ScanCodeBehavior::ChangeConfig(_) => CHANGE_CONFIG_SERIAL_LENGTH,
+            ScanCodeBehavior::ToggleMouseInvert(_) => TOGGLE_MOUSE_INVERT_SERIAL_LENGTH,
+            ScanCodeBehavior::AnalogLayer { .. } => ANALOG_LAYER_SERIAL_LENGTH,
+            ScanCodeBehavior::DualStage { .. } => DUAL_STAGE_SERIAL_LENGTH,
+            ScanCodeBehavior::TapDance { .. } => TAP_DANCE_SERIAL_LENGTH,
+            ScanCodeBehavior::ModSwap { .. } => MOD_SWAP_SERIAL_LENGTH,
+            ScanCodeBehavior::OsSwap { .. } => OS_SWAP_SERIAL_LENGTH,
+            ScanCodeBehavior::Velocity { .. } => VELOCITY_SERIAL_LENGTH,
+            ScanCodeBehavior::SwitchHost(_) => SWITCH_HOST_SERIAL_LENGTH,
+            ScanCodeBehavior::Bootloader => BOOTLOADER_SERIAL_LENGTH,
+            ScanCodeBehavior::LockKeyboard { .. } => LOCK_KEYBOARD_SERIAL_LENGTH,
+            ScanCodeBehavior::RecordMacro { .. } => RECORD_MACRO_SERIAL_LENGTH,
+            ScanCodeBehavior::PlayMacro => PLAY_MACRO_SERIAL_LENGTH,
+            ScanCodeBehavior::TripleTapDance { .. } => TRIPLE_TAP_DANCE_SERIAL_LENGTH,
         }
     }
 
@@ -125,6 +278,106 @@ impl ScanCodeBehavior {
                     buffer[0] = HidScanCodeType::ChangeConfig as u8;
                     buffer[1] = config_num;
                 }
+                ScanCodeBehavior::ToggleMouseInvert(axis) => {
+                    buffer[0] = HidScanCodeType::ToggleMouseInvert as u8;
+                    buffer[1] = axis as u8;
+                }
+                ScanCodeBehavior::AnalogLayer {
+                    partial_layer,
+                    full_layer,
+                    partial_travel,
+                    full_travel,
+                } => {
+                    buffer[0] = HidScanCodeType::AnalogLayer as u8;
+                    buffer[1] = partial_layer;
+                    buffer[2] = full_layer;
+                    buffer[3] = partial_travel;
+                    buffer[4] = full_travel;
+                }
+                ScanCodeBehavior::DualStage {
+                    first_code,
+                    second_code,
+                    first_travel,
+                    second_travel,
+                } => {
+                    buffer[0] = HidScanCodeType::DualStage as u8;
+                    buffer[1] = first_code as u8;
+                    buffer[2] = second_code as u8;
+                    buffer[3] = first_travel;
+                    buffer[4] = second_travel;
+                }
+                ScanCodeBehavior::TapDance {
+                    tap,
+                    double_tap,
+                    hold,
+                    term,
+                } => {
+                    buffer[0] = HidScanCodeType::TapDance as u8;
+                    buffer[1] = tap as u8;
+                    buffer[2] = double_tap as u8;
+                    buffer[3] = hold as u8;
+                    buffer[4] = term;
+                }
+                ScanCodeBehavior::ModSwap {
+                    normal_code,
+                    swapped_code,
+                    mods,
+                } => {
+                    buffer[0] = HidScanCodeType::ModSwap as u8;
+                    buffer[1] = normal_code as u8;
+                    buffer[2] = swapped_code as u8;
+                    buffer[3] = mods;
+                }
+                ScanCodeBehavior::OsSwap {
+                    normal_code,
+                    mac_code,
+                } => {
+                    buffer[0] = HidScanCodeType::OsSwap as u8;
+                    buffer[1] = normal_code as u8;
+                    buffer[2] = mac_code as u8;
+                }
+                ScanCodeBehavior::Velocity {
+                    slow_code,
+                    fast_code,
+                    threshold,
+                } => {
+                    buffer[0] = HidScanCodeType::Velocity as u8;
+                    buffer[1] = slow_code as u8;
+                    buffer[2] = fast_code as u8;
+                    buffer[3] = threshold;
+                }
+                ScanCodeBehavior::SwitchHost(host) => {
+                    buffer[0] = HidScanCodeType::SwitchHost as u8;
+                    buffer[1] = host;
+                }
+                ScanCodeBehavior::Bootloader => {
+                    buffer[0] = HidScanCodeType::Bootloader as u8;
+                }
+                ScanCodeBehavior::LockKeyboard { other_index } => {
+                    buffer[0] = HidScanCodeType::LockKeyboard as u8;
+                    buffer[1] = other_index as u8;
+                }
+                ScanCodeBehavior::RecordMacro { save } => {
+                    buffer[0] = HidScanCodeType::RecordMacro as u8;
+                    buffer[1] = save as u8;
+                }
+                ScanCodeBehavior::PlayMacro => {
+                    buffer[0] = HidScanCodeType::PlayMacro as u8;
+                }
+                ScanCodeBehavior::TripleTapDance {
+                    tap,
+                    double_tap,
+                    triple_tap,
+                    hold,
+                    term,
+                } => {
+                    buffer[0] = HidScanCodeType::TripleTapDance as u8;
+                    buffer[1] = tap as u8;
+                    buffer[2] = double_tap as u8;
+                    buffer[3] = triple_tap as u8;
+                    buffer[4] = hold as u8;
+                    buffer[5] = term;
+                }
             }
             Ok(())
         }
@@ -150,7 +403,10 @@ impl<'a> Value<'a> for ScanCodeBehavior {
     where
         Self: Sized,
     {
-        let hid_type = HidScanCodeType::try_from(buffer[0])
+        let code_type = *buffer
+            .first()
+            .ok_or(sequential_storage::map::SerializationError::BufferTooSmall)?;
+        let hid_type = HidScanCodeType::try_from(code_type)
             .map_err(|_| sequential_storage::map::SerializationError::InvalidFormat)?;
         match hid_type {
             HidScanCodeType::Single => {
@@ -210,6 +466,183 @@ impl<'a> Value<'a> for ScanCodeBehavior {
                     ))
                 }
             }
+            HidScanCodeType::ToggleMouseInvert => {
+                if buffer.len() < TOGGLE_MOUSE_INVERT_SERIAL_LENGTH {
+                    Err(sequential_storage::map::SerializationError::BufferTooSmall)
+                } else {
+                    let axis = MouseAxis::try_from(buffer[1])
+                        .map_err(|_| sequential_storage::map::SerializationError::InvalidFormat)?;
+                    Ok((
+                        ScanCodeBehavior::ToggleMouseInvert(axis),
+                        TOGGLE_MOUSE_INVERT_SERIAL_LENGTH,
+                    ))
+                }
+            }
+            HidScanCodeType::AnalogLayer => {
+                if buffer.len() < ANALOG_LAYER_SERIAL_LENGTH {
+                    Err(sequential_storage::map::SerializationError::BufferTooSmall)
+                } else if buffer[1] as usize >= NUM_LAYERS
+                    || buffer[2] as usize >= NUM_LAYERS
+                    || buffer[3] > buffer[4]
+                    || buffer[4] > 100
+                {
+                    Err(sequential_storage::map::SerializationError::InvalidFormat)
+                } else {
+                    Ok((
+                        ScanCodeBehavior::AnalogLayer {
+                            partial_layer: buffer[1],
+                            full_layer: buffer[2],
+                            partial_travel: buffer[3],
+                            full_travel: buffer[4],
+                        },
+                        ANALOG_LAYER_SERIAL_LENGTH,
+                    ))
+                }
+            }
+            HidScanCodeType::DualStage => {
+                if buffer.len() < DUAL_STAGE_SERIAL_LENGTH {
+                    Err(sequential_storage::map::SerializationError::BufferTooSmall)
+                } else if buffer[3] > buffer[4] || buffer[4] > 100 {
+                    Err(sequential_storage::map::SerializationError::InvalidFormat)
+                } else {
+                    Ok((
+                        ScanCodeBehavior::DualStage {
+                            first_code: buffer[1].into(),
+                            second_code: buffer[2].into(),
+                            first_travel: buffer[3],
+                            second_travel: buffer[4],
+                        },
+                        DUAL_STAGE_SERIAL_LENGTH,
+                    ))
+                }
+            }
+            HidScanCodeType::TapDance => {
+                if buffer.len() < TAP_DANCE_SERIAL_LENGTH {
+                    Err(sequential_storage::map::SerializationError::BufferTooSmall)
+                } else if buffer[4] == 0 {
+                    Err(sequential_storage::map::SerializationError::InvalidFormat)
+                } else {
+                    Ok((
+                        ScanCodeBehavior::TapDance {
+                            tap: buffer[1].into(),
+                            double_tap: buffer[2].into(),
+                            hold: buffer[3].into(),
+                            term: buffer[4],
+                        },
+                        TAP_DANCE_SERIAL_LENGTH,
+                    ))
+                }
+            }
+            HidScanCodeType::ModSwap => {
+                if buffer.len() < MOD_SWAP_SERIAL_LENGTH {
+                    Err(sequential_storage::map::SerializationError::BufferTooSmall)
+                } else if buffer[3] == 0 {
+                    Err(sequential_storage::map::SerializationError::InvalidFormat)
+                } else {
+                    Ok((
+                        ScanCodeBehavior::ModSwap {
+                            normal_code: buffer[1].into(),
+                            swapped_code: buffer[2].into(),
+                            mods: buffer[3],
+                        },
+                        MOD_SWAP_SERIAL_LENGTH,
+                    ))
+                }
+            }
+            HidScanCodeType::OsSwap => {
+                if buffer.len() < OS_SWAP_SERIAL_LENGTH {
+                    Err(sequential_storage::map::SerializationError::BufferTooSmall)
+                } else {
+                    Ok((
+                        ScanCodeBehavior::OsSwap {
+                            normal_code: buffer[1].into(),
+                            mac_code: buffer[2].into(),
+                        },
+                        OS_SWAP_SERIAL_LENGTH,
+                    ))
+                }
+            }
+            HidScanCodeType::Velocity => {
+                if buffer.len() < VELOCITY_SERIAL_LENGTH {
+                    Err(sequential_storage::map::SerializationError::BufferTooSmall)
+                } else if buffer[3] == 0 {
+                    Err(sequential_storage::map::SerializationError::InvalidFormat)
+                } else {
+                    Ok((
+                        ScanCodeBehavior::Velocity {
+                            slow_code: buffer[1].into(),
+                            fast_code: buffer[2].into(),
+                            threshold: buffer[3],
+                        },
+                        VELOCITY_SERIAL_LENGTH,
+                    ))
+                }
+            }
+            HidScanCodeType::SwitchHost => {
+                if buffer.len() < SWITCH_HOST_SERIAL_LENGTH {
+                    Err(sequential_storage::map::SerializationError::BufferTooSmall)
+                } else if buffer[1] as usize >= MAX_HOSTS {
+                    Err(sequential_storage::map::SerializationError::InvalidFormat)
+                } else {
+                    Ok((
+                        ScanCodeBehavior::SwitchHost(buffer[1]),
+                        SWITCH_HOST_SERIAL_LENGTH,
+                    ))
+                }
+            }
+            HidScanCodeType::Bootloader => {
+                Ok((ScanCodeBehavior::Bootloader, BOOTLOADER_SERIAL_LENGTH))
+            }
+            HidScanCodeType::LockKeyboard => {
+                if buffer.len() < LOCK_KEYBOARD_SERIAL_LENGTH {
+                    Err(sequential_storage::map::SerializationError::BufferTooSmall)
+                } else {
+                    Ok((
+                        ScanCodeBehavior::LockKeyboard {
+                            other_index: buffer[1] as usize,
+                        },
+                        LOCK_KEYBOARD_SERIAL_LENGTH,
+                    ))
+                }
+            }
+            HidScanCodeType::RecordMacro => {
+                if buffer.len() < RECORD_MACRO_SERIAL_LENGTH {
+                    Err(sequential_storage::map::SerializationError::BufferTooSmall)
+                } else {
+                    let save = match buffer[1] {
+                        0 => false,
+                        1 => true,
+                        _ => {
+                            return Err(sequential_storage::map::SerializationError::InvalidFormat);
+                        }
+                    };
+                    Ok((
+                        ScanCodeBehavior::RecordMacro { save },
+                        RECORD_MACRO_SERIAL_LENGTH,
+                    ))
+                }
+            }
+            HidScanCodeType::PlayMacro => {
+                Ok((ScanCodeBehavior::PlayMacro, PLAY_MACRO_SERIAL_LENGTH))
+            }
+            HidScanCodeType::TripleTapDance => {
+                if buffer.len() < TRIPLE_TAP_DANCE_SERIAL_LENGTH {
+                    Err(sequential_storage::map::SerializationError::BufferTooSmall)
+                } else if buffer[5] == 0 {
+                    Err(sequential_storage::map::SerializationError::InvalidFormat)
+                } else {
+                    Ok((
+                        ScanCodeBehavior::TripleTapDance {
+                            tap: buffer[1].into(),
+                            double_tap: buffer[2].into(),
+                            triple_tap: buffer[3].into(),
+                            hold: buffer[4].into(),
+                            term: buffer[5],
+                        },
+                        TRIPLE_TAP_DANCE_SERIAL_LENGTH,
+                    ))
+                }
+            }
         }
     }
 }
@@ -268,3 +701,141 @@ impl<'a, const N: usize> Value<'a> for ScanCodeLayerStorage<N> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BEHAVIORS: [ScanCodeBehavior; 18] = [
+        ScanCodeBehavior::Single(KeyCodes::KeyboardAa),
+        ScanCodeBehavior::Double(KeyCodes::KeyboardLeftShift, KeyCodes::KeyboardBb),
+        ScanCodeBehavior::Triple(
+            KeyCodes::KeyboardLeftControl,
+            KeyCodes::KeyboardLeftAlt,
+            KeyCodes::KeyboardDelete,
+        ),
+        ScanCodeBehavior::CombinedKey {
+            other_index: 7,
+            normal_code: KeyCodes::KeyboardCc,
+            combined_code: KeyCodes::KeyboardDd,
+        },
+        ScanCodeBehavior::ChangeConfig(2),
+        ScanCodeBehavior::ToggleMouseInvert(MouseAxis::Scroll),
+        ScanCodeBehavior::AnalogLayer {
+            partial_layer: 1,
+            full_layer: 2,
+            partial_travel: 30,
+            full_travel: 90,
+        },
+        ScanCodeBehavior::DualStage {
+            first_code: KeyCodes::KeyboardEe,
+            second_code: KeyCodes::KeyboardFf,
+            first_travel: 40,
+            second_travel: 80,
+        },
+        ScanCodeBehavior::TapDance {
+            tap: KeyCodes::KeyboardGg,
+            double_tap: KeyCodes::KeyboardHh,
+            hold: KeyCodes::KeyboardLeftGUI,
+            term: 20,
+        },
+        ScanCodeBehavior::grave_escape(),
+        ScanCodeBehavior::OsSwap {
+            normal_code: KeyCodes::KeyboardLeftGUI,
+            mac_code: KeyCodes::KeyboardLeftAlt,
+        },
+        ScanCodeBehavior::Velocity {
+            slow_code: KeyCodes::KeyboardIi,
+            fast_code: KeyCodes::KeyboardJj,
+            threshold: 60,
+        },
+        ScanCodeBehavior::SwitchHost(1),
+        ScanCodeBehavior::Bootloader,
+        ScanCodeBehavior::LockKeyboard { other_index: 9 },
+        ScanCodeBehavior::RecordMacro { save: true },
+        ScanCodeBehavior::PlayMacro,
+        ScanCodeBehavior::TripleTapDance {
+            tap: KeyCodes::KeyboardKk,
+            double_tap: KeyCodes::KeyboardLl,
+            triple_tap: KeyCodes::KeyboardMm,
+            hold: KeyCodes::KeyboardLeftControl,
+            term: 25,
+        },
+    ];
+
+    #[test]
+    fn behaviors_round_trip() {
+        for behavior in BEHAVIORS {
+            let mut buf = [0u8; 8];
+            let len = behavior.serialize_into(&mut buf).unwrap();
+            assert_eq!(len, behavior.into_buffer_len());
+            let hid_type = HidScanCodeType::try_from(buf[0]).unwrap();
+            assert_eq!(hid_type.get_len(), len);
+            assert_eq!(
+                ScanCodeBehavior::deserialize_from(&buf[..len]).unwrap(),
+                (behavior, len)
+            );
+        }
+    }
+
+    #[test]
+    fn short_buffers_are_rejected() {
+        for behavior in BEHAVIORS {
+            let mut buf = [0u8; 8];
+            let len = behavior.serialize_into(&mut buf).unwrap();
+            assert_eq!(
+                behavior.serialize_into(&mut buf[..len - 1]),
+                Err(SerializationError::BufferTooSmall)
+            );
+            assert_eq!(
+                ScanCodeBehavior::deserialize_from(&buf[..len - 1]),
+                Err(SerializationError::BufferTooSmall)
+            );
+        }
+    }
+
+    #[test]
+    fn invalid_behaviors_are_rejected() {
+        assert_eq!(
+            ScanCodeBehavior::deserialize_from(&[0xFF, 0]),
+            Err(SerializationError::InvalidFormat)
+        );
+        let zero_threshold = [HidScanCodeType::Velocity as u8, 4, 5, 0];
+        assert_eq!(
+            ScanCodeBehavior::deserialize_from(&zero_threshold),
+            Err(SerializationError::InvalidFormat)
+        );
+        let missing_host = [HidScanCodeType::SwitchHost as u8, MAX_HOSTS as u8];
+        assert_eq!(
+            ScanCodeBehavior::deserialize_from(&missing_host),
+            Err(SerializationError::InvalidFormat)
+        );
+        let missing_layer = [
+            HidScanCodeType::AnalogLayer as u8,
+            1,
+            NUM_LAYERS as u8,
+            30,
+            90,
+        ];
+        assert_eq!(
+            ScanCodeBehavior::deserialize_from(&missing_layer),
+            Err(SerializationError::InvalidFormat)
+        );
+    }
+
+    #[test]
+    fn layer_storage_round_trips() {
+        let storage = ScanCodeLayerStorage { codes: BEHAVIORS };
+        let mut buf = [0u8; 64];
+        let len = storage.serialize_into(&mut buf).unwrap();
+        assert_eq!(
+            ScanCodeLayerStorage::<18>::deserialize_from(&buf[..len]).unwrap(),
+            (storage, len)
+        );
+        // Trailing bytes mean the stored layer doesn't match the key count
+        assert_eq!(
+            ScanCodeLayerStorage::<17>::deserialize_from(&buf[..len]),
+            Err(SerializationError::InvalidFormat)
+        );
+    }
+}
@@ -1,19 +1,154 @@
 use core::ops::{Deref, DerefMut};
 
-use defmt::{error, info};
+use defmt::{Format, error, info};
+use embassy_futures::select::{Either, select};
 use embassy_sync::blocking_mutex::raw::RawMutex;
 use embassy_sync::mutex::Mutex;
-use embassy_time::Instant;
+use embassy_time::{Duration, Instant, Ticker};
 use embassy_usb::class::hid::{HidReader, HidWriter};
 use embassy_usb::driver::Driver;
+use num_enum::TryFromPrimitive;
+use sequential_storage::map::Value;
 
-use crate::keys::{ConfigIndicator, Keys};
+use crate::backup::{
+    BACKUP_BODY, BACKUP_HEADER_LEN, BackupHeader, BackupRecord, ImportStatus, RecordKind, Records,
+    push_record, validate,
+};
+use crate::bootloader::request_bootloader;
+use crate::combo::{COMBOS_SERIAL_LENGTH, Combos};
+use crate::config_slots::{mark_written, slot_state};
+use crate::keys::{ConfigIndicator, HostLeds, Keys};
+use crate::lighting::{LIGHTING, LIGHTING_SERIAL_LENGTH, LightingSettings, store_lighting};
 
+use crate::crash::last_crash;
 use crate::descriptor::BufferReport;
+use crate::diagnostics::{
+    DiagnosticKind, HealthReport, LINK_STATS_SERIAL_LENGTH, LinkReport, boot_report,
+    write_diagnostic, write_unsupported,
+};
+use crate::dynamic_macro::set_dynamic_macro;
+use crate::error::{Endpoint, KeyLibError, record_endpoint_error, record_error, take_last_error};
+use crate::factory_reset::{FACTORY_RESET_CONFIRM, request_factory_reset};
+use crate::host_switch::request_host_switch;
+use crate::inject::{InjectCommand, InjectStatus, inject};
+use crate::key_stats::{
+    KEY_STATS_SERIAL_LENGTH, KeyStatsCommand, clear_key_stats, key_stats, set_key_stats_enabled,
+};
+use crate::latency::{LatencyReport, set_latency_tracking};
+use crate::link_watch::{HalfLinkState, LINK_WATCH_FRAME_LEN, link_watch_frame};
+use crate::os::store_os_override;
+use crate::position::CalibrationCommand;
+#[cfg(feature = "hall-effect")]
+use crate::position::{
+    analog_readings, request_calibration, set_analog_streaming, set_drift_compensation,
+};
+use crate::power::{
+    BATTERY_THRESHOLDS, BatteryThresholds, store_battery_thresholds, store_link_timing,
+};
+use crate::reaction::{ReactionStatus, next_reaction_event, start_reaction_test};
+use crate::scan::set_scan_interval;
+use crate::self_test::{SelfTestCommand, self_test_report, start_self_test, stop_self_test};
+use crate::settings::{SWITCH_PROFILES_SERIAL_LENGTH, SwitchProfiles};
+#[cfg(feature = "sim")]
+use crate::sim::{SIM_KEYS_LEN, set_live_keys};
+use crate::socd::{SOCD_PAIRS_SERIAL_LENGTH, SocdPairs};
+use crate::storage::{
+    MAX_SETTING_LEN, SettingId, StorageItem, StorageKey, compact_storage, get_item, storage_stats,
+    store_val,
+};
 use crate::{IS_SPLIT, NUM_CONFIGS, NUM_KEYS, NUM_LAYERS};
 
 const BUFFER_SIZE: usize = 32;
 
+/// Length of the HidRequest::LinkStatus response. The first byte is 1 if the
+/// board has a wireless link followed by the time in ms since each half was
+/// last heard from as a le u32, where u32::MAX means the half hasn't been seen
+pub const LINK_STATUS_LEN: usize = 9;
+
+/// Length of the HidRequest::LinkStats response. The first byte is 1 if the
+/// board has a wireless link followed by the stats of each half: packets sent,
+/// packets received, acks missed and crc errors as le u32s then the rssi of
+/// the last packet received in dBm as an i8
+pub const LINK_STATS_LEN: usize = 1 + 2 * LINK_STATS_SERIAL_LENGTH;
+
+/// Largest request that can be forwarded to a half with
+/// HidRequest::ForwardToHalf. Responses are bounded by the same length
+pub const MAX_FORWARD_LEN: usize = 31;
+
+/// Status byte sent before the response of a HidRequest::ForwardToHalf
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Format, TryFromPrimitive)]
+pub enum ForwardStatus {
+    Ok = 0,
+    Unsupported = 1,
+    Invalid = 2,
+    Timeout = 3,
+}
+
+/// Largest chunk of a firmware image sent with HidRequest::DfuData
+pub const MAX_DFU_CHUNK_LEN: usize = 24;
+
+/// Length of each frame sent by HidRequest::StreamAnalog. A frame holds the
+/// averaged reading of every key as le u16s
+pub const ANALOG_FRAME_LEN: usize = NUM_KEYS * 2;
+
+/// First byte of a framed request. Legacy requests start with their
+/// HidRequest, which is always lower, so both can be told apart
+pub const FRAME_MAGIC: u8 = 0xFA;
+
+/// Version of the framed protocol. Sent after the magic of every framed
+/// request and response, and at the end of the HidRequest::KeyboardMetaInfo
+/// response so hosts can check it before sending a frame. Firmware from
+/// before framing sends 0 there
+pub const COM_PROTOCOL_VERSION: u8 = 1;
+
+/// Length of the header of a framed request: the magic, the version, the
+/// HidRequest and the length of the payload after the header as a le u16
+pub const FRAME_HEADER_LEN: usize = 5;
+
+/// Length of the report sent before the response of a framed request: the
+/// magic, the firmware's protocol version and a FrameStatus
+pub const FRAME_STATUS_LEN: usize = 3;
+
+/// Status of a framed request. The status is sent on its own report before
+/// the response, and requests that aren't Ok have no response
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Format)]
+pub enum FrameStatus {
+    Ok = 0,
+    /// The frame's version doesn't match [COM_PROTOCOL_VERSION]
+    UnsupportedVersion = 1,
+    /// The request isn't a HidRequest this firmware knows
+    UnknownRequest = 2,
+}
+
+impl FrameStatus {
+    pub const fn report(self) -> [u8; FRAME_STATUS_LEN] {
+        [FRAME_MAGIC, COM_PROTOCOL_VERSION, self as u8]
+    }
+}
+
+/// Reads the header of a framed request after the magic. Returns the request
+/// and the length of its payload, or the status to reply with
+pub fn parse_frame_header(
+    header: [u8; FRAME_HEADER_LEN - 1],
+) -> (Result<HidRequest, FrameStatus>, usize) {
+    let [version, request, len @ ..] = header;
+    let len = u16::from_le_bytes(len) as usize;
+    if version != COM_PROTOCOL_VERSION {
+        return (Err(FrameStatus::UnsupportedVersion), len);
+    }
+    let request = HidRequest::try_from(request).map_err(|_| FrameStatus::UnknownRequest);
+    (request, len)
+}
+
+/// Status byte sent before the blob in a HidRequest::ReadSetting response
+#[repr(u8)]
+pub enum SettingStatus {
+    Ok = 0,
+    Invalid = 1,
+}
+
 pub struct ContinuousWriter<'d, T: Driver<'d>> {
     writer: HidWriter<'d, T, 32>,
     index: usize,
@@ -32,7 +167,7 @@ impl<'d, T: Driver<'d>> ContinuousWriter<'d, T> {
         }
     }
 
-    pub async fn write(&mut self, buf: &[u8]) {
+    pub async fn write(&mut self, buf: &[u8]) -> Result<(), KeyLibError> {
         let mut buf_index = 0;
         while buf_index < buf.len() {
             let buf_end = (buf_index + (BUFFER_SIZE - self.index)).min(buf.len());
@@ -41,20 +176,34 @@ impl<'d, T: Driver<'d>> ContinuousWriter<'d, T> {
             self.buffer.input[self.index..rep_end].copy_from_slice(&buf[buf_index..buf_end]);
             buf_index = buf_end;
             if rep_end == 32 {
-                self.writer.write_serialize(&self.buffer).await.unwrap();
                 self.index = 0;
+                self.writer
+                    .write_serialize(&self.buffer)
+                    .await
+                    .map_err(|_| KeyLibError::Com)?;
             } else {
                 self.index = rep_end;
             }
         }
+        Ok(())
     }
 
-    pub async fn flush(&mut self) {
+    pub async fn flush(&mut self) -> Result<(), KeyLibError> {
         if self.index != 0 {
             self.buffer.input[self.index..].fill(0);
-            self.writer.write_serialize(&self.buffer).await.unwrap();
             self.index = 0;
+            self.writer
+                .write_serialize(&self.buffer)
+                .await
+                .map_err(|_| KeyLibError::Com)?;
         }
+        Ok(())
+    }
+
+    /// Drops the bytes of a report that wasn't sent yet, e.g. a response cut
+    /// short by an endpoint error
+    pub fn reset(&mut self) {
+        self.index = 0;
     }
 }
 
@@ -63,6 +212,8 @@ pub struct ContinuousReader<'d, T: Driver<'d>> {
     index: usize,
     buffer_len: usize,
     buffer: [u8; 32],
+    // Bytes popped so far, used to find the end of a framed request
+    popped: usize,
 }
 
 impl<'d, T: Driver<'d>> ContinuousReader<'d, T> {
@@ -72,6 +223,7 @@ impl<'d, T: Driver<'d>> ContinuousReader<'d, T> {
             index: 0,
             buffer_len: 0,
             buffer: [0u8; BUFFER_SIZE],
+            popped: 0,
         }
     }
 
@@ -79,31 +231,42 @@ impl<'d, T: Driver<'d>> ContinuousReader<'d, T> {
         self.index = 0;
     }
 
-    pub async fn pop(&mut self) -> u8 {
+    async fn read_report(&mut self) -> Result<(), KeyLibError> {
+        self.buffer_len = self
+            .reader
+            .read(&mut self.buffer)
+            .await
+            .map_err(|_| KeyLibError::Com)?;
+        Ok(())
+    }
+
+    pub async fn pop(&mut self) -> Result<u8, KeyLibError> {
         if self.index == 0 {
-            self.buffer_len = self.reader.read(&mut self.buffer).await.unwrap();
+            self.read_report().await?;
         }
 
         let val = self.buffer[self.index];
+        self.popped = self.popped.wrapping_add(1);
 
         self.index += 1;
         if self.index == self.buffer_len {
             self.index = 0;
         }
-        val
+        Ok(val)
     }
 
-    pub async fn pop_slice(&mut self, buf: &mut [u8]) {
+    pub async fn pop_slice(&mut self, buf: &mut [u8]) -> Result<(), KeyLibError> {
         let mut buf_index = 0;
         while buf_index < buf.len() {
             if self.index == 0 {
-                self.buffer_len = self.reader.read(&mut self.buffer).await.unwrap();
+                self.read_report().await?;
             }
             let buf_end = (buf_index + (self.buffer_len - self.index)).min(buf.len());
             let write_len = buf_end - buf_index;
 
             let rep_end = self.index + write_len;
             buf[buf_index..buf_end].copy_from_slice(&self.buffer[self.index..rep_end]);
+            self.popped = self.popped.wrapping_add(write_len);
 
             buf_index = buf_end;
             if rep_end == self.buffer_len {
@@ -112,10 +275,21 @@ impl<'d, T: Driver<'d>> ContinuousReader<'d, T> {
                 self.index = rep_end;
             }
         }
+        Ok(())
+    }
+
+    /// Drops the next len bytes, e.g. the part of a framed request's payload
+    /// its handler didn't read
+    pub async fn skip(&mut self, len: usize) -> Result<(), KeyLibError> {
+        for _ in 0..len {
+            self.pop().await?;
+        }
+        Ok(())
     }
 }
 
 #[repr(u8)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Format, TryFromPrimitive)]
 pub enum HidRequest {
     UpdateKeys = 0,
     KeyboardInfo = 1,
@@ -123,20 +297,94 @@ pub enum HidRequest {
     KeyboardMetaInfo = 3,
     CurrentMode = 4,
     ToggleSlave = 5,
-}
-
-impl From<u8> for HidRequest {
-    fn from(value: u8) -> Self {
-        match value {
-            0 => Self::UpdateKeys,
-            1 => Self::KeyboardInfo,
-            2 => Self::WriteToFlash,
-            3 => Self::KeyboardMetaInfo,
-            4 => Self::CurrentMode,
-            5 => Self::ToggleSlave,
-            _ => todo!(),
-        }
-    }
+    BatteryThresholds = 6,
+    UpdateBatteryThresholds = 7,
+    Combos = 8,
+    UpdateCombos = 9,
+    SocdPairs = 10,
+    UpdateSocdPairs = 11,
+    LinkStatus = 12,
+    ReadSetting = 13,
+    WriteSetting = 14,
+    LinkStats = 15,
+    ForwardToHalf = 16,
+    DfuStart = 17,
+    DfuData = 18,
+    DfuFinish = 19,
+    Lighting = 20,
+    UpdateLighting = 21,
+    HostLeds = 22,
+    LastError = 23,
+    SwitchProfiles = 24,
+    UpdateSwitchProfiles = 25,
+    /// Streams analog frames every n ms, where n is the first byte of the
+    /// request, until the host sends another report. The report that stops
+    /// the stream is discarded. Boards without analog switches ignore it
+    StreamAnalog = 26,
+    /// Runs the CalibrationCommand in the first byte of the request. Boards
+    /// without analog switches ignore it
+    Calibration = 27,
+    /// Responds with a framed diagnostic of the DiagnosticKind in the first
+    /// byte of the request. See [crate::diagnostics]
+    Diagnostics = 28,
+    /// Presses the keys whose bits are set in the request, replacing the
+    /// pattern of simulated sensors. See [crate::sim]. Boards built without
+    /// the sim feature ignore it
+    SimKeys = 29,
+    /// Runs the InjectCommand in the first byte of the request on the key in
+    /// the second byte and responds with an InjectStatus. See [crate::inject]
+    InjectKey = 30,
+    /// Turns latency tracking on if the first byte of the request is 1 and
+    /// off if it's 0. See [crate::latency]
+    LatencyTracking = 31,
+    /// Switches a wireless keyboard to the host in the first byte of the
+    /// request, like ScanCodeBehavior::SwitchHost. Dongles relay it to the
+    /// halves with the host's addresses after the host. See
+    /// [crate::host_switch]
+    SwitchHost = 32,
+    /// Streams link watch frames every n * 100ms, where n is the first byte
+    /// of the request, until the host sends another report. The report that
+    /// stops the stream is discarded. See [crate::link_watch]
+    WatchLink = 33,
+    /// Responds with a blob of every config and setting. See [crate::backup]
+    ExportConfig = 34,
+    /// Restores a blob from HidRequest::ExportConfig sent after the request
+    /// and responds with an ImportStatus. Nothing is written unless the
+    /// whole blob is valid
+    ImportConfig = 35,
+    /// Rewrites storage with only the newest copy of each item and responds
+    /// with the Storage diagnostic taken after it
+    CompactStorage = 36,
+    /// Starts a reaction test. Responds with a ReactionEvent when the cue is
+    /// shown and another once the test ends. See [crate::reaction]
+    ReactionTest = 37,
+    /// Reboots the board into its bootloader, like
+    /// ScanCodeBehavior::Bootloader. Has no response. See [crate::bootloader]
+    Bootloader = 38,
+    /// Responds with the crc of the config in the first byte of the request
+    /// as a little endian u32. See [Keys::checksum]
+    KeymapChecksum = 39,
+    /// Runs the KeyStatsCommand in the first byte of the request. Only
+    /// KeyStatsCommand::Read has a response. See [crate::key_stats]
+    KeyStats = 40,
+    /// Runs the SelfTestCommand in the first byte of the request.
+    /// SelfTestCommand::Read and SelfTestCommand::Stop respond with the
+    /// serialized SelfTestReport. See [crate::self_test]
+    SelfTest = 41,
+    /// Erases storage and reboots the board with its built-in keymap if the
+    /// request starts with FACTORY_RESET_CONFIRM. Has no response. See
+    /// [crate::factory_reset]
+    FactoryReset = 42,
+    /// Reads a layer of the keymap a wireless half keeps for running on its
+    /// own. The request is the half's address, the config and the layer.
+    /// Responds with a ForwardStatus and the length as a le u16 followed by
+    /// the serialized layer. Only dongles relay it
+    HalfLayer = 43,
+    /// Writes a layer of a wireless half's own keymap to its flash. The
+    /// request is the half's address, the config, the layer and the length
+    /// as a le u16 followed by the serialized layer. Responds with a
+    /// ForwardStatus. Only dongles relay it
+    UpdateHalfLayer = 44,
 }
 
 pub trait KeyboardState {
@@ -145,7 +393,7 @@ pub trait KeyboardState {
         request: HidRequest,
         reader: &mut ContinuousReader<'d, T>,
         writer: &mut ContinuousWriter<'d, T>,
-    ) -> impl Future<Output = ()>;
+    ) -> impl Future<Output = Result<(), KeyLibError>>;
 }
 
 impl<M: RawMutex, I: ConfigIndicator> KeyboardState for Mutex<M, Keys<I>> {
@@ -154,17 +402,18 @@ impl<M: RawMutex, I: ConfigIndicator> KeyboardState for Mutex<M, Keys<I>> {
         hid_request: HidRequest,
         reader: &mut ContinuousReader<'d, T>,
         writer: &mut ContinuousWriter<'d, T>,
-    ) {
+    ) -> Result<(), KeyLibError> {
         match hid_request {
             HidRequest::UpdateKeys => {
-                let config_num = reader.pop().await as usize;
+                let config_num = reader.pop().await? as usize;
                 let mut keys = self.lock().await;
                 match keys.load_keys_from_com(reader, config_num).await {
                     Ok(_) => {
                         info!("Finished Receiving bytes");
                     }
-                    Err(_) => {
+                    Err(err) => {
                         error!("Unable to read from com to deserialzie keyboard config");
+                        record_error(err);
                         let _ = keys.load_keys_from_storage(0).await;
                     }
                 }
@@ -184,7 +433,7 @@ impl<M: RawMutex, I: ConfigIndicator> KeyboardState for Mutex<M, Keys<I>> {
                         &default_keys
                     };
                     let load_time = Instant::now();
-                    keys.write_keys_to_com(writer).await;
+                    keys.write_keys_to_com(writer).await?;
                     let write_time = Instant::now();
                     info!(
                         "Writing to com config {} | Write Time : {}ms | Load Time : {}ms",
@@ -193,7 +442,7 @@ impl<M: RawMutex, I: ConfigIndicator> KeyboardState for Mutex<M, Keys<I>> {
                         (load_time - start).as_millis(),
                     );
                 }
-                writer.flush().await;
+                writer.flush().await?;
                 info!("Finished sending keyboard config!");
             }
             HidRequest::WriteToFlash => {
@@ -206,7 +455,7 @@ impl<M: RawMutex, I: ConfigIndicator> KeyboardState for Mutex<M, Keys<I>> {
                         drop(lock);
                         &mut default_keys
                     };
-                    keys.load_keys_from_com(reader, config_num).await.unwrap();
+                    keys.load_keys_from_com(reader, config_num).await?;
                     info!("Succesfully loaded config {}!", config_num);
                     keys.write_keys_to_storage(config_num).await;
                 }
@@ -214,23 +463,703 @@ impl<M: RawMutex, I: ConfigIndicator> KeyboardState for Mutex<M, Keys<I>> {
             }
             HidRequest::KeyboardMetaInfo => {
                 info!("Requested Keyboard meta info!");
+                let keys = self.lock().await;
+                let config_num = keys.config_num as u8;
+                let crc = keys.checksum()?;
+                drop(keys);
                 writer
                     .write(&[
                         NUM_CONFIGS as u8,
                         NUM_KEYS as u8,
                         NUM_LAYERS as u8,
                         IS_SPLIT as u8,
+                        config_num,
                     ])
-                    .await;
-                writer.flush().await;
+                    .await?;
+                // The checksum of the active config follows the meta info
+                writer.write(&crc.to_le_bytes()).await?;
+                writer.write(&[COM_PROTOCOL_VERSION]).await?;
+                writer.flush().await?;
             }
             HidRequest::CurrentMode => {
-                writer.write(&[0]).await;
+                writer.write(&[0]).await?;
             }
             HidRequest::ToggleSlave => {}
+            HidRequest::BatteryThresholds => {
+                let mut buf = [0u8; 4];
+                let thresholds = *BATTERY_THRESHOLDS.lock().await;
+                thresholds.into_buffer(&mut buf)?;
+                writer.write(&buf).await?;
+                writer.flush().await?;
+            }
+            HidRequest::UpdateBatteryThresholds => {
+                let mut buf = [0u8; 4];
+                reader.pop_slice(&mut buf).await?;
+                match BatteryThresholds::deserialize_from(&buf) {
+                    Ok((thresholds, _)) => {
+                        info!("Updating battery thresholds to {}", thresholds);
+                        store_battery_thresholds(thresholds).await;
+                    }
+                    Err(_) => {
+                        error!("Received invalid battery thresholds");
+                    }
+                }
+            }
+            HidRequest::Combos => {
+                let config_num = reader.pop().await? as usize;
+                check_config(config_num)?;
+                let lock = self.lock().await;
+                let combos = if lock.config_num == config_num {
+                    lock.combos.combos
+                } else {
+                    drop(lock);
+                    match get_item(StorageKey::Combo { config_num }).await {
+                        Some(StorageItem::Combos(combos)) => combos,
+                        _ => Combos::default(),
+                    }
+                };
+                let mut buf = [0u8; COMBOS_SERIAL_LENGTH];
+                combos.into_buffer(&mut buf)?;
+                writer.write(&buf).await?;
+                writer.flush().await?;
+            }
+            HidRequest::UpdateCombos => {
+                let config_num = reader.pop().await? as usize;
+                let mut buf = [0u8; COMBOS_SERIAL_LENGTH];
+                reader.pop_slice(&mut buf).await?;
+                check_config(config_num)?;
+                match Combos::deserialize_from(&buf) {
+                    Ok((combos, _)) => {
+                        info!("Updating combos for config {}", config_num);
+                        let mut keys = self.lock().await;
+                        if keys.config_num == config_num {
+                            keys.combos.set_combos(combos);
+                        }
+                        drop(keys);
+                        store_val(
+                            StorageKey::Combo { config_num },
+                            &StorageItem::Combos(combos),
+                        )
+                        .await;
+                    }
+                    Err(_) => {
+                        error!("Received invalid combos");
+                    }
+                }
+            }
+            HidRequest::SocdPairs => {
+                let config_num = reader.pop().await? as usize;
+                check_config(config_num)?;
+                let lock = self.lock().await;
+                let pairs = if lock.config_num == config_num {
+                    lock.socd
+                } else {
+                    drop(lock);
+                    match get_item(StorageKey::Socd { config_num }).await {
+                        Some(StorageItem::SocdPairs(pairs)) => pairs,
+                        _ => SocdPairs::default(),
+                    }
+                };
+                let mut buf = [0u8; SOCD_PAIRS_SERIAL_LENGTH];
+                pairs.into_buffer(&mut buf)?;
+                writer.write(&buf).await?;
+                writer.flush().await?;
+            }
+            HidRequest::UpdateSocdPairs => {
+                let config_num = reader.pop().await? as usize;
+                let mut buf = [0u8; SOCD_PAIRS_SERIAL_LENGTH];
+                reader.pop_slice(&mut buf).await?;
+                check_config(config_num)?;
+                match SocdPairs::deserialize_from(&buf) {
+                    Ok((pairs, _)) => {
+                        info!("Updating socd pairs for config {}", config_num);
+                        let mut keys = self.lock().await;
+                        if keys.config_num == config_num {
+                            keys.socd = pairs;
+                        }
+                        drop(keys);
+                        store_val(
+                            StorageKey::Socd { config_num },
+                            &StorageItem::SocdPairs(pairs),
+                        )
+                        .await;
+                    }
+                    Err(_) => {
+                        error!("Received invalid socd pairs");
+                    }
+                }
+            }
+            HidRequest::SwitchProfiles => {
+                let config_num = reader.pop().await? as usize;
+                check_config(config_num)?;
+                let lock = self.lock().await;
+                let profiles = if lock.config_num == config_num {
+                    lock.position_types()
+                } else {
+                    drop(lock);
+                    match get_item(StorageKey::SwitchProfiles { config_num }).await {
+                        Some(StorageItem::SwitchProfiles(profiles)) => profiles,
+                        _ => SwitchProfiles::default(),
+                    }
+                };
+                let mut buf = [0u8; SWITCH_PROFILES_SERIAL_LENGTH];
+                profiles.serialize_into(&mut buf)?;
+                writer.write(&buf).await?;
+                writer.flush().await?;
+            }
+            HidRequest::UpdateSwitchProfiles => {
+                let config_num = reader.pop().await? as usize;
+                let mut buf = [0u8; SWITCH_PROFILES_SERIAL_LENGTH];
+                reader.pop_slice(&mut buf).await?;
+                check_config(config_num)?;
+                match SwitchProfiles::deserialize_from(&buf) {
+                    Ok((profiles, _)) => {
+                        info!("Updating switch profiles for config {}", config_num);
+                        let mut keys = self.lock().await;
+                        if keys.config_num == config_num {
+                            keys.set_position_type_per_key(profiles);
+                        }
+                        drop(keys);
+                        store_val(
+                            StorageKey::SwitchProfiles { config_num },
+                            &StorageItem::SwitchProfiles(profiles),
+                        )
+                        .await;
+                    }
+                    Err(_) => {
+                        error!("Received invalid switch profiles");
+                    }
+                }
+            }
+            HidRequest::LinkStatus => {
+                // Boards without a wireless link have no link status to report
+                writer.write(&[0; LINK_STATUS_LEN]).await?;
+                writer.flush().await?;
+            }
+            HidRequest::LinkStats => {
+                writer.write(&[0; LINK_STATS_LEN]).await?;
+                writer.flush().await?;
+            }
+            HidRequest::ForwardToHalf
+            | HidRequest::DfuStart
+            | HidRequest::DfuFinish
+            | HidRequest::UpdateHalfLayer => {
+                // Only boards with wireless halves can forward requests
+                writer.write(&[ForwardStatus::Unsupported as u8, 0]).await?;
+                writer.flush().await?;
+            }
+            HidRequest::HalfLayer => {
+                writer
+                    .write(&[ForwardStatus::Unsupported as u8, 0, 0])
+                    .await?;
+                writer.flush().await?;
+            }
+            HidRequest::DfuData => {}
+            HidRequest::HostLeds => {
+                let leds = HostLeds(reader.pop().await?);
+                self.lock().await.set_host_leds(leds).await;
+            }
+            HidRequest::Lighting => {
+                let mut buf = [0u8; LIGHTING_SERIAL_LENGTH];
+                let settings = *LIGHTING.lock().await;
+                settings.into_buffer(&mut buf)?;
+                writer.write(&buf).await?;
+                writer.flush().await?;
+            }
+            HidRequest::UpdateLighting => {
+                let mut buf = [0u8; LIGHTING_SERIAL_LENGTH];
+                reader.pop_slice(&mut buf).await?;
+                match LightingSettings::deserialize_from(&buf) {
+                    Ok((settings, _)) => store_lighting(settings).await,
+                    Err(_) => {
+                        error!("Received invalid lighting settings");
+                    }
+                }
+            }
+            HidRequest::ReadSetting => {
+                let id = reader.pop().await?;
+                let config_num = reader.pop().await? as usize;
+                let Some((setting, key)) = SettingId::try_from(id)
+                    .ok()
+                    .filter(|setting| setting.is_readable())
+                    .and_then(|setting| Some((setting, setting.storage_key(config_num)?)))
+                else {
+                    error!("Requested invalid setting {} for config {}", id, config_num);
+                    writer.write(&[SettingStatus::Invalid as u8, 0]).await?;
+                    writer.flush().await?;
+                    return Ok(());
+                };
+                let item = match get_item(key).await {
+                    Some(item) => item,
+                    None => setting.default_item(),
+                };
+                let mut buf = [0u8; MAX_SETTING_LEN];
+                let len = item.serialize_into(&mut buf)?;
+                writer.write(&[SettingStatus::Ok as u8, len as u8]).await?;
+                writer.write(&buf[..len]).await?;
+                writer.flush().await?;
+            }
+            HidRequest::WriteSetting => {
+                let id = reader.pop().await?;
+                let config_num = reader.pop().await? as usize;
+                let len = reader.pop().await? as usize;
+                let Some((setting, key)) = SettingId::try_from(id)
+                    .ok()
+                    .filter(|_| len <= MAX_SETTING_LEN)
+                    .and_then(|setting| Some((setting, setting.storage_key(config_num)?)))
+                else {
+                    error!(
+                        "Received invalid setting {} of len {} for config {}",
+                        id, len, config_num
+                    );
+                    return Ok(());
+                };
+                let mut buf = [0u8; MAX_SETTING_LEN];
+                reader.pop_slice(&mut buf[..len]).await?;
+                let item = match setting.deserialize(&buf[..len]) {
+                    Ok(item) => item,
+                    Err(_) => {
+                        error!("Received invalid blob for setting {}", setting);
+                        return Ok(());
+                    }
+                };
+                info!("Updating setting {} for config {}", setting, config_num);
+                store_setting(self, config_num, key, item).await;
+            }
+            HidRequest::LastError => {
+                let err = take_last_error().map_or(0, |err| err as u8);
+                writer.write(&[err]).await?;
+                writer.flush().await?;
+            }
+            HidRequest::Diagnostics => {
+                let kind = reader.pop().await?;
+                match DiagnosticKind::try_from(kind) {
+                    Ok(DiagnosticKind::Link) => {
+                        write_diagnostic(writer, &LinkReport::wired()).await?
+                    }
+                    Ok(DiagnosticKind::Health) => {
+                        write_diagnostic(writer, &HealthReport::current()).await?
+                    }
+                    Ok(DiagnosticKind::Latency) => {
+                        write_diagnostic(writer, &LatencyReport::current()).await?
+                    }
+                    Ok(DiagnosticKind::Storage) => {
+                        write_diagnostic(writer, &storage_stats().await).await?
+                    }
+                    Ok(DiagnosticKind::Boot) => match boot_report() {
+                        Some(report) => write_diagnostic(writer, &report).await?,
+                        None => write_unsupported(writer, kind).await?,
+                    },
+                    Ok(DiagnosticKind::Crash) => write_diagnostic(writer, &last_crash()).await?,
+                    _ => write_unsupported(writer, kind).await?,
+                }
+            }
+            HidRequest::StreamAnalog => {
+                let interval = reader.pop().await?;
+                reader.flush();
+                #[cfg(feature = "hall-effect")]
+                {
+                    info!("Streaming analog readings every {}ms", interval);
+                    set_analog_streaming(true);
+                    let res = stream_analog(reader, writer, interval).await;
+                    set_analog_streaming(false);
+                    info!("Stopped streaming analog readings");
+                    res?;
+                }
+                #[cfg(not(feature = "hall-effect"))]
+                let _ = interval;
+            }
+            HidRequest::Calibration => {
+                let command = reader.pop().await?;
+                match CalibrationCommand::try_from(command) {
+                    #[cfg(feature = "hall-effect")]
+                    Ok(command) => request_calibration(command),
+                    #[cfg(not(feature = "hall-effect"))]
+                    Ok(_) => {}
+                    Err(_) => error!("Received invalid calibration command {}", command),
+                }
+            }
+            HidRequest::SimKeys => {
+                #[cfg(feature = "sim")]
+                {
+                    let mut keys = [0u8; SIM_KEYS_LEN];
+                    reader.pop_slice(&mut keys).await?;
+                    set_live_keys(keys);
+                }
+            }
+            HidRequest::InjectKey => {
+                let command = reader.pop().await?;
+                let key = reader.pop().await?;
+                let status = match InjectCommand::try_from(command) {
+                    Ok(command) => inject(command, key),
+                    Err(_) => InjectStatus::Invalid,
+                };
+                writer.write(&[status as u8]).await?;
+                writer.flush().await?;
+            }
+            HidRequest::LatencyTracking => {
+                let enabled = reader.pop().await? != 0;
+                set_latency_tracking(enabled);
+            }
+            HidRequest::SwitchHost => {
+                request_host_switch(reader.pop().await?);
+            }
+            HidRequest::WatchLink => {
+                reader.pop().await?;
+                reader.flush();
+                // Boards without a wireless link have no link state to watch
+                writer.write(&[0; LINK_WATCH_FRAME_LEN]).await?;
+                writer.flush().await?;
+            }
+            HidRequest::ExportConfig => {
+                info!("Exporting backup");
+                let mut body = BACKUP_BODY.lock().await;
+                body.clear();
+                let mut default_keys = Keys::default();
+                for config_num in 0..NUM_CONFIGS {
+                    let lock = self.lock().await;
+                    let keys = if lock.config_num == config_num {
+                        lock.deref()
+                    } else {
+                        drop(lock);
+                        let _ = default_keys.load_keys_from_storage(config_num).await;
+                        &default_keys
+                    };
+                    for layer in 0..NUM_LAYERS {
+                        let item = StorageItem::Key(keys.layer(layer));
+                        push_record(&mut body, RecordKind::Layer, config_num, layer as u8, &item)?;
+                    }
+                }
+                for setting in SettingId::ALL
+                    .iter()
+                    .filter(|setting| setting.is_readable())
+                {
+                    let configs = if setting.is_global() { 1 } else { NUM_CONFIGS };
+                    for config_num in 0..configs {
+                        let Some(key) = setting.storage_key(config_num) else {
+                            continue;
+                        };
+                        let item = match get_item(key).await {
+                            Some(item) => item,
+                            None => setting.default_item(),
+                        };
+                        push_record(
+                            &mut body,
+                            RecordKind::Setting,
+                            config_num,
+                            *setting as u8,
+                            &item,
+                        )?;
+                    }
+                }
+                writer.write(&BackupHeader::new(&body).to_bytes()).await?;
+                writer.write(&body).await?;
+                writer.flush().await?;
+                info!("Exported backup of {} bytes", body.len());
+            }
+            HidRequest::ImportConfig => {
+                let mut buf = [0u8; BACKUP_HEADER_LEN];
+                reader.pop_slice(&mut buf).await?;
+                let header = BackupHeader::from_bytes(&buf);
+                let mut body = BACKUP_BODY.lock().await;
+                body.clear();
+                // The whole blob is read even if it's rejected so it isn't
+                // handled as requests
+                for _ in 0..header.len {
+                    let byte = reader.pop().await?;
+                    let _ = body.push(byte);
+                }
+                let status = match validate(&header, &body) {
+                    Ok(()) => {
+                        info!("Importing backup of {} bytes", body.len());
+                        let mut imported = [false; NUM_CONFIGS];
+                        for record in Records::new(&body).flatten() {
+                            match record {
+                                BackupRecord::Layer {
+                                    config_num,
+                                    layer,
+                                    codes,
+                                } => {
+                                    let mut keys = self.lock().await;
+                                    if keys.config_num == config_num {
+                                        keys.set_layer(layer, &codes);
+                                    }
+                                    drop(keys);
+                                    let key = StorageKey::KeyScanCode {
+                                        config_num,
+                                        layer,
+                                        slot: slot_state(config_num).await.write_slot(),
+                                    };
+                                    store_val(key, &StorageItem::Key(codes)).await;
+                                    imported[config_num] = true;
+                                }
+                                BackupRecord::Setting {
+                                    config_num,
+                                    key,
+                                    item,
+                                } => store_setting(self, config_num, key, item).await,
+                            }
+                        }
+                        // The imported layers boot like ones written with
+                        // HidRequest::WriteToFlash
+                        for config_num in (0..NUM_CONFIGS).filter(|&i| imported[i]) {
+                            mark_written(config_num).await;
+                        }
+                        ImportStatus::Ok
+                    }
+                    Err(status) => {
+                        error!("Rejected backup with status {}", status);
+                        status
+                    }
+                };
+                writer.write(&[status as u8]).await?;
+                writer.flush().await?;
+            }
+            HidRequest::CompactStorage => {
+                info!("Compacting storage");
+                write_diagnostic(writer, &compact_storage().await).await?;
+            }
+            HidRequest::ReactionTest => {
+                start_reaction_test();
+                loop {
+                    let event = next_reaction_event().await;
+                    writer.write(&event.to_bytes()).await?;
+                    writer.flush().await?;
+                    if event.status != ReactionStatus::Cue {
+                        info!("Reaction test ended with {}", event);
+                        break;
+                    }
+                }
+            }
+            HidRequest::Bootloader => request_bootloader(),
+            HidRequest::KeymapChecksum => {
+                let config_num = reader.pop().await? as usize;
+                check_config(config_num)?;
+                let lock = self.lock().await;
+                let crc = if lock.config_num == config_num {
+                    lock.checksum()?
+                } else {
+                    drop(lock);
+                    let mut keys = Keys::<I>::default();
+                    keys.load_keys_from_storage(config_num).await?;
+                    keys.checksum()?
+                };
+                info!("Config {} has checksum {:x}", config_num, crc);
+                writer.write(&crc.to_le_bytes()).await?;
+                writer.flush().await?;
+            }
+            HidRequest::KeyStats => {
+                let command = reader.pop().await?;
+                match KeyStatsCommand::try_from(command) {
+                    Ok(KeyStatsCommand::Disable) => set_key_stats_enabled(false).await,
+                    Ok(KeyStatsCommand::Enable) => set_key_stats_enabled(true).await,
+                    Ok(KeyStatsCommand::Read) => {
+                        let mut buf = [0u8; KEY_STATS_SERIAL_LENGTH];
+                        key_stats().serialize_into(&mut buf)?;
+                        writer.write(&buf).await?;
+                        writer.flush().await?;
+                    }
+                    Ok(KeyStatsCommand::Clear) => clear_key_stats().await,
+                    Err(_) => error!("Received invalid key stats command {}", command),
+                }
+            }
+            HidRequest::SelfTest => {
+                let command = reader.pop().await?;
+                let report = match SelfTestCommand::try_from(command) {
+                    Ok(SelfTestCommand::Start) => {
+                        start_self_test();
+                        return Ok(());
+                    }
+                    Ok(SelfTestCommand::Read) => self_test_report(),
+                    Ok(SelfTestCommand::Stop) => stop_self_test(),
+                    Err(_) => {
+                        error!("Received invalid self test command {}", command);
+                        return Ok(());
+                    }
+                };
+                writer.write(&report.to_bytes()).await?;
+                writer.flush().await?;
+            }
+            HidRequest::FactoryReset => {
+                let mut confirm = [0u8; FACTORY_RESET_CONFIRM.len()];
+                for byte in confirm.iter_mut() {
+                    *byte = reader.pop().await?;
+                }
+                if confirm == FACTORY_RESET_CONFIRM {
+                    request_factory_reset();
+                } else {
+                    error!("Factory reset wasn't confirmed");
+                }
+            }
         }
+        Ok(())
+    }
+}
+
+// Per config storage keys are offsets from each other, so a config past
+// NUM_CONFIGS from the host would reach the keys of other items
+fn check_config(config_num: usize) -> Result<(), KeyLibError> {
+    if config_num < NUM_CONFIGS {
+        Ok(())
+    } else {
+        error!("Config {} doesn't exist", config_num);
+        Err(KeyLibError::InvalidConfig)
     }
 }
+
+/// Stores a setting from the host and applies it to the running keys if it
+/// belongs to the current config
+async fn store_setting<M: RawMutex, I: ConfigIndicator>(
+    keys: &Mutex<M, Keys<I>>,
+    config_num: usize,
+    key: StorageKey,
+    item: StorageItem,
+) {
+    match item {
+        StorageItem::BatteryThresholds(thresholds) => {
+            store_battery_thresholds(thresholds).await;
+            return;
+        }
+        StorageItem::Lighting(settings) => {
+            store_lighting(settings).await;
+            return;
+        }
+        StorageItem::HostOs(os) => {
+            store_os_override(os).await;
+            return;
+        }
+        StorageItem::LinkTiming(timing) => {
+            store_link_timing(timing).await;
+            return;
+        }
+        #[cfg(feature = "hall-effect")]
+        StorageItem::DriftCompensation(settings) => set_drift_compensation(settings),
+        #[cfg(not(feature = "hall-effect"))]
+        StorageItem::DriftCompensation(_) => {}
+        StorageItem::MouseSettings(settings) => {
+            let mut keys = keys.lock().await;
+            if keys.config_num == config_num {
+                keys.mouse_settings = settings;
+            }
+        }
+        StorageItem::LayerMouseSpeeds(speeds) => {
+            let mut keys = keys.lock().await;
+            if keys.config_num == config_num {
+                keys.layer_mouse_speeds = speeds;
+            }
+        }
+        StorageItem::Combos(combos) => {
+            let mut keys = keys.lock().await;
+            if keys.config_num == config_num {
+                keys.combos.set_combos(combos);
+            }
+        }
+        StorageItem::SocdPairs(pairs) => {
+            let mut keys = keys.lock().await;
+            if keys.config_num == config_num {
+                keys.socd = pairs;
+            }
+        }
+        StorageItem::SwitchProfiles(profiles) => {
+            let mut keys = keys.lock().await;
+            if keys.config_num == config_num {
+                keys.set_position_type_per_key(profiles);
+            }
+        }
+        StorageItem::LayerTimeouts(timeouts) => {
+            let mut keys = keys.lock().await;
+            if keys.config_num == config_num {
+                keys.layer_timeouts = timeouts;
+            }
+        }
+        StorageItem::StandaloneLayer(layer) => {
+            let mut keys = keys.lock().await;
+            if keys.config_num == config_num {
+                keys.standalone_layer = layer;
+            }
+        }
+        StorageItem::AutoShift(settings) => {
+            let mut keys = keys.lock().await;
+            if keys.config_num == config_num {
+                keys.auto_shift = settings;
+            }
+        }
+        StorageItem::DynamicMacro(ref recorded) => set_dynamic_macro(recorded.clone()),
+        StorageItem::ConfigInfo(ref info) => {
+            let mut keys = keys.lock().await;
+            if keys.config_num == config_num {
+                keys.set_config_color(info.color()).await;
+            }
+        }
+        // The polling interval is only read at boot
+        StorageItem::ReportRate(rate) => set_scan_interval(rate.scan_interval()),
+        // The side, usb identity and pairing key are only read at
+        // boot. Host pairings are read on every host switch
+        StorageItem::HostPairings(_)
+        | StorageItem::Side(_)
+        | StorageItem::UsbIdentity(_)
+        | StorageItem::PairingKey(_) => {}
+        StorageItem::Key(_) | StorageItem::Calibration(_) | StorageItem::KeyStats(_) => return,
+    }
+    store_val(key, &item).await;
+}
+
+/// Writes a frame every interval until a report is received
+async fn stream_frames<'d, T: Driver<'d>, const N: usize>(
+    reader: &mut ContinuousReader<'d, T>,
+    writer: &mut ContinuousWriter<'d, T>,
+    interval: Duration,
+    mut frame: impl FnMut() -> [u8; N],
+) -> Result<(), KeyLibError> {
+    let mut ticker = Ticker::every(interval);
+    loop {
+        match select(reader.pop(), ticker.next()).await {
+            Either::First(res) => {
+                reader.flush();
+                return res.map(|_| ());
+            }
+            Either::Second(_) => {
+                writer.write(&frame()).await?;
+                writer.flush().await?;
+            }
+        }
+    }
+}
+
+/// Writes a frame of readings every interval ms until a report is received
+#[cfg(feature = "hall-effect")]
+async fn stream_analog<'d, T: Driver<'d>>(
+    reader: &mut ContinuousReader<'d, T>,
+    writer: &mut ContinuousWriter<'d, T>,
+    interval: u8,
+) -> Result<(), KeyLibError> {
+    let interval = Duration::from_millis(interval.max(1) as u64);
+    stream_frames(reader, writer, interval, || {
+        let mut buf = [0u8; ANALOG_FRAME_LEN];
+        buf.chunks_exact_mut(2)
+            .zip(analog_readings().iter())
+            .for_each(|(bytes, reading)| bytes.copy_from_slice(&reading.to_le_bytes()));
+        buf
+    })
+    .await
+}
+
+/// Answers HidRequest::WatchLink on boards with a wireless link. Writes a
+/// frame of the states returned by halves every interval * 100ms until a
+/// report is received
+pub async fn watch_link<'d, T: Driver<'d>>(
+    reader: &mut ContinuousReader<'d, T>,
+    writer: &mut ContinuousWriter<'d, T>,
+    interval: u8,
+    mut halves: impl FnMut() -> [HalfLinkState; 2],
+) -> Result<(), KeyLibError> {
+    info!("Watching link every {}00ms", interval);
+    let interval = Duration::from_millis(interval.max(1) as u64 * 100);
+    let res = stream_frames(reader, writer, interval, || link_watch_frame(&halves())).await;
+    info!("Stopped watching link");
+    res
+}
+
 pub struct Com<'a, 'd, T: Driver<'d>, K: KeyboardState> {
     keys: &'a K,
     reader: ContinuousReader<'d, T>,
@@ -253,11 +1182,85 @@ impl<'a, 'd, K: KeyboardState, T: Driver<'d>> Com<'a, 'd, T, K> {
     pub async fn com_loop(&mut self) -> ! {
         self.reader.reader.ready().await;
         loop {
-            let hid_request = self.reader.pop().await.into();
-            self.keys
-                .handle_request(hid_request, &mut self.reader, &mut self.writer)
-                .await;
+            let res = match self.reader.pop().await {
+                Ok(FRAME_MAGIC) => self.handle_frame().await,
+                Ok(request) => match HidRequest::try_from(request) {
+                    Ok(request) => {
+                        self.keys
+                            .handle_request(request, &mut self.reader, &mut self.writer)
+                            .await
+                    }
+                    Err(_) => {
+                        error!("Received unknown request {}", request);
+                        Ok(())
+                    }
+                },
+                Err(err) => Err(err),
+            };
+            if let Err(err) = res {
+                record_error(err);
+                if err == KeyLibError::Com {
+                    record_endpoint_error(Endpoint::Com);
+                    // Endpoint is likely disabled so wait for the host to
+                    // reconnect. The cut request is dropped on both ends
+                    self.writer.reset();
+                    self.reader.reader.ready().await;
+                }
+            }
             self.reader.flush();
         }
     }
+
+    /// Handles a framed request after its magic. The status goes out before
+    /// the handler runs, so requests that stream responses aren't held back
+    async fn handle_frame(&mut self) -> Result<(), KeyLibError> {
+        let mut header = [0u8; FRAME_HEADER_LEN - 1];
+        self.reader.pop_slice(&mut header).await?;
+        let start = self.reader.popped;
+        let (request, len) = parse_frame_header(header);
+        let status = request.err().unwrap_or(FrameStatus::Ok);
+        self.writer.write(&status.report()).await?;
+        self.writer.flush().await?;
+        let res = match request {
+            Ok(request) => {
+                self.keys
+                    .handle_request(request, &mut self.reader, &mut self.writer)
+                    .await
+            }
+            Err(status) => {
+                error!("Rejected framed request {}: {}", header[1], status);
+                Ok(())
+            }
+        };
+        // The rest of the payload is dropped so the next frame starts on its
+        // magic
+        let read = self.reader.popped.wrapping_sub(start);
+        self.reader.skip(len.saturating_sub(read)).await?;
+        self.writer.flush().await?;
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frame_header_checks_version_and_request() {
+        let (request, len) = parse_frame_header([COM_PROTOCOL_VERSION, 3, 0x2C, 0x01]);
+        assert_eq!(request, Ok(HidRequest::KeyboardMetaInfo));
+        assert_eq!(len, 300);
+        let (request, _) = parse_frame_header([COM_PROTOCOL_VERSION + 1, 3, 0, 0]);
+        assert_eq!(request, Err(FrameStatus::UnsupportedVersion));
+        let (request, len) = parse_frame_header([COM_PROTOCOL_VERSION, 0xF0, 2, 0]);
+        assert_eq!(request, Err(FrameStatus::UnknownRequest));
+        // The payload of a rejected frame is still skipped
+        assert_eq!(len, 2);
+    }
+
+    #[test]
+    fn configs_past_num_configs_are_rejected() {
+        assert_eq!(check_config(NUM_CONFIGS - 1), Ok(()));
+        assert_eq!(check_config(NUM_CONFIGS), Err(KeyLibError::InvalidConfig));
+    }
 }
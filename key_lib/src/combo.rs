@@ -0,0 +1,255 @@
+use embassy_time::{Duration, Instant};
+use sequential_storage::map::{SerializationError, Value};
+
+use crate::{
+    NUM_KEYS,
+    keys::{PressedCodes, push_code},
+    scan_codes::KeyCodes,
+};
+
+pub const MAX_COMBOS: usize = 16;
+pub const MAX_COMBO_KEYS: usize = 4;
+
+const COMBO_SERIAL_LENGTH: usize = MAX_COMBO_KEYS + 2;
+pub const COMBOS_SERIAL_LENGTH: usize = 2 + COMBO_SERIAL_LENGTH * MAX_COMBOS;
+
+/// A set of key indexes that output a single code when pressed together
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Combo {
+    keys: [u8; MAX_COMBO_KEYS],
+    len: u8,
+    pub code: KeyCodes,
+}
+
+impl Combo {
+    pub const fn default() -> Self {
+        Self {
+            keys: [0; MAX_COMBO_KEYS],
+            len: 0,
+            code: KeyCodes::Undefined,
+        }
+    }
+
+    /// Returns a combo for the provided key indexes. Returns None if there
+    /// are less than 2 or more than MAX_COMBO_KEYS indexes
+    pub fn new(indexes: &[u8], code: KeyCodes) -> Option<Self> {
+        if indexes.len() < 2 || indexes.len() > MAX_COMBO_KEYS {
+            return None;
+        }
+        let mut keys = [0; MAX_COMBO_KEYS];
+        keys[..indexes.len()].copy_from_slice(indexes);
+        Some(Self {
+            keys,
+            len: indexes.len() as u8,
+            code,
+        })
+    }
+
+    pub fn keys(&self) -> &[u8] {
+        &self.keys[..self.len as usize]
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn contains(&self, index: usize) -> bool {
+        self.keys().iter().any(|key| *key as usize == index)
+    }
+}
+
+/// All the combos of a config along with the window the keys of a combo
+/// have to be pressed in
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Combos {
+    pub term_ms: u16,
+    pub combos: [Combo; MAX_COMBOS],
+}
+
+impl Combos {
+    pub const fn default() -> Self {
+        Self {
+            term_ms: 50,
+            combos: [Combo::default(); MAX_COMBOS],
+        }
+    }
+
+    fn term(&self) -> Duration {
+        Duration::from_millis(self.term_ms as u64)
+    }
+
+    pub fn into_buffer(&self, buffer: &mut [u8]) -> Result<(), SerializationError> {
+        if buffer.len() < COMBOS_SERIAL_LENGTH {
+            return Err(SerializationError::BufferTooSmall);
+        }
+        buffer[0..2].copy_from_slice(&self.term_ms.to_le_bytes());
+        for (combo, buf) in self
+            .combos
+            .iter()
+            .zip(buffer[2..COMBOS_SERIAL_LENGTH].chunks_exact_mut(COMBO_SERIAL_LENGTH))
+        {
+            buf[0] = combo.len;
+            buf[1] = combo.code as u8;
+            buf[2..].copy_from_slice(&combo.keys);
+        }
+        Ok(())
+    }
+}
+
+impl<'a> Value<'a> for Combos {
+    fn serialize_into(&self, buffer: &mut [u8]) -> Result<usize, SerializationError> {
+        self.into_buffer(buffer)?;
+        Ok(COMBOS_SERIAL_LENGTH)
+    }
+
+    fn deserialize_from(buffer: &'a [u8]) -> Result<(Self, usize), SerializationError>
+    where
+        Self: Sized,
+    {
+        if buffer.len() < COMBOS_SERIAL_LENGTH {
+            return Err(SerializationError::BufferTooSmall);
+        }
+        let mut combos = Combos::default();
+        combos.term_ms = u16::from_le_bytes([buffer[0], buffer[1]]);
+        for (combo, buf) in combos
+            .combos
+            .iter_mut()
+            .zip(buffer[2..COMBOS_SERIAL_LENGTH].chunks_exact(COMBO_SERIAL_LENGTH))
+        {
+            let len = buf[0];
+            // A len of 0 marks an unused combo slot
+            if len != 0 && !(2..=MAX_COMBO_KEYS as u8).contains(&len) {
+                return Err(SerializationError::InvalidFormat);
+            }
+            if buf[2..2 + len as usize]
+                .iter()
+                .any(|key| *key as usize >= NUM_KEYS)
+            {
+                return Err(SerializationError::InvalidFormat);
+            }
+            combo.len = len;
+            combo.code = buf[1].into();
+            combo.keys.copy_from_slice(&buf[2..]);
+        }
+        Ok((combos, COMBOS_SERIAL_LENGTH))
+    }
+}
+
+/// How a key should be handled after the combos are processed
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ComboFilter {
+    /// Key isn't used by a combo and should be handled normally
+    Normal,
+    /// Key is used by a combo or is waiting to see if a combo gets
+    /// completed and shouldn't be reported
+    Skip,
+    /// Key was released before the combo window ended without completing a
+    /// combo. The key should be reported as pressed for a single report
+    Tap,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct ComboEngine {
+    pub combos: Combos,
+    active: [bool; MAX_COMBOS],
+    pressed_at: [Option<Instant>; NUM_KEYS],
+    // Keys used by an active combo. Stays set until the key is released
+    suppressed: [bool; NUM_KEYS],
+    // Keys that were skipped while waiting for a combo to be completed
+    held: [bool; NUM_KEYS],
+}
+
+impl ComboEngine {
+    pub const fn default() -> Self {
+        Self {
+            combos: Combos::default(),
+            active: [false; MAX_COMBOS],
+            pressed_at: [None; NUM_KEYS],
+            suppressed: [false; NUM_KEYS],
+            held: [false; NUM_KEYS],
+        }
+    }
+
+    /// Replaces the combos and clears the current combo state
+    pub fn set_combos(&mut self, combos: Combos) {
+        *self = Self {
+            combos,
+            ..Self::default()
+        };
+    }
+
+    /// Pushes the codes of all active combos onto the provided vec and returns
+    /// how each key should be handled
+    pub fn update(
+        &mut self,
+        pressed: &[bool; NUM_KEYS],
+        set: &mut PressedCodes,
+    ) -> [ComboFilter; NUM_KEYS] {
+        let mut filters = [ComboFilter::Normal; NUM_KEYS];
+        if self.combos.combos.iter().all(|combo| combo.is_empty()) {
+            return filters;
+        }
+
+        let now = Instant::now();
+        let term = self.combos.term();
+        for (i, &pressed) in pressed.iter().enumerate() {
+            if pressed {
+                self.pressed_at[i].get_or_insert(now);
+            } else {
+                self.pressed_at[i] = None;
+                self.suppressed[i] = false;
+            }
+        }
+
+        for (combo, active) in self.combos.combos.iter().zip(self.active.iter_mut()) {
+            if combo.is_empty() {
+                *active = false;
+                continue;
+            }
+            let all_pressed = combo.keys().iter().all(|key| pressed[*key as usize]);
+            if *active {
+                *active = all_pressed;
+            } else if all_pressed {
+                let mut first = now;
+                let mut last = Instant::MIN;
+                let mut available = true;
+                for key in combo.keys() {
+                    let key = *key as usize;
+                    available &= !self.suppressed[key];
+                    if let Some(time) = self.pressed_at[key] {
+                        first = first.min(time);
+                        last = last.max(time);
+                    }
+                }
+                if available && last - first <= term {
+                    *active = true;
+                    combo
+                        .keys()
+                        .iter()
+                        .for_each(|key| self.suppressed[*key as usize] = true);
+                }
+            }
+            if *active {
+                push_code(set, combo.code.into());
+            }
+        }
+
+        for (i, filter) in filters.iter_mut().enumerate() {
+            let waiting = match self.pressed_at[i] {
+                Some(time) => {
+                    now - time < term && self.combos.combos.iter().any(|combo| combo.contains(i))
+                }
+                None => false,
+            };
+            *filter = if self.suppressed[i] || waiting {
+                ComboFilter::Skip
+            } else if self.held[i] && self.pressed_at[i].is_none() {
+                ComboFilter::Tap
+            } else {
+                ComboFilter::Normal
+            };
+            self.held[i] = waiting && !self.suppressed[i];
+        }
+        filters
+    }
+}
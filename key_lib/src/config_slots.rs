@@ -0,0 +1,302 @@
+//! Two slots for the layers of every config, so a keymap written with
+//! HidRequest::WriteToFlash that doesn't load doesn't replace the last one
+//! that did. New layers go to the slot not in use and the config is marked
+//! pending. The board loads the pending slot on the next boot and promotes it
+//! once it ran with it for [CONFIRM_AFTER], see [confirm_running].
+//!
+//! A pending slot that fails to load is dropped right away. One the board
+//! keeps crashing with is dropped once it was loaded [MAX_BOOT_ATTEMPTS]
+//! times without being promoted. Either way the board goes back to the
+//! previous slot
+
+use core::cell::Cell;
+
+use defmt::{Format, info, warn};
+use embassy_sync::blocking_mutex::{Mutex, raw::CriticalSectionRawMutex};
+use embassy_time::{Duration, Instant};
+use sequential_storage::map::{SerializationError, Value};
+
+use crate::{
+    NUM_CONFIGS,
+    storage::{StorageItem, StorageKey, get_item, store_val},
+};
+
+/// Loads of a pending slot before it's dropped
+pub const MAX_BOOT_ATTEMPTS: u8 = 3;
+
+/// Time the board has to run with a pending slot before it's promoted
+pub const CONFIRM_AFTER: Duration = Duration::from_secs(30);
+
+const SLOT_STATE_SERIAL_LENGTH: usize = 3;
+pub const CONFIG_SLOTS_SERIAL_LENGTH: usize = SLOT_STATE_SERIAL_LENGTH * NUM_CONFIGS;
+
+/// Config the board loaded from a pending slot and when
+static RUNNING_PENDING: Mutex<CriticalSectionRawMutex, Cell<Option<(usize, Instant)>>> =
+    Mutex::new(Cell::new(None));
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Format)]
+pub struct SlotState {
+    /// Slot of the last layers that loaded and ran
+    pub active: u8,
+    /// Set while the other slot holds layers that weren't promoted yet
+    pub pending: bool,
+    /// Loads of the pending slot so far
+    pub attempts: u8,
+}
+
+impl SlotState {
+    pub const fn default() -> Self {
+        Self {
+            active: 0,
+            pending: false,
+            attempts: 0,
+        }
+    }
+
+    /// Slot new layers are written to. Replaces the pending layers if there
+    /// are any
+    pub fn write_slot(&self) -> u8 {
+        1 - self.active
+    }
+
+    /// Slot holding the newest layers, pending or not
+    pub fn newest_slot(&self) -> u8 {
+        if self.pending {
+            self.write_slot()
+        } else {
+            self.active
+        }
+    }
+
+    /// Returns the slot the board should load and counts the attempt if
+    /// it's pending. Drops the pending slot once it used up its attempts
+    fn begin_load(&mut self) -> u8 {
+        if self.pending && self.attempts >= MAX_BOOT_ATTEMPTS {
+            self.roll_back();
+        }
+        if self.pending {
+            self.attempts += 1;
+        }
+        self.newest_slot()
+    }
+
+    fn mark_written(&mut self) {
+        self.pending = true;
+        self.attempts = 0;
+    }
+
+    fn promote(&mut self) {
+        if self.pending {
+            self.active = self.write_slot();
+            self.roll_back();
+        }
+    }
+
+    fn roll_back(&mut self) {
+        self.pending = false;
+        self.attempts = 0;
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Format)]
+pub struct ConfigSlots(pub [SlotState; NUM_CONFIGS]);
+
+impl ConfigSlots {
+    pub const fn default() -> Self {
+        Self([SlotState::default(); NUM_CONFIGS])
+    }
+}
+
+impl<'a> Value<'a> for ConfigSlots {
+    fn serialize_into(&self, buffer: &mut [u8]) -> Result<usize, SerializationError> {
+        if buffer.len() < CONFIG_SLOTS_SERIAL_LENGTH {
+            return Err(SerializationError::BufferTooSmall);
+        }
+        for (state, chunk) in self
+            .0
+            .iter()
+            .zip(buffer.chunks_exact_mut(SLOT_STATE_SERIAL_LENGTH))
+        {
+            chunk.copy_from_slice(&[state.active, state.pending as u8, state.attempts]);
+        }
+        Ok(CONFIG_SLOTS_SERIAL_LENGTH)
+    }
+
+    fn deserialize_from(buffer: &'a [u8]) -> Result<(Self, usize), SerializationError>
+    where
+        Self: Sized,
+    {
+        if buffer.len() < CONFIG_SLOTS_SERIAL_LENGTH {
+            return Err(SerializationError::BufferTooSmall);
+        }
+        let mut slots = Self::default();
+        for (state, chunk) in slots
+            .0
+            .iter_mut()
+            .zip(buffer.chunks_exact(SLOT_STATE_SERIAL_LENGTH))
+        {
+            if chunk[0] > 1 {
+                return Err(SerializationError::InvalidFormat);
+            }
+            *state = SlotState {
+                active: chunk[0],
+                pending: chunk[1] != 0,
+                attempts: chunk[2],
+            };
+        }
+        Ok((slots, CONFIG_SLOTS_SERIAL_LENGTH))
+    }
+}
+
+async fn load_slots() -> ConfigSlots {
+    match get_item(StorageKey::ConfigSlots).await {
+        Some(StorageItem::ConfigSlots(slots)) => slots,
+        _ => ConfigSlots::default(),
+    }
+}
+
+// Changes the state of the config's slots and stores it
+async fn update(config_num: usize, f: impl FnOnce(&mut SlotState)) -> SlotState {
+    let mut slots = load_slots().await;
+    f(&mut slots.0[config_num]);
+    store_val(StorageKey::ConfigSlots, &StorageItem::ConfigSlots(slots)).await;
+    slots.0[config_num]
+}
+
+/// Returns the state of the config's slots
+pub async fn slot_state(config_num: usize) -> SlotState {
+    load_slots().await.0[config_num]
+}
+
+// Stops the config from being promoted by [confirm_running]
+fn stop_running(config_num: usize) {
+    RUNNING_PENDING.lock(|running| {
+        if running
+            .get()
+            .is_some_and(|(config, _)| config == config_num)
+        {
+            running.set(None);
+        }
+    });
+}
+
+/// Marks the layers written to [SlotState::write_slot] as pending. They
+/// have to be booted with before they're promoted
+pub(crate) async fn mark_written(config_num: usize) {
+    info!("Config {} written, pending until it runs", config_num);
+    stop_running(config_num);
+    update(config_num, SlotState::mark_written).await;
+}
+
+/// Returns the slot the board should load the config from. Loading a pending
+/// slot counts as an attempt, and it's promoted by [confirm_running] once
+/// the board ran with it long enough
+pub(crate) async fn begin_load(config_num: usize) -> SlotState {
+    let state = slot_state(config_num).await;
+    if !state.pending {
+        return state;
+    }
+    let state = update(config_num, |state| {
+        state.begin_load();
+    })
+    .await;
+    if state.pending {
+        RUNNING_PENDING.lock(|running| running.set(Some((config_num, Instant::now()))));
+    } else {
+        warn!("Config {} kept failing to boot, rolling back", config_num);
+    }
+    state
+}
+
+/// Drops the pending slot of a config whose layers didn't load. Returns the
+/// state with the previous slot
+pub(crate) async fn roll_back(config_num: usize) -> SlotState {
+    warn!("Config {} failed to load, rolling back", config_num);
+    stop_running(config_num);
+    update(config_num, SlotState::roll_back).await
+}
+
+/// Promotes the pending slot the board is running with once it ran for
+/// [CONFIRM_AFTER]
+pub(crate) async fn confirm_running(now: Instant) {
+    let due = RUNNING_PENDING.lock(|running| match running.get() {
+        Some((config_num, since)) if now.saturating_duration_since(since) >= CONFIRM_AFTER => {
+            running.set(None);
+            Some(config_num)
+        }
+        _ => None,
+    });
+    if let Some(config_num) = due {
+        info!("Config {} ran, promoting it", config_num);
+        update(config_num, SlotState::promote).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn written_slot_is_promoted() {
+        let mut state = SlotState::default();
+        state.mark_written();
+        assert_eq!(state.begin_load(), 1);
+        state.promote();
+        assert_eq!(
+            state,
+            SlotState {
+                active: 1,
+                pending: false,
+                attempts: 0,
+            }
+        );
+        // The next write goes to the old slot
+        assert_eq!(state.write_slot(), 0);
+    }
+
+    #[test]
+    fn pending_slot_is_dropped_after_its_attempts() {
+        let mut state = SlotState::default();
+        state.mark_written();
+        for _ in 0..MAX_BOOT_ATTEMPTS {
+            assert_eq!(state.begin_load(), 1);
+        }
+        assert_eq!(state.begin_load(), 0);
+        assert!(!state.pending);
+        // Promoting without a pending slot keeps the active one
+        state.promote();
+        assert_eq!(state.active, 0);
+    }
+
+    #[test]
+    fn rewriting_a_pending_slot_restarts_its_attempts() {
+        let mut state = SlotState::default();
+        state.mark_written();
+        state.begin_load();
+        state.begin_load();
+        state.mark_written();
+        assert_eq!(state.attempts, 0);
+        assert_eq!(state.write_slot(), 1);
+    }
+
+    #[test]
+    fn slots_round_trip() {
+        let mut slots = ConfigSlots::default();
+        slots.0[NUM_CONFIGS - 1] = SlotState {
+            active: 1,
+            pending: true,
+            attempts: 2,
+        };
+        let mut buffer = [0u8; CONFIG_SLOTS_SERIAL_LENGTH];
+        assert_eq!(
+            slots.serialize_into(&mut buffer).unwrap(),
+            CONFIG_SLOTS_SERIAL_LENGTH
+        );
+        assert_eq!(ConfigSlots::deserialize_from(&buffer).unwrap().0, slots);
+        buffer[0] = 2;
+        assert_eq!(
+            ConfigSlots::deserialize_from(&buffer),
+            Err(SerializationError::InvalidFormat)
+        );
+    }
+}
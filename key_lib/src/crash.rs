@@ -0,0 +1,228 @@
+//! Record of the last panic or hard fault. Boards write it to a reserved
+//! flash page from their panic and HardFault handlers before resetting, and
+//! load it back on the next boot with [set_last_crash] so the host can read
+//! it as DiagnosticKind::Crash.
+//!
+//! A record is `[magic][kind][message len][0; 2][pc][uptime_ms][message][crc]`
+//! with the crc taken over everything before it, so an erased or half written
+//! page isn't mistaken for a crash
+
+use core::cell::Cell;
+use core::fmt::{self, Write};
+use core::panic::PanicInfo;
+
+use embassy_sync::blocking_mutex::{self, raw::CriticalSectionRawMutex};
+use embassy_time::Instant;
+use num_enum::TryFromPrimitive;
+
+use crate::dfu::Crc32;
+use crate::diagnostics::{Diagnostic, DiagnosticKind};
+
+/// Longest panic message kept in a record. Longer messages are cut short
+pub const MAX_CRASH_MESSAGE_LEN: usize = 64;
+
+/// Length of a record in flash. A multiple of 4 for flashes written in words
+pub const CRASH_RECORD_LEN: usize = 20 + MAX_CRASH_MESSAGE_LEN;
+
+/// Length of the payload of DiagnosticKind::Crash
+pub const CRASH_REPORT_SERIAL_LENGTH: usize = 10 + MAX_CRASH_MESSAGE_LEN;
+
+const CRASH_MAGIC: u32 = 0x4853_5243;
+
+static LAST_CRASH: blocking_mutex::Mutex<CriticalSectionRawMutex, Cell<CrashRecord>> =
+    blocking_mutex::Mutex::new(Cell::new(CrashRecord::none()));
+
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, TryFromPrimitive)]
+pub enum CrashKind {
+    /// The board didn't crash before its last boot
+    None = 0,
+    Panic = 1,
+    HardFault = 2,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct CrashRecord {
+    pub kind: CrashKind,
+    /// Program counter of a hard fault. 0 for panics, which have their
+    /// location in the message instead
+    pub pc: u32,
+    /// Time since boot when the board crashed
+    pub uptime_ms: u32,
+    message: [u8; MAX_CRASH_MESSAGE_LEN],
+    message_len: u8,
+}
+
+impl CrashRecord {
+    pub const fn none() -> Self {
+        Self {
+            kind: CrashKind::None,
+            pc: 0,
+            uptime_ms: 0,
+            message: [0; MAX_CRASH_MESSAGE_LEN],
+            message_len: 0,
+        }
+    }
+
+    fn new(kind: CrashKind, pc: u32) -> Self {
+        Self {
+            kind,
+            pc,
+            uptime_ms: Instant::now().as_millis().min(u32::MAX as u64) as u32,
+            ..Self::none()
+        }
+    }
+
+    /// Returns the record of the panic, with its location and as much of
+    /// its message as fits
+    pub fn panic(info: &PanicInfo) -> Self {
+        let mut record = Self::new(CrashKind::Panic, 0);
+        let mut writer = MessageWriter {
+            record: &mut record,
+        };
+        // The writer cuts the message short instead of failing
+        let _ = match info.location() {
+            Some(location) => write!(
+                writer,
+                "{}:{}: {}",
+                location.file(),
+                location.line(),
+                info.message()
+            ),
+            None => write!(writer, "{}", info.message()),
+        };
+        record
+    }
+
+    pub fn hard_fault(pc: u32) -> Self {
+        Self::new(CrashKind::HardFault, pc)
+    }
+
+    pub fn message(&self) -> &str {
+        // The writer only cuts the message at char boundaries
+        core::str::from_utf8(&self.message[..self.message_len as usize]).unwrap_or("")
+    }
+
+    pub fn to_bytes(&self) -> [u8; CRASH_RECORD_LEN] {
+        let mut buf = [0u8; CRASH_RECORD_LEN];
+        buf[0..4].copy_from_slice(&CRASH_MAGIC.to_le_bytes());
+        buf[4] = self.kind as u8;
+        buf[5] = self.message_len;
+        buf[8..12].copy_from_slice(&self.pc.to_le_bytes());
+        buf[12..16].copy_from_slice(&self.uptime_ms.to_le_bytes());
+        buf[16..16 + MAX_CRASH_MESSAGE_LEN].copy_from_slice(&self.message);
+        let crc_start = CRASH_RECORD_LEN - 4;
+        let mut crc = Crc32::default();
+        crc.update(&buf[..crc_start]);
+        buf[crc_start..].copy_from_slice(&crc.finish().to_le_bytes());
+        buf
+    }
+
+    /// Reads a record written by [CrashRecord::to_bytes]. Returns None if
+    /// buf doesn't hold a valid record, like an erased page
+    pub fn from_bytes(buf: &[u8]) -> Option<Self> {
+        let buf = buf.get(..CRASH_RECORD_LEN)?;
+        let read_u32 =
+            |at: usize| u32::from_le_bytes([buf[at], buf[at + 1], buf[at + 2], buf[at + 3]]);
+        let crc_start = CRASH_RECORD_LEN - 4;
+        let mut crc = Crc32::default();
+        crc.update(&buf[..crc_start]);
+        if read_u32(0) != CRASH_MAGIC || read_u32(crc_start) != crc.finish() {
+            return None;
+        }
+        let message_len = buf[5];
+        if message_len as usize > MAX_CRASH_MESSAGE_LEN {
+            return None;
+        }
+        let mut message = [0; MAX_CRASH_MESSAGE_LEN];
+        message.copy_from_slice(&buf[16..16 + MAX_CRASH_MESSAGE_LEN]);
+        Some(Self {
+            kind: CrashKind::try_from(buf[4]).ok()?,
+            pc: read_u32(8),
+            uptime_ms: read_u32(12),
+            message,
+            message_len,
+        })
+    }
+}
+
+impl Diagnostic for CrashRecord {
+    const KIND: DiagnosticKind = DiagnosticKind::Crash;
+    const LEN: usize = CRASH_REPORT_SERIAL_LENGTH;
+
+    fn write_payload(&self, buffer: &mut [u8]) {
+        buffer[0] = self.kind as u8;
+        buffer[1..5].copy_from_slice(&self.pc.to_le_bytes());
+        buffer[5..9].copy_from_slice(&self.uptime_ms.to_le_bytes());
+        buffer[9] = self.message_len;
+        buffer[10..10 + MAX_CRASH_MESSAGE_LEN].copy_from_slice(&self.message);
+    }
+}
+
+/// Appends to the message of a record, dropping whatever doesn't fit
+struct MessageWriter<'a> {
+    record: &'a mut CrashRecord,
+}
+
+impl Write for MessageWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let start = self.record.message_len as usize;
+        let mut len = s.len().min(MAX_CRASH_MESSAGE_LEN - start);
+        while !s.is_char_boundary(len) {
+            len -= 1;
+        }
+        self.record.message[start..start + len].copy_from_slice(&s.as_bytes()[..len]);
+        self.record.message_len += len as u8;
+        Ok(())
+    }
+}
+
+/// Sets the crash the board had before this boot. Called by boards on boot
+/// with the record read from their crash page
+pub fn set_last_crash(record: CrashRecord) {
+    LAST_CRASH.lock(|crash| crash.set(record));
+}
+
+/// Returns the crash the board had before this boot, with CrashKind::None if
+/// it didn't crash
+pub fn last_crash() -> CrashRecord {
+    LAST_CRASH.lock(|crash| crash.get())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn record_round_trips() {
+        let mut record = CrashRecord::hard_fault(0x2_6f3c);
+        MessageWriter {
+            record: &mut record,
+        }
+        .write_str("radio.rs:12: oops")
+        .unwrap();
+        let read = CrashRecord::from_bytes(&record.to_bytes()).unwrap();
+        assert_eq!(read.kind, CrashKind::HardFault);
+        assert_eq!(read.pc, 0x2_6f3c);
+        assert_eq!(read.message(), "radio.rs:12: oops");
+        // An erased page or a corrupted record isn't a crash
+        assert!(CrashRecord::from_bytes(&[0xff; CRASH_RECORD_LEN]).is_none());
+        let mut bytes = record.to_bytes();
+        bytes[20] ^= 1;
+        assert!(CrashRecord::from_bytes(&bytes).is_none());
+    }
+
+    #[test]
+    fn long_messages_are_cut_at_char_boundaries() {
+        let mut record = CrashRecord::none();
+        let mut writer = MessageWriter {
+            record: &mut record,
+        };
+        for _ in 0..MAX_CRASH_MESSAGE_LEN - 1 {
+            writer.write_str("a").unwrap();
+        }
+        writer.write_str("é and more").unwrap();
+        assert_eq!(record.message_len as usize, MAX_CRASH_MESSAGE_LEN - 1);
+        assert!(record.message().chars().all(|c| c == 'a'));
+    }
+}
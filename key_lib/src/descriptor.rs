@@ -1,6 +1,12 @@
 use usbd_hid::descriptor::gen_hid_descriptor;
 use usbd_hid::descriptor::{AsInputReport, generator_prelude::SerializedDescriptor};
 
+/// Length of a boot protocol keyboard report: modifiers, a reserved byte and
+/// six key codes
+pub const BOOT_REPORT_LEN: usize = 8;
+
+const ERROR_ROLL_OVER: u8 = 0x01;
+
 #[gen_hid_descriptor(
     (collection = APPLICATION, usage_page = GENERIC_DESKTOP, usage = KEYBOARD) = {
         (usage_page = KEYBOARD, usage_min = 0xE0, usage_max = 0xE7) = {
@@ -27,6 +33,9 @@ use usbd_hid::descriptor::{AsInputReport, generator_prelude::SerializedDescripto
         (usage_page = KEYBOARD, usage_min = 0xC0, usage_max = 0xDF) = {
             #[packed_bits = 32] #[item_settings(data,variable,absolute)] nkro_6=input;
         };
+        (usage_page = LEDS, usage_min = 0x01, usage_max = 0x05) = {
+            #[packed_bits = 5] #[item_settings(data,variable,absolute)] leds=output;
+        };
     }
 )]
 #[allow(dead_code)]
@@ -40,6 +49,8 @@ pub struct KeyboardReportNKRO {
     pub nkro_4: u32,
     pub nkro_5: u32,
     pub nkro_6: u32,
+    /// Lock key leds set by the host. See [crate::keys::HostLeds]
+    pub leds: u8,
 }
 
 impl KeyboardReportNKRO {
@@ -53,10 +64,72 @@ impl KeyboardReportNKRO {
             nkro_4: 0,
             nkro_5: 0,
             nkro_6: 0,
+            leds: 0,
+        }
+    }
+
+    /// Packs the report into a boot protocol report for links that can't carry
+    /// the nkro report, e.g. BLE notifications at the default MTU. More than
+    /// six pressed keys report ErrorRollOver in every slot
+    pub fn to_boot(&self) -> [u8; BOOT_REPORT_LEN] {
+        let mut report = [0u8; BOOT_REPORT_LEN];
+        report[0] = self.modifier;
+        let words = [
+            self.nkro_0,
+            self.nkro_1,
+            self.nkro_2,
+            self.nkro_3,
+            self.nkro_4,
+            self.nkro_5,
+            self.nkro_6,
+        ];
+        let mut slot = 2;
+        for (i, word) in words.into_iter().enumerate() {
+            let mut bits = word;
+            while bits != 0 {
+                if slot == BOOT_REPORT_LEN {
+                    report[2..].fill(ERROR_ROLL_OVER);
+                    return report;
+                }
+                report[slot] = (i as u32 * 32 + bits.trailing_zeros()) as u8;
+                slot += 1;
+                bits &= bits - 1;
+            }
         }
+        report
+    }
+}
+
+/// 6kro keyboard report of the boot interface. Laid out like the report the
+/// hid spec defines for boot keyboards, so hosts that ignore the descriptor
+/// still read it. Written with [KeyboardReportNKRO::to_boot], see
+/// [crate::boot_protocol]
+#[gen_hid_descriptor(
+    (collection = APPLICATION, usage_page = GENERIC_DESKTOP, usage = KEYBOARD) = {
+        (usage_page = KEYBOARD, usage_min = 0xE0, usage_max = 0xE7) = {
+            #[packed_bits = 8] #[item_settings(data,variable,absolute)] modifier=input;
+        };
+        (usage_min = 0x00, usage_max = 0xFF) = {
+            #[item_settings(constant,variable,absolute)] reserved=input;
+        };
+        (usage_page = LEDS, usage_min = 0x01, usage_max = 0x05) = {
+            #[packed_bits = 5] #[item_settings(data,variable,absolute)] leds=output;
+        };
+        (usage_page = KEYBOARD, usage_min = 0x00, usage_max = 0xDD) = {
+            #[item_settings(data,array,absolute)] keycodes=input;
+        };
     }
+)]
+#[allow(dead_code)]
+#[derive(Default)]
+pub struct KeyboardReportBoot {
+    pub modifier: u8,
+    pub reserved: u8,
+    pub leds: u8,
+    pub keycodes: [u8; 6],
 }
 
+#[cfg(feature = "mouse")]
 #[gen_hid_descriptor(
     (collection = APPLICATION, usage_page = GENERIC_DESKTOP, usage = MOUSE) = {
         (collection = PHYSICAL, usage = POINTER) = {
@@ -70,12 +143,22 @@ impl KeyboardReportNKRO {
                 (usage = Y,) = {
                     #[item_settings(data,variable,relative)] y=input;
                 };
-                (usage = WHEEL,) = {
+            };
+            // Each scroll axis shares a logical collection with its resolution
+            // multiplier so the host knows which axis the multiplier applies to
+            (collection = LOGICAL,) = {
+                (usage_page = GENERIC_DESKTOP, usage = 0x48, logical_min = 0, logical_max = 1, physical_min = 1, physical_max = 8) = {
+                    #[item_settings(data,variable,absolute)] wheel_multiplier=feature;
+                };
+                (usage_page = GENERIC_DESKTOP, usage = WHEEL, physical_min = 0, physical_max = 0) = {
                     #[item_settings(data,variable,relative)] wheel=input;
                 };
             };
-            (usage_page = CONSUMER,) = {
-                (usage = AC_PAN,) = {
+            (collection = LOGICAL,) = {
+                (usage_page = GENERIC_DESKTOP, usage = 0x48, logical_min = 0, logical_max = 1, physical_min = 1, physical_max = 8) = {
+                    #[item_settings(data,variable,absolute)] pan_multiplier=feature;
+                };
+                (usage_page = CONSUMER, usage = AC_PAN, physical_min = 0, physical_max = 0) = {
                     #[item_settings(data,variable,relative)] pan=input;
                 };
             };
@@ -90,6 +173,47 @@ pub struct MouseReport {
     pub y: i8,
     pub wheel: i8, // Scroll down (negative) or up (positive) this many units
     pub pan: i8,   // Scroll left (negative) or right (positive) this many units
+    /// Resolution multiplier of the wheel, see [crate::hires_scroll]. Only
+    /// set by the host with a feature report
+    pub wheel_multiplier: u8,
+    /// Resolution multiplier of horizontal scroll
+    pub pan_multiplier: u8,
+}
+
+/// Single finger touch pad that reports absolute positions. Coordinates go
+/// from 0 to [crate::pointer::POINTER_MAX]
+#[cfg(feature = "digitizer")]
+#[gen_hid_descriptor(
+    (collection = APPLICATION, usage_page = DIGITIZER, usage = 0x05) = {
+        (collection = LOGICAL, usage = 0x22) = {
+            (usage = 0x42, logical_max = 1) = {
+                #[item_settings(data,variable,absolute)] tip_switch=input;
+            };
+            (usage = 0x51,) = {
+                #[item_settings(data,variable,absolute)] contact_id=input;
+            };
+            (usage_page = GENERIC_DESKTOP, logical_max = 4095) = {
+                (usage = X,) = {
+                    #[item_settings(data,variable,absolute)] x=input;
+                };
+                (usage = Y,) = {
+                    #[item_settings(data,variable,absolute)] y=input;
+                };
+            };
+        };
+        (usage_page = DIGITIZER, usage = 0x54, logical_max = 1) = {
+            #[item_settings(data,variable,absolute)] contact_count=input;
+        };
+    }
+)]
+#[allow(dead_code)]
+#[derive(Default)]
+pub struct DigitizerReport {
+    pub tip_switch: u8,
+    pub contact_id: u8,
+    pub x: u16,
+    pub y: u16,
+    pub contact_count: u8,
 }
 
 #[gen_hid_descriptor(
@@ -119,3 +243,35 @@ pub struct SlaveReport {
     pub input: [u8; 32],
     pub output: [u8; 32],
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn boot_report_rolls_over_past_six_keys() {
+        let mut report = KeyboardReportNKRO::default();
+        report.modifier = 0x02;
+        report.nkro_0 = 1 << 0x04;
+        report.nkro_1 = 1 << (0x2C - 32);
+        assert_eq!(report.to_boot(), [0x02, 0, 0x04, 0x2C, 0, 0, 0, 0]);
+        report.nkro_0 = 0b111111 << 0x04;
+        assert_eq!(report.to_boot(), [0x02, 0, 1, 1, 1, 1, 1, 1]);
+    }
+
+    #[test]
+    fn boot_descriptor_matches_boot_report() {
+        let mut buf = [0u8; BOOT_REPORT_LEN + 1];
+        let report = KeyboardReportBoot {
+            modifier: 0x02,
+            keycodes: [0x04, 0x2C, 0, 0, 0, 0],
+            ..KeyboardReportBoot::default()
+        };
+        assert_eq!(report.serialize(&mut buf).ok(), Some(BOOT_REPORT_LEN));
+        let mut nkro = KeyboardReportNKRO::default();
+        nkro.modifier = 0x02;
+        nkro.nkro_0 = 1 << 0x04;
+        nkro.nkro_1 = 1 << (0x2C - 32);
+        assert_eq!(buf[..BOOT_REPORT_LEN], nkro.to_boot());
+    }
+}
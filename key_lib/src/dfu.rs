@@ -0,0 +1,171 @@
+use core::ops::Range;
+
+use defmt::{Format, info};
+use embedded_storage_async::nor_flash::NorFlash;
+
+/// Marks a staged image as complete and verified for the bootloader
+pub const DFU_MAGIC: u32 = 0x4446_5521;
+
+/// Size of the header at the start of the staging area. The header holds the
+/// magic, the image length and the image crc as le u32s followed by padding
+pub const DFU_HEADER_SIZE: u32 = 16;
+
+// Bytes buffered before writing to flash. Must be a multiple of the flash's write size
+const WRITE_BUFFER_SIZE: usize = 64;
+
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Format)]
+pub enum DfuError {
+    NotStarted = 1,
+    TooLarge = 2,
+    OutOfOrder = 3,
+    Flash = 4,
+    Incomplete = 5,
+    CrcMismatch = 6,
+}
+
+/// Crc32 (IEEE) used to verify firmware images
+#[derive(Clone, Copy)]
+pub struct Crc32 {
+    value: u32,
+}
+
+impl Crc32 {
+    pub const fn default() -> Self {
+        Self { value: 0xFFFF_FFFF }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        for byte in data {
+            self.value ^= *byte as u32;
+            for _ in 0..8 {
+                let mask = (self.value & 1).wrapping_neg();
+                self.value = (self.value >> 1) ^ (0xEDB8_8320 & mask);
+            }
+        }
+    }
+
+    pub const fn finish(&self) -> u32 {
+        !self.value
+    }
+}
+
+/// Writes a firmware image received in chunks to a staging area in flash.
+/// The header is only written once the whole image is received and its crc
+/// matches, so a bootloader never applies a partial image
+pub struct DfuWriter<F: NorFlash> {
+    flash: F,
+    range: Range<u32>,
+    started: bool,
+    len: u32,
+    expected_crc: u32,
+    received: u32,
+    crc: Crc32,
+    buffer: [u8; WRITE_BUFFER_SIZE],
+    buffer_len: usize,
+    flushed: u32,
+}
+
+impl<F: NorFlash> DfuWriter<F> {
+    /// Creates a writer for the staging range. The range must be aligned to
+    /// the flash's erase size
+    pub fn new(flash: F, range: Range<u32>) -> Self {
+        Self {
+            flash,
+            range,
+            started: false,
+            len: 0,
+            expected_crc: 0,
+            received: 0,
+            crc: Crc32::default(),
+            buffer: [0xFF; WRITE_BUFFER_SIZE],
+            buffer_len: 0,
+            flushed: 0,
+        }
+    }
+
+    /// Erases the staging area and prepares to receive an image of len bytes
+    pub async fn start(&mut self, len: u32, crc: u32) -> Result<(), DfuError> {
+        if len > self.range.len() as u32 - DFU_HEADER_SIZE {
+            return Err(DfuError::TooLarge);
+        }
+        self.flash
+            .erase(self.range.start, self.range.end)
+            .await
+            .map_err(|_| DfuError::Flash)?;
+        self.started = true;
+        self.len = len;
+        self.expected_crc = crc;
+        self.received = 0;
+        self.crc = Crc32::default();
+        self.buffer_len = 0;
+        self.flushed = 0;
+        info!("Started dfu for {} bytes", len);
+        Ok(())
+    }
+
+    /// Writes the next chunk of the image. Chunks must arrive in order
+    pub async fn write(&mut self, offset: u32, data: &[u8]) -> Result<(), DfuError> {
+        if !self.started {
+            return Err(DfuError::NotStarted);
+        }
+        if offset != self.received {
+            return Err(DfuError::OutOfOrder);
+        }
+        if self.received + data.len() as u32 > self.len {
+            return Err(DfuError::TooLarge);
+        }
+        self.crc.update(data);
+        self.received += data.len() as u32;
+        for byte in data {
+            self.buffer[self.buffer_len] = *byte;
+            self.buffer_len += 1;
+            if self.buffer_len == WRITE_BUFFER_SIZE {
+                self.flush().await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes out the remaining bytes and verifies the image. The header is
+    /// written if the image is complete and its crc matches
+    pub async fn finish(&mut self) -> Result<(), DfuError> {
+        if !self.started {
+            return Err(DfuError::NotStarted);
+        }
+        self.started = false;
+        if self.buffer_len != 0 {
+            // Pad the last write to the flash's write size
+            self.buffer_len = self.buffer_len.next_multiple_of(F::WRITE_SIZE);
+            self.flush().await?;
+        }
+        if self.received != self.len {
+            return Err(DfuError::Incomplete);
+        }
+        if self.crc.finish() != self.expected_crc {
+            return Err(DfuError::CrcMismatch);
+        }
+        let mut header = [0xFF; DFU_HEADER_SIZE as usize];
+        header[0..4].copy_from_slice(&DFU_MAGIC.to_le_bytes());
+        header[4..8].copy_from_slice(&self.len.to_le_bytes());
+        header[8..12].copy_from_slice(&self.expected_crc.to_le_bytes());
+        self.flash
+            .write(self.range.start, &header)
+            .await
+            .map_err(|_| DfuError::Flash)?;
+        info!("Staged firmware image of {} bytes", self.len);
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> Result<(), DfuError> {
+        let address = self.range.start + DFU_HEADER_SIZE + self.flushed;
+        self.flash
+            .write(address, &self.buffer[..self.buffer_len])
+            .await
+            .map_err(|_| DfuError::Flash)?;
+        self.flushed += self.buffer_len as u32;
+        self.buffer = [0xFF; WRITE_BUFFER_SIZE];
+        self.buffer_len = 0;
+        Ok(())
+    }
+}
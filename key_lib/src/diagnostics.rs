@@ -0,0 +1,257 @@
+//! Telemetry sent over com with HidRequest::Diagnostics. Every report is
+//! framed as `[DIAGNOSTICS_VERSION][kind][len][payload]` so the host can skip
+//! kinds it doesn't know. A len of 0 means the board can't produce the kind.
+//! All integers are little endian
+
+use core::cell::Cell;
+
+use embassy_sync::blocking_mutex::{self, raw::CriticalSectionRawMutex};
+use embassy_time::Instant;
+use embassy_usb::driver::Driver;
+use num_enum::TryFromPrimitive;
+use sequential_storage::map::SerializationError;
+
+use crate::com::ContinuousWriter;
+use crate::crash::CRASH_REPORT_SERIAL_LENGTH;
+use crate::error::{EndpointErrors, KeyLibError, endpoint_errors, last_error};
+use crate::latency::LATENCY_REPORT_SERIAL_LENGTH;
+
+/// Version of the payload layouts below. Bumped when a layout changes
+pub const DIAGNOSTICS_VERSION: u8 = 2;
+
+pub const DIAGNOSTIC_HEADER_LEN: usize = 3;
+
+/// Length of a serialized [LinkStats]
+pub const LINK_STATS_SERIAL_LENGTH: usize = 17;
+const LINK_REPORT_SERIAL_LENGTH: usize = 1 + 2 * LINK_STATS_SERIAL_LENGTH;
+const HEALTH_REPORT_SERIAL_LENGTH: usize = 17;
+const BOOT_REPORT_SERIAL_LENGTH: usize = 4;
+const STORAGE_STATS_SERIAL_LENGTH: usize = 13;
+
+/// Longest frame of any diagnostic
+pub const MAX_DIAGNOSTIC_LEN: usize = DIAGNOSTIC_HEADER_LEN
+    + max(
+        CRASH_REPORT_SERIAL_LENGTH,
+        max(LINK_REPORT_SERIAL_LENGTH, LATENCY_REPORT_SERIAL_LENGTH),
+    );
+
+static BOOT_REPORT: blocking_mutex::Mutex<CriticalSectionRawMutex, Cell<Option<BootReport>>> =
+    blocking_mutex::Mutex::new(Cell::new(None));
+
+const fn max(a: usize, b: usize) -> usize {
+    if a > b { a } else { b }
+}
+
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, TryFromPrimitive)]
+pub enum DiagnosticKind {
+    Link = 1,
+    Health = 2,
+    Boot = 3,
+    Storage = 4,
+    /// See [crate::latency]
+    Latency = 5,
+    /// See [crate::crash]
+    Crash = 6,
+}
+
+/// A payload that can be framed into a diagnostic report
+pub trait Diagnostic {
+    const KIND: DiagnosticKind;
+    const LEN: usize;
+
+    /// Writes the payload into buffer, which is at least LEN long
+    fn write_payload(&self, buffer: &mut [u8]);
+}
+
+/// Frames the diagnostic into buffer and returns the length of the frame
+pub fn encode<D: Diagnostic>(
+    diagnostic: &D,
+    buffer: &mut [u8],
+) -> Result<usize, SerializationError> {
+    let len = DIAGNOSTIC_HEADER_LEN + D::LEN;
+    if buffer.len() < len {
+        return Err(SerializationError::BufferTooSmall);
+    }
+    buffer[0] = DIAGNOSTICS_VERSION;
+    buffer[1] = D::KIND as u8;
+    buffer[2] = D::LEN as u8;
+    diagnostic.write_payload(&mut buffer[DIAGNOSTIC_HEADER_LEN..len]);
+    Ok(len)
+}
+
+/// Writes the framed diagnostic to com and flushes it
+pub async fn write_diagnostic<'d, T: Driver<'d>, D: Diagnostic>(
+    writer: &mut ContinuousWriter<'d, T>,
+    diagnostic: &D,
+) -> Result<(), KeyLibError> {
+    let mut buf = [0u8; MAX_DIAGNOSTIC_LEN];
+    let len = encode(diagnostic, &mut buf)?;
+    writer.write(&buf[..len]).await?;
+    writer.flush().await
+}
+
+/// Writes an empty frame for a kind the board can't produce
+pub async fn write_unsupported<'d, T: Driver<'d>>(
+    writer: &mut ContinuousWriter<'d, T>,
+    kind: u8,
+) -> Result<(), KeyLibError> {
+    writer.write(&[DIAGNOSTICS_VERSION, kind, 0]).await?;
+    writer.flush().await
+}
+
+/// Counters for the packets exchanged with one half over a wireless link
+#[derive(Debug, Clone, Copy, defmt::Format)]
+pub struct LinkStats {
+    pub packets_sent: u32,
+    pub packets_received: u32,
+    pub acks_missed: u32,
+    pub crc_errors: u32,
+    // Signal strength of the last packet received in dBm. 0 if nothing was received
+    pub rssi: i8,
+}
+
+impl LinkStats {
+    pub const fn default() -> Self {
+        Self {
+            packets_sent: 0,
+            packets_received: 0,
+            acks_missed: 0,
+            crc_errors: 0,
+            rssi: 0,
+        }
+    }
+
+    pub fn into_buffer(&self, buffer: &mut [u8]) {
+        buffer[0..4].copy_from_slice(&self.packets_sent.to_le_bytes());
+        buffer[4..8].copy_from_slice(&self.packets_received.to_le_bytes());
+        buffer[8..12].copy_from_slice(&self.acks_missed.to_le_bytes());
+        buffer[12..16].copy_from_slice(&self.crc_errors.to_le_bytes());
+        buffer[16] = self.rssi as u8;
+    }
+}
+
+/// Link stats of both halves. Boards without a wireless link send it with
+/// wireless set to false and zeroed stats
+#[derive(Debug, Clone, Copy)]
+pub struct LinkReport {
+    pub wireless: bool,
+    pub halves: [LinkStats; 2],
+}
+
+impl LinkReport {
+    pub const fn wired() -> Self {
+        Self {
+            wireless: false,
+            halves: [LinkStats::default(); 2],
+        }
+    }
+}
+
+impl Diagnostic for LinkReport {
+    const KIND: DiagnosticKind = DiagnosticKind::Link;
+    const LEN: usize = LINK_REPORT_SERIAL_LENGTH;
+
+    fn write_payload(&self, buffer: &mut [u8]) {
+        buffer[0] = self.wireless as u8;
+        for (stats, chunk) in self
+            .halves
+            .iter()
+            .zip(buffer[1..].chunks_exact_mut(LINK_STATS_SERIAL_LENGTH))
+        {
+            stats.into_buffer(chunk);
+        }
+    }
+}
+
+/// State of the running firmware
+#[derive(Debug, Clone, Copy)]
+pub struct HealthReport {
+    pub uptime_ms: u32,
+    pub last_error: Option<KeyLibError>,
+    pub endpoint_errors: EndpointErrors,
+}
+
+impl HealthReport {
+    /// Returns the health of the firmware without clearing the last error
+    pub fn current() -> Self {
+        Self {
+            uptime_ms: Instant::now().as_millis().min(u32::MAX as u64) as u32,
+            last_error: last_error(),
+            endpoint_errors: endpoint_errors(),
+        }
+    }
+}
+
+impl Diagnostic for HealthReport {
+    const KIND: DiagnosticKind = DiagnosticKind::Health;
+    const LEN: usize = HEALTH_REPORT_SERIAL_LENGTH;
+
+    fn write_payload(&self, buffer: &mut [u8]) {
+        buffer[0..4].copy_from_slice(&self.uptime_ms.to_le_bytes());
+        buffer[4] = self.last_error.map_or(0, |err| err as u8);
+        buffer[5..9].copy_from_slice(&self.endpoint_errors.com.to_le_bytes());
+        buffer[9..13].copy_from_slice(&self.endpoint_errors.report.to_le_bytes());
+        buffer[13..17].copy_from_slice(&self.endpoint_errors.link.to_le_bytes());
+    }
+}
+
+/// Why the board last started
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, TryFromPrimitive)]
+pub enum ResetReason {
+    Unknown = 0,
+    PowerOn = 1,
+    Pin = 2,
+    Watchdog = 3,
+    Software = 4,
+    Lockup = 5,
+}
+
+/// Version of the running firmware and why it booted
+#[derive(Debug, Clone, Copy)]
+pub struct BootReport {
+    pub version: [u8; 3],
+    pub reset_reason: ResetReason,
+}
+
+impl Diagnostic for BootReport {
+    const KIND: DiagnosticKind = DiagnosticKind::Boot;
+    const LEN: usize = BOOT_REPORT_SERIAL_LENGTH;
+
+    fn write_payload(&self, buffer: &mut [u8]) {
+        buffer[0..3].copy_from_slice(&self.version);
+        buffer[3] = self.reset_reason as u8;
+    }
+}
+
+/// Sets the boot report sent for DiagnosticKind::Boot. Called by boards on
+/// boot, boards that don't call it report the kind as unsupported
+pub fn set_boot_report(report: BootReport) {
+    BOOT_REPORT.lock(|boot| boot.set(Some(report)));
+}
+
+pub fn boot_report() -> Option<BootReport> {
+    BOOT_REPORT.lock(|boot| boot.get())
+}
+
+/// Usage of the flash range used for settings
+#[derive(Debug, Clone, Copy)]
+pub struct StorageStats {
+    pub used_bytes: u32,
+    pub capacity_bytes: u32,
+    pub erase_count: u32,
+    pub last_error: Option<KeyLibError>,
+}
+
+impl Diagnostic for StorageStats {
+    const KIND: DiagnosticKind = DiagnosticKind::Storage;
+    const LEN: usize = STORAGE_STATS_SERIAL_LENGTH;
+
+    fn write_payload(&self, buffer: &mut [u8]) {
+        buffer[0..4].copy_from_slice(&self.used_bytes.to_le_bytes());
+        buffer[4..8].copy_from_slice(&self.capacity_bytes.to_le_bytes());
+        buffer[8..12].copy_from_slice(&self.erase_count.to_le_bytes());
+        buffer[12] = self.last_error.map_or(0, |err| err as u8);
+    }
+}
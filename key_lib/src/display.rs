@@ -0,0 +1,478 @@
+//! Status displays, e.g. a small oled on a half. The firmware updates the
+//! [DisplayStatus] as the layer, config, battery or link changes and a board
+//! with a display runs [display_loop] next to its keys. The loop only draws
+//! when the status changed, at most every [MIN_FRAME_INTERVAL], and only
+//! sends the pages that differ from the last frame so the display bus doesn't
+//! hold up the key loop
+
+use core::{cell::Cell, fmt::Write};
+
+use defmt::Format;
+use embassy_sync::{
+    blocking_mutex::{self, raw::CriticalSectionRawMutex},
+    signal::Signal,
+};
+use embassy_time::{Duration, Timer};
+use embedded_hal_async::i2c::I2c;
+use heapless::String;
+
+use crate::error::{KeyLibError, record_error};
+
+/// Columns of a display
+pub const DISPLAY_WIDTH: usize = 128;
+
+/// Least time between drawn frames, so a burst of changes like layers flipping
+/// while typing is drawn once
+pub const MIN_FRAME_INTERVAL: Duration = Duration::from_millis(50);
+
+// Time before a display that couldn't be written is set up again
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// I2C address of most SSD1306 and SH1106 modules
+pub const OLED_ADDRESS: u8 = 0x3c;
+
+// Width of a glyph and the gap after it
+const GLYPH_WIDTH: usize = 5;
+const CHAR_WIDTH: usize = GLYPH_WIDTH + 1;
+
+/// State shown on the display. Updated by the firmware every time it changes
+static STATUS: blocking_mutex::Mutex<CriticalSectionRawMutex, Cell<DisplayStatus>> =
+    blocking_mutex::Mutex::new(Cell::new(DisplayStatus::default()));
+
+// Raised when the status changes so the display loop draws a new frame
+static STATUS_CHANGED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Link between a wireless half and the dongle
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Format)]
+pub enum LinkStatus {
+    /// The board doesn't have a wireless link
+    None,
+    Connected,
+    /// Packets aren't acknowledged by the other end
+    Lost,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Format)]
+pub struct DisplayStatus {
+    pub layer: u8,
+    pub config: u8,
+    /// Last battery reading of a battery powered board
+    pub battery_mv: Option<u16>,
+    /// Sending reports over usb
+    pub wired: bool,
+    pub link: LinkStatus,
+    /// The host is asleep, so the display is blanked
+    pub asleep: bool,
+}
+
+impl DisplayStatus {
+    pub const fn default() -> Self {
+        Self {
+            layer: 0,
+            config: 0,
+            battery_mv: None,
+            wired: false,
+            link: LinkStatus::None,
+            asleep: false,
+        }
+    }
+
+    /// Draws the status as a line of text per page. Lines that don't fit
+    /// the frame are left out. Nothing is drawn while the host is asleep
+    pub fn render<const PAGES: usize>(&self, frame: &mut Frame<PAGES>) {
+        if self.asleep {
+            frame.clear();
+            return;
+        }
+        let mut lines: [String<{ DISPLAY_WIDTH / CHAR_WIDTH }>; 4] = Default::default();
+        let mut count = 0;
+        let mut line = |args: core::fmt::Arguments| {
+            // Lines are cut at the display's width
+            let _ = lines[count].write_fmt(args);
+            count += 1;
+        };
+        line(format_args!("LAYER {}", self.layer));
+        line(format_args!("CONFIG {}", self.config));
+        if let Some(mv) = self.battery_mv {
+            line(format_args!("BAT {}.{:02}V", mv / 1000, mv % 1000 / 10));
+        }
+        if self.wired {
+            line(format_args!("USB"));
+        } else {
+            match self.link {
+                LinkStatus::None => {}
+                LinkStatus::Connected => line(format_args!("LINK OK")),
+                LinkStatus::Lost => line(format_args!("LINK LOST")),
+            }
+        }
+        frame.clear();
+        for (page, text) in lines.iter().take(count).enumerate().take(PAGES) {
+            frame.draw_text(page, 0, text);
+        }
+    }
+}
+
+/// Returns the status currently shown on the display
+pub fn status() -> DisplayStatus {
+    STATUS.lock(|cell| cell.get())
+}
+
+// Updates the status and wakes the display loop if it changed
+fn update_status(f: impl FnOnce(&mut DisplayStatus)) {
+    let changed = STATUS.lock(|cell| {
+        let mut status = cell.get();
+        f(&mut status);
+        let changed = status != cell.get();
+        cell.set(status);
+        changed
+    });
+    if changed {
+        STATUS_CHANGED.signal(());
+    }
+}
+
+/// Waits for the status to change and returns it. Boards without a display
+/// can follow the status with this, e.g. to relay it to a half with one. The
+/// change is only seen by one waiter, so it can't be used next to
+/// [display_loop]
+pub async fn wait_status_change() -> DisplayStatus {
+    STATUS_CHANGED.wait().await;
+    status()
+}
+
+pub fn set_layer(layer: usize) {
+    update_status(|status| status.layer = layer as u8);
+}
+
+pub fn set_config(config_num: usize) {
+    update_status(|status| status.config = config_num as u8);
+}
+
+pub fn set_battery(millivolts: u16) {
+    update_status(|status| status.battery_mv = Some(millivolts));
+}
+
+pub fn set_wired(wired: bool) {
+    update_status(|status| status.wired = wired);
+}
+
+pub fn set_link(link: LinkStatus) {
+    update_status(|status| status.link = link);
+}
+
+pub fn set_asleep(asleep: bool) {
+    update_status(|status| status.asleep = asleep);
+}
+
+/// Monochrome frame stored as pages of 8 rows. Each byte is a column of a
+/// page with the top row in the lowest bit, which is how SSD1306 and SH1106
+/// controllers take their data
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Frame<const PAGES: usize> {
+    pages: [[u8; DISPLAY_WIDTH]; PAGES],
+}
+
+impl<const PAGES: usize> Frame<PAGES> {
+    pub const fn new() -> Self {
+        Self {
+            pages: [[0; DISPLAY_WIDTH]; PAGES],
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.pages = [[0; DISPLAY_WIDTH]; PAGES];
+    }
+
+    pub fn page(&self, page: usize) -> &[u8; DISPLAY_WIDTH] {
+        &self.pages[page]
+    }
+
+    /// Draws text on the page starting at column x. Text past the right edge
+    /// is cut. Returns the column after the last drawn character
+    pub fn draw_text(&mut self, page: usize, x: usize, text: &str) -> usize {
+        let mut x = x;
+        for c in text.chars() {
+            if x + GLYPH_WIDTH > DISPLAY_WIDTH {
+                break;
+            }
+            self.pages[page][x..x + GLYPH_WIDTH].copy_from_slice(&glyph(c));
+            x += CHAR_WIDTH;
+        }
+        x
+    }
+
+    /// Returns true if the page has to be sent for the display to show this
+    /// frame instead of drawn. Every page is sent if nothing was drawn yet
+    pub fn is_page_dirty(&self, drawn: Option<&Self>, page: usize) -> bool {
+        drawn.is_none_or(|drawn| drawn.pages[page] != self.pages[page])
+    }
+}
+
+impl<const PAGES: usize> Default for Frame<PAGES> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A display the status is drawn on
+pub trait StatusDisplay {
+    /// Sets up the display. Called before the first frame and after a frame
+    /// couldn't be written
+    fn init(&mut self) -> impl Future<Output = Result<(), KeyLibError>>;
+
+    /// Writes a page of 8 rows
+    fn write_page(
+        &mut self,
+        page: usize,
+        data: &[u8; DISPLAY_WIDTH],
+    ) -> impl Future<Output = Result<(), KeyLibError>>;
+}
+
+// Sends the pages of the frame that differ from the drawn one. The display is
+// set up first if nothing was drawn
+async fn draw<D: StatusDisplay, const PAGES: usize>(
+    display: &mut D,
+    frame: &Frame<PAGES>,
+    drawn: Option<&Frame<PAGES>>,
+) -> Result<(), KeyLibError> {
+    if drawn.is_none() {
+        display.init().await?;
+    }
+    for page in 0..PAGES {
+        if frame.is_page_dirty(drawn, page) {
+            display.write_page(page, frame.page(page)).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Draws the status on the display every time it changes. PAGES is the
+/// number of pages the display has, e.g. 4 for a 128x32 oled
+pub async fn display_loop<D: StatusDisplay, const PAGES: usize>(display: &mut D) -> ! {
+    let mut drawn: Option<Frame<PAGES>> = None;
+    let mut frame = Frame::new();
+    loop {
+        status().render(&mut frame);
+        match draw(display, &frame, drawn.as_ref()).await {
+            Ok(()) => drawn = Some(frame),
+            Err(err) => {
+                // The whole display is set up and drawn again
+                record_error(err);
+                drawn = None;
+                Timer::after(RETRY_INTERVAL).await;
+                continue;
+            }
+        }
+        Timer::after(MIN_FRAME_INTERVAL).await;
+        STATUS_CHANGED.wait().await;
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Format)]
+pub enum OledController {
+    Ssd1306,
+    /// Has 132 columns of ram with the display in the middle and no horizontal
+    /// addressing mode
+    Sh1106,
+}
+
+/// SSD1306 or SH1106 oled connected over I2C
+pub struct OledDisplay<I: I2c> {
+    i2c: I,
+    address: u8,
+    controller: OledController,
+    pages: u8,
+}
+
+impl<I: I2c> OledDisplay<I> {
+    // Control bytes sent before a list of commands or display data
+    const COMMANDS: u8 = 0x00;
+    const DATA: u8 = 0x40;
+
+    /// Creates a display with pages of 8 rows, e.g. 4 for a 128x32 oled
+    pub fn new(i2c: I, controller: OledController, pages: u8) -> Self {
+        Self {
+            i2c,
+            address: OLED_ADDRESS,
+            controller,
+            pages,
+        }
+    }
+
+    /// Uses a different address for modules with the address pin pulled high
+    pub fn with_address(mut self, address: u8) -> Self {
+        self.address = address;
+        self
+    }
+
+    async fn commands(&mut self, commands: &[u8]) -> Result<(), KeyLibError> {
+        let mut buf = [Self::COMMANDS; 8];
+        buf[1..=commands.len()].copy_from_slice(commands);
+        self.i2c
+            .write(self.address, &buf[..=commands.len()])
+            .await
+            .map_err(|_| KeyLibError::Display)
+    }
+}
+
+impl<I: I2c> StatusDisplay for OledDisplay<I> {
+    async fn init(&mut self) -> Result<(), KeyLibError> {
+        let rows = self.pages * 8;
+        // Com pins are wired sequentially on 128x32 modules
+        let com_pins = if rows <= 32 { 0x02 } else { 0x12 };
+        // Display off, clock, multiplex ratio and display offset
+        self.commands(&[0xae, 0xd5, 0x80, 0xa8, rows - 1, 0xd3, 0x00])
+            .await?;
+        match self.controller {
+            // Charge pump on and page addressing mode
+            OledController::Ssd1306 => self.commands(&[0x8d, 0x14, 0x20, 0x02]).await?,
+            // Dc-dc converter on
+            OledController::Sh1106 => self.commands(&[0xad, 0x8b]).await?,
+        }
+        // Start line, flipped segments and com scan so the top left is
+        // column 0 of page 0, com pins and contrast
+        self.commands(&[0x40, 0xa1, 0xc8, 0xda, com_pins, 0x81, 0x8f])
+            .await?;
+        // Precharge, vcom level, follow ram, normal colors and display on
+        self.commands(&[0xd9, 0xf1, 0xdb, 0x40, 0xa4, 0xa6, 0xaf])
+            .await
+    }
+
+    async fn write_page(
+        &mut self,
+        page: usize,
+        data: &[u8; DISPLAY_WIDTH],
+    ) -> Result<(), KeyLibError> {
+        let column = match self.controller {
+            OledController::Ssd1306 => 0,
+            OledController::Sh1106 => 2,
+        };
+        self.commands(&[0xb0 | page as u8, column & 0x0f, 0x10 | (column >> 4)])
+            .await?;
+        let mut buf = [Self::DATA; DISPLAY_WIDTH + 1];
+        buf[1..].copy_from_slice(data);
+        self.i2c
+            .write(self.address, &buf)
+            .await
+            .map_err(|_| KeyLibError::Display)
+    }
+}
+
+/// Returns the columns of the character in a 5x7 font. Lowercase letters are
+/// drawn as uppercase and characters without a glyph as a question mark
+fn glyph(c: char) -> [u8; GLYPH_WIDTH] {
+    match c.to_ascii_uppercase() {
+        ' ' => [0x00, 0x00, 0x00, 0x00, 0x00],
+        '%' => [0x23, 0x13, 0x08, 0x64, 0x62],
+        '-' => [0x08, 0x08, 0x08, 0x08, 0x08],
+        '.' => [0x00, 0x60, 0x60, 0x00, 0x00],
+        '/' => [0x20, 0x10, 0x08, 0x04, 0x02],
+        ':' => [0x00, 0x36, 0x36, 0x00, 0x00],
+        '0' => [0x3e, 0x51, 0x49, 0x45, 0x3e],
+        '1' => [0x00, 0x42, 0x7f, 0x40, 0x00],
+        '2' => [0x42, 0x61, 0x51, 0x49, 0x46],
+        '3' => [0x21, 0x41, 0x45, 0x4b, 0x31],
+        '4' => [0x18, 0x14, 0x12, 0x7f, 0x10],
+        '5' => [0x27, 0x45, 0x45, 0x45, 0x39],
+        '6' => [0x3c, 0x4a, 0x49, 0x49, 0x30],
+        '7' => [0x01, 0x71, 0x09, 0x05, 0x03],
+        '8' => [0x36, 0x49, 0x49, 0x49, 0x36],
+        '9' => [0x06, 0x49, 0x49, 0x29, 0x1e],
+        'A' => [0x7e, 0x11, 0x11, 0x11, 0x7e],
+        'B' => [0x7f, 0x49, 0x49, 0x49, 0x36],
+        'C' => [0x3e, 0x41, 0x41, 0x41, 0x22],
+        'D' => [0x7f, 0x41, 0x41, 0x22, 0x1c],
+        'E' => [0x7f, 0x49, 0x49, 0x49, 0x41],
+        'F' => [0x7f, 0x09, 0x09, 0x09, 0x01],
+        'G' => [0x3e, 0x41, 0x49, 0x49, 0x7a],
+        'H' => [0x7f, 0x08, 0x08, 0x08, 0x7f],
+        'I' => [0x00, 0x41, 0x7f, 0x41, 0x00],
+        'J' => [0x20, 0x40, 0x41, 0x3f, 0x01],
+        'K' => [0x7f, 0x08, 0x14, 0x22, 0x41],
+        'L' => [0x7f, 0x40, 0x40, 0x40, 0x40],
+        'M' => [0x7f, 0x02, 0x0c, 0x02, 0x7f],
+        'N' => [0x7f, 0x04, 0x08, 0x10, 0x7f],
+        'O' => [0x3e, 0x41, 0x41, 0x41, 0x3e],
+        'P' => [0x7f, 0x09, 0x09, 0x09, 0x06],
+        'Q' => [0x3e, 0x41, 0x51, 0x21, 0x5e],
+        'R' => [0x7f, 0x09, 0x19, 0x29, 0x46],
+        'S' => [0x46, 0x49, 0x49, 0x49, 0x31],
+        'T' => [0x01, 0x01, 0x7f, 0x01, 0x01],
+        'U' => [0x3f, 0x40, 0x40, 0x40, 0x3f],
+        'V' => [0x1f, 0x20, 0x40, 0x20, 0x1f],
+        'W' => [0x3f, 0x40, 0x38, 0x40, 0x3f],
+        'X' => [0x63, 0x14, 0x08, 0x14, 0x63],
+        'Y' => [0x07, 0x08, 0x70, 0x08, 0x07],
+        'Z' => [0x61, 0x51, 0x49, 0x45, 0x43],
+        _ => [0x02, 0x01, 0x51, 0x09, 0x06],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn text_is_drawn_and_cut_at_the_edge() {
+        let mut frame = Frame::<1>::new();
+        assert_eq!(frame.draw_text(0, 0, "l1"), 2 * CHAR_WIDTH);
+        assert_eq!(frame.page(0)[..5], glyph('L'));
+        assert_eq!(frame.page(0)[6..11], glyph('1'));
+        assert_eq!(frame.page(0)[5], 0);
+
+        let end = frame.draw_text(0, DISPLAY_WIDTH - 8, "AB");
+        assert_eq!(end, DISPLAY_WIDTH - 2);
+        assert_eq!(frame.page(0)[DISPLAY_WIDTH - 2..], [0, 0]);
+    }
+
+    #[test]
+    fn render_skips_missing_lines() {
+        let status = DisplayStatus {
+            layer: 2,
+            battery_mv: Some(3856),
+            link: LinkStatus::Lost,
+            ..DisplayStatus::default()
+        };
+        let mut frame = Frame::<4>::new();
+        status.render(&mut frame);
+        let mut expected = Frame::<4>::new();
+        expected.draw_text(0, 0, "LAYER 2");
+        expected.draw_text(1, 0, "CONFIG 0");
+        expected.draw_text(2, 0, "BAT 3.85V");
+        expected.draw_text(3, 0, "LINK LOST");
+        assert_eq!(frame, expected);
+
+        let wired = DisplayStatus {
+            wired: true,
+            ..status
+        };
+        wired.render(&mut frame);
+        expected.clear();
+        expected.draw_text(0, 0, "LAYER 2");
+        expected.draw_text(1, 0, "CONFIG 0");
+        expected.draw_text(2, 0, "BAT 3.85V");
+        expected.draw_text(3, 0, "USB");
+        assert_eq!(frame, expected);
+
+        let asleep = DisplayStatus {
+            asleep: true,
+            ..status
+        };
+        asleep.render(&mut frame);
+        assert_eq!(frame, Frame::new());
+    }
+
+    #[test]
+    fn only_changed_pages_are_dirty() {
+        let mut drawn = Frame::<2>::new();
+        drawn.draw_text(0, 0, "LAYER 0");
+        drawn.draw_text(1, 0, "CONFIG 0");
+        let mut frame = drawn;
+        frame.clear();
+        frame.draw_text(0, 0, "LAYER 1");
+        frame.draw_text(1, 0, "CONFIG 0");
+        assert!(frame.is_page_dirty(Some(&drawn), 0));
+        assert!(!frame.is_page_dirty(Some(&drawn), 1));
+        assert!(frame.is_page_dirty(None, 1));
+    }
+}
@@ -0,0 +1,352 @@
+//! Macros recorded from the keyboard itself. A RecordMacro key starts a
+//! recording and every change to the keyboard report is recorded until it's
+//! pressed again. A PlayMacro key then sends the recorded changes again, one
+//! per report, with the keys pressed while it plays held back.
+//!
+//! The macro is kept in ram until the next recording. Record keys bound with
+//! save also write it to storage, and boards load it at boot with
+//! [load_dynamic_macro]
+
+use core::cell::RefCell;
+
+use defmt::{Format, info, warn};
+use embassy_sync::blocking_mutex::{Mutex, raw::CriticalSectionRawMutex};
+use heapless::Vec;
+use sequential_storage::map::{SerializationError, Value};
+
+use crate::{
+    descriptor::KeyboardReportNKRO,
+    storage::{StorageItem, StorageKey, get_item, schedule_save},
+};
+
+/// Most report changes a macro can hold
+pub const MAX_MACRO_EVENTS: usize = 48;
+pub const DYNAMIC_MACRO_SERIAL_LENGTH: usize = 1 + 2 * MAX_MACRO_EVENTS;
+
+// Usage of the first modifier in the keyboard report
+const MODIFIER_USAGE: u8 = 0xE0;
+
+static MACRO: Mutex<CriticalSectionRawMutex, RefCell<MacroState>> =
+    Mutex::new(RefCell::new(MacroState::new()));
+
+/// A key press or release in a recorded macro
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Format)]
+pub struct MacroEvent {
+    /// Usage of the key in the keyboard report, modifiers included
+    pub code: u8,
+    pub pressed: bool,
+}
+
+/// Report changes of a recorded macro in the order they happened
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct DynamicMacro {
+    pub events: Vec<MacroEvent, MAX_MACRO_EVENTS>,
+}
+
+impl<'a> Value<'a> for DynamicMacro {
+    fn serialize_into(&self, buffer: &mut [u8]) -> Result<usize, SerializationError> {
+        if buffer.len() < DYNAMIC_MACRO_SERIAL_LENGTH {
+            return Err(SerializationError::BufferTooSmall);
+        }
+        buffer[..DYNAMIC_MACRO_SERIAL_LENGTH].fill(0);
+        buffer[0] = self.events.len() as u8;
+        for (event, chunk) in self.events.iter().zip(buffer[1..].chunks_exact_mut(2)) {
+            chunk[0] = event.code;
+            chunk[1] = event.pressed as u8;
+        }
+        Ok(DYNAMIC_MACRO_SERIAL_LENGTH)
+    }
+
+    fn deserialize_from(buffer: &'a [u8]) -> Result<(Self, usize), SerializationError>
+    where
+        Self: Sized,
+    {
+        if buffer.len() < DYNAMIC_MACRO_SERIAL_LENGTH {
+            return Err(SerializationError::BufferTooSmall);
+        }
+        let len = buffer[0] as usize;
+        if len > MAX_MACRO_EVENTS {
+            return Err(SerializationError::InvalidFormat);
+        }
+        let mut recorded = Self::default();
+        for chunk in buffer[1..].chunks_exact(2).take(len) {
+            let pressed = match chunk[1] {
+                0 => false,
+                1 => true,
+                _ => return Err(SerializationError::InvalidFormat),
+            };
+            // The length was checked against the capacity
+            let _ = recorded.events.push(MacroEvent {
+                code: chunk[0],
+                pressed,
+            });
+        }
+        Ok((recorded, DYNAMIC_MACRO_SERIAL_LENGTH))
+    }
+}
+
+/// Report to send for a scan while a macro plays
+pub enum Playback {
+    /// No macro is playing
+    Idle,
+    /// Report with the next change of the macro applied
+    Report(KeyboardReportNKRO),
+    /// The macro ended, so the report of the held keys has to be sent again
+    Ended,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum Phase {
+    Idle,
+    Recording { save: bool },
+    Playing { next: usize },
+}
+
+struct MacroState {
+    phase: Phase,
+    recorded: DynamicMacro,
+    // Report sent so far while playing
+    report: KeyboardReportNKRO,
+}
+
+impl MacroState {
+    const fn new() -> Self {
+        Self {
+            phase: Phase::Idle,
+            recorded: DynamicMacro { events: Vec::new() },
+            report: KeyboardReportNKRO::default(),
+        }
+    }
+
+    /// Starts a recording, or stops the running one. Returns the macro if it
+    /// should be written to storage
+    fn toggle_recording(&mut self, save: bool) -> Option<DynamicMacro> {
+        match self.phase {
+            Phase::Recording { save } => {
+                self.phase = Phase::Idle;
+                info!("Recorded a macro of {} changes", self.recorded.events.len());
+                save.then(|| self.recorded.clone())
+            }
+            Phase::Idle => {
+                self.phase = Phase::Recording { save };
+                self.recorded.events.clear();
+                None
+            }
+            Phase::Playing { .. } => None,
+        }
+    }
+
+    fn play(&mut self) {
+        if self.phase == Phase::Idle && !self.recorded.events.is_empty() {
+            self.phase = Phase::Playing { next: 0 };
+            self.report = KeyboardReportNKRO::default();
+        }
+    }
+
+    fn record(&mut self, old: &KeyboardReportNKRO, new: &KeyboardReportNKRO) {
+        if !matches!(self.phase, Phase::Recording { .. }) {
+            return;
+        }
+        for event in changes(old, new) {
+            if self.recorded.events.push(event).is_err() {
+                warn!("Macro is full, the rest of the recording is dropped");
+                return;
+            }
+        }
+    }
+
+    fn next_report(&mut self) -> Playback {
+        let Phase::Playing { next } = self.phase else {
+            return Playback::Idle;
+        };
+        match self.recorded.events.get(next) {
+            Some(event) => {
+                set_code(&mut self.report, *event);
+                self.phase = Phase::Playing { next: next + 1 };
+                Playback::Report(self.report)
+            }
+            None => {
+                self.phase = Phase::Idle;
+                Playback::Ended
+            }
+        }
+    }
+}
+
+fn nkro(report: &KeyboardReportNKRO) -> [u32; 7] {
+    [
+        report.nkro_0,
+        report.nkro_1,
+        report.nkro_2,
+        report.nkro_3,
+        report.nkro_4,
+        report.nkro_5,
+        report.nkro_6,
+    ]
+}
+
+/// Returns the keys pressed or released between the two reports
+fn changes(old: &KeyboardReportNKRO, new: &KeyboardReportNKRO) -> impl Iterator<Item = MacroEvent> {
+    let (old_mods, new_mods) = (old.modifier, new.modifier);
+    let modifiers = (0..8u8)
+        .filter(move |bit| (old_mods ^ new_mods) & (1 << bit) != 0)
+        .map(move |bit| MacroEvent {
+            code: MODIFIER_USAGE + bit,
+            pressed: new_mods & (1 << bit) != 0,
+        });
+    let (old_keys, new_keys) = (nkro(old), nkro(new));
+    let keys = (0..7 * 32u8)
+        .filter(move |code| {
+            let (word, bit) = ((code / 32) as usize, code % 32);
+            (old_keys[word] ^ new_keys[word]) & (1 << bit) != 0
+        })
+        .map(move |code| MacroEvent {
+            code,
+            pressed: new_keys[(code / 32) as usize] & (1 << (code % 32)) != 0,
+        });
+    // Modifiers are pressed before and released after the keys they change
+    let pressed_mods = modifiers.clone().filter(|event| event.pressed);
+    let released_mods = modifiers.filter(|event| !event.pressed);
+    pressed_mods.chain(keys).chain(released_mods)
+}
+
+fn set_code(report: &mut KeyboardReportNKRO, event: MacroEvent) {
+    let set = |bits: u32, bit: u8| {
+        if event.pressed {
+            bits | 1 << bit
+        } else {
+            bits & !(1 << bit)
+        }
+    };
+    if event.code >= MODIFIER_USAGE {
+        let bit = (event.code - MODIFIER_USAGE) % 8;
+        report.modifier = set(report.modifier as u32, bit) as u8;
+        return;
+    }
+    // The report is packed, so the words are copied out and back
+    let mut keys = nkro(report);
+    let word = (event.code / 32) as usize;
+    keys[word] = set(keys[word], event.code % 32);
+    [
+        report.nkro_0,
+        report.nkro_1,
+        report.nkro_2,
+        report.nkro_3,
+        report.nkro_4,
+        report.nkro_5,
+        report.nkro_6,
+    ] = keys;
+}
+
+/// Starts recording a macro, or stops the running recording. The macro is
+/// written to storage when a recording started with save stops
+pub async fn toggle_recording(save: bool) {
+    let recorded = MACRO.lock(|state| state.borrow_mut().toggle_recording(save));
+    if let Some(recorded) = recorded {
+        schedule_save(
+            StorageKey::DynamicMacro,
+            StorageItem::DynamicMacro(recorded),
+        )
+        .await;
+    }
+}
+
+/// Plays the recorded macro from the next report. Does nothing while
+/// recording or if nothing was recorded
+pub fn play_macro() {
+    MACRO.lock(|state| state.borrow_mut().play());
+}
+
+/// Records the changes between the last sent keyboard report and the new one
+/// if a recording is running. Called whenever a keyboard report changes
+pub fn record(old: &KeyboardReportNKRO, new: &KeyboardReportNKRO) {
+    MACRO.lock(|state| state.borrow_mut().record(old, new));
+}
+
+/// Returns the report to send in place of the keys' report while a macro
+/// plays
+pub fn next_report() -> Playback {
+    MACRO.lock(|state| state.borrow_mut().next_report())
+}
+
+/// Replaces the recorded macro, e.g. after it was written over com
+pub fn set_dynamic_macro(recorded: DynamicMacro) {
+    MACRO.lock(|state| state.borrow_mut().recorded = recorded);
+}
+
+/// Loads the stored macro so it can be played after a restart
+pub async fn load_dynamic_macro() {
+    if let Some(StorageItem::DynamicMacro(recorded)) = get_item(StorageKey::DynamicMacro).await {
+        set_dynamic_macro(recorded);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report(modifier: u8, nkro_0: u32) -> KeyboardReportNKRO {
+        KeyboardReportNKRO {
+            modifier,
+            nkro_0,
+            ..KeyboardReportNKRO::default()
+        }
+    }
+
+    #[test]
+    fn recorded_changes_play_back_in_order() {
+        let mut state = MacroState::new();
+        let reports = [
+            report(0, 0),
+            report(0b10, 1 << 4),
+            report(0, 1 << 4),
+            report(0, 0),
+        ];
+        assert_eq!(state.toggle_recording(true), None);
+        for pair in reports.windows(2) {
+            state.record(&pair[0], &pair[1]);
+        }
+        let recorded = state.toggle_recording(false).unwrap();
+        assert_eq!(recorded.events.len(), 4);
+        // Shift is pressed before the key it changes
+        assert_eq!(recorded.events[0].code, MODIFIER_USAGE + 1);
+
+        state.play();
+        let mut played = Vec::<KeyboardReportNKRO, 8>::new();
+        while let Playback::Report(report) = state.next_report() {
+            played.push(report).unwrap();
+        }
+        assert_eq!(played.len(), 4);
+        assert_eq!(played[1], reports[1]);
+        assert_eq!(played[3], reports[3]);
+        assert!(matches!(state.next_report(), Playback::Idle));
+    }
+
+    #[test]
+    fn macro_round_trips() {
+        let mut recorded = DynamicMacro::default();
+        for code in [4, 0xE1, 4] {
+            recorded
+                .events
+                .push(MacroEvent {
+                    code,
+                    pressed: code != 4 || recorded.events.is_empty(),
+                })
+                .unwrap();
+        }
+        let mut buf = [0u8; DYNAMIC_MACRO_SERIAL_LENGTH];
+        assert_eq!(
+            recorded.serialize_into(&mut buf),
+            Ok(DYNAMIC_MACRO_SERIAL_LENGTH)
+        );
+        assert_eq!(
+            DynamicMacro::deserialize_from(&buf),
+            Ok((recorded, DYNAMIC_MACRO_SERIAL_LENGTH))
+        );
+        buf[0] = MAX_MACRO_EVENTS as u8 + 1;
+        assert_eq!(
+            DynamicMacro::deserialize_from(&buf),
+            Err(SerializationError::InvalidFormat)
+        );
+    }
+}
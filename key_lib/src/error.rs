@@ -0,0 +1,101 @@
+use core::cell::Cell;
+
+use defmt::{Format, error, warn};
+use embassy_sync::blocking_mutex::{Mutex, raw::CriticalSectionRawMutex};
+use sequential_storage::map::SerializationError;
+
+/// Most recent error recorded with [record_error]. Read over com with
+/// HidRequest::LastError
+static LAST_ERROR: Mutex<CriticalSectionRawMutex, Cell<Option<KeyLibError>>> =
+    Mutex::new(Cell::new(None));
+
+static ENDPOINT_ERRORS: Mutex<CriticalSectionRawMutex, Cell<EndpointErrors>> =
+    Mutex::new(Cell::new(EndpointErrors::new()));
+
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Format)]
+pub enum KeyLibError {
+    /// Reading or writing flash failed
+    Storage = 1,
+    /// Stored or received data couldn't be deserialized
+    Serialization = 2,
+    /// Reading or writing the com endpoint failed
+    Com = 3,
+    /// A key sensor couldn't be read
+    Sensor = 4,
+    /// Writing to a status display failed
+    Display = 5,
+    /// A config past NUM_CONFIGS was requested
+    InvalidConfig = 6,
+}
+
+impl From<SerializationError> for KeyLibError {
+    fn from(_: SerializationError) -> Self {
+        Self::Serialization
+    }
+}
+
+/// Logs the error and keeps it as the last error so it can be read over com
+pub fn record_error(err: KeyLibError) {
+    error!("{}", err);
+    LAST_ERROR.lock(|cell| cell.set(Some(err)));
+}
+
+/// Returns the last recorded error without clearing it
+pub fn last_error() -> Option<KeyLibError> {
+    LAST_ERROR.lock(|cell| cell.get())
+}
+
+/// Returns the last recorded error and clears it
+pub fn take_last_error() -> Option<KeyLibError> {
+    LAST_ERROR.lock(|cell| cell.take())
+}
+
+/// Usb endpoint a failed transfer was dropped on
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Format)]
+pub enum Endpoint {
+    Com,
+    /// Keyboard and mouse reports
+    Report,
+    /// Reports between the halves relayed by the host
+    Link,
+}
+
+/// Failed transfers the firmware recovered from since boot. Sent with the
+/// health diagnostic
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Format)]
+pub struct EndpointErrors {
+    pub com: u32,
+    pub report: u32,
+    pub link: u32,
+}
+
+impl EndpointErrors {
+    const fn new() -> Self {
+        Self {
+            com: 0,
+            report: 0,
+            link: 0,
+        }
+    }
+}
+
+/// Counts a failed transfer on the endpoint. The transfer is dropped and the
+/// caller carries on once the endpoint is ready again
+pub fn record_endpoint_error(endpoint: Endpoint) {
+    warn!("Transfer on the {} endpoint failed", endpoint);
+    ENDPOINT_ERRORS.lock(|cell| {
+        let mut errors = cell.get();
+        match endpoint {
+            Endpoint::Com => errors.com = errors.com.saturating_add(1),
+            Endpoint::Report => errors.report = errors.report.saturating_add(1),
+            Endpoint::Link => errors.link = errors.link.saturating_add(1),
+        }
+        cell.set(errors);
+    });
+}
+
+/// Returns the failed transfers counted so far
+pub fn endpoint_errors() -> EndpointErrors {
+    ENDPOINT_ERRORS.lock(|cell| cell.get())
+}
@@ -0,0 +1,121 @@
+//! Factory reset for recovering a board from a bad stored config. It's
+//! requested with HidRequest::FactoryReset, which has to carry
+//! [FACTORY_RESET_CONFIRM] so a stray report can't wipe the board, or by
+//! holding the board's reset chord from boot for [CHORD_HOLD], see
+//! [ResetChord].
+//!
+//! [wait_for_factory_reset] erases storage with every config, setting and
+//! calibration. Boards then restore their built-in keymap, show
+//! Indicate::FactoryReset on their leds and reboot so the other settings
+//! are loaded from the empty storage
+
+use defmt::{info, warn};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+use embassy_time::{Duration, Instant};
+
+use crate::storage::erase_storage;
+
+/// Bytes HidRequest::FactoryReset has to start with to reset the board
+pub const FACTORY_RESET_CONFIRM: [u8; 4] = *b"WIPE";
+
+/// Time the reset chord has to be held from boot
+pub const CHORD_HOLD: Duration = Duration::from_secs(5);
+
+static FACTORY_RESET: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Asks the board to erase its storage and reboot
+pub fn request_factory_reset() {
+    FACTORY_RESET.signal(());
+}
+
+/// Waits for a factory reset to be requested and erases storage
+pub async fn wait_for_factory_reset() {
+    FACTORY_RESET.wait().await;
+    warn!("Factory reset, erasing storage");
+    let stats = erase_storage().await;
+    info!("Storage erased, {} bytes used", stats.used_bytes);
+}
+
+/// Watches the first scans after boot for the keys of the reset chord. The
+/// chord only counts if it's held from the first scan until [CHORD_HOLD]
+/// passed, so it can't be hit while typing. Releasing any of its keys ends
+/// the check for this boot
+pub struct ResetChord<'a> {
+    keys: &'a [usize],
+    held_since: Option<Instant>,
+    done: bool,
+}
+
+impl<'a> ResetChord<'a> {
+    /// Boards without a reset chord pass no keys
+    pub const fn new(keys: &'a [usize]) -> Self {
+        Self {
+            keys,
+            held_since: None,
+            done: keys.is_empty(),
+        }
+    }
+
+    /// Updates the check with the keys pressed this scan. Returns true once
+    /// the chord was held long enough, after which the check is done
+    fn check(&mut self, pressed: impl Fn(usize) -> bool, now: Instant) -> bool {
+        if self.done {
+            return false;
+        }
+        if !self.keys.iter().all(|&key| pressed(key)) {
+            self.done = true;
+            return false;
+        }
+        let held_since = *self.held_since.get_or_insert(now);
+        if now.saturating_duration_since(held_since) >= CHORD_HOLD {
+            self.done = true;
+            return true;
+        }
+        false
+    }
+
+    /// Requests a factory reset once the chord was held from boot for
+    /// [CHORD_HOLD]
+    pub fn update(&mut self, pressed: impl Fn(usize) -> bool) {
+        if self.check(pressed, Instant::now()) {
+            info!("Reset chord held");
+            request_factory_reset();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHORD: [usize; 2] = [0, 3];
+
+    fn at(ms: u64) -> Instant {
+        Instant::from_millis(ms)
+    }
+
+    #[test]
+    fn chord_held_from_boot_resets() {
+        let mut chord = ResetChord::new(&CHORD);
+        assert!(!chord.check(|i| CHORD.contains(&i), at(100)));
+        assert!(!chord.check(|i| CHORD.contains(&i), at(100 + CHORD_HOLD.as_millis() - 1)));
+        assert!(chord.check(|i| CHORD.contains(&i), at(100 + CHORD_HOLD.as_millis())));
+        // Only requested once
+        assert!(!chord.check(|_| true, at(200 + CHORD_HOLD.as_millis())));
+    }
+
+    #[test]
+    fn chord_pressed_after_boot_is_ignored() {
+        let mut chord = ResetChord::new(&CHORD);
+        assert!(!chord.check(|i| i == 0, at(0)));
+        assert!(!chord.check(|i| CHORD.contains(&i), at(10)));
+        assert!(!chord.check(|i| CHORD.contains(&i), at(10 + CHORD_HOLD.as_millis())));
+    }
+
+    #[test]
+    fn boards_without_a_chord_never_reset() {
+        let mut chord = ResetChord::new(&[]);
+        assert!(!chord.check(|_| true, at(0)));
+        assert!(!chord.check(|_| true, at(CHORD_HOLD.as_millis())));
+    }
+}
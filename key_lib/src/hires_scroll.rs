@@ -0,0 +1,87 @@
+//! High resolution scrolling for hosts that support the hid resolution
+//! multiplier. [MouseReport](crate::descriptor::MouseReport) has a multiplier
+//! feature for the wheel and one for horizontal scroll. Hosts that support it
+//! set it with SET_REPORT, after which a unit of that axis is
+//! 1/[HIRES_SCROLL_MULTIPLIER] of a notch and scroll keys tick that many times
+//! as often, so pages scroll smoothly at the same speed. Hosts reset it to a
+//! whole notch per unit after every bus reset
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use defmt::info;
+
+/// Units per notch while high resolution scrolling is on. Needs to match the
+/// physical max of the multipliers in the mouse descriptor
+pub const HIRES_SCROLL_MULTIPLIER: u8 = 8;
+
+/// Length of the feature report with the multiplier of the wheel and of
+/// horizontal scroll
+pub const RESOLUTION_REPORT_LEN: usize = 2;
+
+static WHEEL_HIRES: AtomicBool = AtomicBool::new(false);
+static PAN_HIRES: AtomicBool = AtomicBool::new(false);
+
+/// Should be called from the mouse interface's request handler with the
+/// feature report the host sets. Each byte is 1 for high resolution and 0
+/// for a notch per unit. Returns false if the report is too short
+pub fn set_resolution_report(data: &[u8]) -> bool {
+    let [wheel, pan, ..] = *data else {
+        return false;
+    };
+    info!(
+        "Host set the scroll resolution, wheel {} pan {}",
+        wheel, pan
+    );
+    WHEEL_HIRES.store(wheel != 0, Ordering::Relaxed);
+    PAN_HIRES.store(pan != 0, Ordering::Relaxed);
+    true
+}
+
+/// Returns the feature report for a GET_REPORT of the mouse interface
+pub fn resolution_report() -> [u8; RESOLUTION_REPORT_LEN] {
+    [
+        WHEEL_HIRES.load(Ordering::Relaxed) as u8,
+        PAN_HIRES.load(Ordering::Relaxed) as u8,
+    ]
+}
+
+/// Should be called on a usb bus reset
+pub fn reset_resolution() {
+    WHEEL_HIRES.store(false, Ordering::Relaxed);
+    PAN_HIRES.store(false, Ordering::Relaxed);
+}
+
+fn multiplier(hires: &AtomicBool) -> u8 {
+    if hires.load(Ordering::Relaxed) {
+        HIRES_SCROLL_MULTIPLIER
+    } else {
+        1
+    }
+}
+
+/// Units the host divides a notch of the wheel into
+pub(crate) fn wheel_multiplier() -> u8 {
+    multiplier(&WHEEL_HIRES)
+}
+
+/// Units the host divides a notch of horizontal scroll into
+pub(crate) fn pan_multiplier() -> u8 {
+    multiplier(&PAN_HIRES)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn feature_report_sets_each_axis() {
+        assert!(!set_resolution_report(&[1]));
+        assert!(set_resolution_report(&[1, 0]));
+        assert_eq!(wheel_multiplier(), HIRES_SCROLL_MULTIPLIER);
+        assert_eq!(pan_multiplier(), 1);
+        assert_eq!(resolution_report(), [1, 0]);
+        reset_resolution();
+        assert_eq!(wheel_multiplier(), 1);
+        assert_eq!(resolution_report(), [0, 0]);
+    }
+}
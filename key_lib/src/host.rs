@@ -0,0 +1,21 @@
+//! Shims for building on the host. Firmware gets its defmt logger from
+//! defmt-rtt and panic-probe, so a host build needs a logger that drops the
+//! encoded frames and a panic handler that unwinds like a normal panic
+
+#[defmt::global_logger]
+struct DiscardLogger;
+
+unsafe impl defmt::Logger for DiscardLogger {
+    fn acquire() {}
+
+    unsafe fn flush() {}
+
+    unsafe fn release() {}
+
+    unsafe fn write(_bytes: &[u8]) {}
+}
+
+#[defmt::panic_handler]
+fn defmt_panic() -> ! {
+    panic!("defmt panic")
+}
@@ -0,0 +1,130 @@
+//! Switching a wireless keyboard between the dongles of several hosts.
+//! ScanCodeBehavior::SwitchHost requests a switch with [request_host_switch]
+//! and the board's radio link hands the halves over to the dongle at the
+//! address stored in [HostPairings]
+
+use defmt::Format;
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+use sequential_storage::map::{SerializationError, Value};
+
+use crate::storage::{StorageItem, StorageKey, get_item};
+
+pub const MAX_HOSTS: usize = 3;
+
+const HOST_ADDRESS_SERIAL_LENGTH: usize = 8;
+pub const HOST_PAIRINGS_SERIAL_LENGTH: usize = 1 + MAX_HOSTS * HOST_ADDRESS_SERIAL_LENGTH;
+
+static HOST_SWITCH: Signal<CriticalSectionRawMutex, u8> = Signal::new();
+
+/// Radio base addresses of a host's dongle. The dongle listens on the
+/// keyboard address and the halves listen on the dongle address
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Format)]
+pub struct HostAddress {
+    pub dongle: u32,
+    pub keyboard: u32,
+}
+
+impl HostAddress {
+    /// Address of a host that wasn't paired. Boards use their default
+    /// addresses for it, which is also where the halves start after a restart
+    pub const fn unpaired() -> Self {
+        Self {
+            dongle: 0,
+            keyboard: 0,
+        }
+    }
+
+    pub fn is_paired(&self) -> bool {
+        *self != Self::unpaired()
+    }
+}
+
+/// Addresses of the hosts a keyboard switches between. Written to every
+/// dongle with SettingId::HostPairings, with own set to the index of the
+/// dongle's own host
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Format)]
+pub struct HostPairings {
+    pub own: u8,
+    pub hosts: [HostAddress; MAX_HOSTS],
+}
+
+impl HostPairings {
+    pub const fn default() -> Self {
+        Self {
+            own: 0,
+            hosts: [HostAddress::unpaired(); MAX_HOSTS],
+        }
+    }
+
+    /// Returns the address of the host, or None if it's out of range
+    pub fn host(&self, host: usize) -> Option<HostAddress> {
+        self.hosts.get(host).copied()
+    }
+
+    /// Returns the address the dongle itself uses
+    pub fn own_address(&self) -> HostAddress {
+        self.hosts[self.own as usize]
+    }
+}
+
+impl<'a> Value<'a> for HostPairings {
+    fn serialize_into(&self, buffer: &mut [u8]) -> Result<usize, SerializationError> {
+        if buffer.len() < HOST_PAIRINGS_SERIAL_LENGTH {
+            return Err(SerializationError::BufferTooSmall);
+        }
+        buffer[0] = self.own;
+        for (host, chunk) in self
+            .hosts
+            .iter()
+            .zip(buffer[1..].chunks_exact_mut(HOST_ADDRESS_SERIAL_LENGTH))
+        {
+            chunk[..4].copy_from_slice(&host.dongle.to_le_bytes());
+            chunk[4..].copy_from_slice(&host.keyboard.to_le_bytes());
+        }
+        Ok(HOST_PAIRINGS_SERIAL_LENGTH)
+    }
+
+    fn deserialize_from(buffer: &'a [u8]) -> Result<(Self, usize), SerializationError>
+    where
+        Self: Sized,
+    {
+        if buffer.len() < HOST_PAIRINGS_SERIAL_LENGTH {
+            return Err(SerializationError::BufferTooSmall);
+        }
+        if buffer[0] as usize >= MAX_HOSTS {
+            return Err(SerializationError::InvalidFormat);
+        }
+        let mut pairings = Self {
+            own: buffer[0],
+            ..Self::default()
+        };
+        for (host, chunk) in pairings
+            .hosts
+            .iter_mut()
+            .zip(buffer[1..].chunks_exact(HOST_ADDRESS_SERIAL_LENGTH))
+        {
+            host.dongle = u32::from_le_bytes(chunk[..4].try_into().unwrap());
+            host.keyboard = u32::from_le_bytes(chunk[4..].try_into().unwrap());
+        }
+        Ok((pairings, HOST_PAIRINGS_SERIAL_LENGTH))
+    }
+}
+
+/// Returns the stored host pairings, or the default if none were written
+pub async fn load_host_pairings() -> HostPairings {
+    match get_item(StorageKey::HostPairings).await {
+        Some(StorageItem::HostPairings(pairings)) => pairings,
+        _ => HostPairings::default(),
+    }
+}
+
+/// Asks the radio link to switch to the host. Only the latest request is
+/// kept if several come in before the link handles them
+pub fn request_host_switch(host: u8) {
+    HOST_SWITCH.signal(host);
+}
+
+/// Waits for a key to request a host switch
+pub async fn wait_host_switch() -> u8 {
+    HOST_SWITCH.wait().await
+}
@@ -0,0 +1,175 @@
+//! Key events injected over com with HidRequest::InjectKey, so end to end
+//! tests can drive the whole firmware path on real hardware. Injection is
+//! disabled until the host enables it and is disabled again on restart.
+//!
+//! Injected keys are kept apart from the sensors and only merged in by
+//! [crate::keys::Keys::get_keys], so they act as digital presses. Lighting,
+//! calibration, analog streaming and behaviors that read travel only see the
+//! switches
+
+use core::cell::Cell;
+
+use defmt::Format;
+use embassy_sync::blocking_mutex::{Mutex, raw::CriticalSectionRawMutex};
+use embassy_time::{Duration, Instant};
+use num_enum::TryFromPrimitive;
+
+use crate::NUM_KEYS;
+
+/// Shortest time between two injected events. Faster events are dropped so a
+/// runaway test can't flood the host with reports
+pub const MIN_EVENT_INTERVAL: Duration = Duration::from_millis(2);
+
+const INJECTED_LEN: usize = NUM_KEYS.div_ceil(8);
+
+/// Command in the first byte of a HidRequest::InjectKey. Press and Release are
+/// followed by the index of the key
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Format, TryFromPrimitive)]
+pub enum InjectCommand {
+    /// Releases every injected key and drops later events
+    Disable = 0,
+    Enable = 1,
+    Press = 2,
+    Release = 3,
+}
+
+/// Status byte sent in response to a HidRequest::InjectKey
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Format)]
+pub enum InjectStatus {
+    Ok = 0,
+    /// Injection wasn't enabled
+    Disabled = 1,
+    /// The event came within [MIN_EVENT_INTERVAL] of the last one
+    RateLimited = 2,
+    /// The command or key index was out of range
+    Invalid = 3,
+}
+
+/// Keys pressed by injected events
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct InjectedKeys([u8; INJECTED_LEN]);
+
+impl InjectedKeys {
+    pub const fn none() -> Self {
+        Self([0; INJECTED_LEN])
+    }
+
+    pub fn contains(&self, key: usize) -> bool {
+        self.0[key / 8] & (1 << (key % 8)) != 0
+    }
+
+    fn set(&mut self, key: usize, pressed: bool) {
+        if pressed {
+            self.0[key / 8] |= 1 << (key % 8);
+        } else {
+            self.0[key / 8] &= !(1 << (key % 8));
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct InjectState {
+    enabled: bool,
+    keys: InjectedKeys,
+    last_event: Instant,
+}
+
+impl InjectState {
+    const fn new() -> Self {
+        Self {
+            enabled: false,
+            keys: InjectedKeys::none(),
+            last_event: Instant::MIN,
+        }
+    }
+
+    fn apply(&mut self, command: InjectCommand, key: u8, now: Instant) -> InjectStatus {
+        match command {
+            InjectCommand::Disable => {
+                *self = Self::new();
+                InjectStatus::Ok
+            }
+            InjectCommand::Enable => {
+                self.enabled = true;
+                InjectStatus::Ok
+            }
+            InjectCommand::Press | InjectCommand::Release => {
+                let key = key as usize;
+                if !self.enabled {
+                    InjectStatus::Disabled
+                } else if key >= NUM_KEYS {
+                    InjectStatus::Invalid
+                } else if now < self.last_event + MIN_EVENT_INTERVAL {
+                    InjectStatus::RateLimited
+                } else {
+                    self.keys.set(key, command == InjectCommand::Press);
+                    self.last_event = now;
+                    InjectStatus::Ok
+                }
+            }
+        }
+    }
+}
+
+static INJECT: Mutex<CriticalSectionRawMutex, Cell<InjectState>> =
+    Mutex::new(Cell::new(InjectState::new()));
+
+/// Runs the command. key is ignored by Enable and Disable
+pub fn inject(command: InjectCommand, key: u8) -> InjectStatus {
+    INJECT.lock(|inject| {
+        let mut state = inject.get();
+        let status = state.apply(command, key, Instant::now());
+        inject.set(state);
+        status
+    })
+}
+
+/// Returns the keys currently pressed by injected events
+pub fn injected_keys() -> InjectedKeys {
+    INJECT.lock(|inject| inject.get().keys)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn events_need_injection_enabled() {
+        let mut state = InjectState::new();
+        let start = Instant::from_millis(1000);
+        assert_eq!(
+            state.apply(InjectCommand::Press, 0, start),
+            InjectStatus::Disabled
+        );
+        assert_eq!(
+            state.apply(InjectCommand::Enable, 0, start),
+            InjectStatus::Ok
+        );
+        assert_eq!(
+            state.apply(InjectCommand::Press, 0, start),
+            InjectStatus::Ok
+        );
+        assert!(state.keys.contains(0));
+        assert_eq!(
+            state.apply(InjectCommand::Release, 0, start + MIN_EVENT_INTERVAL / 2),
+            InjectStatus::RateLimited
+        );
+        assert!(state.keys.contains(0));
+        let later = start + MIN_EVENT_INTERVAL;
+        assert_eq!(
+            state.apply(InjectCommand::Press, NUM_KEYS as u8, later),
+            InjectStatus::Invalid
+        );
+        assert_eq!(
+            state.apply(InjectCommand::Disable, 0, later),
+            InjectStatus::Ok
+        );
+        assert_eq!(state.keys, InjectedKeys::none());
+        assert_eq!(
+            state.apply(InjectCommand::Press, 0, later),
+            InjectStatus::Disabled
+        );
+    }
+}
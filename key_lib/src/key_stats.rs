@@ -0,0 +1,272 @@
+//! Press counts of every key, so host tools can draw a heatmap of the layout
+//! and check how much each key is used. Counting is off until it's turned on
+//! with HidRequest::KeyStats, and Report::generate_report counts the keys
+//! that went down since the last scan.
+//!
+//! Counts are kept in ram and written to storage at most every
+//! [SAVE_INTERVAL] while they change, so typing doesn't wear the flash.
+//! Presses since the last write are lost when the board loses power. Boards
+//! load the stored counts at boot with [load_key_stats]
+
+use core::cell::RefCell;
+
+use defmt::{Format, info};
+use embassy_sync::blocking_mutex::{Mutex, raw::CriticalSectionRawMutex};
+use embassy_time::{Duration, Instant};
+use num_enum::TryFromPrimitive;
+use sequential_storage::map::{SerializationError, Value};
+
+use crate::{
+    NUM_KEYS,
+    position::KeyState,
+    storage::{StorageItem, StorageKey, get_item, schedule_save, store_val},
+};
+
+/// Least time between writes of the counts to storage
+pub const SAVE_INTERVAL: Duration = Duration::from_secs(30 * 60);
+
+pub const KEY_STATS_SERIAL_LENGTH: usize = 5 + 4 * NUM_KEYS;
+
+const PRESSED_LEN: usize = NUM_KEYS.div_ceil(8);
+
+static STATS: Mutex<CriticalSectionRawMutex, RefCell<StatsState>> =
+    Mutex::new(RefCell::new(StatsState::new()));
+
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Format, TryFromPrimitive)]
+pub enum KeyStatsCommand {
+    /// Stops counting. The counts are kept
+    Disable = 0,
+    Enable = 1,
+    /// Responds with the serialized [KeyStats]
+    Read = 2,
+    /// Resets the counts to 0
+    Clear = 3,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Format)]
+pub struct KeyStats {
+    pub enabled: bool,
+    /// Presses of every key
+    pub total: u32,
+    /// Presses of each key index
+    pub presses: [u32; NUM_KEYS],
+}
+
+impl KeyStats {
+    pub const fn default() -> Self {
+        Self {
+            enabled: false,
+            total: 0,
+            presses: [0; NUM_KEYS],
+        }
+    }
+}
+
+impl<'a> Value<'a> for KeyStats {
+    fn serialize_into(&self, buffer: &mut [u8]) -> Result<usize, SerializationError> {
+        if buffer.len() < KEY_STATS_SERIAL_LENGTH {
+            return Err(SerializationError::BufferTooSmall);
+        }
+        buffer[0] = self.enabled as u8;
+        buffer[1..5].copy_from_slice(&self.total.to_le_bytes());
+        for (count, chunk) in self
+            .presses
+            .iter()
+            .zip(buffer[5..KEY_STATS_SERIAL_LENGTH].chunks_exact_mut(4))
+        {
+            chunk.copy_from_slice(&count.to_le_bytes());
+        }
+        Ok(KEY_STATS_SERIAL_LENGTH)
+    }
+
+    fn deserialize_from(buffer: &'a [u8]) -> Result<(Self, usize), SerializationError>
+    where
+        Self: Sized,
+    {
+        if buffer.len() < KEY_STATS_SERIAL_LENGTH {
+            return Err(SerializationError::BufferTooSmall);
+        }
+        let mut presses = [0; NUM_KEYS];
+        for (count, chunk) in presses
+            .iter_mut()
+            .zip(buffer[5..KEY_STATS_SERIAL_LENGTH].chunks_exact(4))
+        {
+            *count = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+        }
+        Ok((
+            Self {
+                enabled: buffer[0] != 0,
+                total: u32::from_le_bytes([buffer[1], buffer[2], buffer[3], buffer[4]]),
+                presses,
+            },
+            KEY_STATS_SERIAL_LENGTH,
+        ))
+    }
+}
+
+struct StatsState {
+    stats: KeyStats,
+    // Keys pressed in the last scan. Kept while counting is off so keys held
+    // when it's turned on aren't counted
+    pressed: [u8; PRESSED_LEN],
+    // Counts changed since they were last stored
+    dirty: bool,
+    last_saved: Instant,
+}
+
+impl StatsState {
+    const fn new() -> Self {
+        Self {
+            stats: KeyStats::default(),
+            pressed: [0; PRESSED_LEN],
+            dirty: false,
+            last_saved: Instant::from_ticks(0),
+        }
+    }
+
+    /// Counts the keys that went down since the last call. Returns true if
+    /// the counts should be stored
+    fn record(&mut self, pressed: impl Fn(usize) -> bool, now: Instant) -> bool {
+        for i in 0..NUM_KEYS {
+            let (byte, bit) = (i / 8, 1 << (i % 8));
+            let was_pressed = self.pressed[byte] & bit != 0;
+            if pressed(i) {
+                self.pressed[byte] |= bit;
+                if !was_pressed && self.stats.enabled {
+                    self.stats.presses[i] = self.stats.presses[i].saturating_add(1);
+                    self.stats.total = self.stats.total.saturating_add(1);
+                    self.dirty = true;
+                }
+            } else {
+                self.pressed[byte] &= !bit;
+            }
+        }
+        if self.dirty && now.saturating_duration_since(self.last_saved) >= SAVE_INTERVAL {
+            self.dirty = false;
+            self.last_saved = now;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Counts the keys pressed since the last scan and schedules a save of the
+/// counts once [SAVE_INTERVAL] passed since the last one
+pub(crate) async fn record_presses<K: KeyState>(positions: &[K], now: Instant) {
+    let save = STATS.lock(|state| {
+        let mut state = state.borrow_mut();
+        state
+            .record(|i| positions.get(i).is_some_and(|p| p.is_pressed()), now)
+            .then_some(state.stats)
+    });
+    if let Some(stats) = save {
+        schedule_save(StorageKey::KeyStats, StorageItem::KeyStats(stats)).await;
+    }
+}
+
+/// Returns the counts so far
+pub fn key_stats() -> KeyStats {
+    STATS.lock(|state| state.borrow().stats)
+}
+
+// Applies a change from the host and stores the counts right away
+async fn update_stored(f: impl FnOnce(&mut KeyStats)) {
+    let stats = STATS.lock(|state| {
+        let mut state = state.borrow_mut();
+        f(&mut state.stats);
+        state.dirty = false;
+        state.last_saved = Instant::now();
+        state.stats
+    });
+    store_val(StorageKey::KeyStats, &StorageItem::KeyStats(stats)).await;
+}
+
+/// Turns counting on or off and persists it with the counts
+pub async fn set_key_stats_enabled(enabled: bool) {
+    info!("Key stats enabled: {}", enabled);
+    update_stored(|stats| stats.enabled = enabled).await;
+}
+
+/// Resets every count to 0
+pub async fn clear_key_stats() {
+    info!("Clearing key stats");
+    update_stored(|stats| {
+        stats.total = 0;
+        stats.presses = [0; NUM_KEYS];
+    })
+    .await;
+}
+
+/// Loads the stored counts so counting carries on after a restart
+pub async fn load_key_stats() {
+    if let Some(StorageItem::KeyStats(stored)) = get_item(StorageKey::KeyStats).await {
+        STATS.lock(|state| state.borrow_mut().stats = stored);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn enabled() -> StatsState {
+        let mut state = StatsState::new();
+        state.stats.enabled = true;
+        state
+    }
+
+    #[test]
+    fn counts_presses_not_holds() {
+        let mut state = enabled();
+        let start = Instant::from_ticks(0);
+        assert!(!state.record(|i| i == 3, start));
+        assert!(!state.record(|i| i == 3, start));
+        assert!(!state.record(|_| false, start));
+        assert!(!state.record(|i| i == 3 || i == NUM_KEYS - 1, start));
+        assert_eq!(state.stats.presses[3], 2);
+        assert_eq!(state.stats.presses[NUM_KEYS - 1], 1);
+        assert_eq!(state.stats.total, 3);
+    }
+
+    #[test]
+    fn keys_held_while_disabled_are_not_counted() {
+        let mut state = StatsState::new();
+        let start = Instant::from_ticks(0);
+        state.record(|i| i == 0, start);
+        state.stats.enabled = true;
+        state.record(|i| i == 0, start);
+        assert_eq!(state.stats.total, 0);
+        assert!(!state.dirty);
+    }
+
+    #[test]
+    fn saves_are_rate_limited() {
+        let mut state = enabled();
+        let start = Instant::from_ticks(0) + SAVE_INTERVAL;
+        assert!(state.record(|i| i == 0, start));
+        state.record(|_| false, start);
+        assert!(!state.record(|i| i == 0, start + SAVE_INTERVAL / 2));
+        // Nothing to save without new presses
+        let mut idle = enabled();
+        assert!(!idle.record(|_| false, start + SAVE_INTERVAL));
+        assert!(state.record(|i| i == 0, start + SAVE_INTERVAL));
+    }
+
+    #[test]
+    fn stats_round_trip() {
+        let mut stats = KeyStats {
+            enabled: true,
+            total: 70_000,
+            ..KeyStats::default()
+        };
+        stats.presses[0] = 69_999;
+        stats.presses[NUM_KEYS - 1] = 1;
+        let mut buffer = [0u8; KEY_STATS_SERIAL_LENGTH];
+        assert_eq!(
+            stats.serialize_into(&mut buffer).unwrap(),
+            KEY_STATS_SERIAL_LENGTH
+        );
+        assert_eq!(KeyStats::deserialize_from(&buffer).unwrap().0, stats);
+    }
+}
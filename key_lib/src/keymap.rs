@@ -0,0 +1,75 @@
+//! The [keymap!](crate::keymap!) macro, which sets the codes of a keymap
+//! written as a list of layers.
+
+/// Sets the codes of the keys layer by layer. Each layer is a bracketed list
+/// of codes for keys 0 and up, and `_` leaves a key's code unchanged. Layers
+/// with more keys than `NUM_KEYS` or more layers than `NUM_LAYERS` fail to
+/// compile
+///
+/// ```ignore
+/// keymap!(keys,
+///     // Layer 0
+///     [Single(KeyboardQq), Single(KeyboardWw), _, Single(Layer1)],
+///     // Layer 1
+///     [Single(KeyboardTab), _, _, Single(Layer1)],
+/// );
+/// ```
+#[macro_export]
+macro_rules! keymap {
+    (@keys $keys:ident, $layer:expr, $index:expr;) => {
+        const _: () = assert!($index <= $crate::NUM_KEYS, "Layer has more keys than NUM_KEYS");
+    };
+    (@keys $keys:ident, $layer:expr, $index:expr; _ $(, $($rest:tt)*)?) => {
+        $crate::keymap!(@keys $keys, $layer, $index + 1; $($($rest)*)?);
+    };
+    (@keys $keys:ident, $layer:expr, $index:expr; $code:expr $(, $($rest:tt)*)?) => {
+        $keys.set_code($code, $index, $layer);
+        $crate::keymap!(@keys $keys, $layer, $index + 1; $($($rest)*)?);
+    };
+    (@layers $keys:ident, $layer:expr;) => {
+        const _: () = assert!($layer <= $crate::NUM_LAYERS, "Keymap has more layers than NUM_LAYERS");
+    };
+    (@layers $keys:ident, $layer:expr; [$($codes:tt)*] $(, $($rest:tt)*)?) => {
+        $crate::keymap!(@keys $keys, $layer, 0usize; $($codes)*);
+        $crate::keymap!(@layers $keys, $layer + 1; $($($rest)*)?);
+    };
+    ($keys:expr, $($layers:tt)*) => {{
+        let keys = &mut *$keys;
+        $crate::keymap!(@layers keys, 0usize; $($layers)*);
+    }};
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        codes::ScanCodeBehavior::{self, *},
+        keys::{ConfigIndicator, Indicate, Keys},
+        scan_codes::KeyCodes::*,
+    };
+
+    struct NoIndicator;
+
+    impl ConfigIndicator for NoIndicator {
+        async fn indicate_config(&self, _: Indicate) {}
+    }
+
+    #[test]
+    fn layers_set_codes_in_order() {
+        let mut keys = Keys::<NoIndicator>::default();
+        keys.set_code(Single(KeyboardEscape), 1, 1);
+        keymap!(
+            &mut keys,
+            [Single(KeyboardQq), Single(KeyboardWw), Single(Layer1)],
+            [Single(KeyboardTab), _, Single(Layer1),],
+        );
+        let base = keys.layer(0).codes;
+        assert_eq!(base[0], Single(KeyboardQq));
+        assert_eq!(base[2], Single(Layer1));
+        assert_eq!(base[3], ScanCodeBehavior::default());
+        let upper = keys.layer(1).codes;
+        assert_eq!(upper[0], Single(KeyboardTab));
+        // Skipped keys keep their code
+        assert_eq!(upper[1], Single(KeyboardEscape));
+        assert_eq!(upper[2], Single(Layer1));
+    }
+}
@@ -1,30 +1,137 @@
-use core::{mem, ops::Range};
+use core::{array, mem, ops::Range};
 
-use defmt::{error, info};
-use embassy_time::Timer;
+use defmt::{Format, error, info, warn};
+use embassy_time::{Duration, Instant, Timer};
 use embassy_usb::driver::Driver;
 use heapless::Vec;
 use sequential_storage::map::Value;
 
+#[cfg(feature = "hall-effect")]
+use crate::position::HeSwitch;
 use crate::{
-    NUM_KEYS, NUM_LAYERS,
+    NUM_CONFIGS, NUM_KEYS, NUM_LAYERS,
+    auto_shift::{AutoShift, AutoShiftOutput, is_shiftable},
+    bootloader::request_bootloader,
     codes::{HidScanCodeType, MAX_SERIAL_LENGTH, ScanCodeBehavior, ScanCodeLayerStorage},
     com::{ContinuousReader, ContinuousWriter},
+    combo::{ComboEngine, ComboFilter, Combos, MAX_COMBOS},
+    config_slots,
+    dfu::Crc32,
+    display,
+    dynamic_macro::{play_macro, toggle_recording},
+    error::{KeyLibError, record_error},
+    host_switch::request_host_switch,
+    inject::injected_keys,
+    os::{HostOs, host_os},
     position::{KeySensors, KeyState},
-    scan_codes::ReportCodes,
-    slave_com::{Slave, SlaveState},
-    storage::{StorageItem, StorageKey, get_item, store_val},
+    scan_codes::{KeyCodes, ReportCodes},
+    settings::{
+        AutoShiftSettings, LayerMouseSpeeds, LayerTimeouts, MouseSettings, StandaloneLayer,
+        SwitchProfiles,
+    },
+    slave_com::{Slave, SlaveState, peer_present},
+    socd::SocdPairs,
+    storage::{StorageItem, StorageKey, get_item, schedule_save, store_val},
+    tap_dance::{TapDance, TapDanceOutput},
 };
 
 pub enum Indicate {
-    Config(usize),
+    /// Sent when the config changes with the color set in its ConfigInfo, or
+    /// None to show the board's color for the config
+    Config(usize, Option<[u8; 3]>),
     Enable,
     Disable,
+    HostLeds(HostLeds),
+    /// Sent when a lock keyboard chord locks or unlocks the keyboard
+    Locked(bool),
+    /// Sent once a factory reset erased storage, before the board reboots.
+    /// See [crate::factory_reset]
+    FactoryReset,
+}
+
+/// Lock key leds set by the host in the keyboard's output report
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Format)]
+pub struct HostLeds(pub u8);
+
+impl HostLeds {
+    pub const NUM_LOCK: u8 = 1 << 0;
+    pub const CAPS_LOCK: u8 = 1 << 1;
+    pub const SCROLL_LOCK: u8 = 1 << 2;
+    pub const COMPOSE: u8 = 1 << 3;
+    pub const KANA: u8 = 1 << 4;
+
+    pub const fn is_on(&self, led: u8) -> bool {
+        self.0 & led == led
+    }
 }
 pub trait ConfigIndicator {
     fn indicate_config(&self, config_num: Indicate) -> impl Future<Output = ()>;
 }
 
+// Travel a dual stage key has to be released past a threshold before it
+// leaves the stage. Stops the key from flickering between stages
+const STAGE_HYSTERESIS: u8 = 5;
+
+/// Most codes a single scan can produce. A key sends at most three codes and
+/// each combo sends one
+pub const MAX_PRESSED_CODES: usize = NUM_KEYS * 3 + MAX_COMBOS;
+
+/// Codes pressed during a single scan
+pub type PressedCodes = Vec<ReportCodes, MAX_PRESSED_CODES>;
+
+/// Pushes a code onto the set. If the set is full the lowest priority code is
+/// dropped so a full set never loses a layer or modifier to a letter
+pub(crate) fn push_code(set: &mut PressedCodes, code: ReportCodes) {
+    let Err(code) = set.push(code) else {
+        return;
+    };
+    warn!("Too many codes pressed, dropping the lowest priority code");
+    if let Some(lowest) = set
+        .iter_mut()
+        .min_by_key(|code| code.priority())
+        .filter(|lowest| lowest.priority() < code.priority())
+    {
+        *lowest = code;
+    }
+}
+
+/// Replaces mod swap codes with the code matching the modifiers held in the set
+fn resolve_mod_swaps(set: &mut PressedCodes) {
+    let held = set.iter().fold(0u8, |held, code| match code {
+        ReportCodes::Modifier(bit) => held | 1 << (bit % 8),
+        _ => held,
+    });
+    for code in set.iter_mut() {
+        if let ReportCodes::ModSwap {
+            normal,
+            swapped,
+            mods,
+        } = *code
+        {
+            *code = if held & mods != 0 { swapped } else { normal }.into();
+        }
+    }
+}
+
+/// Returns the new stage of a dual stage key from its travel. Stage 0 is
+/// released, stage 1 is past the first threshold and stage 2 is past the second
+fn update_stage(stage: u8, travel: u8, first_travel: u8, second_travel: u8) -> u8 {
+    let threshold = |travel_point: u8, active: bool| {
+        if active {
+            travel_point.saturating_sub(STAGE_HYSTERESIS)
+        } else {
+            travel_point
+        }
+    };
+    if travel >= threshold(second_travel, stage >= 2) {
+        2
+    } else if travel >= threshold(first_travel, stage >= 1) {
+        1
+    } else {
+        0
+    }
+}
+
 enum PressResult {
     Pressed,
     Function,
@@ -37,6 +144,27 @@ pub struct Keys<I: ConfigIndicator> {
     indicator: Option<I>,
     pub current_layer: [Option<usize>; NUM_KEYS],
     pub config_num: usize,
+    pub mouse_settings: MouseSettings,
+    pub layer_mouse_speeds: LayerMouseSpeeds,
+    pub combos: ComboEngine,
+    pub socd: SocdPairs,
+    pub layer_timeouts: LayerTimeouts,
+    pub standalone_layer: StandaloneLayer,
+    pub auto_shift: AutoShiftSettings,
+    /// Indicator color from the config's ConfigInfo
+    pub config_color: Option<[u8; 3]>,
+    switch_profiles: SwitchProfiles,
+    // Set when the switch profiles changed and haven't been applied yet
+    profiles_changed: bool,
+    stages: [u8; NUM_KEYS],
+    tap_dances: [TapDance; NUM_KEYS],
+    // Code a velocity key picked when it actuated, kept until it's released
+    velocity_codes: [Option<KeyCodes>; NUM_KEYS],
+    auto_shifts: [AutoShift; NUM_KEYS],
+    // Set while a lock keyboard chord has locked the keyboard
+    locked: bool,
+    // Set while the lock chord is held so holding it only toggles once
+    lock_chord_held: bool,
 }
 
 impl<I: ConfigIndicator> Keys<I> {
@@ -47,6 +175,23 @@ impl<I: ConfigIndicator> Keys<I> {
             indicator: None,
             current_layer: [None; NUM_KEYS],
             config_num: 0,
+            mouse_settings: MouseSettings::default(),
+            layer_mouse_speeds: LayerMouseSpeeds::default(),
+            combos: ComboEngine::default(),
+            socd: SocdPairs::default(),
+            layer_timeouts: LayerTimeouts::default(),
+            standalone_layer: StandaloneLayer::default(),
+            auto_shift: AutoShiftSettings::default(),
+            config_color: None,
+            switch_profiles: SwitchProfiles::default(),
+            // Applied once so positions match after the keys are reset
+            profiles_changed: true,
+            stages: [0; NUM_KEYS],
+            tap_dances: [TapDance::default(); NUM_KEYS],
+            velocity_codes: [None; NUM_KEYS],
+            auto_shifts: [AutoShift::default(); NUM_KEYS],
+            locked: false,
+            lock_chord_held: false,
         }
     }
 
@@ -54,14 +199,56 @@ impl<I: ConfigIndicator> Keys<I> {
         self.indicator = Some(indicator);
     }
 
-    // pub fn set_position_type_ranged(&mut self, range: Range<usize>, switch_type: K) {
-    //     self.key_states[range].fill(switch_type);
-    // }
+    /// Sets the switch profile of each key. The profiles are applied to the
+    /// positions on the next apply_position_types call
+    pub fn set_position_type_per_key(&mut self, profiles: SwitchProfiles) {
+        self.switch_profiles = profiles;
+        self.profiles_changed = true;
+    }
+
+    pub fn position_types(&self) -> SwitchProfiles {
+        self.switch_profiles
+    }
+
+    /// Switches the positions to the profiles set with set_position_type_per_key.
+    /// Should be called from the scan loop before the positions are updated
+    #[cfg(feature = "hall-effect")]
+    pub fn apply_position_types(&mut self, positions: &mut [HeSwitch]) {
+        if !mem::take(&mut self.profiles_changed) {
+            return;
+        }
+        positions
+            .iter_mut()
+            .zip(self.switch_profiles.0.iter())
+            .for_each(|(position, profile)| position.set_profile(*profile));
+    }
 
     // pub fn get_pressed(&self, index: usize) -> bool {
     //     self.key_states[index].is_pressed()
     // }
 
+    /// Passes the lock key leds set by the host to the indicator
+    pub async fn set_host_leds(&self, leds: HostLeds) {
+        if let Some(indicator) = self.indicator.as_ref() {
+            indicator.indicate_config(Indicate::HostLeds(leds)).await;
+        }
+    }
+
+    /// Sets the indicator color of the current config and shows it
+    pub async fn set_config_color(&mut self, color: Option<[u8; 3]>) {
+        self.config_color = color;
+        if let Some(indicator) = self.indicator.as_ref() {
+            indicator
+                .indicate_config(Indicate::Config(self.config_num, color))
+                .await;
+        }
+    }
+
+    /// Returns true while a lock keyboard chord has the keyboard locked
+    pub fn is_locked(&self) -> bool {
+        self.locked
+    }
+
     pub fn set_code(&mut self, code: ScanCodeBehavior, index: usize, layer: usize) {
         self.codes[index][layer] = code;
     }
@@ -75,20 +262,44 @@ impl<I: ConfigIndicator> Keys<I> {
     //     sensors.setup(&mut self.key_states).await;
     // }
 
-    /// Pushes the resulting ScanResult onto the provided vec depending on the indexed key's
-    /// position. Returns true if a key was pushed into the provided index set
+    /// Pushes the resulting ScanResult onto the provided vec depending on if the indexed key
+    /// is pressed. Returns true if a key was pushed into the provided index set
     async fn get_pressed_code<K: KeyState>(
         &mut self,
         index: usize,
         layer: usize,
+        pressed: bool,
         states: &[K; NUM_KEYS],
-        set: &mut Vec<ReportCodes, 64>,
+        pressed_keys: &[bool; NUM_KEYS],
+        set: &mut PressedCodes,
     ) -> PressResult {
-        let pressed = states[index].is_pressed();
         match self.codes[index][layer] {
             ScanCodeBehavior::Single(code) => {
+                if let Some(timeout) = self
+                    .auto_shift
+                    .timeout(index)
+                    .filter(|_| is_shiftable(code))
+                {
+                    let output = self.auto_shifts[index].update(pressed, Instant::now(), timeout);
+                    match output {
+                        AutoShiftOutput::None => {}
+                        AutoShiftOutput::Tap => push_code(set, code.into()),
+                        AutoShiftOutput::Shifted => {
+                            push_code(set, KeyCodes::KeyboardLeftShift.into());
+                            push_code(set, code.into());
+                        }
+                    }
+                    // Keep the layer until the key is resolved so the code is
+                    // sent from the layer it was pressed on
+                    let resolving = self.auto_shifts[index].is_active();
+                    return if output != AutoShiftOutput::None || resolving {
+                        PressResult::Pressed
+                    } else {
+                        PressResult::None
+                    };
+                }
                 if pressed {
-                    set.push(code.into()).unwrap();
+                    push_code(set, code.into());
                     PressResult::Pressed
                 } else {
                     PressResult::None
@@ -96,8 +307,8 @@ impl<I: ConfigIndicator> Keys<I> {
             }
             ScanCodeBehavior::Double(code0, code1) => {
                 if pressed {
-                    set.push(code0.into()).unwrap();
-                    set.push(code1.into()).unwrap();
+                    push_code(set, code0.into());
+                    push_code(set, code1.into());
                     PressResult::Pressed
                 } else {
                     PressResult::None
@@ -105,9 +316,9 @@ impl<I: ConfigIndicator> Keys<I> {
             }
             ScanCodeBehavior::Triple(code0, code1, code2) => {
                 if pressed {
-                    set.push(code0.into()).unwrap();
-                    set.push(code1.into()).unwrap();
-                    set.push(code2.into()).unwrap();
+                    push_code(set, code0.into());
+                    push_code(set, code1.into());
+                    push_code(set, code2.into());
                     PressResult::Pressed
                 } else {
                     PressResult::None
@@ -119,12 +330,12 @@ impl<I: ConfigIndicator> Keys<I> {
                 combined_code: other_key_code,
             } => {
                 if pressed {
-                    set.push(ReportCodes::Sticky).unwrap();
-                    if states[other_index].is_pressed() {
-                        set.push(other_key_code.into()).unwrap();
+                    push_code(set, ReportCodes::Sticky);
+                    if pressed_keys[other_index] {
+                        push_code(set, other_key_code.into());
                         PressResult::Pressed
                     } else {
-                        set.push(normal_code.into()).unwrap();
+                        push_code(set, normal_code.into());
                         PressResult::Pressed
                     }
                 } else {
@@ -133,31 +344,255 @@ impl<I: ConfigIndicator> Keys<I> {
             }
             ScanCodeBehavior::ChangeConfig(config_num) => {
                 if pressed {
-                    self.load_keys_from_storage(config_num as usize).await;
+                    if let Err(err) = self.load_keys_from_storage(config_num as usize).await {
+                        record_error(err);
+                    }
                     PressResult::Function
                 } else {
                     PressResult::None
                 }
             }
+            ScanCodeBehavior::AnalogLayer {
+                partial_layer,
+                full_layer,
+                partial_travel,
+                full_travel,
+            } => {
+                let travel = states[index].travel();
+                if pressed && travel >= partial_travel {
+                    push_code(
+                        set,
+                        ReportCodes::AnalogLayer {
+                            partial: partial_layer,
+                            full: full_layer,
+                            bottomed: travel >= full_travel,
+                        },
+                    );
+                    PressResult::Pressed
+                } else {
+                    PressResult::None
+                }
+            }
+            ScanCodeBehavior::DualStage {
+                first_code,
+                second_code,
+                first_travel,
+                second_travel,
+            } => {
+                self.stages[index] = if pressed {
+                    update_stage(
+                        self.stages[index],
+                        states[index].travel(),
+                        first_travel,
+                        second_travel,
+                    )
+                } else {
+                    0
+                };
+                match self.stages[index] {
+                    0 => PressResult::None,
+                    1 => {
+                        push_code(set, first_code.into());
+                        PressResult::Pressed
+                    }
+                    _ => {
+                        push_code(set, first_code.into());
+                        push_code(set, second_code.into());
+                        PressResult::Pressed
+                    }
+                }
+            }
+            ScanCodeBehavior::TapDance {
+                tap,
+                double_tap,
+                hold,
+                term,
+            } => self.tap_dance_code(index, pressed, &[tap, double_tap], hold, term, set),
+            ScanCodeBehavior::TripleTapDance {
+                tap,
+                double_tap,
+                triple_tap,
+                hold,
+                term,
+            } => self.tap_dance_code(
+                index,
+                pressed,
+                &[tap, double_tap, triple_tap],
+                hold,
+                term,
+                set,
+            ),
+            ScanCodeBehavior::ModSwap {
+                normal_code,
+                swapped_code,
+                mods,
+            } => {
+                if pressed {
+                    push_code(
+                        set,
+                        ReportCodes::ModSwap {
+                            normal: normal_code,
+                            swapped: swapped_code,
+                            mods,
+                        },
+                    );
+                    PressResult::Pressed
+                } else {
+                    PressResult::None
+                }
+            }
+            ScanCodeBehavior::OsSwap {
+                normal_code,
+                mac_code,
+            } => {
+                if pressed {
+                    let code = match host_os() {
+                        HostOs::MacOs => mac_code,
+                        _ => normal_code,
+                    };
+                    push_code(set, code.into());
+                    PressResult::Pressed
+                } else {
+                    PressResult::None
+                }
+            }
+            ScanCodeBehavior::Velocity {
+                slow_code,
+                fast_code,
+                threshold,
+            } => {
+                if pressed {
+                    let code = *self.velocity_codes[index].get_or_insert_with(|| {
+                        if states[index].velocity() >= threshold {
+                            fast_code
+                        } else {
+                            slow_code
+                        }
+                    });
+                    push_code(set, code.into());
+                    PressResult::Pressed
+                } else {
+                    self.velocity_codes[index] = None;
+                    PressResult::None
+                }
+            }
+            ScanCodeBehavior::SwitchHost(host) => {
+                if pressed {
+                    request_host_switch(host);
+                    PressResult::Function
+                } else {
+                    PressResult::None
+                }
+            }
+            ScanCodeBehavior::Bootloader => {
+                if pressed {
+                    request_bootloader();
+                    PressResult::Function
+                } else {
+                    PressResult::None
+                }
+            }
+            // Handled in get_keys before any code is read
+            ScanCodeBehavior::LockKeyboard { .. } => PressResult::None,
+            ScanCodeBehavior::RecordMacro { save } => {
+                if pressed {
+                    toggle_recording(save).await;
+                    PressResult::Function
+                } else {
+                    PressResult::None
+                }
+            }
+            ScanCodeBehavior::PlayMacro => {
+                if pressed {
+                    play_macro();
+                    PressResult::Function
+                } else {
+                    PressResult::None
+                }
+            }
+            ScanCodeBehavior::ToggleMouseInvert(axis) => {
+                if pressed {
+                    self.mouse_settings.toggle_invert(axis);
+                    schedule_save(
+                        StorageKey::MouseSettings {
+                            config_num: self.config_num,
+                        },
+                        StorageItem::MouseSettings(self.mouse_settings),
+                    )
+                    .await;
+                    PressResult::Function
+                } else {
+                    PressResult::None
+                }
+            }
+        }
+    }
+
+    // Pushes the code of a tap dance key. taps holds the code sent for each
+    // number of taps, so its length is the most taps the dance counts
+    fn tap_dance_code(
+        &mut self,
+        index: usize,
+        pressed: bool,
+        taps: &[KeyCodes],
+        hold: KeyCodes,
+        term: u8,
+        set: &mut PressedCodes,
+    ) -> PressResult {
+        let term = Duration::from_millis(term as u64 * 10);
+        let dance = &mut self.tap_dances[index];
+        let code = match dance.update(pressed, Instant::now(), term, taps.len() as u8) {
+            TapDanceOutput::None => None,
+            TapDanceOutput::Taps(count) => taps.get(count as usize - 1).copied(),
+            TapDanceOutput::Hold => Some(hold),
+        };
+        if let Some(code) = code {
+            push_code(set, code.into());
+        }
+        // Keep the layer while waiting for the dance to resolve so the
+        // tap is sent from the layer it started on
+        if code.is_some() || dance.is_active() {
+            PressResult::Pressed
+        } else {
+            PressResult::None
         }
     }
 
     /// Returns all the pressed scancodes in the Keys struct. Returns it through
     /// the passed in vector. The passed in vector should be empty.
     /// Note that if a key is held, it will ignore the passed in layer and use the
-    /// previous layer it's holding
+    /// previous layer it's holding. Keys used by a combo are replaced with the combo's code
     pub async fn get_keys<K: KeyState>(
         &mut self,
         layer: usize,
-        set: &mut Vec<ReportCodes, 64>,
+        set: &mut PressedCodes,
         states: &[K; NUM_KEYS],
     ) {
+        // Keys pressed by injected events count as pressed switches
+        let injected = injected_keys();
+        let pressed_keys: [bool; NUM_KEYS] =
+            array::from_fn(|i| states[i].is_pressed() || injected.contains(i));
+        let layer = self.lookup_layer(layer, peer_present());
+        if self.update_lock(layer, &pressed_keys).await {
+            set.clear();
+            self.current_layer.fill(None);
+            return;
+        }
+        let filters = self.combos.update(&pressed_keys, set);
         for i in 0..NUM_KEYS {
             let layer = match self.current_layer[i] {
                 Some(num) => num,
                 None => layer,
             };
-            match self.get_pressed_code(i, layer, states, set).await {
+            let pressed = match filters[i] {
+                ComboFilter::Normal => pressed_keys[i],
+                ComboFilter::Skip => false,
+                ComboFilter::Tap => true,
+            };
+            match self
+                .get_pressed_code(i, layer, pressed, states, &pressed_keys, set)
+                .await
+            {
                 PressResult::Function => {
                     set.clear();
                     self.current_layer.fill(None);
@@ -174,99 +609,261 @@ impl<I: ConfigIndicator> Keys<I> {
                 }
             }
         }
+        resolve_mod_swaps(set);
+    }
+
+    // Returns true if a lock keyboard key is held together with its other key.
+    // While locked the chord is looked for on every layer, since the layer
+    // keys that would reach it are suppressed
+    fn lock_chord_pressed(&self, layer: usize, pressed_keys: &[bool; NUM_KEYS]) -> bool {
+        let is_chord = |code: &ScanCodeBehavior| match *code {
+            ScanCodeBehavior::LockKeyboard { other_index } => {
+                pressed_keys.get(other_index).copied().unwrap_or(false)
+            }
+            _ => false,
+        };
+        (0..NUM_KEYS).filter(|&i| pressed_keys[i]).any(|i| {
+            if self.locked {
+                self.codes[i].iter().any(is_chord)
+            } else {
+                is_chord(&self.codes[i][self.current_layer[i].unwrap_or(layer)])
+            }
+        })
+    }
+
+    // Toggles the lock when the lock chord is pressed. Returns true if no
+    // codes should be sent this scan
+    async fn update_lock(&mut self, layer: usize, pressed_keys: &[bool; NUM_KEYS]) -> bool {
+        let chord = self.lock_chord_pressed(layer, pressed_keys);
+        let toggled = chord && !self.lock_chord_held;
+        self.lock_chord_held = chord;
+        if toggled {
+            self.locked = !self.locked;
+            info!("Keyboard locked: {}", self.locked);
+            if let Some(indicator) = self.indicator.as_ref() {
+                indicator
+                    .indicate_config(Indicate::Locked(self.locked))
+                    .await;
+            }
+        }
+        // The chord itself is never sent, so unlocking doesn't type anything
+        self.locked || chord
     }
 
-    pub async fn write_keys_to_com<'d, T: Driver<'d>>(&self, writer: &mut ContinuousWriter<'d, T>) {
+    // Returns the layer codes are read from. A half used on its own reads its
+    // base layer codes from the standalone layer
+    fn lookup_layer(&self, layer: usize, peer_present: bool) -> usize {
+        match self.standalone_layer.layer() {
+            Some(standalone) if layer == 0 && !peer_present => standalone,
+            _ => layer,
+        }
+    }
+
+    pub async fn write_keys_to_com<'d, T: Driver<'d>>(
+        &self,
+        writer: &mut ContinuousWriter<'d, T>,
+    ) -> Result<(), KeyLibError> {
         let mut buf = [0u8; MAX_SERIAL_LENGTH];
         for codes in self.codes {
             for code in codes {
-                code.into_buffer(&mut buf[..code.into_buffer_len()])
-                    .unwrap();
-                writer.write(&buf[..code.into_buffer_len()]).await;
+                code.into_buffer(&mut buf[..code.into_buffer_len()])?;
+                writer.write(&buf[..code.into_buffer_len()]).await?;
             }
         }
+        Ok(())
+    }
+
+    /// Returns a crc of the codes in the order they're written to com, so a
+    /// host can tell if the keymap matches a file without reading it
+    pub fn checksum(&self) -> Result<u32, KeyLibError> {
+        let mut buf = [0u8; MAX_SERIAL_LENGTH];
+        let mut crc = Crc32::default();
+        for codes in self.codes {
+            for code in codes {
+                code.into_buffer(&mut buf[..code.into_buffer_len()])?;
+                crc.update(&buf[..code.into_buffer_len()]);
+            }
+        }
+        Ok(crc.finish())
+    }
+
+    /// Returns the codes of every key on the layer
+    pub fn layer(&self, layer: usize) -> ScanCodeLayerStorage<NUM_KEYS> {
+        ScanCodeLayerStorage {
+            codes: self.codes.map(|codes| codes[layer]),
+        }
+    }
+
+    /// Replaces the codes of every key on the layer
+    pub fn set_layer(&mut self, layer: usize, codes: &ScanCodeLayerStorage<NUM_KEYS>) {
+        self.codes
+            .iter_mut()
+            .zip(codes.codes.iter())
+            .for_each(|(key, code)| key[layer] = *code);
     }
 
+    /// Writes the layers of the config to the slot not in use and marks them
+    /// pending, see [crate::config_slots]. Nothing is written if they match
+    /// the newest stored layers
     pub async fn write_keys_to_storage(&self, config_num: usize) {
+        let state = config_slots::slot_state(config_num).await;
+        let mut changed = false;
         for layer in 0..NUM_LAYERS {
-            let new_keys = StorageItem::Key(ScanCodeLayerStorage {
-                codes: self.codes.map(|codes| codes[layer]),
-            });
-            let StorageItem::Key(keys) = &new_keys;
-            let storage_key = StorageKey::KeyScanCode { config_num, layer };
-            let stored_keys = get_item(storage_key).await;
-            match stored_keys {
-                Some(stored_keys) => {
-                    if let StorageItem::Key(stored_keys) = stored_keys {
-                        if stored_keys != *keys {
-                            info!("Storing config {} | layer {}", config_num, layer);
-                            store_val(storage_key, &new_keys).await;
-                        } else {
-                            info!("Equal config {} | layer {}", config_num, layer);
-                        }
-                    } else {
-                    }
-                }
-                None => {
-                    info!("No config {} | layer {}", config_num, layer);
-                    store_val(storage_key, &new_keys).await;
+            let storage_key = StorageKey::KeyScanCode {
+                config_num,
+                layer,
+                slot: state.newest_slot(),
+            };
+            match get_item(storage_key).await {
+                Some(StorageItem::Key(stored_keys)) if stored_keys == self.layer(layer) => {}
+                _ => {
+                    changed = true;
+                    break;
                 }
             }
         }
+        if !changed {
+            info!("Equal config {}", config_num);
+            return;
+        }
+        let slot = state.write_slot();
+        for layer in 0..NUM_LAYERS {
+            info!(
+                "Storing config {} | layer {} | slot {}",
+                config_num, layer, slot
+            );
+            let storage_key = StorageKey::KeyScanCode {
+                config_num,
+                layer,
+                slot,
+            };
+            store_val(storage_key, &StorageItem::Key(self.layer(layer))).await;
+        }
+        config_slots::mark_written(config_num).await;
     }
 
-    pub async fn load_keys_from_storage(&mut self, config_num: usize) -> Result<(), ()> {
-        self.config_num = config_num;
+    // Loads every layer of the config from the slot
+    async fn load_layers(&mut self, config_num: usize, slot: u8) -> Result<(), KeyLibError> {
         for layer in 0..NUM_LAYERS {
-            let storage_key = StorageKey::KeyScanCode { config_num, layer };
+            let storage_key = StorageKey::KeyScanCode {
+                config_num,
+                layer,
+                slot,
+            };
             match get_item(storage_key).await {
-                Some(val) => match val {
-                    StorageItem::Key(codes) => {
-                        self.codes
-                            .iter_mut()
-                            .zip(codes.codes.iter())
-                            .for_each(|(key, code)| key[layer] = *code);
-                    }
-                    _ => {
-                        error!("Invalid key stored at {}", storage_key);
-                        *self = Keys::default();
-                        return Err(());
-                    }
-                },
+                Some(StorageItem::Key(codes)) => self.set_layer(layer, &codes),
+                Some(_) => {
+                    error!("Invalid key stored at {}", storage_key);
+                    return Err(KeyLibError::Serialization);
+                }
                 None => {
-                    *self = Keys::default();
                     error!("No key stored at {}", storage_key);
-                    return Err(());
+                    return Err(KeyLibError::Storage);
                 }
             }
         }
+        Ok(())
+    }
+
+    /// Loads the config from the slot with its newest layers. Layers that
+    /// don't load are rolled back to the previous slot, see
+    /// [crate::config_slots]
+    pub async fn load_keys_from_storage(&mut self, config_num: usize) -> Result<(), KeyLibError> {
+        if config_num >= NUM_CONFIGS {
+            error!("Config {} doesn't exist", config_num);
+            return Err(KeyLibError::InvalidConfig);
+        }
+        self.config_num = config_num;
+        // Only the keys the board runs count as booting a pending slot, not
+        // ones loaded to read another config
+        let state = if self.indicator.is_some() {
+            config_slots::begin_load(config_num).await
+        } else {
+            config_slots::slot_state(config_num).await
+        };
+        let mut loaded = self.load_layers(config_num, state.newest_slot()).await;
+        if loaded.is_err() && state.pending {
+            let state = config_slots::roll_back(config_num).await;
+            loaded = self.load_layers(config_num, state.active).await;
+        }
+        if let Err(err) = loaded {
+            *self = Keys::default();
+            return Err(err);
+        }
+        self.mouse_settings = match get_item(StorageKey::MouseSettings { config_num }).await {
+            Some(StorageItem::MouseSettings(settings)) => settings,
+            _ => MouseSettings::default(),
+        };
+        self.layer_mouse_speeds = match get_item(StorageKey::LayerMouseSpeeds { config_num }).await
+        {
+            Some(StorageItem::LayerMouseSpeeds(speeds)) => speeds,
+            _ => LayerMouseSpeeds::default(),
+        };
+        self.load_combos_from_storage(config_num).await;
+        self.socd = match get_item(StorageKey::Socd { config_num }).await {
+            Some(StorageItem::SocdPairs(pairs)) => pairs,
+            _ => SocdPairs::default(),
+        };
+        self.layer_timeouts = match get_item(StorageKey::LayerTimeouts { config_num }).await {
+            Some(StorageItem::LayerTimeouts(timeouts)) => timeouts,
+            _ => LayerTimeouts::default(),
+        };
+        self.standalone_layer = match get_item(StorageKey::StandaloneLayer { config_num }).await {
+            Some(StorageItem::StandaloneLayer(layer)) => layer,
+            _ => StandaloneLayer::default(),
+        };
+        self.auto_shift = match get_item(StorageKey::AutoShift { config_num }).await {
+            Some(StorageItem::AutoShift(settings)) => settings,
+            _ => AutoShiftSettings::default(),
+        };
+        self.config_color = match get_item(StorageKey::ConfigInfo { config_num }).await {
+            Some(StorageItem::ConfigInfo(info)) => info.color(),
+            _ => None,
+        };
+        let profiles = match get_item(StorageKey::SwitchProfiles { config_num }).await {
+            Some(StorageItem::SwitchProfiles(profiles)) => profiles,
+            _ => SwitchProfiles::default(),
+        };
+        self.set_position_type_per_key(profiles);
+        // Only the keys the board runs have an indicator, not ones loaded
+        // to read another config
         if let Some(indicator) = self.indicator.as_ref() {
+            display::set_config(self.config_num);
             indicator
-                .indicate_config(Indicate::Config(self.config_num))
+                .indicate_config(Indicate::Config(self.config_num, self.config_color))
                 .await;
         }
         Ok(())
     }
+    pub async fn load_combos_from_storage(&mut self, config_num: usize) {
+        let combos = match get_item(StorageKey::Combo { config_num }).await {
+            Some(StorageItem::Combos(combos)) => combos,
+            _ => Combos::default(),
+        };
+        self.combos.set_combos(combos);
+    }
+
     pub async fn load_keys_from_com<'d, T: Driver<'d>>(
         &mut self,
         reader: &mut ContinuousReader<'d, T>,
         config_num: usize,
-    ) -> Result<(), sequential_storage::map::SerializationError> {
+    ) -> Result<(), KeyLibError> {
+        if config_num >= NUM_CONFIGS {
+            error!("Config {} doesn't exist", config_num);
+            return Err(KeyLibError::InvalidConfig);
+        }
         self.config_num = config_num;
         let mut buf = [0u8; MAX_SERIAL_LENGTH];
         for code in self.codes.iter_mut().flatten() {
-            buf[0] = reader.pop().await;
-            let hid_type: HidScanCodeType = buf[0]
-                .try_into()
-                .map_err(|_| sequential_storage::map::SerializationError::InvalidFormat)?;
-            reader.pop_slice(&mut buf[1..hid_type.get_len()]).await;
-            *code = ScanCodeBehavior::deserialize_from(&buf[..hid_type.get_len()])
-                .unwrap()
-                .0;
+            buf[0] = reader.pop().await?;
+            let hid_type: HidScanCodeType =
+                buf[0].try_into().map_err(|_| KeyLibError::Serialization)?;
+            reader.pop_slice(&mut buf[1..hid_type.get_len()]).await?;
+            *code = ScanCodeBehavior::deserialize_from(&buf[..hid_type.get_len()])?.0;
         }
         if let Some(indicator) = self.indicator.as_ref() {
             indicator
-                .indicate_config(Indicate::Config(self.config_num))
+                .indicate_config(Indicate::Config(self.config_num, self.config_color))
                 .await;
         }
         Ok(())
@@ -297,3 +894,146 @@ impl<SL: SlaveState, S: Slave<SlaveState = SL>> SlaveKeys<SL, S> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use embassy_futures::block_on;
+
+    use super::*;
+    use crate::position::DefaultSwitch;
+
+    struct NoIndicator;
+
+    impl ConfigIndicator for NoIndicator {
+        async fn indicate_config(&self, _: Indicate) {}
+    }
+
+    fn positions(pressed: &[usize]) -> [DefaultSwitch; NUM_KEYS] {
+        let mut positions = [DefaultSwitch::DEFAULT; NUM_KEYS];
+        for &i in pressed {
+            positions[i].update_buf(true);
+        }
+        positions
+    }
+
+    fn letters(keys: &mut Keys<NoIndicator>, layer: usize, pressed: &[usize]) -> Vec<u8, 8> {
+        let mut set = PressedCodes::new();
+        block_on(keys.get_keys(layer, &mut set, &positions(pressed)));
+        set.iter()
+            .filter_map(|code| match code {
+                ReportCodes::Letter(letter) => Some(*letter),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn checksum_follows_codes() {
+        let mut keys = Keys::<NoIndicator>::default();
+        let empty = keys.checksum().unwrap();
+        assert_eq!(empty, Keys::<NoIndicator>::default().checksum().unwrap());
+        keys.set_code(ScanCodeBehavior::Single(KeyCodes::KeyboardAa), 1, 0);
+        let single = keys.checksum().unwrap();
+        assert_ne!(single, empty);
+        // Same code on another layer is a different keymap
+        keys.set_code(ScanCodeBehavior::default(), 1, 0);
+        keys.set_code(ScanCodeBehavior::Single(KeyCodes::KeyboardAa), 1, 1);
+        assert_ne!(keys.checksum().unwrap(), single);
+    }
+
+    #[test]
+    fn change_config_past_num_configs_keeps_keys() {
+        let mut keys = Keys::<NoIndicator>::default();
+        keys.set_code(ScanCodeBehavior::ChangeConfig(NUM_CONFIGS as u8), 1, 0);
+        keys.set_code(ScanCodeBehavior::Single(KeyCodes::KeyboardAa), 2, 0);
+
+        letters(&mut keys, 0, &[1]);
+        assert_eq!(keys.config_num, 0);
+        letters(&mut keys, 0, &[]);
+        assert_eq!(letters(&mut keys, 0, &[2]), [KeyCodes::KeyboardAa as u8]);
+    }
+
+    #[test]
+    fn held_key_keeps_its_layer() {
+        let mut keys = Keys::<NoIndicator>::default();
+        keys.set_code(ScanCodeBehavior::Single(KeyCodes::KeyboardAa), 1, 0);
+        keys.set_code(ScanCodeBehavior::Single(KeyCodes::KeyboardBb), 1, 1);
+
+        assert_eq!(letters(&mut keys, 1, &[1]), [KeyCodes::KeyboardBb as u8]);
+        // Changing layers while the key is held doesn't change its code
+        assert_eq!(letters(&mut keys, 0, &[1]), [KeyCodes::KeyboardBb as u8]);
+        letters(&mut keys, 0, &[]);
+        assert_eq!(letters(&mut keys, 0, &[1]), [KeyCodes::KeyboardAa as u8]);
+    }
+
+    #[test]
+    fn mod_swap_follows_held_modifiers() {
+        let mut keys = Keys::<NoIndicator>::default();
+        keys.set_code(ScanCodeBehavior::grave_escape(), 0, 0);
+        keys.set_code(ScanCodeBehavior::Single(KeyCodes::KeyboardLeftShift), 1, 0);
+
+        assert_eq!(
+            letters(&mut keys, 0, &[0]),
+            [KeyCodes::KeyboardEscape as u8]
+        );
+        letters(&mut keys, 0, &[]);
+        // Shift is read after the escape key but still swaps it
+        assert_eq!(
+            letters(&mut keys, 0, &[0, 1]),
+            [KeyCodes::KeyboardBacktickTilde as u8]
+        );
+    }
+
+    #[test]
+    fn full_set_drops_lowest_priority_code() {
+        let mut set = PressedCodes::new();
+        while !set.is_full() {
+            push_code(&mut set, ReportCodes::Letter(KeyCodes::KeyboardAa as u8));
+        }
+        push_code(&mut set, ReportCodes::Layer(1));
+        assert!(set.iter().any(|code| matches!(code, ReportCodes::Layer(1))));
+        assert_eq!(set.len(), MAX_PRESSED_CODES);
+    }
+
+    #[test]
+    fn lock_chord_suppresses_keys_until_pressed_again() {
+        let mut keys = Keys::<NoIndicator>::default();
+        keys.set_code(ScanCodeBehavior::LockKeyboard { other_index: 3 }, 2, 0);
+        keys.set_code(ScanCodeBehavior::Single(KeyCodes::KeyboardAa), 3, 0);
+        keys.set_code(ScanCodeBehavior::Single(KeyCodes::KeyboardBb), 4, 0);
+
+        assert_eq!(letters(&mut keys, 0, &[3]), [KeyCodes::KeyboardAa as u8]);
+        assert!(letters(&mut keys, 0, &[2, 3]).is_empty());
+        assert!(keys.is_locked());
+        // Holding the chord doesn't unlock it again
+        assert!(letters(&mut keys, 0, &[2, 3]).is_empty());
+        assert!(letters(&mut keys, 0, &[3, 4]).is_empty());
+        // The chord unlocks from any layer
+        assert!(letters(&mut keys, 1, &[2, 3]).is_empty());
+        assert!(!keys.is_locked());
+        assert_eq!(letters(&mut keys, 0, &[4]), [KeyCodes::KeyboardBb as u8]);
+    }
+
+    #[test]
+    fn auto_shift_key_is_sent_on_release() {
+        let mut keys = Keys::<NoIndicator>::default();
+        keys.set_code(ScanCodeBehavior::Single(KeyCodes::KeyboardAa), 5, 0);
+        keys.auto_shift.timeout = 255;
+        keys.auto_shift.keys[0] = 1 << 5;
+
+        assert!(letters(&mut keys, 0, &[5]).is_empty());
+        assert_eq!(letters(&mut keys, 0, &[]), [KeyCodes::KeyboardAa as u8]);
+        assert!(letters(&mut keys, 0, &[]).is_empty());
+    }
+
+    #[test]
+    fn standalone_layer_replaces_base_layer_without_peer() {
+        let mut keys = Keys::<NoIndicator>::default();
+        assert_eq!(keys.lookup_layer(0, false), 0);
+        keys.standalone_layer = StandaloneLayer(2);
+        assert_eq!(keys.lookup_layer(0, true), 0);
+        assert_eq!(keys.lookup_layer(0, false), 2);
+        // Other layers are used as is
+        assert_eq!(keys.lookup_layer(1, false), 1);
+    }
+}
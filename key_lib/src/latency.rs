@@ -0,0 +1,219 @@
+//! Measures the time from a key flipping in the sensors to the write of the
+//! report it changed. Key loops call [record_flips] after reading the sensors
+//! and [report_written] once a key report is written, while
+//! Report::generate_report hands the flip over to the report it changed.
+//!
+//! Tracking is turned on with HidRequest::LatencyTracking and the histogram is
+//! read with DiagnosticKind::Latency. Only the first flip waiting for a report
+//! is timed, so flips that land in the same report count once
+
+use core::cell::Cell;
+
+use embassy_sync::blocking_mutex::{Mutex, raw::CriticalSectionRawMutex};
+use embassy_time::{Duration, Instant};
+
+use crate::NUM_KEYS;
+use crate::diagnostics::{Diagnostic, DiagnosticKind};
+use crate::position::KeyState;
+
+/// Number of buckets in the histogram. Bucket 0 holds latencies under
+/// [BUCKET_BASE] and each later bucket is twice as wide as the last, with the
+/// last one holding everything longer
+pub const LATENCY_BUCKETS: usize = 10;
+pub const BUCKET_BASE: Duration = Duration::from_micros(250);
+
+pub const LATENCY_REPORT_SERIAL_LENGTH: usize = 13 + 2 * LATENCY_BUCKETS;
+
+// Flips that haven't changed a report within this long are dropped, e.g. a
+// key without a code
+const STALE_FLIP: Duration = Duration::from_secs(1);
+
+const PRESSED_LEN: usize = NUM_KEYS.div_ceil(8);
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct LatencyHistogram {
+    pub buckets: [u16; LATENCY_BUCKETS],
+    pub samples: u32,
+    pub max_us: u32,
+    total_us: u64,
+}
+
+impl LatencyHistogram {
+    pub const fn new() -> Self {
+        Self {
+            buckets: [0; LATENCY_BUCKETS],
+            samples: 0,
+            max_us: 0,
+            total_us: 0,
+        }
+    }
+
+    pub fn add(&mut self, latency: Duration) {
+        let us = latency.as_micros();
+        let mut bucket = 0;
+        let mut bound = BUCKET_BASE.as_micros();
+        while us >= bound && bucket < LATENCY_BUCKETS - 1 {
+            bucket += 1;
+            bound *= 2;
+        }
+        self.buckets[bucket] = self.buckets[bucket].saturating_add(1);
+        self.samples = self.samples.saturating_add(1);
+        self.max_us = self.max_us.max(us.min(u32::MAX as u64) as u32);
+        self.total_us = self.total_us.saturating_add(us);
+    }
+
+    pub fn mean_us(&self) -> u32 {
+        match self.samples {
+            0 => 0,
+            samples => (self.total_us / samples as u64).min(u32::MAX as u64) as u32,
+        }
+    }
+}
+
+impl Default for LatencyHistogram {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Clone, Copy)]
+struct LatencyState {
+    enabled: bool,
+    pressed: [u8; PRESSED_LEN],
+    // First flip that hasn't changed a report yet
+    flipped_at: Option<Instant>,
+    // Flip of the report being written
+    in_flight: Option<Instant>,
+    histogram: LatencyHistogram,
+}
+
+impl LatencyState {
+    const fn new() -> Self {
+        Self {
+            enabled: false,
+            pressed: [0; PRESSED_LEN],
+            flipped_at: None,
+            in_flight: None,
+            histogram: LatencyHistogram::new(),
+        }
+    }
+}
+
+static LATENCY: Mutex<CriticalSectionRawMutex, Cell<LatencyState>> =
+    Mutex::new(Cell::new(LatencyState::new()));
+
+fn update(f: impl FnOnce(&mut LatencyState)) {
+    LATENCY.lock(|latency| {
+        let mut state = latency.get();
+        if state.enabled {
+            f(&mut state);
+            latency.set(state);
+        }
+    });
+}
+
+/// Turns tracking on or off. Turning it on clears the histogram
+pub fn set_latency_tracking(enabled: bool) {
+    LATENCY.lock(|latency| {
+        let mut state = LatencyState::new();
+        state.enabled = enabled;
+        latency.set(state);
+    });
+}
+
+/// Timestamps the first flip since the last report. Should be called right
+/// after the sensors update the positions
+pub fn record_flips<K: KeyState>(positions: &[K]) {
+    update(|state| {
+        let mut pressed = [0u8; PRESSED_LEN];
+        for (i, position) in positions.iter().enumerate().take(NUM_KEYS) {
+            if position.is_pressed() {
+                pressed[i / 8] |= 1 << (i % 8);
+            }
+        }
+        if pressed != state.pressed && state.flipped_at.is_none() {
+            state.flipped_at = Some(Instant::now());
+        }
+        state.pressed = pressed;
+    });
+}
+
+/// Hands the waiting flip over to the report if it changed
+pub(crate) fn report_generated(changed: bool, now: Instant) {
+    update(|state| {
+        if changed {
+            state.in_flight = state.flipped_at.take();
+        } else if state
+            .flipped_at
+            .is_some_and(|flipped_at| now - flipped_at > STALE_FLIP)
+        {
+            state.flipped_at = None;
+        }
+    });
+}
+
+/// Adds the latency of the report that was just written to the histogram
+pub fn report_written() {
+    let now = Instant::now();
+    update(|state| {
+        if let Some(flipped_at) = state.in_flight.take() {
+            state.histogram.add(now - flipped_at);
+        }
+    });
+}
+
+/// Histogram of the latencies since tracking was turned on
+#[derive(Debug, Clone, Copy)]
+pub struct LatencyReport {
+    pub enabled: bool,
+    pub histogram: LatencyHistogram,
+}
+
+impl LatencyReport {
+    pub fn current() -> Self {
+        let state = LATENCY.lock(|latency| latency.get());
+        Self {
+            enabled: state.enabled,
+            histogram: state.histogram,
+        }
+    }
+}
+
+impl Diagnostic for LatencyReport {
+    const KIND: DiagnosticKind = DiagnosticKind::Latency;
+    const LEN: usize = LATENCY_REPORT_SERIAL_LENGTH;
+
+    fn write_payload(&self, buffer: &mut [u8]) {
+        let histogram = &self.histogram;
+        buffer[0] = self.enabled as u8;
+        buffer[1..5].copy_from_slice(&histogram.samples.to_le_bytes());
+        buffer[5..9].copy_from_slice(&histogram.mean_us().to_le_bytes());
+        buffer[9..13].copy_from_slice(&histogram.max_us.to_le_bytes());
+        for (count, chunk) in histogram
+            .buckets
+            .iter()
+            .zip(buffer[13..].chunks_exact_mut(2))
+        {
+            chunk.copy_from_slice(&count.to_le_bytes());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn latencies_fall_into_doubling_buckets() {
+        let mut histogram = LatencyHistogram::new();
+        histogram.add(Duration::from_micros(100));
+        histogram.add(Duration::from_micros(600));
+        histogram.add(Duration::from_millis(900));
+        assert_eq!(histogram.buckets[0], 1);
+        assert_eq!(histogram.buckets[2], 1);
+        assert_eq!(histogram.buckets[LATENCY_BUCKETS - 1], 1);
+        assert_eq!(histogram.samples, 3);
+        assert_eq!(histogram.max_us, 900_000);
+        assert_eq!(histogram.mean_us(), 300_233);
+    }
+}
@@ -1,12 +1,50 @@
-#![no_std]
+#![cfg_attr(not(feature = "std"), no_std)]
 include!("config.rs");
+pub mod auto_shift;
+pub mod backup;
+pub mod board;
+pub mod boot_protocol;
+pub mod bootloader;
 pub mod codes;
 pub mod com;
+pub mod combo;
 pub mod config;
+pub mod config_slots;
+pub mod crash;
 pub mod descriptor;
+pub mod dfu;
+pub mod diagnostics;
+pub mod display;
+pub mod dynamic_macro;
+pub mod error;
+pub mod factory_reset;
+#[cfg(feature = "mouse")]
+pub mod hires_scroll;
+#[cfg(feature = "std")]
+mod host;
+pub mod host_switch;
+pub mod inject;
+pub mod key_stats;
+pub mod keymap;
 pub mod keys;
+pub mod latency;
+pub mod lighting;
+pub mod link_watch;
+pub mod os;
+#[cfg(feature = "digitizer")]
+pub mod pointer;
 pub mod position;
+pub mod power;
+pub mod reaction;
 pub mod report;
+pub mod scan;
 pub mod scan_codes;
+pub mod self_test;
+pub mod settings;
+#[cfg(feature = "sim")]
+pub mod sim;
 pub mod slave_com;
+pub mod socd;
 pub mod storage;
+pub mod tap_dance;
+pub mod watchdog;
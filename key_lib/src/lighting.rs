@@ -0,0 +1,299 @@
+use core::cell::Cell;
+
+use defmt::{Format, info};
+use embassy_sync::{
+    blocking_mutex::{self, raw::CriticalSectionRawMutex},
+    mutex::Mutex,
+};
+use embassy_time::{Duration, Instant};
+use num_enum::TryFromPrimitive;
+use sequential_storage::map::{SerializationError, Value};
+
+use crate::{
+    NUM_KEYS, NUM_LAYERS, reaction, self_test,
+    storage::{StorageItem, StorageKey, get_item, store_val},
+};
+
+/// Time between rendered lighting frames
+pub const FRAME_INTERVAL: Duration = Duration::from_millis(16);
+
+// Time a key takes to fade out after being released with the reactive effect
+const REACTIVE_FADE: Duration = Duration::from_millis(500);
+
+// Breathing period at the lowest and highest speed
+const SLOWEST_BREATH_MS: u64 = 4000;
+const FASTEST_BREATH_MS: u64 = 500;
+
+pub const LIGHTING_SERIAL_LENGTH: usize = 6 + 3 * NUM_LAYERS;
+
+/// Lighting settings currently used by boards with per key leds. Updated over
+/// com and loaded from storage with [load_lighting]
+pub static LIGHTING: Mutex<CriticalSectionRawMutex, LightingSettings> =
+    Mutex::new(LightingSettings::default());
+
+/// Key state the lighting effects react to. Updated by the report every scan
+static INPUT: blocking_mutex::Mutex<CriticalSectionRawMutex, Cell<LightingInput>> =
+    blocking_mutex::Mutex::new(Cell::new(LightingInput {
+        layer: 0,
+        pressed: [false; NUM_KEYS],
+    }));
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Format)]
+pub struct Color {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+impl Color {
+    pub const OFF: Self = Self::new(0, 0, 0);
+
+    pub const fn new(r: u8, g: u8, b: u8) -> Self {
+        Self { r, g, b }
+    }
+
+    /// Scales the color by level, where 255 keeps the color unchanged
+    pub const fn scale(&self, level: u8) -> Self {
+        const fn channel(val: u8, level: u8) -> u8 {
+            ((val as u16 * level as u16) / 255) as u8
+        }
+        Self::new(
+            channel(self.r, level),
+            channel(self.g, level),
+            channel(self.b, level),
+        )
+    }
+
+    fn into_buffer(self, buffer: &mut [u8]) {
+        buffer[0] = self.r;
+        buffer[1] = self.g;
+        buffer[2] = self.b;
+    }
+
+    fn from_buffer(buffer: &[u8]) -> Self {
+        Self::new(buffer[0], buffer[1], buffer[2])
+    }
+}
+
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Format, TryFromPrimitive)]
+pub enum LightingEffect {
+    Off = 0,
+    /// Every key shows the color
+    Static = 1,
+    /// Every key fades the color in and out
+    Breathing = 2,
+    /// Keys light up with the color when pressed and fade out after release
+    Reactive = 3,
+    /// Every key shows the color of the active layer
+    LayerColors = 4,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Format)]
+pub struct LightingSettings {
+    pub effect: LightingEffect,
+    pub color: Color,
+    /// Brightness applied on top of every effect, where 255 is full brightness
+    pub brightness: u8,
+    /// Speed of animated effects, where 255 is the fastest
+    pub speed: u8,
+    pub layer_colors: [Color; NUM_LAYERS],
+}
+
+impl LightingSettings {
+    pub const fn default() -> Self {
+        Self {
+            effect: LightingEffect::Off,
+            color: Color::new(255, 255, 255),
+            brightness: 64,
+            speed: 128,
+            layer_colors: [Color::OFF; NUM_LAYERS],
+        }
+    }
+
+    pub fn into_buffer(&self, buffer: &mut [u8]) -> Result<(), SerializationError> {
+        if buffer.len() < LIGHTING_SERIAL_LENGTH {
+            Err(SerializationError::BufferTooSmall)
+        } else {
+            buffer[0] = self.effect as u8;
+            self.color.into_buffer(&mut buffer[1..4]);
+            buffer[4] = self.brightness;
+            buffer[5] = self.speed;
+            for (color, chunk) in self
+                .layer_colors
+                .iter()
+                .zip(buffer[6..LIGHTING_SERIAL_LENGTH].chunks_exact_mut(3))
+            {
+                color.into_buffer(chunk);
+            }
+            Ok(())
+        }
+    }
+
+    /// Period of a single breath at the configured speed
+    fn breath_period(&self) -> u64 {
+        SLOWEST_BREATH_MS
+            - (SLOWEST_BREATH_MS - FASTEST_BREATH_MS) * self.speed as u64 / u8::MAX as u64
+    }
+}
+
+impl<'a> Value<'a> for LightingSettings {
+    fn serialize_into(&self, buffer: &mut [u8]) -> Result<usize, SerializationError> {
+        self.into_buffer(buffer)?;
+        Ok(LIGHTING_SERIAL_LENGTH)
+    }
+
+    fn deserialize_from(buffer: &'a [u8]) -> Result<(Self, usize), SerializationError>
+    where
+        Self: Sized,
+    {
+        if buffer.len() < LIGHTING_SERIAL_LENGTH {
+            return Err(SerializationError::BufferTooSmall);
+        }
+        let effect =
+            LightingEffect::try_from(buffer[0]).map_err(|_| SerializationError::InvalidFormat)?;
+        let mut layer_colors = [Color::OFF; NUM_LAYERS];
+        for (color, chunk) in layer_colors
+            .iter_mut()
+            .zip(buffer[6..LIGHTING_SERIAL_LENGTH].chunks_exact(3))
+        {
+            *color = Color::from_buffer(chunk);
+        }
+        Ok((
+            Self {
+                effect,
+                color: Color::from_buffer(&buffer[1..4]),
+                brightness: buffer[4],
+                speed: buffer[5],
+                layer_colors,
+            },
+            LIGHTING_SERIAL_LENGTH,
+        ))
+    }
+}
+
+#[derive(Clone, Copy)]
+struct LightingInput {
+    layer: u8,
+    pressed: [bool; NUM_KEYS],
+}
+
+/// Updates the layer and pressed keys the lighting effects react to
+pub fn update_input(layer: usize, pressed: impl Fn(usize) -> bool) {
+    let mut input = LightingInput {
+        layer: layer as u8,
+        pressed: [false; NUM_KEYS],
+    };
+    for (i, key) in input.pressed.iter_mut().enumerate() {
+        *key = pressed(i);
+    }
+    INPUT.lock(|cell| cell.set(input));
+}
+
+/// Renders lighting effects into frames of led colors
+pub struct LightingEngine {
+    started: Instant,
+    // Time each key was last seen pressed
+    last_pressed: [Option<Instant>; NUM_KEYS],
+}
+
+impl LightingEngine {
+    pub fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            last_pressed: [None; NUM_KEYS],
+        }
+    }
+
+    /// Renders a frame into leds. The key index of each led is given by map,
+    /// so map and leds should be the same length
+    pub fn render(
+        &mut self,
+        settings: &LightingSettings,
+        now: Instant,
+        map: &[usize],
+        leds: &mut [Color],
+    ) {
+        let input = INPUT.lock(|cell| cell.get());
+        for (last, pressed) in self.last_pressed.iter_mut().zip(input.pressed) {
+            if pressed {
+                *last = Some(now);
+            }
+        }
+
+        // The reaction test cue ignores the settings so it shows with the
+        // leds off too
+        if reaction::is_cue_shown() {
+            leds.fill(Color::new(255, 255, 255));
+            return;
+        }
+        if let Some(color) = self_test::sweep_color(now) {
+            leds.fill(color);
+            return;
+        }
+
+        let color = settings.color.scale(settings.brightness);
+        match settings.effect {
+            LightingEffect::Off => leds.fill(Color::OFF),
+            LightingEffect::Static => leds.fill(color),
+            LightingEffect::Breathing => {
+                let period = settings.breath_period();
+                let half = period / 2;
+                let phase = now.saturating_duration_since(self.started).as_millis() % period;
+                let level = if phase < half {
+                    phase * 255 / half
+                } else {
+                    (period - phase) * 255 / half
+                };
+                leds.fill(color.scale(level.min(255) as u8));
+            }
+            LightingEffect::Reactive => {
+                for (led, key) in leds.iter_mut().zip(map) {
+                    *led = match self.last_pressed.get(*key).copied().flatten() {
+                        Some(time) => {
+                            let elapsed = now.saturating_duration_since(time);
+                            if elapsed >= REACTIVE_FADE {
+                                Color::OFF
+                            } else {
+                                let remaining = (REACTIVE_FADE - elapsed).as_millis();
+                                color.scale((remaining * 255 / REACTIVE_FADE.as_millis()) as u8)
+                            }
+                        }
+                        None => Color::OFF,
+                    };
+                }
+            }
+            LightingEffect::LayerColors => {
+                let layer_color = settings
+                    .layer_colors
+                    .get(input.layer as usize)
+                    .copied()
+                    .unwrap_or(Color::OFF);
+                leds.fill(layer_color.scale(settings.brightness));
+            }
+        }
+    }
+}
+
+impl Default for LightingEngine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Loads the stored lighting settings into [LIGHTING]. The defaults are kept
+/// if no settings were stored
+pub async fn load_lighting() -> LightingSettings {
+    let mut lighting = LIGHTING.lock().await;
+    if let Some(StorageItem::Lighting(stored)) = get_item(StorageKey::Lighting).await {
+        *lighting = stored;
+    }
+    *lighting
+}
+
+/// Updates [LIGHTING] and persists the new settings to storage
+pub async fn store_lighting(settings: LightingSettings) {
+    info!("Updating lighting to {}", settings);
+    *LIGHTING.lock().await = settings;
+    store_val(StorageKey::Lighting, &StorageItem::Lighting(settings)).await;
+}
@@ -0,0 +1,114 @@
+//! Link state of wireless halves streamed to the host with
+//! HidRequest::WatchLink, so a host daemon can tell when a half disconnects
+//! or its battery runs low without polling diagnostics.
+//!
+//! Each frame is 1 if the board has a wireless link followed by the
+//! [HalfLinkState] of the left and right half. Boards without a wireless link
+//! send a single frame of zeros and stop
+
+use defmt::Format;
+
+/// Length of a serialized [HalfLinkState]
+pub const HALF_LINK_STATE_SERIAL_LENGTH: usize = 8;
+
+/// Length of each frame sent by HidRequest::WatchLink
+pub const LINK_WATCH_FRAME_LEN: usize = 1 + 2 * HALF_LINK_STATE_SERIAL_LENGTH;
+
+/// Link state of a half as seen by the dongle
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Format)]
+pub struct HalfLinkState {
+    // Heard from recently enough to be considered connected
+    pub connected: bool,
+    // Sending its reports over its own usb connection
+    pub wired: bool,
+    pub battery_low: bool,
+    // Runs firmware whose link protocol doesn't match the dongle's
+    pub version_mismatch: bool,
+    // Signal strength of the last packet received in dBm. 0 if nothing was received
+    pub rssi: i8,
+    // Last battery reading sent by the half. 0 if it hasn't sent one
+    pub battery_mv: u16,
+    // Time since the half was last heard from
+    pub last_seen_ms: Option<u32>,
+}
+
+impl HalfLinkState {
+    const CONNECTED: u8 = 1 << 0;
+    const WIRED: u8 = 1 << 1;
+    const BATTERY_LOW: u8 = 1 << 2;
+    const VERSION_MISMATCH: u8 = 1 << 3;
+
+    pub const fn default() -> Self {
+        Self {
+            connected: false,
+            wired: false,
+            battery_low: false,
+            version_mismatch: false,
+            rssi: 0,
+            battery_mv: 0,
+            last_seen_ms: None,
+        }
+    }
+
+    /// Writes the flags, the rssi, the battery as a le u16 and the time since
+    /// the half was last seen as a le u32, where u32::MAX means never
+    pub fn into_buffer(&self, buffer: &mut [u8]) {
+        let mut flags = 0;
+        for (set, flag) in [
+            (self.connected, Self::CONNECTED),
+            (self.wired, Self::WIRED),
+            (self.battery_low, Self::BATTERY_LOW),
+            (self.version_mismatch, Self::VERSION_MISMATCH),
+        ] {
+            if set {
+                flags |= flag;
+            }
+        }
+        buffer[0] = flags;
+        buffer[1] = self.rssi as u8;
+        buffer[2..4].copy_from_slice(&self.battery_mv.to_le_bytes());
+        let last_seen = match self.last_seen_ms {
+            Some(ms) => ms.min(u32::MAX - 1),
+            None => u32::MAX,
+        };
+        buffer[4..8].copy_from_slice(&last_seen.to_le_bytes());
+    }
+}
+
+/// Returns the frame for the states of the left and right half
+pub fn link_watch_frame(halves: &[HalfLinkState; 2]) -> [u8; LINK_WATCH_FRAME_LEN] {
+    let mut frame = [0u8; LINK_WATCH_FRAME_LEN];
+    frame[0] = 1;
+    for (half, chunk) in halves
+        .iter()
+        .zip(frame[1..].chunks_exact_mut(HALF_LINK_STATE_SERIAL_LENGTH))
+    {
+        half.into_buffer(chunk);
+    }
+    frame
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frame_holds_both_halves() {
+        let left = HalfLinkState {
+            connected: true,
+            battery_low: true,
+            version_mismatch: true,
+            rssi: -60,
+            battery_mv: 3650,
+            last_seen_ms: Some(1200),
+            ..HalfLinkState::default()
+        };
+        let frame = link_watch_frame(&[left, HalfLinkState::default()]);
+        assert_eq!(frame[0], 1);
+        assert_eq!(
+            frame[1..9],
+            [0b1101, -60i8 as u8, 0x42, 0x0e, 0xb0, 0x04, 0, 0]
+        );
+        assert_eq!(frame[9..], [0, 0, 0, 0, 0xff, 0xff, 0xff, 0xff]);
+    }
+}
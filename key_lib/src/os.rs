@@ -0,0 +1,169 @@
+use core::cell::Cell;
+
+use defmt::{Format, info};
+use embassy_sync::{
+    blocking_mutex::{Mutex, raw::CriticalSectionRawMutex},
+    signal::Signal,
+};
+use embassy_time::{Duration, Timer};
+use num_enum::TryFromPrimitive;
+use sequential_storage::map::{SerializationError, Value};
+
+use crate::storage::{StorageItem, StorageKey, get_item, store_val};
+
+pub const HOST_OS_SERIAL_LENGTH: usize = 1;
+
+/// Time after the device is configured that requests are still counted. Hosts
+/// set up the hid interfaces right after they configure the device
+pub const DETECTION_TIME: Duration = Duration::from_millis(1000);
+
+/// String index Windows requests for the Microsoft OS 1.0 descriptor
+const MS_OS_STRING_INDEX: u8 = 0xee;
+
+/// Operating system of the host the board is plugged into
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Format, TryFromPrimitive)]
+pub enum HostOs {
+    Unknown = 0,
+    Windows = 1,
+    MacOs = 2,
+    Linux = 3,
+}
+
+impl<'a> Value<'a> for HostOs {
+    fn serialize_into(&self, buffer: &mut [u8]) -> Result<usize, SerializationError> {
+        if buffer.len() < HOST_OS_SERIAL_LENGTH {
+            return Err(SerializationError::BufferTooSmall);
+        }
+        buffer[0] = *self as u8;
+        Ok(HOST_OS_SERIAL_LENGTH)
+    }
+
+    fn deserialize_from(buffer: &'a [u8]) -> Result<(Self, usize), SerializationError>
+    where
+        Self: Sized,
+    {
+        let os = buffer.first().ok_or(SerializationError::BufferTooSmall)?;
+        let os = HostOs::try_from(*os).map_err(|_| SerializationError::InvalidFormat)?;
+        Ok((os, HOST_OS_SERIAL_LENGTH))
+    }
+}
+
+/// Requests counted while the host enumerates the board
+#[derive(Debug, Clone, Copy, Format)]
+struct Enumeration {
+    set_idles: u8,
+    led_reports: u8,
+    ms_os_string: bool,
+}
+
+impl Enumeration {
+    const fn default() -> Self {
+        Self {
+            set_idles: 0,
+            led_reports: 0,
+            ms_os_string: false,
+        }
+    }
+
+    // Windows sets the idle rate of every hid interface and asks for its os
+    // string. Linux only sets the idle rate of boot keyboards but syncs the
+    // lock leds as soon as the keyboard is bound. macOS does neither
+    fn guess(&self) -> HostOs {
+        if self.ms_os_string || self.set_idles > 0 {
+            HostOs::Windows
+        } else if self.led_reports > 0 {
+            HostOs::Linux
+        } else {
+            HostOs::MacOs
+        }
+    }
+}
+
+static ENUMERATION: Mutex<CriticalSectionRawMutex, Cell<Enumeration>> =
+    Mutex::new(Cell::new(Enumeration::default()));
+static DETECTED_OS: Mutex<CriticalSectionRawMutex, Cell<HostOs>> =
+    Mutex::new(Cell::new(HostOs::Unknown));
+/// Os set by the user. Unknown means the detected os is used
+static OS_OVERRIDE: Mutex<CriticalSectionRawMutex, Cell<HostOs>> =
+    Mutex::new(Cell::new(HostOs::Unknown));
+static CONFIGURED_SIGNAL: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Clears the requests counted so far. Should be called on a usb bus reset
+pub fn reset_detection() {
+    ENUMERATION.lock(|cell| cell.set(Enumeration::default()));
+    DETECTED_OS.lock(|cell| cell.set(HostOs::Unknown));
+}
+
+/// Should be called from the hid request handlers' set_idle_ms
+pub fn record_set_idle() {
+    ENUMERATION.lock(|cell| {
+        let mut enumeration = cell.get();
+        enumeration.set_idles = enumeration.set_idles.saturating_add(1);
+        cell.set(enumeration);
+    });
+}
+
+/// Should be called when the host sets the keyboard's lock leds
+pub fn record_led_report() {
+    ENUMERATION.lock(|cell| {
+        let mut enumeration = cell.get();
+        enumeration.led_reports = enumeration.led_reports.saturating_add(1);
+        cell.set(enumeration);
+    });
+}
+
+/// Should be called from the usb handler's get_string with the requested index
+pub fn record_string_request(index: u8) {
+    if index == MS_OS_STRING_INDEX {
+        ENUMERATION.lock(|cell| {
+            let mut enumeration = cell.get();
+            enumeration.ms_os_string = true;
+            cell.set(enumeration);
+        });
+    }
+}
+
+/// Should be called from the usb handler once the device is configured
+pub fn set_configured() {
+    CONFIGURED_SIGNAL.signal(());
+}
+
+/// Guesses the host os from the requests counted in the [DETECTION_TIME]
+/// after every configuration
+pub async fn detection_loop() -> ! {
+    loop {
+        CONFIGURED_SIGNAL.wait().await;
+        Timer::after(DETECTION_TIME).await;
+        let enumeration = ENUMERATION.lock(|cell| cell.get());
+        let os = enumeration.guess();
+        info!("Detected {} from {}", os, enumeration);
+        DETECTED_OS.lock(|cell| cell.set(os));
+    }
+}
+
+/// Returns the os set by the user, or the detected os if none was set
+pub fn host_os() -> HostOs {
+    match OS_OVERRIDE.lock(|cell| cell.get()) {
+        HostOs::Unknown => DETECTED_OS.lock(|cell| cell.get()),
+        os => os,
+    }
+}
+
+/// Loads the os set by the user from storage
+pub async fn load_os_override() -> HostOs {
+    let os = match get_item(StorageKey::HostOs).await {
+        Some(StorageItem::HostOs(os)) => os,
+        _ => HostOs::Unknown,
+    };
+    OS_OVERRIDE.lock(|cell| cell.set(os));
+    os
+}
+
+/// Sets the os used instead of the detected one and persists it to storage.
+/// Unknown goes back to detecting the os
+pub async fn store_os_override(os: HostOs) {
+    info!("Overriding host os with {}", os);
+    OS_OVERRIDE.lock(|cell| cell.set(os));
+    store_val(StorageKey::HostOs, &StorageItem::HostOs(os)).await;
+}
@@ -0,0 +1,75 @@
+//! Absolute pointer input from trackpads. A board runs [pointer_loop] with
+//! its [PointerSensors] and [digitizer_loop] with the writer of its digitizer
+//! endpoint, so the sensors can be read at their own rate next to the keys
+
+use defmt::{Format, error};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+use embassy_usb::{class::hid::HidWriter, driver::Driver};
+
+use crate::descriptor::DigitizerReport;
+
+/// Largest coordinate of a touch. Needs to match the logical max of the
+/// coordinates in [DigitizerReport]
+pub const POINTER_MAX: u16 = 4095;
+
+/// Position of a finger on the sensor
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Format)]
+pub struct Touch {
+    pub x: u16,
+    pub y: u16,
+}
+
+/// A sensor that reads absolute touch positions, e.g. an I2C or SPI trackpad
+pub trait PointerSensors {
+    /// Waits for the next reading of the sensor. Returns None while no finger
+    /// is on the sensor. Coordinates are scaled from 0 to [POINTER_MAX]
+    fn read_touch(&mut self) -> impl Future<Output = Option<Touch>>;
+}
+
+// Latest reading of the sensors. Readings the writer hasn't caught up with are
+// dropped since only the latest position matters
+static TOUCH_SIGNAL: Signal<CriticalSectionRawMutex, Option<Touch>> = Signal::new();
+
+impl DigitizerReport {
+    pub fn from_touch(touch: Option<Touch>) -> Self {
+        match touch {
+            Some(touch) => Self {
+                tip_switch: 1,
+                contact_id: 0,
+                x: touch.x.min(POINTER_MAX),
+                y: touch.y.min(POINTER_MAX),
+                contact_count: 1,
+            },
+            None => Self::default(),
+        }
+    }
+}
+
+/// Reads the sensors and passes the readings to [digitizer_loop]
+pub async fn pointer_loop<P: PointerSensors>(sensors: &mut P) -> ! {
+    loop {
+        let touch = sensors.read_touch().await;
+        TOUCH_SIGNAL.signal(touch);
+    }
+}
+
+/// Writes a report for every new reading. Only the first reading without a
+/// finger is sent so the host sees the finger lift once
+pub async fn digitizer_loop<'d, D: Driver<'d>, const N: usize>(
+    writer: &mut HidWriter<'d, D, N>,
+) -> ! {
+    let mut last = None;
+    loop {
+        let touch = TOUCH_SIGNAL.wait().await;
+        if touch.is_none() && last.is_none() {
+            continue;
+        }
+        last = touch;
+        if let Err(e) = writer
+            .write_serialize(&DigitizerReport::from_touch(touch))
+            .await
+        {
+            error!("Failed to write digitizer report: {}", e);
+        }
+    }
+}
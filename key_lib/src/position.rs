@@ -1,7 +1,35 @@
 #[cfg(feature = "hall-effect")]
-pub const DEFAULT_HIGH: u32 = 1700;
+use core::cell::Cell;
+use core::ops::Range;
+#[cfg(feature = "hall-effect")]
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use defmt::Format;
+#[cfg(feature = "hall-effect")]
+use defmt::info;
+use embassy_futures::select::select_array;
+#[cfg(feature = "hall-effect")]
+use embassy_sync::blocking_mutex::Mutex;
+#[cfg(feature = "hall-effect")]
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+use embassy_time::{Duration, Instant};
+use embedded_hal_1::digital::{InputPin, OutputPin};
+use embedded_hal_async::digital::Wait;
+use num_enum::TryFromPrimitive;
+use sequential_storage::map::{SerializationError, Value};
+
+use crate::error::{KeyLibError, record_error};
 #[cfg(feature = "hall-effect")]
+use crate::{
+    NUM_KEYS,
+    settings::SwitchProfile,
+    storage::{StorageItem, StorageKey, get_item, store_val},
+};
+
+pub const DEFAULT_HIGH: u32 = 1700;
 pub const DEFAULT_LOW: u32 = 1400;
+pub const KEY_CALIBRATION_SERIAL_LENGTH: usize = 4;
+pub const DRIFT_COMPENSATION_SERIAL_LENGTH: usize = 5;
 #[cfg(feature = "hall-effect")]
 const DIF: f32 = (DEFAULT_HIGH - DEFAULT_LOW) as f32;
 #[cfg(feature = "hall-effect")]
@@ -10,8 +38,393 @@ const DEFAULT_RELEASE_SCALE: f32 = 0.30;
 const DEFAULT_ACTUATE_SCALE: f32 = 0.35;
 #[cfg(feature = "hall-effect")]
 const TOLERANCE_SCALE: f32 = 0.1;
+// Time between steps of the rest point of a key that's being re-centered
+#[cfg(feature = "hall-effect")]
+const DRIFT_STEP_INTERVAL: Duration = Duration::from_secs(1);
+// Time between velocity samples. Shorter windows only see a percent or two of
+// travel per sample, which makes the velocity too coarse
+#[cfg(feature = "hall-effect")]
+const VELOCITY_SAMPLE_TIME: Duration = Duration::from_millis(2);
+
+/// Filter applied to the readings of every hall effect switch. Set by the
+/// board at boot with [set_adc_filter]
+#[cfg(feature = "hall-effect")]
+static ADC_FILTER: Mutex<CriticalSectionRawMutex, Cell<AdcFilter>> =
+    Mutex::new(Cell::new(AdcFilter::None));
+
+/// Drift compensation used by every hall effect switch. Loaded with the
+/// calibration and set over com with SettingId::DriftCompensation
+#[cfg(feature = "hall-effect")]
+static DRIFT_COMPENSATION: Mutex<CriticalSectionRawMutex, Cell<DriftCompensation>> =
+    Mutex::new(Cell::new(DriftCompensation::default()));
+
+/// Last time any hall effect switch was pressed. Rest points aren't moved
+/// while the board is typed on, since neighbouring keys and the flex of the
+/// plate move the readings of untouched keys
+#[cfg(feature = "hall-effect")]
+static LAST_PRESS: Mutex<CriticalSectionRawMutex, Cell<Instant>> =
+    Mutex::new(Cell::new(Instant::from_ticks(0)));
+
+/// Set while readings are streamed with HidRequest::StreamAnalog
+#[cfg(feature = "hall-effect")]
+static ANALOG_STREAMING: AtomicBool = AtomicBool::new(false);
+#[cfg(feature = "hall-effect")]
+static ANALOG_READINGS: Mutex<CriticalSectionRawMutex, Cell<[u16; NUM_KEYS]>> =
+    Mutex::new(Cell::new([0; NUM_KEYS]));
+
+/// Copies the averaged reading of each key so it can be streamed over com.
+/// Should be called from the scan loop after the positions are updated. Does
+/// nothing unless a stream is running
+#[cfg(feature = "hall-effect")]
+pub fn publish_readings<K: KeyState<Item = u16>>(positions: &[K]) {
+    if !ANALOG_STREAMING.load(Ordering::Relaxed) {
+        return;
+    }
+    let mut readings = [0; NUM_KEYS];
+    readings
+        .iter_mut()
+        .zip(positions.iter())
+        .for_each(|(reading, position)| *reading = position.get_buf());
+    ANALOG_READINGS.lock(|cell| cell.set(readings));
+}
+
 #[cfg(feature = "hall-effect")]
-const BUFFER_SIZE: usize = 1;
+pub(crate) fn set_analog_streaming(streaming: bool) {
+    ANALOG_STREAMING.store(streaming, Ordering::Relaxed);
+}
+
+#[cfg(feature = "hall-effect")]
+pub(crate) fn analog_readings() -> [u16; NUM_KEYS] {
+    ANALOG_READINGS.lock(|cell| cell.get())
+}
+
+/// Readings of a hall effect switch when it's released and fully pressed
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Format)]
+pub struct KeyCalibration {
+    pub highest_point: u16,
+    pub lowest_point: u16,
+}
+
+impl KeyCalibration {
+    /// Points switches start with before they're calibrated
+    pub const fn default() -> Self {
+        Self {
+            highest_point: DEFAULT_HIGH as u16,
+            lowest_point: DEFAULT_LOW as u16,
+        }
+    }
+}
+
+impl<'a> Value<'a> for KeyCalibration {
+    fn serialize_into(&self, buffer: &mut [u8]) -> Result<usize, SerializationError> {
+        if buffer.len() < KEY_CALIBRATION_SERIAL_LENGTH {
+            return Err(SerializationError::BufferTooSmall);
+        }
+        buffer[0..2].copy_from_slice(&self.highest_point.to_le_bytes());
+        buffer[2..4].copy_from_slice(&self.lowest_point.to_le_bytes());
+        Ok(KEY_CALIBRATION_SERIAL_LENGTH)
+    }
+
+    fn deserialize_from(buffer: &'a [u8]) -> Result<(Self, usize), SerializationError>
+    where
+        Self: Sized,
+    {
+        if buffer.len() < KEY_CALIBRATION_SERIAL_LENGTH {
+            return Err(SerializationError::BufferTooSmall);
+        }
+        let highest_point = u16::from_le_bytes([buffer[0], buffer[1]]);
+        let lowest_point = u16::from_le_bytes([buffer[2], buffer[3]]);
+        if highest_point < lowest_point {
+            return Err(SerializationError::InvalidFormat);
+        }
+        Ok((
+            Self {
+                highest_point,
+                lowest_point,
+            },
+            KEY_CALIBRATION_SERIAL_LENGTH,
+        ))
+    }
+}
+
+/// Slowly moves the rest point of keys that aren't touched for a while to
+/// their current reading. Rest readings of hall sensors drift with
+/// temperature, which otherwise leaves released keys with some travel or
+/// needing more travel to actuate
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Format)]
+pub struct DriftCompensation {
+    pub enabled: bool,
+    /// Seconds a key and the rest of the board have to be untouched before
+    /// its rest point moves
+    pub idle_secs: u16,
+    /// Most the rest point moves per second, in adc counts
+    pub step: u8,
+    /// Largest difference from the rest point in percent of travel that's
+    /// still taken as an untouched key
+    pub band_percent: u8,
+}
+
+impl DriftCompensation {
+    pub const fn default() -> Self {
+        Self {
+            enabled: false,
+            idle_secs: 30,
+            step: 1,
+            band_percent: 5,
+        }
+    }
+}
+
+impl<'a> Value<'a> for DriftCompensation {
+    fn serialize_into(&self, buffer: &mut [u8]) -> Result<usize, SerializationError> {
+        if buffer.len() < DRIFT_COMPENSATION_SERIAL_LENGTH {
+            return Err(SerializationError::BufferTooSmall);
+        }
+        buffer[0] = self.enabled as u8;
+        buffer[1..3].copy_from_slice(&self.idle_secs.to_le_bytes());
+        buffer[3] = self.step;
+        buffer[4] = self.band_percent;
+        Ok(DRIFT_COMPENSATION_SERIAL_LENGTH)
+    }
+
+    fn deserialize_from(buffer: &'a [u8]) -> Result<(Self, usize), SerializationError>
+    where
+        Self: Sized,
+    {
+        if buffer.len() < DRIFT_COMPENSATION_SERIAL_LENGTH {
+            return Err(SerializationError::BufferTooSmall);
+        }
+        let settings = Self {
+            enabled: buffer[0] != 0,
+            idle_secs: u16::from_le_bytes([buffer[1], buffer[2]]),
+            step: buffer[3],
+            band_percent: buffer[4],
+        };
+        // A band past half the travel would follow keys that are pressed
+        if settings.idle_secs == 0
+            || settings.step == 0
+            || settings.band_percent == 0
+            || settings.band_percent > 50
+        {
+            return Err(SerializationError::InvalidFormat);
+        }
+        Ok((settings, DRIFT_COMPENSATION_SERIAL_LENGTH))
+    }
+}
+
+/// Replaces the drift compensation used by every hall effect switch
+#[cfg(feature = "hall-effect")]
+pub fn set_drift_compensation(settings: DriftCompensation) {
+    info!("Drift compensation set to {}", settings);
+    DRIFT_COMPENSATION.lock(|cell| cell.set(settings));
+}
+
+/// Re-centers the rest point of a key after it and the board were untouched
+/// for long enough
+#[derive(Copy, Clone, Default, Debug)]
+#[cfg(feature = "hall-effect")]
+struct DriftTracker {
+    // Time since the key's reading is in the band around its rest point
+    idle_since: Option<Instant>,
+    last_step: Option<Instant>,
+}
+
+#[cfg(feature = "hall-effect")]
+impl DriftTracker {
+    const DEFAULT: Self = Self {
+        idle_since: None,
+        last_step: None,
+    };
+
+    /// Returns the new rest point if it should move toward the reading
+    fn update(
+        &mut self,
+        reading: u16,
+        pressed: bool,
+        highest_point: u16,
+        lowest_point: u16,
+        now: Instant,
+    ) -> Option<u16> {
+        if pressed {
+            LAST_PRESS.lock(|cell| cell.set(now));
+            self.idle_since = None;
+            return None;
+        }
+        let settings = DRIFT_COMPENSATION.lock(|cell| cell.get());
+        if !settings.enabled {
+            return None;
+        }
+        let band = (highest_point.saturating_sub(lowest_point) as u32
+            * settings.band_percent as u32
+            / 100) as u16;
+        if reading < highest_point.saturating_sub(band) {
+            self.idle_since = None;
+            return None;
+        }
+        let idle_since = *self.idle_since.get_or_insert(now);
+        let idle = Duration::from_secs(settings.idle_secs as u64);
+        let last_press = LAST_PRESS.lock(|cell| cell.get());
+        if now.saturating_duration_since(idle_since) < idle
+            || now.saturating_duration_since(last_press) < idle
+            || self
+                .last_step
+                .is_some_and(|last| now.saturating_duration_since(last) < DRIFT_STEP_INTERVAL)
+        {
+            return None;
+        }
+        // Readings above the rest point already raise it while calibrating,
+        // so only a rest reading that sank is followed
+        let target = reading.max(lowest_point.saturating_add(1));
+        if target >= highest_point {
+            return None;
+        }
+        self.last_step = Some(now);
+        Some(highest_point - (highest_point - target).min(settings.step as u16))
+    }
+
+    fn reset(&mut self) {
+        *self = Self::DEFAULT;
+    }
+}
+
+/// Calibration steps requested with HidRequest::Calibration
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Format, TryFromPrimitive)]
+pub enum CalibrationCommand {
+    /// Forgets the calibrated points so they're learned again from the
+    /// current readings. Every key should then be pressed all the way down
+    Start = 0,
+    /// Writes the calibrated points of every key to storage
+    Save = 1,
+    /// Goes back to the default points and clears the stored calibration
+    Clear = 2,
+}
+
+#[cfg(feature = "hall-effect")]
+static CALIBRATION_SIGNAL: Signal<CriticalSectionRawMutex, CalibrationCommand> = Signal::new();
+
+/// Queues the calibration command for the next handle_calibration call
+#[cfg(feature = "hall-effect")]
+pub fn request_calibration(command: CalibrationCommand) {
+    CALIBRATION_SIGNAL.signal(command);
+}
+
+/// Loads the stored calibration of each key and the drift compensation.
+/// Should be called before the scan loop starts
+#[cfg(feature = "hall-effect")]
+pub async fn load_calibration<K: KeyState<Item = u16>>(positions: &mut [K]) {
+    if let Some(StorageItem::DriftCompensation(settings)) =
+        get_item(StorageKey::DriftCompensation).await
+    {
+        set_drift_compensation(settings);
+    }
+    for (key, position) in positions.iter_mut().enumerate() {
+        if position.calibration().is_none() {
+            continue;
+        }
+        if let Some(StorageItem::Calibration(calibration)) =
+            get_item(StorageKey::Calibration { key }).await
+        {
+            position.set_calibration(calibration);
+        }
+    }
+}
+
+/// Runs the calibration command requested over com, if any. Should be called
+/// from the scan loop
+#[cfg(feature = "hall-effect")]
+pub async fn handle_calibration<K: KeyState<Item = u16>>(positions: &mut [K]) {
+    let Some(command) = CALIBRATION_SIGNAL.try_take() else {
+        return;
+    };
+    info!("Running calibration command {}", command);
+    for (key, position) in positions.iter_mut().enumerate() {
+        let Some(calibration) = position.calibration() else {
+            continue;
+        };
+        match command {
+            CalibrationCommand::Start => {
+                let reading = position.get_buf();
+                position.set_calibration(KeyCalibration {
+                    highest_point: reading,
+                    lowest_point: reading,
+                });
+            }
+            CalibrationCommand::Save => {
+                store_val(
+                    StorageKey::Calibration { key },
+                    &StorageItem::Calibration(calibration),
+                )
+                .await;
+            }
+            CalibrationCommand::Clear => {
+                // Storing the default points acts the same as having none stored
+                position.set_calibration(KeyCalibration::default());
+                store_val(
+                    StorageKey::Calibration { key },
+                    &StorageItem::Calibration(KeyCalibration::default()),
+                )
+                .await;
+            }
+        }
+    }
+}
+
+/// Tracks how fast a key is pressed down. Velocity is in percent of travel per
+/// 10ms and is the fastest seen since the key last moved up
+#[derive(Copy, Clone, Debug)]
+#[cfg(feature = "hall-effect")]
+struct VelocityTracker {
+    last_travel: u8,
+    last_sample: Instant,
+    peak: u8,
+}
+
+#[cfg(feature = "hall-effect")]
+impl VelocityTracker {
+    const DEFAULT: Self = Self {
+        last_travel: 0,
+        last_sample: Instant::MIN,
+        peak: 0,
+    };
+
+    fn update(&mut self, travel: u8, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last_sample);
+        if elapsed < VELOCITY_SAMPLE_TIME {
+            return;
+        }
+        if travel < self.last_travel {
+            self.peak = 0;
+        } else {
+            let velocity = (travel - self.last_travel) as u64 * 10_000 / elapsed.as_micros().max(1);
+            self.peak = self.peak.max(velocity.min(u8::MAX as u64) as u8);
+        }
+        self.last_travel = travel;
+        self.last_sample = now;
+    }
+
+    fn reset(&mut self) {
+        self.last_travel = 0;
+        self.peak = 0;
+    }
+}
+
+#[cfg(feature = "hall-effect")]
+impl Default for VelocityTracker {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+// Returns how far a key is pressed as a percentage of its calibrated range
+#[cfg(feature = "hall-effect")]
+fn travel_percent(reading: u16, highest_point: u16, lowest_point: u16) -> u8 {
+    if highest_point <= lowest_point {
+        return 0;
+    }
+    let travel = highest_point.saturating_sub(reading) as u32 * 100;
+    (travel / (highest_point - lowest_point) as u32).min(100) as u8
+}
 
 pub trait KeyState: Copy {
     const DEFAULT: Self;
@@ -20,6 +433,18 @@ pub trait KeyState: Copy {
 
     fn is_pressed(&self) -> bool;
 
+    /// Returns how far the key is pressed from 0 to 100. Switches that can't
+    /// measure travel are either fully pressed or released
+    fn travel(&self) -> u8 {
+        if self.is_pressed() { 100 } else { 0 }
+    }
+
+    /// Returns the fastest the key moved down since it last moved up, in
+    /// percent of travel per 10ms. Switches that can't measure travel return 0
+    fn velocity(&self) -> u8 {
+        0
+    }
+
     fn reset(&mut self);
 
     #[cfg(feature = "hall-effect")]
@@ -33,6 +458,21 @@ pub trait KeyState: Copy {
 
     #[cfg(feature = "hall-effect")]
     fn setup(&mut self, buf: Self::Item) -> bool;
+
+    /// Returns the calibrated points of switches that calibrate themselves
+    #[cfg(feature = "hall-effect")]
+    fn calibration(&self) -> Option<KeyCalibration> {
+        None
+    }
+
+    /// Replaces the calibrated points, e.g. with points loaded from storage
+    #[cfg(feature = "hall-effect")]
+    fn set_calibration(&mut self, _calibration: KeyCalibration) {}
+
+    /// Updates the travel of a key measured by the other half of a split
+    /// board or by a wireless half. Switches that only follow the pressed
+    /// state ignore it
+    fn update_depth(&mut self, _travel: u8) {}
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -74,49 +514,216 @@ impl KeyState for DefaultSwitch {
     }
 }
 
+/// Time a held [RampSwitch] takes to reach full travel
+pub const RAMP_TIME: Duration = Duration::from_millis(300);
+
+/// Mechanical switch whose travel ramps up from 0 to 100 over [RAMP_TIME]
+/// while it's held. Lets boards without hall effect switches use the
+/// behaviors that read travel, like analog layers and dual stage keys, by
+/// holding keys for longer instead of pressing them deeper
+#[derive(Copy, Clone, Debug)]
+pub struct RampSwitch {
+    pressed_at: Option<Instant>,
+}
+
+impl RampSwitch {
+    fn travel_at(&self, now: Instant) -> u8 {
+        let Some(pressed_at) = self.pressed_at else {
+            return 0;
+        };
+        let held = now.saturating_duration_since(pressed_at).as_micros();
+        (held * 100 / RAMP_TIME.as_micros()).min(100) as u8
+    }
+}
+
+impl KeyState for RampSwitch {
+    const DEFAULT: Self = Self { pressed_at: None };
+    type Item = bool;
+    fn update_buf(&mut self, buf: Self::Item) {
+        if !buf {
+            self.pressed_at = None;
+        } else if self.pressed_at.is_none() {
+            self.pressed_at = Some(Instant::now());
+        }
+    }
+
+    fn is_pressed(&self) -> bool {
+        self.pressed_at.is_some()
+    }
+
+    fn travel(&self) -> u8 {
+        self.travel_at(Instant::now())
+    }
+
+    fn reset(&mut self) {
+        self.pressed_at = None;
+    }
+
+    #[cfg(feature = "hall-effect")]
+    fn is_analog(&self) -> bool {
+        false
+    }
+
+    #[cfg(feature = "hall-effect")]
+    fn calibrate(&mut self, _: Self::Item) {}
+
+    #[cfg(feature = "hall-effect")]
+    fn get_buf(&self) -> Self::Item {
+        self.is_pressed()
+    }
+
+    #[cfg(feature = "hall-effect")]
+    fn setup(&mut self, _: Self::Item) -> bool {
+        true
+    }
+}
+
+/// Filter that smooths the adc readings of hall effect switches before they're
+/// compared to the actuation points, so noise doesn't trigger rapid trigger
+/// releases
+#[cfg(feature = "hall-effect")]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Format)]
+pub enum AdcFilter {
+    /// Readings are used as is
+    None,
+    /// Exponential moving average, where each reading moves the output by
+    /// alpha / 256 of its difference to the output. Lower alphas smooth more
+    /// but follow the key slower
+    Ema { alpha: u8 },
+    /// Median of the last 3 readings. Drops single noisy readings at the cost
+    /// of a reading of latency
+    Median3,
+}
+
+/// Sets the filter used by every hall effect switch. Boards should call it
+/// before the first scan
+#[cfg(feature = "hall-effect")]
+pub fn set_adc_filter(filter: AdcFilter) {
+    info!("Filtering adc readings with {}", filter);
+    ADC_FILTER.lock(|cell| cell.set(filter));
+}
+
+#[cfg(feature = "hall-effect")]
+fn adc_filter() -> AdcFilter {
+    ADC_FILTER.lock(|cell| cell.get())
+}
+
+// State of the filters of a key. Both filters are kept up to date so the
+// filter can be changed without a jump in the output
+#[cfg(feature = "hall-effect")]
+#[derive(Copy, Clone, Default, Debug)]
+struct ReadingFilter {
+    // Last 3 readings, newest first
+    samples: [u16; 3],
+    // Moving average scaled by 256 so small alphas still move it
+    average: u32,
+    // 0 until the first reading
+    output: u16,
+}
+
+#[cfg(feature = "hall-effect")]
+impl ReadingFilter {
+    const DEFAULT: Self = Self {
+        samples: [0; 3],
+        average: 0,
+        output: 0,
+    };
+
+    // Filters the reading and returns the new output. The first reading is
+    // used as is
+    fn apply(&mut self, filter: AdcFilter, reading: u16) -> u16 {
+        if self.output == 0 {
+            self.fill(reading);
+            return reading;
+        }
+        self.samples = [reading, self.samples[0], self.samples[1]];
+        let alpha = match filter {
+            AdcFilter::Ema { alpha } => alpha.max(1),
+            _ => u8::MAX,
+        } as i64;
+        let average = self.average as i64;
+        let target = (reading as i64) << 8;
+        self.average = (average + (target - average) * alpha / 256) as u32;
+        self.output = match filter {
+            AdcFilter::None => reading,
+            AdcFilter::Ema { .. } => (self.average >> 8) as u16,
+            AdcFilter::Median3 => {
+                let [a, b, c] = self.samples;
+                a.max(b).min(a.min(b).max(c))
+            }
+        };
+        self.output
+    }
+
+    // Sets the output and every stored reading to the reading
+    fn fill(&mut self, reading: u16) {
+        self.samples = [reading; 3];
+        self.average = (reading as u32) << 8;
+        self.output = reading;
+    }
+}
+
 // Makes hall effect switches act like a normal mechanical switch
 #[cfg(feature = "hall-effect")]
 #[derive(Copy, Clone, Default, Debug)]
 pub struct DigitalPosition {
-    buffer: [u16; BUFFER_SIZE], // Take multiple readings to smooth out buffer
-    buffer_pos: usize,
+    filter: ReadingFilter,
     release_point: u16,
     actuation_point: u16,
     lowest_point: u16,
     highest_point: u16,
     pressed: bool,
+    velocity: VelocityTracker,
+    drift: DriftTracker,
+}
+
+#[cfg(feature = "hall-effect")]
+impl DigitalPosition {
+    // Moves the actuation and release points to match the calibrated range
+    fn update_points(&mut self) {
+        let dif = (self.highest_point - self.lowest_point) as f32;
+        self.release_point = self.highest_point - (DEFAULT_RELEASE_SCALE * dif) as u16;
+        self.actuation_point = self.highest_point - (DEFAULT_ACTUATE_SCALE * dif) as u16;
+    }
 }
 
 #[cfg(feature = "hall-effect")]
 impl KeyState for DigitalPosition {
     type Item = u16;
     const DEFAULT: Self = Self {
-        buffer: [0; BUFFER_SIZE],
-        buffer_pos: 0,
+        filter: ReadingFilter::DEFAULT,
         release_point: (DEFAULT_HIGH - (DEFAULT_RELEASE_SCALE * DIF) as u32) as u16,
         actuation_point: (DEFAULT_HIGH - (DEFAULT_ACTUATE_SCALE * DIF) as u32) as u16,
         pressed: false,
         lowest_point: DEFAULT_LOW as u16,
         highest_point: DEFAULT_HIGH as u16,
+        velocity: VelocityTracker::DEFAULT,
+        drift: DriftTracker::DEFAULT,
     };
 
     // is_pressed is set like a normal mechanical switch, where if the buf
     // is higher than the release point, is_pressed is false, and if
     // the buf is lower than the acutation point, is_pressed is true
     fn update_buf(&mut self, pos: u16) {
-        self.buffer[self.buffer_pos] = pos;
-        self.buffer_pos = (self.buffer_pos + 1) % BUFFER_SIZE;
-        let mut sum = 0;
-        for buf in self.buffer {
-            sum += buf;
-        }
-        let avg = sum / BUFFER_SIZE as u16;
+        let avg = self.filter.apply(adc_filter(), pos);
         self.calibrate(avg);
         if avg <= self.actuation_point {
             self.pressed = true;
         } else if avg > self.release_point {
             self.pressed = false;
         }
+        let now = Instant::now();
+        self.velocity.update(self.travel(), now);
+        if let Some(highest_point) = self.drift.update(
+            avg,
+            self.pressed,
+            self.highest_point,
+            self.lowest_point,
+            now,
+        ) {
+            self.highest_point = highest_point;
+            self.update_points();
+        }
     }
 
     fn is_pressed(&self) -> bool {
@@ -124,27 +731,17 @@ impl KeyState for DigitalPosition {
     }
 
     fn get_buf(&self) -> u16 {
-        let mut sum = 0;
-        for buf in self.buffer {
-            sum += buf;
-        }
-        sum / BUFFER_SIZE as u16
+        self.filter.output
     }
 
     // Keep calling this function with adc readings
     // until it returns true to calibrate keys
     fn setup(&mut self, reading: u16) -> bool {
-        if self.buffer[0] == 0 || self.buffer_pos != 0 {
-            self.buffer[self.buffer_pos] = reading;
-            self.buffer_pos = (self.buffer_pos + 1) % BUFFER_SIZE;
+        if self.filter.output == 0 {
+            self.filter.apply(adc_filter(), reading);
             false
         } else {
-            let mut buf = 0;
-            for num in self.buffer {
-                buf += num;
-            }
-            let avg = buf / BUFFER_SIZE as u16;
-            self.calibrate(avg);
+            self.calibrate(self.filter.output);
             true
         }
     }
@@ -160,28 +757,48 @@ impl KeyState for DigitalPosition {
         }
 
         if changed {
-            let dif = (self.highest_point - self.lowest_point) as f32;
-            self.release_point = self.highest_point - (DEFAULT_RELEASE_SCALE * dif) as u16;
-            self.actuation_point = self.highest_point - (DEFAULT_ACTUATE_SCALE * dif) as u16;
+            self.update_points();
         }
     }
 
+    fn calibration(&self) -> Option<KeyCalibration> {
+        Some(KeyCalibration {
+            highest_point: self.highest_point,
+            lowest_point: self.lowest_point,
+        })
+    }
+
+    fn set_calibration(&mut self, calibration: KeyCalibration) {
+        self.highest_point = calibration.highest_point;
+        self.lowest_point = calibration.lowest_point;
+        self.update_points();
+        self.reset();
+    }
+
     fn is_analog(&self) -> bool {
         true
     }
 
+    fn travel(&self) -> u8 {
+        travel_percent(self.get_buf(), self.highest_point, self.lowest_point)
+    }
+
+    fn velocity(&self) -> u8 {
+        self.velocity.peak
+    }
+
     fn reset(&mut self) {
-        self.buffer.fill(self.highest_point);
-        self.buffer_pos = 0;
+        self.filter.fill(self.highest_point);
         self.pressed = false;
+        self.velocity.reset();
+        self.drift.reset();
     }
 }
 
 #[derive(Copy, Clone, Default, Debug)]
 #[cfg(feature = "hall-effect")]
 pub struct WootingPosition {
-    buffer: [u16; BUFFER_SIZE], // Take multiple readings to smooth out buffer
-    buffer_pos: usize,
+    filter: ReadingFilter,
     release_point: u16,
     actuation_point: u16,
     lowest_point: u16,
@@ -190,15 +807,28 @@ pub struct WootingPosition {
     last_pos: u16,
     wooting: bool,
     tolerance: u16,
+    velocity: VelocityTracker,
+    drift: DriftTracker,
+}
+
+#[cfg(feature = "hall-effect")]
+impl WootingPosition {
+    // Moves the actuation and release points and the rapid trigger tolerance
+    // to match the calibrated range
+    fn update_points(&mut self) {
+        let dif = (self.highest_point - self.lowest_point) as f32;
+        self.release_point = self.highest_point - (DEFAULT_RELEASE_SCALE * dif) as u16;
+        self.actuation_point = self.highest_point - (DEFAULT_ACTUATE_SCALE * dif) as u16;
+        self.tolerance = (dif * TOLERANCE_SCALE) as u16;
+    }
 }
 
 #[cfg(feature = "hall-effect")]
 impl KeyState for WootingPosition {
     type Item = u16;
     const DEFAULT: Self = Self {
-        buffer: [0; BUFFER_SIZE],
+        filter: ReadingFilter::DEFAULT,
         last_pos: 0,
-        buffer_pos: 0,
         release_point: (DEFAULT_HIGH - (DEFAULT_RELEASE_SCALE * DIF) as u32) as u16,
         actuation_point: (DEFAULT_HIGH - (DEFAULT_ACTUATE_SCALE * DIF) as u32) as u16,
         lowest_point: DEFAULT_LOW as u16,
@@ -206,16 +836,12 @@ impl KeyState for WootingPosition {
         pressed: false,
         wooting: false,
         tolerance: (DIF * TOLERANCE_SCALE) as u16,
+        velocity: VelocityTracker::DEFAULT,
+        drift: DriftTracker::DEFAULT,
     };
 
     fn update_buf(&mut self, pos: u16) {
-        self.buffer[self.buffer_pos] = pos;
-        self.buffer_pos = (self.buffer_pos + 1) % BUFFER_SIZE;
-        let mut sum = 0;
-        for buf in self.buffer {
-            sum += buf;
-        }
-        let avg = sum / BUFFER_SIZE as u16;
+        let avg = self.filter.apply(adc_filter(), pos);
         if avg > self.release_point {
             self.last_pos = avg;
             self.wooting = false;
@@ -236,6 +862,18 @@ impl KeyState for WootingPosition {
             self.last_pos = avg;
             self.pressed = false;
         }
+        let now = Instant::now();
+        self.velocity.update(self.travel(), now);
+        if let Some(highest_point) = self.drift.update(
+            avg,
+            self.pressed,
+            self.highest_point,
+            self.lowest_point,
+            now,
+        ) {
+            self.highest_point = highest_point;
+            self.update_points();
+        }
     }
 
     fn calibrate(&mut self, buf: u16) {
@@ -249,25 +887,30 @@ impl KeyState for WootingPosition {
         }
 
         if changed {
-            let dif = (self.highest_point - self.lowest_point) as f32;
-            self.release_point = self.highest_point - (DEFAULT_RELEASE_SCALE * dif) as u16;
-            self.actuation_point = self.highest_point - (DEFAULT_ACTUATE_SCALE * dif) as u16;
-            self.tolerance = (dif * TOLERANCE_SCALE) as u16;
+            self.update_points();
         }
     }
 
+    fn calibration(&self) -> Option<KeyCalibration> {
+        Some(KeyCalibration {
+            highest_point: self.highest_point,
+            lowest_point: self.lowest_point,
+        })
+    }
+
+    fn set_calibration(&mut self, calibration: KeyCalibration) {
+        self.highest_point = calibration.highest_point;
+        self.lowest_point = calibration.lowest_point;
+        self.update_points();
+        self.reset();
+    }
+
     fn setup(&mut self, reading: u16) -> bool {
-        if self.buffer[0] == 0 || self.buffer_pos != 0 {
-            self.buffer[self.buffer_pos] = reading;
-            self.buffer_pos = (self.buffer_pos + 1) % BUFFER_SIZE;
+        if self.filter.output == 0 {
+            self.filter.apply(adc_filter(), reading);
             false
         } else {
-            let mut buf = 0;
-            for num in self.buffer {
-                buf += num;
-            }
-            let avg = buf / BUFFER_SIZE as u16;
-            self.calibrate(avg);
+            self.calibrate(self.filter.output);
             true
         }
     }
@@ -277,30 +920,88 @@ impl KeyState for WootingPosition {
     }
 
     fn get_buf(&self) -> u16 {
-        let mut sum = 0;
-        for buf in self.buffer {
-            sum += buf;
-        }
-        sum / BUFFER_SIZE as u16
+        self.filter.output
     }
 
     fn is_analog(&self) -> bool {
         true
     }
 
+    fn travel(&self) -> u8 {
+        travel_percent(self.get_buf(), self.highest_point, self.lowest_point)
+    }
+
+    fn velocity(&self) -> u8 {
+        self.velocity.peak
+    }
+
     fn reset(&mut self) {
-        self.buffer.fill(self.highest_point);
+        self.filter.fill(self.highest_point);
         self.pressed = false;
         self.wooting = false;
-        self.buffer_pos = 0;
+        self.velocity.reset();
+        self.drift.reset();
     }
 }
 
+// Actuation and release points and the rapid trigger tolerance of a slave
+// key in percent of travel, matching the points of the local switches
+#[cfg(feature = "hall-effect")]
+const SLAVE_ACTUATE_TRAVEL: u8 = (DEFAULT_ACTUATE_SCALE * 100.0) as u8;
+#[cfg(feature = "hall-effect")]
+const SLAVE_RELEASE_TRAVEL: u8 = (DEFAULT_RELEASE_SCALE * 100.0) as u8;
+#[cfg(feature = "hall-effect")]
+const SLAVE_TOLERANCE: u8 = (TOLERANCE_SCALE * 100.0) as u8;
+
+/// Key of the other half of a split board. Follows the pressed state sent by
+/// the other half until it streams the key's travel, after which the key is
+/// pressed by its travel and switch profile like the local keys
 #[derive(Copy, Clone)]
 #[cfg(feature = "hall-effect")]
 pub struct SlavePosition {
     state: u16,
     analog_reading: u16,
+    profile: SwitchProfile,
+    // Last streamed travel, None until the other half streams it
+    depth: Option<u8>,
+    pressed: bool,
+    last_depth: u8,
+    wooting: bool,
+    velocity: VelocityTracker,
+}
+
+#[cfg(feature = "hall-effect")]
+impl SlavePosition {
+    // Same points as WootingPosition and DigitalPosition, in travel instead
+    // of readings
+    fn update_pressed(&mut self, depth: u8) {
+        match self.profile {
+            SwitchProfile::Digital => {
+                if depth >= SLAVE_ACTUATE_TRAVEL {
+                    self.pressed = true;
+                } else if depth < SLAVE_RELEASE_TRAVEL {
+                    self.pressed = false;
+                }
+            }
+            SwitchProfile::Wooting => {
+                if depth < SLAVE_RELEASE_TRAVEL {
+                    self.last_depth = depth;
+                    self.wooting = false;
+                    self.pressed = false;
+                } else if depth >= 100
+                    || depth > self.last_depth.saturating_add(SLAVE_TOLERANCE)
+                    || (depth >= SLAVE_ACTUATE_TRAVEL && !self.wooting)
+                {
+                    self.last_depth = depth;
+                    self.wooting = true;
+                    self.pressed = true;
+                } else if depth < self.last_depth.saturating_sub(SLAVE_TOLERANCE) {
+                    self.last_depth = depth;
+                    self.pressed = false;
+                }
+            }
+        }
+    }
 }
 
 #[cfg(feature = "hall-effect")]
@@ -308,6 +1009,12 @@ impl KeyState for SlavePosition {
     const DEFAULT: Self = Self {
         state: 0,
         analog_reading: u16::MAX,
+        profile: SwitchProfile::Wooting,
+        depth: None,
+        pressed: false,
+        last_depth: 0,
+        wooting: false,
+        velocity: VelocityTracker::DEFAULT,
     };
     type Item = u16;
 
@@ -324,7 +1031,28 @@ impl KeyState for SlavePosition {
     }
 
     fn is_pressed(&self) -> bool {
-        self.state != 0
+        match self.depth {
+            Some(_) => self.pressed,
+            None => self.state != 0,
+        }
+    }
+
+    fn travel(&self) -> u8 {
+        match self.depth {
+            Some(depth) => depth,
+            None if self.state != 0 => 100,
+            None => 0,
+        }
+    }
+
+    fn velocity(&self) -> u8 {
+        self.velocity.peak
+    }
+
+    fn update_depth(&mut self, travel: u8) {
+        self.depth = Some(travel);
+        self.update_pressed(travel);
+        self.velocity.update(travel, Instant::now());
     }
 
     fn is_analog(&self) -> bool {
@@ -332,8 +1060,10 @@ impl KeyState for SlavePosition {
     }
 
     fn reset(&mut self) {
-        self.state = 0;
-        self.analog_reading = u16::MAX;
+        *self = Self {
+            profile: self.profile,
+            ..Self::DEFAULT
+        };
     }
 
     fn calibrate(&mut self, _: Self::Item) {}
@@ -381,6 +1111,28 @@ impl KeyState for HeSwitch {
         }
     }
 
+    fn travel(&self) -> u8 {
+        match self {
+            HeSwitch::Wooting(wp) => wp.travel(),
+            HeSwitch::Digital(dp) => dp.travel(),
+            HeSwitch::Slave(sp) => sp.travel(),
+        }
+    }
+
+    fn velocity(&self) -> u8 {
+        match self {
+            HeSwitch::Wooting(wp) => wp.velocity(),
+            HeSwitch::Digital(dp) => dp.velocity(),
+            HeSwitch::Slave(sp) => sp.velocity(),
+        }
+    }
+
+    fn update_depth(&mut self, travel: u8) {
+        if let HeSwitch::Slave(sp) = self {
+            sp.update_depth(travel);
+        }
+    }
+
     fn is_analog(&self) -> bool {
         true
     }
@@ -408,6 +1160,50 @@ impl KeyState for HeSwitch {
             HeSwitch::Slave(sp) => sp.setup(buf),
         }
     }
+
+    fn calibration(&self) -> Option<KeyCalibration> {
+        match self {
+            HeSwitch::Wooting(wp) => wp.calibration(),
+            HeSwitch::Digital(dp) => dp.calibration(),
+            HeSwitch::Slave(sp) => sp.calibration(),
+        }
+    }
+
+    fn set_calibration(&mut self, calibration: KeyCalibration) {
+        match self {
+            HeSwitch::Wooting(wp) => wp.set_calibration(calibration),
+            HeSwitch::Digital(dp) => dp.set_calibration(calibration),
+            HeSwitch::Slave(sp) => sp.set_calibration(calibration),
+        }
+    }
+}
+
+#[cfg(feature = "hall-effect")]
+impl HeSwitch {
+    /// Switches the key to the profile while keeping its calibration. Slave
+    /// keys keep their type and use the profile once their travel is streamed
+    pub fn set_profile(&mut self, profile: SwitchProfile) {
+        let (highest_point, lowest_point) = match (&mut *self, profile) {
+            (HeSwitch::Slave(sp), _) => {
+                sp.profile = profile;
+                return;
+            }
+            (HeSwitch::Wooting(_), SwitchProfile::Wooting)
+            | (HeSwitch::Digital(_), SwitchProfile::Digital) => return,
+            (HeSwitch::Wooting(wp), _) => (wp.highest_point, wp.lowest_point),
+            (HeSwitch::Digital(dp), _) => (dp.highest_point, dp.lowest_point),
+        };
+        let mut switch = match profile {
+            SwitchProfile::Wooting => HeSwitch::Wooting(WootingPosition::DEFAULT),
+            SwitchProfile::Digital => HeSwitch::Digital(DigitalPosition::DEFAULT),
+        };
+        // Calibration only widens the range, so the defaults are always
+        // within the calibrated points
+        switch.calibrate(highest_point);
+        switch.calibrate(lowest_point);
+        switch.reset();
+        *self = switch;
+    }
 }
 
 pub trait KeySensors {
@@ -423,3 +1219,346 @@ pub trait KeySensors {
         positions: &mut [K],
     ) -> impl core::future::Future<Output = ()>;
 }
+
+/// Filters the raw readings of a switch so contact bounce isn't
+/// reported as multiple presses
+pub trait Debouncer: Copy {
+    /// Returns the pressed status of the position
+    fn is_pressed(&self) -> bool;
+
+    /// Updates the buf of the key. Updating the buf will also update
+    /// the value returned from the is_pressed function
+    fn update_buf(&mut self, buf: bool);
+
+    /// Time a released key can still change state. The matrix won't go to
+    /// sleep until all keys have been released for this long
+    fn settle_time(&self) -> Duration;
+}
+
+/// Only changes state once the reading has been stable for the debounce time.
+/// Adds the debounce time as latency to both presses and releases
+#[derive(Copy, Clone, Debug)]
+pub struct DeferDebouncer {
+    state: bool,
+    changed: Option<Instant>,
+    time: Duration,
+}
+
+impl DeferDebouncer {
+    pub const fn new(time: Duration) -> Self {
+        Self {
+            state: false,
+            changed: None,
+            time,
+        }
+    }
+}
+
+impl Debouncer for DeferDebouncer {
+    fn is_pressed(&self) -> bool {
+        self.state
+    }
+
+    fn update_buf(&mut self, buf: bool) {
+        if buf == self.state {
+            self.changed = None;
+            return;
+        }
+        match self.changed {
+            Some(time) => {
+                if time.elapsed() >= self.time {
+                    self.state = buf;
+                    self.changed = None;
+                }
+            }
+            None => {
+                self.changed = Some(Instant::now());
+            }
+        }
+    }
+
+    fn settle_time(&self) -> Duration {
+        self.time
+    }
+}
+
+/// Changes state as soon as the reading changes and ignores the key for the
+/// debounce time afterwards
+#[derive(Copy, Clone, Debug)]
+pub struct EagerDebouncer {
+    state: bool,
+    locked: Option<Instant>,
+    time: Duration,
+}
+
+impl EagerDebouncer {
+    pub const fn new(time: Duration) -> Self {
+        Self {
+            state: false,
+            locked: None,
+            time,
+        }
+    }
+}
+
+impl Debouncer for EagerDebouncer {
+    fn is_pressed(&self) -> bool {
+        self.state
+    }
+
+    fn update_buf(&mut self, buf: bool) {
+        if let Some(time) = self.locked {
+            if time.elapsed() < self.time {
+                return;
+            }
+            self.locked = None;
+        }
+        if buf != self.state {
+            self.state = buf;
+            self.locked = Some(Instant::now());
+        }
+    }
+
+    fn settle_time(&self) -> Duration {
+        self.time
+    }
+}
+
+/// Uses different debounce times for presses and releases. Presses are
+/// registered eagerly while releases are deferred until the reading is stable
+#[derive(Copy, Clone, Debug)]
+pub struct AsymmetricDebouncer {
+    state: bool,
+    changed: Option<Instant>,
+    press_time: Duration,
+    release_time: Duration,
+}
+
+impl AsymmetricDebouncer {
+    pub const fn new(press_time: Duration, release_time: Duration) -> Self {
+        Self {
+            state: false,
+            changed: None,
+            press_time,
+            release_time,
+        }
+    }
+}
+
+impl Debouncer for AsymmetricDebouncer {
+    fn is_pressed(&self) -> bool {
+        self.state
+    }
+
+    fn update_buf(&mut self, buf: bool) {
+        if self.state {
+            // Wait for the release to be stable
+            if buf {
+                self.changed = None;
+            } else {
+                match self.changed {
+                    Some(time) => {
+                        if time.elapsed() >= self.release_time {
+                            self.state = false;
+                            self.changed = Some(Instant::now());
+                        }
+                    }
+                    None => {
+                        self.changed = Some(Instant::now());
+                    }
+                }
+            }
+        } else {
+            // Ignore presses until the key has been released for the press time
+            if let Some(time) = self.changed {
+                if time.elapsed() < self.press_time {
+                    return;
+                }
+                self.changed = None;
+            }
+            if buf {
+                self.state = true;
+            }
+        }
+    }
+
+    fn settle_time(&self) -> Duration {
+        self.press_time.max(self.release_time)
+    }
+}
+
+/// Scans a switch matrix by driving each output high and reading the inputs.
+/// Each position of the matrix is mapped to a key index with the order table
+pub struct Matrix<O, I, D, const INPUT_SIZE: usize, const OUTPUT_SIZE: usize>
+where
+    O: OutputPin,
+    I: InputPin + Wait,
+    D: Debouncer,
+{
+    out: [O; OUTPUT_SIZE],
+    input: [I; INPUT_SIZE],
+    // Key index of each position, or None if no switch is wired there
+    order: [[Option<usize>; OUTPUT_SIZE]; INPUT_SIZE],
+    debouncers: [[D; OUTPUT_SIZE]; INPUT_SIZE],
+    settle_time: Duration,
+    pressed: Option<Instant>,
+}
+
+impl<O, I, D, const INPUT_SIZE: usize, const OUTPUT_SIZE: usize>
+    Matrix<O, I, D, INPUT_SIZE, OUTPUT_SIZE>
+where
+    O: OutputPin,
+    I: InputPin + Wait,
+    D: Debouncer,
+{
+    /// Returns a matrix where every key is debounced with a copy of the
+    /// provided debouncer. Keys are indexed by input then output
+    pub fn new(out: [O; OUTPUT_SIZE], input: [I; INPUT_SIZE], debouncer: D) -> Self {
+        let mut order = [[None; OUTPUT_SIZE]; INPUT_SIZE];
+        for (i, pos) in order.iter_mut().flatten().enumerate() {
+            *pos = Some(i);
+        }
+        Self {
+            out,
+            input,
+            order,
+            debouncers: [[debouncer; OUTPUT_SIZE]; INPUT_SIZE],
+            settle_time: debouncer.settle_time(),
+            pressed: None,
+        }
+    }
+
+    /// Sets the key index of each position, indexed by input then output.
+    /// Positions set to None are ignored
+    pub fn set_order(&mut self, order: [[Option<usize>; OUTPUT_SIZE]; INPUT_SIZE]) {
+        self.order = order;
+    }
+
+    /// Ignores the positions in range, counted by input then output. The
+    /// remaining positions are indexed in the same order
+    pub fn disable_debouncer(&mut self, range: Range<usize>) {
+        let mut index = 0;
+        for (i, pos) in self.order.iter_mut().flatten().enumerate() {
+            if range.contains(&i) {
+                *pos = None;
+            } else if pos.is_some() {
+                *pos = Some(index);
+                index += 1;
+            }
+        }
+    }
+
+    pub async fn update(&mut self) {
+        // If no keys were pressed in the previous scan,
+        // we'll set all the output pins high and await
+        // for one of the channels to go high to save battery
+        if self
+            .pressed
+            .is_some_and(|time| time.elapsed() >= self.settle_time)
+        {
+            for power in &mut self.out {
+                let _ = power.set_high();
+            }
+            let (res, _) = select_array(self.input.each_mut().map(|pin| pin.wait_for_high())).await;
+            if res.is_err() {
+                record_error(KeyLibError::Sensor);
+            }
+            for power in &mut self.out {
+                let _ = power.set_low();
+            }
+        }
+
+        let mut pressed = false;
+        for i in 0..OUTPUT_SIZE {
+            let _ = self.out[i].set_high();
+            for j in 0..INPUT_SIZE {
+                let reading = match self.input[j].is_high() {
+                    Ok(reading) => reading,
+                    Err(_) => {
+                        record_error(KeyLibError::Sensor);
+                        continue;
+                    }
+                };
+                self.debouncers[j][i].update_buf(reading);
+                pressed = pressed || self.debouncers[j][i].is_pressed();
+            }
+            let _ = self.out[i].set_low();
+        }
+        if pressed {
+            self.pressed = None;
+        } else if self.pressed.is_none() {
+            self.pressed = Some(Instant::now());
+        }
+    }
+
+    /// Returns the pressed keys as a bitmask of their key index
+    pub fn get_state(&self) -> u32 {
+        let mut state = 0u32;
+        self.debouncers
+            .iter()
+            .flatten()
+            .zip(self.order.iter().flatten())
+            .for_each(|(deb, key)| {
+                if let Some(key) = key
+                    && deb.is_pressed()
+                {
+                    state |= 1 << key;
+                }
+            });
+        state
+    }
+}
+
+impl<O, I, D, const INPUT_SIZE: usize, const OUTPUT_SIZE: usize> KeySensors
+    for Matrix<O, I, D, INPUT_SIZE, OUTPUT_SIZE>
+where
+    O: OutputPin,
+    I: InputPin + Wait,
+    D: Debouncer,
+{
+    type Item = bool;
+
+    async fn update_positions<K: KeyState<Item = Self::Item>>(&mut self, positions: &mut [K]) {
+        self.update().await;
+        for (deb, key) in self
+            .debouncers
+            .iter()
+            .flatten()
+            .zip(self.order.iter().flatten())
+        {
+            if let Some(position) = key.and_then(|key| positions.get_mut(key)) {
+                position.update_buf(deb.is_pressed());
+            }
+        }
+    }
+
+    #[cfg(feature = "hall-effect")]
+    async fn setup<K: KeyState<Item = Self::Item>>(&mut self, _: &mut [K]) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn drift_compensation_round_trip() {
+        let settings = DriftCompensation {
+            enabled: true,
+            idle_secs: 600,
+            step: 2,
+            band_percent: 10,
+        };
+        let mut buffer = [0u8; DRIFT_COMPENSATION_SERIAL_LENGTH];
+        settings.serialize_into(&mut buffer).unwrap();
+        assert_eq!(
+            DriftCompensation::deserialize_from(&buffer),
+            Ok((settings, DRIFT_COMPENSATION_SERIAL_LENGTH))
+        );
+        // Bands wide enough to follow pressed keys are rejected
+        buffer[4] = 51;
+        assert!(DriftCompensation::deserialize_from(&buffer).is_err());
+        buffer[4] = 10;
+        buffer[3] = 0;
+        assert!(DriftCompensation::deserialize_from(&buffer).is_err());
+    }
+}
@@ -0,0 +1,352 @@
+use defmt::{Format, info};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, mutex::Mutex, signal::Signal};
+use embassy_time::Duration;
+use num_enum::TryFromPrimitive;
+use sequential_storage::map::{SerializationError, Value};
+
+use crate::storage::{StorageItem, StorageKey, get_item, store_val};
+
+/// Thresholds currently used by battery powered boards. Updated over com
+/// and loaded from storage with [load_battery_thresholds]
+pub static BATTERY_THRESHOLDS: Mutex<CriticalSectionRawMutex, BatteryThresholds> =
+    Mutex::new(BatteryThresholds::default());
+
+// Voltage the battery has to recover past a threshold before the rate is raised again
+const HYSTERESIS_MV: u16 = 50;
+
+pub const BATTERY_THRESHOLDS_SERIAL_LENGTH: usize = 4;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Format)]
+pub struct BatteryThresholds {
+    /// Battery voltage in millivolts where the scan rate gets reduced
+    pub low_mv: u16,
+    /// Battery voltage in millivolts where the scan rate drops to the minimum
+    pub critical_mv: u16,
+}
+
+impl BatteryThresholds {
+    pub const fn default() -> Self {
+        Self {
+            low_mv: 3500,
+            critical_mv: 3300,
+        }
+    }
+
+    pub fn into_buffer(&self, buffer: &mut [u8]) -> Result<(), SerializationError> {
+        if buffer.len() < BATTERY_THRESHOLDS_SERIAL_LENGTH {
+            Err(SerializationError::BufferTooSmall)
+        } else {
+            buffer[0..2].copy_from_slice(&self.low_mv.to_le_bytes());
+            buffer[2..4].copy_from_slice(&self.critical_mv.to_le_bytes());
+            Ok(())
+        }
+    }
+}
+
+impl<'a> Value<'a> for BatteryThresholds {
+    fn serialize_into(&self, buffer: &mut [u8]) -> Result<usize, SerializationError> {
+        self.into_buffer(buffer)?;
+        Ok(BATTERY_THRESHOLDS_SERIAL_LENGTH)
+    }
+
+    fn deserialize_from(buffer: &'a [u8]) -> Result<(Self, usize), SerializationError>
+    where
+        Self: Sized,
+    {
+        if buffer.len() < BATTERY_THRESHOLDS_SERIAL_LENGTH {
+            Err(SerializationError::BufferTooSmall)
+        } else {
+            let low_mv = u16::from_le_bytes([buffer[0], buffer[1]]);
+            let critical_mv = u16::from_le_bytes([buffer[2], buffer[3]]);
+            if critical_mv > low_mv {
+                Err(SerializationError::InvalidFormat)
+            } else {
+                Ok((
+                    Self {
+                        low_mv,
+                        critical_mv,
+                    },
+                    BATTERY_THRESHOLDS_SERIAL_LENGTH,
+                ))
+            }
+        }
+    }
+}
+
+/// Rate at which a battery powered board scans its keys and sends radio events
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Format, TryFromPrimitive)]
+pub enum ScanRate {
+    Full = 0,
+    Reduced = 1,
+    Minimal = 2,
+}
+
+impl ScanRate {
+    /// Delay between each matrix scan
+    pub const fn scan_interval(&self) -> Duration {
+        match self {
+            ScanRate::Full => Duration::from_micros(5),
+            ScanRate::Reduced => Duration::from_millis(2),
+            ScanRate::Minimal => Duration::from_millis(10),
+        }
+    }
+}
+
+pub const LINK_TIMING_SERIAL_LENGTH: usize = 3;
+
+/// Shortest and longest time between heartbeats of an idle half
+const MIN_HEARTBEAT_MS: u16 = 1000;
+const MAX_HEARTBEAT_MS: u16 = 30_000;
+
+/// Written when the host changes the battery thresholds, so the dongle can
+/// send them to the halves
+static BATTERY_THRESHOLDS_CHANGED: Signal<CriticalSectionRawMutex, BatteryThresholds> =
+    Signal::new();
+
+/// Written by com when the host changes the link timing, so the dongle can
+/// send it to the halves
+static LINK_TIMING_CHANGED: Signal<CriticalSectionRawMutex, LinkTiming> = Signal::new();
+
+/// Timing of the radio link between a dongle and its halves. Stored on the
+/// dongle with SettingId::LinkTiming and sent to each half when it connects,
+/// so both ends agree on how often an idle half is heard from
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Format)]
+pub struct LinkTiming {
+    /// Time between key states sent by an idle half in ms
+    pub heartbeat_ms: u16,
+    /// Fastest rate the halves scan at. Slower rates trade latency for
+    /// battery life
+    pub scan_rate: ScanRate,
+}
+
+impl LinkTiming {
+    pub const fn default() -> Self {
+        Self {
+            heartbeat_ms: 10_000,
+            scan_rate: ScanRate::Full,
+        }
+    }
+
+    pub fn heartbeat_interval(&self) -> Duration {
+        Duration::from_millis(self.heartbeat_ms as u64)
+    }
+
+    /// Time without a packet before a half is shown as disconnected. A few
+    /// heartbeats so a single missed one doesn't drop the half
+    pub fn connected_timeout(&self) -> Duration {
+        self.heartbeat_interval() * 5 / 2
+    }
+
+    /// Returns the slower of the rate and the fastest rate allowed
+    pub fn limit(&self, rate: ScanRate) -> ScanRate {
+        if (rate as u8) < (self.scan_rate as u8) {
+            self.scan_rate
+        } else {
+            rate
+        }
+    }
+}
+
+impl<'a> Value<'a> for LinkTiming {
+    fn serialize_into(&self, buffer: &mut [u8]) -> Result<usize, SerializationError> {
+        if buffer.len() < LINK_TIMING_SERIAL_LENGTH {
+            return Err(SerializationError::BufferTooSmall);
+        }
+        buffer[0..2].copy_from_slice(&self.heartbeat_ms.to_le_bytes());
+        buffer[2] = self.scan_rate as u8;
+        Ok(LINK_TIMING_SERIAL_LENGTH)
+    }
+
+    fn deserialize_from(buffer: &'a [u8]) -> Result<(Self, usize), SerializationError>
+    where
+        Self: Sized,
+    {
+        if buffer.len() < LINK_TIMING_SERIAL_LENGTH {
+            return Err(SerializationError::BufferTooSmall);
+        }
+        let heartbeat_ms = u16::from_le_bytes([buffer[0], buffer[1]]);
+        if !(MIN_HEARTBEAT_MS..=MAX_HEARTBEAT_MS).contains(&heartbeat_ms) {
+            return Err(SerializationError::InvalidFormat);
+        }
+        let scan_rate =
+            ScanRate::try_from(buffer[2]).map_err(|_| SerializationError::InvalidFormat)?;
+        Ok((
+            Self {
+                heartbeat_ms,
+                scan_rate,
+            },
+            LINK_TIMING_SERIAL_LENGTH,
+        ))
+    }
+}
+
+/// Returns the stored link timing, or the default if none was written
+pub async fn load_link_timing() -> LinkTiming {
+    match get_item(StorageKey::LinkTiming).await {
+        Some(StorageItem::LinkTiming(timing)) => timing,
+        _ => LinkTiming::default(),
+    }
+}
+
+/// Persists the link timing set by the host and hands it to the radio link
+pub async fn store_link_timing(timing: LinkTiming) {
+    info!("Link timing set to {}", timing);
+    LINK_TIMING_CHANGED.signal(timing);
+    store_val(StorageKey::LinkTiming, &StorageItem::LinkTiming(timing)).await;
+}
+
+/// Waits for the host to change the link timing
+pub async fn wait_link_timing() -> LinkTiming {
+    LINK_TIMING_CHANGED.wait().await
+}
+
+/// Picks the scan rate from battery readings. The rate will only be raised
+/// once the voltage recovers past the threshold by a small margin so a
+/// battery hovering around a threshold doesn't flip the rate every reading
+#[derive(Debug, Clone, Copy)]
+pub struct BatteryMonitor {
+    thresholds: BatteryThresholds,
+    rate: ScanRate,
+}
+
+impl BatteryMonitor {
+    pub const fn new(thresholds: BatteryThresholds) -> Self {
+        Self {
+            thresholds,
+            rate: ScanRate::Full,
+        }
+    }
+
+    pub fn set_thresholds(&mut self, thresholds: BatteryThresholds) {
+        self.thresholds = thresholds;
+    }
+
+    /// Updates the rate with a new battery reading. Boards that are charging or
+    /// powered over usb always run at the full rate
+    pub fn update(&mut self, millivolts: u16, usb_powered: bool) -> ScanRate {
+        let BatteryThresholds {
+            low_mv,
+            critical_mv,
+        } = self.thresholds;
+        let new_rate = if usb_powered {
+            ScanRate::Full
+        } else {
+            match self.rate {
+                ScanRate::Full => {
+                    if millivolts < critical_mv {
+                        ScanRate::Minimal
+                    } else if millivolts < low_mv {
+                        ScanRate::Reduced
+                    } else {
+                        ScanRate::Full
+                    }
+                }
+                ScanRate::Reduced => {
+                    if millivolts < critical_mv {
+                        ScanRate::Minimal
+                    } else if millivolts >= low_mv.saturating_add(HYSTERESIS_MV) {
+                        ScanRate::Full
+                    } else {
+                        ScanRate::Reduced
+                    }
+                }
+                ScanRate::Minimal => {
+                    if millivolts >= low_mv.saturating_add(HYSTERESIS_MV) {
+                        ScanRate::Full
+                    } else if millivolts >= critical_mv.saturating_add(HYSTERESIS_MV) {
+                        ScanRate::Reduced
+                    } else {
+                        ScanRate::Minimal
+                    }
+                }
+            }
+        };
+        if new_rate != self.rate {
+            info!(
+                "Battery at {}mV, switching scan rate to {}",
+                millivolts, new_rate
+            );
+            self.rate = new_rate;
+        }
+        self.rate
+    }
+
+    pub fn rate(&self) -> ScanRate {
+        self.rate
+    }
+
+    /// Returns true if the battery is low enough that the user should be warned
+    pub fn is_low(&self) -> bool {
+        self.rate != ScanRate::Full
+    }
+}
+
+/// Loads the stored battery thresholds into [BATTERY_THRESHOLDS]. The defaults
+/// are kept if no thresholds were stored
+pub async fn load_battery_thresholds() -> BatteryThresholds {
+    let mut thresholds = BATTERY_THRESHOLDS.lock().await;
+    if let Some(StorageItem::BatteryThresholds(stored)) =
+        get_item(StorageKey::BatteryThresholds).await
+    {
+        *thresholds = stored;
+    }
+    *thresholds
+}
+
+/// Updates [BATTERY_THRESHOLDS] and persists the new thresholds to storage
+pub async fn store_battery_thresholds(new_thresholds: BatteryThresholds) {
+    *BATTERY_THRESHOLDS.lock().await = new_thresholds;
+    BATTERY_THRESHOLDS_CHANGED.signal(new_thresholds);
+    store_val(
+        StorageKey::BatteryThresholds,
+        &StorageItem::BatteryThresholds(new_thresholds),
+    )
+    .await;
+}
+
+/// Waits for the host to change the battery thresholds
+pub async fn wait_battery_thresholds() -> BatteryThresholds {
+    BATTERY_THRESHOLDS_CHANGED.wait().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn link_timing_round_trip() {
+        let timing = LinkTiming {
+            heartbeat_ms: 2500,
+            scan_rate: ScanRate::Reduced,
+        };
+        let mut buffer = [0u8; LINK_TIMING_SERIAL_LENGTH];
+        timing.serialize_into(&mut buffer).unwrap();
+        assert_eq!(
+            LinkTiming::deserialize_from(&buffer),
+            Ok((timing, LINK_TIMING_SERIAL_LENGTH))
+        );
+        // Heartbeats too close together or too far apart are rejected
+        buffer[0..2].copy_from_slice(&500u16.to_le_bytes());
+        assert!(LinkTiming::deserialize_from(&buffer).is_err());
+        buffer[0..2].copy_from_slice(&60_000u16.to_le_bytes());
+        assert!(LinkTiming::deserialize_from(&buffer).is_err());
+        buffer[0..2].copy_from_slice(&2500u16.to_le_bytes());
+        buffer[2] = 3;
+        assert!(LinkTiming::deserialize_from(&buffer).is_err());
+    }
+
+    #[test]
+    fn link_timing_limits_scan_rate() {
+        let timing = LinkTiming {
+            scan_rate: ScanRate::Reduced,
+            ..LinkTiming::default()
+        };
+        assert_eq!(timing.limit(ScanRate::Full), ScanRate::Reduced);
+        assert_eq!(timing.limit(ScanRate::Minimal), ScanRate::Minimal);
+        assert_eq!(
+            LinkTiming::default().connected_timeout(),
+            Duration::from_secs(25)
+        );
+    }
+}
@@ -0,0 +1,247 @@
+//! Reaction test started with HidRequest::ReactionTest. After a random delay
+//! the firmware shows a cue, lighting every led on boards with per key leds,
+//! and times the first key press after it. Useful for demos and for checking
+//! the timing of the whole input path after changes.
+//!
+//! Keys pressed during a test are held back from the reports until they're
+//! released, so the test doesn't type into the host
+
+use core::cell::Cell;
+
+use defmt::{Format, info};
+use embassy_sync::{
+    blocking_mutex::{self, raw::CriticalSectionRawMutex},
+    channel::Channel,
+};
+use embassy_time::{Duration, Instant};
+
+use crate::NUM_KEYS;
+
+pub const REACTION_EVENT_SERIAL_LENGTH: usize = 5;
+
+// Range of the random delay before the cue
+const MIN_CUE_DELAY: Duration = Duration::from_millis(1500);
+const MAX_CUE_DELAY: Duration = Duration::from_millis(4000);
+
+// Time after the cue before the test gives up on a press
+const REACTION_TIMEOUT: Duration = Duration::from_secs(5);
+
+static TEST: blocking_mutex::Mutex<CriticalSectionRawMutex, Cell<ReactionTest>> =
+    blocking_mutex::Mutex::new(Cell::new(ReactionTest::new()));
+
+/// Events of the running test, sent to the host by the com handler
+static EVENTS: Channel<CriticalSectionRawMutex, ReactionEvent, 2> = Channel::new();
+
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Format)]
+pub enum ReactionStatus {
+    /// The cue was shown. Followed by another event once the test ends
+    Cue = 0,
+    Ok = 1,
+    /// A key was pressed before the cue
+    FalseStart = 2,
+    /// No key was pressed within a few seconds of the cue
+    Timeout = 3,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Format)]
+pub struct ReactionEvent {
+    pub status: ReactionStatus,
+    /// Time from the cue to the press. Only set with ReactionStatus::Ok
+    pub reaction_us: u32,
+}
+
+impl ReactionEvent {
+    const fn new(status: ReactionStatus) -> Self {
+        Self {
+            status,
+            reaction_us: 0,
+        }
+    }
+
+    /// Writes the status followed by the reaction time as a le u32
+    pub fn to_bytes(&self) -> [u8; REACTION_EVENT_SERIAL_LENGTH] {
+        let mut buf = [0u8; REACTION_EVENT_SERIAL_LENGTH];
+        buf[0] = self.status as u8;
+        buf[1..5].copy_from_slice(&self.reaction_us.to_le_bytes());
+        buf
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum Phase {
+    Idle,
+    // Waiting to show the cue
+    Armed { cue_at: Instant },
+    Cued { since: Instant },
+    // The test ended but keys pressed during it are still held
+    Releasing,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct ReactionTest {
+    phase: Phase,
+    // Any key was pressed on the last update. Only new presses end a test
+    was_pressed: bool,
+}
+
+impl ReactionTest {
+    const fn new() -> Self {
+        Self {
+            phase: Phase::Idle,
+            was_pressed: false,
+        }
+    }
+
+    fn start(&mut self, now: Instant, delay: Duration) {
+        self.phase = Phase::Armed {
+            cue_at: now + delay,
+        };
+        // Keys held when the test starts have to be released first
+        self.was_pressed = true;
+    }
+
+    /// Advances the test with the keys pressed this scan. Returns the event
+    /// the host should be sent, if any
+    fn update(&mut self, now: Instant, pressed: bool) -> Option<ReactionEvent> {
+        let new_press = pressed && !self.was_pressed;
+        self.was_pressed = pressed;
+        match self.phase {
+            Phase::Armed { .. } if new_press => {
+                self.phase = Phase::Releasing;
+                Some(ReactionEvent::new(ReactionStatus::FalseStart))
+            }
+            Phase::Armed { cue_at } if now >= cue_at => {
+                self.phase = Phase::Cued { since: now };
+                Some(ReactionEvent::new(ReactionStatus::Cue))
+            }
+            Phase::Cued { since } if new_press => {
+                self.phase = Phase::Releasing;
+                let reaction = now.saturating_duration_since(since).as_micros();
+                Some(ReactionEvent {
+                    status: ReactionStatus::Ok,
+                    reaction_us: reaction.min(u32::MAX as u64) as u32,
+                })
+            }
+            Phase::Cued { since } if now.saturating_duration_since(since) >= REACTION_TIMEOUT => {
+                self.phase = Phase::Releasing;
+                Some(ReactionEvent::new(ReactionStatus::Timeout))
+            }
+            Phase::Releasing if !pressed => {
+                self.phase = Phase::Idle;
+                None
+            }
+            _ => None,
+        }
+    }
+
+    fn holds_keys(&self) -> bool {
+        self.phase != Phase::Idle
+    }
+}
+
+// Picks the delay before the cue from the low bits of the clock, which is
+// random enough that the cue can't be anticipated
+fn cue_delay(now: Instant) -> Duration {
+    let range = (MAX_CUE_DELAY - MIN_CUE_DELAY).as_millis();
+    let ticks = now.as_ticks();
+    let mixed = ticks ^ (ticks >> 7) ^ (ticks >> 13);
+    MIN_CUE_DELAY + Duration::from_millis(mixed % range)
+}
+
+/// Starts a test, replacing any running one
+pub fn start_reaction_test() {
+    EVENTS.clear();
+    let now = Instant::now();
+    let delay = cue_delay(now);
+    info!("Starting reaction test, cue in {}ms", delay.as_millis());
+    TEST.lock(|test| {
+        let mut state = test.get();
+        state.start(now, delay);
+        test.set(state);
+    });
+}
+
+/// Advances the running test with the pressed keys. Returns true while the
+/// keys should be held back from the report
+pub(crate) fn update(pressed: impl Fn(usize) -> bool) -> bool {
+    TEST.lock(|test| {
+        let mut state = test.get();
+        if !state.holds_keys() {
+            return false;
+        }
+        let pressed = (0..NUM_KEYS).any(pressed);
+        if let Some(event) = state.update(Instant::now(), pressed) {
+            let _ = EVENTS.try_send(event);
+        }
+        test.set(state);
+        true
+    })
+}
+
+/// Returns true while the cue of a test is shown
+pub fn is_cue_shown() -> bool {
+    TEST.lock(|test| matches!(test.get().phase, Phase::Cued { .. }))
+}
+
+/// Waits for the next event of the running test
+pub async fn next_reaction_event() -> ReactionEvent {
+    EVENTS.receive().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DELAY: Duration = Duration::from_secs(2);
+
+    fn started() -> (ReactionTest, Instant) {
+        let mut test = ReactionTest::new();
+        let start = Instant::from_secs(10);
+        test.start(start, DELAY);
+        (test, start)
+    }
+
+    #[test]
+    fn press_after_cue_is_timed() {
+        let (mut test, start) = started();
+        assert_eq!(test.update(start, false), None);
+        let cue = start + DELAY;
+        assert_eq!(
+            test.update(cue, false),
+            Some(ReactionEvent::new(ReactionStatus::Cue))
+        );
+        let event = test.update(cue + Duration::from_millis(180), true);
+        assert_eq!(
+            event,
+            Some(ReactionEvent {
+                status: ReactionStatus::Ok,
+                reaction_us: 180_000,
+            })
+        );
+        // Keys stay held back until released
+        assert!(test.holds_keys());
+        assert_eq!(test.update(cue + Duration::from_millis(300), false), None);
+        assert!(!test.holds_keys());
+    }
+
+    #[test]
+    fn press_before_cue_is_a_false_start() {
+        let (mut test, start) = started();
+        // A key held when the test starts doesn't count
+        assert_eq!(test.update(start, true), None);
+        assert_eq!(test.update(start + Duration::from_millis(10), false), None);
+        assert_eq!(
+            test.update(start + Duration::from_millis(500), true),
+            Some(ReactionEvent::new(ReactionStatus::FalseStart))
+        );
+    }
+
+    #[test]
+    fn cue_delay_stays_in_range() {
+        for ticks in [0, 1, 12345, u64::MAX / 3] {
+            let delay = cue_delay(Instant::from_ticks(ticks));
+            assert!(delay >= MIN_CUE_DELAY && delay < MAX_CUE_DELAY);
+        }
+    }
+}
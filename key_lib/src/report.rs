@@ -1,14 +1,30 @@
+use core::convert::Infallible;
+
 use defmt::info;
 use embassy_sync::{blocking_mutex::raw::RawMutex, mutex::Mutex};
 use embassy_time::{Duration, Instant};
 use heapless::Vec;
 
 use crate::{
-    NUM_KEYS,
-    descriptor::{KeyboardReportNKRO, MouseReport},
+    NUM_KEYS, config_slots,
+    descriptor::KeyboardReportNKRO,
+    display,
+    dynamic_macro::{self, Playback},
+    key_stats,
     keys::{ConfigIndicator, Keys},
+    latency, lighting,
     position::{KeySensors, KeyState},
+    reaction,
     scan_codes::ReportCodes,
+    self_test,
+    settings::LayerTimeouts,
+    socd::SocdResolver,
+};
+#[cfg(feature = "mouse")]
+use crate::{
+    descriptor::MouseReport,
+    hires_scroll,
+    settings::{DEFAULT_MOUSE_SPEED, MouseAcceleration, MouseAxis},
 };
 
 fn set_bit(num: &mut u8, bit: u8, pos: u8) {
@@ -25,29 +41,90 @@ fn set_bit_u32(num: u32, bit: u8, pos: u8) -> u32 {
     if bit == 1 { num | mask } else { num & !mask }
 }
 
+/// Returns true if a key or modifier that wasn't in the old report is in the new report
+fn has_new_press(old: &KeyboardReportNKRO, new: &KeyboardReportNKRO) -> bool {
+    new.modifier & !old.modifier != 0
+        || new.nkro_0 & !old.nkro_0 != 0
+        || new.nkro_1 & !old.nkro_1 != 0
+        || new.nkro_2 & !old.nkro_2 != 0
+        || new.nkro_3 & !old.nkro_3 != 0
+        || new.nkro_4 & !old.nkro_4 != 0
+        || new.nkro_5 & !old.nkro_5 != 0
+        || new.nkro_6 & !old.nkro_6 != 0
+}
+
 enum State {
     Stick(u8),
     Pressed,
     None,
 }
 
+// Time a tapped one shot layer waits for the next key press before it's dropped
+const ONE_SHOT_TIMEOUT: Duration = Duration::from_millis(3000);
+// Max time between two taps of a one shot layer key for the layer to latch
+const LATCH_TERM: Duration = Duration::from_millis(300);
+
+#[derive(Copy, Clone, Debug)]
+enum OneShot {
+    // One shot key is held down. Acts like a normal layer key if another
+    // key gets pressed while it's held
+    Held { layer: u8, used: bool },
+    // One shot key was tapped so the layer applies to the next key press
+    Pending { layer: u8, tapped: Instant },
+    // One shot key was double tapped so the layer stays active until it's tapped again
+    Latched(u8),
+    None,
+}
+
+// Time between the first two ticks of the linear profile at the default speed.
+// The expo curve starts at the same interval
+#[cfg(feature = "mouse")]
+const INITIAL_TICK_MS: u64 = 50;
+// Time between ticks of the constant profile at the default speed
+#[cfg(feature = "mouse")]
+const CONSTANT_TICK_MS: u64 = 10;
+// Time the linear profile takes to reach full speed
+#[cfg(feature = "mouse")]
+const LINEAR_RAMP_MS: u64 = 1000;
+// Shape of the expo curve. Higher term0 reaches full speed sooner and higher
+// term1 keeps it slow for longer
+#[cfg(feature = "mouse")]
+const EXPO_TERM0: u64 = 1000000;
+#[cfg(feature = "mouse")]
+const EXPO_TERM1: u64 = 500000;
+
+/// Returns the time until the next tick of a mouse key that has been held
+/// for held_ms. Speed is in tenths like the mouse settings, but can go past
+/// them so high resolution scrolling can tick faster
+#[cfg(feature = "mouse")]
+fn tick_interval(acceleration: MouseAcceleration, speed: u16, held_ms: u64) -> Duration {
+    let interval = match acceleration {
+        MouseAcceleration::Constant => CONSTANT_TICK_MS,
+        MouseAcceleration::Linear => {
+            INITIAL_TICK_MS * (LINEAR_RAMP_MS - held_ms.min(LINEAR_RAMP_MS)) / LINEAR_RAMP_MS
+        }
+        MouseAcceleration::Expo => {
+            500000 / (((EXPO_TERM0 * held_ms.pow(2)) / (held_ms + EXPO_TERM1)) + 10000)
+        }
+    };
+    Duration::from_micros(interval * 1000 * DEFAULT_MOUSE_SPEED as u64 / speed.max(1) as u64)
+}
+
+#[cfg(feature = "mouse")]
 #[derive(Copy, Clone, Debug)]
 struct MouseDelta {
     initial_press: Option<Instant>,
     next_tick: Instant,
-    term0: u64,
-    term1: u64,
     check_state: bool,
     res: bool,
 }
 
+#[cfg(feature = "mouse")]
 impl MouseDelta {
-    pub fn new(term0: u64, term1: u64) -> Self {
+    pub fn new() -> Self {
         Self {
             initial_press: None,
             next_tick: Instant::from_micros(0),
-            term0,
-            term1,
             check_state: false,
             res: false,
         }
@@ -61,25 +138,25 @@ impl MouseDelta {
         self.check_state = false;
     }
 
-    fn check(&mut self) -> bool {
+    fn check(&mut self, acceleration: MouseAcceleration, speed: u16) -> bool {
         if self.check_state {
             self.res
         } else {
-            self.update_state();
+            self.update_state(acceleration, speed);
             self.check_state = true;
             self.res
         }
     }
 
-    fn update_state(&mut self) {
+    fn update_state(&mut self, acceleration: MouseAcceleration, speed: u16) {
         match self.initial_press {
             Some(time) => {
                 let new_time = Instant::now();
                 if new_time > self.next_tick {
                     let x = time.elapsed().as_millis();
-                    let val = 500000 / (((self.term0 * x.pow(2)) / (x + self.term1)) + 10000);
-                    info!("Current val: {}", val);
-                    self.next_tick = new_time.checked_add(Duration::from_millis(val)).unwrap();
+                    let val = tick_interval(acceleration, speed, x);
+                    info!("Current val: {}", val.as_millis());
+                    self.next_tick = new_time.checked_add(val).unwrap();
                     self.res = true;
                 } else {
                     self.res = false;
@@ -88,120 +165,246 @@ impl MouseDelta {
             None => {
                 let new_time = Instant::now();
                 self.initial_press = Some(new_time);
-                self.next_tick = new_time + Duration::from_millis(50);
+                self.next_tick = new_time + tick_interval(acceleration, speed, 0);
                 self.res = true;
             }
         }
     }
 }
 
-pub struct Report {
+/// Latches mouse buttons for users who can't hold a key while moving the
+/// pointer. With drag lock on, each press of a button key toggles the button
+/// instead of holding it
+#[cfg(feature = "mouse")]
+#[derive(Copy, Clone, Debug)]
+struct DragLock {
+    // Buttons held by keys in the last scan
+    held: u8,
+    latched: u8,
+    // Last time a button was latched or the pointer moved
+    active_at: Instant,
+}
+
+#[cfg(feature = "mouse")]
+impl DragLock {
+    const fn new() -> Self {
+        Self {
+            held: 0,
+            latched: 0,
+            active_at: Instant::MIN,
+        }
+    }
+
+    /// Returns the buttons to report for the buttons held by keys. Latched
+    /// buttons are released once the pointer stays still for the timeout
+    fn update(&mut self, held: u8, timeout: Option<Duration>, moved: bool, now: Instant) -> u8 {
+        let pressed = held & !self.held;
+        self.held = held;
+        let Some(timeout) = timeout else {
+            self.latched = 0;
+            return held;
+        };
+        if moved {
+            self.active_at = now;
+        }
+        if now - self.active_at >= timeout {
+            self.latched = 0;
+        }
+        if pressed != 0 {
+            self.latched ^= pressed;
+            self.active_at = now;
+        }
+        self.latched
+    }
+}
+
+/// Non mouse codes pressed during a single scan
+#[derive(Default)]
+struct ReportInput {
+    key_report: KeyboardReportNKRO,
+    // True if a non modifier key was pressed
+    pressed: bool,
+    stick: bool,
+    new_layer: Option<u8>,
+    toggle: bool,
+    one_shot_key: Option<u8>,
+    analog_layer: Option<(u8, u8, bool)>,
+}
+
+impl ReportInput {
+    /// Adds a pressed code to the input. Mouse codes are ignored since they're
+    /// handled with the mouse report
+    fn add_code(&mut self, code: ReportCodes) {
+        match code {
+            ReportCodes::Modifier(code) => {
+                let b_idx = code % 8;
+                set_bit(&mut self.key_report.modifier, 1, b_idx);
+            }
+            ReportCodes::Letter(code) => {
+                let n_idx = (code / 32) as usize;
+                let b_idx = code % 32;
+                let report = &mut self.key_report;
+                match n_idx {
+                    0 => report.nkro_0 = set_bit_u32(report.nkro_0, 1, b_idx),
+                    1 => report.nkro_1 = set_bit_u32(report.nkro_1, 1, b_idx),
+                    2 => report.nkro_2 = set_bit_u32(report.nkro_2, 1, b_idx),
+                    3 => report.nkro_3 = set_bit_u32(report.nkro_3, 1, b_idx),
+                    4 => report.nkro_4 = set_bit_u32(report.nkro_4, 1, b_idx),
+                    5 => report.nkro_5 = set_bit_u32(report.nkro_5, 1, b_idx),
+                    6 => report.nkro_6 = set_bit_u32(report.nkro_6, 1, b_idx),
+                    _ => {}
+                }
+                self.pressed = true;
+            }
+            ReportCodes::LayerToggle(layer) => {
+                self.new_layer = Some(layer);
+                self.toggle = true;
+            }
+            ReportCodes::Layer(layer) => {
+                if self.new_layer.is_none() {
+                    self.new_layer = Some(layer);
+                }
+            }
+            ReportCodes::OneShotLayer(layer) => {
+                self.one_shot_key = Some(layer);
+            }
+            ReportCodes::AnalogLayer {
+                partial,
+                full,
+                bottomed,
+            } => {
+                if self.analog_layer.is_none() {
+                    self.analog_layer = Some((partial, full, bottomed));
+                }
+            }
+            ReportCodes::Sticky => {
+                self.stick = true;
+            }
+            ReportCodes::ModSwap { .. } => {}
+            #[cfg(feature = "mouse")]
+            ReportCodes::MouseButton(_)
+            | ReportCodes::MouseX(_)
+            | ReportCodes::MouseY(_)
+            | ReportCodes::MouseScroll(_)
+            | ReportCodes::MouseHScroll(_) => {}
+        }
+    }
+}
+
+/// Keyboard report and layer state that's carried between scans. Doesn't read
+/// the time itself so it can be driven by tests
+struct ReportState {
     key_report: KeyboardReportNKRO,
-    mouse_report: MouseReport,
-    mouse_delta: MouseDelta,
-    scroll_delta: MouseDelta,
     current_layer: usize,
     reset_layer: usize,
     stick: State,
+    one_shot: OneShot,
+    one_shot_key: Option<u8>,
+    // Set once an analog layer key bottoms out so the full layer stays
+    // active until the key is released
+    analog_bottomed: bool,
+    // Last scan with a key pressed, used to time out toggled layers
+    last_press: Instant,
 }
 
-impl Report {
-    pub fn new() -> Self {
+impl ReportState {
+    fn new() -> Self {
         Self {
             key_report: KeyboardReportNKRO::default(),
-            mouse_report: MouseReport::default(),
-            mouse_delta: MouseDelta::new(1000000, 500000),
-            scroll_delta: MouseDelta::new(1000000, 500000),
             current_layer: 0,
             reset_layer: 0,
             stick: State::None,
+            one_shot: OneShot::None,
+            one_shot_key: None,
+            analog_bottomed: false,
+            last_press: Instant::MIN,
         }
     }
 
-    /// Generates a report with the provided keys. Returns a option tuple
-    /// where it returns a Some when a report need to be sent
-    pub async fn generate_report<I: ConfigIndicator, K: KeyState, M: RawMutex>(
-        &mut self,
-        keys: &Mutex<M, Keys<I>>,
-        positions: &[K; NUM_KEYS],
-    ) -> (Option<&KeyboardReportNKRO>, Option<&MouseReport>) {
-        let mut new_layer = None;
-        let mut pressed_keys = Vec::new();
-        let mut new_key_report = KeyboardReportNKRO::default();
-        let mut new_mouse_report = MouseReport::default();
-        let mut pressed = false;
-        let mut stick = false;
-        let mut toggle = false;
-        keys.lock()
-            .await
-            .get_keys(self.current_layer, &mut pressed_keys, positions)
-            .await;
-        for key in pressed_keys {
-            match key {
-                ReportCodes::Modifier(code) => {
-                    let b_idx = code % 8;
-                    set_bit(&mut new_key_report.modifier, 1, b_idx);
-                }
-                ReportCodes::Letter(code) => {
-                    let n_idx = (code / 32) as usize;
-                    let b_idx = code % 32;
-                    match n_idx {
-                        0 => new_key_report.nkro_0 = set_bit_u32(new_key_report.nkro_0, 1, b_idx),
-                        1 => new_key_report.nkro_1 = set_bit_u32(new_key_report.nkro_1, 1, b_idx),
-                        2 => new_key_report.nkro_2 = set_bit_u32(new_key_report.nkro_2, 1, b_idx),
-                        3 => new_key_report.nkro_3 = set_bit_u32(new_key_report.nkro_3, 1, b_idx),
-                        4 => new_key_report.nkro_4 = set_bit_u32(new_key_report.nkro_4, 1, b_idx),
-                        5 => new_key_report.nkro_5 = set_bit_u32(new_key_report.nkro_5, 1, b_idx),
-                        6 => new_key_report.nkro_6 = set_bit_u32(new_key_report.nkro_6, 1, b_idx),
-                        _ => {}
-                    }
-                    pressed = true;
-                }
-                ReportCodes::MouseButton(code) => {
-                    let b_idx = code % 8;
-                    set_bit(&mut new_mouse_report.buttons, 1, b_idx);
-                }
-                ReportCodes::MouseX(code) => {
-                    if self.mouse_delta.check() {
-                        new_mouse_report.x += code;
-                    }
-                }
-                ReportCodes::MouseY(code) => {
-                    if self.mouse_delta.check() {
-                        new_mouse_report.y += code;
-                    }
-                }
-                ReportCodes::MouseScroll(code) => {
-                    if self.scroll_delta.check() {
-                        new_mouse_report.wheel += code;
+    /// Returns to the base layer once a toggled or latched layer has gone
+    /// without a key press for its timeout
+    fn expire_layer(&mut self, timeouts: &LayerTimeouts, pressed: bool, now: Instant) {
+        if pressed {
+            self.last_press = now;
+            return;
+        }
+        let layer = match self.one_shot {
+            OneShot::Latched(layer) => layer as usize,
+            _ => self.reset_layer,
+        };
+        if layer == 0 {
+            return;
+        }
+        if let Some(timeout) = timeouts.timeout(layer)
+            && now.saturating_duration_since(self.last_press) > timeout
+        {
+            self.one_shot = OneShot::None;
+            self.reset_layer = 0;
+            self.current_layer = 0;
+        }
+    }
+
+    /// Drops a tapped one shot layer if no key was pressed before the timeout
+    fn expire_one_shot(&mut self, now: Instant) {
+        if let OneShot::Pending { layer, tapped } = self.one_shot
+            && now.saturating_duration_since(tapped) > ONE_SHOT_TIMEOUT
+        {
+            self.one_shot = OneShot::None;
+            if self.current_layer == layer as usize {
+                self.current_layer = self.reset_layer;
+            }
+        }
+    }
+
+    /// Updates the one shot state machine with the one shot key in the current
+    /// report and whether a new key was pressed in the current report
+    fn update_one_shot(&mut self, key: Option<u8>, new_press: bool, now: Instant) {
+        match (self.one_shot_key, key) {
+            (None, Some(layer)) => {
+                self.one_shot = match self.one_shot {
+                    OneShot::Pending {
+                        layer: pending,
+                        tapped,
+                    } if pending == layer
+                        && now.saturating_duration_since(tapped) <= LATCH_TERM =>
+                    {
+                        OneShot::Latched(layer)
                     }
-                }
-                ReportCodes::LayerToggle(layer) => {
-                    match new_layer {
-                        Some(_) => {
-                            new_layer = Some(layer);
-                        }
-                        None => {
-                            new_layer = Some(layer);
-                        }
+                    OneShot::Latched(latched) if latched == layer => OneShot::None,
+                    _ => OneShot::Held { layer, used: false },
+                };
+            }
+            (Some(_), None) => {
+                if let OneShot::Held { layer, used } = self.one_shot {
+                    self.one_shot = if used {
+                        OneShot::None
+                    } else {
+                        OneShot::Pending { layer, tapped: now }
                     };
-                    toggle = true;
                 }
-                ReportCodes::Layer(layer) => {
-                    if new_layer.is_none() {
-                        new_layer = Some(layer);
-                    }
+            }
+            _ => {}
+        }
+        self.one_shot_key = key;
+
+        if new_press {
+            match self.one_shot {
+                OneShot::Held { layer, .. } => {
+                    self.one_shot = OneShot::Held { layer, used: true };
                 }
-                ReportCodes::Sticky => {
-                    stick = true;
+                OneShot::Pending { .. } => {
+                    // The pressed key already used the layer and will keep it
+                    // while it's held
+                    self.one_shot = OneShot::None;
                 }
-            };
+                OneShot::Latched(_) | OneShot::None => {}
+            }
         }
+    }
 
-        self.mouse_delta.reset();
-        self.scroll_delta.reset();
-        if stick {
-            if pressed {
+    fn update_stick(&mut self, input: &mut ReportInput) {
+        if input.stick {
+            if input.pressed {
                 match self.stick {
                     State::Stick(_) => {
                         self.stick = State::Pressed;
@@ -214,14 +417,14 @@ impl Report {
             } else {
                 match self.stick {
                     State::Stick(_) => {
-                        if new_key_report.modifier != 0 {
-                            self.stick = State::Stick(new_key_report.modifier)
+                        if input.key_report.modifier != 0 {
+                            self.stick = State::Stick(input.key_report.modifier)
                         }
                     }
                     State::Pressed => {}
                     State::None => {
-                        if new_key_report.modifier != 0 {
-                            self.stick = State::Stick(new_key_report.modifier)
+                        if input.key_report.modifier != 0 {
+                            self.stick = State::Stick(input.key_report.modifier)
                         } else {
                             self.stick = State::None;
                         }
@@ -231,8 +434,8 @@ impl Report {
         } else {
             match self.stick {
                 State::Stick(val) => {
-                    if pressed {
-                        new_key_report.modifier = val;
+                    if input.pressed {
+                        input.key_report.modifier = val;
                         self.stick = State::None;
                     }
                 }
@@ -242,32 +445,714 @@ impl Report {
                 State::None => {}
             }
         }
+    }
+
+    fn update_layer(&mut self, input: &ReportInput, now: Instant) {
+        let mut new_layer = input.new_layer;
+        match input.analog_layer {
+            Some((partial, full, bottomed)) => {
+                self.analog_bottomed |= bottomed;
+                if new_layer.is_none() {
+                    new_layer = Some(if self.analog_bottomed { full } else { partial });
+                }
+            }
+            None => {
+                self.analog_bottomed = false;
+            }
+        }
+
+        let new_press = has_new_press(&self.key_report, &input.key_report);
+        self.update_one_shot(input.one_shot_key, new_press, now);
 
         match new_layer {
             Some(layer) => {
-                if toggle {
+                if input.toggle {
                     self.reset_layer = layer as usize;
                 }
                 self.current_layer = layer as usize;
             }
             None => {
-                self.current_layer = self.reset_layer;
+                self.current_layer = match self.one_shot {
+                    OneShot::Held { layer, .. }
+                    | OneShot::Pending { layer, .. }
+                    | OneShot::Latched(layer) => layer as usize,
+                    OneShot::None => self.reset_layer,
+                };
             }
         }
-        let mut returned_report = (None, None);
-        if self.key_report != new_key_report {
-            self.key_report = new_key_report;
-            returned_report.0 = Some(&self.key_report);
+    }
+
+    /// Applies the codes pressed during a scan to the state. Returns true if
+    /// the key report changed and needs to be sent
+    fn update(&mut self, mut input: ReportInput, now: Instant) -> bool {
+        self.update_stick(&mut input);
+        self.update_layer(&input, now);
+        if self.key_report != input.key_report {
+            self.key_report = input.key_report;
+            true
+        } else {
+            false
         }
+    }
+}
 
-        if self.mouse_report.buttons != new_mouse_report.buttons
-            || new_mouse_report.x != 0
-            || new_mouse_report.y != 0
-            || new_mouse_report.wheel != 0
-        {
-            self.mouse_report = new_mouse_report;
-            returned_report.1 = Some(&self.mouse_report);
+/// Mouse half of the reports returned by [Report::generate_report]. Boards
+/// built without the mouse feature have no mouse report to send
+#[cfg(feature = "mouse")]
+pub type MouseOutput<'a> = Option<&'a MouseReport>;
+#[cfg(not(feature = "mouse"))]
+pub type MouseOutput<'a> = ();
+
+/// Output the reports of [Report] are sent to, e.g. the usb endpoints, a BLE
+/// connection or the console while testing. Every sink gets reports from the
+/// same layer, sticky key and macro handling of [Report]
+pub trait ReportSink {
+    type Error;
+    /// Sends the reports that changed, None for the ones that didn't
+    fn send(
+        &mut self,
+        key_report: Option<&KeyboardReportNKRO>,
+        mouse_report: MouseOutput<'_>,
+    ) -> impl Future<Output = Result<(), Self::Error>>;
+}
+
+/// Sink that logs the reports instead of sending them, for testing a board
+/// without a host
+pub struct LogSink;
+
+impl ReportSink for LogSink {
+    type Error = Infallible;
+
+    #[cfg_attr(not(feature = "mouse"), allow(unused_variables))]
+    async fn send(
+        &mut self,
+        key_report: Option<&KeyboardReportNKRO>,
+        mouse_report: MouseOutput<'_>,
+    ) -> Result<(), Infallible> {
+        if let Some(rep) = key_report {
+            info!(
+                "Key report: modifier {:#x}, keys {:#x} {:#x} {:#x} {:#x} {:#x} {:#x} {:#x}",
+                rep.modifier,
+                rep.nkro_0,
+                rep.nkro_1,
+                rep.nkro_2,
+                rep.nkro_3,
+                rep.nkro_4,
+                rep.nkro_5,
+                rep.nkro_6
+            );
+        }
+        #[cfg(feature = "mouse")]
+        if let Some(rep) = mouse_report {
+            info!(
+                "Mouse report: buttons {:#x}, x {}, y {}, wheel {}, pan {}",
+                rep.buttons, rep.x, rep.y, rep.wheel, rep.pan
+            );
+        }
+        Ok(())
+    }
+}
+
+pub struct Report {
+    state: ReportState,
+    #[cfg(feature = "mouse")]
+    mouse_report: MouseReport,
+    #[cfg(feature = "mouse")]
+    mouse_delta: MouseDelta,
+    #[cfg(feature = "mouse")]
+    scroll_delta: MouseDelta,
+    #[cfg(feature = "mouse")]
+    pan_delta: MouseDelta,
+    #[cfg(feature = "mouse")]
+    drag_lock: DragLock,
+    socd: SocdResolver,
+    // Last report sent by a playing dynamic macro
+    macro_report: KeyboardReportNKRO,
+    // Set when the last reports weren't sent, so the next ones go out even if
+    // nothing changed
+    resend: bool,
+}
+
+impl Report {
+    pub fn new() -> Self {
+        Self {
+            state: ReportState::new(),
+            #[cfg(feature = "mouse")]
+            mouse_report: MouseReport::default(),
+            #[cfg(feature = "mouse")]
+            mouse_delta: MouseDelta::new(),
+            #[cfg(feature = "mouse")]
+            scroll_delta: MouseDelta::new(),
+            #[cfg(feature = "mouse")]
+            pan_delta: MouseDelta::new(),
+            #[cfg(feature = "mouse")]
+            drag_lock: DragLock::new(),
+            socd: SocdResolver::new(),
+            macro_report: KeyboardReportNKRO::default(),
+            resend: false,
+        }
+    }
+
+    /// Makes the next generated report return the key and mouse reports even
+    /// if they didn't change. Used after a report couldn't be written so the
+    /// host doesn't miss a release
+    pub fn resend(&mut self) {
+        self.resend = true;
+    }
+
+    /// Generates a report with the provided keys. Returns a option tuple
+    /// where it returns a Some when a report need to be sent
+    pub async fn generate_report<I: ConfigIndicator, K: KeyState, M: RawMutex>(
+        &mut self,
+        keys: &Mutex<M, Keys<I>>,
+        positions: &[K; NUM_KEYS],
+    ) -> (Option<&KeyboardReportNKRO>, MouseOutput<'_>) {
+        let mut pressed_keys = Vec::new();
+        let mut input = ReportInput::default();
+        #[cfg(feature = "mouse")]
+        let mut new_mouse_report = MouseReport::default();
+        self.state.expire_one_shot(Instant::now());
+        #[cfg_attr(not(feature = "mouse"), allow(unused_variables))]
+        let (mouse_settings, mouse_speeds, socd_pairs, layer_timeouts) = {
+            let mut keys = keys.lock().await;
+            keys.get_keys(self.state.current_layer, &mut pressed_keys, positions)
+                .await;
+            (
+                keys.mouse_settings,
+                keys.layer_mouse_speeds,
+                keys.socd,
+                keys.layer_timeouts,
+            )
+        };
+        // Layers can slow down or speed up the mouse keys. Scrolling ticks
+        // faster while the host divides a notch into smaller units
+        #[cfg(feature = "mouse")]
+        let (speed, wheel_speed, pan_speed) = {
+            let layer = self.state.current_layer;
+            let scroll_speed = mouse_speeds.scale(layer, mouse_settings.scroll_speed) as u16;
+            (
+                mouse_speeds.scale(layer, mouse_settings.speed) as u16,
+                scroll_speed * hires_scroll::wheel_multiplier() as u16,
+                scroll_speed * hires_scroll::pan_multiplier() as u16,
+            )
+        };
+        let testing = self_test::update(|i| positions[i].is_pressed());
+        if reaction::update(|i| positions[i].is_pressed()) || testing {
+            pressed_keys.clear();
+        }
+        let pressed = !pressed_keys.is_empty();
+        for key in pressed_keys {
+            match key {
+                #[cfg(feature = "mouse")]
+                ReportCodes::MouseButton(code) => {
+                    let b_idx = code % 8;
+                    set_bit(&mut new_mouse_report.buttons, 1, b_idx);
+                }
+                #[cfg(feature = "mouse")]
+                ReportCodes::MouseX(code) => {
+                    if self.mouse_delta.check(mouse_settings.acceleration, speed) {
+                        new_mouse_report.x += mouse_settings.apply(MouseAxis::X, code);
+                    }
+                }
+                #[cfg(feature = "mouse")]
+                ReportCodes::MouseY(code) => {
+                    if self.mouse_delta.check(mouse_settings.acceleration, speed) {
+                        new_mouse_report.y += mouse_settings.apply(MouseAxis::Y, code);
+                    }
+                }
+                #[cfg(feature = "mouse")]
+                ReportCodes::MouseScroll(code) => {
+                    if self
+                        .scroll_delta
+                        .check(mouse_settings.acceleration, wheel_speed)
+                    {
+                        new_mouse_report.wheel += mouse_settings.apply(MouseAxis::Scroll, code);
+                    }
+                }
+                #[cfg(feature = "mouse")]
+                ReportCodes::MouseHScroll(code) => {
+                    if self.pan_delta.check(mouse_settings.acceleration, pan_speed) {
+                        new_mouse_report.pan += mouse_settings.apply(MouseAxis::Scroll, code);
+                    }
+                }
+                code => input.add_code(code),
+            };
+        }
+
+        self.socd.resolve(&socd_pairs, &mut input.key_report);
+        let now = Instant::now();
+        key_stats::record_presses(positions, now).await;
+        config_slots::confirm_running(now).await;
+        let old_report = self.state.key_report;
+        let changed = self.state.update(input, now);
+        if changed {
+            dynamic_macro::record(&old_report, &self.state.key_report);
+        }
+        let resend = core::mem::take(&mut self.resend);
+        latency::report_generated(changed, now);
+        self.state.expire_layer(&layer_timeouts, pressed, now);
+        // A playing macro replaces the keys' report until it ends
+        let key_report = match dynamic_macro::next_report() {
+            Playback::Report(report) => {
+                self.macro_report = report;
+                Some(&self.macro_report)
+            }
+            Playback::Ended => Some(&self.state.key_report),
+            Playback::Idle if changed || resend => Some(&self.state.key_report),
+            Playback::Idle => None,
+        };
+        lighting::update_input(self.state.current_layer, |i| positions[i].is_pressed());
+        display::set_layer(self.state.current_layer);
+
+        #[cfg(feature = "mouse")]
+        let mouse_report = {
+            self.mouse_delta.reset();
+            self.scroll_delta.reset();
+            self.pan_delta.reset();
+            new_mouse_report.buttons = self.drag_lock.update(
+                new_mouse_report.buttons,
+                mouse_settings.drag_lock_timeout(),
+                new_mouse_report.x != 0 || new_mouse_report.y != 0,
+                now,
+            );
+            if resend
+                || self.mouse_report.buttons != new_mouse_report.buttons
+                || new_mouse_report.x != 0
+                || new_mouse_report.y != 0
+                || new_mouse_report.wheel != 0
+                || new_mouse_report.pan != 0
+            {
+                self.mouse_report = new_mouse_report;
+                Some(&self.mouse_report)
+            } else {
+                None
+            }
+        };
+        #[cfg(not(feature = "mouse"))]
+        let mouse_report = ();
+        (key_report, mouse_report)
+    }
+
+    /// Releases every key and mouse button, e.g. when the scan was paused too
+    /// long. Returns the reports that need to be sent like [Report::generate_report].
+    /// Keys still held are pressed again by the next generated report
+    pub fn release_all(&mut self) -> (Option<&KeyboardReportNKRO>, MouseOutput<'_>) {
+        let key_report = if self.state.key_report != KeyboardReportNKRO::default() {
+            self.state.key_report = KeyboardReportNKRO::default();
+            Some(&self.state.key_report)
+        } else {
+            None
+        };
+
+        #[cfg(feature = "mouse")]
+        let mouse_report = if self.mouse_report.buttons != 0 {
+            self.drag_lock = DragLock::new();
+            self.mouse_report = MouseReport::default();
+            Some(&self.mouse_report)
+        } else {
+            None
+        };
+        #[cfg(not(feature = "mouse"))]
+        let mouse_report = ();
+        (key_report, mouse_report)
+    }
+
+    /// Generates a report like [Report::generate_report] and sends it to the
+    /// sink. Reports the sink fails to send go out again with the next one
+    pub async fn send_report<I: ConfigIndicator, K: KeyState, M: RawMutex, S: ReportSink>(
+        &mut self,
+        keys: &Mutex<M, Keys<I>>,
+        positions: &[K; NUM_KEYS],
+        sink: &mut S,
+    ) -> Result<(), S::Error> {
+        let (key_report, mouse_report) = self.generate_report(keys, positions).await;
+        let res = sink.send(key_report, mouse_report).await;
+        self.resend |= res.is_err();
+        res
+    }
+
+    /// Releases every key like [Report::release_all] and sends the release to
+    /// the sink
+    pub async fn send_release_all<S: ReportSink>(&mut self, sink: &mut S) -> Result<(), S::Error> {
+        let (key_report, mouse_report) = self.release_all();
+        let res = sink.send(key_report, mouse_report).await;
+        self.resend |= res.is_err();
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embassy_futures::block_on;
+    use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+
+    use super::*;
+    use crate::{codes::ScanCodeBehavior, position::DefaultSwitch, scan_codes::KeyCodes};
+
+    fn input(codes: &[KeyCodes]) -> ReportInput {
+        let mut input = ReportInput::default();
+        for code in codes {
+            input.add_code((*code).into());
+        }
+        input
+    }
+
+    fn sticky_input(codes: &[KeyCodes]) -> ReportInput {
+        let mut input = input(codes);
+        input.add_code(ReportCodes::Sticky);
+        input
+    }
+
+    fn at(ms: u64) -> Instant {
+        Instant::from_millis(ms)
+    }
+
+    fn modifier(state: &ReportState) -> u8 {
+        state.key_report.modifier
+    }
+
+    fn a_pressed(state: &ReportState) -> bool {
+        let nkro_0 = state.key_report.nkro_0;
+        nkro_0 & (1 << KeyCodes::KeyboardAa as u8) != 0
+    }
+
+    const SHIFT: u8 =
+        1 << (KeyCodes::KeyboardLeftShift as u8 - KeyCodes::KeyboardLeftControl as u8);
+
+    #[test]
+    fn report_only_changes_on_new_codes() {
+        let mut state = ReportState::new();
+        assert!(state.update(input(&[KeyCodes::KeyboardAa]), at(0)));
+        assert!(a_pressed(&state));
+        assert!(!state.update(input(&[KeyCodes::KeyboardAa]), at(1)));
+        assert!(state.update(input(&[]), at(2)));
+        assert!(!a_pressed(&state));
+    }
+
+    #[test]
+    fn momentary_layer_resets_on_release() {
+        let mut state = ReportState::new();
+        state.update(input(&[KeyCodes::Layer2]), at(0));
+        assert_eq!(state.current_layer, 2);
+        state.update(input(&[]), at(1));
+        assert_eq!(state.current_layer, 0);
+    }
+
+    #[test]
+    fn toggle_wins_over_simultaneous_momentary() {
+        let mut state = ReportState::new();
+        state.update(input(&[KeyCodes::Layer2, KeyCodes::Layer1Toggle]), at(0));
+        assert_eq!(state.current_layer, 1);
+        assert_eq!(state.reset_layer, 1);
+        state.update(input(&[]), at(1));
+        assert_eq!(state.current_layer, 1);
+
+        let mut state = ReportState::new();
+        state.update(input(&[KeyCodes::Layer1Toggle, KeyCodes::Layer2]), at(0));
+        assert_eq!(state.current_layer, 1);
+        assert_eq!(state.reset_layer, 1);
+    }
+
+    #[test]
+    fn momentary_layer_over_toggled_layer() {
+        let mut state = ReportState::new();
+        state.update(input(&[KeyCodes::Layer1Toggle]), at(0));
+        state.update(input(&[]), at(1));
+        state.update(input(&[KeyCodes::Layer2]), at(2));
+        assert_eq!(state.current_layer, 2);
+        state.update(input(&[]), at(3));
+        assert_eq!(state.current_layer, 1);
+    }
+
+    #[test]
+    fn sticky_modifier_applies_to_next_key() {
+        let mut state = ReportState::new();
+        state.update(sticky_input(&[KeyCodes::KeyboardLeftShift]), at(0));
+        assert_eq!(modifier(&state), SHIFT);
+        state.update(input(&[]), at(1));
+        assert_eq!(modifier(&state), 0);
+        state.update(input(&[KeyCodes::KeyboardAa]), at(2));
+        assert_eq!(modifier(&state), SHIFT);
+        assert!(a_pressed(&state));
+        state.update(input(&[KeyCodes::KeyboardAa]), at(3));
+        assert_eq!(modifier(&state), 0);
+    }
+
+    #[test]
+    fn sticky_modifier_survives_layer_change() {
+        let mut state = ReportState::new();
+        state.update(sticky_input(&[KeyCodes::KeyboardLeftShift]), at(0));
+        state.update(input(&[]), at(1));
+        state.update(input(&[KeyCodes::Layer1]), at(2));
+        assert_eq!(state.current_layer, 1);
+        assert_eq!(modifier(&state), 0);
+        state.update(input(&[KeyCodes::Layer1, KeyCodes::KeyboardAa]), at(3));
+        assert_eq!(state.current_layer, 1);
+        assert_eq!(modifier(&state), SHIFT);
+        state.update(input(&[]), at(4));
+        assert_eq!(state.current_layer, 0);
+        assert_eq!(modifier(&state), 0);
+    }
+
+    #[test]
+    fn sticky_key_with_letter_isnt_stuck() {
+        let mut state = ReportState::new();
+        state.update(
+            sticky_input(&[KeyCodes::KeyboardLeftShift, KeyCodes::KeyboardAa]),
+            at(0),
+        );
+        assert_eq!(modifier(&state), SHIFT);
+        state.update(input(&[]), at(1));
+        state.update(input(&[KeyCodes::KeyboardAa]), at(2));
+        assert_eq!(modifier(&state), 0);
+    }
+
+    #[test]
+    fn one_shot_layer_applies_to_next_press() {
+        let mut state = ReportState::new();
+        state.update(input(&[KeyCodes::Layer1OneShot]), at(0));
+        assert_eq!(state.current_layer, 1);
+        state.update(input(&[]), at(100));
+        assert_eq!(state.current_layer, 1);
+        state.update(input(&[KeyCodes::KeyboardAa]), at(600));
+        assert!(a_pressed(&state));
+        assert_eq!(state.current_layer, 0);
+    }
+
+    #[test]
+    fn one_shot_layer_times_out() {
+        let mut state = ReportState::new();
+        state.update(input(&[KeyCodes::Layer1OneShot]), at(0));
+        state.update(input(&[]), at(100));
+        state.expire_one_shot(at(100 + ONE_SHOT_TIMEOUT.as_millis() + 1));
+        assert_eq!(state.current_layer, 0);
+    }
+
+    #[test]
+    fn held_one_shot_acts_as_momentary() {
+        let mut state = ReportState::new();
+        state.update(input(&[KeyCodes::Layer1OneShot]), at(0));
+        state.update(
+            input(&[KeyCodes::Layer1OneShot, KeyCodes::KeyboardAa]),
+            at(50),
+        );
+        assert_eq!(state.current_layer, 1);
+        state.update(input(&[]), at(500));
+        assert_eq!(state.current_layer, 0);
+    }
+
+    #[test]
+    fn double_tapped_one_shot_latches() {
+        let mut state = ReportState::new();
+        state.update(input(&[KeyCodes::Layer1OneShot]), at(0));
+        state.update(input(&[]), at(50));
+        state.update(input(&[KeyCodes::Layer1OneShot]), at(150));
+        state.update(input(&[]), at(200));
+        state.update(input(&[KeyCodes::KeyboardAa]), at(300));
+        state.update(input(&[]), at(400));
+        state.expire_one_shot(at(400 + ONE_SHOT_TIMEOUT.as_millis() * 2));
+        assert_eq!(state.current_layer, 1);
+
+        // Tapping the key again unlatches the layer
+        state.update(input(&[KeyCodes::Layer1OneShot]), at(10000));
+        state.update(input(&[]), at(10050));
+        assert_eq!(state.current_layer, 0);
+    }
+
+    #[test]
+    fn analog_layer_holds_full_layer_until_release() {
+        let analog = |bottomed| {
+            let mut input = ReportInput::default();
+            input.add_code(ReportCodes::AnalogLayer {
+                partial: 1,
+                full: 2,
+                bottomed,
+            });
+            input
+        };
+        let mut state = ReportState::new();
+        state.update(analog(false), at(0));
+        assert_eq!(state.current_layer, 1);
+        state.update(analog(true), at(1));
+        assert_eq!(state.current_layer, 2);
+        state.update(analog(false), at(2));
+        assert_eq!(state.current_layer, 2);
+        state.update(input(&[]), at(3));
+        assert_eq!(state.current_layer, 0);
+        state.update(analog(false), at(4));
+        assert_eq!(state.current_layer, 1);
+    }
+
+    fn timeouts(layer: usize, secs: u8) -> LayerTimeouts {
+        let mut timeouts = LayerTimeouts::default();
+        timeouts.0[layer] = secs;
+        timeouts
+    }
+
+    #[test]
+    fn toggled_layer_times_out_without_presses() {
+        let timeouts = timeouts(2, 5);
+        let mut state = ReportState::new();
+        state.update(input(&[KeyCodes::Layer2Toggle]), at(0));
+        state.expire_layer(&timeouts, true, at(0));
+        state.update(input(&[]), at(1));
+        state.expire_layer(&timeouts, false, at(1));
+        assert_eq!(state.current_layer, 2);
+        // A press restarts the timeout
+        state.expire_layer(&timeouts, true, at(4_000));
+        state.expire_layer(&timeouts, false, at(8_000));
+        assert_eq!(state.current_layer, 2);
+        state.expire_layer(&timeouts, false, at(9_001));
+        assert_eq!(state.current_layer, 0);
+        state.update(input(&[]), at(9_002));
+        assert_eq!(state.current_layer, 0);
+    }
+
+    #[test]
+    fn layers_without_timeout_stay_toggled() {
+        let timeouts = timeouts(2, 5);
+        let mut state = ReportState::new();
+        state.update(input(&[KeyCodes::Layer1Toggle]), at(0));
+        state.expire_layer(&timeouts, true, at(0));
+        state.update(input(&[]), at(1));
+        state.expire_layer(&timeouts, false, at(60_000));
+        assert_eq!(state.current_layer, 1);
+    }
+
+    struct NoIndicator;
+
+    impl ConfigIndicator for NoIndicator {
+        async fn indicate_config(&self, _: crate::keys::Indicate) {}
+    }
+
+    fn keys() -> Mutex<CriticalSectionRawMutex, Keys<NoIndicator>> {
+        let mut keys = Keys::default();
+        keys.set_code(ScanCodeBehavior::Single(KeyCodes::Layer1), 0, 0);
+        keys.set_code(ScanCodeBehavior::Single(KeyCodes::KeyboardAa), 1, 0);
+        keys.set_code(ScanCodeBehavior::Single(KeyCodes::KeyboardBb), 1, 1);
+        Mutex::new(keys)
+    }
+
+    fn positions(pressed: &[usize]) -> [DefaultSwitch; NUM_KEYS] {
+        let mut positions = [DefaultSwitch::DEFAULT; NUM_KEYS];
+        for &i in pressed {
+            positions[i].update_buf(true);
+        }
+        positions
+    }
+
+    fn nkro_0(report: Option<&KeyboardReportNKRO>) -> Option<u32> {
+        report.map(|report| report.nkro_0)
+    }
+
+    #[test]
+    fn layer_key_changes_generated_codes() {
+        let keys = keys();
+        let mut report = Report::new();
+        let (key_report, _) = block_on(report.generate_report(&keys, &positions(&[1])));
+        assert_eq!(nkro_0(key_report), Some(1 << KeyCodes::KeyboardAa as u8));
+        block_on(report.generate_report(&keys, &positions(&[])));
+        // The layer applies from the scan after the layer key is read
+        block_on(report.generate_report(&keys, &positions(&[0])));
+        let (key_report, _) = block_on(report.generate_report(&keys, &positions(&[0, 1])));
+        assert_eq!(nkro_0(key_report), Some(1 << KeyCodes::KeyboardBb as u8));
+    }
+
+    #[test]
+    fn release_all_sends_empty_report_once() {
+        let keys = keys();
+        let mut report = Report::new();
+        block_on(report.generate_report(&keys, &positions(&[1])));
+        let (key_report, _) = report.release_all();
+        assert_eq!(nkro_0(key_report), Some(0));
+        let (key_report, _) = report.release_all();
+        assert!(key_report.is_none());
+        // Keys still held are pressed again once the scan resumes
+        let (key_report, _) = block_on(report.generate_report(&keys, &positions(&[1])));
+        assert_eq!(nkro_0(key_report), Some(1 << KeyCodes::KeyboardAa as u8));
+    }
+
+    #[test]
+    fn resend_repeats_unchanged_report() {
+        let keys = keys();
+        let mut report = Report::new();
+        block_on(report.generate_report(&keys, &positions(&[1])));
+        let (key_report, _) = block_on(report.generate_report(&keys, &positions(&[1])));
+        assert!(key_report.is_none());
+        report.resend();
+        let (key_report, _) = block_on(report.generate_report(&keys, &positions(&[1])));
+        assert_eq!(nkro_0(key_report), Some(1 << KeyCodes::KeyboardAa as u8));
+        let (key_report, _) = block_on(report.generate_report(&keys, &positions(&[1])));
+        assert!(key_report.is_none());
+    }
+
+    // Sink that records the key reports it's given and fails while failing is
+    // set
+    #[derive(Default)]
+    struct RecordingSink {
+        sent: Vec<Option<u32>, 8>,
+        failing: bool,
+    }
+
+    impl ReportSink for RecordingSink {
+        type Error = ();
+
+        async fn send(
+            &mut self,
+            key_report: Option<&KeyboardReportNKRO>,
+            _: MouseOutput<'_>,
+        ) -> Result<(), ()> {
+            self.sent.push(nkro_0(key_report)).unwrap();
+            if self.failing { Err(()) } else { Ok(()) }
+        }
+    }
+
+    #[test]
+    fn failed_sends_are_sent_again() {
+        let keys = keys();
+        let mut report = Report::new();
+        let mut sink = RecordingSink {
+            failing: true,
+            ..Default::default()
+        };
+        let pressed = positions(&[1]);
+        assert!(block_on(report.send_report(&keys, &pressed, &mut sink)).is_err());
+        sink.failing = false;
+        assert!(block_on(report.send_report(&keys, &pressed, &mut sink)).is_ok());
+        assert!(block_on(report.send_report(&keys, &pressed, &mut sink)).is_ok());
+        assert!(block_on(report.send_release_all(&mut sink)).is_ok());
+        let a = Some(1 << KeyCodes::KeyboardAa as u8);
+        assert_eq!(sink.sent, [a, a, None, Some(0)]);
+    }
+
+    #[cfg(feature = "mouse")]
+    #[test]
+    fn drag_lock_latches_until_tapped_or_still() {
+        let timeout = Some(Duration::from_secs(1));
+        let mut lock = DragLock::new();
+        assert_eq!(lock.update(1, timeout, false, at(1000)), 1);
+        assert_eq!(lock.update(0, timeout, false, at(1010)), 1);
+        // Moving keeps the button latched past the timeout
+        assert_eq!(lock.update(0, timeout, true, at(1900)), 1);
+        assert_eq!(lock.update(0, timeout, false, at(2500)), 1);
+        assert_eq!(lock.update(1, timeout, false, at(2600)), 0);
+        assert_eq!(lock.update(0, timeout, false, at(2610)), 0);
+        assert_eq!(lock.update(1, timeout, false, at(3000)), 1);
+        assert_eq!(lock.update(0, timeout, false, at(4000)), 0);
+        assert_eq!(lock.update(1, None, false, at(5000)), 1);
+        assert_eq!(lock.update(0, None, false, at(5010)), 0);
+    }
+
+    #[cfg(feature = "mouse")]
+    #[test]
+    fn hires_scroll_ticks_as_often_as_its_multiplier() {
+        let speed = DEFAULT_MOUSE_SPEED as u16;
+        let multiplier = hires_scroll::HIRES_SCROLL_MULTIPLIER as u32;
+        for acceleration in [MouseAcceleration::Constant, MouseAcceleration::Expo] {
+            assert_eq!(
+                tick_interval(acceleration, speed * multiplier as u16, 0),
+                tick_interval(acceleration, speed, 0) / multiplier
+            );
         }
-        returned_report
     }
 }
@@ -0,0 +1,115 @@
+//! Pausing of the key scan around flash operations. Writing or erasing flash
+//! can stall the executor and skew debounce and ADC timing, so storage holds
+//! a [ScanPause] while it works on the flash and the key loops check
+//! [pause_exceeded] before scanning
+
+use core::cell::Cell;
+
+use embassy_futures::select::{Either, select};
+use embassy_sync::{
+    blocking_mutex::{Mutex, raw::CriticalSectionRawMutex},
+    signal::Signal,
+};
+use embassy_time::{Duration, Instant, Timer};
+
+/// Pauses longer than this release every key on the host, so a key held
+/// during a long erase doesn't repeat until the scan resumes
+pub const RELEASE_THRESHOLD: Duration = Duration::from_millis(20);
+
+#[derive(Clone, Copy)]
+struct PauseState {
+    depth: u8,
+    since: Instant,
+}
+
+static PAUSE: Mutex<CriticalSectionRawMutex, Cell<PauseState>> =
+    Mutex::new(Cell::new(PauseState {
+        depth: 0,
+        since: Instant::MIN,
+    }));
+static RESUME_SIGNAL: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+static SCAN_INTERVAL: Mutex<CriticalSectionRawMutex, Cell<Duration>> =
+    Mutex::new(Cell::new(Duration::from_micros(5)));
+
+/// Time the key loops wait between scans. Set from SettingId::ReportRate
+pub fn scan_interval() -> Duration {
+    SCAN_INTERVAL.lock(|interval| interval.get())
+}
+
+pub fn set_scan_interval(interval: Duration) {
+    SCAN_INTERVAL.lock(|cell| cell.set(interval));
+}
+
+/// Pauses the scan until dropped. Pauses can be nested, the scan resumes once
+/// the last one is dropped
+pub struct ScanPause {
+    _private: (),
+}
+
+impl ScanPause {
+    pub fn new() -> Self {
+        PAUSE.lock(|pause| {
+            let mut state = pause.get();
+            if state.depth == 0 {
+                state.since = Instant::now();
+            }
+            state.depth = state.depth.saturating_add(1);
+            pause.set(state);
+        });
+        Self { _private: () }
+    }
+}
+
+impl Default for ScanPause {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for ScanPause {
+    fn drop(&mut self) {
+        let resumed = PAUSE.lock(|pause| {
+            let mut state = pause.get();
+            state.depth = state.depth.saturating_sub(1);
+            let resumed = state.depth == 0;
+            pause.set(state);
+            resumed
+        });
+        if resumed {
+            RESUME_SIGNAL.signal(());
+        }
+    }
+}
+
+// Returns when the current pause started, or None if the scan isn't paused
+fn paused_since() -> Option<Instant> {
+    PAUSE.lock(|pause| {
+        let state = pause.get();
+        (state.depth > 0).then_some(state.since)
+    })
+}
+
+pub fn is_paused() -> bool {
+    paused_since().is_some()
+}
+
+/// Waits until the scan isn't paused. Only one task should wait at a time
+pub async fn wait_for_scan() {
+    while is_paused() {
+        RESUME_SIGNAL.wait().await;
+    }
+}
+
+/// Waits for a paused scan to resume until the pause reaches
+/// [RELEASE_THRESHOLD]. Returns true if it didn't resume in time, in which
+/// case the caller should send a released report and then [wait_for_scan]
+pub async fn pause_exceeded() -> bool {
+    let Some(since) = paused_since() else {
+        return false;
+    };
+    match select(wait_for_scan(), Timer::at(since + RELEASE_THRESHOLD)).await {
+        Either::First(()) => false,
+        Either::Second(()) => true,
+    }
+}
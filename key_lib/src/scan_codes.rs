@@ -339,7 +339,14 @@ pub enum KeyCodes {
     KeyboardCrSelProps = 0xA3,
     /// Keyboard ExSel
     KeyboardExSel = 0xA4,
-    // 0xA5-0xAF: Reserved
+    // 0xA5-0xAA One Shot Layer Keys (reserved range in the HID usage tables)
+    Layer0OneShot = 0xA5,
+    Layer1OneShot = 0xA6,
+    Layer2OneShot = 0xA7,
+    Layer3OneShot = 0xA8,
+    Layer4OneShot = 0xA9,
+    Layer5OneShot = 0xAA,
+    // 0xAB-0xAF: Reserved
     /// Keypad 00
     Keypad00 = 0xB0,
     /// Keypad 000
@@ -464,19 +471,37 @@ pub enum KeyCodes {
     Layer3Toggle = 0xF2,
     Layer4Toggle = 0xF3,
     Layer5Toggle = 0xF4,
+    #[cfg(feature = "mouse")]
     MouseLeftClick = 0xF5,
+    #[cfg(feature = "mouse")]
     MouseRightClick = 0xF6,
+    #[cfg(feature = "mouse")]
     MouseMiddleClick = 0xF7,
+    #[cfg(feature = "mouse")]
     MouseXPos = 0xF8,
+    #[cfg(feature = "mouse")]
     MouseXNeg = 0xF9,
+    #[cfg(feature = "mouse")]
     MouseYPos = 0xFA,
+    #[cfg(feature = "mouse")]
     MouseYNeg = 0xFB,
+    #[cfg(feature = "mouse")]
     MouseScrollPos = 0xFC,
+    #[cfg(feature = "mouse")]
     MouseScrollNeg = 0xFD,
+    #[cfg(feature = "mouse")]
+    MouseHScrollPos = 0xFE,
+    #[cfg(feature = "mouse")]
+    MouseHScrollNeg = 0xFF,
 }
 
 impl From<u8> for KeyCodes {
     fn from(value: u8) -> Self {
+        // Mouse codes don't exist without the mouse feature so they can't be transmuted
+        #[cfg(not(feature = "mouse"))]
+        if (0xF5..=0xFF).contains(&value) {
+            return KeyCodes::Undefined;
+        }
         unsafe { mem::transmute(value) }
     }
 }
@@ -487,27 +512,81 @@ pub enum ReportCodes {
     Modifier(u8),
     Layer(u8),
     LayerToggle(u8),
+    #[cfg(feature = "mouse")]
     MouseButton(u8),
+    #[cfg(feature = "mouse")]
     MouseX(i8),
+    #[cfg(feature = "mouse")]
     MouseY(i8),
+    #[cfg(feature = "mouse")]
     MouseScroll(i8),
+    #[cfg(feature = "mouse")]
+    MouseHScroll(i8),
+    OneShotLayer(u8),
+    // Layer from a key with partial and full travel layers
+    AnalogLayer {
+        partial: u8,
+        full: u8,
+        bottomed: bool,
+    },
     Sticky,
+    // Key that depends on the held modifiers. Resolved once every key has been
+    // read since the modifiers can come from keys read after it
+    ModSwap {
+        normal: KeyCodes,
+        swapped: KeyCodes,
+        mods: u8,
+    },
+}
+
+impl ReportCodes {
+    /// Priority used to pick which code to drop when too many are pressed. Layer
+    /// codes are kept first since dropping them changes what every other key sends
+    pub fn priority(&self) -> u8 {
+        match self {
+            ReportCodes::Layer(_)
+            | ReportCodes::LayerToggle(_)
+            | ReportCodes::OneShotLayer(_)
+            | ReportCodes::AnalogLayer { .. }
+            | ReportCodes::Sticky => 3,
+            ReportCodes::Modifier(_) | ReportCodes::ModSwap { .. } => 2,
+            ReportCodes::Letter(_) => 1,
+            #[cfg(feature = "mouse")]
+            ReportCodes::MouseButton(_)
+            | ReportCodes::MouseX(_)
+            | ReportCodes::MouseY(_)
+            | ReportCodes::MouseScroll(_)
+            | ReportCodes::MouseHScroll(_) => 0,
+        }
+    }
 }
 
 impl From<KeyCodes> for ReportCodes {
     fn from(value: KeyCodes) -> Self {
         match value as u8 {
+            0xA5..=0xAA => ReportCodes::OneShotLayer(value as u8 - KeyCodes::Layer0OneShot as u8),
             0x00..=0xDF => ReportCodes::Letter(value as u8),
             0xE0..=0xE8 => ReportCodes::Modifier(value as u8 - KeyCodes::KeyboardLeftControl as u8),
             0xE9..=0xEE => ReportCodes::Layer(value as u8 - KeyCodes::Layer0 as u8),
             0xEF..=0xF4 => ReportCodes::LayerToggle(value as u8 - KeyCodes::Layer0Toggle as u8),
+            #[cfg(feature = "mouse")]
             0xF5..=0xF7 => ReportCodes::MouseButton(value as u8 - KeyCodes::MouseLeftClick as u8),
+            #[cfg(feature = "mouse")]
             0xF8 => ReportCodes::MouseX(1),
+            #[cfg(feature = "mouse")]
             0xF9 => ReportCodes::MouseX(-1),
+            #[cfg(feature = "mouse")]
             0xFA => ReportCodes::MouseY(1),
+            #[cfg(feature = "mouse")]
             0xFB => ReportCodes::MouseY(-1),
+            #[cfg(feature = "mouse")]
             0xFC => ReportCodes::MouseScroll(1),
+            #[cfg(feature = "mouse")]
             0xFD => ReportCodes::MouseScroll(-1),
+            #[cfg(feature = "mouse")]
+            0xFE => ReportCodes::MouseHScroll(1),
+            #[cfg(feature = "mouse")]
+            0xFF => ReportCodes::MouseHScroll(-1),
             _ => ReportCodes::Letter(KeyCodes::Undefined as u8),
         }
     }
@@ -0,0 +1,280 @@
+//! Hardware self test started with HidRequest::SelfTest, for checking newly
+//! assembled boards. While it runs every key has to be pressed and released
+//! once, and keys are held back from the reports so the test doesn't type
+//! into the host. Boards with per key leds cycle every led through red,
+//! green, blue and white so dead leds and color channels show.
+//!
+//! Boards with a radio check their link when the test starts, see
+//! [wait_self_test_start], and record the outcome with [record_radio_check].
+//! The host reads the [SelfTestReport] while the test runs and stops the test
+//! once every key passed or it gives up. A summary is logged when it stops
+
+use core::cell::Cell;
+
+use defmt::{Format, info, warn};
+use embassy_sync::{
+    blocking_mutex::{self, raw::CriticalSectionRawMutex},
+    signal::Signal,
+};
+use embassy_time::{Duration, Instant};
+use num_enum::TryFromPrimitive;
+
+use crate::{NUM_KEYS, lighting::Color};
+
+pub const SELF_TEST_REPORT_SERIAL_LENGTH: usize = 2 + NUM_KEYS;
+
+// Time each color of the led sweep is shown
+const SWEEP_STEP: Duration = Duration::from_millis(500);
+
+const SWEEP_COLORS: [Color; 4] = [
+    Color::new(255, 0, 0),
+    Color::new(0, 255, 0),
+    Color::new(0, 0, 255),
+    Color::new(255, 255, 255),
+];
+
+static TEST: blocking_mutex::Mutex<CriticalSectionRawMutex, Cell<SelfTest>> =
+    blocking_mutex::Mutex::new(Cell::new(SelfTest::new()));
+
+/// Signaled when a test starts, so boards with a radio can check it
+static STARTED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Format, TryFromPrimitive)]
+pub enum SelfTestCommand {
+    /// Starts a test, replacing any running one
+    Start = 0,
+    /// Responds with the serialized [SelfTestReport]
+    Read = 1,
+    /// Stops the test and responds with its final report
+    Stop = 2,
+}
+
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Format)]
+pub enum KeyResult {
+    /// Not pressed since the test started
+    Untested = 0,
+    /// Held since the test started
+    Stuck = 1,
+    /// Pressed but not released yet
+    Pressed = 2,
+    /// Pressed and released
+    Passed = 3,
+}
+
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Format)]
+pub enum RadioCheck {
+    /// The board has no radio or doesn't check it
+    Unsupported = 0,
+    Running = 1,
+    Passed = 2,
+    Failed = 3,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Format)]
+pub struct SelfTestReport {
+    pub running: bool,
+    pub radio: RadioCheck,
+    pub keys: [KeyResult; NUM_KEYS],
+}
+
+impl SelfTestReport {
+    /// Returns true once every key passed and the radio check didn't fail
+    pub fn passed(&self) -> bool {
+        self.keys.iter().all(|&key| key == KeyResult::Passed)
+            && matches!(self.radio, RadioCheck::Unsupported | RadioCheck::Passed)
+    }
+
+    fn count(&self, result: KeyResult) -> usize {
+        self.keys.iter().filter(|&&key| key == result).count()
+    }
+
+    /// Writes whether the test runs and the radio check, followed by the
+    /// KeyResult of each key
+    pub fn to_bytes(&self) -> [u8; SELF_TEST_REPORT_SERIAL_LENGTH] {
+        let mut buf = [0u8; SELF_TEST_REPORT_SERIAL_LENGTH];
+        buf[0] = self.running as u8;
+        buf[1] = self.radio as u8;
+        for (byte, key) in buf[2..].iter_mut().zip(self.keys) {
+            *byte = key as u8;
+        }
+        buf
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct SelfTest {
+    report: SelfTestReport,
+    started: Instant,
+    // Keys held on the first scan of a test are stuck until released
+    first_scan: bool,
+}
+
+impl SelfTest {
+    const fn new() -> Self {
+        Self {
+            report: SelfTestReport {
+                running: false,
+                radio: RadioCheck::Unsupported,
+                keys: [KeyResult::Untested; NUM_KEYS],
+            },
+            started: Instant::from_ticks(0),
+            first_scan: false,
+        }
+    }
+
+    fn start(&mut self, now: Instant) {
+        *self = Self::new();
+        self.report.running = true;
+        self.started = now;
+        self.first_scan = true;
+    }
+
+    /// Advances the test with the keys pressed this scan
+    fn update(&mut self, pressed: impl Fn(usize) -> bool) {
+        let first_scan = core::mem::take(&mut self.first_scan);
+        for (i, key) in self.report.keys.iter_mut().enumerate() {
+            *key = match (*key, pressed(i)) {
+                (KeyResult::Untested, true) if first_scan => KeyResult::Stuck,
+                (KeyResult::Untested, true) => KeyResult::Pressed,
+                // Stuck keys that come free still need a real press
+                (KeyResult::Stuck, false) => KeyResult::Untested,
+                (KeyResult::Pressed, false) => KeyResult::Passed,
+                (result, _) => result,
+            };
+        }
+    }
+}
+
+/// Starts a test, replacing any running one
+pub fn start_self_test() {
+    info!("Starting self test");
+    TEST.lock(|test| {
+        let mut state = test.get();
+        state.start(Instant::now());
+        test.set(state);
+    });
+    STARTED.signal(());
+}
+
+/// Stops the test, logs a summary and returns its final report
+pub fn stop_self_test() -> SelfTestReport {
+    let report = TEST.lock(|test| {
+        let mut state = test.get();
+        state.report.running = false;
+        test.set(state);
+        state.report
+    });
+    let summary = (
+        report.count(KeyResult::Passed),
+        report.count(KeyResult::Untested) + report.count(KeyResult::Pressed),
+        report.count(KeyResult::Stuck),
+    );
+    if report.passed() {
+        info!("Self test passed, radio {}", report.radio);
+    } else {
+        warn!(
+            "Self test failed: {} of {} keys passed, {} untested, {} stuck, radio {}",
+            summary.0, NUM_KEYS, summary.1, summary.2, report.radio
+        );
+    }
+    report
+}
+
+pub fn self_test_report() -> SelfTestReport {
+    TEST.lock(|test| test.get().report)
+}
+
+/// Advances the running test with the pressed keys. Returns true while the
+/// keys should be held back from the report
+pub(crate) fn update(pressed: impl Fn(usize) -> bool) -> bool {
+    TEST.lock(|test| {
+        let mut state = test.get();
+        if !state.report.running {
+            return false;
+        }
+        state.update(pressed);
+        test.set(state);
+        true
+    })
+}
+
+/// Returns the color every led should show while a test runs
+pub fn sweep_color(now: Instant) -> Option<Color> {
+    let test = TEST.lock(|test| test.get());
+    if !test.report.running {
+        return None;
+    }
+    let step = now.saturating_duration_since(test.started).as_ticks() / SWEEP_STEP.as_ticks();
+    Some(SWEEP_COLORS[step as usize % SWEEP_COLORS.len()])
+}
+
+/// Waits for a test to start
+pub async fn wait_self_test_start() {
+    STARTED.wait().await
+}
+
+/// Records the outcome of the radio check of the running test
+pub fn record_radio_check(result: RadioCheck) {
+    info!("Self test radio check: {}", result);
+    TEST.lock(|test| {
+        let mut state = test.get();
+        state.report.radio = result;
+        test.set(state);
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn started() -> SelfTest {
+        let mut test = SelfTest::new();
+        test.start(Instant::from_secs(10));
+        test
+    }
+
+    #[test]
+    fn keys_pass_once_pressed_and_released() {
+        let mut test = started();
+        test.update(|_| false);
+        test.update(|i| i == 0);
+        assert_eq!(test.report.keys[0], KeyResult::Pressed);
+        test.update(|_| false);
+        assert_eq!(test.report.keys[0], KeyResult::Passed);
+        assert_eq!(test.report.keys[1], KeyResult::Untested);
+        assert!(!test.report.passed());
+        test.update(|_| true);
+        test.update(|_| false);
+        assert!(test.report.passed());
+    }
+
+    #[test]
+    fn keys_held_at_start_are_stuck() {
+        let mut test = started();
+        test.update(|i| i == 1);
+        test.update(|i| i == 1);
+        assert_eq!(test.report.keys[1], KeyResult::Stuck);
+        // Released keys have to be pressed again to pass
+        test.update(|_| false);
+        assert_eq!(test.report.keys[1], KeyResult::Untested);
+        test.update(|i| i == 1);
+        test.update(|_| false);
+        assert_eq!(test.report.keys[1], KeyResult::Passed);
+    }
+
+    #[test]
+    fn failed_radio_check_fails_the_test() {
+        let mut test = started();
+        test.update(|_| false);
+        test.update(|_| true);
+        test.update(|_| false);
+        test.report.radio = RadioCheck::Failed;
+        assert!(!test.report.passed());
+        test.report.radio = RadioCheck::Passed;
+        assert!(test.report.passed());
+        assert_eq!(test.report.to_bytes()[..3], [1, 2, 3]);
+    }
+}
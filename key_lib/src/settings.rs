@@ -0,0 +1,498 @@
+use defmt::Format;
+use embassy_time::Duration;
+use heapless::String;
+use num_enum::TryFromPrimitive;
+use sequential_storage::map::{SerializationError, Value};
+
+use crate::{NUM_KEYS, NUM_LAYERS};
+
+pub const MOUSE_SETTINGS_SERIAL_LENGTH: usize = 5;
+const LEGACY_MOUSE_SETTINGS_SERIAL_LENGTH: usize = 1;
+const PRE_DRAG_LOCK_MOUSE_SETTINGS_SERIAL_LENGTH: usize = 4;
+pub const SWITCH_PROFILES_SERIAL_LENGTH: usize = NUM_KEYS;
+pub const LAYER_TIMEOUTS_SERIAL_LENGTH: usize = NUM_LAYERS;
+pub const LAYER_MOUSE_SPEEDS_SERIAL_LENGTH: usize = NUM_LAYERS;
+pub const STANDALONE_LAYER_SERIAL_LENGTH: usize = 1;
+const AUTO_SHIFT_MASK_LEN: usize = NUM_KEYS.div_ceil(8);
+pub const AUTO_SHIFT_SERIAL_LENGTH: usize = 1 + AUTO_SHIFT_MASK_LEN;
+/// Longest name that can be stored in a [ConfigInfo]
+pub const MAX_CONFIG_NAME_LEN: usize = 16;
+pub const CONFIG_INFO_SERIAL_LENGTH: usize = 4 + MAX_CONFIG_NAME_LEN;
+
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Format, TryFromPrimitive)]
+pub enum MouseAxis {
+    X = 0,
+    Y = 1,
+    Scroll = 2,
+}
+
+/// How mouse keys speed up while they're held
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Format, TryFromPrimitive)]
+pub enum MouseAcceleration {
+    /// Moves at the same rate for as long as the key is held
+    Constant = 0,
+    /// Speeds up at a steady rate until it reaches full speed
+    Linear = 1,
+    /// Starts slow for precise movements then quickly reaches full speed
+    Expo = 2,
+}
+
+/// Speed that moves the mouse at the rate of the acceleration curve
+pub const DEFAULT_MOUSE_SPEED: u8 = 10;
+
+/// Mouse settings that are scoped to a single config
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Format)]
+pub struct MouseSettings {
+    pub invert_x: bool,
+    pub invert_y: bool,
+    /// Inverts the scroll wheel for natural scrolling
+    pub invert_scroll: bool,
+    pub acceleration: MouseAcceleration,
+    /// Pointer speed in tenths, so [DEFAULT_MOUSE_SPEED] is the speed of the
+    /// curve and 20 moves twice as often
+    pub speed: u8,
+    /// Scroll speed in tenths like speed
+    pub scroll_speed: u8,
+    /// Seconds a mouse button tapped with drag lock stays held without the
+    /// pointer moving. 0 turns drag lock off
+    pub drag_lock: u8,
+}
+
+impl MouseSettings {
+    pub const fn default() -> Self {
+        Self {
+            invert_x: false,
+            invert_y: false,
+            invert_scroll: false,
+            acceleration: MouseAcceleration::Expo,
+            speed: DEFAULT_MOUSE_SPEED,
+            scroll_speed: DEFAULT_MOUSE_SPEED,
+            drag_lock: 0,
+        }
+    }
+
+    /// Returns how long a latched button stays held, or None if drag lock is
+    /// off
+    pub fn drag_lock_timeout(&self) -> Option<Duration> {
+        (self.drag_lock != 0).then(|| Duration::from_secs(self.drag_lock as u64))
+    }
+
+    pub fn toggle_invert(&mut self, axis: MouseAxis) {
+        match axis {
+            MouseAxis::X => self.invert_x = !self.invert_x,
+            MouseAxis::Y => self.invert_y = !self.invert_y,
+            MouseAxis::Scroll => self.invert_scroll = !self.invert_scroll,
+        }
+    }
+
+    /// Returns the movement for the axis with the inversion applied
+    pub fn apply(&self, axis: MouseAxis, val: i8) -> i8 {
+        let inverted = match axis {
+            MouseAxis::X => self.invert_x,
+            MouseAxis::Y => self.invert_y,
+            MouseAxis::Scroll => self.invert_scroll,
+        };
+        if inverted { val.saturating_neg() } else { val }
+    }
+}
+
+impl<'a> Value<'a> for MouseSettings {
+    fn serialize_into(&self, buffer: &mut [u8]) -> Result<usize, SerializationError> {
+        if buffer.len() < MOUSE_SETTINGS_SERIAL_LENGTH {
+            Err(SerializationError::BufferTooSmall)
+        } else {
+            buffer[0] = (self.invert_x as u8)
+                | ((self.invert_y as u8) << 1)
+                | ((self.invert_scroll as u8) << 2);
+            buffer[1] = self.acceleration as u8;
+            buffer[2] = self.speed;
+            buffer[3] = self.scroll_speed;
+            buffer[4] = self.drag_lock;
+            Ok(MOUSE_SETTINGS_SERIAL_LENGTH)
+        }
+    }
+
+    // Settings stored before the acceleration was added are only the invert
+    // byte and get the default acceleration and speeds. Settings stored before
+    // drag lock was added have it off
+    fn deserialize_from(buffer: &'a [u8]) -> Result<(Self, usize), SerializationError>
+    where
+        Self: Sized,
+    {
+        let Some(&inverts) = buffer.first() else {
+            return Err(SerializationError::BufferTooSmall);
+        };
+        let mut settings = Self {
+            invert_x: inverts & 1 != 0,
+            invert_y: (inverts >> 1) & 1 != 0,
+            invert_scroll: (inverts >> 2) & 1 != 0,
+            ..Self::default()
+        };
+        if buffer.len() < PRE_DRAG_LOCK_MOUSE_SETTINGS_SERIAL_LENGTH {
+            return Ok((settings, LEGACY_MOUSE_SETTINGS_SERIAL_LENGTH));
+        }
+        settings.acceleration = MouseAcceleration::try_from(buffer[1])
+            .map_err(|_| SerializationError::InvalidFormat)?;
+        settings.speed = buffer[2];
+        settings.scroll_speed = buffer[3];
+        if settings.speed == 0 || settings.scroll_speed == 0 {
+            return Err(SerializationError::InvalidFormat);
+        }
+        let Some(&drag_lock) = buffer.get(4) else {
+            return Ok((settings, PRE_DRAG_LOCK_MOUSE_SETTINGS_SERIAL_LENGTH));
+        };
+        settings.drag_lock = drag_lock;
+        Ok((settings, MOUSE_SETTINGS_SERIAL_LENGTH))
+    }
+}
+
+/// How a hall effect switch turns its position into presses
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Format, TryFromPrimitive)]
+pub enum SwitchProfile {
+    /// Rapid trigger, where the key is pressed and released by movement
+    Wooting = 0,
+    /// Fixed actuation and release points like a mechanical switch
+    Digital = 1,
+}
+
+/// Switch profile of each key. Scoped to a single config
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Format)]
+pub struct SwitchProfiles(pub [SwitchProfile; NUM_KEYS]);
+
+impl SwitchProfiles {
+    pub const fn default() -> Self {
+        Self([SwitchProfile::Wooting; NUM_KEYS])
+    }
+}
+
+impl<'a> Value<'a> for SwitchProfiles {
+    fn serialize_into(&self, buffer: &mut [u8]) -> Result<usize, SerializationError> {
+        if buffer.len() < SWITCH_PROFILES_SERIAL_LENGTH {
+            return Err(SerializationError::BufferTooSmall);
+        }
+        buffer
+            .iter_mut()
+            .zip(self.0.iter())
+            .for_each(|(byte, profile)| *byte = *profile as u8);
+        Ok(SWITCH_PROFILES_SERIAL_LENGTH)
+    }
+
+    fn deserialize_from(buffer: &'a [u8]) -> Result<(Self, usize), SerializationError>
+    where
+        Self: Sized,
+    {
+        if buffer.len() < SWITCH_PROFILES_SERIAL_LENGTH {
+            return Err(SerializationError::BufferTooSmall);
+        }
+        let mut profiles = Self::default();
+        for (profile, byte) in profiles.0.iter_mut().zip(buffer.iter()) {
+            *profile =
+                SwitchProfile::try_from(*byte).map_err(|_| SerializationError::InvalidFormat)?;
+        }
+        Ok((profiles, SWITCH_PROFILES_SERIAL_LENGTH))
+    }
+}
+
+/// Seconds without a key press before a toggled or latched layer returns to
+/// the base layer, indexed by layer. 0 keeps the layer until it's changed.
+/// Scoped to a single config
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Format)]
+pub struct LayerTimeouts(pub [u8; NUM_LAYERS]);
+
+impl LayerTimeouts {
+    pub const fn default() -> Self {
+        Self([0; NUM_LAYERS])
+    }
+
+    /// Returns the timeout of the layer, or None if it doesn't time out
+    pub fn timeout(&self, layer: usize) -> Option<Duration> {
+        self.0
+            .get(layer)
+            .filter(|secs| **secs != 0)
+            .map(|secs| Duration::from_secs(*secs as u64))
+    }
+}
+
+impl<'a> Value<'a> for LayerTimeouts {
+    fn serialize_into(&self, buffer: &mut [u8]) -> Result<usize, SerializationError> {
+        if buffer.len() < LAYER_TIMEOUTS_SERIAL_LENGTH {
+            return Err(SerializationError::BufferTooSmall);
+        }
+        buffer[..LAYER_TIMEOUTS_SERIAL_LENGTH].copy_from_slice(&self.0);
+        Ok(LAYER_TIMEOUTS_SERIAL_LENGTH)
+    }
+
+    fn deserialize_from(buffer: &'a [u8]) -> Result<(Self, usize), SerializationError>
+    where
+        Self: Sized,
+    {
+        if buffer.len() < LAYER_TIMEOUTS_SERIAL_LENGTH {
+            return Err(SerializationError::BufferTooSmall);
+        }
+        let mut timeouts = Self::default();
+        timeouts
+            .0
+            .copy_from_slice(&buffer[..LAYER_TIMEOUTS_SERIAL_LENGTH]);
+        Ok((timeouts, LAYER_TIMEOUTS_SERIAL_LENGTH))
+    }
+}
+
+/// Multiplier of the mouse and scroll speeds in tenths while a layer is
+/// active, indexed by layer. [DEFAULT_MOUSE_SPEED] keeps the config's speeds,
+/// so a precision layer can use 5 to move at half speed and a fast layer 30
+/// to move three times as fast with the same mouse keys. Scoped to a single
+/// config
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Format)]
+pub struct LayerMouseSpeeds(pub [u8; NUM_LAYERS]);
+
+impl LayerMouseSpeeds {
+    pub const fn default() -> Self {
+        Self([DEFAULT_MOUSE_SPEED; NUM_LAYERS])
+    }
+
+    /// Returns the speed with the layer's multiplier applied
+    pub fn scale(&self, layer: usize, speed: u8) -> u8 {
+        let multiplier = self.0.get(layer).copied().unwrap_or(DEFAULT_MOUSE_SPEED);
+        (speed as u16 * multiplier as u16 / DEFAULT_MOUSE_SPEED as u16).clamp(1, u8::MAX as u16)
+            as u8
+    }
+}
+
+impl<'a> Value<'a> for LayerMouseSpeeds {
+    fn serialize_into(&self, buffer: &mut [u8]) -> Result<usize, SerializationError> {
+        if buffer.len() < LAYER_MOUSE_SPEEDS_SERIAL_LENGTH {
+            return Err(SerializationError::BufferTooSmall);
+        }
+        buffer[..LAYER_MOUSE_SPEEDS_SERIAL_LENGTH].copy_from_slice(&self.0);
+        Ok(LAYER_MOUSE_SPEEDS_SERIAL_LENGTH)
+    }
+
+    fn deserialize_from(buffer: &'a [u8]) -> Result<(Self, usize), SerializationError>
+    where
+        Self: Sized,
+    {
+        if buffer.len() < LAYER_MOUSE_SPEEDS_SERIAL_LENGTH {
+            return Err(SerializationError::BufferTooSmall);
+        }
+        // A multiplier of 0 would stop the mouse on the layer
+        if buffer[..LAYER_MOUSE_SPEEDS_SERIAL_LENGTH].contains(&0) {
+            return Err(SerializationError::InvalidFormat);
+        }
+        let mut speeds = Self::default();
+        speeds
+            .0
+            .copy_from_slice(&buffer[..LAYER_MOUSE_SPEEDS_SERIAL_LENGTH]);
+        Ok((speeds, LAYER_MOUSE_SPEEDS_SERIAL_LENGTH))
+    }
+}
+
+/// Layer whose codes replace the base layer while the other half of a split
+/// board isn't connected, e.g. so a half used on its own can be a macropad. 0
+/// keeps the base layer. Scoped to a single config
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Format)]
+pub struct StandaloneLayer(pub u8);
+
+impl StandaloneLayer {
+    pub const fn default() -> Self {
+        Self(0)
+    }
+
+    /// Returns the layer read in place of the base layer, or None if it's kept
+    pub fn layer(&self) -> Option<usize> {
+        (self.0 != 0).then_some(self.0 as usize)
+    }
+}
+
+impl<'a> Value<'a> for StandaloneLayer {
+    fn serialize_into(&self, buffer: &mut [u8]) -> Result<usize, SerializationError> {
+        if buffer.len() < STANDALONE_LAYER_SERIAL_LENGTH {
+            return Err(SerializationError::BufferTooSmall);
+        }
+        buffer[0] = self.0;
+        Ok(STANDALONE_LAYER_SERIAL_LENGTH)
+    }
+
+    fn deserialize_from(buffer: &'a [u8]) -> Result<(Self, usize), SerializationError>
+    where
+        Self: Sized,
+    {
+        let &layer = buffer.first().ok_or(SerializationError::BufferTooSmall)?;
+        if layer as usize >= NUM_LAYERS {
+            return Err(SerializationError::InvalidFormat);
+        }
+        Ok((Self(layer), STANDALONE_LAYER_SERIAL_LENGTH))
+    }
+}
+
+/// Keys that send their shifted code when held past the timeout instead of
+/// repeating. Only keys that send a single character are shifted. Scoped to a
+/// single config
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Format)]
+pub struct AutoShiftSettings {
+    /// Tens of milliseconds a key has to be held before it's shifted. 0 turns
+    /// auto shift off
+    pub timeout: u8,
+    /// Bit per key of the keys that are shifted
+    pub keys: [u8; AUTO_SHIFT_MASK_LEN],
+}
+
+impl AutoShiftSettings {
+    pub const fn default() -> Self {
+        Self {
+            timeout: 0,
+            keys: [0; AUTO_SHIFT_MASK_LEN],
+        }
+    }
+
+    /// Returns the time the key has to be held before it's shifted, or None
+    /// if the key isn't shifted
+    pub fn timeout(&self, index: usize) -> Option<Duration> {
+        let enabled = self.timeout != 0 && self.keys[index / 8] & (1 << (index % 8)) != 0;
+        enabled.then(|| Duration::from_millis(self.timeout as u64 * 10))
+    }
+}
+
+impl<'a> Value<'a> for AutoShiftSettings {
+    fn serialize_into(&self, buffer: &mut [u8]) -> Result<usize, SerializationError> {
+        if buffer.len() < AUTO_SHIFT_SERIAL_LENGTH {
+            return Err(SerializationError::BufferTooSmall);
+        }
+        buffer[0] = self.timeout;
+        buffer[1..AUTO_SHIFT_SERIAL_LENGTH].copy_from_slice(&self.keys);
+        Ok(AUTO_SHIFT_SERIAL_LENGTH)
+    }
+
+    fn deserialize_from(buffer: &'a [u8]) -> Result<(Self, usize), SerializationError>
+    where
+        Self: Sized,
+    {
+        if buffer.len() < AUTO_SHIFT_SERIAL_LENGTH {
+            return Err(SerializationError::BufferTooSmall);
+        }
+        let mut settings = Self::default();
+        settings.timeout = buffer[0];
+        settings
+            .keys
+            .copy_from_slice(&buffer[1..AUTO_SHIFT_SERIAL_LENGTH]);
+        Ok((settings, AUTO_SHIFT_SERIAL_LENGTH))
+    }
+}
+
+/// Name and indicator color of a config, so hosts can tell which profile is
+/// active after a ChangeConfig key. Scoped to a single config
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ConfigInfo {
+    pub name: String<MAX_CONFIG_NAME_LEN>,
+    /// Rgb color of the indicator. Black keeps the board's color for the
+    /// config
+    pub color: [u8; 3],
+}
+
+impl ConfigInfo {
+    pub const fn default() -> Self {
+        Self {
+            name: String::new(),
+            color: [0; 3],
+        }
+    }
+
+    /// Returns the color the indicator shows for the config, or None if the
+    /// board's color is kept
+    pub fn color(&self) -> Option<[u8; 3]> {
+        (self.color != [0; 3]).then_some(self.color)
+    }
+}
+
+impl<'a> Value<'a> for ConfigInfo {
+    fn serialize_into(&self, buffer: &mut [u8]) -> Result<usize, SerializationError> {
+        let len = 4 + self.name.len();
+        if buffer.len() < len {
+            return Err(SerializationError::BufferTooSmall);
+        }
+        buffer[0..3].copy_from_slice(&self.color);
+        buffer[3] = self.name.len() as u8;
+        buffer[4..len].copy_from_slice(self.name.as_bytes());
+        Ok(len)
+    }
+
+    fn deserialize_from(buffer: &'a [u8]) -> Result<(Self, usize), SerializationError>
+    where
+        Self: Sized,
+    {
+        if buffer.len() < 4 {
+            return Err(SerializationError::BufferTooSmall);
+        }
+        let name_len = buffer[3] as usize;
+        if name_len > MAX_CONFIG_NAME_LEN {
+            return Err(SerializationError::InvalidFormat);
+        }
+        let name = buffer
+            .get(4..4 + name_len)
+            .ok_or(SerializationError::BufferTooSmall)?;
+        let name = core::str::from_utf8(name).map_err(|_| SerializationError::InvalidFormat)?;
+        Ok((
+            Self {
+                name: String::try_from(name).map_err(|_| SerializationError::InvalidFormat)?,
+                color: [buffer[0], buffer[1], buffer[2]],
+            },
+            4 + name_len,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn config_info_round_trips() {
+        let info = ConfigInfo {
+            name: String::try_from("Gaming").unwrap(),
+            color: [0, 10, 4],
+        };
+        let mut buffer = [0u8; CONFIG_INFO_SERIAL_LENGTH];
+        let len = info.serialize_into(&mut buffer).unwrap();
+        assert_eq!(len, 10);
+        assert_eq!(
+            ConfigInfo::deserialize_from(&buffer[..len]),
+            Ok((info, len))
+        );
+        // Names longer than the limit are rejected
+        buffer[3] = MAX_CONFIG_NAME_LEN as u8 + 1;
+        assert_eq!(
+            ConfigInfo::deserialize_from(&buffer),
+            Err(SerializationError::InvalidFormat)
+        );
+        assert_eq!(ConfigInfo::default().color(), None);
+    }
+
+    #[test]
+    fn layer_mouse_speeds_scale_the_speed() {
+        let mut speeds = LayerMouseSpeeds::default();
+        speeds.0[1] = 5;
+        speeds.0[2] = 30;
+        assert_eq!(speeds.scale(0, 20), 20);
+        assert_eq!(speeds.scale(1, 20), 10);
+        assert_eq!(speeds.scale(2, 20), 60);
+        // The speed never reaches 0 and saturates instead of wrapping
+        assert_eq!(speeds.scale(1, 1), 1);
+        assert_eq!(speeds.scale(2, 200), u8::MAX);
+        let mut buffer = [0u8; LAYER_MOUSE_SPEEDS_SERIAL_LENGTH];
+        assert_eq!(
+            speeds.serialize_into(&mut buffer),
+            Ok(LAYER_MOUSE_SPEEDS_SERIAL_LENGTH)
+        );
+        assert_eq!(
+            LayerMouseSpeeds::deserialize_from(&buffer),
+            Ok((speeds, LAYER_MOUSE_SPEEDS_SERIAL_LENGTH))
+        );
+        buffer[0] = 0;
+        assert_eq!(
+            LayerMouseSpeeds::deserialize_from(&buffer),
+            Err(SerializationError::InvalidFormat)
+        );
+    }
+}
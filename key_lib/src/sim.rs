@@ -0,0 +1,120 @@
+//! Simulated key sensors for exercising the firmware on a bare dev board
+//! without the keyboard PCB. [SimSensors] feeds the positions readings from a
+//! [SimPattern] until the host takes over with HidRequest::SimKeys, after which
+//! the keys follow the pressed bits sent by the host
+
+use core::cell::Cell;
+
+use embassy_sync::blocking_mutex::{Mutex, raw::CriticalSectionRawMutex};
+use embassy_time::{Duration, Instant};
+
+use crate::{
+    NUM_KEYS,
+    position::{KeySensors, KeyState},
+};
+
+/// Length of the pressed bits sent with HidRequest::SimKeys. Bit i of byte
+/// i / 8 is set while key i is pressed
+pub const SIM_KEYS_LEN: usize = NUM_KEYS.div_ceil(8);
+
+static LIVE_KEYS: Mutex<CriticalSectionRawMutex, Cell<Option<[u8; SIM_KEYS_LEN]>>> =
+    Mutex::new(Cell::new(None));
+
+/// Replaces the pattern of every [SimSensors] with the pressed bits until the
+/// board restarts
+pub fn set_live_keys(keys: [u8; SIM_KEYS_LEN]) {
+    LIVE_KEYS.lock(|live| live.set(Some(keys)));
+}
+
+/// Keys held for a duration
+#[derive(Debug, Clone, Copy)]
+pub struct SimStep {
+    pub keys: &'static [u8],
+    pub duration: Duration,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum SimPattern {
+    /// Taps every key in order, holding each for hold and waiting gap before
+    /// the next tap
+    Sweep { hold: Duration, gap: Duration },
+    /// Plays the steps in a loop
+    Script(&'static [SimStep]),
+}
+
+impl SimPattern {
+    // Returns true if key is pressed at elapsed into the pattern
+    fn is_pressed(&self, key: usize, num_keys: usize, elapsed: Duration) -> bool {
+        match *self {
+            SimPattern::Sweep { hold, gap } => {
+                let period = (hold + gap).as_micros().max(1);
+                let tap = elapsed.as_micros() / period;
+                tap % num_keys.max(1) as u64 == key as u64
+                    && elapsed.as_micros() % period < hold.as_micros()
+            }
+            SimPattern::Script(steps) => {
+                let total: u64 = steps.iter().map(|step| step.duration.as_micros()).sum();
+                if total == 0 {
+                    return false;
+                }
+                let mut offset = elapsed.as_micros() % total;
+                for step in steps {
+                    if offset < step.duration.as_micros() {
+                        return step.keys.contains(&(key as u8));
+                    }
+                    offset -= step.duration.as_micros();
+                }
+                false
+            }
+        }
+    }
+}
+
+/// Sensors that read pressed or released for the first num_keys positions
+/// instead of reading switches
+pub struct SimSensors<T: Copy> {
+    num_keys: usize,
+    released: T,
+    pressed: T,
+    pattern: SimPattern,
+    start: Instant,
+}
+
+impl<T: Copy> SimSensors<T> {
+    pub fn new(num_keys: usize, released: T, pressed: T, pattern: SimPattern) -> Self {
+        Self {
+            num_keys,
+            released,
+            pressed,
+            pattern,
+            start: Instant::now(),
+        }
+    }
+}
+
+impl<T: Copy> KeySensors for SimSensors<T> {
+    type Item = T;
+
+    async fn update_positions<K: KeyState<Item = Self::Item>>(&mut self, positions: &mut [K]) {
+        let live = LIVE_KEYS.lock(|live| live.get());
+        let elapsed = self.start.elapsed();
+        for (i, position) in positions.iter_mut().take(self.num_keys).enumerate() {
+            let pressed = match live {
+                Some(keys) => keys[i / 8] & (1 << (i % 8)) != 0,
+                None => self.pattern.is_pressed(i, self.num_keys, elapsed),
+            };
+            position.update_buf(if pressed { self.pressed } else { self.released });
+        }
+    }
+
+    #[cfg(feature = "hall-effect")]
+    async fn setup<K: KeyState<Item = Self::Item>>(&mut self, positions: &mut [K]) {
+        let mut setup = false;
+        while !setup {
+            setup = true;
+            for position in positions.iter_mut().take(self.num_keys) {
+                setup &= position.setup(self.released);
+            }
+        }
+    }
+}
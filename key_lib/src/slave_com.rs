@@ -1,3 +1,27 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use defmt::Format;
+use embassy_time::{Duration, Instant};
+use sequential_storage::map::{SerializationError, Value};
+
+use crate::storage::{StorageItem, StorageKey, get_item};
+
+pub const PAIRING_KEY_LEN: usize = 32;
+
+/// Cleared by the master of a split board while the other half isn't
+/// connected. Boards without a peer leave it set
+static PEER_PRESENT: AtomicBool = AtomicBool::new(true);
+
+/// Records whether the other half is connected. Keys switch to the
+/// standalone layer of the config while it isn't
+pub fn set_peer_present(present: bool) {
+    PEER_PRESENT.store(present, Ordering::Relaxed);
+}
+
+pub fn peer_present() -> bool {
+    PEER_PRESENT.load(Ordering::Relaxed)
+}
+
 pub trait SlaveState: Eq + Ord + Clone + Copy {
     const DEFAULT: Self;
     fn update_state(&mut self, index: usize, pressed: bool);
@@ -48,3 +72,292 @@ pub trait Slave {
     async fn send_slave_state(&self, state: Self::SlaveState);
     async fn get_request(&self) -> Self::Request;
 }
+
+/// Features supported by a slave's firmware. Sent to the master in the
+/// handshake so it doesn't have to assume both halves run the same build
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Format)]
+pub struct SlaveCapabilities {
+    pub features: u8,
+    pub version: [u8; 3],
+}
+
+pub const SLAVE_CAPABILITIES_SERIAL_LENGTH: usize = 4;
+
+impl SlaveCapabilities {
+    /// Slave streams analog readings of its keys
+    pub const ANALOG_STREAMING: u8 = 1 << 0;
+    /// Slave has an indicator that mirrors the master's config
+    pub const INDICATOR: u8 = 1 << 1;
+    /// Slave has a rotary encoder
+    pub const ENCODER: u8 = 1 << 2;
+    /// Slave shows the lock key leds set by the host
+    pub const HOST_LEDS: u8 = 1 << 3;
+    /// Slave answers pings and sends the age of its key states, so the master
+    /// can order its keys by when they changed
+    pub const TIME_SYNC: u8 = 1 << 4;
+    /// Slave streams the travel of its keys, so the master can press them
+    /// with rapid trigger and their switch profiles
+    pub const DEPTH_STREAMING: u8 = 1 << 5;
+
+    /// Capabilities assumed for slaves that never answer the handshake. These
+    /// builds predate the handshake and support what the master expected then
+    pub const LEGACY: Self = Self {
+        features: Self::ANALOG_STREAMING | Self::INDICATOR,
+        version: [0, 0, 0],
+    };
+
+    pub const fn new(features: u8, version: [u8; 3]) -> Self {
+        Self { features, version }
+    }
+
+    pub const fn supports(&self, feature: u8) -> bool {
+        self.features & feature == feature
+    }
+
+    pub fn into_buffer(&self, buf: &mut [u8]) {
+        buf[0] = self.features;
+        buf[1..4].copy_from_slice(&self.version);
+    }
+
+    pub fn from_buffer(buf: &[u8]) -> Self {
+        Self {
+            features: buf[0],
+            version: [buf[1], buf[2], buf[3]],
+        }
+    }
+}
+
+/// Key shared by the halves to encrypt the slave link. Set on both halves
+/// with SettingId::PairingKey. A key of all zeros leaves the link unencrypted
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct PairingKey(pub [u8; PAIRING_KEY_LEN]);
+
+impl PairingKey {
+    pub const fn default() -> Self {
+        Self([0; PAIRING_KEY_LEN])
+    }
+
+    pub fn is_set(&self) -> bool {
+        self.0.iter().any(|&byte| byte != 0)
+    }
+}
+
+impl<'a> Value<'a> for PairingKey {
+    fn serialize_into(&self, buffer: &mut [u8]) -> Result<usize, SerializationError> {
+        if buffer.len() < PAIRING_KEY_LEN {
+            return Err(SerializationError::BufferTooSmall);
+        }
+        buffer[..PAIRING_KEY_LEN].copy_from_slice(&self.0);
+        Ok(PAIRING_KEY_LEN)
+    }
+
+    fn deserialize_from(buffer: &'a [u8]) -> Result<(Self, usize), SerializationError>
+    where
+        Self: Sized,
+    {
+        let key = buffer
+            .get(..PAIRING_KEY_LEN)
+            .ok_or(SerializationError::BufferTooSmall)?;
+        let mut pairing_key = Self::default();
+        pairing_key.0.copy_from_slice(key);
+        Ok((pairing_key, PAIRING_KEY_LEN))
+    }
+}
+
+/// Returns the stored pairing key if one was set
+pub async fn load_pairing_key() -> Option<PairingKey> {
+    match get_item(StorageKey::PairingKey).await {
+        Some(StorageItem::PairingKey(key)) if key.is_set() => Some(key),
+        _ => None,
+    }
+}
+
+/// Resolution of the state age sent with each slave report
+pub const STATE_AGE_UNIT: Duration = Duration::from_micros(32);
+
+/// Number of round trips [LinkLatency] keeps
+pub const LATENCY_WINDOW: usize = 8;
+
+/// Time between heartbeats sent by the slave, whether or not its keys changed
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Time without a heartbeat before the master releases the slave's keys. A
+/// few intervals so a single dropped report doesn't release them
+pub const HEARTBEAT_TIMEOUT: Duration = Duration::from_millis(800);
+
+/// Time between the travels streamed by the slave. Only the keys whose
+/// travel changed are sent, a few at a time
+pub const DEPTH_INTERVAL: Duration = Duration::from_millis(2);
+
+/// Highest level a streamed travel is quantized to, so two keys fit in a byte
+pub const MAX_DEPTH_LEVEL: u8 = 15;
+
+/// Quantizes the travel of a key from 0 to 100 for streaming
+pub fn quantize_depth(travel: u8) -> u8 {
+    ((travel.min(100) as u16 * MAX_DEPTH_LEVEL as u16 + 50) / 100) as u8
+}
+
+/// Returns the travel from 0 to 100 of a streamed level
+pub fn dequantize_depth(level: u8) -> u8 {
+    (level.min(MAX_DEPTH_LEVEL) as u16 * 100 / MAX_DEPTH_LEVEL as u16) as u8
+}
+
+/// Encodes how long a key state waited on the slave before being sent. Ages
+/// past the range saturate at about 8ms
+pub fn encode_state_age(age: Duration) -> u8 {
+    (age.as_micros() / STATE_AGE_UNIT.as_micros()).min(u8::MAX as u64) as u8
+}
+
+pub fn decode_state_age(age: u8) -> Duration {
+    STATE_AGE_UNIT * age as u32
+}
+
+/// Estimate of the time a report takes to cross the slave link, from pings
+/// timed by the master. The fastest recent round trip is used since slower
+/// ones were held up behind other reports
+#[derive(Clone, Copy, Debug)]
+pub struct LinkLatency {
+    round_trips: [Option<Duration>; LATENCY_WINDOW],
+    next: usize,
+}
+
+impl LinkLatency {
+    pub const fn new() -> Self {
+        Self {
+            round_trips: [None; LATENCY_WINDOW],
+            next: 0,
+        }
+    }
+
+    pub fn add(&mut self, round_trip: Duration) {
+        self.round_trips[self.next] = Some(round_trip);
+        self.next = (self.next + 1) % LATENCY_WINDOW;
+    }
+
+    /// Forgets the round trips, e.g. once the slave disconnects
+    pub fn clear(&mut self) {
+        *self = Self::new();
+    }
+
+    /// Half the fastest round trip, or None before the first ping returns
+    pub fn one_way(&self) -> Option<Duration> {
+        self.round_trips
+            .iter()
+            .flatten()
+            .min()
+            .map(|round_trip| *round_trip / 2)
+    }
+}
+
+impl Default for LinkLatency {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Heartbeats received by the master. Slaves number their heartbeats from 0
+/// after they start and skip 0 once the sequence wraps, so a restart isn't
+/// counted as missed heartbeats
+#[derive(Clone, Copy, Debug)]
+pub struct Heartbeat {
+    last_seq: u8,
+    // Cleared once the heartbeats stop, until the next one arrives
+    last_at: Option<Instant>,
+}
+
+impl Heartbeat {
+    pub const fn new() -> Self {
+        Self {
+            last_seq: 0,
+            last_at: None,
+        }
+    }
+
+    /// Records a heartbeat and returns the number missed before it
+    pub fn beat(&mut self, seq: u8, now: Instant) -> u8 {
+        let missed = match self.last_at {
+            // Sequences run from 1 to 255 once started
+            Some(_) if seq != 0 => ((seq as u16 + 254 - self.last_seq as u16) % 255) as u8,
+            _ => 0,
+        };
+        self.last_seq = seq;
+        self.last_at = Some(now);
+        missed
+    }
+
+    /// Returns true once the heartbeats stopped for [HEARTBEAT_TIMEOUT]. Only
+    /// returns true once per outage, and never before the first heartbeat so
+    /// slaves that don't send them aren't released
+    pub fn stopped(&mut self, now: Instant) -> bool {
+        let stopped = self
+            .last_at
+            .is_some_and(|last_at| now.saturating_duration_since(last_at) >= HEARTBEAT_TIMEOUT);
+        if stopped {
+            self.last_at = None;
+        }
+        stopped
+    }
+}
+
+impl Default for Heartbeat {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn latency_uses_fastest_recent_round_trip() {
+        let mut latency = LinkLatency::new();
+        assert_eq!(latency.one_way(), None);
+        latency.add(Duration::from_micros(900));
+        latency.add(Duration::from_micros(400));
+        assert_eq!(latency.one_way(), Some(Duration::from_micros(200)));
+        for _ in 0..LATENCY_WINDOW {
+            latency.add(Duration::from_micros(1000));
+        }
+        assert_eq!(latency.one_way(), Some(Duration::from_micros(500)));
+        assert_eq!(
+            decode_state_age(encode_state_age(Duration::from_micros(100))),
+            Duration::from_micros(96)
+        );
+        assert_eq!(encode_state_age(Duration::from_millis(20)), u8::MAX);
+    }
+
+    #[test]
+    fn depths_keep_their_range() {
+        assert_eq!(quantize_depth(0), 0);
+        assert_eq!(quantize_depth(100), MAX_DEPTH_LEVEL);
+        assert_eq!(quantize_depth(200), MAX_DEPTH_LEVEL);
+        assert_eq!(dequantize_depth(MAX_DEPTH_LEVEL), 100);
+        for travel in 0..=100 {
+            let error = dequantize_depth(quantize_depth(travel)).abs_diff(travel);
+            assert!(error <= 100 / MAX_DEPTH_LEVEL / 2 + 1);
+        }
+    }
+
+    #[test]
+    fn heartbeat_stops_once_per_outage() {
+        let mut heartbeat = Heartbeat::new();
+        let start = Instant::from_secs(1);
+        // Slaves without heartbeats are never released
+        assert!(!heartbeat.stopped(start + HEARTBEAT_TIMEOUT * 2));
+        assert_eq!(heartbeat.beat(0, start), 0);
+        assert_eq!(heartbeat.beat(1, start + HEARTBEAT_INTERVAL), 0);
+        assert_eq!(heartbeat.beat(4, start + HEARTBEAT_INTERVAL * 4), 2);
+        let quiet = start + HEARTBEAT_INTERVAL * 4 + HEARTBEAT_TIMEOUT;
+        assert!(!heartbeat.stopped(quiet - HEARTBEAT_INTERVAL));
+        assert!(heartbeat.stopped(quiet));
+        assert!(!heartbeat.stopped(quiet + HEARTBEAT_INTERVAL));
+        // A restarted slave starts over from 0 without counting misses
+        assert_eq!(heartbeat.beat(4, quiet + HEARTBEAT_INTERVAL), 0);
+        assert_eq!(heartbeat.beat(0, quiet + HEARTBEAT_INTERVAL * 2), 0);
+        // The sequence skips 0 when it wraps
+        heartbeat.beat(u8::MAX, quiet + HEARTBEAT_INTERVAL * 3);
+        assert_eq!(heartbeat.beat(1, quiet + HEARTBEAT_INTERVAL * 4), 0);
+        assert_eq!(heartbeat.beat(3, quiet + HEARTBEAT_INTERVAL * 5), 1);
+    }
+}
@@ -0,0 +1,220 @@
+use num_enum::TryFromPrimitive;
+use sequential_storage::map::{SerializationError, Value};
+
+use crate::{
+    descriptor::KeyboardReportNKRO,
+    scan_codes::{KeyCodes, ReportCodes},
+};
+
+pub const MAX_SOCD_PAIRS: usize = 8;
+
+const SOCD_PAIR_SERIAL_LENGTH: usize = 3;
+pub const SOCD_PAIRS_SERIAL_LENGTH: usize = 1 + SOCD_PAIR_SERIAL_LENGTH * MAX_SOCD_PAIRS;
+
+/// Decides which key of a pair is sent when both are held
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, TryFromPrimitive)]
+pub enum SocdMode {
+    LastWins = 0,
+    FirstWins = 1,
+    Neutral = 2,
+}
+
+/// Two keys that cancel each other out, such as left and right movement keys
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct SocdPair {
+    pub first: KeyCodes,
+    pub second: KeyCodes,
+    pub mode: SocdMode,
+}
+
+impl SocdPair {
+    pub const fn default() -> Self {
+        Self {
+            first: KeyCodes::Undefined,
+            second: KeyCodes::Undefined,
+            mode: SocdMode::LastWins,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct SocdPairs {
+    pairs: [SocdPair; MAX_SOCD_PAIRS],
+    len: u8,
+}
+
+impl SocdPairs {
+    pub const fn default() -> Self {
+        Self {
+            pairs: [SocdPair::default(); MAX_SOCD_PAIRS],
+            len: 0,
+        }
+    }
+
+    pub fn pairs(&self) -> &[SocdPair] {
+        &self.pairs[..self.len as usize]
+    }
+
+    /// Adds a pair to the table. Returns the pair back if the table is full
+    pub fn push(&mut self, pair: SocdPair) -> Result<(), SocdPair> {
+        if self.len as usize >= MAX_SOCD_PAIRS {
+            return Err(pair);
+        }
+        self.pairs[self.len as usize] = pair;
+        self.len += 1;
+        Ok(())
+    }
+
+    pub fn into_buffer(&self, buffer: &mut [u8]) -> Result<(), SerializationError> {
+        if buffer.len() < SOCD_PAIRS_SERIAL_LENGTH {
+            return Err(SerializationError::BufferTooSmall);
+        }
+        buffer[0] = self.len;
+        for (pair, buf) in self
+            .pairs
+            .iter()
+            .zip(buffer[1..SOCD_PAIRS_SERIAL_LENGTH].chunks_exact_mut(SOCD_PAIR_SERIAL_LENGTH))
+        {
+            buf[0] = pair.first as u8;
+            buf[1] = pair.second as u8;
+            buf[2] = pair.mode as u8;
+        }
+        Ok(())
+    }
+}
+
+impl<'a> Value<'a> for SocdPairs {
+    fn serialize_into(&self, buffer: &mut [u8]) -> Result<usize, SerializationError> {
+        self.into_buffer(buffer)?;
+        Ok(SOCD_PAIRS_SERIAL_LENGTH)
+    }
+
+    fn deserialize_from(buffer: &'a [u8]) -> Result<(Self, usize), SerializationError>
+    where
+        Self: Sized,
+    {
+        if buffer.len() < SOCD_PAIRS_SERIAL_LENGTH {
+            return Err(SerializationError::BufferTooSmall);
+        }
+        let len = buffer[0];
+        if len as usize > MAX_SOCD_PAIRS {
+            return Err(SerializationError::InvalidFormat);
+        }
+        let mut pairs = SocdPairs::default();
+        pairs.len = len;
+        for (pair, buf) in pairs.pairs[..len as usize]
+            .iter_mut()
+            .zip(buffer[1..SOCD_PAIRS_SERIAL_LENGTH].chunks_exact(SOCD_PAIR_SERIAL_LENGTH))
+        {
+            let first: KeyCodes = buf[0].into();
+            let second: KeyCodes = buf[1].into();
+            // Only normal keys can be resolved since the pair is resolved on the key report
+            if !matches!(first.into(), ReportCodes::Letter(_))
+                || !matches!(second.into(), ReportCodes::Letter(_))
+            {
+                return Err(SerializationError::InvalidFormat);
+            }
+            pair.first = first;
+            pair.second = second;
+            pair.mode =
+                SocdMode::try_from(buf[2]).map_err(|_| SerializationError::InvalidFormat)?;
+        }
+        Ok((pairs, SOCD_PAIRS_SERIAL_LENGTH))
+    }
+}
+
+/// Returns the nkro field holding the bit for the provided letter code
+fn nkro_field(report: &KeyboardReportNKRO, code: u8) -> Option<u32> {
+    match code / 32 {
+        0 => Some(report.nkro_0),
+        1 => Some(report.nkro_1),
+        2 => Some(report.nkro_2),
+        3 => Some(report.nkro_3),
+        4 => Some(report.nkro_4),
+        5 => Some(report.nkro_5),
+        6 => Some(report.nkro_6),
+        _ => None,
+    }
+}
+
+fn is_set(report: &KeyboardReportNKRO, code: KeyCodes) -> bool {
+    let code = code as u8;
+    nkro_field(report, code).is_some_and(|field| field & (1 << (code % 32)) != 0)
+}
+
+fn clear(report: &mut KeyboardReportNKRO, code: KeyCodes) {
+    let code = code as u8;
+    let mask = !(1 << (code % 32));
+    match code / 32 {
+        0 => report.nkro_0 &= mask,
+        1 => report.nkro_1 &= mask,
+        2 => report.nkro_2 &= mask,
+        3 => report.nkro_3 &= mask,
+        4 => report.nkro_4 &= mask,
+        5 => report.nkro_5 &= mask,
+        6 => report.nkro_6 &= mask,
+        _ => {}
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum Side {
+    First,
+    Second,
+}
+
+#[derive(Copy, Clone, Debug)]
+struct PairState {
+    first_held: bool,
+    second_held: bool,
+    // Side that was pressed most recently
+    last: Option<Side>,
+}
+
+/// Tracks the press order of each pair across reports
+#[derive(Copy, Clone, Debug)]
+pub struct SocdResolver {
+    states: [PairState; MAX_SOCD_PAIRS],
+}
+
+impl SocdResolver {
+    pub const fn new() -> Self {
+        Self {
+            states: [PairState {
+                first_held: false,
+                second_held: false,
+                last: None,
+            }; MAX_SOCD_PAIRS],
+        }
+    }
+
+    /// Removes the losing key of every pair that has both keys held from the report
+    pub fn resolve(&mut self, pairs: &SocdPairs, report: &mut KeyboardReportNKRO) {
+        for (pair, state) in pairs.pairs().iter().zip(self.states.iter_mut()) {
+            let first_held = is_set(report, pair.first);
+            let second_held = is_set(report, pair.second);
+            if first_held && !state.first_held {
+                state.last = Some(Side::First);
+            }
+            if second_held && !state.second_held {
+                state.last = Some(Side::Second);
+            }
+            state.first_held = first_held;
+            state.second_held = second_held;
+
+            if !(first_held && second_held) {
+                continue;
+            }
+            match (pair.mode, state.last) {
+                (SocdMode::Neutral, _) => {
+                    clear(report, pair.first);
+                    clear(report, pair.second);
+                }
+                (SocdMode::LastWins, Some(Side::First))
+                | (SocdMode::FirstWins, Some(Side::Second)) => clear(report, pair.second),
+                (SocdMode::LastWins, _) | (SocdMode::FirstWins, _) => clear(report, pair.first),
+            }
+        }
+    }
+}
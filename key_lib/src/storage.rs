@@ -1,18 +1,58 @@
-use core::ops::{DerefMut, Range};
+use core::{
+    cell::Cell,
+    ops::{DerefMut, Range},
+};
 
 use defmt::{Format, error, info};
-use embassy_futures::join::join;
+use embassy_futures::{
+    join::join4,
+    select::{Either, select},
+};
 use embassy_sync::{
-    blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel, mutex::Mutex, signal::Signal,
+    blocking_mutex::{self, raw::CriticalSectionRawMutex},
+    channel::Channel,
+    mutex::Mutex,
+    signal::Signal,
 };
-use embassy_time::Timer;
-use embedded_storage_async::nor_flash::NorFlash;
+use embassy_time::{Duration, Timer};
+use embedded_storage_async::nor_flash::{ErrorType, NorFlash, ReadNorFlash};
+use heapless::Vec;
+use num_enum::TryFromPrimitive;
 use sequential_storage::{
     cache::{KeyCacheImpl, NoCache},
-    map::{Key, MapConfig, MapStorage, Value},
+    map::{Key, MapConfig, MapStorage, SerializationError, Value},
 };
 
-use crate::{NUM_KEYS, NUM_LAYERS, codes::ScanCodeLayerStorage};
+use crate::{
+    NUM_CONFIGS, NUM_KEYS, NUM_LAYERS,
+    board::{REPORT_RATE_SERIAL_LENGTH, ReportRate, Side, USB_IDENTITY_SERIAL_LENGTH, UsbIdentity},
+    codes::{MAX_SERIAL_LENGTH, ScanCodeLayerStorage},
+    combo::{COMBOS_SERIAL_LENGTH, Combos},
+    config_slots::{CONFIG_SLOTS_SERIAL_LENGTH, ConfigSlots},
+    diagnostics::StorageStats,
+    dynamic_macro::{DYNAMIC_MACRO_SERIAL_LENGTH, DynamicMacro},
+    error::{KeyLibError, record_error},
+    host_switch::{HOST_PAIRINGS_SERIAL_LENGTH, HostPairings},
+    key_stats::{KEY_STATS_SERIAL_LENGTH, KeyStats},
+    lighting::{LIGHTING_SERIAL_LENGTH, LightingSettings},
+    os::{HOST_OS_SERIAL_LENGTH, HostOs},
+    position::{
+        DRIFT_COMPENSATION_SERIAL_LENGTH, DriftCompensation, KEY_CALIBRATION_SERIAL_LENGTH,
+        KeyCalibration,
+    },
+    power::{
+        BATTERY_THRESHOLDS_SERIAL_LENGTH, BatteryThresholds, LINK_TIMING_SERIAL_LENGTH, LinkTiming,
+    },
+    scan::ScanPause,
+    settings::{
+        AUTO_SHIFT_SERIAL_LENGTH, AutoShiftSettings, CONFIG_INFO_SERIAL_LENGTH, ConfigInfo,
+        LAYER_MOUSE_SPEEDS_SERIAL_LENGTH, LAYER_TIMEOUTS_SERIAL_LENGTH, LayerMouseSpeeds,
+        LayerTimeouts, MOUSE_SETTINGS_SERIAL_LENGTH, MouseSettings, STANDALONE_LAYER_SERIAL_LENGTH,
+        SWITCH_PROFILES_SERIAL_LENGTH, StandaloneLayer, SwitchProfiles,
+    },
+    slave_com::{PAIRING_KEY_LEN, PairingKey},
+    socd::{SOCD_PAIRS_SERIAL_LENGTH, SocdPairs},
+};
 
 pub static STORAGE_WRITE_CHANNEL: Channel<CriticalSectionRawMutex, (StorageKey, StorageItem), 10> =
     Channel::new();
@@ -21,48 +61,751 @@ pub static STORAGE_SIGNAL_READ: Signal<CriticalSectionRawMutex, StorageKey> = Si
 pub static STORAGE_SIGNAL_ITEM: Signal<CriticalSectionRawMutex, Option<StorageItem>> =
     Signal::new();
 
+/// Time without further changes before items scheduled with [schedule_save]
+/// are written to flash
+pub const AUTOSAVE_DELAY: Duration = Duration::from_secs(5);
+const MAX_PENDING_SAVES: usize = 8;
+
+static PENDING_SAVES: Mutex<
+    CriticalSectionRawMutex,
+    Vec<(StorageKey, StorageItem), MAX_PENDING_SAVES>,
+> = Mutex::new(Vec::new());
+static AUTOSAVE_SIGNAL: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+static STORAGE_REQUEST_MAINTENANCE_LOCK: Mutex<CriticalSectionRawMutex, ()> = Mutex::new(());
+static STORAGE_SIGNAL_MAINTENANCE: Signal<CriticalSectionRawMutex, Maintenance> = Signal::new();
+static STORAGE_SIGNAL_STATS: Signal<CriticalSectionRawMutex, StorageStats> = Signal::new();
+
+/// Pages erased since boot, counted by [WearFlash]
+static ERASE_COUNT: blocking_mutex::Mutex<CriticalSectionRawMutex, Cell<u32>> =
+    blocking_mutex::Mutex::new(Cell::new(0));
+/// Last error hit while reading or writing flash. Unlike the error kept by
+/// [record_error] it isn't replaced by errors from other modules
+static LAST_STORAGE_ERROR: blocking_mutex::Mutex<
+    CriticalSectionRawMutex,
+    Cell<Option<KeyLibError>>,
+> = blocking_mutex::Mutex::new(Cell::new(None));
+
+fn record_storage_error(err: KeyLibError) {
+    LAST_STORAGE_ERROR.lock(|cell| cell.set(Some(err)));
+    record_error(err);
+}
+
+#[derive(Debug, Clone, Copy, Format)]
+enum Maintenance {
+    Stats,
+    Compact,
+    Erase,
+}
+
 type InternalStorageKey = u16;
 
 #[derive(Debug, Clone, Copy, Format)]
 pub enum StorageKey {
     StorageCheck,
-    KeyScanCode { config_num: usize, layer: usize },
+    /// [StorageLayout] of the firmware that wrote the map
+    Layout,
+    BatteryThresholds,
+    Lighting,
+    Side,
+    HostOs,
+    UsbIdentity,
+    PairingKey,
+    ReportRate,
+    HostPairings,
+    DynamicMacro,
+    /// Press counts of every key, see [crate::key_stats]
+    KeyStats,
+    LinkTiming,
+    DriftCompensation,
+    /// State of the two slots of each config's layers, see
+    /// [crate::config_slots]
+    ConfigSlots,
+    MouseSettings {
+        config_num: usize,
+    },
+    Combo {
+        config_num: usize,
+    },
+    Socd {
+        config_num: usize,
+    },
+    SwitchProfiles {
+        config_num: usize,
+    },
+    LayerTimeouts {
+        config_num: usize,
+    },
+    StandaloneLayer {
+        config_num: usize,
+    },
+    AutoShift {
+        config_num: usize,
+    },
+    ConfigInfo {
+        config_num: usize,
+    },
+    LayerMouseSpeeds {
+        config_num: usize,
+    },
+    KeyScanCode {
+        config_num: usize,
+        layer: usize,
+        /// Slot of the layer, see [crate::config_slots]
+        slot: u8,
+    },
+    Calibration {
+        key: usize,
+    },
 }
 
 impl StorageKey {
     pub fn to_key(&self) -> InternalStorageKey {
+        const MOUSE_SETTINGS_OFFSET: InternalStorageKey = 10;
+        const COMBO_OFFSET: InternalStorageKey = 30;
+        const SOCD_OFFSET: InternalStorageKey = 50;
+        const SWITCH_PROFILES_OFFSET: InternalStorageKey = 70;
         const SCAN_CODE_OFFSET: InternalStorageKey = 100;
+        const SCAN_CODE_B_OFFSET: InternalStorageKey = 2200;
+        const CALIBRATION_OFFSET: InternalStorageKey = 1000;
+        const LAYER_TIMEOUTS_OFFSET: InternalStorageKey = 2000;
+        const STANDALONE_LAYER_OFFSET: InternalStorageKey = 2020;
+        const AUTO_SHIFT_OFFSET: InternalStorageKey = 2040;
+        const CONFIG_INFO_OFFSET: InternalStorageKey = 2080;
+        const LAYER_MOUSE_SPEEDS_OFFSET: InternalStorageKey = 2140;
         match self {
             StorageKey::StorageCheck => 0 as InternalStorageKey,
-            StorageKey::KeyScanCode { config_num, layer } => {
-                SCAN_CODE_OFFSET
+            StorageKey::BatteryThresholds => 1 as InternalStorageKey,
+            StorageKey::Lighting => 2 as InternalStorageKey,
+            StorageKey::Side => 3 as InternalStorageKey,
+            StorageKey::HostOs => 4 as InternalStorageKey,
+            StorageKey::UsbIdentity => 5 as InternalStorageKey,
+            StorageKey::PairingKey => 6 as InternalStorageKey,
+            StorageKey::ReportRate => 7 as InternalStorageKey,
+            StorageKey::HostPairings => 8 as InternalStorageKey,
+            StorageKey::Layout => 9 as InternalStorageKey,
+            StorageKey::DynamicMacro => 2060 as InternalStorageKey,
+            StorageKey::KeyStats => 2100 as InternalStorageKey,
+            StorageKey::LinkTiming => 2120 as InternalStorageKey,
+            StorageKey::DriftCompensation => 2121 as InternalStorageKey,
+            StorageKey::ConfigSlots => 2122 as InternalStorageKey,
+            StorageKey::MouseSettings { config_num } => {
+                MOUSE_SETTINGS_OFFSET + *config_num as InternalStorageKey
+            }
+            StorageKey::Combo { config_num } => COMBO_OFFSET + *config_num as InternalStorageKey,
+            StorageKey::Socd { config_num } => SOCD_OFFSET + *config_num as InternalStorageKey,
+            StorageKey::SwitchProfiles { config_num } => {
+                SWITCH_PROFILES_OFFSET + *config_num as InternalStorageKey
+            }
+            StorageKey::LayerTimeouts { config_num } => {
+                LAYER_TIMEOUTS_OFFSET + *config_num as InternalStorageKey
+            }
+            StorageKey::StandaloneLayer { config_num } => {
+                STANDALONE_LAYER_OFFSET + *config_num as InternalStorageKey
+            }
+            StorageKey::AutoShift { config_num } => {
+                AUTO_SHIFT_OFFSET + *config_num as InternalStorageKey
+            }
+            StorageKey::ConfigInfo { config_num } => {
+                CONFIG_INFO_OFFSET + *config_num as InternalStorageKey
+            }
+            StorageKey::LayerMouseSpeeds { config_num } => {
+                LAYER_MOUSE_SPEEDS_OFFSET + *config_num as InternalStorageKey
+            }
+            StorageKey::KeyScanCode {
+                config_num,
+                layer,
+                slot,
+            } => {
+                // The first slot keeps the keys layers were stored at before
+                // there were two
+                let offset = if *slot == 0 {
+                    SCAN_CODE_OFFSET
+                } else {
+                    SCAN_CODE_B_OFFSET
+                };
+                offset
                     + ((NUM_LAYERS * *config_num) as InternalStorageKey)
                     + *layer as InternalStorageKey
             }
+            StorageKey::Calibration { key } => CALIBRATION_OFFSET + *key as InternalStorageKey,
+        }
+    }
+}
+
+/// Version of the formats items are stored in. Bump it when an item's format
+/// changes without a way to read the old one, so the map is reset instead
+pub const STORAGE_SCHEMA_VERSION: u8 = 1;
+const STORAGE_LAYOUT_SERIAL_LENGTH: usize = 4;
+
+/// Counts the items in the map were written for. Keymaps and a few settings
+/// are sized by the counts and scan code keys are offset by the layer count,
+/// so items written for other counts can't be read
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Format)]
+pub struct StorageLayout {
+    pub version: u8,
+    pub keys: u8,
+    pub layers: u8,
+    pub configs: u8,
+}
+
+impl StorageLayout {
+    pub const fn current() -> Self {
+        Self {
+            version: STORAGE_SCHEMA_VERSION,
+            keys: NUM_KEYS as u8,
+            layers: NUM_LAYERS as u8,
+            configs: NUM_CONFIGS as u8,
+        }
+    }
+}
+
+impl<'a> Value<'a> for StorageLayout {
+    fn serialize_into(&self, buffer: &mut [u8]) -> Result<usize, SerializationError> {
+        if buffer.len() < STORAGE_LAYOUT_SERIAL_LENGTH {
+            return Err(SerializationError::BufferTooSmall);
+        }
+        buffer[0] = self.version;
+        buffer[1] = self.keys;
+        buffer[2] = self.layers;
+        buffer[3] = self.configs;
+        Ok(STORAGE_LAYOUT_SERIAL_LENGTH)
+    }
+
+    fn deserialize_from(buffer: &'a [u8]) -> Result<(Self, usize), SerializationError>
+    where
+        Self: Sized,
+    {
+        if buffer.len() < STORAGE_LAYOUT_SERIAL_LENGTH {
+            return Err(SerializationError::BufferTooSmall);
+        }
+        Ok((
+            Self {
+                version: buffer[0],
+                keys: buffer[1],
+                layers: buffer[2],
+                configs: buffer[3],
+            },
+            STORAGE_LAYOUT_SERIAL_LENGTH,
+        ))
+    }
+}
+
+/// Length of the largest serialized setting
+pub const MAX_SETTING_LEN: usize = {
+    let mut len = BATTERY_THRESHOLDS_SERIAL_LENGTH;
+    if MOUSE_SETTINGS_SERIAL_LENGTH > len {
+        len = MOUSE_SETTINGS_SERIAL_LENGTH;
+    }
+    if COMBOS_SERIAL_LENGTH > len {
+        len = COMBOS_SERIAL_LENGTH;
+    }
+    if SOCD_PAIRS_SERIAL_LENGTH > len {
+        len = SOCD_PAIRS_SERIAL_LENGTH;
+    }
+    if LIGHTING_SERIAL_LENGTH > len {
+        len = LIGHTING_SERIAL_LENGTH;
+    }
+    if SWITCH_PROFILES_SERIAL_LENGTH > len {
+        len = SWITCH_PROFILES_SERIAL_LENGTH;
+    }
+    if HOST_OS_SERIAL_LENGTH > len {
+        len = HOST_OS_SERIAL_LENGTH;
+    }
+    if USB_IDENTITY_SERIAL_LENGTH > len {
+        len = USB_IDENTITY_SERIAL_LENGTH;
+    }
+    if PAIRING_KEY_LEN > len {
+        len = PAIRING_KEY_LEN;
+    }
+    if LAYER_TIMEOUTS_SERIAL_LENGTH > len {
+        len = LAYER_TIMEOUTS_SERIAL_LENGTH;
+    }
+    if LAYER_MOUSE_SPEEDS_SERIAL_LENGTH > len {
+        len = LAYER_MOUSE_SPEEDS_SERIAL_LENGTH;
+    }
+    if REPORT_RATE_SERIAL_LENGTH > len {
+        len = REPORT_RATE_SERIAL_LENGTH;
+    }
+    if STANDALONE_LAYER_SERIAL_LENGTH > len {
+        len = STANDALONE_LAYER_SERIAL_LENGTH;
+    }
+    if HOST_PAIRINGS_SERIAL_LENGTH > len {
+        len = HOST_PAIRINGS_SERIAL_LENGTH;
+    }
+    if AUTO_SHIFT_SERIAL_LENGTH > len {
+        len = AUTO_SHIFT_SERIAL_LENGTH;
+    }
+    if DYNAMIC_MACRO_SERIAL_LENGTH > len {
+        len = DYNAMIC_MACRO_SERIAL_LENGTH;
+    }
+    if CONFIG_INFO_SERIAL_LENGTH > len {
+        len = CONFIG_INFO_SERIAL_LENGTH;
+    }
+    if LINK_TIMING_SERIAL_LENGTH > len {
+        len = LINK_TIMING_SERIAL_LENGTH;
+    }
+    if DRIFT_COMPENSATION_SERIAL_LENGTH > len {
+        len = DRIFT_COMPENSATION_SERIAL_LENGTH;
+    }
+    len
+};
+
+/// Non keymap items in storage. Settings can be read and written as raw
+/// blobs so host tools can back them up without knowing their format
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Format, TryFromPrimitive)]
+pub enum SettingId {
+    BatteryThresholds = 0,
+    MouseSettings = 1,
+    Combos = 2,
+    SocdPairs = 3,
+    Lighting = 4,
+    /// Side of a split board that runs a single firmware image on both halves
+    Side = 5,
+    SwitchProfiles = 6,
+    /// Os used instead of the detected one. Unknown detects the os
+    HostOs = 7,
+    /// Usb ids and product string. Takes effect on the next boot
+    UsbIdentity = 8,
+    /// Key that encrypts the link between the halves. Can be written but not
+    /// read back. Takes effect on the next boot
+    PairingKey = 9,
+    /// Seconds before a toggled or latched layer returns to the base layer
+    LayerTimeouts = 10,
+    /// Usb polling interval and scan interval. The polling interval takes
+    /// effect on the next boot
+    ReportRate = 11,
+    /// Layer used in place of the base layer while the other half is absent
+    StandaloneLayer = 12,
+    /// Dongle addresses of the hosts a wireless keyboard switches between
+    HostPairings = 13,
+    /// Timeout and keys of auto shift
+    AutoShift = 14,
+    /// Macro recorded from the keyboard, written when a recording with save
+    /// stops
+    DynamicMacro = 15,
+    /// Name and indicator color of the config
+    ConfigInfo = 16,
+    /// Heartbeat interval and fastest scan rate of the halves of a wireless
+    /// board. Sent to the halves by the dongle
+    LinkTiming = 17,
+    /// Re-centering of the rest points of hall effect switches that drifted
+    /// with temperature
+    DriftCompensation = 18,
+    /// Multipliers of the mouse and scroll speeds on each layer
+    LayerMouseSpeeds = 19,
+}
+
+/// Number of settings shared by every config
+pub const GLOBAL_SETTINGS: usize = {
+    let mut count = 0;
+    let mut i = 0;
+    while i < SettingId::ALL.len() {
+        if SettingId::ALL[i].is_global() {
+            count += 1;
+        }
+        i += 1;
+    }
+    count
+};
+
+impl SettingId {
+    pub const ALL: [SettingId; 20] = [
+        SettingId::BatteryThresholds,
+        SettingId::MouseSettings,
+        SettingId::Combos,
+        SettingId::SocdPairs,
+        SettingId::Lighting,
+        SettingId::Side,
+        SettingId::SwitchProfiles,
+        SettingId::HostOs,
+        SettingId::UsbIdentity,
+        SettingId::PairingKey,
+        SettingId::LayerTimeouts,
+        SettingId::ReportRate,
+        SettingId::StandaloneLayer,
+        SettingId::HostPairings,
+        SettingId::AutoShift,
+        SettingId::DynamicMacro,
+        SettingId::ConfigInfo,
+        SettingId::LinkTiming,
+        SettingId::DriftCompensation,
+        SettingId::LayerMouseSpeeds,
+    ];
+
+    /// Returns true if the setting is shared by every config
+    pub const fn is_global(&self) -> bool {
+        matches!(
+            self,
+            SettingId::BatteryThresholds
+                | SettingId::Lighting
+                | SettingId::Side
+                | SettingId::HostOs
+                | SettingId::UsbIdentity
+                | SettingId::PairingKey
+                | SettingId::ReportRate
+                | SettingId::HostPairings
+                | SettingId::DynamicMacro
+                | SettingId::LinkTiming
+                | SettingId::DriftCompensation
+        )
+    }
+
+    /// Returns the storage key of the setting. Returns None if the setting is
+    /// per config and config_num is out of range
+    pub fn storage_key(&self, config_num: usize) -> Option<StorageKey> {
+        match self {
+            SettingId::BatteryThresholds => return Some(StorageKey::BatteryThresholds),
+            SettingId::Lighting => return Some(StorageKey::Lighting),
+            SettingId::Side => return Some(StorageKey::Side),
+            SettingId::HostOs => return Some(StorageKey::HostOs),
+            SettingId::UsbIdentity => return Some(StorageKey::UsbIdentity),
+            SettingId::PairingKey => return Some(StorageKey::PairingKey),
+            SettingId::ReportRate => return Some(StorageKey::ReportRate),
+            SettingId::HostPairings => return Some(StorageKey::HostPairings),
+            SettingId::DynamicMacro => return Some(StorageKey::DynamicMacro),
+            SettingId::LinkTiming => return Some(StorageKey::LinkTiming),
+            SettingId::DriftCompensation => return Some(StorageKey::DriftCompensation),
+            _ => {}
+        }
+        if config_num >= NUM_CONFIGS {
+            return None;
+        }
+        match self {
+            SettingId::BatteryThresholds
+            | SettingId::Lighting
+            | SettingId::Side
+            | SettingId::HostOs
+            | SettingId::UsbIdentity
+            | SettingId::PairingKey
+            | SettingId::ReportRate
+            | SettingId::HostPairings
+            | SettingId::DynamicMacro
+            | SettingId::LinkTiming
+            | SettingId::DriftCompensation => None,
+            SettingId::MouseSettings => Some(StorageKey::MouseSettings { config_num }),
+            SettingId::Combos => Some(StorageKey::Combo { config_num }),
+            SettingId::SocdPairs => Some(StorageKey::Socd { config_num }),
+            SettingId::SwitchProfiles => Some(StorageKey::SwitchProfiles { config_num }),
+            SettingId::LayerTimeouts => Some(StorageKey::LayerTimeouts { config_num }),
+            SettingId::StandaloneLayer => Some(StorageKey::StandaloneLayer { config_num }),
+            SettingId::AutoShift => Some(StorageKey::AutoShift { config_num }),
+            SettingId::ConfigInfo => Some(StorageKey::ConfigInfo { config_num }),
+            SettingId::LayerMouseSpeeds => Some(StorageKey::LayerMouseSpeeds { config_num }),
+        }
+    }
+
+    /// Returns true if the setting's format and meaning don't depend on the
+    /// key, layer or config counts, so it's kept when they change
+    pub const fn survives_layout_change(&self) -> bool {
+        matches!(
+            self,
+            SettingId::BatteryThresholds
+                | SettingId::MouseSettings
+                | SettingId::SocdPairs
+                | SettingId::Side
+                | SettingId::HostOs
+                | SettingId::UsbIdentity
+                | SettingId::PairingKey
+                | SettingId::ReportRate
+                | SettingId::HostPairings
+                | SettingId::DynamicMacro
+                | SettingId::ConfigInfo
+                | SettingId::LinkTiming
+                | SettingId::DriftCompensation
+        )
+    }
+
+    /// Returns false for secrets, which can be written over com but not read
+    pub fn is_readable(&self) -> bool {
+        !matches!(self, SettingId::PairingKey)
+    }
+
+    /// Value used when the setting was never written to storage
+    pub fn default_item(&self) -> StorageItem {
+        match self {
+            SettingId::BatteryThresholds => {
+                StorageItem::BatteryThresholds(BatteryThresholds::default())
+            }
+            SettingId::MouseSettings => StorageItem::MouseSettings(MouseSettings::default()),
+            SettingId::Combos => StorageItem::Combos(Combos::default()),
+            SettingId::SocdPairs => StorageItem::SocdPairs(SocdPairs::default()),
+            SettingId::Lighting => StorageItem::Lighting(LightingSettings::default()),
+            SettingId::Side => StorageItem::Side(Side::Left),
+            SettingId::SwitchProfiles => StorageItem::SwitchProfiles(SwitchProfiles::default()),
+            SettingId::HostOs => StorageItem::HostOs(HostOs::Unknown),
+            SettingId::UsbIdentity => StorageItem::UsbIdentity(UsbIdentity::default()),
+            SettingId::PairingKey => StorageItem::PairingKey(PairingKey::default()),
+            SettingId::LayerTimeouts => StorageItem::LayerTimeouts(LayerTimeouts::default()),
+            SettingId::ReportRate => StorageItem::ReportRate(ReportRate::default()),
+            SettingId::StandaloneLayer => StorageItem::StandaloneLayer(StandaloneLayer::default()),
+            SettingId::HostPairings => StorageItem::HostPairings(HostPairings::default()),
+            SettingId::AutoShift => StorageItem::AutoShift(AutoShiftSettings::default()),
+            SettingId::ConfigInfo => StorageItem::ConfigInfo(ConfigInfo::default()),
+            SettingId::DynamicMacro => StorageItem::DynamicMacro(DynamicMacro::default()),
+            SettingId::LinkTiming => StorageItem::LinkTiming(LinkTiming::default()),
+            SettingId::DriftCompensation => {
+                StorageItem::DriftCompensation(DriftCompensation::default())
+            }
+            SettingId::LayerMouseSpeeds => {
+                StorageItem::LayerMouseSpeeds(LayerMouseSpeeds::default())
+            }
         }
     }
+
+    /// Deserializes and validates a blob of the setting
+    pub fn deserialize(&self, buffer: &[u8]) -> Result<StorageItem, SerializationError> {
+        Ok(match self {
+            SettingId::BatteryThresholds => {
+                StorageItem::BatteryThresholds(BatteryThresholds::deserialize_from(buffer)?.0)
+            }
+            SettingId::MouseSettings => {
+                StorageItem::MouseSettings(MouseSettings::deserialize_from(buffer)?.0)
+            }
+            SettingId::Combos => StorageItem::Combos(Combos::deserialize_from(buffer)?.0),
+            SettingId::SocdPairs => StorageItem::SocdPairs(SocdPairs::deserialize_from(buffer)?.0),
+            SettingId::Lighting => {
+                StorageItem::Lighting(LightingSettings::deserialize_from(buffer)?.0)
+            }
+            SettingId::Side => StorageItem::Side(Side::deserialize_from(buffer)?.0),
+            SettingId::SwitchProfiles => {
+                StorageItem::SwitchProfiles(SwitchProfiles::deserialize_from(buffer)?.0)
+            }
+            SettingId::HostOs => StorageItem::HostOs(HostOs::deserialize_from(buffer)?.0),
+            SettingId::UsbIdentity => {
+                StorageItem::UsbIdentity(UsbIdentity::deserialize_from(buffer)?.0)
+            }
+            SettingId::PairingKey => {
+                StorageItem::PairingKey(PairingKey::deserialize_from(buffer)?.0)
+            }
+            SettingId::LayerTimeouts => {
+                StorageItem::LayerTimeouts(LayerTimeouts::deserialize_from(buffer)?.0)
+            }
+            SettingId::ReportRate => {
+                StorageItem::ReportRate(ReportRate::deserialize_from(buffer)?.0)
+            }
+            SettingId::StandaloneLayer => {
+                StorageItem::StandaloneLayer(StandaloneLayer::deserialize_from(buffer)?.0)
+            }
+            SettingId::HostPairings => {
+                StorageItem::HostPairings(HostPairings::deserialize_from(buffer)?.0)
+            }
+            SettingId::AutoShift => {
+                StorageItem::AutoShift(AutoShiftSettings::deserialize_from(buffer)?.0)
+            }
+            SettingId::ConfigInfo => {
+                StorageItem::ConfigInfo(ConfigInfo::deserialize_from(buffer)?.0)
+            }
+            SettingId::DynamicMacro => {
+                StorageItem::DynamicMacro(DynamicMacro::deserialize_from(buffer)?.0)
+            }
+            SettingId::LinkTiming => {
+                StorageItem::LinkTiming(LinkTiming::deserialize_from(buffer)?.0)
+            }
+            SettingId::DriftCompensation => {
+                StorageItem::DriftCompensation(DriftCompensation::deserialize_from(buffer)?.0)
+            }
+            SettingId::LayerMouseSpeeds => {
+                StorageItem::LayerMouseSpeeds(LayerMouseSpeeds::deserialize_from(buffer)?.0)
+            }
+        })
+    }
 }
 
+// Key and length of an item as le u16s, followed by its data
+const REWRITE_ITEM_HEADER_LEN: usize = 4;
+
+/// Length of every item the map can hold once, with room for the header of
+/// each
+const REWRITE_BUFFER_LEN: usize = (GLOBAL_SETTINGS
+    + NUM_CONFIGS * (SettingId::ALL.len() - GLOBAL_SETTINGS))
+    * (REWRITE_ITEM_HEADER_LEN + MAX_SETTING_LEN)
+    + 2 * NUM_CONFIGS * NUM_LAYERS * (REWRITE_ITEM_HEADER_LEN + NUM_KEYS * MAX_SERIAL_LENGTH)
+    + NUM_KEYS * (REWRITE_ITEM_HEADER_LEN + KEY_CALIBRATION_SERIAL_LENGTH)
+    + REWRITE_ITEM_HEADER_LEN
+    + KEY_STATS_SERIAL_LENGTH
+    + REWRITE_ITEM_HEADER_LEN
+    + CONFIG_SLOTS_SERIAL_LENGTH;
+
+/// Items copied out of flash while it's erased by [Storage::compact] or a
+/// layout migration. Kept in a static since it's too large for the storage
+/// task's stack
+static REWRITE_BUFFER: Mutex<CriticalSectionRawMutex, Vec<u8, REWRITE_BUFFER_LEN>> =
+    Mutex::new(Vec::new());
+
+/// Returns the key of every item the map can hold, other than StorageCheck
+fn live_keys() -> impl Iterator<Item = StorageKey> {
+    let settings = (0..NUM_CONFIGS).flat_map(|config_num| {
+        SettingId::ALL
+            .iter()
+            .filter(move |setting| config_num == 0 || !setting.is_global())
+            .filter_map(move |setting| setting.storage_key(config_num))
+    });
+    let layers = (0..NUM_CONFIGS).flat_map(|config_num| {
+        (0..NUM_LAYERS).flat_map(move |layer| {
+            (0..2).map(move |slot| StorageKey::KeyScanCode {
+                config_num,
+                layer,
+                slot,
+            })
+        })
+    });
+    let calibrations = (0..NUM_KEYS).map(|key| StorageKey::Calibration { key });
+    settings
+        .chain(layers)
+        .chain(calibrations)
+        .chain([StorageKey::KeyStats, StorageKey::ConfigSlots])
+}
+
+/// Returns the key of every setting kept when the layout changes
+fn portable_keys() -> impl Iterator<Item = StorageKey> {
+    (0..NUM_CONFIGS).flat_map(|config_num| {
+        SettingId::ALL
+            .iter()
+            .filter(|setting| setting.survives_layout_change())
+            .filter(move |setting| config_num == 0 || !setting.is_global())
+            .filter_map(move |setting| setting.storage_key(config_num))
+    })
+}
+
+/// Item moved as is without knowing its format
+struct RawItem<'a>(&'a [u8]);
+
+impl<'a> Value<'a> for RawItem<'a> {
+    fn serialize_into(&self, buffer: &mut [u8]) -> Result<usize, SerializationError> {
+        buffer
+            .get_mut(..self.0.len())
+            .ok_or(SerializationError::BufferTooSmall)?
+            .copy_from_slice(self.0);
+        Ok(self.0.len())
+    }
+
+    fn deserialize_from(buffer: &'a [u8]) -> Result<(Self, usize), SerializationError> {
+        Ok((RawItem(buffer), buffer.len()))
+    }
+}
+
+/// Flash that counts the pages erased through it in [ERASE_COUNT]
+pub struct WearFlash<S: NorFlash> {
+    flash: S,
+}
+
+impl<S: NorFlash> ErrorType for WearFlash<S> {
+    type Error = S::Error;
+}
+
+impl<S: NorFlash> ReadNorFlash for WearFlash<S> {
+    const READ_SIZE: usize = S::READ_SIZE;
+
+    async fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        self.flash.read(offset, bytes).await
+    }
+
+    fn capacity(&self) -> usize {
+        self.flash.capacity()
+    }
+}
+
+impl<S: NorFlash> NorFlash for WearFlash<S> {
+    const WRITE_SIZE: usize = S::WRITE_SIZE;
+    const ERASE_SIZE: usize = S::ERASE_SIZE;
+
+    async fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        self.flash.erase(from, to).await?;
+        let pages = (to - from) / S::ERASE_SIZE as u32;
+        ERASE_COUNT.lock(|count| count.set(count.get().wrapping_add(pages)));
+        Ok(())
+    }
+
+    async fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        self.flash.write(offset, bytes).await
+    }
+}
+
+type Map<S> = MapStorage<InternalStorageKey, WearFlash<S>, NoCache>;
+
 pub struct Storage<S: NorFlash> {
-    map: Mutex<CriticalSectionRawMutex, MapStorage<InternalStorageKey, S, NoCache>>,
+    map: Mutex<CriticalSectionRawMutex, Map<S>>,
+    capacity: u32,
 }
 
 #[derive(Debug, Clone)]
 pub enum StorageItem {
     Key(ScanCodeLayerStorage<NUM_KEYS>),
+    BatteryThresholds(BatteryThresholds),
+    MouseSettings(MouseSettings),
+    Combos(Combos),
+    SocdPairs(SocdPairs),
+    Lighting(LightingSettings),
+    Side(Side),
+    SwitchProfiles(SwitchProfiles),
+    HostOs(HostOs),
+    UsbIdentity(UsbIdentity),
+    PairingKey(PairingKey),
+    LayerTimeouts(LayerTimeouts),
+    ReportRate(ReportRate),
+    StandaloneLayer(StandaloneLayer),
+    HostPairings(HostPairings),
+    AutoShift(AutoShiftSettings),
+    ConfigInfo(ConfigInfo),
+    DynamicMacro(DynamicMacro),
+    LinkTiming(LinkTiming),
+    DriftCompensation(DriftCompensation),
+    LayerMouseSpeeds(LayerMouseSpeeds),
+    Calibration(KeyCalibration),
+    KeyStats(KeyStats),
+    ConfigSlots(ConfigSlots),
+}
+
+impl StorageItem {
+    /// Serializes the item into the buffer. Returns the number of bytes used
+    pub fn serialize_into(&self, buffer: &mut [u8]) -> Result<usize, SerializationError> {
+        match self {
+            StorageItem::Key(code) => code.serialize_into(buffer),
+            StorageItem::BatteryThresholds(thresholds) => thresholds.serialize_into(buffer),
+            StorageItem::MouseSettings(settings) => settings.serialize_into(buffer),
+            StorageItem::Combos(combos) => combos.serialize_into(buffer),
+            StorageItem::SocdPairs(pairs) => pairs.serialize_into(buffer),
+            StorageItem::Lighting(settings) => settings.serialize_into(buffer),
+            StorageItem::Side(side) => side.serialize_into(buffer),
+            StorageItem::SwitchProfiles(profiles) => profiles.serialize_into(buffer),
+            StorageItem::HostOs(os) => os.serialize_into(buffer),
+            StorageItem::UsbIdentity(identity) => identity.serialize_into(buffer),
+            StorageItem::PairingKey(key) => key.serialize_into(buffer),
+            StorageItem::LayerTimeouts(timeouts) => timeouts.serialize_into(buffer),
+            StorageItem::ReportRate(rate) => rate.serialize_into(buffer),
+            StorageItem::StandaloneLayer(layer) => layer.serialize_into(buffer),
+            StorageItem::HostPairings(pairings) => pairings.serialize_into(buffer),
+            StorageItem::AutoShift(settings) => settings.serialize_into(buffer),
+            StorageItem::ConfigInfo(info) => info.serialize_into(buffer),
+            StorageItem::DynamicMacro(recorded) => recorded.serialize_into(buffer),
+            StorageItem::LinkTiming(timing) => timing.serialize_into(buffer),
+            StorageItem::DriftCompensation(settings) => settings.serialize_into(buffer),
+            StorageItem::LayerMouseSpeeds(speeds) => speeds.serialize_into(buffer),
+            StorageItem::Calibration(calibration) => calibration.serialize_into(buffer),
+            StorageItem::KeyStats(stats) => stats.serialize_into(buffer),
+            StorageItem::ConfigSlots(slots) => slots.serialize_into(buffer),
+        }
+    }
 }
 
 impl<S: NorFlash> Storage<S> {
     /// Returns Storage Struct. This method will clear
     /// the flash range if not intialized.
-    pub async fn init(mut flash: S, flash_range: Range<u32>) -> Self {
+    pub async fn init(flash: S, flash_range: Range<u32>) -> Self {
         info!("Init Stage");
-        let mut data_buffer = [0; 128];
+        // Large enough for a keymap moved by a layout migration
+        let mut data_buffer = [0; 256];
 
         Timer::after_millis(10).await;
 
-        let mut map: MapStorage<InternalStorageKey, S, NoCache> =
-            MapStorage::new(flash, MapConfig::new(flash_range), NoCache::default());
+        let capacity = flash_range.end - flash_range.start;
+        let mut map: Map<S> = MapStorage::new(
+            WearFlash { flash },
+            MapConfig::new(flash_range),
+            NoCache::default(),
+        );
         // Check if the key value pair (0x0, 0x69) is in the map
         // If the pair is not in the map, it indicates that the
         // storage isn't initialized
@@ -73,46 +816,131 @@ impl<S: NorFlash> Storage<S> {
             Ok(res) => match res {
                 Some(val) => {
                     if val != 0x69 {
-                        map.erase_all().await.unwrap();
-                        map.store_item(
-                            &mut data_buffer,
-                            &StorageKey::StorageCheck.to_key(),
-                            &0x69u32,
-                        )
-                        .await
-                        .unwrap();
                         info!("Key Exists, invalid value");
+                        if let Err(err) = Self::reset(&mut map, &mut data_buffer).await {
+                            record_storage_error(err);
+                        }
                     } else {
                         info!("Valid Storage");
+                        if let Err(err) = Self::check_layout(&mut map, &mut data_buffer).await {
+                            record_storage_error(err);
+                        }
                     }
                 }
                 None => {
-                    map.erase_all().await.unwrap();
-                    map.store_item(
-                        &mut data_buffer,
-                        &StorageKey::StorageCheck.to_key(),
-                        &0x69u32,
-                    )
-                    .await
-                    .unwrap();
                     info!("Key Doesn't exist");
+                    if let Err(err) = Self::reset(&mut map, &mut data_buffer).await {
+                        record_storage_error(err);
+                    }
                 }
             },
             Err(_) => {
-                info!("Error occured");
+                error!("Unable to read storage check");
+                record_storage_error(KeyLibError::Storage);
             }
         };
         Self {
             map: Mutex::new(map),
+            capacity,
         }
     }
 
+    // Erases the map and marks it as initialized with the current layout
+    async fn reset(map: &mut Map<S>, data_buffer: &mut [u8]) -> Result<(), KeyLibError> {
+        let _pause = ScanPause::new();
+        map.erase_all().await.map_err(|_| KeyLibError::Storage)?;
+        map.store_item(data_buffer, &StorageKey::StorageCheck.to_key(), &0x69u32)
+            .await
+            .map_err(|_| KeyLibError::Storage)?;
+        Self::store_layout(map, data_buffer).await
+    }
+
+    async fn store_layout(map: &mut Map<S>, data_buffer: &mut [u8]) -> Result<(), KeyLibError> {
+        map.store_item(
+            data_buffer,
+            &StorageKey::Layout.to_key(),
+            &StorageLayout::current(),
+        )
+        .await
+        .map_err(|_| KeyLibError::Storage)
+    }
+
+    // Migrates the map if it was written for other counts. Settings that
+    // don't depend on the counts are kept and everything else goes back to
+    // its default. A new schema version resets the whole map
+    async fn check_layout(map: &mut Map<S>, data_buffer: &mut [u8]) -> Result<(), KeyLibError> {
+        let layout = map
+            .fetch_item::<StorageLayout>(data_buffer, &StorageKey::Layout.to_key())
+            .await;
+        match layout {
+            Ok(Some(layout)) if layout == StorageLayout::current() => Ok(()),
+            Ok(Some(layout)) if layout.version == STORAGE_SCHEMA_VERSION => {
+                info!("Storage layout changed from {}, migrating", layout);
+                let _pause = ScanPause::new();
+                Self::rewrite(map, data_buffer, portable_keys()).await
+            }
+            Ok(Some(layout)) => {
+                info!("Storage schema changed from {}, resetting", layout);
+                Self::reset(map, data_buffer).await
+            }
+            // Maps written before the layout was stored are assumed to
+            // match, since the counts of a board never changed before
+            Ok(None) => Self::store_layout(map, data_buffer).await,
+            Err(_) => Err(KeyLibError::Storage),
+        }
+    }
+
+    // Erases the map and writes back the newest copy of the items with the
+    // keys. Items are held in ram while the flash is erased, so losing power
+    // during a rewrite loses them
+    async fn rewrite(
+        map: &mut Map<S>,
+        buffer: &mut [u8],
+        keys: impl Iterator<Item = StorageKey>,
+    ) -> Result<(), KeyLibError> {
+        let mut items = REWRITE_BUFFER.lock().await;
+        items.clear();
+        for key in keys {
+            let key = key.to_key();
+            let Some(item) = map
+                .fetch_item::<RawItem>(buffer, &key)
+                .await
+                .map_err(|_| KeyLibError::Storage)?
+            else {
+                continue;
+            };
+            items
+                .extend_from_slice(&key.to_le_bytes())
+                .and_then(|_| items.extend_from_slice(&(item.0.len() as u16).to_le_bytes()))
+                .and_then(|_| items.extend_from_slice(item.0))
+                .map_err(|_| KeyLibError::Storage)?;
+        }
+        Self::reset(map, buffer).await?;
+        let mut rest = items.as_slice();
+        while rest.len() >= REWRITE_ITEM_HEADER_LEN {
+            let key = u16::from_le_bytes([rest[0], rest[1]]);
+            let len = u16::from_le_bytes([rest[2], rest[3]]) as usize;
+            let (data, next) = rest[REWRITE_ITEM_HEADER_LEN..].split_at(len);
+            map.store_item(buffer, &key, &RawItem(data))
+                .await
+                .map_err(|_| KeyLibError::Storage)?;
+            rest = next;
+        }
+        info!("Rewrote storage, {} bytes of items", items.len());
+        Ok(())
+    }
+
     pub async fn store_item<'a, V: Value<'a>>(&self, key: InternalStorageKey, value: &V) {
         let mut buffer = [0; 256];
         let mut map = self.map.lock().await;
+        // Storing can erase a page once the map fills up
+        let _pause = ScanPause::new();
         match map.store_item(&mut buffer, &key, value).await {
             Ok(_) => info!("Item Stored succesfully"),
-            Err(_) => error!("Failed to store item"),
+            Err(_) => {
+                error!("Failed to store item");
+                record_storage_error(KeyLibError::Storage);
+            }
         }
     }
 
@@ -126,6 +954,49 @@ impl<S: NorFlash> Storage<S> {
                 let key_index = key.to_key();
                 match value {
                     StorageItem::Key(code) => self.store_item(key_index, &code).await,
+                    StorageItem::BatteryThresholds(thresholds) => {
+                        self.store_item(key_index, &thresholds).await
+                    }
+                    StorageItem::MouseSettings(settings) => {
+                        self.store_item(key_index, &settings).await
+                    }
+                    StorageItem::Combos(combos) => self.store_item(key_index, &combos).await,
+                    StorageItem::SocdPairs(pairs) => self.store_item(key_index, &pairs).await,
+                    StorageItem::Lighting(settings) => self.store_item(key_index, &settings).await,
+                    StorageItem::Side(side) => self.store_item(key_index, &side).await,
+                    StorageItem::SwitchProfiles(profiles) => {
+                        self.store_item(key_index, &profiles).await
+                    }
+                    StorageItem::HostOs(os) => self.store_item(key_index, &os).await,
+                    StorageItem::UsbIdentity(identity) => {
+                        self.store_item(key_index, &identity).await
+                    }
+                    StorageItem::PairingKey(key) => self.store_item(key_index, &key).await,
+                    StorageItem::LayerTimeouts(timeouts) => {
+                        self.store_item(key_index, &timeouts).await
+                    }
+                    StorageItem::ReportRate(rate) => self.store_item(key_index, &rate).await,
+                    StorageItem::StandaloneLayer(layer) => self.store_item(key_index, &layer).await,
+                    StorageItem::HostPairings(pairings) => {
+                        self.store_item(key_index, &pairings).await
+                    }
+                    StorageItem::AutoShift(settings) => self.store_item(key_index, &settings).await,
+                    StorageItem::ConfigInfo(info) => self.store_item(key_index, &info).await,
+                    StorageItem::DynamicMacro(recorded) => {
+                        self.store_item(key_index, &recorded).await
+                    }
+                    StorageItem::LinkTiming(timing) => self.store_item(key_index, &timing).await,
+                    StorageItem::DriftCompensation(settings) => {
+                        self.store_item(key_index, &settings).await
+                    }
+                    StorageItem::LayerMouseSpeeds(speeds) => {
+                        self.store_item(key_index, &speeds).await
+                    }
+                    StorageItem::Calibration(calibration) => {
+                        self.store_item(key_index, &calibration).await
+                    }
+                    StorageItem::KeyStats(stats) => self.store_item(key_index, &stats).await,
+                    StorageItem::ConfigSlots(slots) => self.store_item(key_index, &slots).await,
                 };
             }
         };
@@ -136,27 +1007,275 @@ impl<S: NorFlash> Storage<S> {
                 let key_index = key.to_key();
                 let mut buf = [0u8; 256];
                 match key {
-                    StorageKey::StorageCheck => {
+                    StorageKey::StorageCheck | StorageKey::Layout => {
                         STORAGE_SIGNAL_ITEM.signal(None);
                     }
                     StorageKey::KeyScanCode { .. } => {
                         match self
                             .get_item::<ScanCodeLayerStorage<NUM_KEYS>>(key_index, &mut buf)
                             .await
-                            .unwrap()
                         {
-                            Some(val) => {
+                            Ok(Some(val)) => {
                                 STORAGE_SIGNAL_ITEM.signal(Some(StorageItem::Key(val)));
                             }
-                            None => {
+                            Ok(None) => {
+                                STORAGE_SIGNAL_ITEM.signal(None);
+                            }
+                            Err(_) => {
+                                error!("Failed to read key {}", key);
+                                record_storage_error(KeyLibError::Storage);
                                 STORAGE_SIGNAL_ITEM.signal(None);
                             }
                         }
                     }
+                    StorageKey::BatteryThresholds => {
+                        let item = self
+                            .get_item::<BatteryThresholds>(key_index, &mut buf)
+                            .await
+                            .ok()
+                            .flatten()
+                            .map(StorageItem::BatteryThresholds);
+                        STORAGE_SIGNAL_ITEM.signal(item);
+                    }
+                    StorageKey::MouseSettings { .. } => {
+                        let item = self
+                            .get_item::<MouseSettings>(key_index, &mut buf)
+                            .await
+                            .ok()
+                            .flatten()
+                            .map(StorageItem::MouseSettings);
+                        STORAGE_SIGNAL_ITEM.signal(item);
+                    }
+                    StorageKey::Combo { .. } => {
+                        let item = self
+                            .get_item::<Combos>(key_index, &mut buf)
+                            .await
+                            .ok()
+                            .flatten()
+                            .map(StorageItem::Combos);
+                        STORAGE_SIGNAL_ITEM.signal(item);
+                    }
+                    StorageKey::Socd { .. } => {
+                        let item = self
+                            .get_item::<SocdPairs>(key_index, &mut buf)
+                            .await
+                            .ok()
+                            .flatten()
+                            .map(StorageItem::SocdPairs);
+                        STORAGE_SIGNAL_ITEM.signal(item);
+                    }
+                    StorageKey::Lighting => {
+                        let item = self
+                            .get_item::<LightingSettings>(key_index, &mut buf)
+                            .await
+                            .ok()
+                            .flatten()
+                            .map(StorageItem::Lighting);
+                        STORAGE_SIGNAL_ITEM.signal(item);
+                    }
+                    StorageKey::Side => {
+                        let item = self
+                            .get_item::<Side>(key_index, &mut buf)
+                            .await
+                            .ok()
+                            .flatten()
+                            .map(StorageItem::Side);
+                        STORAGE_SIGNAL_ITEM.signal(item);
+                    }
+                    StorageKey::Calibration { .. } => {
+                        let item = self
+                            .get_item::<KeyCalibration>(key_index, &mut buf)
+                            .await
+                            .ok()
+                            .flatten()
+                            .map(StorageItem::Calibration);
+                        STORAGE_SIGNAL_ITEM.signal(item);
+                    }
+                    StorageKey::PairingKey => {
+                        let item = self
+                            .get_item::<PairingKey>(key_index, &mut buf)
+                            .await
+                            .ok()
+                            .flatten()
+                            .map(StorageItem::PairingKey);
+                        STORAGE_SIGNAL_ITEM.signal(item);
+                    }
+                    StorageKey::UsbIdentity => {
+                        let item = self
+                            .get_item::<UsbIdentity>(key_index, &mut buf)
+                            .await
+                            .ok()
+                            .flatten()
+                            .map(StorageItem::UsbIdentity);
+                        STORAGE_SIGNAL_ITEM.signal(item);
+                    }
+                    StorageKey::DynamicMacro => {
+                        let item = self
+                            .get_item::<DynamicMacro>(key_index, &mut buf)
+                            .await
+                            .ok()
+                            .flatten()
+                            .map(StorageItem::DynamicMacro);
+                        STORAGE_SIGNAL_ITEM.signal(item);
+                    }
+                    StorageKey::KeyStats => {
+                        let item = self
+                            .get_item::<KeyStats>(key_index, &mut buf)
+                            .await
+                            .ok()
+                            .flatten()
+                            .map(StorageItem::KeyStats);
+                        STORAGE_SIGNAL_ITEM.signal(item);
+                    }
+                    StorageKey::ConfigSlots => {
+                        let item = self
+                            .get_item::<ConfigSlots>(key_index, &mut buf)
+                            .await
+                            .ok()
+                            .flatten()
+                            .map(StorageItem::ConfigSlots);
+                        STORAGE_SIGNAL_ITEM.signal(item);
+                    }
+                    StorageKey::HostPairings => {
+                        let item = self
+                            .get_item::<HostPairings>(key_index, &mut buf)
+                            .await
+                            .ok()
+                            .flatten()
+                            .map(StorageItem::HostPairings);
+                        STORAGE_SIGNAL_ITEM.signal(item);
+                    }
+                    StorageKey::ReportRate => {
+                        let item = self
+                            .get_item::<ReportRate>(key_index, &mut buf)
+                            .await
+                            .ok()
+                            .flatten()
+                            .map(StorageItem::ReportRate);
+                        STORAGE_SIGNAL_ITEM.signal(item);
+                    }
+                    StorageKey::LinkTiming => {
+                        let item = self
+                            .get_item::<LinkTiming>(key_index, &mut buf)
+                            .await
+                            .ok()
+                            .flatten()
+                            .map(StorageItem::LinkTiming);
+                        STORAGE_SIGNAL_ITEM.signal(item);
+                    }
+                    StorageKey::DriftCompensation => {
+                        let item = self
+                            .get_item::<DriftCompensation>(key_index, &mut buf)
+                            .await
+                            .ok()
+                            .flatten()
+                            .map(StorageItem::DriftCompensation);
+                        STORAGE_SIGNAL_ITEM.signal(item);
+                    }
+                    StorageKey::HostOs => {
+                        let item = self
+                            .get_item::<HostOs>(key_index, &mut buf)
+                            .await
+                            .ok()
+                            .flatten()
+                            .map(StorageItem::HostOs);
+                        STORAGE_SIGNAL_ITEM.signal(item);
+                    }
+                    StorageKey::SwitchProfiles { .. } => {
+                        let item = self
+                            .get_item::<SwitchProfiles>(key_index, &mut buf)
+                            .await
+                            .ok()
+                            .flatten()
+                            .map(StorageItem::SwitchProfiles);
+                        STORAGE_SIGNAL_ITEM.signal(item);
+                    }
+                    StorageKey::LayerTimeouts { .. } => {
+                        let item = self
+                            .get_item::<LayerTimeouts>(key_index, &mut buf)
+                            .await
+                            .ok()
+                            .flatten()
+                            .map(StorageItem::LayerTimeouts);
+                        STORAGE_SIGNAL_ITEM.signal(item);
+                    }
+                    StorageKey::LayerMouseSpeeds { .. } => {
+                        let item = self
+                            .get_item::<LayerMouseSpeeds>(key_index, &mut buf)
+                            .await
+                            .ok()
+                            .flatten()
+                            .map(StorageItem::LayerMouseSpeeds);
+                        STORAGE_SIGNAL_ITEM.signal(item);
+                    }
+                    StorageKey::StandaloneLayer { .. } => {
+                        let item = self
+                            .get_item::<StandaloneLayer>(key_index, &mut buf)
+                            .await
+                            .ok()
+                            .flatten()
+                            .map(StorageItem::StandaloneLayer);
+                        STORAGE_SIGNAL_ITEM.signal(item);
+                    }
+                    StorageKey::AutoShift { .. } => {
+                        let item = self
+                            .get_item::<AutoShiftSettings>(key_index, &mut buf)
+                            .await
+                            .ok()
+                            .flatten()
+                            .map(StorageItem::AutoShift);
+                        STORAGE_SIGNAL_ITEM.signal(item);
+                    }
+                    StorageKey::ConfigInfo { .. } => {
+                        let item = self
+                            .get_item::<ConfigInfo>(key_index, &mut buf)
+                            .await
+                            .ok()
+                            .flatten()
+                            .map(StorageItem::ConfigInfo);
+                        STORAGE_SIGNAL_ITEM.signal(item);
+                    }
+                }
+            }
+        };
+        let autosave_loop = async {
+            loop {
+                AUTOSAVE_SIGNAL.wait().await;
+                // Restart the delay on every change so items are only written
+                // once they stop changing
+                while let Either::Second(_) =
+                    select(Timer::after(AUTOSAVE_DELAY), AUTOSAVE_SIGNAL.wait()).await
+                {
+                }
+                flush_saves().await;
+            }
+        };
+        let maintenance_loop = async {
+            loop {
+                let request = STORAGE_SIGNAL_MAINTENANCE.wait().await;
+                info!("Storage maintenance: {}", request);
+                match request {
+                    Maintenance::Compact => {
+                        // Scheduled items would be lost if they were still
+                        // pending when the flash is erased
+                        flush_saves().await;
+                        if let Err(err) = self.compact().await {
+                            record_storage_error(err);
+                        }
+                    }
+                    Maintenance::Erase => {
+                        // Scheduled items would write the old settings back
+                        PENDING_SAVES.lock().await.clear();
+                        if let Err(err) = self.erase().await {
+                            record_storage_error(err);
+                        }
+                    }
+                    Maintenance::Stats => {}
                 }
+                STORAGE_SIGNAL_STATS.signal(self.stats().await);
             }
         };
-        join(write_loop, read_loop).await;
+        join4(write_loop, read_loop, autosave_loop, maintenance_loop).await;
     }
 
     pub async fn get_item<'a, V: Value<'a>>(
@@ -168,19 +1287,174 @@ impl<S: NorFlash> Storage<S> {
         map.fetch_item(buffer, &key).await
     }
 
-    pub async fn clear(&self) {
+    pub async fn clear(&self) -> Result<(), KeyLibError> {
+        let mut map = self.map.lock().await;
+        let _pause = ScanPause::new();
+        map.erase_all().await.map_err(|_| KeyLibError::Storage)
+    }
+
+    /// Returns how much of the flash range is filled. Stale copies of items
+    /// count as used until the page holding them is erased
+    pub async fn stats(&self) -> StorageStats {
+        let mut buffer = [0; 256];
+        let mut map = self.map.lock().await;
+        let overhead = Map::<S>::item_overhead_size();
+        let mut used_bytes = 0;
+        let mut last_error = None;
+        match map.fetch_all_items(&mut buffer).await {
+            Ok(mut items) => loop {
+                match items.next::<RawItem>(&mut buffer).await {
+                    Ok(Some((_, item))) => {
+                        let len = size_of::<InternalStorageKey>() + item.0.len();
+                        used_bytes += overhead + len.next_multiple_of(S::WRITE_SIZE) as u32;
+                    }
+                    Ok(None) => break,
+                    Err(_) => {
+                        last_error = Some(KeyLibError::Storage);
+                        break;
+                    }
+                }
+            },
+            Err(_) => last_error = Some(KeyLibError::Storage),
+        }
+        drop(map);
+        if let Some(err) = last_error {
+            record_storage_error(err);
+        }
+        StorageStats {
+            used_bytes,
+            capacity_bytes: self.capacity,
+            erase_count: ERASE_COUNT.lock(|count| count.get()),
+            last_error: LAST_STORAGE_ERROR.lock(|cell| cell.get()),
+        }
+    }
+
+    /// Erases the map and writes back the newest copy of every item, so the
+    /// map only holds live items. Items are held in ram while the flash is
+    /// erased, so losing power during a compaction loses them
+    pub async fn compact(&self) -> Result<(), KeyLibError> {
+        let mut buffer = [0; 256];
+        let mut map = self.map.lock().await;
+        let _pause = ScanPause::new();
+        Self::rewrite(&mut map, &mut buffer, live_keys()).await
+    }
+
+    /// Erases every item, leaving the map like it was initialized by this
+    /// firmware
+    pub async fn erase(&self) -> Result<(), KeyLibError> {
+        let mut buffer = [0; 256];
         let mut map = self.map.lock().await;
-        map.erase_all().await.unwrap();
+        Self::reset(&mut map, &mut buffer).await
     }
 }
 
 pub async fn get_item(key: StorageKey) -> Option<StorageItem> {
     info!("Requested {} | {}", key, key.to_key());
+    // Items waiting to be autosaved are newer than the ones in flash
+    if let Some((_, item)) = PENDING_SAVES
+        .lock()
+        .await
+        .iter()
+        .find(|(pending, _)| pending.to_key() == key.to_key())
+    {
+        return Some(item.clone());
+    }
     let _lock = STORAGE_REQUEST_READ_LOCK.lock().await;
     STORAGE_SIGNAL_READ.signal(key);
     STORAGE_SIGNAL_ITEM.wait().await
 }
 
 pub async fn store_val(key: StorageKey, item: &StorageItem) {
+    // Drop any scheduled save of the key so it doesn't overwrite this item
+    PENDING_SAVES
+        .lock()
+        .await
+        .retain(|(pending, _)| pending.to_key() != key.to_key());
     STORAGE_WRITE_CHANNEL.send((key, item.clone())).await;
 }
+
+/// Schedules the item to be stored once nothing has been scheduled for
+/// [AUTOSAVE_DELAY]. Used for settings changed with key presses so repeated
+/// presses don't each write to flash
+pub async fn schedule_save(key: StorageKey, item: StorageItem) {
+    let mut pending = PENDING_SAVES.lock().await;
+    if let Some(entry) = pending
+        .iter_mut()
+        .find(|(pending, _)| pending.to_key() == key.to_key())
+    {
+        entry.1 = item;
+    } else if let Err(entry) = pending.push((key, item)) {
+        // Store the oldest item right away to make room
+        let (old_key, old_item) = pending.remove(0);
+        STORAGE_WRITE_CHANNEL.send((old_key, old_item)).await;
+        let _ = pending.push(entry);
+    }
+    drop(pending);
+    AUTOSAVE_SIGNAL.signal(());
+}
+
+/// Returns the fill level, erase count and last error of storage
+pub async fn storage_stats() -> StorageStats {
+    let _lock = STORAGE_REQUEST_MAINTENANCE_LOCK.lock().await;
+    STORAGE_SIGNAL_MAINTENANCE.signal(Maintenance::Stats);
+    STORAGE_SIGNAL_STATS.wait().await
+}
+
+/// Compacts storage with [Storage::compact] and returns the stats after it
+pub async fn compact_storage() -> StorageStats {
+    let _lock = STORAGE_REQUEST_MAINTENANCE_LOCK.lock().await;
+    STORAGE_SIGNAL_MAINTENANCE.signal(Maintenance::Compact);
+    STORAGE_SIGNAL_STATS.wait().await
+}
+
+/// Erases storage with [Storage::erase] and returns the stats after it
+pub async fn erase_storage() -> StorageStats {
+    let _lock = STORAGE_REQUEST_MAINTENANCE_LOCK.lock().await;
+    STORAGE_SIGNAL_MAINTENANCE.signal(Maintenance::Erase);
+    STORAGE_SIGNAL_STATS.wait().await
+}
+
+/// Writes all items scheduled with [schedule_save] to flash without waiting
+/// for the autosave delay
+pub async fn flush_saves() {
+    let pending = core::mem::take(&mut *PENDING_SAVES.lock().await);
+    for (key, item) in pending {
+        info!("Autosaving {}", key);
+        STORAGE_WRITE_CHANNEL.send((key, item)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn layout_round_trips() {
+        let mut buffer = [0u8; STORAGE_LAYOUT_SERIAL_LENGTH];
+        let layout = StorageLayout::current();
+        assert_eq!(layout.serialize_into(&mut buffer), Ok(4));
+        assert_eq!(
+            buffer,
+            [
+                STORAGE_SCHEMA_VERSION,
+                NUM_KEYS as u8,
+                NUM_LAYERS as u8,
+                NUM_CONFIGS as u8
+            ]
+        );
+        assert_eq!(StorageLayout::deserialize_from(&buffer), Ok((layout, 4)));
+    }
+
+    #[test]
+    fn migration_drops_items_sized_by_the_counts() {
+        let keys: Vec<InternalStorageKey, 32> = portable_keys().map(|key| key.to_key()).collect();
+        assert!(keys.contains(&StorageKey::PairingKey.to_key()));
+        assert!(keys.contains(&StorageKey::MouseSettings { config_num: 1 }.to_key()));
+        assert!(!keys.contains(&StorageKey::Lighting.to_key()));
+        assert!(!keys.contains(&StorageKey::SwitchProfiles { config_num: 0 }.to_key()));
+        assert!(portable_keys().all(|key| !matches!(
+            key,
+            StorageKey::KeyScanCode { .. } | StorageKey::Calibration { .. } | StorageKey::KeyStats
+        )));
+    }
+}
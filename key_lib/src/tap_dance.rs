@@ -0,0 +1,142 @@
+use embassy_time::{Duration, Instant};
+
+/// Code a tap dance key should send in the current report
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum TapDanceOutput {
+    None,
+    /// Key was tapped the number of times. Taps below the dance's max are
+    /// sent for a single report, and the max is sent while its press is held
+    Taps(u8),
+    /// Key was held past the tapping term. Sent while held
+    Hold,
+}
+
+/// Resolves a tap dance key into a number of taps or a hold
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum TapDance {
+    Idle,
+    // Press of the tap, waiting to see if the key is held past the term
+    Pressed { since: Instant, taps: u8 },
+    // Released before the term, waiting to see if the key is pressed again
+    Released { since: Instant, taps: u8 },
+    Held,
+    // Resolved into the taps while the last press is still held
+    Tapped(u8),
+}
+
+impl TapDance {
+    pub const fn default() -> Self {
+        Self::Idle
+    }
+
+    /// Returns true if the key is being held or waiting to be resolved
+    pub fn is_active(&self) -> bool {
+        *self != Self::Idle
+    }
+
+    /// Advances the state with the key's pressed status and returns the
+    /// code the key should send. A dance resolves as soon as it's pressed
+    /// max_taps times
+    pub fn update(
+        &mut self,
+        pressed: bool,
+        now: Instant,
+        term: Duration,
+        max_taps: u8,
+    ) -> TapDanceOutput {
+        let expired = |time: Instant| now.saturating_duration_since(time) >= term;
+        let (state, output) = match (*self, pressed) {
+            (Self::Idle, true) => (
+                Self::Pressed {
+                    since: now,
+                    taps: 1,
+                },
+                TapDanceOutput::None,
+            ),
+            (Self::Idle, false) => (Self::Idle, TapDanceOutput::None),
+            (Self::Pressed { since, taps: 1 }, true) if expired(since) => {
+                (Self::Held, TapDanceOutput::Hold)
+            }
+            // A tap held past the term resolves into the taps so far
+            (Self::Pressed { since, taps }, true) if expired(since) => {
+                (Self::Tapped(taps), TapDanceOutput::Taps(taps))
+            }
+            (Self::Pressed { since, taps }, true) => {
+                (Self::Pressed { since, taps }, TapDanceOutput::None)
+            }
+            (Self::Pressed { taps, .. }, false) => {
+                (Self::Released { since: now, taps }, TapDanceOutput::None)
+            }
+            (Self::Released { taps, .. }, true) if taps + 1 >= max_taps => {
+                (Self::Tapped(max_taps), TapDanceOutput::Taps(max_taps))
+            }
+            (Self::Released { taps, .. }, true) => (
+                Self::Pressed {
+                    since: now,
+                    taps: taps + 1,
+                },
+                TapDanceOutput::None,
+            ),
+            (Self::Released { since, taps }, false) if expired(since) => {
+                (Self::Idle, TapDanceOutput::Taps(taps))
+            }
+            (Self::Released { since, taps }, false) => {
+                (Self::Released { since, taps }, TapDanceOutput::None)
+            }
+            (Self::Held, true) => (Self::Held, TapDanceOutput::Hold),
+            (Self::Tapped(taps), true) => (Self::Tapped(taps), TapDanceOutput::Taps(taps)),
+            (Self::Held | Self::Tapped(_), false) => (Self::Idle, TapDanceOutput::None),
+        };
+        *self = state;
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TERM: Duration = Duration::from_millis(200);
+
+    fn run(dance: &mut TapDance, max_taps: u8, presses: &[(u64, bool)]) -> TapDanceOutput {
+        let mut output = TapDanceOutput::None;
+        for &(ms, pressed) in presses {
+            output = dance.update(pressed, Instant::from_millis(ms), TERM, max_taps);
+        }
+        output
+    }
+
+    #[test]
+    fn taps_are_counted_until_the_term() {
+        let mut dance = TapDance::default();
+        let taps = [(0, true), (50, false), (100, true), (150, false)];
+        assert_eq!(run(&mut dance, 3, &taps), TapDanceOutput::None);
+        assert_eq!(run(&mut dance, 3, &[(400, false)]), TapDanceOutput::Taps(2));
+        assert!(!dance.is_active());
+    }
+
+    #[test]
+    fn max_taps_resolve_on_press() {
+        let mut dance = TapDance::default();
+        let taps = [
+            (0, true),
+            (50, false),
+            (100, true),
+            (150, false),
+            (200, true),
+        ];
+        assert_eq!(run(&mut dance, 3, &taps), TapDanceOutput::Taps(3));
+        // Sent while the last press is held
+        assert_eq!(run(&mut dance, 3, &[(600, true)]), TapDanceOutput::Taps(3));
+        assert_eq!(run(&mut dance, 3, &[(650, false)]), TapDanceOutput::None);
+    }
+
+    #[test]
+    fn first_press_held_is_a_hold() {
+        let mut dance = TapDance::default();
+        assert_eq!(
+            run(&mut dance, 2, &[(0, true), (300, true)]),
+            TapDanceOutput::Hold
+        );
+    }
+}
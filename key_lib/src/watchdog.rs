@@ -0,0 +1,134 @@
+//! Software side of the hardware watchdog. The loops a board watches check in
+//! with [check_in] on every pass, and [run_watchdog] only feeds the hardware
+//! watchdog while all of them keep checking in. A loop that stops, or an
+//! executor that never yields, resets the board instead of locking it up.
+//!
+//! Waits on the host or the other half can legitimately take any time, so
+//! they're wrapped in [idle], which excuses the loop until the wait ends
+
+use core::cell::Cell;
+
+use defmt::{Format, error};
+use embassy_sync::blocking_mutex::{self, raw::CriticalSectionRawMutex};
+use embassy_time::{Duration, Instant, Timer};
+
+/// Time between feeds of the hardware watchdog. Every watched loop has to
+/// check in within it
+pub const FEED_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Timeout boards should start their hardware watchdog with. A few feed
+/// intervals so a single slow pass doesn't reset the board
+pub const WATCHDOG_TIMEOUT: Duration = Duration::from_secs(4);
+
+/// Time the watched loops get to start after boot, while storage and usb are
+/// set up. The watchdog is fed until every loop checked in once or this ends
+pub const STARTUP_TIMEOUT: Duration = Duration::from_secs(10);
+
+static CHECK_INS: blocking_mutex::Mutex<CriticalSectionRawMutex, Cell<CheckIns>> =
+    blocking_mutex::Mutex::new(Cell::new(CheckIns::new()));
+
+/// Loops that can be watched
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Format)]
+pub enum Watched {
+    Keys = 1 << 0,
+    Radio = 1 << 1,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct CheckIns {
+    // Loops that checked in since the last feed
+    seen: u8,
+    // Loops waiting in [idle]
+    idle: u8,
+}
+
+impl CheckIns {
+    const fn new() -> Self {
+        Self { seen: 0, idle: 0 }
+    }
+
+    /// Returns the loops of watched that neither checked in nor were idle
+    /// since the last call
+    fn take_missing(&mut self, watched: u8) -> u8 {
+        let missing = watched & !(self.seen | self.idle);
+        self.seen = 0;
+        missing
+    }
+}
+
+fn update<R>(f: impl FnOnce(&mut CheckIns) -> R) -> R {
+    CHECK_INS.lock(|check_ins| {
+        let mut state = check_ins.get();
+        let res = f(&mut state);
+        check_ins.set(state);
+        res
+    })
+}
+
+/// Marks the loop as alive until the next feed
+pub fn check_in(watched: Watched) {
+    update(|state| state.seen |= watched as u8);
+}
+
+/// Runs fut without the loop having to check in while it's pending
+pub async fn idle<F: Future>(watched: Watched, fut: F) -> F::Output {
+    update(|state| state.idle |= watched as u8);
+    let res = fut.await;
+    update(|state| {
+        state.idle &= !(watched as u8);
+        state.seen |= watched as u8;
+    });
+    res
+}
+
+/// Calls feed every [FEED_INTERVAL] as long as every loop in watched checked
+/// in. Boards start their hardware watchdog with [WATCHDOG_TIMEOUT] before
+/// running it
+pub async fn run_watchdog(watched: &[Watched], mut feed: impl FnMut()) -> ! {
+    let watched = watched.iter().fold(0, |mask, w| mask | *w as u8);
+    let boot = Instant::now();
+    let mut starting = watched;
+    while starting != 0 && boot.elapsed() < STARTUP_TIMEOUT {
+        Timer::after(FEED_INTERVAL).await;
+        starting = update(|state| state.take_missing(starting));
+        feed();
+    }
+    loop {
+        Timer::after(FEED_INTERVAL).await;
+        let missing = update(|state| state.take_missing(watched));
+        if missing == 0 {
+            feed();
+        } else {
+            error!("Watched loops {:#04x} didn't check in", missing);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BOTH: u8 = Watched::Keys as u8 | Watched::Radio as u8;
+
+    #[test]
+    fn every_loop_has_to_check_in() {
+        let mut check_ins = CheckIns::new();
+        check_ins.seen |= Watched::Keys as u8;
+        assert_eq!(check_ins.take_missing(BOTH), Watched::Radio as u8);
+        // Check ins only count until the next feed
+        check_ins.seen |= Watched::Radio as u8;
+        assert_eq!(check_ins.take_missing(BOTH), Watched::Keys as u8);
+        check_ins.seen |= BOTH;
+        assert_eq!(check_ins.take_missing(BOTH), 0);
+    }
+
+    #[test]
+    fn idle_loops_are_excused() {
+        let mut check_ins = CheckIns::new();
+        check_ins.idle |= Watched::Radio as u8;
+        check_ins.seen |= Watched::Keys as u8;
+        assert_eq!(check_ins.take_missing(BOTH), 0);
+        assert_eq!(check_ins.take_missing(BOTH), Watched::Keys as u8);
+    }
+}
@@ -0,0 +1,50 @@
+use crate::keymap::Meta;
+
+// Needs to match key_lib::backup
+pub const BACKUP_VERSION: u8 = 1;
+pub const BACKUP_HEADER_LEN: usize = 12;
+
+/// Returns the length of the body after the header of a blob
+pub fn body_len(header: &[u8; BACKUP_HEADER_LEN]) -> usize {
+    u32::from_le_bytes([header[4], header[5], header[6], header[7]]) as usize
+}
+
+/// Checks a blob read from a file was exported from a keyboard like the
+/// connected one, so it isn't sent to a keyboard that would reject it
+pub fn check(blob: &[u8], meta: &Meta) -> Result<(), String> {
+    let header: &[u8; BACKUP_HEADER_LEN] = blob
+        .get(..BACKUP_HEADER_LEN)
+        .and_then(|header| header.try_into().ok())
+        .ok_or("The backup is missing its header")?;
+    if header[0] != BACKUP_VERSION {
+        return Err(format!(
+            "The backup has version {} but keyctl supports version {BACKUP_VERSION}",
+            header[0]
+        ));
+    }
+    let counts = (header[1] as usize, header[2] as usize, header[3] as usize);
+    if counts != (meta.configs, meta.keys, meta.layers) {
+        return Err(format!(
+            "The backup was made for {} configs, {} keys and {} layers but the keyboard has {} \
+             configs, {} keys and {} layers",
+            counts.0, counts.1, counts.2, meta.configs, meta.keys, meta.layers
+        ));
+    }
+    if blob.len() - BACKUP_HEADER_LEN != body_len(header) {
+        return Err("The backup is truncated".into());
+    }
+    Ok(())
+}
+
+/// Returns the message for a key_lib::backup::ImportStatus
+pub fn import_result(status: u8) -> Result<(), String> {
+    match status {
+        0 => Ok(()),
+        1 => Err("The keyboard doesn't support the backup's version".into()),
+        2 => Err("The backup was made for another keyboard".into()),
+        3 => Err("The backup is too large for the keyboard".into()),
+        4 => Err("The backup is corrupted".into()),
+        5 => Err("The backup holds an invalid config or setting".into()),
+        status => Err(format!("Unknown import status {status}")),
+    }
+}
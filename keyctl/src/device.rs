@@ -0,0 +1,863 @@
+use std::time::Duration;
+
+use async_hid::{AsyncHidRead, AsyncHidWrite, DeviceReader, DeviceWriter, HidBackend};
+use futures::StreamExt;
+use tokio::time::timeout;
+
+use crate::backup::{self, BACKUP_HEADER_LEN};
+use crate::diagnostics::{Diagnostic, DiagnosticKind, HEADER_LEN};
+use crate::keymap::{Behavior, Config, Meta};
+use crate::watch::{self, HalfLink, LINK_WATCH_FRAME_LEN};
+
+const USAGE_PAGE: u16 = 0xFF69;
+const USAGE: u16 = 0x1;
+const VENDOR_ID: u16 = 0xa55;
+
+const REPORT_SIZE: usize = 32;
+const READ_TIMEOUT: Duration = Duration::from_secs(2);
+// Importing a backup writes every config to flash before responding
+const IMPORT_TIMEOUT: Duration = Duration::from_secs(30);
+// Compacting erases and rewrites the whole storage range
+const COMPACT_TIMEOUT: Duration = Duration::from_secs(30);
+// The dongle relays a half's keymap once the half sends a packet, which can
+// take until its next heartbeat, and a write waits for the half's flash
+const HALF_TIMEOUT: Duration = Duration::from_secs(20);
+// The cue comes a few seconds after a reaction test starts and the test gives
+// up a few seconds after the cue
+const REACTION_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Needs to match key_lib::com framing
+const FRAME_MAGIC: u8 = 0xFA;
+const COM_PROTOCOL_VERSION: u8 = 1;
+// Index of the protocol version in the KeyboardMetaInfo response
+const META_VERSION_INDEX: usize = 9;
+
+// Needs to match key_lib::storage::SettingId::ConfigInfo
+const CONFIG_INFO_SETTING: u8 = 16;
+// Needs to match key_lib::storage::SettingId::LinkTiming
+const LINK_TIMING_SETTING: u8 = 17;
+// Needs to match key_lib::storage::SettingId::DriftCompensation
+const DRIFT_COMPENSATION_SETTING: u8 = 18;
+// Needs to match key_lib::factory_reset::FACTORY_RESET_CONFIRM
+const FACTORY_RESET_CONFIRM: [u8; 4] = *b"WIPE";
+// Needs to match key_lib::settings::MAX_CONFIG_NAME_LEN
+pub const MAX_CONFIG_NAME_LEN: usize = 16;
+
+/// Name and indicator color of a config. Black keeps the board's color
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ConfigInfo {
+    pub name: String,
+    pub color: [u8; 3],
+}
+
+// Needs to match key_lib::com::HidRequest
+#[repr(u8)]
+#[derive(Clone, Copy, Debug)]
+pub enum HidRequest {
+    UpdateKeys = 0,
+    KeyboardInfo = 1,
+    WriteToFlash = 2,
+    KeyboardMetaInfo = 3,
+    ReadSetting = 13,
+    WriteSetting = 14,
+    Diagnostics = 28,
+    SimKeys = 29,
+    InjectKey = 30,
+    LatencyTracking = 31,
+    SwitchHost = 32,
+    WatchLink = 33,
+    ExportConfig = 34,
+    ImportConfig = 35,
+    CompactStorage = 36,
+    ReactionTest = 37,
+    Bootloader = 38,
+    KeymapChecksum = 39,
+    KeyStats = 40,
+    SelfTest = 41,
+    FactoryReset = 42,
+    HalfLayer = 43,
+    UpdateHalfLayer = 44,
+}
+
+// Needs to match Half in tychocs/src/link.rs
+#[repr(u8)]
+#[derive(Clone, Copy, Debug)]
+pub enum Half {
+    Left = 1,
+    Right = 2,
+}
+
+impl Half {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "left" => Some(Self::Left),
+            "right" => Some(Self::Right),
+            _ => None,
+        }
+    }
+}
+
+// Needs to match key_lib::com::ForwardStatus
+fn forward_status(status: u8, half: Half) -> Result<(), String> {
+    match status {
+        0 => Ok(()),
+        1 => Err(format!("The {half:?} half doesn't keep its own keymap")),
+        2 => Err("The dongle or the half rejected the request".into()),
+        3 => Err(format!(
+            "Timed out waiting for the {half:?} half, press a key on it to wake it"
+        )),
+        status => Err(format!("Unknown forward status {status}")),
+    }
+}
+
+// Needs to match key_lib::reaction::ReactionEvent
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReactionEvent {
+    Cue,
+    Reaction { us: u32 },
+    FalseStart,
+    Timeout,
+}
+
+// Needs to match key_lib::inject::InjectCommand
+#[repr(u8)]
+#[derive(Clone, Copy, Debug)]
+pub enum InjectCommand {
+    Disable = 0,
+    Enable = 1,
+    Press = 2,
+    Release = 3,
+}
+
+impl InjectCommand {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "disable" => Some(Self::Disable),
+            "enable" => Some(Self::Enable),
+            "press" => Some(Self::Press),
+            "release" => Some(Self::Release),
+            _ => None,
+        }
+    }
+}
+
+// Needs to match key_lib::key_stats::KeyStatsCommand
+#[repr(u8)]
+#[derive(Clone, Copy, Debug)]
+pub enum KeyStatsCommand {
+    Disable = 0,
+    Enable = 1,
+    Read = 2,
+    Clear = 3,
+}
+
+impl KeyStatsCommand {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "off" => Some(Self::Disable),
+            "on" => Some(Self::Enable),
+            "clear" => Some(Self::Clear),
+            _ => None,
+        }
+    }
+}
+
+// Needs to match key_lib::power::ScanRate
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ScanRate {
+    Full = 0,
+    Reduced = 1,
+    Minimal = 2,
+}
+
+impl ScanRate {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "full" => Some(Self::Full),
+            "reduced" => Some(Self::Reduced),
+            "minimal" => Some(Self::Minimal),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Full => "full",
+            Self::Reduced => "reduced",
+            Self::Minimal => "minimal",
+        }
+    }
+}
+
+/// Host side copy of key_lib::power::LinkTiming
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LinkTiming {
+    pub heartbeat_ms: u16,
+    pub scan_rate: ScanRate,
+}
+
+// Needs to match key_lib::self_test::SelfTestCommand
+#[repr(u8)]
+#[derive(Clone, Copy, Debug)]
+pub enum SelfTestCommand {
+    Start = 0,
+    Read = 1,
+    Stop = 2,
+}
+
+// Needs to match key_lib::self_test::KeyResult
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeyResult {
+    Untested,
+    Stuck,
+    Pressed,
+    Passed,
+}
+
+// Needs to match key_lib::self_test::RadioCheck
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RadioCheck {
+    Unsupported,
+    Running,
+    Passed,
+    Failed,
+}
+
+/// Host side copy of key_lib::self_test::SelfTestReport
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SelfTestReport {
+    pub running: bool,
+    pub radio: RadioCheck,
+    pub keys: Vec<KeyResult>,
+}
+
+impl SelfTestReport {
+    /// Returns the keys with the result
+    pub fn keys_with(&self, result: KeyResult) -> Vec<usize> {
+        (0..self.keys.len())
+            .filter(|&key| self.keys[key] == result)
+            .collect()
+    }
+
+    /// Returns true once every key passed and the radio check is done
+    pub fn is_done(&self) -> bool {
+        self.keys.iter().all(|&key| key == KeyResult::Passed) && self.radio != RadioCheck::Running
+    }
+}
+
+/// Host side copy of key_lib::position::DriftCompensation
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DriftCompensation {
+    pub enabled: bool,
+    pub idle_secs: u16,
+    pub step: u8,
+    pub band_percent: u8,
+}
+
+/// Host side copy of key_lib::key_stats::KeyStats
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KeyStats {
+    pub enabled: bool,
+    pub total: u32,
+    pub presses: Vec<u32>,
+}
+
+/// Host side of key_lib::com::Com. Requests are sent as a stream of 32 byte
+/// output reports and responses are read back as a stream of input reports
+pub struct Com {
+    reader: DeviceReader,
+    writer: DeviceWriter,
+    buffer: [u8; REPORT_SIZE],
+    index: usize,
+    buffer_len: usize,
+}
+
+impl Com {
+    /// Opens the com interface of the first connected keyboard
+    pub async fn open() -> Result<Self, String> {
+        let backend = HidBackend::default();
+        let mut devices = backend.enumerate().await.map_err(|e| e.to_string())?;
+        while let Some(dev) = devices.next().await {
+            if dev.vendor_id == VENDOR_ID && dev.usage_page == USAGE_PAGE && dev.usage_id == USAGE {
+                log::debug!("Connected to {} {:x}", dev.name, dev.product_id);
+                let (reader, writer) = dev.open().await.map_err(|e| e.to_string())?;
+                let mut com = Self {
+                    reader,
+                    writer,
+                    buffer: [0; REPORT_SIZE],
+                    index: 0,
+                    buffer_len: 0,
+                };
+                com.check_protocol().await?;
+                return Ok(com);
+            }
+        }
+        Err("No keyboard found".into())
+    }
+
+    /// Makes sure the keyboard speaks the same framed protocol. The version
+    /// is read with a legacy request, which every firmware understands
+    async fn check_protocol(&mut self) -> Result<(), String> {
+        self.write_reports(&[HidRequest::KeyboardMetaInfo as u8])
+            .await?;
+        let mut buf = [0u8; META_VERSION_INDEX + 1];
+        for byte in &mut buf {
+            *byte = self.pop().await?;
+        }
+        self.index = 0;
+        match buf[META_VERSION_INDEX] {
+            COM_PROTOCOL_VERSION => Ok(()),
+            0 => Err("The keyboard's firmware is too old for keyctl, update it first".into()),
+            version => Err(format!(
+                "The keyboard speaks com protocol {version} but keyctl speaks {COM_PROTOCOL_VERSION}"
+            )),
+        }
+    }
+
+    /// Sends a request framed with its length and reads the status the
+    /// keyboard replies with before any response
+    pub async fn send(&mut self, request: HidRequest, payload: &[u8]) -> Result<(), String> {
+        let len = u16::try_from(payload.len()).map_err(|_| "Request is too long".to_string())?;
+        let mut data = Vec::with_capacity(payload.len() + 5);
+        data.extend([FRAME_MAGIC, COM_PROTOCOL_VERSION, request as u8]);
+        data.extend(len.to_le_bytes());
+        data.extend_from_slice(payload);
+        self.write_reports(&data).await?;
+        let status = [self.pop().await?, self.pop().await?, self.pop().await?];
+        // The status is sent on its own report
+        self.index = 0;
+        match status {
+            [FRAME_MAGIC, COM_PROTOCOL_VERSION, 0] => Ok(()),
+            [FRAME_MAGIC, version, 1] => Err(format!(
+                "The keyboard speaks com protocol {version} but keyctl speaks {COM_PROTOCOL_VERSION}"
+            )),
+            [FRAME_MAGIC, _, 2] => Err(format!("The keyboard doesn't support {request:?}")),
+            _ => Err(format!("Invalid status {status:?} for {request:?}")),
+        }
+    }
+
+    /// Writes the data in reports. Every request starts on a new report since
+    /// the keyboard drops the rest of a report once it's done with a request
+    async fn write_reports(&mut self, data: &[u8]) -> Result<(), String> {
+        for chunk in data.chunks(REPORT_SIZE) {
+            // First byte is the report id
+            let mut buf = [0u8; REPORT_SIZE + 1];
+            buf[1..chunk.len() + 1].copy_from_slice(chunk);
+            log::debug!("To keyboard | {:?}", buf);
+            self.writer
+                .write_output_report(&buf)
+                .await
+                .map_err(|e| e.to_string())?;
+        }
+        self.index = 0;
+        Ok(())
+    }
+
+    async fn pop(&mut self) -> Result<u8, String> {
+        self.pop_within(READ_TIMEOUT).await
+    }
+
+    async fn pop_within(&mut self, wait: Duration) -> Result<u8, String> {
+        if self.index == 0 {
+            self.buffer_len = timeout(wait, self.reader.read_input_report(&mut self.buffer))
+                .await
+                .map_err(|_| "Timed out waiting for the keyboard".to_string())?
+                .map_err(|e| e.to_string())?;
+            log::debug!("From keyboard | {:?}", self.buffer);
+        }
+        let val = self.buffer[self.index];
+        self.index += 1;
+        if self.index >= self.buffer_len {
+            self.index = 0;
+        }
+        Ok(val)
+    }
+
+    pub async fn meta(&mut self) -> Result<Meta, String> {
+        self.send(HidRequest::KeyboardMetaInfo, &[]).await?;
+        let mut buf = [0u8; 4];
+        for byte in &mut buf {
+            *byte = self.pop().await?;
+        }
+        Ok(Meta {
+            configs: buf[0] as usize,
+            keys: buf[1] as usize,
+            layers: buf[2] as usize,
+            split: buf[3] != 0,
+        })
+    }
+
+    /// Returns the active config and its checksum, which the keyboard sends
+    /// after the meta info
+    pub async fn active_config(&mut self) -> Result<(u8, u32), String> {
+        self.send(HidRequest::KeyboardMetaInfo, &[]).await?;
+        let mut buf = [0u8; 9];
+        for byte in &mut buf {
+            *byte = self.pop().await?;
+        }
+        Ok((buf[4], u32::from_le_bytes(buf[5..].try_into().unwrap())))
+    }
+
+    /// Returns the checksum of a config on the keyboard
+    pub async fn checksum(&mut self, config_num: u8) -> Result<u32, String> {
+        self.send(HidRequest::KeymapChecksum, &[config_num]).await?;
+        let mut buf = [0u8; 4];
+        for byte in &mut buf {
+            *byte = self.pop().await?;
+        }
+        Ok(u32::from_le_bytes(buf))
+    }
+
+    /// Reads the name and indicator color of a config
+    pub async fn config_info(&mut self, config_num: u8) -> Result<ConfigInfo, String> {
+        self.send(HidRequest::ReadSetting, &[CONFIG_INFO_SETTING, config_num])
+            .await?;
+        let status = self.pop().await?;
+        let len = self.pop().await? as usize;
+        if status != 0 || len < 4 {
+            return Err(format!("The keyboard has no config {config_num}"));
+        }
+        let mut buf = vec![0u8; len];
+        for byte in &mut buf {
+            *byte = self.pop().await?;
+        }
+        self.index = 0;
+        let name = buf
+            .get(4..4 + buf[3] as usize)
+            .ok_or("Invalid config info")?;
+        Ok(ConfigInfo {
+            name: String::from_utf8_lossy(name).into_owned(),
+            color: [buf[0], buf[1], buf[2]],
+        })
+    }
+
+    /// Sets the name and indicator color of a config
+    pub async fn set_config_info(
+        &mut self,
+        config_num: u8,
+        info: &ConfigInfo,
+    ) -> Result<(), String> {
+        if info.name.len() > MAX_CONFIG_NAME_LEN {
+            return Err(format!(
+                "Config names can be at most {MAX_CONFIG_NAME_LEN} bytes"
+            ));
+        }
+        let mut payload = vec![CONFIG_INFO_SETTING, config_num, 4 + info.name.len() as u8];
+        payload.extend_from_slice(&info.color);
+        payload.push(info.name.len() as u8);
+        payload.extend_from_slice(info.name.as_bytes());
+        self.send(HidRequest::WriteSetting, &payload).await
+    }
+
+    // Reads a global setting. Returns None if the keyboard doesn't have it
+    async fn global_setting(&mut self, setting: u8) -> Result<Option<Vec<u8>>, String> {
+        self.send(HidRequest::ReadSetting, &[setting, 0]).await?;
+        let status = self.pop().await?;
+        let len = self.pop().await? as usize;
+        let mut buf = vec![0u8; len];
+        for byte in &mut buf {
+            *byte = self.pop().await?;
+        }
+        self.index = 0;
+        Ok((status == 0).then_some(buf))
+    }
+
+    /// Reads the heartbeat interval and fastest scan rate of the halves of a
+    /// wireless board
+    pub async fn link_timing(&mut self) -> Result<LinkTiming, String> {
+        let buf = self
+            .global_setting(LINK_TIMING_SETTING)
+            .await?
+            .filter(|buf| buf.len() >= 3)
+            .ok_or("The keyboard has no link timing")?;
+        let scan_rate = match buf[2] {
+            0 => ScanRate::Full,
+            1 => ScanRate::Reduced,
+            2 => ScanRate::Minimal,
+            _ => return Err("Invalid link timing".into()),
+        };
+        Ok(LinkTiming {
+            heartbeat_ms: u16::from_le_bytes([buf[0], buf[1]]),
+            scan_rate,
+        })
+    }
+
+    /// Sets the link timing. The dongle sends it to each half with the ack of
+    /// the half's next packet
+    pub async fn set_link_timing(&mut self, timing: LinkTiming) -> Result<(), String> {
+        let mut payload = vec![LINK_TIMING_SETTING, 0, 3];
+        payload.extend_from_slice(&timing.heartbeat_ms.to_le_bytes());
+        payload.push(timing.scan_rate as u8);
+        self.send(HidRequest::WriteSetting, &payload).await
+    }
+
+    /// Reads the drift compensation of the keyboard's hall effect switches
+    pub async fn drift_compensation(&mut self) -> Result<DriftCompensation, String> {
+        let buf = self
+            .global_setting(DRIFT_COMPENSATION_SETTING)
+            .await?
+            .filter(|buf| buf.len() >= 5)
+            .ok_or("The keyboard has no drift compensation")?;
+        Ok(DriftCompensation {
+            enabled: buf[0] != 0,
+            idle_secs: u16::from_le_bytes([buf[1], buf[2]]),
+            step: buf[3],
+            band_percent: buf[4],
+        })
+    }
+
+    /// Sets the drift compensation. The keyboard rejects settings that are
+    /// out of range
+    pub async fn set_drift_compensation(
+        &mut self,
+        settings: DriftCompensation,
+    ) -> Result<(), String> {
+        let mut payload = vec![DRIFT_COMPENSATION_SETTING, 0, 5, settings.enabled as u8];
+        payload.extend_from_slice(&settings.idle_secs.to_le_bytes());
+        payload.extend_from_slice(&[settings.step, settings.band_percent]);
+        self.send(HidRequest::WriteSetting, &payload).await
+    }
+
+    /// Reads a diagnostic frame from the keyboard
+    pub async fn diagnostic(&mut self, kind: DiagnosticKind) -> Result<Diagnostic, String> {
+        self.send(HidRequest::Diagnostics, &[kind as u8]).await?;
+        self.read_diagnostic(READ_TIMEOUT).await
+    }
+
+    /// Compacts the keyboard's storage and returns its storage diagnostic
+    /// after the compaction
+    pub async fn compact_storage(&mut self) -> Result<Diagnostic, String> {
+        self.send(HidRequest::CompactStorage, &[]).await?;
+        self.read_diagnostic(COMPACT_TIMEOUT).await
+    }
+
+    async fn read_diagnostic(&mut self, wait: Duration) -> Result<Diagnostic, String> {
+        let mut header = [0u8; HEADER_LEN];
+        header[0] = self.pop_within(wait).await?;
+        for byte in &mut header[1..] {
+            *byte = self.pop().await?;
+        }
+        let mut payload = vec![0u8; header[2] as usize];
+        for byte in &mut payload {
+            *byte = self.pop().await?;
+        }
+        self.index = 0;
+        Diagnostic::decode(header, &payload)
+    }
+
+    /// Presses the keys on a board running simulated sensors and releases
+    /// the rest
+    pub async fn sim_keys(&mut self, meta: &Meta, keys: &[usize]) -> Result<(), String> {
+        let mut bits = vec![0u8; meta.keys.div_ceil(8)];
+        for &key in keys {
+            if key >= meta.keys {
+                return Err(format!("The keyboard only has {} keys", meta.keys));
+            }
+            bits[key / 8] |= 1 << (key % 8);
+        }
+        self.send(HidRequest::SimKeys, &bits).await
+    }
+
+    /// Runs an injection command on the keyboard. key is ignored when enabling
+    /// or disabling injection
+    pub async fn inject(&mut self, command: InjectCommand, key: u8) -> Result<(), String> {
+        self.send(HidRequest::InjectKey, &[command as u8, key])
+            .await?;
+        let status = self.pop().await?;
+        self.index = 0;
+        // Needs to match key_lib::inject::InjectStatus
+        match status {
+            0 => Ok(()),
+            1 => Err("Injection is disabled, run `inject enable` first".into()),
+            2 => Err("Events were sent too quickly".into()),
+            3 => Err(format!("Invalid key {key}")),
+            status => Err(format!("Unknown inject status {status}")),
+        }
+    }
+
+    /// Turns latency tracking on, which clears the histogram, or off
+    pub async fn latency_tracking(&mut self, enabled: bool) -> Result<(), String> {
+        self.send(HidRequest::LatencyTracking, &[enabled as u8])
+            .await
+    }
+
+    /// Turns key press counting on or off, or clears the counts
+    pub async fn key_stats_command(&mut self, command: KeyStatsCommand) -> Result<(), String> {
+        self.send(HidRequest::KeyStats, &[command as u8]).await
+    }
+
+    /// Reads the press counts of every key
+    pub async fn key_stats(&mut self, meta: &Meta) -> Result<KeyStats, String> {
+        self.send(HidRequest::KeyStats, &[KeyStatsCommand::Read as u8])
+            .await?;
+        let mut buf = vec![0u8; 5 + 4 * meta.keys];
+        for byte in &mut buf {
+            *byte = self.pop().await?;
+        }
+        self.index = 0;
+        Ok(KeyStats {
+            enabled: buf[0] != 0,
+            total: u32::from_le_bytes([buf[1], buf[2], buf[3], buf[4]]),
+            presses: buf[5..]
+                .chunks_exact(4)
+                .map(|chunk| u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]))
+                .collect(),
+        })
+    }
+
+    /// Starts a self test. Read its progress with [Com::self_test_report]
+    pub async fn start_self_test(&mut self) -> Result<(), String> {
+        self.send(HidRequest::SelfTest, &[SelfTestCommand::Start as u8])
+            .await
+    }
+
+    /// Reads the report of the self test, stopping it first if stop is set
+    pub async fn self_test_report(
+        &mut self,
+        meta: &Meta,
+        stop: bool,
+    ) -> Result<SelfTestReport, String> {
+        let command = if stop {
+            SelfTestCommand::Stop
+        } else {
+            SelfTestCommand::Read
+        };
+        self.send(HidRequest::SelfTest, &[command as u8]).await?;
+        let mut buf = vec![0u8; 2 + meta.keys];
+        for byte in &mut buf {
+            *byte = self.pop().await?;
+        }
+        self.index = 0;
+        let radio = match buf[1] {
+            0 => RadioCheck::Unsupported,
+            1 => RadioCheck::Running,
+            2 => RadioCheck::Passed,
+            3 => RadioCheck::Failed,
+            other => return Err(format!("Invalid radio check {other}")),
+        };
+        let keys = buf[2..]
+            .iter()
+            .map(|&result| match result {
+                0 => Ok(KeyResult::Untested),
+                1 => Ok(KeyResult::Stuck),
+                2 => Ok(KeyResult::Pressed),
+                3 => Ok(KeyResult::Passed),
+                other => Err(format!("Invalid key result {other}")),
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        Ok(SelfTestReport {
+            running: buf[0] != 0,
+            radio,
+            keys,
+        })
+    }
+
+    /// Switches a wireless keyboard to the dongle of another host
+    pub async fn switch_host(&mut self, host: u8) -> Result<(), String> {
+        self.send(HidRequest::SwitchHost, &[host]).await
+    }
+
+    /// Reboots the keyboard into its bootloader. The keyboard disconnects
+    /// without a response
+    pub async fn bootloader(&mut self) -> Result<(), String> {
+        self.send(HidRequest::Bootloader, &[]).await
+    }
+
+    /// Erases every config and setting on the keyboard and reboots it with
+    /// its built-in keymap. The keyboard disconnects without a response
+    pub async fn factory_reset(&mut self) -> Result<(), String> {
+        self.send(HidRequest::FactoryReset, &FACTORY_RESET_CONFIRM)
+            .await
+    }
+
+    /// Starts streaming the link state of the halves every interval * 100ms
+    pub async fn watch_link(&mut self, interval: u8) -> Result<(), String> {
+        self.send(HidRequest::WatchLink, &[interval]).await
+    }
+
+    /// Reads the next frame of a link watch. Returns None if the keyboard has
+    /// no wireless link
+    pub async fn link_frame(&mut self) -> Result<Option<[HalfLink; 2]>, String> {
+        let mut frame = [0u8; LINK_WATCH_FRAME_LEN];
+        for byte in &mut frame {
+            *byte = self.pop().await?;
+        }
+        self.index = 0;
+        Ok(watch::decode(&frame))
+    }
+
+    /// Reads a backup blob of every config and setting on the keyboard
+    pub async fn export_config(&mut self) -> Result<Vec<u8>, String> {
+        self.send(HidRequest::ExportConfig, &[]).await?;
+        let mut header = [0u8; BACKUP_HEADER_LEN];
+        for byte in &mut header {
+            *byte = self.pop().await?;
+        }
+        let mut blob = header.to_vec();
+        for _ in 0..backup::body_len(&header) {
+            blob.push(self.pop().await?);
+        }
+        self.index = 0;
+        Ok(blob)
+    }
+
+    /// Restores a backup blob. The keyboard checks the whole blob before
+    /// writing anything
+    pub async fn import_config(&mut self, blob: &[u8]) -> Result<(), String> {
+        self.send(HidRequest::ImportConfig, blob).await?;
+        let status = self.pop_within(IMPORT_TIMEOUT).await?;
+        self.index = 0;
+        backup::import_result(status)
+    }
+
+    /// Starts a reaction test. Read its events with [Com::reaction_event]
+    pub async fn reaction_test(&mut self) -> Result<(), String> {
+        self.send(HidRequest::ReactionTest, &[]).await
+    }
+
+    /// Reads the next event of a reaction test
+    pub async fn reaction_event(&mut self) -> Result<ReactionEvent, String> {
+        let mut buf = [0u8; 5];
+        buf[0] = self.pop_within(REACTION_TIMEOUT).await?;
+        for byte in &mut buf[1..] {
+            *byte = self.pop().await?;
+        }
+        self.index = 0;
+        match buf[0] {
+            0 => Ok(ReactionEvent::Cue),
+            1 => Ok(ReactionEvent::Reaction {
+                us: u32::from_le_bytes([buf[1], buf[2], buf[3], buf[4]]),
+            }),
+            2 => Ok(ReactionEvent::FalseStart),
+            3 => Ok(ReactionEvent::Timeout),
+            status => Err(format!("Unknown reaction status {status}")),
+        }
+    }
+
+    /// Reads every config from the keyboard
+    pub async fn read_configs(&mut self, meta: &Meta) -> Result<Vec<Config>, String> {
+        self.send(HidRequest::KeyboardInfo, &[]).await?;
+        let mut configs = Vec::with_capacity(meta.configs);
+        for config_num in 0..meta.configs {
+            let mut layers = vec![Vec::with_capacity(meta.keys); meta.layers];
+            // The keyboard sends every layer of a key before moving onto the next key
+            for _ in 0..meta.keys {
+                for layer in &mut layers {
+                    let mut buf = [0u8; 5];
+                    buf[0] = self.pop().await?;
+                    let len = Behavior::serial_len(buf[0]).ok_or(format!(
+                        "Unknown scan code type {} in config {}",
+                        buf[0], config_num
+                    ))?;
+                    for byte in &mut buf[1..len] {
+                        *byte = self.pop().await?;
+                    }
+                    layer.push(Behavior::from_bytes(&buf[..len]).unwrap());
+                }
+            }
+            configs.push(Config { layers });
+        }
+        self.index = 0;
+        Ok(configs)
+    }
+
+    /// Loads a config into the keyboard without saving it to flash
+    pub async fn load_config(
+        &mut self,
+        meta: &Meta,
+        config_num: u8,
+        config: &Config,
+    ) -> Result<(), String> {
+        let mut payload = vec![config_num];
+        config.write_bytes(meta, &mut payload)?;
+        self.send(HidRequest::UpdateKeys, &payload).await
+    }
+
+    /// Reads every config of the keymap a half keeps for running on its own
+    /// through the dongle, one layer at a time
+    pub async fn read_half_configs(
+        &mut self,
+        half: Half,
+        meta: &Meta,
+    ) -> Result<Vec<Config>, String> {
+        let mut configs = Vec::with_capacity(meta.configs);
+        for config_num in 0..meta.configs as u8 {
+            let mut layers = Vec::with_capacity(meta.layers);
+            for layer in 0..meta.layers as u8 {
+                self.send(HidRequest::HalfLayer, &[half as u8, config_num, layer])
+                    .await?;
+                let status = self.pop_within(HALF_TIMEOUT).await?;
+                let len = u16::from_le_bytes([self.pop().await?, self.pop().await?]) as usize;
+                if let Err(e) = forward_status(status, half) {
+                    self.index = 0;
+                    return Err(e);
+                }
+                let mut buf = vec![0u8; len];
+                for byte in &mut buf {
+                    *byte = self.pop().await?;
+                }
+                self.index = 0;
+                let mut codes = Vec::with_capacity(meta.keys);
+                let mut rest = buf.as_slice();
+                while let Some(&code_type) = rest.first() {
+                    let behavior = Behavior::from_bytes(rest).ok_or(format!(
+                        "Unknown scan code type {code_type} in config {config_num}"
+                    ))?;
+                    codes.push(behavior);
+                    rest = &rest[Behavior::serial_len(code_type).unwrap()..];
+                }
+                layers.push(codes);
+            }
+            configs.push(Config { layers });
+        }
+        Ok(configs)
+    }
+
+    /// Writes every config to the flash of a half through the dongle, one
+    /// layer at a time
+    pub async fn flash_half_configs(
+        &mut self,
+        half: Half,
+        meta: &Meta,
+        configs: &[Config],
+    ) -> Result<(), String> {
+        if configs.len() != meta.configs {
+            return Err(format!(
+                "Expected {} configs, found {}",
+                meta.configs,
+                configs.len()
+            ));
+        }
+        for (config_num, config) in configs.iter().enumerate() {
+            for layer in 0..meta.layers {
+                let mut data = Vec::new();
+                config.write_layer_bytes(meta, layer, &mut data)?;
+                let mut payload = vec![half as u8, config_num as u8, layer as u8];
+                payload.extend((data.len() as u16).to_le_bytes());
+                payload.extend(data);
+                self.send(HidRequest::UpdateHalfLayer, &payload).await?;
+                let status = self.pop_within(HALF_TIMEOUT).await?;
+                self.index = 0;
+                forward_status(status, half)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes every config to the keyboard's flash
+    pub async fn flash_configs(&mut self, meta: &Meta, configs: &[Config]) -> Result<(), String> {
+        if configs.len() != meta.configs {
+            return Err(format!(
+                "Expected {} configs, found {}",
+                meta.configs,
+                configs.len()
+            ));
+        }
+        let mut payload = Vec::new();
+        for config in configs {
+            config.write_bytes(meta, &mut payload)?;
+        }
+        self.send(HidRequest::WriteToFlash, &payload).await
+    }
+}
@@ -0,0 +1,331 @@
+use std::fmt;
+
+// Needs to match key_lib::diagnostics
+pub const DIAGNOSTICS_VERSION: u8 = 2;
+pub const HEADER_LEN: usize = 3;
+const LINK_STATS_LEN: usize = 17;
+// Needs to match key_lib::latency
+const LATENCY_BUCKETS: usize = 10;
+const BUCKET_BASE_US: u32 = 250;
+// Needs to match key_lib::crash
+const MAX_CRASH_MESSAGE_LEN: usize = 64;
+
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DiagnosticKind {
+    Link = 1,
+    Health = 2,
+    Boot = 3,
+    Storage = 4,
+    Latency = 5,
+    Crash = 6,
+}
+
+impl DiagnosticKind {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "link" => Some(Self::Link),
+            "health" => Some(Self::Health),
+            "boot" => Some(Self::Boot),
+            "storage" => Some(Self::Storage),
+            "latency" => Some(Self::Latency),
+            "crash" => Some(Self::Crash),
+            _ => None,
+        }
+    }
+
+    fn from_u8(kind: u8) -> Option<Self> {
+        match kind {
+            1 => Some(Self::Link),
+            2 => Some(Self::Health),
+            3 => Some(Self::Boot),
+            4 => Some(Self::Storage),
+            5 => Some(Self::Latency),
+            6 => Some(Self::Crash),
+            _ => None,
+        }
+    }
+
+    /// Length of the kind's payload
+    fn payload_len(&self) -> usize {
+        match self {
+            Self::Link => 1 + 2 * LINK_STATS_LEN,
+            Self::Health => 17,
+            Self::Boot => 4,
+            Self::Storage => 13,
+            Self::Latency => 13 + 2 * LATENCY_BUCKETS,
+            Self::Crash => 10 + MAX_CRASH_MESSAGE_LEN,
+        }
+    }
+}
+
+/// Host side copy of key_lib::diagnostics::LinkStats
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LinkStats {
+    pub packets_sent: u32,
+    pub packets_received: u32,
+    pub acks_missed: u32,
+    pub crc_errors: u32,
+    pub rssi: i8,
+}
+
+impl LinkStats {
+    fn from_bytes(buf: &[u8]) -> Self {
+        Self {
+            packets_sent: le_u32(&buf[0..4]),
+            packets_received: le_u32(&buf[4..8]),
+            acks_missed: le_u32(&buf[8..12]),
+            crc_errors: le_u32(&buf[12..16]),
+            rssi: buf[16] as i8,
+        }
+    }
+}
+
+/// A decoded diagnostic frame
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Diagnostic {
+    Link {
+        wireless: bool,
+        halves: [LinkStats; 2],
+    },
+    Health {
+        uptime_ms: u32,
+        last_error: u8,
+        /// Failed transfers on the com, report and link endpoints
+        endpoint_errors: [u32; 3],
+    },
+    Boot {
+        version: [u8; 3],
+        reset_reason: u8,
+    },
+    Storage {
+        used_bytes: u32,
+        capacity_bytes: u32,
+        erase_count: u32,
+        last_error: u8,
+    },
+    Latency {
+        enabled: bool,
+        samples: u32,
+        mean_us: u32,
+        max_us: u32,
+        buckets: [u16; LATENCY_BUCKETS],
+    },
+    Crash {
+        kind: u8,
+        pc: u32,
+        uptime_ms: u32,
+        message: String,
+    },
+    /// The keyboard can't produce the kind
+    Unsupported(u8),
+}
+
+impl Diagnostic {
+    /// Decodes the payload of a frame with the given header
+    pub fn decode(header: [u8; HEADER_LEN], payload: &[u8]) -> Result<Self, String> {
+        let [version, kind, len] = header;
+        if version != DIAGNOSTICS_VERSION {
+            return Err(format!("Unsupported diagnostics version {version}"));
+        }
+        if len == 0 {
+            return Ok(Diagnostic::Unsupported(kind));
+        }
+        let kind =
+            DiagnosticKind::from_u8(kind).ok_or(format!("Unknown diagnostic kind {kind}"))?;
+        if payload.len() != kind.payload_len() {
+            return Err(format!(
+                "Diagnostic {kind:?} has len {} instead of {}",
+                payload.len(),
+                kind.payload_len()
+            ));
+        }
+        let diagnostic = match kind {
+            DiagnosticKind::Link => Diagnostic::Link {
+                wireless: payload[0] != 0,
+                halves: [
+                    LinkStats::from_bytes(&payload[1..1 + LINK_STATS_LEN]),
+                    LinkStats::from_bytes(&payload[1 + LINK_STATS_LEN..]),
+                ],
+            },
+            DiagnosticKind::Health => Diagnostic::Health {
+                uptime_ms: le_u32(&payload[0..4]),
+                last_error: payload[4],
+                endpoint_errors: [
+                    le_u32(&payload[5..9]),
+                    le_u32(&payload[9..13]),
+                    le_u32(&payload[13..17]),
+                ],
+            },
+            DiagnosticKind::Boot => Diagnostic::Boot {
+                version: [payload[0], payload[1], payload[2]],
+                reset_reason: payload[3],
+            },
+            DiagnosticKind::Storage => Diagnostic::Storage {
+                used_bytes: le_u32(&payload[0..4]),
+                capacity_bytes: le_u32(&payload[4..8]),
+                erase_count: le_u32(&payload[8..12]),
+                last_error: payload[12],
+            },
+            DiagnosticKind::Latency => {
+                let mut buckets = [0u16; LATENCY_BUCKETS];
+                for (bucket, bytes) in buckets.iter_mut().zip(payload[13..].chunks_exact(2)) {
+                    *bucket = u16::from_le_bytes([bytes[0], bytes[1]]);
+                }
+                Diagnostic::Latency {
+                    enabled: payload[0] != 0,
+                    samples: le_u32(&payload[1..5]),
+                    mean_us: le_u32(&payload[5..9]),
+                    max_us: le_u32(&payload[9..13]),
+                    buckets,
+                }
+            }
+            DiagnosticKind::Crash => {
+                let len = (payload[9] as usize).min(MAX_CRASH_MESSAGE_LEN);
+                Diagnostic::Crash {
+                    kind: payload[0],
+                    pc: le_u32(&payload[1..5]),
+                    uptime_ms: le_u32(&payload[5..9]),
+                    message: String::from_utf8_lossy(&payload[10..10 + len]).into_owned(),
+                }
+            }
+        };
+        Ok(diagnostic)
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Diagnostic::Link { wireless: false, .. } => write!(f, "No wireless link"),
+            Diagnostic::Link { halves, .. } => {
+                for (name, stats) in ["Left", "Right"].iter().zip(halves) {
+                    writeln!(
+                        f,
+                        "{name}: sent {} | received {} | acks missed {} | crc errors {} | rssi {}dBm",
+                        stats.packets_sent,
+                        stats.packets_received,
+                        stats.acks_missed,
+                        stats.crc_errors,
+                        stats.rssi
+                    )?;
+                }
+                Ok(())
+            }
+            Diagnostic::Health {
+                uptime_ms,
+                last_error,
+                endpoint_errors: [com, report, link],
+            } => write!(
+                f,
+                "Uptime: {}s | Last error: {} | Endpoint errors: com {com} | report {report} | link {link}",
+                uptime_ms / 1000,
+                error_name(*last_error)
+            ),
+            Diagnostic::Boot {
+                version,
+                reset_reason,
+            } => write!(
+                f,
+                "Version: {}.{}.{} | Reset reason: {}",
+                version[0],
+                version[1],
+                version[2],
+                reset_reason_name(*reset_reason)
+            ),
+            Diagnostic::Storage {
+                used_bytes,
+                capacity_bytes,
+                erase_count,
+                last_error,
+            } => write!(
+                f,
+                "Used: {used_bytes}/{capacity_bytes} bytes | Erases: {erase_count} | Last error: {}",
+                error_name(*last_error)
+            ),
+            Diagnostic::Latency {
+                enabled,
+                samples,
+                mean_us,
+                max_us,
+                buckets,
+            } => {
+                let state = if *enabled { "on" } else { "off" };
+                writeln!(
+                    f,
+                    "Tracking: {state} | Samples: {samples} | Mean: {mean_us}us | Max: {max_us}us"
+                )?;
+                let mut bound = BUCKET_BASE_US;
+                for (i, count) in buckets.iter().enumerate() {
+                    if i == LATENCY_BUCKETS - 1 {
+                        writeln!(f, ">= {bound}us: {count}")?;
+                    } else {
+                        writeln!(f, "<  {bound}us: {count}")?;
+                    }
+                    if i < LATENCY_BUCKETS - 2 {
+                        bound *= 2;
+                    }
+                }
+                Ok(())
+            }
+            Diagnostic::Crash { kind: 0, .. } => write!(f, "No crash before the last boot"),
+            Diagnostic::Crash {
+                kind,
+                pc,
+                uptime_ms,
+                message,
+            } => {
+                write!(f, "{} after {}s", crash_name(*kind), uptime_ms / 1000)?;
+                if *pc != 0 {
+                    write!(f, " at pc {pc:#010x}")?;
+                }
+                if !message.is_empty() {
+                    write!(f, ": {message}")?;
+                }
+                Ok(())
+            }
+            Diagnostic::Unsupported(kind) => {
+                write!(f, "The keyboard doesn't support diagnostic {kind}")
+            }
+        }
+    }
+}
+
+fn le_u32(buf: &[u8]) -> u32 {
+    u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]])
+}
+
+// Needs to match key_lib::error::KeyLibError
+fn error_name(err: u8) -> &'static str {
+    match err {
+        0 => "none",
+        1 => "storage",
+        2 => "serialization",
+        3 => "com",
+        4 => "sensor",
+        5 => "display",
+        6 => "invalid config",
+        _ => "unknown",
+    }
+}
+
+// Needs to match key_lib::diagnostics::ResetReason
+fn reset_reason_name(reason: u8) -> &'static str {
+    match reason {
+        1 => "power on",
+        2 => "reset pin",
+        3 => "watchdog",
+        4 => "software",
+        5 => "lockup",
+        _ => "unknown",
+    }
+}
+
+// Needs to match key_lib::crash::CrashKind
+fn crash_name(kind: u8) -> &'static str {
+    match kind {
+        1 => "Panic",
+        2 => "Hard fault",
+        _ => "Unknown crash",
+    }
+}
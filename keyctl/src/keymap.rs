@@ -0,0 +1,389 @@
+use serde::{Deserialize, Serialize};
+
+// Serialized scan code types. Needs to match HidScanCodeType in key_lib::codes
+const SINGLE: u8 = 0;
+const DOUBLE: u8 = 1;
+const TRIPLE: u8 = 2;
+const COMBINED_KEY: u8 = 3;
+const CHANGE_CONFIG: u8 = 4;
+const TOGGLE_MOUSE_INVERT: u8 = 5;
+const ANALOG_LAYER: u8 = 6;
+const DUAL_STAGE: u8 = 7;
+const TAP_DANCE: u8 = 8;
+const MOD_SWAP: u8 = 9;
+const OS_SWAP: u8 = 10;
+const VELOCITY: u8 = 11;
+const SWITCH_HOST: u8 = 12;
+const BOOTLOADER: u8 = 13;
+const LOCK_KEYBOARD: u8 = 14;
+const RECORD_MACRO: u8 = 15;
+const PLAY_MACRO: u8 = 16;
+const TRIPLE_TAP_DANCE: u8 = 17;
+
+/// Host side copy of key_lib::codes::ScanCodeBehavior. Codes are kept as the
+/// raw KeyCodes values sent over com
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Behavior {
+    Single {
+        code: u8,
+    },
+    Double {
+        codes: [u8; 2],
+    },
+    Triple {
+        codes: [u8; 3],
+    },
+    CombinedKey {
+        other_index: u8,
+        normal_code: u8,
+        combined_code: u8,
+    },
+    ChangeConfig {
+        config: u8,
+    },
+    ToggleMouseInvert {
+        axis: u8,
+    },
+    AnalogLayer {
+        partial_layer: u8,
+        full_layer: u8,
+        partial_travel: u8,
+        full_travel: u8,
+    },
+    DualStage {
+        first_code: u8,
+        second_code: u8,
+        first_travel: u8,
+        second_travel: u8,
+    },
+    TapDance {
+        tap: u8,
+        double_tap: u8,
+        hold: u8,
+        term: u8,
+    },
+    ModSwap {
+        normal_code: u8,
+        swapped_code: u8,
+        mods: u8,
+    },
+    OsSwap {
+        normal_code: u8,
+        mac_code: u8,
+    },
+    Velocity {
+        slow_code: u8,
+        fast_code: u8,
+        threshold: u8,
+    },
+    SwitchHost {
+        host: u8,
+    },
+    Bootloader,
+    LockKeyboard {
+        other_index: u8,
+    },
+    RecordMacro {
+        save: bool,
+    },
+    PlayMacro,
+    TripleTapDance {
+        tap: u8,
+        double_tap: u8,
+        triple_tap: u8,
+        hold: u8,
+        term: u8,
+    },
+}
+
+impl Behavior {
+    /// Returns the serialized length of the scan code type in the first byte
+    /// of a serialized behavior
+    pub fn serial_len(code_type: u8) -> Option<usize> {
+        match code_type {
+            BOOTLOADER | PLAY_MACRO => Some(1),
+            SINGLE | CHANGE_CONFIG | TOGGLE_MOUSE_INVERT | SWITCH_HOST | LOCK_KEYBOARD
+            | RECORD_MACRO => Some(2),
+            DOUBLE | OS_SWAP => Some(3),
+            TRIPLE | COMBINED_KEY | MOD_SWAP | VELOCITY => Some(4),
+            ANALOG_LAYER | DUAL_STAGE | TAP_DANCE => Some(5),
+            TRIPLE_TAP_DANCE => Some(6),
+            _ => None,
+        }
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Option<Self> {
+        if buf.len() < Self::serial_len(*buf.first()?)? {
+            return None;
+        }
+        let behavior = match buf[0] {
+            SINGLE => Behavior::Single { code: buf[1] },
+            DOUBLE => Behavior::Double {
+                codes: [buf[1], buf[2]],
+            },
+            TRIPLE => Behavior::Triple {
+                codes: [buf[1], buf[2], buf[3]],
+            },
+            COMBINED_KEY => Behavior::CombinedKey {
+                normal_code: buf[1],
+                combined_code: buf[2],
+                other_index: buf[3],
+            },
+            CHANGE_CONFIG => Behavior::ChangeConfig { config: buf[1] },
+            TOGGLE_MOUSE_INVERT => Behavior::ToggleMouseInvert { axis: buf[1] },
+            ANALOG_LAYER => Behavior::AnalogLayer {
+                partial_layer: buf[1],
+                full_layer: buf[2],
+                partial_travel: buf[3],
+                full_travel: buf[4],
+            },
+            DUAL_STAGE => Behavior::DualStage {
+                first_code: buf[1],
+                second_code: buf[2],
+                first_travel: buf[3],
+                second_travel: buf[4],
+            },
+            TAP_DANCE => Behavior::TapDance {
+                tap: buf[1],
+                double_tap: buf[2],
+                hold: buf[3],
+                term: buf[4],
+            },
+            MOD_SWAP => Behavior::ModSwap {
+                normal_code: buf[1],
+                swapped_code: buf[2],
+                mods: buf[3],
+            },
+            OS_SWAP => Behavior::OsSwap {
+                normal_code: buf[1],
+                mac_code: buf[2],
+            },
+            VELOCITY => Behavior::Velocity {
+                slow_code: buf[1],
+                fast_code: buf[2],
+                threshold: buf[3],
+            },
+            SWITCH_HOST => Behavior::SwitchHost { host: buf[1] },
+            BOOTLOADER => Behavior::Bootloader,
+            LOCK_KEYBOARD => Behavior::LockKeyboard {
+                other_index: buf[1],
+            },
+            RECORD_MACRO => Behavior::RecordMacro {
+                save: match buf[1] {
+                    0 => false,
+                    1 => true,
+                    _ => return None,
+                },
+            },
+            PLAY_MACRO => Behavior::PlayMacro,
+            TRIPLE_TAP_DANCE => Behavior::TripleTapDance {
+                tap: buf[1],
+                double_tap: buf[2],
+                triple_tap: buf[3],
+                hold: buf[4],
+                term: buf[5],
+            },
+            _ => return None,
+        };
+        Some(behavior)
+    }
+
+    pub fn write_bytes(&self, out: &mut Vec<u8>) {
+        match *self {
+            Behavior::Single { code } => out.extend([SINGLE, code]),
+            Behavior::Double { codes } => out.extend([DOUBLE, codes[0], codes[1]]),
+            Behavior::Triple { codes } => out.extend([TRIPLE, codes[0], codes[1], codes[2]]),
+            Behavior::CombinedKey {
+                other_index,
+                normal_code,
+                combined_code,
+            } => out.extend([COMBINED_KEY, normal_code, combined_code, other_index]),
+            Behavior::ChangeConfig { config } => out.extend([CHANGE_CONFIG, config]),
+            Behavior::ToggleMouseInvert { axis } => out.extend([TOGGLE_MOUSE_INVERT, axis]),
+            Behavior::AnalogLayer {
+                partial_layer,
+                full_layer,
+                partial_travel,
+                full_travel,
+            } => out.extend([
+                ANALOG_LAYER,
+                partial_layer,
+                full_layer,
+                partial_travel,
+                full_travel,
+            ]),
+            Behavior::DualStage {
+                first_code,
+                second_code,
+                first_travel,
+                second_travel,
+            } => out.extend([
+                DUAL_STAGE,
+                first_code,
+                second_code,
+                first_travel,
+                second_travel,
+            ]),
+            Behavior::TapDance {
+                tap,
+                double_tap,
+                hold,
+                term,
+            } => out.extend([TAP_DANCE, tap, double_tap, hold, term]),
+            Behavior::ModSwap {
+                normal_code,
+                swapped_code,
+                mods,
+            } => out.extend([MOD_SWAP, normal_code, swapped_code, mods]),
+            Behavior::OsSwap {
+                normal_code,
+                mac_code,
+            } => out.extend([OS_SWAP, normal_code, mac_code]),
+            Behavior::Velocity {
+                slow_code,
+                fast_code,
+                threshold,
+            } => out.extend([VELOCITY, slow_code, fast_code, threshold]),
+            Behavior::SwitchHost { host } => out.extend([SWITCH_HOST, host]),
+            Behavior::Bootloader => out.push(BOOTLOADER),
+            Behavior::LockKeyboard { other_index } => out.extend([LOCK_KEYBOARD, other_index]),
+            Behavior::RecordMacro { save } => out.extend([RECORD_MACRO, save as u8]),
+            Behavior::PlayMacro => out.push(PLAY_MACRO),
+            Behavior::TripleTapDance {
+                tap,
+                double_tap,
+                triple_tap,
+                hold,
+                term,
+            } => out.extend([TRIPLE_TAP_DANCE, tap, double_tap, triple_tap, hold, term]),
+        }
+    }
+}
+
+/// Sizes reported by the keyboard with HidRequest::KeyboardMetaInfo
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Meta {
+    pub configs: usize,
+    pub keys: usize,
+    pub layers: usize,
+    pub split: bool,
+}
+
+/// A single config where codes are indexed as layers[layer][key]
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Config {
+    pub layers: Vec<Vec<Behavior>>,
+}
+
+impl Config {
+    /// Serializes the config in the order the firmware reads it, which is
+    /// every layer of a key before moving onto the next key
+    pub fn write_bytes(&self, meta: &Meta, out: &mut Vec<u8>) -> Result<(), String> {
+        self.check(meta)?;
+        for key in 0..meta.keys {
+            for layer in &self.layers {
+                layer[key].write_bytes(out);
+            }
+        }
+        Ok(())
+    }
+
+    /// Serializes a layer the way the firmware stores it, which is every key
+    /// of the layer in order
+    pub fn write_layer_bytes(
+        &self,
+        meta: &Meta,
+        layer: usize,
+        out: &mut Vec<u8>,
+    ) -> Result<(), String> {
+        self.check(meta)?;
+        for behavior in &self.layers[layer] {
+            behavior.write_bytes(out);
+        }
+        Ok(())
+    }
+
+    // Makes sure the config has the layer and key counts of the keyboard
+    fn check(&self, meta: &Meta) -> Result<(), String> {
+        if self.layers.len() != meta.layers {
+            return Err(format!(
+                "Expected {} layers, found {}",
+                meta.layers,
+                self.layers.len()
+            ));
+        }
+        if let Some(layer) = self.layers.iter().find(|layer| layer.len() != meta.keys) {
+            return Err(format!(
+                "Expected {} keys per layer, found {}",
+                meta.keys,
+                layer.len()
+            ));
+        }
+        Ok(())
+    }
+
+    /// Crc of the config's bytes. Matches the checksum the keyboard reports
+    /// for a config with the same codes
+    pub fn checksum(&self, meta: &Meta) -> Result<u32, String> {
+        let mut bytes = Vec::new();
+        self.write_bytes(meta, &mut bytes)?;
+        Ok(crc32(&bytes))
+    }
+}
+
+// Needs to match key_lib::dfu::Crc32
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Keymap {
+    pub meta: Meta,
+    pub configs: Vec<Config>,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Format {
+    Toml,
+    Json,
+}
+
+impl Format {
+    pub fn from_path(path: &str) -> Result<Self, String> {
+        if path.ends_with(".toml") {
+            Ok(Format::Toml)
+        } else if path.ends_with(".json") {
+            Ok(Format::Json)
+        } else {
+            Err(format!("{path} needs to be a .toml or .json file"))
+        }
+    }
+}
+
+impl Keymap {
+    pub fn load(path: &str) -> Result<Self, String> {
+        let format = Format::from_path(path)?;
+        let contents = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+        match format {
+            Format::Toml => toml::from_str(&contents).map_err(|e| e.to_string()),
+            Format::Json => serde_json::from_str(&contents).map_err(|e| e.to_string()),
+        }
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), String> {
+        let contents = match Format::from_path(path)? {
+            Format::Toml => toml::to_string(self).map_err(|e| e.to_string())?,
+            Format::Json => serde_json::to_string_pretty(self).map_err(|e| e.to_string())?,
+        };
+        std::fs::write(path, contents).map_err(|e| e.to_string())
+    }
+}
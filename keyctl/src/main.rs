@@ -0,0 +1,442 @@
+mod backup;
+mod device;
+mod diagnostics;
+mod keymap;
+mod watch;
+
+use device::{
+    Com, ConfigInfo, DriftCompensation, Half, InjectCommand, KeyResult, KeyStatsCommand,
+    LinkTiming, RadioCheck, ReactionEvent, ScanRate,
+};
+use diagnostics::DiagnosticKind;
+use keymap::Keymap;
+
+const USAGE: &str = "Usage: keyctl <command>
+
+Commands:
+    info                   Print the keyboard's number of configs, keys and layers
+    dump <file>            Save every config on the keyboard to a .toml or .json file
+    load <file> <config>   Load a config from the file without saving it to flash
+    flash <file>           Write every config in the file to flash
+    verify <file>          Check that every config on the keyboard matches the file
+    half-dump <half> <file>
+                           Save every config of the keymap a wireless half runs on its
+                           own to a .toml or .json file, where half is left or right
+    half-flash <half> <file>
+                           Write every config in the file to the flash of a wireless
+                           half through the dongle
+    label <config> <name> [color]
+                           Name a config and set its indicator color as rrggbb
+    backup <file>          Save every config and setting on the keyboard to a binary file
+    restore <file>         Write a file saved with backup to the keyboard
+    compact                Rewrite the keyboard's storage without stale copies of
+                           settings
+    diag <kind>            Print a diagnostic, where kind is link, health, boot, storage,
+                           latency or crash
+    latency <on|off>       Start timing key presses from a clear histogram or stop
+    stats [on|off|clear]   Print how often each key was pressed, or start counting,
+                           stop counting or clear the counts
+    host <host>            Switch a wireless keyboard to the dongle of another host
+    timing [<ms> <rate>]   Print or set the heartbeat interval of an idle wireless half
+                           and its fastest scan rate, where rate is full, reduced or
+                           minimal. Slower rates and heartbeats save battery
+    watch                  Print a line whenever a half connects, disconnects, runs
+                           low on battery or runs firmware that doesn't match the
+                           dongle
+    react                  Time a key press after the leds light up
+    drift [off | <seconds> <step> <band>]
+                           Print or set the re-centering of hall effect keys left
+                           untouched for seconds, by up to step adc counts a second
+                           while within band percent of travel of their rest point
+    selftest [seconds]     Check a newly assembled board. Press and release every key
+                           while the leds cycle through their colors, then print the
+                           keys that never worked and the result of the radio check
+    bootloader             Reboot the keyboard into its bootloader to flash it
+    factory-reset --yes    Erase every config, setting and calibration on the keyboard
+                           and reboot it with its built-in keymap
+    sim [keys...]          Hold the keys on a board built with simulated sensors and
+                           release the rest
+    inject <command> [key] Inject key events, where command is enable, disable, press
+                           or release";
+
+// Time between link watch frames in 100ms
+const WATCH_INTERVAL: u8 = 10;
+
+// Time between reads of the self test's progress
+const SELF_TEST_POLL: std::time::Duration = std::time::Duration::from_millis(500);
+// Time the self test runs for unless every key passed before
+const SELF_TEST_SECONDS: u64 = 120;
+
+#[tokio::main]
+async fn main() {
+    env_logger::init();
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if let Err(e) = run(&args).await {
+        eprintln!("{e}");
+        std::process::exit(1);
+    }
+}
+
+async fn run(args: &[String]) -> Result<(), String> {
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    match args.as_slice() {
+        ["info"] => {
+            let mut com = Com::open().await?;
+            let meta = com.meta().await?;
+            let (active, checksum) = com.active_config().await?;
+            println!(
+                "Configs: {} | Keys: {} | Layers: {} | Split: {}",
+                meta.configs, meta.keys, meta.layers, meta.split
+            );
+            println!("Active config: {active} | Checksum: {checksum:08x}");
+            for config_num in 0..meta.configs as u8 {
+                let info = com.config_info(config_num).await?;
+                let [r, g, b] = info.color;
+                println!(
+                    "Config {config_num}: {:?} | Color: {r:02x}{g:02x}{b:02x}",
+                    info.name
+                );
+            }
+        }
+        ["dump", path] => {
+            let mut com = Com::open().await?;
+            let meta = com.meta().await?;
+            let configs = com.read_configs(&meta).await?;
+            Keymap { meta, configs }.save(path)?;
+            println!("Saved {} configs to {}", meta.configs, path);
+        }
+        ["load", path, config_num] => {
+            let config_num: u8 = config_num
+                .parse()
+                .map_err(|_| format!("Invalid config {config_num}"))?;
+            let keymap = Keymap::load(path)?;
+            let mut com = Com::open().await?;
+            let meta = check_meta(&mut com, &keymap).await?;
+            let config = keymap
+                .configs
+                .get(config_num as usize)
+                .ok_or(format!("{path} doesn't have config {config_num}"))?;
+            com.load_config(&meta, config_num, config).await?;
+            println!("Loaded config {config_num}");
+        }
+        ["flash", path] => {
+            let keymap = Keymap::load(path)?;
+            let mut com = Com::open().await?;
+            let meta = check_meta(&mut com, &keymap).await?;
+            com.flash_configs(&meta, &keymap.configs).await?;
+            println!("Wrote {} configs to flash", meta.configs);
+        }
+        ["verify", path] => {
+            let keymap = Keymap::load(path)?;
+            let mut com = Com::open().await?;
+            let meta = check_meta(&mut com, &keymap).await?;
+            let mut mismatched = 0;
+            for (config_num, config) in keymap.configs.iter().enumerate() {
+                let expected = config.checksum(&meta)?;
+                let found = com.checksum(config_num as u8).await?;
+                if expected == found {
+                    println!("Config {config_num} matches ({found:08x})");
+                } else {
+                    println!("Config {config_num} differs: {found:08x} on the keyboard, {expected:08x} in {path}");
+                    mismatched += 1;
+                }
+            }
+            if mismatched > 0 {
+                return Err(format!("{mismatched} configs don't match {path}"));
+            }
+        }
+        ["half-dump", half, path] => {
+            let half = Half::parse(half).ok_or(USAGE)?;
+            let mut com = Com::open().await?;
+            let meta = com.meta().await?;
+            let configs = com.read_half_configs(half, &meta).await?;
+            Keymap { meta, configs }.save(path)?;
+            println!(
+                "Saved {} configs of the {half:?} half to {}",
+                meta.configs, path
+            );
+        }
+        ["half-flash", half, path] => {
+            let half = Half::parse(half).ok_or(USAGE)?;
+            let keymap = Keymap::load(path)?;
+            let mut com = Com::open().await?;
+            let meta = check_meta(&mut com, &keymap).await?;
+            com.flash_half_configs(half, &meta, &keymap.configs).await?;
+            println!("Wrote {} configs to the {half:?} half", meta.configs);
+        }
+        ["label", config_num, name, color @ ..] => {
+            let config_num: u8 = config_num
+                .parse()
+                .map_err(|_| format!("Invalid config {config_num}"))?;
+            let color = match color {
+                [] => [0; 3],
+                [color] => parse_color(color).ok_or(format!("Invalid color {color}"))?,
+                _ => return Err(USAGE.into()),
+            };
+            let info = ConfigInfo {
+                name: name.to_string(),
+                color,
+            };
+            let mut com = Com::open().await?;
+            com.set_config_info(config_num, &info).await?;
+            println!("Labeled config {config_num} {name}");
+        }
+        ["backup", path] => {
+            let mut com = Com::open().await?;
+            let blob = com.export_config().await?;
+            std::fs::write(path, &blob).map_err(|e| e.to_string())?;
+            println!("Saved {} bytes to {}", blob.len(), path);
+        }
+        ["restore", path] => {
+            let blob = std::fs::read(path).map_err(|e| e.to_string())?;
+            let mut com = Com::open().await?;
+            let meta = com.meta().await?;
+            backup::check(&blob, &meta)?;
+            com.import_config(&blob).await?;
+            println!("Restored {}", path);
+        }
+        ["compact"] => {
+            let mut com = Com::open().await?;
+            println!("{}", com.compact_storage().await?);
+        }
+        ["diag", kind] => {
+            let kind = DiagnosticKind::parse(kind).ok_or(format!("Unknown diagnostic {kind}"))?;
+            let mut com = Com::open().await?;
+            println!("{}", com.diagnostic(kind).await?);
+        }
+        ["sim", keys @ ..] => {
+            let keys = keys
+                .iter()
+                .map(|key| key.parse().map_err(|_| format!("Invalid key {key}")))
+                .collect::<Result<Vec<usize>, String>>()?;
+            let mut com = Com::open().await?;
+            let meta = com.meta().await?;
+            com.sim_keys(&meta, &keys).await?;
+            println!("Holding {} keys", keys.len());
+        }
+        ["latency", state @ ("on" | "off")] => {
+            let mut com = Com::open().await?;
+            com.latency_tracking(*state == "on").await?;
+        }
+        ["stats"] => {
+            let mut com = Com::open().await?;
+            let meta = com.meta().await?;
+            let stats = com.key_stats(&meta).await?;
+            let state = if stats.enabled { "on" } else { "off" };
+            println!("Counting: {state} | Total presses: {}", stats.total);
+            let mut keys: Vec<(usize, u32)> = stats.presses.into_iter().enumerate().collect();
+            // Most used keys first
+            keys.sort_by(|a, b| b.1.cmp(&a.1));
+            for (key, presses) in keys {
+                let share = presses as f64 * 100.0 / stats.total.max(1) as f64;
+                println!("Key {key}: {presses} ({share:.1}%)");
+            }
+        }
+        ["stats", command] => {
+            let command = KeyStatsCommand::parse(command).ok_or(USAGE)?;
+            let mut com = Com::open().await?;
+            com.key_stats_command(command).await?;
+        }
+        ["host", host] => {
+            let host = host.parse().map_err(|_| format!("Invalid host {host}"))?;
+            let mut com = Com::open().await?;
+            com.switch_host(host).await?;
+        }
+        ["timing"] => {
+            let mut com = Com::open().await?;
+            let timing = com.link_timing().await?;
+            println!(
+                "Heartbeat: {}ms | Scan rate: {}",
+                timing.heartbeat_ms,
+                timing.scan_rate.name()
+            );
+        }
+        ["timing", heartbeat_ms, rate] => {
+            let heartbeat_ms = heartbeat_ms
+                .parse()
+                .map_err(|_| format!("Invalid heartbeat {heartbeat_ms}"))?;
+            let scan_rate = ScanRate::parse(rate).ok_or(format!("Invalid scan rate {rate}"))?;
+            let mut com = Com::open().await?;
+            com.set_link_timing(LinkTiming {
+                heartbeat_ms,
+                scan_rate,
+            })
+            .await?;
+        }
+        ["watch"] => {
+            let mut com = Com::open().await?;
+            com.watch_link(WATCH_INTERVAL).await?;
+            let mut halves = com
+                .link_frame()
+                .await?
+                .ok_or("The keyboard has no wireless link")?;
+            for (name, half) in ["Left", "Right"].iter().zip(&halves) {
+                println!(
+                    "{name} half: connected {} | wired {} | battery {}mV | rssi {}dBm | version mismatch {}",
+                    half.connected, half.wired, half.battery_mv, half.rssi, half.version_mismatch
+                );
+            }
+            loop {
+                let Some(new) = com.link_frame().await? else {
+                    return Err("The keyboard stopped the link watch".into());
+                };
+                for line in watch::changes(&halves, &new) {
+                    println!("{line}");
+                }
+                halves = new;
+            }
+        }
+        ["react"] => {
+            let mut com = Com::open().await?;
+            com.reaction_test().await?;
+            println!("Press any key once the leds light up or Now! is printed");
+            loop {
+                match com.reaction_event().await? {
+                    ReactionEvent::Cue => println!("Now!"),
+                    ReactionEvent::Reaction { us } => {
+                        println!("Reaction time: {:.1}ms", us as f64 / 1000.0);
+                        break;
+                    }
+                    ReactionEvent::FalseStart => return Err("Pressed before the cue".into()),
+                    ReactionEvent::Timeout => return Err("No key was pressed".into()),
+                }
+            }
+        }
+        ["drift"] => {
+            let mut com = Com::open().await?;
+            let settings = com.drift_compensation().await?;
+            let state = if settings.enabled { "on" } else { "off" };
+            println!(
+                "Drift compensation: {state} | Idle: {}s | Step: {} | Band: {}%",
+                settings.idle_secs, settings.step, settings.band_percent
+            );
+        }
+        ["drift", "off"] => {
+            let mut com = Com::open().await?;
+            let settings = com.drift_compensation().await?;
+            com.set_drift_compensation(DriftCompensation {
+                enabled: false,
+                ..settings
+            })
+            .await?;
+        }
+        ["drift", idle_secs, step, band_percent] => {
+            let settings = DriftCompensation {
+                enabled: true,
+                idle_secs: idle_secs
+                    .parse()
+                    .map_err(|_| format!("Invalid idle time {idle_secs}"))?,
+                step: step.parse().map_err(|_| format!("Invalid step {step}"))?,
+                band_percent: band_percent
+                    .parse()
+                    .map_err(|_| format!("Invalid band {band_percent}"))?,
+            };
+            let mut com = Com::open().await?;
+            com.set_drift_compensation(settings).await?;
+        }
+        ["selftest", seconds @ ..] => {
+            let seconds = match seconds {
+                [] => SELF_TEST_SECONDS,
+                [seconds] => seconds
+                    .parse()
+                    .map_err(|_| format!("Invalid duration {seconds}"))?,
+                _ => return Err(USAGE.into()),
+            };
+            let mut com = Com::open().await?;
+            let meta = com.meta().await?;
+            com.start_self_test().await?;
+            println!("Press and release every key");
+            let deadline = std::time::Instant::now() + std::time::Duration::from_secs(seconds);
+            let mut passed = 0;
+            while std::time::Instant::now() < deadline {
+                tokio::time::sleep(SELF_TEST_POLL).await;
+                let report = com.self_test_report(&meta, false).await?;
+                let now_passed = report.keys_with(KeyResult::Passed).len();
+                if now_passed != passed {
+                    passed = now_passed;
+                    println!("{passed} of {} keys passed", meta.keys);
+                }
+                if report.is_done() {
+                    break;
+                }
+            }
+            let report = com.self_test_report(&meta, true).await?;
+            let untested: Vec<usize> = [KeyResult::Untested, KeyResult::Pressed]
+                .into_iter()
+                .flat_map(|result| report.keys_with(result))
+                .collect();
+            let stuck = report.keys_with(KeyResult::Stuck);
+            if !untested.is_empty() {
+                println!("Never pressed and released: {untested:?}");
+            }
+            if !stuck.is_empty() {
+                println!("Held the whole test: {stuck:?}");
+            }
+            let radio = match report.radio {
+                RadioCheck::Unsupported => "not checked",
+                RadioCheck::Running => "no response yet",
+                RadioCheck::Passed => "passed",
+                RadioCheck::Failed => "failed",
+            };
+            println!("Radio: {radio}");
+            if !untested.is_empty()
+                || !stuck.is_empty()
+                || matches!(report.radio, RadioCheck::Running | RadioCheck::Failed)
+            {
+                return Err("Self test failed".into());
+            }
+            println!("Self test passed");
+        }
+        ["bootloader"] => {
+            let mut com = Com::open().await?;
+            com.bootloader().await?;
+            println!("Rebooting into the bootloader");
+        }
+        ["factory-reset"] => {
+            return Err(
+                "factory-reset erases every config and setting, run it with --yes to confirm"
+                    .into(),
+            );
+        }
+        ["factory-reset", "--yes"] => {
+            let mut com = Com::open().await?;
+            com.factory_reset().await?;
+            println!("Erasing the keyboard's storage and rebooting");
+        }
+        ["inject", command, key @ ..] => {
+            let command = InjectCommand::parse(command).ok_or(USAGE)?;
+            let key = match (command, key) {
+                (InjectCommand::Press | InjectCommand::Release, [key]) => {
+                    key.parse().map_err(|_| format!("Invalid key {key}"))?
+                }
+                (InjectCommand::Enable | InjectCommand::Disable, []) => 0,
+                _ => return Err(USAGE.into()),
+            };
+            let mut com = Com::open().await?;
+            com.inject(command, key).await?;
+        }
+        _ => return Err(USAGE.into()),
+    }
+    Ok(())
+}
+
+/// Parses a color written as rrggbb
+fn parse_color(color: &str) -> Option<[u8; 3]> {
+    let value = u32::from_str_radix(color, 16)
+        .ok()
+        .filter(|_| color.len() == 6)?;
+    let [_, r, g, b] = value.to_be_bytes();
+    Some([r, g, b])
+}
+
+/// Makes sure the keymap was made for the connected keyboard
+async fn check_meta(com: &mut Com, keymap: &Keymap) -> Result<keymap::Meta, String> {
+    let meta = com.meta().await?;
+    if meta != keymap.meta {
+        return Err(format!(
+            "Keymap was made for {:?} but the keyboard is {:?}",
+            keymap.meta, meta
+        ));
+    }
+    Ok(meta)
+}
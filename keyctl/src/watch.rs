@@ -0,0 +1,75 @@
+// Needs to match key_lib::link_watch
+const HALF_LINK_STATE_LEN: usize = 8;
+pub const LINK_WATCH_FRAME_LEN: usize = 1 + 2 * HALF_LINK_STATE_LEN;
+
+const CONNECTED: u8 = 1 << 0;
+const WIRED: u8 = 1 << 1;
+const BATTERY_LOW: u8 = 1 << 2;
+const VERSION_MISMATCH: u8 = 1 << 3;
+
+/// Host side copy of key_lib::link_watch::HalfLinkState
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct HalfLink {
+    pub connected: bool,
+    pub wired: bool,
+    pub battery_low: bool,
+    pub version_mismatch: bool,
+    pub rssi: i8,
+    pub battery_mv: u16,
+    pub last_seen_ms: Option<u32>,
+}
+
+impl HalfLink {
+    fn from_bytes(buf: &[u8]) -> Self {
+        let last_seen = u32::from_le_bytes([buf[4], buf[5], buf[6], buf[7]]);
+        Self {
+            connected: buf[0] & CONNECTED != 0,
+            wired: buf[0] & WIRED != 0,
+            battery_low: buf[0] & BATTERY_LOW != 0,
+            version_mismatch: buf[0] & VERSION_MISMATCH != 0,
+            rssi: buf[1] as i8,
+            battery_mv: u16::from_le_bytes([buf[2], buf[3]]),
+            last_seen_ms: (last_seen != u32::MAX).then_some(last_seen),
+        }
+    }
+}
+
+/// Decodes a link watch frame. Returns None if the keyboard has no wireless
+/// link
+pub fn decode(frame: &[u8; LINK_WATCH_FRAME_LEN]) -> Option<[HalfLink; 2]> {
+    if frame[0] == 0 {
+        return None;
+    }
+    Some([
+        HalfLink::from_bytes(&frame[1..1 + HALF_LINK_STATE_LEN]),
+        HalfLink::from_bytes(&frame[1 + HALF_LINK_STATE_LEN..]),
+    ])
+}
+
+/// Returns a line for each change between the states a notifier should show
+pub fn changes(old: &[HalfLink; 2], new: &[HalfLink; 2]) -> Vec<String> {
+    let mut lines = Vec::new();
+    for ((name, old), new) in ["Left", "Right"].iter().zip(old).zip(new) {
+        if new.connected != old.connected {
+            let state = if new.connected {
+                "connected"
+            } else {
+                "disconnected"
+            };
+            lines.push(format!("{name} half {state}"));
+        }
+        if new.wired != old.wired {
+            let link = if new.wired { "usb" } else { "the dongle" };
+            lines.push(format!("{name} half switched to {link}"));
+        }
+        if new.battery_low && !old.battery_low {
+            lines.push(format!("{name} half battery low ({}mV)", new.battery_mv));
+        }
+        if new.version_mismatch && !old.version_mismatch {
+            lines.push(format!(
+                "{name} half firmware doesn't match the dongle, flash both with the same release"
+            ));
+        }
+    }
+    lines
+}
@@ -15,7 +15,9 @@ use embassy_sync::mutex::Mutex;
 use embassy_time::Timer;
 use embassy_usb::class::hid::{HidReaderWriter, HidWriter, State};
 use embassy_usb::{Builder, Config, Handler};
-use key_lib::descriptor::{BufferReport, KeyboardReportNKRO, MouseReport, SlaveReport};
+#[cfg(feature = "mouse")]
+use key_lib::descriptor::MouseReport;
+use key_lib::descriptor::{BufferReport, KeyboardReportNKRO, SlaveReport};
 use usbd_hid::descriptor::SerializedDescriptor;
 use {defmt_rtt as _, panic_probe as _};
 
@@ -50,6 +52,7 @@ async fn main(_spawner: Spawner) {
 
     let mut key_state = State::new();
     let mut slave_state = State::new();
+    #[cfg(feature = "mouse")]
     let mut mouse_state = State::new();
     let mut com_state = State::new();
     let mut device_handler =
@@ -83,6 +86,7 @@ async fn main(_spawner: Spawner) {
         poll_ms: 1,
         max_packet_size: 64,
     };
+    #[cfg(feature = "mouse")]
     let mouse_config = embassy_usb::class::hid::Config {
         report_descriptor: MouseReport::desc(),
         request_handler: None,
@@ -95,6 +99,7 @@ async fn main(_spawner: Spawner) {
         HidReaderWriter::<_, 32, 32>::new(&mut builder, &mut slave_state, slave_config);
     let (com_reader, com_writer) =
         HidReaderWriter::<_, 32, 32>::new(&mut builder, &mut com_state, com_config).split();
+    #[cfg(feature = "mouse")]
     let mut mouse_writer = HidWriter::<_, 5>::new(&mut builder, &mut mouse_state, mouse_config);
 
     // Build the builder.
@@ -0,0 +1,103 @@
+//! Firmware for both halves of the Tybeast Ones HE. The side is read from
+//! storage if it was set over com with SettingId::Side, otherwise from the
+//! strap pin. The strap pin is pulled up, so a half with the pin grounded
+//! runs as the right half. Either half can be plugged into the host, the one
+//! that enumerates first runs as the master (see tybeast_ones_he::election)
+
+#![no_std]
+#![no_main]
+
+use core::panic::PanicInfo;
+
+use cortex_m_rt::{exception, ExceptionFrame};
+use defmt::{error, info, Display2Format};
+use defmt_rtt as _;
+use embassy_executor::Spawner;
+use embassy_rp::adc::{Adc, Channel as AdcChannel, Config as AdcConfig};
+use embassy_rp::flash::{Async, Flash};
+use embassy_rp::gpio::{Input, Level, Output, Pull};
+use embassy_rp::usb::Driver;
+use embassy_rp::watchdog::Watchdog;
+use key_lib::board::{load_side, Side};
+use key_lib::crash::CrashRecord;
+use key_lib::storage::Storage;
+#[cfg(feature = "wired-link")]
+use tybeast_ones_he::board::LinkResources;
+use tybeast_ones_he::board::{
+    board_config, bootloader_task, storage_task, HalfResources, Irqs, FLASH_END, FLASH_SIZE,
+    FLASH_START,
+};
+use tybeast_ones_he::half;
+use tybeast_ones_he::watchdog::{load_boot_state, record_crash, watchdog_task};
+
+#[embassy_executor::main]
+async fn main(spawner: Spawner) {
+    info!("Device Started!");
+    let mut p = embassy_rp::init(Default::default());
+    let watchdog = Watchdog::new(p.WATCHDOG);
+    load_boot_state(p.FLASH.reborrow(), &watchdog);
+    spawner.spawn(watchdog_task(watchdog).unwrap());
+
+    let storage = Storage::init(
+        Flash::<_, Async, FLASH_SIZE>::new(p.FLASH, p.DMA_CH0, Irqs),
+        FLASH_START..FLASH_END,
+    )
+    .await;
+    spawner.spawn(storage_task(storage).unwrap());
+    spawner.spawn(bootloader_task().unwrap());
+
+    let strap = Input::new(p.PIN_3, Pull::Up);
+    let strapped = if strap.is_low() {
+        Side::Right
+    } else {
+        Side::Left
+    };
+    drop(strap);
+    let side = load_side(strapped).await;
+
+    let sel = match side {
+        Side::Left => [
+            Output::new(p.PIN_2, Level::Low),
+            Output::new(p.PIN_1, Level::Low),
+            Output::new(p.PIN_0, Level::Low),
+        ],
+        Side::Right => [
+            Output::new(p.PIN_0, Level::Low),
+            Output::new(p.PIN_1, Level::Low),
+            Output::new(p.PIN_2, Level::Low),
+        ],
+    };
+    let res = HalfResources {
+        driver: Driver::new(p.USB, Irqs),
+        adc: Adc::new(p.ADC, Irqs, AdcConfig::default()),
+        chans: [
+            AdcChannel::new_pin(p.PIN_29, Pull::None),
+            AdcChannel::new_pin(p.PIN_28, Pull::None),
+            AdcChannel::new_pin(p.PIN_27, Pull::None),
+            AdcChannel::new_pin(p.PIN_26, Pull::None),
+        ],
+        sel,
+        pio: p.PIO0,
+        led_dma: p.DMA_CH1,
+        led_pin: p.PIN_17,
+        #[cfg(feature = "wired-link")]
+        link: LinkResources {
+            uart: p.UART1,
+            tx: p.PIN_4,
+            rx: p.PIN_5,
+        },
+    };
+
+    half::run(res, board_config(side)).await;
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    error!("{}", Display2Format(info));
+    record_crash(&CrashRecord::panic(info))
+}
+
+#[exception]
+unsafe fn HardFault(frame: &ExceptionFrame) -> ! {
+    record_crash(&CrashRecord::hard_fault(frame.pc()))
+}
@@ -0,0 +1,107 @@
+use core::ops::Range;
+
+use embassy_rp::adc::{self, Adc, Async as AdcAsync, Channel as AdcChannel};
+use embassy_rp::flash::{Async, Flash};
+use embassy_rp::gpio::Output;
+use embassy_rp::peripherals::{DMA_CH1, FLASH, PIN_17, PIN_4, PIN_5, PIO0, UART1, USB};
+use embassy_rp::usb::Driver;
+use embassy_rp::{bind_interrupts, peripherals, uart, usb, Peri};
+use key_lib::board::{BoardConfig, Side};
+use key_lib::bootloader::wait_for_bootloader;
+use key_lib::position::AdcFilter;
+use key_lib::storage::Storage;
+use key_lib::NUM_KEYS;
+
+pub const FLASH_START: u32 = 1024 * 1024;
+pub const FLASH_END: u32 = FLASH_START + 4096 * 5;
+pub const FLASH_SIZE: usize = 2 * 1024 * 1024;
+
+/// Filter of the hall sensor readings. The median drops the single noisy
+/// readings of the rp2040's adc that would otherwise release keys with rapid
+/// trigger
+pub const ADC_FILTER: AdcFilter = AdcFilter::Median3;
+
+/// Number of keys on each half
+pub const HALF_KEYS: usize = NUM_KEYS / 2;
+
+/// Keys of the half held from boot to factory reset the board, as indices
+/// of the keys on the half. See [key_lib::factory_reset::ResetChord]
+pub const RESET_CHORD: [usize; 2] = [0, HALF_KEYS - 1];
+
+/// Indices of the half's keys in the keymap of the whole board
+pub fn half_keys(side: Side) -> Range<usize> {
+    match side {
+        Side::Left => 0..HALF_KEYS,
+        Side::Right => HALF_KEYS..NUM_KEYS,
+    }
+}
+
+bind_interrupts!(pub struct Irqs {
+    USBCTRL_IRQ => usb::InterruptHandler<peripherals::USB>;
+    ADC_IRQ_FIFO => adc::InterruptHandler;
+    DMA_IRQ_0 => embassy_rp::dma::InterruptHandler<peripherals::DMA_CH0>, embassy_rp::dma::InterruptHandler<peripherals::DMA_CH1>;
+    PIO0_IRQ_0 => embassy_rp::pio::InterruptHandler<peripherals::PIO0>;
+    UART1_IRQ => uart::BufferedInterruptHandler<peripherals::UART1>;
+});
+
+pub const LEFT: BoardConfig<HALF_KEYS> = BoardConfig {
+    side: Side::Left,
+    vid: 0xa55,
+    pid: 0xa55,
+    product: "Tybeast Ones HE (Left)",
+    order: [
+        7, 14, 2, 18, 5, 0, 3, 11, 6, 1, 9, 4, 15, 19, 10, 13, 17, 8, 12, 16, 20,
+    ],
+};
+
+pub const RIGHT: BoardConfig<HALF_KEYS> = BoardConfig {
+    side: Side::Right,
+    vid: 0x727,
+    pid: 0x727,
+    product: "Tybeast Ones HE (Right)",
+    order: [
+        4, 5, 18, 2, 14, 7, 0, 9, 1, 6, 11, 3, 12, 17, 13, 10, 19, 15, 20, 16, 8,
+    ],
+};
+
+pub fn board_config(side: Side) -> &'static BoardConfig<HALF_KEYS> {
+    match side {
+        Side::Left => &LEFT,
+        Side::Right => &RIGHT,
+    }
+}
+
+/// Peripherals used by both halves. The select pins are wired in reverse on
+/// the right half, so they should be given in the order of the detected side
+pub struct HalfResources {
+    pub driver: Driver<'static, USB>,
+    pub adc: Adc<'static, AdcAsync>,
+    pub chans: [AdcChannel<'static>; 4],
+    pub sel: [Output<'static>; 3],
+    pub pio: Peri<'static, PIO0>,
+    pub led_dma: Peri<'static, DMA_CH1>,
+    pub led_pin: Peri<'static, PIN_17>,
+    #[cfg(feature = "wired-link")]
+    pub link: LinkResources,
+}
+
+/// Pins of the wired link between the halves. The cable crosses them, so tx
+/// of one half goes to rx of the other
+pub struct LinkResources {
+    pub uart: Peri<'static, UART1>,
+    pub tx: Peri<'static, PIN_4>,
+    pub rx: Peri<'static, PIN_5>,
+}
+
+#[embassy_executor::task]
+pub async fn storage_task(storage: Storage<Flash<'static, FLASH, Async, FLASH_SIZE>>) {
+    storage.run_storage().await;
+}
+
+/// Reboots into BOOTSEL once a key or com requests it, where the half shows
+/// up as a usb drive a uf2 can be copied to
+#[embassy_executor::task]
+pub async fn bootloader_task() {
+    wait_for_bootloader().await;
+    embassy_rp::rom_data::reset_to_usb_boot(0, 0);
+}
@@ -0,0 +1,109 @@
+//! Election of the master half. Both halves run the same image, so either can
+//! be plugged into the host: the half that enumerates on usb first claims the
+//! master role over the link and the other half runs as its slave.
+//!
+//! Claims are sent as plain reports starting with [CLAIM_MAGIC]. Sealed
+//! reports and link requests start with a small tag and plain slave reports
+//! with key states that leave the top byte clear, so none of them are taken
+//! for a claim. A running master answers every claim and keeps claiming while
+//! it has no slave, so a half that restarts or is plugged in later becomes its
+//! slave
+
+use defmt::{info, Format};
+use embassy_futures::select::{select, Either};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+use embassy_time::{with_timeout, Duration};
+use key_lib::board::Side;
+use key_lib::descriptor::SlaveReport;
+
+use crate::link_crypto::Role;
+use crate::slave_com::{LinkReader, LinkWriter};
+
+const CLAIM_MAGIC: [u8; 4] = *b"ELCT";
+
+// Time a half that claimed the master role listens for a competing claim
+// before taking it. Covers a claim relayed by the host
+const SETTLE_TIME: Duration = Duration::from_millis(500);
+
+static ENUMERATED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Claim of the master role sent over the link
+#[derive(Clone, Copy, Eq, PartialEq, Format)]
+pub enum Claim {
+    /// Sent by a half that just enumerated. The left half wins if both halves
+    /// claim at once
+    Fresh(Side),
+    /// Sent by a half already running as the master
+    Established,
+}
+
+impl Claim {
+    pub fn report(self) -> SlaveReport {
+        let mut rep = SlaveReport::default();
+        rep.input[..4].copy_from_slice(&CLAIM_MAGIC);
+        rep.input[4] = match self {
+            Claim::Established => 0,
+            Claim::Fresh(side) => 1 + side as u8,
+        };
+        rep
+    }
+
+    /// Returns the claim in the report, or None if it isn't one
+    pub fn read(buf: &[u8; 32]) -> Option<Self> {
+        if buf[..4] != CLAIM_MAGIC {
+            return None;
+        }
+        match buf[4] {
+            0 => Some(Claim::Established),
+            tag => Side::try_from(tag - 1).ok().map(Claim::Fresh),
+        }
+    }
+}
+
+/// Records that the usb device was configured by a host. Called from the
+/// device handler
+pub fn set_enumerated() {
+    ENUMERATED.signal(());
+}
+
+async fn read_claim<R: LinkReader>(reader: &mut R) -> Claim {
+    loop {
+        let mut buf = [0u8; 32];
+        reader.receive(&mut buf).await;
+        if let Some(claim) = Claim::read(&buf) {
+            return claim;
+        }
+    }
+}
+
+/// Waits until either this half enumerates or the other half claims the
+/// master role, and returns the role this half should run
+pub async fn elect<R: LinkReader, W: LinkWriter>(
+    (reader, writer): &mut (R, W),
+    side: Side,
+) -> Role {
+    if let Either::Second(claim) = select(ENUMERATED.wait(), read_claim(reader)).await {
+        info!("The other half claimed the master role: {}", claim);
+        return Role::Slave;
+    }
+    writer.send(&Claim::Fresh(side).report()).await;
+    let settle = async {
+        loop {
+            match read_claim(reader).await {
+                Claim::Established => return,
+                Claim::Fresh(Side::Left) if side == Side::Right => return,
+                Claim::Fresh(_) => {}
+            }
+        }
+    };
+    match with_timeout(SETTLE_TIME, settle).await {
+        Ok(()) => {
+            info!("Enumerated, but the other half is the master");
+            Role::Slave
+        }
+        Err(_) => {
+            info!("Enumerated first, running as the master");
+            Role::Master
+        }
+    }
+}
@@ -0,0 +1,361 @@
+//! Startup shared by both halves. Every half builds the same usb device, with
+//! the report endpoints of the master and the com and link endpoints both
+//! roles use, then runs as whichever role it wins in [crate::election]
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use defmt::info;
+use embassy_futures::join::join;
+use embassy_rp::peripherals::{PIO0, USB};
+use embassy_rp::pio::Pio;
+use embassy_rp::pio_programs::ws2812::{PioWs2812, PioWs2812Program, Rgb};
+use embassy_rp::usb::Driver;
+use embassy_usb::class::hid::{
+    HidProtocolMode, HidReader, HidReaderWriter, HidWriter, ReportId, RequestHandler, State,
+};
+use embassy_usb::control::OutResponse;
+use embassy_usb::types::StringIndex;
+use embassy_usb::{Builder, Config, Handler};
+use key_lib::board::{load_report_rate, load_usb_identity, BoardConfig};
+use key_lib::boot_protocol::{boot_protocol, set_boot_protocol};
+#[cfg(feature = "digitizer")]
+use key_lib::descriptor::DigitizerReport;
+#[cfg(feature = "mouse")]
+use key_lib::descriptor::MouseReport;
+#[cfg(not(feature = "wired-link"))]
+use key_lib::descriptor::SlaveReport;
+use key_lib::descriptor::{BufferReport, KeyboardReportBoot, KeyboardReportNKRO, BOOT_REPORT_LEN};
+#[cfg(feature = "mouse")]
+use key_lib::hires_scroll::{reset_resolution, resolution_report, set_resolution_report};
+use key_lib::keys::HostLeds;
+use key_lib::os::{
+    record_led_report, record_set_idle, record_string_request, reset_detection, set_configured,
+};
+use key_lib::position::set_adc_filter;
+use key_lib::watchdog::{idle, Watched};
+use usbd_hid::descriptor::SerializedDescriptor;
+
+use crate::board::{HalfResources, Irqs, ADC_FILTER, HALF_KEYS};
+use crate::election::{elect, set_enumerated};
+use crate::indicator::Indicator;
+use crate::link_crypto::Role;
+#[cfg(feature = "sim")]
+use crate::sensors::sim_sensors;
+#[cfg(not(feature = "sim"))]
+use crate::sensors::HallEffectSensors;
+#[cfg(feature = "wired-link")]
+use crate::uart_link::uart_link;
+use crate::{master, slave};
+
+pub(crate) type UsbDriver = Driver<'static, USB>;
+
+/// Com endpoints of the usb device
+pub type ComEndpoints<'d> = (HidReader<'d, UsbDriver, 32>, HidWriter<'d, UsbDriver, 32>);
+
+/// Led of the half
+pub type Led<'d> = PioWs2812<'d, PIO0, 0, 1, Rgb>;
+
+/// Report endpoints of the usb device. Only written while the half is the
+/// master
+pub struct ReportWriters<'d> {
+    pub keys: HidWriter<'d, UsbDriver, 29>,
+    /// Boot keyboard, only written once the host switches it to the boot
+    /// protocol. See [key_lib::boot_protocol]
+    pub boot_keys: HidWriter<'d, UsbDriver, BOOT_REPORT_LEN>,
+    #[cfg(feature = "mouse")]
+    pub mouse: HidWriter<'d, UsbDriver, 5>,
+    #[cfg(feature = "digitizer")]
+    pub digitizer: HidWriter<'d, UsbDriver, 7>,
+}
+
+/// Runs the half as the master or the slave, whichever it's elected as
+pub async fn run(res: HalfResources, board: &BoardConfig<HALF_KEYS>) {
+    // Storage is already running, so the stored identity can be used
+    let identity = load_usb_identity(board.vid, board.pid, board.product).await;
+    // The rp2040 only runs at full speed
+    let b_interval = load_report_rate(false).await;
+    set_adc_filter(ADC_FILTER);
+
+    // Create embassy-usb Config
+    let mut config = Config::new(identity.vid, identity.pid);
+    config.manufacturer = Some("Tybeast Corp.");
+    config.product = Some(identity.product.as_str());
+    config.max_power = 500;
+    config.max_packet_size_0 = 64;
+    config.composite_with_iads = true;
+    config.device_class = 0xef;
+    config.device_sub_class = 0x02;
+    config.device_protocol = 0x01;
+
+    // Create embassy-usb DeviceBuilder using the driver and config.
+    // It needs some buffers for building the descriptors.
+    let mut config_descriptor = [0; 256];
+    let mut bos_descriptor = [0; 256];
+    let mut msos_descriptor = [0; 256];
+    let mut control_buf = [0; 64];
+
+    let mut key_state = State::new();
+    let mut boot_state = State::new();
+    #[cfg(not(feature = "wired-link"))]
+    let mut slave_state = State::new();
+    #[cfg(feature = "mouse")]
+    let mut mouse_state = State::new();
+    #[cfg(feature = "digitizer")]
+    let mut digitizer_state = State::new();
+    let mut com_state = State::new();
+    let mut device_handler = MyDeviceHandler::new();
+    let mut key_handler = KeyboardRequestHandler {
+        indicator: Indicator {},
+    };
+    let mut boot_handler = BootRequestHandler {
+        indicator: Indicator {},
+    };
+    #[cfg(feature = "mouse")]
+    let mut mouse_handler = MouseRequestHandler {};
+
+    let mut builder = Builder::new(
+        res.driver,
+        config,
+        &mut config_descriptor,
+        &mut bos_descriptor,
+        &mut msos_descriptor,
+        &mut control_buf,
+    );
+
+    // Create classes on the builder.
+    let key_config = embassy_usb::class::hid::Config {
+        hid_subclass: embassy_usb::class::hid::HidSubclass::No,
+        hid_boot_protocol: embassy_usb::class::hid::HidBootProtocol::None,
+        report_descriptor: KeyboardReportNKRO::desc(),
+        request_handler: Some(&mut key_handler),
+        poll_ms: b_interval,
+        max_packet_size: 32,
+    };
+    let boot_config = embassy_usb::class::hid::Config {
+        hid_subclass: embassy_usb::class::hid::HidSubclass::Boot,
+        hid_boot_protocol: embassy_usb::class::hid::HidBootProtocol::Keyboard,
+        report_descriptor: KeyboardReportBoot::desc(),
+        request_handler: Some(&mut boot_handler),
+        poll_ms: b_interval,
+        max_packet_size: BOOT_REPORT_LEN as u16,
+    };
+    #[cfg(not(feature = "wired-link"))]
+    let slave_config = embassy_usb::class::hid::Config {
+        hid_subclass: embassy_usb::class::hid::HidSubclass::No,
+        hid_boot_protocol: embassy_usb::class::hid::HidBootProtocol::None,
+        report_descriptor: SlaveReport::desc(),
+        request_handler: None,
+        poll_ms: 1,
+        max_packet_size: 64,
+    };
+    let com_config = embassy_usb::class::hid::Config {
+        hid_subclass: embassy_usb::class::hid::HidSubclass::No,
+        hid_boot_protocol: embassy_usb::class::hid::HidBootProtocol::None,
+        report_descriptor: BufferReport::desc(),
+        request_handler: None,
+        poll_ms: 1,
+        max_packet_size: 64,
+    };
+    #[cfg(feature = "mouse")]
+    let mouse_config = embassy_usb::class::hid::Config {
+        hid_subclass: embassy_usb::class::hid::HidSubclass::No,
+        hid_boot_protocol: embassy_usb::class::hid::HidBootProtocol::None,
+        report_descriptor: MouseReport::desc(),
+        request_handler: Some(&mut mouse_handler),
+        poll_ms: b_interval,
+        max_packet_size: 5,
+    };
+    #[cfg(feature = "digitizer")]
+    let digitizer_config = embassy_usb::class::hid::Config {
+        hid_subclass: embassy_usb::class::hid::HidSubclass::No,
+        hid_boot_protocol: embassy_usb::class::hid::HidBootProtocol::None,
+        report_descriptor: DigitizerReport::desc(),
+        request_handler: None,
+        poll_ms: b_interval,
+        max_packet_size: 8,
+    };
+    builder.handler(&mut device_handler);
+    let key_writer = HidWriter::<_, 29>::new(&mut builder, &mut key_state, key_config);
+    let boot_writer =
+        HidWriter::<_, BOOT_REPORT_LEN>::new(&mut builder, &mut boot_state, boot_config);
+    #[cfg(not(feature = "wired-link"))]
+    let mut slave_link =
+        HidReaderWriter::<_, 32, 32>::new(&mut builder, &mut slave_state, slave_config).split();
+    #[cfg(feature = "wired-link")]
+    let mut slave_link = uart_link(res.link);
+    let com = HidReaderWriter::<_, 32, 32>::new(&mut builder, &mut com_state, com_config).split();
+    let writers = ReportWriters {
+        keys: key_writer,
+        boot_keys: boot_writer,
+        #[cfg(feature = "mouse")]
+        mouse: HidWriter::<_, 5>::new(&mut builder, &mut mouse_state, mouse_config),
+        #[cfg(feature = "digitizer")]
+        digitizer: HidWriter::<_, 7>::new(&mut builder, &mut digitizer_state, digitizer_config),
+    };
+
+    // Build the builder.
+    let mut usb = builder.build();
+    let usb_fut = usb.run();
+
+    #[cfg(not(feature = "sim"))]
+    let sensors = HallEffectSensors::new(res.chans, res.sel, res.adc, board.scan_order());
+    #[cfg(feature = "sim")]
+    let sensors = sim_sensors();
+    let Pio {
+        mut common, sm0, ..
+    } = Pio::new(res.pio, Irqs);
+    let program = PioWs2812Program::new(&mut common);
+    let led: Led =
+        PioWs2812::with_color_order(&mut common, sm0, res.led_dma, Irqs, res.led_pin, &program);
+
+    let role_fut = async {
+        // Waits on the host or the other half for as long as neither shows up
+        match idle(Watched::Keys, elect(&mut slave_link, board.side)).await {
+            Role::Master => master::run(writers, com, slave_link, sensors, led, board.side).await,
+            Role::Slave => slave::run(com, slave_link, sensors, led).await,
+        }
+    };
+    join(usb_fut, role_fut).await;
+}
+
+/// Receives the lock key leds the host sets with the keyboard's output report
+struct KeyboardRequestHandler {
+    indicator: Indicator,
+}
+
+impl RequestHandler for KeyboardRequestHandler {
+    fn set_report(&mut self, id: ReportId, data: &[u8]) -> OutResponse {
+        match (id, data.first()) {
+            (ReportId::Out(_), Some(&leds)) => {
+                record_led_report();
+                self.indicator.host_leds(HostLeds(leds));
+                OutResponse::Accepted
+            }
+            _ => OutResponse::Rejected,
+        }
+    }
+
+    fn set_idle_ms(&mut self, _id: Option<ReportId>, _duration_ms: u32) {
+        record_set_idle();
+    }
+}
+
+/// Receives the lock key leds and the protocol of the boot keyboard. Its
+/// requests aren't counted for os detection, since Linux only sets the idle
+/// rate of boot keyboards
+struct BootRequestHandler {
+    indicator: Indicator,
+}
+
+impl RequestHandler for BootRequestHandler {
+    fn set_report(&mut self, id: ReportId, data: &[u8]) -> OutResponse {
+        match (id, data.first()) {
+            (ReportId::Out(_), Some(&leds)) => {
+                self.indicator.host_leds(HostLeds(leds));
+                OutResponse::Accepted
+            }
+            _ => OutResponse::Rejected,
+        }
+    }
+
+    fn set_protocol(&mut self, protocol: HidProtocolMode) -> OutResponse {
+        info!("Host set the boot keyboard's protocol to {}", protocol);
+        set_boot_protocol(protocol == HidProtocolMode::Boot);
+        OutResponse::Accepted
+    }
+
+    fn get_protocol(&self) -> HidProtocolMode {
+        if boot_protocol() {
+            HidProtocolMode::Boot
+        } else {
+            HidProtocolMode::Report
+        }
+    }
+}
+
+/// Receives the scroll resolution the host sets with the mouse's feature
+/// report
+#[cfg(feature = "mouse")]
+struct MouseRequestHandler {}
+
+#[cfg(feature = "mouse")]
+impl RequestHandler for MouseRequestHandler {
+    fn set_report(&mut self, id: ReportId, data: &[u8]) -> OutResponse {
+        match id {
+            ReportId::Feature(_) if set_resolution_report(data) => OutResponse::Accepted,
+            _ => OutResponse::Rejected,
+        }
+    }
+
+    fn get_report(&mut self, id: ReportId, buf: &mut [u8]) -> Option<usize> {
+        match id {
+            ReportId::Feature(_) => {
+                let report = resolution_report();
+                buf.get_mut(..report.len())?.copy_from_slice(&report);
+                Some(report.len())
+            }
+            _ => None,
+        }
+    }
+}
+
+struct MyDeviceHandler {
+    configured: AtomicBool,
+    indicator: Indicator,
+}
+
+impl MyDeviceHandler {
+    fn new() -> Self {
+        MyDeviceHandler {
+            configured: AtomicBool::new(false),
+            indicator: Indicator {},
+        }
+    }
+}
+
+impl Handler for MyDeviceHandler {
+    fn enabled(&mut self, enabled: bool) {
+        self.configured.store(false, Ordering::Relaxed);
+        if enabled {
+            info!("Device enabled");
+        } else {
+            info!("Device disabled");
+        }
+    }
+
+    fn suspended(&mut self, suspended: bool) {
+        self.indicator.suspend(suspended);
+    }
+
+    fn reset(&mut self) {
+        self.configured.store(false, Ordering::Relaxed);
+        reset_detection();
+        set_boot_protocol(false);
+        #[cfg(feature = "mouse")]
+        reset_resolution();
+        info!("Bus reset, the Vbus current limit is 500mA");
+    }
+
+    fn addressed(&mut self, addr: u8) {
+        self.configured.store(false, Ordering::Relaxed);
+        info!("USB address set to: {}", addr);
+    }
+
+    fn configured(&mut self, configured: bool) {
+        self.configured.store(configured, Ordering::Relaxed);
+        if configured {
+            set_configured();
+            set_enumerated();
+            info!(
+                "Device configured, it may now draw up to the configured current limit from Vbus."
+            )
+        } else {
+            info!("Device is no longer configured, the Vbus current limit is 100mA.");
+        }
+    }
+
+    fn get_string(&mut self, index: StringIndex, _lang_id: u16) -> Option<&str> {
+        record_string_request(index.into());
+        None
+    }
+}
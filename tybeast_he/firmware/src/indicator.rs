@@ -1,11 +1,12 @@
+use embassy_futures::select::{select, Either};
 use embassy_rp::{
     pio::Instance,
     pio_programs::ws2812::{PioWs2812, Rgb},
 };
 use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel};
 use key_lib::{
-    keys::{ConfigIndicator, Indicate},
-    slave_com::Master,
+    keys::{ConfigIndicator, HostLeds, Indicate},
+    slave_com::{Master, SlaveCapabilities},
 };
 use smart_leds::RGB8;
 
@@ -14,10 +15,39 @@ use crate::slave_com::{HidMaster, HidRequest, HidSlave};
 const VAL: u8 = 10;
 static CHAN: Channel<CriticalSectionRawMutex, Indicate, 10> = Channel::new();
 
+/// Color of the indicator. A locked keyboard and caps lock override the
+/// config color so they're visible on whichever config is active. A color set
+/// in the config's ConfigInfo replaces the board's color for the config
+fn indicator_color(
+    config_num: usize,
+    config_color: Option<[u8; 3]>,
+    host_leds: HostLeds,
+    locked: bool,
+) -> Option<RGB8> {
+    if locked {
+        return Some(RGB8::new(VAL, 0, 0));
+    }
+    if host_leds.is_on(HostLeds::CAPS_LOCK) {
+        return Some(RGB8::new(VAL, VAL, VAL));
+    }
+    if let Some([r, g, b]) = config_color {
+        return Some(RGB8::new(r, g, b));
+    }
+    match config_num {
+        0 => Some(RGB8::new(0, VAL, VAL)),
+        1 => Some(RGB8::new(0, 0, VAL)),
+        2 => Some(RGB8::new(0, VAL, 0)),
+        _ => None,
+    }
+}
+
 pub struct MasterIndicatorTask<'d, 'ch, P: Instance, const S: usize> {
     pio: PioWs2812<'d, P, S, 1, Rgb>,
     hid_chan: HidMaster<'ch>,
     config_num: usize,
+    config_color: Option<[u8; 3]>,
+    host_leds: HostLeds,
+    locked: bool,
     suspended: bool,
     check: bool,
 }
@@ -28,17 +58,18 @@ impl<'d, 'ch, P: Instance, const S: usize> MasterIndicatorTask<'d, 'ch, P, S> {
             pio,
             hid_chan,
             config_num: 0,
+            config_color: None,
+            host_leds: HostLeds::default(),
+            locked: false,
             suspended: false,
             check: false,
         }
     }
 
     async fn indicate_config(&mut self, config_num: usize) {
-        match config_num {
-            0 => self.pio.write(&[RGB8::new(0, VAL, VAL)]).await,
-            1 => self.pio.write(&[RGB8::new(0, 0, VAL)]).await,
-            2 => self.pio.write(&[RGB8::new(0, VAL, 0)]).await,
-            _ => {}
+        let color = indicator_color(config_num, self.config_color, self.host_leds, self.locked);
+        if let Some(color) = color {
+            self.pio.write(&[color]).await;
         }
     }
 
@@ -46,12 +77,22 @@ impl<'d, 'ch, P: Instance, const S: usize> MasterIndicatorTask<'d, 'ch, P, S> {
         loop {
             let indicate = CHAN.receive().await;
             match indicate {
-                Indicate::Config(config_num) => {
+                Indicate::Config(config_num, config_color) => {
+                    self.config_color = config_color;
                     if !self.suspended {
                         self.indicate_config(config_num).await;
-                        self.hid_chan
-                            .send_request(HidRequest::ConfigIndicate(config_num as u8))
-                            .await;
+                        if self
+                            .hid_chan
+                            .capabilities()
+                            .supports(SlaveCapabilities::INDICATOR)
+                        {
+                            self.hid_chan
+                                .send_request(HidRequest::ConfigIndicate(
+                                    config_num as u8,
+                                    config_color.unwrap_or_default(),
+                                ))
+                                .await;
+                        }
                     }
                     self.config_num = config_num;
                 }
@@ -67,6 +108,32 @@ impl<'d, 'ch, P: Instance, const S: usize> MasterIndicatorTask<'d, 'ch, P, S> {
                         self.check = true;
                     }
                 }
+                Indicate::HostLeds(leds) => {
+                    self.host_leds = leds;
+                    if !self.suspended {
+                        self.indicate_config(self.config_num).await;
+                    }
+                    if self
+                        .hid_chan
+                        .capabilities()
+                        .supports(SlaveCapabilities::HOST_LEDS)
+                    {
+                        self.hid_chan
+                            .send_request(HidRequest::HostLeds(leds.0))
+                            .await;
+                    }
+                }
+                Indicate::Locked(locked) => {
+                    self.locked = locked;
+                    if !self.suspended {
+                        self.indicate_config(self.config_num).await;
+                    }
+                }
+                // Shown until the half reboots, even if the host suspended
+                Indicate::FactoryReset => {
+                    self.suspended = true;
+                    self.pio.write(&[RGB8::new(VAL, 0, VAL)]).await;
+                }
             }
         }
     }
@@ -84,6 +151,12 @@ impl Indicator {
         };
         CHAN.try_send(msg);
     }
+
+    /// Shows the lock key leds set by the host. Can be called from the usb
+    /// request handler since it doesn't wait
+    pub fn host_leds(&self, leds: HostLeds) {
+        let _ = CHAN.try_send(Indicate::HostLeds(leds));
+    }
 }
 
 impl ConfigIndicator for Indicator {
@@ -103,16 +176,32 @@ impl<'d, 'ch, P: Instance, const S: usize> SlaveIndicatorTask<'d, 'ch, P, S> {
     }
 
     pub async fn run(mut self) {
+        let mut config_num = 0;
+        let mut config_color = None;
+        let mut host_leds = HostLeds::default();
         loop {
-            let mut req = HidRequest::ConfigIndicate(0);
-            self.hid_chan.get_request_ref(&mut req).await;
-            if let HidRequest::ConfigIndicate(config_num) = req {
-                match config_num {
-                    0 => self.pio.write(&[RGB8::new(0, VAL, VAL)]).await,
-                    1 => self.pio.write(&[RGB8::new(0, 0, VAL)]).await,
-                    2 => self.pio.write(&[RGB8::new(0, VAL, 0)]).await,
-                    _ => {}
+            let mut config_req = HidRequest::ConfigIndicate(0, [0; 3]);
+            let mut leds_req = HidRequest::HostLeds(0);
+            match select(
+                self.hid_chan.get_request_ref(&mut config_req),
+                self.hid_chan.get_request_ref(&mut leds_req),
+            )
+            .await
+            {
+                Either::First(_) => {
+                    if let HidRequest::ConfigIndicate(num, color) = config_req {
+                        config_num = num as usize;
+                        config_color = (color != [0; 3]).then_some(color);
+                    }
                 }
+                Either::Second(_) => {
+                    if let HidRequest::HostLeds(leds) = leds_req {
+                        host_leds = HostLeds(leds);
+                    }
+                }
+            }
+            if let Some(color) = indicator_color(config_num, config_color, host_leds, false) {
+                self.pio.write(&[color]).await;
             }
         }
     }
@@ -1,6 +1,15 @@
 #![no_std]
 #![feature(variant_count)]
 
+pub mod board;
+pub mod election;
+pub mod half;
 pub mod indicator;
+pub mod lighting;
+pub mod link_crypto;
+pub mod master;
 pub mod sensors;
+pub mod slave;
 pub mod slave_com;
+pub mod uart_link;
+pub mod watchdog;
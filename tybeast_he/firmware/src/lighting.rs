@@ -0,0 +1,42 @@
+use embassy_rp::{
+    pio::Instance,
+    pio_programs::ws2812::{PioWs2812, Rgb},
+};
+use embassy_time::{Instant, Ticker};
+use key_lib::lighting::{load_lighting, Color, LightingEngine, FRAME_INTERVAL, LIGHTING};
+use smart_leds::RGB8;
+
+/// Drives a chain of N per key leds. map holds the key index of each led in
+/// the order they're chained
+pub struct LightingTask<'d, P: Instance, const S: usize, const N: usize> {
+    pio: PioWs2812<'d, P, S, N, Rgb>,
+    map: [usize; N],
+    engine: LightingEngine,
+}
+
+impl<'d, P: Instance, const S: usize, const N: usize> LightingTask<'d, P, S, N> {
+    pub fn new(pio: PioWs2812<'d, P, S, N, Rgb>, map: [usize; N]) -> Self {
+        Self {
+            pio,
+            map,
+            engine: LightingEngine::new(),
+        }
+    }
+
+    pub async fn run(mut self) -> ! {
+        load_lighting().await;
+        let mut ticker = Ticker::every(FRAME_INTERVAL);
+        let mut frame = [Color::OFF; N];
+        let mut leds = [RGB8::default(); N];
+        loop {
+            let settings = *LIGHTING.lock().await;
+            self.engine
+                .render(&settings, Instant::now(), &self.map, &mut frame);
+            for (led, color) in leds.iter_mut().zip(frame) {
+                *led = RGB8::new(color.r, color.g, color.b);
+            }
+            self.pio.write(&leds).await;
+            ticker.next().await;
+        }
+    }
+}
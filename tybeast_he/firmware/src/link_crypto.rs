@@ -0,0 +1,188 @@
+//! Encryption of the slave link. Over the slave endpoint the halves talk
+//! through reports relayed by the host, so with a pairing key set every
+//! report is sealed with XChaCha20Poly1305 and the host can neither read nor
+//! forge key states.
+//!
+//! Each half picks a random salt at boot and sends it in hello frames until
+//! it hears the other half's salt. Data frames use a nonce made from the
+//! direction, both salts and a counter, so frames recorded in an earlier
+//! session or replayed within one are rejected.
+//!
+//! Hellos aren't authenticated, so the host can send a half an old salt of
+//! the other half. A half that accepted frames from the other half's salt
+//! picks a new salt of its own when that salt changes, so frames sealed for
+//! its old salt never open again. Halves that haven't accepted a frame from
+//! the old salt keep theirs, which keeps two halves from trading new salts
+//! forever.
+
+use chacha20poly1305::aead::AeadInPlace;
+use chacha20poly1305::{KeyInit, Tag, XChaCha20Poly1305, XNonce};
+use defmt::warn;
+use embassy_rp::clocks::RoscRng;
+use key_lib::slave_com::PairingKey;
+
+/// Length of a sealed report
+pub const FRAME_LEN: usize = 32;
+/// Longest payload that fits in a sealed report
+pub const MAX_PAYLOAD_LEN: usize = FRAME_LEN - HEADER_LEN - TAG_LEN;
+
+const KIND_HELLO: u8 = 1;
+const KIND_DATA: u8 = 2;
+const SALT_LEN: usize = 8;
+const COUNTER_LEN: usize = 5;
+const HEADER_LEN: usize = 1 + COUNTER_LEN;
+const TAG_LEN: usize = 16;
+const MAX_COUNTER: u64 = (1 << (8 * COUNTER_LEN)) - 1;
+
+/// Half that sends with the cipher. Keeps the nonces of both directions apart
+#[derive(Clone, Copy, Eq, PartialEq)]
+pub enum Role {
+    Master = 0,
+    Slave = 1,
+}
+
+/// Result of opening a received report
+pub enum Opened {
+    /// The report was decrypted into the payload
+    Data,
+    /// The other half sent its salt. Returns true if the salt changed, which
+    /// means the other half restarted and should get a hello back
+    Hello(bool),
+    /// The report was forged, replayed or not sealed
+    Rejected,
+}
+
+pub struct LinkCipher {
+    cipher: XChaCha20Poly1305,
+    role: Role,
+    salt: [u8; SALT_LEN],
+    peer_salt: Option<[u8; SALT_LEN]>,
+    tx_counter: u64,
+    // Counter of the last frame accepted from the other half
+    rx_counter: Option<u64>,
+    hello_pending: bool,
+}
+
+impl LinkCipher {
+    pub fn new(key: &PairingKey, role: Role) -> Self {
+        let mut salt = [0u8; SALT_LEN];
+        RoscRng.fill_bytes(&mut salt);
+        Self {
+            cipher: XChaCha20Poly1305::new((&key.0).into()),
+            role,
+            salt,
+            peer_salt: None,
+            tx_counter: 0,
+            rx_counter: None,
+            hello_pending: true,
+        }
+    }
+
+    /// Returns true if a hello should be written before the next data frame
+    pub fn needs_hello(&self) -> bool {
+        self.hello_pending || self.peer_salt.is_none()
+    }
+
+    /// Writes a hello frame with this half's salt into frame
+    pub fn hello(&mut self, frame: &mut [u8; FRAME_LEN]) {
+        frame.fill(0);
+        frame[0] = KIND_HELLO;
+        frame[1..1 + SALT_LEN].copy_from_slice(&self.salt);
+        self.hello_pending = false;
+    }
+
+    /// Seals payload into frame. Returns false if the other half's salt isn't
+    /// known yet, in which case the payload can't be sent
+    pub fn seal(&mut self, payload: &[u8], frame: &mut [u8; FRAME_LEN]) -> bool {
+        let Some(peer_salt) = self.peer_salt else {
+            return false;
+        };
+        if self.tx_counter > MAX_COUNTER {
+            warn!("Link counter exhausted, restart the halves");
+            return false;
+        }
+        let len = payload.len().min(MAX_PAYLOAD_LEN);
+        frame.fill(0);
+        frame[0] = KIND_DATA;
+        frame[1..HEADER_LEN].copy_from_slice(&self.tx_counter.to_le_bytes()[..COUNTER_LEN]);
+        frame[HEADER_LEN..HEADER_LEN + len].copy_from_slice(&payload[..len]);
+        let nonce = nonce(self.role, &self.salt, &peer_salt, self.tx_counter);
+        let (header, body) = frame.split_at_mut(HEADER_LEN);
+        let (data, tag) = body.split_at_mut(MAX_PAYLOAD_LEN);
+        let Ok(sealed_tag) = self.cipher.encrypt_in_place_detached(&nonce, header, data) else {
+            return false;
+        };
+        tag.copy_from_slice(&sealed_tag);
+        self.tx_counter += 1;
+        true
+    }
+
+    /// Opens a frame sent by the other half. Data is decrypted into payload
+    pub fn open(&mut self, frame: &[u8; FRAME_LEN], payload: &mut [u8; MAX_PAYLOAD_LEN]) -> Opened {
+        match frame[0] {
+            KIND_HELLO => {
+                let mut salt = [0u8; SALT_LEN];
+                salt.copy_from_slice(&frame[1..1 + SALT_LEN]);
+                let changed = self.peer_salt != Some(salt);
+                if changed {
+                    if self.rx_counter.is_some() {
+                        RoscRng.fill_bytes(&mut self.salt);
+                        self.tx_counter = 0;
+                    }
+                    self.peer_salt = Some(salt);
+                    self.rx_counter = None;
+                    self.hello_pending = true;
+                }
+                Opened::Hello(changed)
+            }
+            KIND_DATA => {
+                let Some(peer_salt) = self.peer_salt else {
+                    return Opened::Rejected;
+                };
+                let mut counter = [0u8; 8];
+                counter[..COUNTER_LEN].copy_from_slice(&frame[1..HEADER_LEN]);
+                let counter = u64::from_le_bytes(counter);
+                if self.rx_counter.is_some_and(|last| counter <= last) {
+                    return Opened::Rejected;
+                }
+                let peer_role = match self.role {
+                    Role::Master => Role::Slave,
+                    Role::Slave => Role::Master,
+                };
+                let nonce = nonce(peer_role, &peer_salt, &self.salt, counter);
+                payload.copy_from_slice(&frame[HEADER_LEN..HEADER_LEN + MAX_PAYLOAD_LEN]);
+                let tag = Tag::from_slice(&frame[HEADER_LEN + MAX_PAYLOAD_LEN..]);
+                match self.cipher.decrypt_in_place_detached(
+                    &nonce,
+                    &frame[..HEADER_LEN],
+                    payload,
+                    tag,
+                ) {
+                    Ok(()) => {
+                        self.rx_counter = Some(counter);
+                        Opened::Data
+                    }
+                    Err(_) => Opened::Rejected,
+                }
+            }
+            _ => Opened::Rejected,
+        }
+    }
+}
+
+// Nonce of a frame sent by sender, which is unique as long as the sender's
+// salt is fresh and its counter never repeats
+fn nonce(
+    sender: Role,
+    sender_salt: &[u8; SALT_LEN],
+    receiver_salt: &[u8; SALT_LEN],
+    counter: u64,
+) -> XNonce {
+    let mut nonce = XNonce::default();
+    nonce[0] = sender as u8;
+    nonce[1..1 + SALT_LEN].copy_from_slice(sender_salt);
+    nonce[1 + SALT_LEN..1 + 2 * SALT_LEN].copy_from_slice(receiver_salt);
+    nonce[1 + 2 * SALT_LEN..1 + 2 * SALT_LEN + COUNTER_LEN]
+        .copy_from_slice(&counter.to_le_bytes()[..COUNTER_LEN]);
+    nonce
+}
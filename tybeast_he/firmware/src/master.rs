@@ -0,0 +1,302 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use defmt::info;
+use embassy_futures::join::{join, join3, join5};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::mutex::Mutex;
+use embassy_time::{Duration, Timer};
+use embassy_usb::class::hid::HidWriter;
+use embassy_usb::driver::EndpointError;
+use key_lib::board::Side;
+use key_lib::boot_protocol::boot_protocol;
+use key_lib::com::{Com, KeyboardState};
+use key_lib::descriptor::{KeyboardReportNKRO, BOOT_REPORT_LEN};
+use key_lib::dynamic_macro::load_dynamic_macro;
+use key_lib::error::{record_endpoint_error, Endpoint, KeyLibError};
+use key_lib::factory_reset::{wait_for_factory_reset, ResetChord};
+use key_lib::key_stats::load_key_stats;
+use key_lib::keys::{ConfigIndicator, Indicate, Keys, SlaveKeys};
+use key_lib::latency::{record_flips, report_written};
+use key_lib::os::{detection_loop, load_os_override};
+#[cfg(feature = "digitizer")]
+use key_lib::pointer::digitizer_loop;
+use key_lib::position::{
+    handle_calibration, load_calibration, publish_readings, HeSwitch, KeySensors, KeyState,
+    SlavePosition,
+};
+use key_lib::report::{MouseOutput, Report, ReportSink};
+use key_lib::scan::{pause_exceeded, scan_interval, wait_for_scan};
+use key_lib::slave_com::load_pairing_key;
+use key_lib::watchdog::{check_in, idle, Watched};
+use key_lib::NUM_KEYS;
+
+use crate::board::{half_keys, RESET_CHORD};
+use crate::half::{ComEndpoints, Led, ReportWriters, UsbDriver};
+use crate::indicator::{Indicator, MasterIndicatorTask};
+use crate::link_crypto::{LinkCipher, Role};
+use crate::sensors::MasterSensors;
+use crate::slave_com::{HidMasterTask, LinkReader, LinkWriter};
+
+// Time a factory reset is shown on the led before the half reboots
+const RESET_INDICATE_TIME: Duration = Duration::from_secs(2);
+
+/// Runs the master half, which scans the slave half over the slave link and
+/// sends the reports of the whole board to the host
+pub async fn run<R: LinkReader, W: LinkWriter, S: KeySensors<Item = u16>>(
+    mut writers: ReportWriters<'_>,
+    (com_reader, com_writer): ComEndpoints<'_>,
+    slave_link: (R, W),
+    half_sensors: S,
+    led: Led<'_>,
+    side: Side,
+) {
+    let hid_master_task = HidMasterTask::new();
+    let mut key_sensors = MasterSensors::new(half_sensors, hid_master_task.chan(), side);
+    let indicator_task = MasterIndicatorTask::new(led, hid_master_task.chan());
+
+    let mut keys = Keys::default();
+    keys.set_indicator(Indicator {});
+    let _ = keys.load_keys_from_storage(0).await;
+    load_os_override().await;
+    load_dynamic_macro().await;
+    load_key_stats().await;
+
+    let master_state = MasterState::new(keys);
+
+    let mut com = Com::new(&master_state, com_reader, com_writer);
+    let mut slave = SlaveKeys::new(hid_master_task.chan());
+    let key_loop = async {
+        let mut report = Report::new();
+        let mut sink = UsbSink {
+            keys: &mut writers.keys,
+            boot_keys: &mut writers.boot_keys,
+            #[cfg(feature = "mouse")]
+            mouse: &mut writers.mouse,
+        };
+        let local = half_keys(side);
+        let chord = RESET_CHORD.map(|i| local.start + i);
+        let mut reset_chord = ResetChord::new(&chord);
+        let mut positions = [HeSwitch::DEFAULT; NUM_KEYS];
+        positions
+            .iter_mut()
+            .enumerate()
+            .filter(|(i, _)| !local.contains(i))
+            .for_each(|(_, x)| *x = HeSwitch::Slave(SlavePosition::DEFAULT));
+        // Calibrations are stored by the index of the key on its half, the
+        // same whether the half runs as the master or the slave
+        load_calibration(&mut positions[local.clone()]).await;
+        loop {
+            check_in(Watched::Keys);
+            // Storage pauses the scan while it writes to flash. Keys are
+            // released on the host if the pause takes too long
+            let released = pause_exceeded().await;
+            if !released {
+                master_state
+                    .keys
+                    .lock()
+                    .await
+                    .apply_position_types(&mut positions);
+                handle_calibration(&mut positions[local.clone()]).await;
+                key_sensors.update_positions(&mut positions).await;
+                reset_chord.update(|i| positions[i].is_pressed());
+                record_flips(&positions);
+                publish_readings(&positions);
+            }
+            let is_slave = master_state.is_slave.load(Ordering::Acquire);
+            if is_slave {
+                if !released {
+                    idle(Watched::Keys, slave.send_report(&positions[local.clone()])).await;
+                }
+            } else {
+                let sent = if released {
+                    report.send_release_all(&mut sink).await
+                } else {
+                    report
+                        .send_report(&master_state.keys, &positions, &mut sink)
+                        .await
+                };
+                if sent.is_err() {
+                    // The dropped reports are sent again once the host is
+                    // back so it doesn't miss a release
+                    record_endpoint_error(Endpoint::Report);
+                    idle(Watched::Keys, sink.keys.ready()).await;
+                }
+            }
+            if released {
+                idle(Watched::Keys, wait_for_scan()).await;
+            }
+            Timer::after(scan_interval()).await;
+        }
+    };
+
+    // The link is only encrypted if both halves were given the pairing key
+    let cipher = load_pairing_key()
+        .await
+        .map(|key| LinkCipher::new(&key, Role::Master));
+    #[cfg(feature = "digitizer")]
+    let digitizer_task = digitizer_loop(&mut writers.digitizer);
+    #[cfg(not(feature = "digitizer"))]
+    let digitizer_task = async {};
+    // Storage only holds the keymaps, so the built-in one is back once it's
+    // erased. The other settings are reloaded by the reboot
+    let reset_task = async {
+        wait_for_factory_reset().await;
+        let mut keys = master_state.keys.lock().await;
+        *keys = Keys::default();
+        keys.set_indicator(Indicator {});
+        drop(keys);
+        Indicator {}.indicate_config(Indicate::FactoryReset).await;
+        Timer::after(RESET_INDICATE_TIME).await;
+        cortex_m::peripheral::SCB::sys_reset();
+    };
+    join3(
+        join5(
+            com.com_loop(),
+            indicator_task.run(),
+            detection_loop(),
+            digitizer_task,
+            reset_task,
+        ),
+        key_loop,
+        hid_master_task.run(slave_link, cipher),
+    )
+    .await;
+}
+
+/// Com state of the master half, which handles the keys of the whole board
+struct MasterState {
+    keys: Mutex<CriticalSectionRawMutex, Keys<Indicator>>,
+    is_slave: AtomicBool,
+}
+
+impl MasterState {
+    pub fn new(keys: Keys<Indicator>) -> Self {
+        Self {
+            keys: Mutex::new(keys),
+            is_slave: AtomicBool::new(false),
+        }
+    }
+}
+
+impl KeyboardState for MasterState {
+    async fn handle_request<'d, T: embassy_usb::driver::Driver<'d>>(
+        &self,
+        request: key_lib::com::HidRequest,
+        reader: &mut key_lib::com::ContinuousReader<'d, T>,
+        writer: &mut key_lib::com::ContinuousWriter<'d, T>,
+    ) -> Result<(), KeyLibError> {
+        match request {
+            key_lib::com::HidRequest::UpdateKeys => {
+                self.keys.handle_request(request, reader, writer).await
+            }
+            key_lib::com::HidRequest::KeyboardInfo => {
+                self.keys.handle_request(request, reader, writer).await
+            }
+            key_lib::com::HidRequest::WriteToFlash => {
+                self.keys.handle_request(request, reader, writer).await
+            }
+            key_lib::com::HidRequest::KeyboardMetaInfo => {
+                self.keys.handle_request(request, reader, writer).await
+            }
+            key_lib::com::HidRequest::CurrentMode => {
+                let is_slave = self.is_slave.load(Ordering::Acquire) as u8;
+                writer.write(&[is_slave]).await?;
+                writer.flush().await
+            }
+            key_lib::com::HidRequest::ToggleSlave => {
+                let is_slave = self.is_slave.load(Ordering::Acquire);
+                self.is_slave.store(!is_slave, Ordering::Release);
+                Ok(())
+            }
+            key_lib::com::HidRequest::BatteryThresholds
+            | key_lib::com::HidRequest::UpdateBatteryThresholds
+            | key_lib::com::HidRequest::Combos
+            | key_lib::com::HidRequest::UpdateCombos
+            | key_lib::com::HidRequest::SocdPairs
+            | key_lib::com::HidRequest::UpdateSocdPairs
+            | key_lib::com::HidRequest::LinkStatus
+            | key_lib::com::HidRequest::LinkStats
+            | key_lib::com::HidRequest::ForwardToHalf
+            | key_lib::com::HidRequest::DfuStart
+            | key_lib::com::HidRequest::DfuData
+            | key_lib::com::HidRequest::DfuFinish
+            | key_lib::com::HidRequest::ReadSetting
+            | key_lib::com::HidRequest::WriteSetting
+            | key_lib::com::HidRequest::Lighting
+            | key_lib::com::HidRequest::UpdateLighting
+            | key_lib::com::HidRequest::HostLeds
+            | key_lib::com::HidRequest::LastError
+            | key_lib::com::HidRequest::SwitchProfiles
+            | key_lib::com::HidRequest::UpdateSwitchProfiles
+            | key_lib::com::HidRequest::StreamAnalog
+            | key_lib::com::HidRequest::Calibration
+            | key_lib::com::HidRequest::Diagnostics
+            | key_lib::com::HidRequest::SimKeys
+            | key_lib::com::HidRequest::InjectKey
+            | key_lib::com::HidRequest::LatencyTracking
+            | key_lib::com::HidRequest::SwitchHost
+            | key_lib::com::HidRequest::WatchLink
+            | key_lib::com::HidRequest::ExportConfig
+            | key_lib::com::HidRequest::ImportConfig
+            | key_lib::com::HidRequest::CompactStorage
+            | key_lib::com::HidRequest::ReactionTest
+            | key_lib::com::HidRequest::Bootloader
+            | key_lib::com::HidRequest::KeymapChecksum
+            | key_lib::com::HidRequest::KeyStats
+            | key_lib::com::HidRequest::SelfTest
+            | key_lib::com::HidRequest::FactoryReset
+            | key_lib::com::HidRequest::HalfLayer
+            | key_lib::com::HidRequest::UpdateHalfLayer => {
+                self.keys.handle_request(request, reader, writer).await
+            }
+        }
+    }
+}
+
+/// Sends the reports to the host over the usb endpoints
+struct UsbSink<'a, 'd> {
+    keys: &'a mut HidWriter<'d, UsbDriver, 29>,
+    boot_keys: &'a mut HidWriter<'d, UsbDriver, BOOT_REPORT_LEN>,
+    #[cfg(feature = "mouse")]
+    mouse: &'a mut HidWriter<'d, UsbDriver, 5>,
+}
+
+impl ReportSink for UsbSink<'_, '_> {
+    type Error = EndpointError;
+
+    #[cfg_attr(not(feature = "mouse"), allow(unused_variables))]
+    async fn send(
+        &mut self,
+        key_report: Option<&KeyboardReportNKRO>,
+        mouse_report: MouseOutput<'_>,
+    ) -> Result<(), EndpointError> {
+        let (keys, boot_keys) = (&mut self.keys, &mut self.boot_keys);
+        let key_task = async {
+            let Some(rep) = key_report else {
+                return Ok(());
+            };
+            info!("Writing key report!");
+            if boot_protocol() {
+                boot_keys.write(&rep.to_boot()).await?;
+            } else {
+                keys.write_serialize(rep).await?;
+            }
+            report_written();
+            Ok::<(), EndpointError>(())
+        };
+        #[cfg(feature = "mouse")]
+        let mouse = &mut self.mouse;
+        #[cfg(feature = "mouse")]
+        let mouse_task = async {
+            match mouse_report {
+                Some(rep) => mouse.write_serialize(rep).await,
+                None => Ok(()),
+            }
+        };
+        #[cfg(not(feature = "mouse"))]
+        let mouse_task = async { Ok::<(), EndpointError>(()) };
+        // A suspended host doesn't poll for reports
+        let (key_res, mouse_res) = idle(Watched::Keys, join(key_task, mouse_task)).await;
+        key_res.and(mouse_res)
+    }
+}
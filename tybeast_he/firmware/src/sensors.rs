@@ -1,17 +1,35 @@
+use core::ops::Range;
+
 use embassy_rp::{
     adc::{Adc, Async, Channel},
     gpio::Output,
 };
 use embassy_sync::{blocking_mutex::raw::ThreadModeRawMutex, channel::Receiver};
-use embassy_time::Timer;
+#[cfg(feature = "sim")]
+use embassy_time::Duration;
+use embassy_time::{Instant, Timer};
+use heapless::Deque;
 
 use key_lib::{
+    board::Side,
+    error::{record_error, KeyLibError},
     position::{KeySensors, KeyState},
-    slave_com::Master,
+    slave_com::{dequantize_depth, Master},
     NUM_KEYS,
 };
 
-use crate::slave_com::HidMaster;
+#[cfg(feature = "sim")]
+use key_lib::{
+    position::{DEFAULT_HIGH, DEFAULT_LOW},
+    sim::{SimPattern, SimSensors},
+};
+
+use crate::board::half_keys;
+use crate::slave_com::{HidMaster, SlaveEvent};
+
+// Local scans that can be held back. Covers the longest skew made up for at
+// the fastest scan rate, older scans are applied early once it fills
+const HELD_SCANS: usize = 64;
 
 pub struct HallEffectSensors<'p, 'd, const N: usize, const M: usize> {
     chans: [Channel<'p>; N],
@@ -58,7 +76,11 @@ impl<'p, 'd, const N: usize, const M: usize> KeySensors for HallEffectSensors<'p
                 change_sel(&mut self.sel, sel);
                 Timer::after_micros(1).await;
             }
-            positions[pos].update_buf(self.adc.read(&mut self.chans[chan]).await.unwrap());
+            // Keys keep their last position if the reading failed
+            match self.adc.read(&mut self.chans[chan]).await {
+                Ok(reading) => positions[pos].update_buf(reading),
+                Err(_) => record_error(KeyLibError::Sensor),
+            }
         }
     }
 
@@ -72,7 +94,13 @@ impl<'p, 'd, const N: usize, const M: usize> KeySensors for HallEffectSensors<'p
                     let sel = i / self.chans.len();
                     change_sel(&mut self.sel, sel);
                 }
-                let res = positions[pos].setup(self.adc.read(&mut self.chans[chan]).await.unwrap());
+                let res = match self.adc.read(&mut self.chans[chan]).await {
+                    Ok(reading) => positions[pos].setup(reading),
+                    Err(_) => {
+                        record_error(KeyLibError::Sensor);
+                        false
+                    }
+                };
                 // If any key isn't setup, the && will cause setup to be false leading to setup
                 // being false after the loop
                 setup = setup && res;
@@ -81,35 +109,166 @@ impl<'p, 'd, const N: usize, const M: usize> KeySensors for HallEffectSensors<'p
     }
 }
 
-pub struct MasterSensors<'p, 'd, 'ch, const N: usize, const M: usize> {
-    sensors: HallEffectSensors<'p, 'd, N, M>,
+/// Sensors of a half on a bare Pico. Taps the keys of the half one after
+/// another so the firmware can be exercised without the PCB
+#[cfg(feature = "sim")]
+pub fn sim_sensors() -> SimSensors<u16> {
+    SimSensors::new(
+        NUM_KEYS / 2,
+        DEFAULT_HIGH as u16,
+        DEFAULT_LOW as u16,
+        SimPattern::Sweep {
+            hold: Duration::from_millis(50),
+            gap: Duration::from_millis(450),
+        },
+    )
+}
+
+/// Readings of the local keys taken in one scan
+#[derive(Clone, Copy)]
+struct LocalScan {
+    at: Instant,
+    readings: [u16; NUM_KEYS / 2],
+}
+
+/// Captures the readings of the local sensors so the scan can be held back
+#[derive(Clone, Copy)]
+struct Reading(u16);
+
+impl KeyState for Reading {
+    const DEFAULT: Self = Self(0);
+    type Item = u16;
+
+    fn update_buf(&mut self, buf: Self::Item) {
+        self.0 = buf;
+    }
+
+    fn is_pressed(&self) -> bool {
+        false
+    }
+
+    fn reset(&mut self) {
+        self.0 = 0;
+    }
+
+    fn is_analog(&self) -> bool {
+        true
+    }
+
+    fn get_buf(&self) -> Self::Item {
+        self.0
+    }
+
+    fn calibrate(&mut self, _: Self::Item) {}
+
+    fn setup(&mut self, _: Self::Item) -> bool {
+        true
+    }
+}
+
+/// Sensors of the master half combined with the key states sent by the
+/// slave half over the slave link. Either half can be the master, so each
+/// half's keys go to its side of the keymap.
+///
+/// Slave states reach the master a link latency after they changed, so the
+/// local scans are held back by the measured latency and both halves are
+/// applied in the order their keys changed. A slave state ends the update, so
+/// local keys that changed after it land in the next report
+///
+/// Slaves that stream the travel of their keys have it applied as soon as it
+/// arrives, so rapid trigger and the switch profiles work on both halves
+pub struct MasterSensors<'ch, S> {
+    sensors: S,
     slave_chan: HidMaster<'ch>,
+    readings: [Reading; NUM_KEYS],
+    // Keys of the master and of the slave in the keymap
+    local: Range<usize>,
+    remote: Range<usize>,
+    held: Deque<LocalScan, HELD_SCANS>,
+    // Slave state waiting for the local scans before it
+    pending: Option<SlaveEvent>,
 }
 
-impl<'p, 'd, 'ch, const N: usize, const M: usize> MasterSensors<'p, 'd, 'ch, N, M> {
-    pub fn new(
-        chans: [Channel<'p>; N],
-        sel: [Output<'p>; M],
-        adc: Adc<'d, Async>,
-        slave_chan: HidMaster<'ch>,
-        order: [usize; NUM_KEYS / 2],
-    ) -> Self {
+impl<'ch, S: KeySensors<Item = u16>> MasterSensors<'ch, S> {
+    pub fn new(sensors: S, slave_chan: HidMaster<'ch>, side: Side) -> Self {
+        let other = match side {
+            Side::Left => Side::Right,
+            Side::Right => Side::Left,
+        };
         Self {
-            sensors: HallEffectSensors::new(chans, sel, adc, order),
+            sensors,
             slave_chan,
+            readings: [Reading::DEFAULT; NUM_KEYS],
+            local: half_keys(side),
+            remote: half_keys(other),
+            held: Deque::new(),
+            pending: None,
         }
     }
 }
 
-impl<'p, 'd, 'ch, const N: usize, const M: usize> KeySensors for MasterSensors<'p, 'd, 'ch, N, M> {
+fn apply_local<T: KeyState<Item = u16>>(positions: &mut [T], scan: &LocalScan) {
+    for (position, &reading) in positions.iter_mut().zip(&scan.readings) {
+        position.update_buf(reading);
+    }
+}
+
+fn apply_slave<T: KeyState<Item = u16>>(positions: &mut [T], event: SlaveEvent) {
+    for (i, position) in positions.iter_mut().enumerate() {
+        let val = (event.state >> i) & 1;
+        position.update_buf(val as u16);
+    }
+}
+
+impl<'ch, S: KeySensors<Item = u16>> KeySensors for MasterSensors<'ch, S> {
     type Item = u16;
     async fn update_positions<T: KeyState<Item = Self::Item>>(&mut self, positions: &mut [T]) {
-        self.sensors.update_positions(positions).await;
-        if let Some(slave_rep) = self.slave_chan.try_get_slave_state() {
-            let offset = NUM_KEYS / 2;
-            for i in 0..(offset) {
-                let val = (slave_rep >> i) & 1;
-                positions[i + offset].update_buf(val as u16);
+        self.sensors.update_positions(&mut self.readings).await;
+        let now = Instant::now();
+        let mut scan = LocalScan {
+            at: now,
+            readings: [0; NUM_KEYS / 2],
+        };
+        for (reading, captured) in scan.readings.iter_mut().zip(&self.readings) {
+            *reading = captured.0;
+        }
+        if self.held.is_full() {
+            if let Some(oldest) = self.held.pop_front() {
+                apply_local(&mut positions[self.local.clone()], &oldest);
+            }
+        }
+        let _ = self.held.push_back(scan);
+        if self.pending.is_none() {
+            self.pending = self.slave_chan.try_get_slave_state();
+        }
+
+        let due = now
+            .checked_sub(self.slave_chan.skew())
+            .unwrap_or(Instant::MIN);
+        loop {
+            let local = self.held.front().filter(|scan| scan.at <= due);
+            match (local, self.pending) {
+                (Some(scan), Some(event)) if event.at < scan.at => {
+                    apply_slave(&mut positions[self.remote.clone()], event);
+                    self.pending = None;
+                    break;
+                }
+                (Some(_), _) => {
+                    if let Some(scan) = self.held.pop_front() {
+                        apply_local(&mut positions[self.local.clone()], &scan);
+                    }
+                }
+                (None, Some(event)) if event.at <= due => {
+                    apply_slave(&mut positions[self.remote.clone()], event);
+                    self.pending = None;
+                    break;
+                }
+                _ => break,
+            }
+        }
+        if let Some(depths) = self.slave_chan.take_depths() {
+            for (position, &level) in positions[self.remote.clone()].iter_mut().zip(&depths) {
+                position.update_depth(dequantize_depth(level));
             }
         }
     }
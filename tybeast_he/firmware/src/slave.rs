@@ -0,0 +1,172 @@
+use defmt::{error, info};
+use embassy_futures::join::{join, join3};
+use embassy_time::Timer;
+use key_lib::bootloader::request_bootloader;
+use key_lib::com::{
+    Com, ContinuousReader, ContinuousWriter, HidRequest, KeyboardState, SettingStatus,
+    COM_PROTOCOL_VERSION,
+};
+use key_lib::crash::last_crash;
+use key_lib::diagnostics::{
+    boot_report, write_diagnostic, write_unsupported, DiagnosticKind, HealthReport,
+};
+use key_lib::error::KeyLibError;
+use key_lib::keys::SlaveKeys;
+use key_lib::position::{
+    handle_calibration, load_calibration, request_calibration, CalibrationCommand, KeySensors,
+    KeyState, WootingPosition,
+};
+use key_lib::scan::{scan_interval, wait_for_scan};
+use key_lib::slave_com::load_pairing_key;
+use key_lib::storage::{get_item, store_val, SettingId, StorageKey, MAX_SETTING_LEN};
+use key_lib::watchdog::{check_in, idle, Watched};
+use key_lib::{IS_SPLIT, NUM_CONFIGS, NUM_KEYS, NUM_LAYERS};
+
+use crate::board::HALF_KEYS;
+use crate::half::{ComEndpoints, Led};
+use crate::indicator::SlaveIndicatorTask;
+use crate::link_crypto::{LinkCipher, Role};
+use crate::slave_com::{HidSlaveTask, LinkReader, LinkWriter};
+
+/// Runs the slave half, which sends its key positions to the master half
+pub async fn run<R: LinkReader, W: LinkWriter, S: KeySensors<Item = u16>>(
+    (com_reader, com_writer): ComEndpoints<'_>,
+    slave_link: (R, W),
+    mut sensors: S,
+    led: Led<'_>,
+) {
+    let slave_hid_task = HidSlaveTask::new();
+    let indicator_task = SlaveIndicatorTask::new(led, slave_hid_task.chan());
+    let mut keys = SlaveKeys::<u32, _>::new(slave_hid_task.chan());
+    let depths = slave_hid_task.chan();
+
+    let slave_state = SlaveHalfState {};
+    let mut com = Com::new(&slave_state, com_reader, com_writer);
+
+    // Main keyboard loop
+    let mut positions = [WootingPosition::DEFAULT; HALF_KEYS];
+    let key_loop = async {
+        load_calibration(&mut positions).await;
+        loop {
+            check_in(Watched::Keys);
+            // Storage pauses the scan while it writes to flash
+            idle(Watched::Keys, wait_for_scan()).await;
+            handle_calibration(&mut positions).await;
+            sensors.update_positions(&mut positions).await;
+            // Reports queue up while the link to the master is down
+            idle(Watched::Keys, keys.send_report(&positions)).await;
+            depths.set_depths(&positions);
+            Timer::after(scan_interval()).await;
+        }
+    };
+    let cipher = load_pairing_key()
+        .await
+        .map(|key| LinkCipher::new(&key, Role::Slave));
+    join3(
+        key_loop,
+        join(slave_hid_task.run(slave_link, cipher), indicator_task.run()),
+        com.com_loop(),
+    )
+    .await;
+}
+
+/// Com state of the slave half. Keys are handled by the master half, so only
+/// the meta info can be read, the side can be read and written to switch halves without a strap pin, the
+/// pairing key of the link can be written, the slave half's switches can be
+/// calibrated and its health can be read
+struct SlaveHalfState {}
+
+impl KeyboardState for SlaveHalfState {
+    async fn handle_request<'d, T: embassy_usb::driver::Driver<'d>>(
+        &self,
+        request: HidRequest,
+        reader: &mut ContinuousReader<'d, T>,
+        writer: &mut ContinuousWriter<'d, T>,
+    ) -> Result<(), KeyLibError> {
+        match request {
+            // Answered so host tools that open the slave half get a reply.
+            // It has no keymap, so the config and its checksum are 0
+            HidRequest::KeyboardMetaInfo => {
+                writer
+                    .write(&[
+                        NUM_CONFIGS as u8,
+                        NUM_KEYS as u8,
+                        NUM_LAYERS as u8,
+                        IS_SPLIT as u8,
+                        0,
+                    ])
+                    .await?;
+                writer.write(&0u32.to_le_bytes()).await?;
+                writer.write(&[COM_PROTOCOL_VERSION]).await?;
+                writer.flush().await
+            }
+            HidRequest::ReadSetting => {
+                let id = reader.pop().await?;
+                let _config_num = reader.pop().await?;
+                if !matches!(SettingId::try_from(id), Ok(SettingId::Side)) {
+                    writer.write(&[SettingStatus::Invalid as u8, 0]).await?;
+                    return writer.flush().await;
+                }
+                let item = match get_item(StorageKey::Side).await {
+                    Some(item) => item,
+                    None => SettingId::Side.default_item(),
+                };
+                let mut buf = [0u8; MAX_SETTING_LEN];
+                let len = item.serialize_into(&mut buf)?;
+                writer.write(&[SettingStatus::Ok as u8, len as u8]).await?;
+                writer.write(&buf[..len]).await?;
+                writer.flush().await
+            }
+            HidRequest::WriteSetting => {
+                let id = reader.pop().await?;
+                let _config_num = reader.pop().await?;
+                let len = reader.pop().await? as usize;
+                let (setting, key) = match SettingId::try_from(id) {
+                    Ok(SettingId::Side) => (SettingId::Side, StorageKey::Side),
+                    Ok(SettingId::PairingKey) => (SettingId::PairingKey, StorageKey::PairingKey),
+                    _ => {
+                        error!("Received invalid setting {}", id);
+                        return Ok(());
+                    }
+                };
+                if len > MAX_SETTING_LEN {
+                    error!("Received invalid setting {} of len {}", id, len);
+                    return Ok(());
+                }
+                let mut buf = [0u8; MAX_SETTING_LEN];
+                reader.pop_slice(&mut buf[..len]).await?;
+                let item = setting.deserialize(&buf[..len])?;
+                info!("Updating setting {}, takes effect on the next boot", id);
+                store_val(key, &item).await;
+                Ok(())
+            }
+            HidRequest::Bootloader => {
+                request_bootloader();
+                Ok(())
+            }
+            HidRequest::Calibration => {
+                let command = reader.pop().await?;
+                match CalibrationCommand::try_from(command) {
+                    Ok(command) => request_calibration(command),
+                    Err(_) => error!("Received invalid calibration command {}", command),
+                }
+                Ok(())
+            }
+            HidRequest::Diagnostics => {
+                let kind = reader.pop().await?;
+                match DiagnosticKind::try_from(kind) {
+                    Ok(DiagnosticKind::Health) => {
+                        write_diagnostic(writer, &HealthReport::current()).await
+                    }
+                    Ok(DiagnosticKind::Boot) => match boot_report() {
+                        Some(report) => write_diagnostic(writer, &report).await,
+                        None => write_unsupported(writer, kind).await,
+                    },
+                    Ok(DiagnosticKind::Crash) => write_diagnostic(writer, &last_crash()).await,
+                    _ => write_unsupported(writer, kind).await,
+                }
+            }
+            _ => Ok(()),
+        }
+    }
+}
@@ -1,34 +1,115 @@
-use core::{array, cell::RefCell, ops::DerefMut};
+use core::{
+    array,
+    cell::{Cell, RefCell},
+    ops::DerefMut,
+};
 
-use embassy_futures::join::join;
+use defmt::{info, warn};
+use embassy_futures::{
+    join::{join3, join4},
+    select::{select, Either},
+};
 use embassy_sync::{
-    blocking_mutex::raw::ThreadModeRawMutex,
+    blocking_mutex::{self, raw::ThreadModeRawMutex},
     channel::{Channel, Receiver, Sender},
+    signal::Signal,
 };
+use embassy_time::{Duration, Instant, Timer};
 use embassy_usb::{
-    class::hid::{HidReader, HidReaderWriter, HidWriter},
+    class::hid::{HidReader, HidWriter},
     driver::Driver,
 };
 use key_lib::{
     descriptor::SlaveReport,
-    slave_com::{Master, MasterRequest, Slave, SlaveRespone, SlaveState},
+    error::{record_endpoint_error, Endpoint},
+    position::KeyState,
+    slave_com::{
+        decode_state_age, encode_state_age, peer_present, quantize_depth, set_peer_present,
+        Heartbeat, LinkLatency, Master, MasterRequest, Slave, SlaveCapabilities, SlaveRespone,
+        SlaveState, DEPTH_INTERVAL, HEARTBEAT_INTERVAL, SLAVE_CAPABILITIES_SERIAL_LENGTH,
+    },
 };
 
+use crate::board::HALF_KEYS;
+use crate::election::Claim;
+use crate::link_crypto::{LinkCipher, Opened, FRAME_LEN, MAX_PAYLOAD_LEN};
+
 const CHANNEL_SIZE: usize = 5;
 
+// Time between handshake attempts while the slave hasn't answered or has
+// been quiet
+const HANDSHAKE_INTERVAL: Duration = Duration::from_secs(1);
+
+// Time without a report from the slave before it's treated as disconnected
+const PEER_TIMEOUT: Duration = Duration::from_secs(3);
+
+// Longest link latency the master makes up for by holding back its own keys.
+// Slower links are only partly made up for
+const MAX_SKEW: Duration = Duration::from_millis(4);
+
+// Byte of the slave report holding the age of its key state. Last byte of the
+// payload so it fits in a sealed report after the longest response
+const STATE_AGE_INDEX: usize = MAX_PAYLOAD_LEN - 1;
+
+/// Keys whose depths fit in a single response, two to a byte after the tag
+/// and the index of the first key
+pub const DEPTHS_PER_RESPONSE: usize = 2 * (STATE_AGE_INDEX - 4 - 2);
+
+// Number of responses that carry the depths of every key of a half
+const DEPTH_CHUNKS: usize = HALF_KEYS.div_ceil(DEPTHS_PER_RESPONSE);
+
+// Depth of a key the slave hasn't sent yet, higher than any quantized level
+const UNSENT_DEPTH: u8 = u8::MAX;
+
+/// Key state of the slave with the time it changed, in the master's clock
+#[derive(Clone, Copy)]
+pub struct SlaveEvent {
+    pub state: u32,
+    pub at: Instant,
+}
+
+/// Version of this firmware build as major, minor and patch
+pub fn firmware_version() -> [u8; 3] {
+    [
+        env!("CARGO_PKG_VERSION_MAJOR").parse().unwrap_or(0),
+        env!("CARGO_PKG_VERSION_MINOR").parse().unwrap_or(0),
+        env!("CARGO_PKG_VERSION_PATCH").parse().unwrap_or(0),
+    ]
+}
+
+/// Capabilities of this firmware build, sent to the master in the handshake
+pub fn capabilities() -> SlaveCapabilities {
+    SlaveCapabilities::new(
+        SlaveCapabilities::ANALOG_STREAMING
+            | SlaveCapabilities::INDICATOR
+            | SlaveCapabilities::HOST_LEDS
+            | SlaveCapabilities::TIME_SYNC
+            | SlaveCapabilities::DEPTH_STREAMING,
+        firmware_version(),
+    )
+}
+
 pub enum HidRequest {
-    ConfigIndicate(u8),
+    /// Config number and the indicator color of the config. Black shows the
+    /// board's color, which is also what older masters send since the rest of
+    /// the report is zeroed
+    ConfigIndicate(u8, [u8; 3]),
     SlaveReport(u32),
     HallEffectReading(u8),
+    Handshake,
+    HostLeds(u8),
+    /// Timed by the master to measure the link latency
+    Ping(u8),
 }
 
 impl HidRequest {
     pub fn send_request(&self, buf: &mut [u8]) -> usize {
         match *self {
-            HidRequest::ConfigIndicate(val) => {
+            HidRequest::ConfigIndicate(val, color) => {
                 buf[0] = self.index() as u8;
                 buf[1] = val;
-                2
+                buf[2..5].copy_from_slice(&color);
+                5
             }
             HidRequest::SlaveReport(rep) => {
                 buf[0] = self.index() as u8;
@@ -40,25 +121,45 @@ impl HidRequest {
                 buf[1] = i;
                 2
             }
+            HidRequest::Handshake => {
+                buf[0] = self.index() as u8;
+                1
+            }
+            HidRequest::HostLeds(leds) => {
+                buf[0] = self.index() as u8;
+                buf[1] = leds;
+                2
+            }
+            HidRequest::Ping(seq) => {
+                buf[0] = self.index() as u8;
+                buf[1] = seq;
+                2
+            }
         }
     }
 
     pub fn index(&self) -> usize {
         match self {
-            Self::ConfigIndicate(_) => 0,
+            Self::ConfigIndicate(..) => 0,
             Self::SlaveReport(_) => 1,
             Self::HallEffectReading(_) => 2,
+            Self::Handshake => 3,
+            Self::HostLeds(_) => 4,
+            Self::Ping(_) => 5,
         }
     }
 
     pub fn get_request(buf: &[u8]) -> Option<HidRequest> {
         match buf[0] {
-            0 => Some(Self::ConfigIndicate(buf[1])),
+            0 => Some(Self::ConfigIndicate(buf[1], [buf[2], buf[3], buf[4]])),
             1 => {
                 let res = u32::from_le_bytes(buf[1..5].try_into().unwrap());
                 Some(Self::SlaveReport(res))
             }
             2 => Some(Self::HallEffectReading(buf[1])),
+            3 => Some(Self::Handshake),
+            4 => Some(Self::HostLeds(buf[1])),
+            5 => Some(Self::Ping(buf[1])),
             _ => None,
         }
     }
@@ -70,24 +171,74 @@ impl MasterRequest for HidRequest {
 
 pub enum HidResponse {
     HallEffectReading(u16),
+    Handshake(SlaveCapabilities),
+    Pong(u8),
+    /// Sent every [HEARTBEAT_INTERVAL] with its sequence number, so the
+    /// master can tell the slave stopped even while its keys are held
+    Heartbeat(u8),
+    /// Quantized depths of the keys from the first index, sent every
+    /// [DEPTH_INTERVAL] while they change
+    Depths {
+        first: u8,
+        levels: [u8; DEPTHS_PER_RESPONSE],
+    },
 }
 
 impl HidResponse {
     pub fn get_response(buf: &[u8]) -> Option<HidResponse> {
         const HALL_INDEX: u8 = HidResponse::HallEffectReading(0).index() as u8;
+        const HANDSHAKE_INDEX: u8 = HidResponse::Handshake(SlaveCapabilities::LEGACY).index() as u8;
+        const PONG_INDEX: u8 = HidResponse::Pong(0).index() as u8;
+        const HEARTBEAT_INDEX: u8 = HidResponse::Heartbeat(0).index() as u8;
+        const DEPTHS_INDEX: u8 = HidResponse::Depths {
+            first: 0,
+            levels: [0; DEPTHS_PER_RESPONSE],
+        }
+        .index() as u8;
         match buf[0] {
             0 => None,
             HALL_INDEX => {
                 let reading = u16::from_le_bytes([buf[1], buf[2]]);
                 Some(HidResponse::HallEffectReading(reading))
             }
+            HANDSHAKE_INDEX => Some(HidResponse::Handshake(SlaveCapabilities::from_buffer(
+                &buf[1..],
+            ))),
+            PONG_INDEX => Some(HidResponse::Pong(buf[1])),
+            HEARTBEAT_INDEX => Some(HidResponse::Heartbeat(buf[1])),
+            DEPTHS_INDEX => {
+                let mut levels = [0; DEPTHS_PER_RESPONSE];
+                for (i, level) in levels.iter_mut().enumerate() {
+                    *level = (buf[2 + i / 2] >> (4 * (i % 2))) & 0xF;
+                }
+                Some(HidResponse::Depths {
+                    first: buf[1],
+                    levels,
+                })
+            }
             _ => None,
         }
     }
 
+    /// Tag of the response in the slave report
     pub const fn index(&self) -> usize {
         match self {
             HidResponse::HallEffectReading(_) => 2,
+            HidResponse::Handshake(_) => 3,
+            HidResponse::Pong(_) => 4,
+            HidResponse::Heartbeat(_) => 5,
+            HidResponse::Depths { .. } => 6,
+        }
+    }
+
+    /// Index of the response's channel on the master
+    const fn slot(&self) -> usize {
+        match self {
+            HidResponse::HallEffectReading(_) => 0,
+            HidResponse::Handshake(_) => 1,
+            HidResponse::Pong(_) => 2,
+            HidResponse::Heartbeat(_) => 3,
+            HidResponse::Depths { .. } => 4,
         }
     }
 
@@ -98,6 +249,24 @@ impl HidResponse {
                 buf[1..3].copy_from_slice(&val.to_le_bytes());
                 3
             }
+            HidResponse::Handshake(capabilities) => {
+                buf[0] = self.index() as u8;
+                capabilities.into_buffer(&mut buf[1..]);
+                1 + SLAVE_CAPABILITIES_SERIAL_LENGTH
+            }
+            HidResponse::Pong(seq) | HidResponse::Heartbeat(seq) => {
+                buf[0] = self.index() as u8;
+                buf[1] = seq;
+                2
+            }
+            HidResponse::Depths { first, levels } => {
+                buf[0] = self.index() as u8;
+                buf[1] = first;
+                for (byte, pair) in buf[2..].iter_mut().zip(levels.chunks(2)) {
+                    *byte = pair[0] | pair[1] << 4;
+                }
+                2 + DEPTHS_PER_RESPONSE / 2
+            }
         }
     }
 }
@@ -106,11 +275,100 @@ impl SlaveRespone for HidResponse {
     type MasterRequest = HidRequest;
 }
 
+/// Receiving end of the link between the halves
+#[allow(async_fn_in_trait)]
+pub trait LinkReader {
+    /// Waits for the next report from the other half
+    async fn receive(&mut self, buf: &mut [u8; 32]);
+}
+
+/// Sending end of the link between the halves
+#[allow(async_fn_in_trait)]
+pub trait LinkWriter {
+    async fn send(&mut self, rep: &SlaveReport);
+}
+
+// Reports relayed by the host through the slave endpoint
+impl<'d, T: Driver<'d>> LinkReader for HidReader<'d, T, 32> {
+    async fn receive(&mut self, buf: &mut [u8; 32]) {
+        while self.read(buf).await.is_err() {
+            record_endpoint_error(Endpoint::Link);
+            self.ready().await;
+        }
+    }
+}
+
+impl<'d, T: Driver<'d>> LinkWriter for HidWriter<'d, T, 32> {
+    // A dropped report is treated like one lost on the link
+    async fn send(&mut self, rep: &SlaveReport) {
+        if self.write_serialize(rep).await.is_err() {
+            record_endpoint_error(Endpoint::Link);
+            self.ready().await;
+        }
+    }
+}
+
+/// Decrypts the report in place if the link is encrypted. Reports of an
+/// unencrypted link are always data
+fn open_report(cipher: &RefCell<Option<LinkCipher>>, buf: &mut [u8; 32]) -> Opened {
+    let mut cipher = cipher.borrow_mut();
+    let Some(cipher) = cipher.as_mut() else {
+        return Opened::Data;
+    };
+    let mut payload = [0u8; MAX_PAYLOAD_LEN];
+    let opened = cipher.open(buf, &mut payload);
+    if let Opened::Data = opened {
+        buf.fill(0);
+        buf[..MAX_PAYLOAD_LEN].copy_from_slice(&payload);
+    }
+    opened
+}
+
+/// Writes the report, sealed if the link is encrypted. A hello goes first
+/// while the other half doesn't know our salt
+async fn write_report<W: LinkWriter>(
+    writer: &mut W,
+    cipher: &RefCell<Option<LinkCipher>>,
+    mut rep: SlaveReport,
+) {
+    let mut hello = None;
+    let send = match cipher.borrow_mut().as_mut() {
+        Some(cipher) => {
+            if cipher.needs_hello() {
+                let mut frame = [0u8; FRAME_LEN];
+                cipher.hello(&mut frame);
+                hello = Some(frame);
+            }
+            let payload = rep.input;
+            cipher.seal(&payload[..MAX_PAYLOAD_LEN], &mut rep.input)
+        }
+        None => true,
+    };
+    if let Some(frame) = hello {
+        let hello_rep = SlaveReport {
+            input: frame,
+            ..Default::default()
+        };
+        writer.send(&hello_rep).await;
+    }
+    if send {
+        writer.send(&rep).await;
+    }
+}
+
 pub struct HidMasterTask {
-    slave_chan: Channel<ThreadModeRawMutex, u32, CHANNEL_SIZE>,
+    slave_chan: Channel<ThreadModeRawMutex, SlaveEvent, CHANNEL_SIZE>,
     requests: Channel<ThreadModeRawMutex, HidRequest, CHANNEL_SIZE>,
     responses: [Channel<ThreadModeRawMutex, HidResponse, CHANNEL_SIZE>;
         core::mem::variant_count::<HidResponse>()],
+    // Set once the slave answers the handshake
+    capabilities: blocking_mutex::Mutex<ThreadModeRawMutex, Cell<Option<SlaveCapabilities>>>,
+    latency: blocking_mutex::Mutex<ThreadModeRawMutex, Cell<LinkLatency>>,
+    // Set to send an established claim, see crate::election
+    claim: Signal<ThreadModeRawMutex, ()>,
+    // Quantized depths of the slave's keys, signaled whenever some arrive
+    depths: blocking_mutex::Mutex<ThreadModeRawMutex, Cell<[u8; HALF_KEYS]>>,
+    depths_changed: Signal<ThreadModeRawMutex, ()>,
 }
 
 #[allow(clippy::new_without_default)]
@@ -120,6 +378,11 @@ impl HidMasterTask {
             slave_chan: Channel::new(),
             requests: Channel::new(),
             responses: array::from_fn(|_| Channel::new()),
+            capabilities: blocking_mutex::Mutex::new(Cell::new(None)),
+            latency: blocking_mutex::Mutex::new(Cell::new(LinkLatency::new())),
+            claim: Signal::new(),
+            depths: blocking_mutex::Mutex::new(Cell::new([0; HALF_KEYS])),
+            depths_changed: Signal::new(),
         }
     }
 
@@ -128,19 +391,105 @@ impl HidMasterTask {
             slave_rec: self.slave_chan.receiver(),
             requests: self.requests.sender(),
             responses: &self.responses,
+            capabilities: &self.capabilities,
+            latency: &self.latency,
+            depths: &self.depths,
+            depths_changed: &self.depths_changed,
         }
     }
 
-    pub async fn run<'d, T: Driver<'d>>(&self, hid: HidReaderWriter<'d, T, 32, 32>) {
-        let (mut reader, mut writer) = hid.split();
+    fn one_way(&self) -> Option<Duration> {
+        self.latency.lock(|latency| latency.get().one_way())
+    }
+
+    fn supports_time_sync(&self) -> bool {
+        self.capabilities.lock(|c| {
+            c.get()
+                .is_some_and(|c| c.supports(SlaveCapabilities::TIME_SYNC))
+        })
+    }
+
+    /// Runs the link to the slave over the given reader and writer, e.g. the
+    /// split slave endpoint or a [crate::uart_link]. Reports are encrypted if
+    /// a cipher is given
+    pub async fn run<R: LinkReader, W: LinkWriter>(
+        &self,
+        (mut reader, mut writer): (R, W),
+        cipher: Option<LinkCipher>,
+    ) {
+        let cipher = RefCell::new(cipher);
+        let last_report = Cell::new(None::<Instant>);
+        let heartbeat = Cell::new(Heartbeat::new());
+        // Sequence and send time of the last ping
+        let ping = Cell::new(None::<(u8, Instant)>);
         let read_loop = async {
             loop {
                 let mut buf = [0u8; 32];
-                reader.read(&mut buf).await.unwrap();
+                reader.receive(&mut buf).await;
+                // A half that just enumerated, answered so it becomes the slave
+                if Claim::read(&buf).is_some() {
+                    self.claim.signal(());
+                    continue;
+                }
+                match open_report(&cipher, &mut buf) {
+                    Opened::Data => last_report.set(Some(Instant::now())),
+                    // The slave restarted, so it needs our salt again
+                    Opened::Hello(true) => {
+                        self.requests.send(HidRequest::Handshake).await;
+                        continue;
+                    }
+                    Opened::Hello(false) | Opened::Rejected => continue,
+                }
                 let slave_state = u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]);
-                self.slave_chan.send(slave_state).await;
-                if let Some(resp) = HidResponse::get_response(&buf[4..]) {
-                    self.responses[resp.index()].send(resp).await;
+                // The state changed its age plus the link latency ago. Slaves
+                // without time sync leave the age at 0
+                let delay =
+                    self.one_way().unwrap_or_default() + decode_state_age(buf[STATE_AGE_INDEX]);
+                let at = Instant::now().checked_sub(delay).unwrap_or(Instant::MIN);
+                self.slave_chan
+                    .send(SlaveEvent {
+                        state: slave_state,
+                        at,
+                    })
+                    .await;
+                match HidResponse::get_response(&buf[4..STATE_AGE_INDEX]) {
+                    Some(HidResponse::Handshake(capabilities)) => {
+                        self.capabilities.lock(|c| c.set(Some(capabilities)));
+                    }
+                    Some(HidResponse::Pong(seq)) => {
+                        if let Some((sent_seq, sent_at)) = ping.get() {
+                            if sent_seq == seq {
+                                let round_trip = sent_at.elapsed();
+                                self.latency.lock(|latency| {
+                                    let mut new_latency = latency.get();
+                                    new_latency.add(round_trip);
+                                    latency.set(new_latency);
+                                });
+                                ping.set(None);
+                            }
+                        }
+                    }
+                    Some(HidResponse::Heartbeat(seq)) => {
+                        let mut new_heartbeat = heartbeat.get();
+                        let missed = new_heartbeat.beat(seq, Instant::now());
+                        heartbeat.set(new_heartbeat);
+                        if missed > 0 {
+                            warn!("Missed {} slave heartbeats", missed);
+                        }
+                    }
+                    Some(HidResponse::Depths { first, levels }) => {
+                        let first = first as usize;
+                        self.depths.lock(|depths| {
+                            let mut new_depths = depths.get();
+                            if let Some(keys) = new_depths.get_mut(first..) {
+                                keys.iter_mut().zip(levels).for_each(|(key, l)| *key = l);
+                            }
+                            depths.set(new_depths);
+                        });
+                        self.depths_changed.signal(());
+                    }
+                    Some(resp) => self.responses[resp.slot()].send(resp).await,
+                    None => {}
                 }
             }
         };
@@ -148,30 +497,126 @@ impl HidMasterTask {
         let write_loop = async {
             loop {
                 let mut rep = SlaveReport::default();
-                let req = self.requests.receive().await;
-                req.send_request(&mut rep.input);
-                writer.write_serialize(&rep).await.unwrap();
+                match select(self.requests.receive(), self.claim.wait()).await {
+                    Either::First(req) => {
+                        req.send_request(&mut rep.input);
+                        write_report(&mut writer, &cipher, rep).await;
+                    }
+                    // Claims are never sealed so a half without a role can read them
+                    Either::Second(()) => writer.send(&Claim::Established.report()).await,
+                }
+            }
+        };
+
+        // The slave may start after the master, so keep asking until it
+        // answers. An idle slave from before heartbeats only sends reports
+        // when asked, so it's asked again whenever it goes quiet to tell if
+        // it's still connected
+        // Slaves with time sync are also pinged to track the link latency
+        let handshake_loop = async {
+            let mut seq = 0u8;
+            loop {
+                let quiet = last_report.get().map(|time| time.elapsed());
+                let present = quiet.is_some_and(|quiet| quiet < PEER_TIMEOUT);
+                if present != peer_present() {
+                    info!("Slave connected: {}", present);
+                    set_peer_present(present);
+                    if !present {
+                        self.latency.lock(|latency| latency.set(LinkLatency::new()));
+                        // Slaves without heartbeats are only released here
+                        self.release_slave_keys().await;
+                    }
+                }
+                if quiet.is_none_or(|quiet| quiet >= HANDSHAKE_INTERVAL) {
+                    self.requests.send(HidRequest::Handshake).await;
+                }
+                // A slave that restarted waits for a claim before it answers
+                if !present {
+                    self.claim.signal(());
+                }
+                if present && self.supports_time_sync() {
+                    seq = seq.wrapping_add(1);
+                    ping.set(Some((seq, Instant::now())));
+                    self.requests.send(HidRequest::Ping(seq)).await;
+                }
+                Timer::after(HANDSHAKE_INTERVAL).await;
+            }
+        };
+
+        // A slave that resets or locks up with keys held never sends the
+        // report releasing them, so they're released once its heartbeats stop
+        let heartbeat_loop = async {
+            loop {
+                Timer::after(HEARTBEAT_INTERVAL).await;
+                let mut new_heartbeat = heartbeat.get();
+                let stopped = new_heartbeat.stopped(Instant::now());
+                heartbeat.set(new_heartbeat);
+                if stopped {
+                    warn!("Slave heartbeats stopped, releasing its keys");
+                    self.release_slave_keys().await;
+                }
             }
         };
-        join(read_loop, write_loop).await;
+        join4(read_loop, write_loop, handshake_loop, heartbeat_loop).await;
+    }
+
+    async fn release_slave_keys(&self) {
+        self.depths.lock(|depths| depths.set([0; HALF_KEYS]));
+        self.depths_changed.signal(());
+        self.slave_chan
+            .send(SlaveEvent {
+                state: 0,
+                at: Instant::now(),
+            })
+            .await;
     }
 }
 
 pub struct HidMaster<'ch> {
-    slave_rec: Receiver<'ch, ThreadModeRawMutex, u32, CHANNEL_SIZE>,
+    slave_rec: Receiver<'ch, ThreadModeRawMutex, SlaveEvent, CHANNEL_SIZE>,
     requests: Sender<'ch, ThreadModeRawMutex, HidRequest, CHANNEL_SIZE>,
     responses: &'ch [Channel<ThreadModeRawMutex, HidResponse, CHANNEL_SIZE>;
              core::mem::variant_count::<HidResponse>()],
+    capabilities: &'ch blocking_mutex::Mutex<ThreadModeRawMutex, Cell<Option<SlaveCapabilities>>>,
+    latency: &'ch blocking_mutex::Mutex<ThreadModeRawMutex, Cell<LinkLatency>>,
+    depths: &'ch blocking_mutex::Mutex<ThreadModeRawMutex, Cell<[u8; HALF_KEYS]>>,
+    depths_changed: &'ch Signal<ThreadModeRawMutex, ()>,
 }
 
 impl<'ch> HidMaster<'ch> {
+    /// Returns the quantized depths of the slave's keys if any arrived since
+    /// the last call. Slaves without depth streaming never send them
+    pub fn take_depths(&self) -> Option<[u8; HALF_KEYS]> {
+        self.depths_changed
+            .try_take()
+            .map(|()| self.depths.lock(|depths| depths.get()))
+    }
+
+    /// Time the master holds back its own keys so they line up with the
+    /// slave's, which reach it a link latency late. Zero until the latency is
+    /// measured
+    pub fn skew(&self) -> Duration {
+        self.latency
+            .lock(|latency| latency.get().one_way())
+            .unwrap_or_default()
+            .min(MAX_SKEW)
+    }
+
     pub async fn get_response_copy(&self, resp: &mut HidResponse) {
-        *resp = self.responses[resp.index()].receive().await;
+        *resp = self.responses[resp.slot()].receive().await;
     }
 
     pub fn try_send_request(&self, request: HidRequest) {
         self.requests.try_send(request);
     }
+
+    /// Returns the capabilities the slave advertised in the handshake. Slaves
+    /// that haven't answered are assumed to run a build from before the handshake
+    pub fn capabilities(&self) -> SlaveCapabilities {
+        self.capabilities
+            .lock(|c| c.get())
+            .unwrap_or(SlaveCapabilities::LEGACY)
+    }
 }
 
 impl<'ch> Master for HidMaster<'ch> {
@@ -179,7 +624,7 @@ impl<'ch> Master for HidMaster<'ch> {
 
     type Response = HidResponse;
 
-    type SlaveState = u32;
+    type SlaveState = SlaveEvent;
 
     async fn send_request(&self, request: Self::Request) {
         self.requests.send(request).await;
@@ -202,7 +647,10 @@ pub struct HidSlaveTask {
     requests: [Channel<ThreadModeRawMutex, HidRequest, CHANNEL_SIZE>;
         core::mem::variant_count::<HidRequest>()],
     responses: Channel<ThreadModeRawMutex, HidResponse, CHANNEL_SIZE>,
-    slave_state: Channel<ThreadModeRawMutex, u32, CHANNEL_SIZE>,
+    // Key states with the time they changed
+    slave_state: Channel<ThreadModeRawMutex, (u32, Instant), CHANNEL_SIZE>,
+    // Quantized depths of the keys from the last scan
+    depths: blocking_mutex::Mutex<ThreadModeRawMutex, Cell<[u8; HALF_KEYS]>>,
 }
 
 #[allow(clippy::new_without_default)]
@@ -212,6 +660,7 @@ impl HidSlaveTask {
             requests: array::from_fn(|_| Channel::new()),
             responses: Channel::new(),
             slave_state: Channel::new(),
+            depths: blocking_mutex::Mutex::new(Cell::new([0; HALF_KEYS])),
         }
     }
 
@@ -220,30 +669,112 @@ impl HidSlaveTask {
             requests: &self.requests,
             responses: self.responses.sender(),
             slave_state: self.slave_state.sender(),
+            depths: &self.depths,
         }
     }
 
-    pub async fn run<'d, T: Driver<'d>>(&self, hid: HidReaderWriter<'d, T, 32, 32>) {
-        let (mut reader, mut writer) = hid.split();
+    /// Runs the link to the master over the given reader and writer. Reports
+    /// are encrypted if a cipher is given
+    pub async fn run<R: LinkReader, W: LinkWriter>(
+        &self,
+        (mut reader, mut writer): (R, W),
+        cipher: Option<LinkCipher>,
+    ) {
+        let cipher = RefCell::new(cipher);
+        // Depths last sent to the master
+        let sent_depths = Cell::new([UNSENT_DEPTH; HALF_KEYS]);
         let read_loop = async {
             loop {
                 let mut buf = [0u8; 32];
-                reader.read(&mut buf).await.unwrap();
-                if let Some(req) = HidRequest::get_request(&buf) {
-                    self.requests[req.index()].send(req).await;
+                reader.receive(&mut buf).await;
+                match open_report(&cipher, &mut buf) {
+                    Opened::Data => {}
+                    // Answering wakes the write loop, which sends our salt first
+                    Opened::Hello(true) => {
+                        self.responses
+                            .send(HidResponse::Handshake(capabilities()))
+                            .await;
+                        continue;
+                    }
+                    Opened::Hello(false) | Opened::Rejected => continue,
+                }
+                match HidRequest::get_request(&buf) {
+                    Some(HidRequest::Handshake) => {
+                        // The master may have restarted, so every depth is sent again
+                        sent_depths.set([UNSENT_DEPTH; HALF_KEYS]);
+                        self.responses
+                            .send(HidResponse::Handshake(capabilities()))
+                            .await;
+                    }
+                    Some(HidRequest::Ping(seq)) => {
+                        self.responses.send(HidResponse::Pong(seq)).await;
+                    }
+                    Some(req) => self.requests[req.index()].send(req).await,
+                    None => {}
                 }
             }
         };
 
         let write_loop = async {
+            let mut slave_state = 0u32;
+            let mut changed_at = Instant::now();
             loop {
                 let mut slave_report = SlaveReport::default();
-                let slave_state = self.slave_state.receive().await;
+                // Responses are sent along with the last slave state
+                match select(self.slave_state.receive(), self.responses.receive()).await {
+                    Either::First((state, at)) => {
+                        slave_state = state;
+                        changed_at = at;
+                    }
+                    Either::Second(resp) => {
+                        resp.send_response(&mut slave_report.input[4..]).await;
+                    }
+                }
                 slave_report.input[0..4].copy_from_slice(&slave_state.to_le_bytes());
-                writer.write_serialize(&slave_report).await.unwrap();
+                slave_report.input[STATE_AGE_INDEX] = encode_state_age(changed_at.elapsed());
+                write_report(&mut writer, &cipher, slave_report).await;
             }
         };
-        join(read_loop, write_loop).await;
+
+        // Numbered from 0 after a restart, see key_lib::slave_com::Heartbeat
+        let heartbeat_loop = async {
+            let mut seq = 0u8;
+            loop {
+                self.responses.send(HidResponse::Heartbeat(seq)).await;
+                seq = seq.checked_add(1).unwrap_or(1);
+                Timer::after(HEARTBEAT_INTERVAL).await;
+            }
+        };
+        // Sends the next chunk of depths that changed, so a few keys moving
+        // don't hold up the others
+        let depth_loop = async {
+            let mut next = 0;
+            loop {
+                Timer::after(DEPTH_INTERVAL).await;
+                let depths = self.depths.lock(|depths| depths.get());
+                let mut sent = sent_depths.get();
+                for _ in 0..DEPTH_CHUNKS {
+                    let first = next * DEPTHS_PER_RESPONSE;
+                    let keys = first..(first + DEPTHS_PER_RESPONSE).min(HALF_KEYS);
+                    next = (next + 1) % DEPTH_CHUNKS;
+                    if depths[keys.clone()] == sent[keys.clone()] {
+                        continue;
+                    }
+                    sent[keys.clone()].copy_from_slice(&depths[keys.clone()]);
+                    sent_depths.set(sent);
+                    let mut levels = [0; DEPTHS_PER_RESPONSE];
+                    levels[..keys.len()].copy_from_slice(&depths[keys]);
+                    self.responses
+                        .send(HidResponse::Depths {
+                            first: first as u8,
+                            levels,
+                        })
+                        .await;
+                    break;
+                }
+            }
+        };
+        join4(read_loop, write_loop, heartbeat_loop, depth_loop).await;
     }
 }
 
@@ -271,10 +802,20 @@ pub struct HidSlave<'ch> {
     requests: &'ch [Channel<ThreadModeRawMutex, HidRequest, CHANNEL_SIZE>;
              core::mem::variant_count::<HidRequest>()],
     responses: Sender<'ch, ThreadModeRawMutex, HidResponse, CHANNEL_SIZE>,
-    slave_state: Sender<'ch, ThreadModeRawMutex, u32, CHANNEL_SIZE>,
+    slave_state: Sender<'ch, ThreadModeRawMutex, (u32, Instant), CHANNEL_SIZE>,
+    depths: &'ch blocking_mutex::Mutex<ThreadModeRawMutex, Cell<[u8; HALF_KEYS]>>,
 }
 
 impl<'ch> HidSlave<'ch> {
+    /// Records the travel of the half's keys to be streamed to the master
+    pub fn set_depths<K: KeyState>(&self, positions: &[K]) {
+        let mut depths = [0; HALF_KEYS];
+        for (depth, position) in depths.iter_mut().zip(positions) {
+            *depth = quantize_depth(position.travel());
+        }
+        self.depths.lock(|cell| cell.set(depths));
+    }
+
     pub async fn get_request_ref(&self, req: &mut HidRequest) {
         *req = self.requests[req.index()].receive().await;
     }
@@ -296,6 +837,6 @@ impl<'ch> Slave for HidSlave<'ch> {
     }
 
     async fn send_slave_state(&self, state: Self::SlaveState) {
-        self.slave_state.send(state).await;
+        self.slave_state.send((state, Instant::now())).await;
     }
 }
@@ -0,0 +1,99 @@
+//! Wired link between the halves over a UART, so only one half needs to be
+//! plugged into the host. The slave endpoint link relies on the host to relay
+//! reports, this one sends them straight to the other half.
+//!
+//! The UART is a byte stream, so each report is sent in a frame that starts
+//! with a sync byte and ends with a crc. A half that starts listening mid
+//! frame drops bytes until a frame checks out.
+
+use defmt::{error, warn};
+use embassy_rp::uart::{BufferedUart, BufferedUartRx, BufferedUartTx, Config};
+use embedded_io_async::{Read, Write};
+use key_lib::descriptor::SlaveReport;
+use static_cell::StaticCell;
+
+use crate::board::{Irqs, LinkResources};
+use crate::slave_com::{LinkReader, LinkWriter};
+
+pub const LINK_BAUDRATE: u32 = 1_000_000;
+
+const SYNC: u8 = 0xa5;
+const REPORT_LEN: usize = 32;
+const FRAME_LEN: usize = 1 + REPORT_LEN + 1;
+// Room for a few frames, the link tasks read them as they come
+const BUF_LEN: usize = 4 * FRAME_LEN;
+
+static TX_BUF: StaticCell<[u8; BUF_LEN]> = StaticCell::new();
+static RX_BUF: StaticCell<[u8; BUF_LEN]> = StaticCell::new();
+
+/// Sets up the UART and splits it into the ends given to
+/// HidMasterTask::run or HidSlaveTask::run. Can only be called once
+pub fn uart_link(res: LinkResources) -> (UartLinkReader, UartLinkWriter) {
+    let mut config = Config::default();
+    config.baudrate = LINK_BAUDRATE;
+    let uart = BufferedUart::new(
+        res.uart,
+        res.tx,
+        res.rx,
+        Irqs,
+        TX_BUF.init([0; BUF_LEN]),
+        RX_BUF.init([0; BUF_LEN]),
+        config,
+    );
+    let (tx, rx) = uart.split();
+    (UartLinkReader { rx }, UartLinkWriter { tx })
+}
+
+// CRC-8 with the polynomial 0x07
+fn crc8(data: &[u8]) -> u8 {
+    data.iter().fold(0, |crc, &byte| {
+        (0..8).fold(crc ^ byte, |crc, _| {
+            if crc & 0x80 != 0 {
+                (crc << 1) ^ 0x07
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
+
+pub struct UartLinkReader {
+    rx: BufferedUartRx,
+}
+
+impl LinkReader for UartLinkReader {
+    async fn receive(&mut self, buf: &mut [u8; 32]) {
+        loop {
+            let mut sync = [0u8];
+            if self.rx.read_exact(&mut sync).await.is_err() || sync[0] != SYNC {
+                continue;
+            }
+            let mut frame = [0u8; REPORT_LEN + 1];
+            if self.rx.read_exact(&mut frame).await.is_err() {
+                continue;
+            }
+            if crc8(&frame[..REPORT_LEN]) != frame[REPORT_LEN] {
+                warn!("Dropped link frame with a bad crc");
+                continue;
+            }
+            buf.copy_from_slice(&frame[..REPORT_LEN]);
+            return;
+        }
+    }
+}
+
+pub struct UartLinkWriter {
+    tx: BufferedUartTx,
+}
+
+impl LinkWriter for UartLinkWriter {
+    async fn send(&mut self, rep: &SlaveReport) {
+        let mut frame = [0u8; FRAME_LEN];
+        frame[0] = SYNC;
+        frame[1..=REPORT_LEN].copy_from_slice(&rep.input);
+        frame[FRAME_LEN - 1] = crc8(&rep.input);
+        if let Err(e) = self.tx.write_all(&frame).await {
+            error!("Failed to write link frame: {}", e);
+        }
+    }
+}
@@ -0,0 +1,74 @@
+//! Hardware watchdog and crash records. The watchdog is fed from
+//! key_lib::watchdog, and panics and hard faults are recorded to
+//! [CRASH_PAGE] before resetting, so a locked up half restarts on its own and
+//! the host can read why it did
+
+use core::ops::Range;
+
+use defmt::info;
+use embassy_rp::flash::{Blocking, Flash, ERASE_SIZE};
+use embassy_rp::peripherals::FLASH;
+use embassy_rp::watchdog::{self, Watchdog};
+use embassy_rp::Peri;
+use key_lib::crash::{set_last_crash, CrashRecord, CRASH_RECORD_LEN};
+use key_lib::diagnostics::{set_boot_report, BootReport, ResetReason};
+use key_lib::watchdog::{run_watchdog, Watched, WATCHDOG_TIMEOUT};
+
+use crate::board::{FLASH_END, FLASH_SIZE};
+use crate::slave_com::firmware_version;
+
+/// Flash page crash records are written to, right after the storage range
+pub const CRASH_PAGE: Range<u32> = FLASH_END..FLASH_END + ERASE_SIZE as u32;
+
+#[embassy_executor::task]
+pub async fn watchdog_task(mut watchdog: Watchdog) {
+    // Halting on a breakpoint shouldn't reset the half
+    watchdog.pause_on_debug(true);
+    watchdog.start(WATCHDOG_TIMEOUT);
+    run_watchdog(&[Watched::Keys], || watchdog.feed(WATCHDOG_TIMEOUT)).await
+}
+
+/// Reads why the half booted and the crash record written before it, and
+/// erases the record so it's only reported once
+pub fn load_boot_state(flash: Peri<'_, FLASH>, watchdog: &Watchdog) {
+    // Resets that didn't come from the watchdog, like power on, the run pin
+    // or a reset after a crash, can't be told apart
+    let reset_reason = match watchdog.reset_reason() {
+        Some(watchdog::ResetReason::TimedOut) => ResetReason::Watchdog,
+        Some(watchdog::ResetReason::Forced) => ResetReason::Software,
+        None => ResetReason::Unknown,
+    };
+    set_boot_report(BootReport {
+        version: firmware_version(),
+        reset_reason,
+    });
+
+    let mut flash = Flash::<_, Blocking, FLASH_SIZE>::new_blocking(flash);
+    let mut buf = [0u8; CRASH_RECORD_LEN];
+    if flash.blocking_read(CRASH_PAGE.start, &mut buf).is_err() {
+        return;
+    }
+    if let Some(record) = CrashRecord::from_bytes(&buf) {
+        info!("Crashed before the last reset: {}", record.message());
+        set_last_crash(record);
+    }
+    if buf.iter().any(|byte| *byte != 0xff) {
+        let _ = flash.blocking_erase(CRASH_PAGE.start, CRASH_PAGE.end);
+    }
+}
+
+/// Writes the record to [CRASH_PAGE] and resets. Called from the panic and
+/// HardFault handlers of the bins
+pub fn record_crash(record: &CrashRecord) -> ! {
+    cortex_m::interrupt::disable();
+    // Whatever owned the flash won't run again before the reset
+    let mut flash = Flash::<_, Blocking, FLASH_SIZE>::new_blocking(unsafe { FLASH::steal() });
+    // Nothing can be done about a failed write while crashing
+    if flash
+        .blocking_erase(CRASH_PAGE.start, CRASH_PAGE.end)
+        .is_ok()
+    {
+        let _ = flash.blocking_write(CRASH_PAGE.start, &record.to_bytes());
+    }
+    cortex_m::peripheral::SCB::sys_reset()
+}
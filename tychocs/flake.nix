@@ -27,6 +27,7 @@
               openssl
               pkg-config
               cargo-binutils
+              cargo-bloat
               cargo-make
               probe-rs
               minicom
@@ -1,6 +1,9 @@
 MEMORY {
 
   /* These values correspond to the NRF52840 with Softdevices S140 7.3.0 */
-     FLASH : ORIGIN = 0x00026000, LENGTH = 868K
+  /* The application region ends at 0x87000 to leave room for the left */
+  /* half's storage from 0x87000, the crash page at 0x8F000 and dfu staging */
+  /* from 0x90000 */
+     FLASH : ORIGIN = 0x00026000, LENGTH = 388K
      RAM : ORIGIN = 0x20020000, LENGTH = 128K
 }
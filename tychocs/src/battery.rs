@@ -0,0 +1,170 @@
+use core::sync::atomic::{AtomicBool, AtomicU16, AtomicU8, Ordering};
+
+use embassy_futures::select::{select3, Either3};
+use embassy_nrf::{
+    gpio::Output,
+    saadc::{self, ChannelConfig, Saadc, VddhDiv5Input},
+    Peri,
+};
+use embassy_time::{Duration, Instant, Timer};
+use key_lib::{
+    display,
+    keys::HostLeds,
+    power::{BatteryMonitor, BatteryThresholds, ScanRate, BATTERY_THRESHOLDS},
+};
+
+use crate::link;
+
+static SCAN_RATE: AtomicU8 = AtomicU8::new(ScanRate::Full as u8);
+static MILLIVOLTS: AtomicU16 = AtomicU16::new(0);
+static LOW: AtomicBool = AtomicBool::new(false);
+
+// Time between battery readings. Readings are taken more often once the
+// battery is low or the dongle's firmware doesn't match so the indicator
+// warning keeps blinking
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(30);
+const LOW_SAMPLE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Returns the scan rate picked from the latest battery reading, limited to
+/// the fastest rate the link timing allows. Halves scan at the minimal rate
+/// while the host is asleep
+pub fn scan_rate() -> ScanRate {
+    if link::is_host_asleep() {
+        return ScanRate::Minimal;
+    }
+    let rate = ScanRate::try_from(SCAN_RATE.load(Ordering::Relaxed)).unwrap_or(ScanRate::Full);
+    link::link_timing().limit(rate)
+}
+
+/// Returns the latest battery reading in millivolts, or 0 before the first
+pub fn last_millivolts() -> u16 {
+    MILLIVOLTS.load(Ordering::Relaxed)
+}
+
+/// Returns true if the latest battery reading was low
+pub fn is_low() -> bool {
+    LOW.load(Ordering::Relaxed)
+}
+
+/// Returns true if the board is currently powered over usb
+pub fn usb_powered() -> bool {
+    embassy_nrf::pac::POWER.usbregstatus().read().vbusdetect()
+}
+
+pub struct Battery<'d> {
+    saadc: Saadc<'d, 1>,
+    monitor: BatteryMonitor,
+    led: Output<'d>,
+    // Led is kept on while caps lock is on
+    caps_lock: bool,
+}
+
+impl<'d> Battery<'d> {
+    pub fn new(
+        saadc: Peri<'d, embassy_nrf::peripherals::SAADC>,
+        irq: impl embassy_nrf::interrupt::typelevel::Binding<
+                embassy_nrf::interrupt::typelevel::SAADC,
+                saadc::InterruptHandler,
+            > + 'd,
+        led: Output<'d>,
+    ) -> Self {
+        let channel = ChannelConfig::single_ended(VddhDiv5Input);
+        let saadc = Saadc::new(saadc, irq, saadc::Config::default(), [channel]);
+        Self {
+            saadc,
+            monitor: BatteryMonitor::new(BatteryThresholds::default()),
+            led,
+            caps_lock: false,
+        }
+    }
+
+    /// Reads the battery voltage in millivolts. The battery is connected to VDDH
+    /// which is sampled through the internal divide by 5 input
+    pub async fn millivolts(&mut self) -> u16 {
+        let mut buf = [0i16; 1];
+        self.saadc.sample(&mut buf).await;
+        // 12 bit reading with a 0.6V reference and 1/6 gain gives a 3.6V range
+        let raw = buf[0].max(0) as u32;
+        (raw * 3600 * 5 / 4095) as u16
+    }
+
+    async fn indicate_low(&mut self) {
+        for _ in 0..2 {
+            self.led.set_high();
+            Timer::after_millis(100).await;
+            self.led.set_low();
+            Timer::after_millis(100).await;
+        }
+        self.show_caps_lock();
+    }
+
+    // Long blinks so a version mismatch can't be mistaken for a low battery
+    async fn indicate_mismatch(&mut self) {
+        for _ in 0..3 {
+            self.led.set_high();
+            Timer::after_millis(400).await;
+            self.led.set_low();
+            Timer::after_millis(200).await;
+        }
+        self.show_caps_lock();
+    }
+
+    // The led is dimmed while the host is asleep
+    fn show_caps_lock(&mut self) {
+        if self.caps_lock && !link::is_host_asleep() {
+            self.led.set_high();
+        } else {
+            self.led.set_low();
+        }
+    }
+
+    /// Waits until the next battery sample while showing the host's caps lock
+    async fn wait_sample(&mut self, interval: Duration) {
+        let next_sample = Instant::now() + interval;
+        loop {
+            match select3(
+                Timer::at(next_sample),
+                link::wait_host_leds(),
+                link::wait_power_state(),
+            )
+            .await
+            {
+                Either3::First(_) => return,
+                Either3::Second(leds) => {
+                    self.caps_lock = leds.is_on(HostLeds::CAPS_LOCK);
+                    self.show_caps_lock();
+                }
+                Either3::Third(_) => self.show_caps_lock(),
+            }
+        }
+    }
+
+    /// Periodically samples the battery and updates the scan rate returned
+    /// from [scan_rate]. Blinks the led while the battery is low or the
+    /// dongle runs an incompatible link protocol, unless the host is asleep
+    pub async fn run(mut self) -> ! {
+        self.saadc.calibrate().await;
+        loop {
+            self.monitor
+                .set_thresholds(*BATTERY_THRESHOLDS.lock().await);
+            let millivolts = self.millivolts().await;
+            let rate = self.monitor.update(millivolts, usb_powered());
+            SCAN_RATE.store(rate as u8, Ordering::Relaxed);
+            MILLIVOLTS.store(millivolts, Ordering::Relaxed);
+            display::set_battery(millivolts);
+            LOW.store(self.monitor.is_low(), Ordering::Relaxed);
+            let asleep = link::is_host_asleep();
+            if link::is_version_mismatched() && !asleep {
+                self.indicate_mismatch().await;
+            }
+            if self.monitor.is_low() && !asleep {
+                self.indicate_low().await;
+            }
+            if self.monitor.is_low() || link::is_version_mismatched() {
+                self.wait_sample(LOW_SAMPLE_INTERVAL).await;
+            } else {
+                self.wait_sample(SAMPLE_INTERVAL).await;
+            }
+        }
+    }
+}
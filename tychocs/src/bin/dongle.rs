@@ -1,17 +1,23 @@
 #![no_std]
 #![no_main]
 
+use core::panic::PanicInfo;
 use core::sync::atomic::{AtomicBool, Ordering};
 
 use bruh78::{
+    dfu,
     key_config::set_keys,
-    radio::{self, Addresses, Radio},
-    sensors::DongleSensors,
+    keymap,
+    link::{self, Half},
+    radio::{self, Addresses, Radio, MAX_MESSAGE_LEN},
+    sensors::{DongleSensors, RadioTransport},
+    watchdog::{load_boot_state, record_crash, run_wdt},
+    Switch,
 };
-use cortex_m_rt::entry;
+use cortex_m_rt::{entry, exception, ExceptionFrame};
 use defmt::{info, *};
 use embassy_executor::{Executor, InterruptExecutor};
-use embassy_futures::join::{join, join3, join4};
+use embassy_futures::join::{join, join4, join5};
 use embassy_nrf::{
     bind_interrupts,
     config::HfclkSource,
@@ -28,19 +34,42 @@ use embassy_nrf as _;
 use embassy_sync::{blocking_mutex::raw::ThreadModeRawMutex, mutex::Mutex};
 use embassy_time::Timer;
 use embassy_usb::{
-    class::hid::{HidReaderWriter, HidWriter, State},
+    class::hid::{HidReaderWriter, HidWriter, ReportId, RequestHandler, State},
+    control::OutResponse,
+    driver::EndpointError,
+    types::StringIndex,
     Builder, Handler,
 };
+#[cfg(feature = "mouse")]
+use key_lib::descriptor::MouseReport;
+#[cfg(feature = "mouse")]
+use key_lib::hires_scroll::{reset_resolution, resolution_report, set_resolution_report};
 use key_lib::{
-    com::Com,
-    descriptor::{BufferReport, KeyboardReportNKRO, MouseReport},
-    keys::{ConfigIndicator, Indicate, Keys},
-    position::DefaultSwitch,
+    board::load_report_rate,
+    bootloader::wait_for_bootloader,
+    com::{
+        watch_link, Com, ContinuousReader, ContinuousWriter, ForwardStatus, HidRequest,
+        KeyboardState, MAX_DFU_CHUNK_LEN, MAX_FORWARD_LEN,
+    },
+    crash::{last_crash, CrashRecord},
+    descriptor::{BufferReport, KeyboardReportNKRO},
+    diagnostics::{boot_report, write_diagnostic, write_unsupported, DiagnosticKind, HealthReport},
+    dynamic_macro::load_dynamic_macro,
+    error::{record_endpoint_error, Endpoint, KeyLibError},
+    factory_reset::wait_for_factory_reset,
+    host_switch::load_host_pairings,
+    key_stats::load_key_stats,
+    keys::{ConfigIndicator, HostLeds, Indicate, Keys},
+    os::{
+        detection_loop, load_os_override, record_led_report, record_set_idle,
+        record_string_request, reset_detection, set_configured,
+    },
+    power::{load_battery_thresholds, load_link_timing},
     report::Report,
-    storage::Storage,
+    scan::{pause_exceeded, scan_interval, wait_for_scan},
+    storage::{storage_stats, Storage},
+    watchdog::{check_in, idle, Watched},
 };
-// time driver
-use panic_probe as _;
 use sequential_storage::cache::NoCache;
 use static_cell::StaticCell;
 use usbd_hid::descriptor::SerializedDescriptor;
@@ -66,17 +95,39 @@ async fn storage_task(storage: Storage<Qspi<'static, peripherals::QSPI>>) {
 
 #[embassy_executor::task]
 async fn radio_task(radio: Peri<'static, peripherals::RADIO>) {
-    let addresses = Addresses::default();
+    // Each host's dongle listens on its own addresses
+    let addresses = Addresses::for_host(load_host_pairings().await.own_address());
     let mut radio = Radio::new(radio, Irqs, addresses);
     radio.set_tx_addresses(|w| w.set_txaddress(0));
     radio.set_rx_addresses(|w| {
         w.set_addr1(true);
         w.set_addr2(true);
     });
-    radio.set_tx_power(embassy_nrf::radio::TxPower::POS8_DBM);
+    radio.set_tx_power(radio::DONGLE_TX_POWER);
     radio.run().await;
 }
 
+#[embassy_executor::task]
+async fn watchdog_task(wdt: Peri<'static, peripherals::WDT>) {
+    run_wdt(wdt, &[Watched::Keys, Watched::Radio]).await
+}
+
+#[embassy_executor::task]
+async fn bootloader_task() {
+    wait_for_bootloader().await;
+    dfu::reset_to_bootloader()
+}
+
+/// Restores the built-in keymap once a factory reset erased storage and
+/// reboots so the other settings are loaded again. The dongle has no led, so
+/// the reset shows as the dongle reconnecting to the host
+#[embassy_executor::task]
+async fn factory_reset_task() {
+    wait_for_factory_reset().await;
+    set_keys(&mut *KEYS.lock().await);
+    cortex_m::peripheral::SCB::sys_reset()
+}
+
 #[embassy_executor::task]
 async fn thread_task(usbd: Peri<'static, peripherals::USBD>) {
     let driver = Driver::new(usbd, Irqs, HardwareVbusDetect::new(Irqs));
@@ -99,10 +150,17 @@ async fn thread_task(usbd: Peri<'static, peripherals::USBD>) {
     let mut msos_descriptor = [0; 256];
     let mut control_buf = [0; 64];
 
+    // The nrf52840 only runs at full speed
+    let b_interval = load_report_rate(false).await;
+
     let mut key_state = State::new();
+    #[cfg(feature = "mouse")]
     let mut mouse_state = State::new();
     let mut com_state = State::new();
     let mut device_handler = MyDeviceHandler::new();
+    let mut key_handler = KeyboardRequestHandler {};
+    #[cfg(feature = "mouse")]
+    let mut mouse_handler = MouseRequestHandler {};
 
     let mut builder = Builder::new(
         driver,
@@ -116,8 +174,8 @@ async fn thread_task(usbd: Peri<'static, peripherals::USBD>) {
     // Create classes on the builder.
     let key_config = embassy_usb::class::hid::Config {
         report_descriptor: KeyboardReportNKRO::desc(),
-        request_handler: None,
-        poll_ms: 1,
+        request_handler: Some(&mut key_handler),
+        poll_ms: b_interval,
         max_packet_size: 32,
     };
     let com_config = embassy_usb::class::hid::Config {
@@ -126,53 +184,107 @@ async fn thread_task(usbd: Peri<'static, peripherals::USBD>) {
         poll_ms: 1,
         max_packet_size: 64,
     };
+    #[cfg(feature = "mouse")]
     let mouse_config = embassy_usb::class::hid::Config {
         report_descriptor: MouseReport::desc(),
-        request_handler: None,
-        poll_ms: 1,
+        request_handler: Some(&mut mouse_handler),
+        poll_ms: b_interval,
         max_packet_size: 5,
     };
     builder.handler(&mut device_handler);
     let mut key_writer = HidWriter::<_, 32>::new(&mut builder, &mut key_state, key_config);
     let (com_reader, com_writer) =
         HidReaderWriter::<_, 32, 32>::new(&mut builder, &mut com_state, com_config).split();
+    #[cfg(feature = "mouse")]
     let mut mouse_writer = HidWriter::<_, 5>::new(&mut builder, &mut mouse_state, mouse_config);
 
     // Build the builder.
     let mut usb = builder.build();
     let usb_fut = usb.run();
 
-    let sensors = DongleSensors::new();
-    let mut report: Report<_, DefaultSwitch> = Report::new(sensors);
+    let sensors = DongleSensors::new(RadioTransport::new());
+    let mut report: Report<_, Switch> = Report::new(sensors);
 
     let mut keys = KEYS.lock().await;
     set_keys(&mut keys);
+    keys.set_indicator(Indicator {});
     // keys.load_keys_from_storage(0).await;
     drop(keys);
+    load_os_override().await;
+    load_dynamic_macro().await;
+    load_key_stats().await;
+    link::set_link_timing(load_link_timing().await);
+    link::set_half_thresholds(load_battery_thresholds().await);
 
-    let mut com = Com::new(&KEYS, com_reader, com_writer);
+    let dongle_state = DongleState {};
+    let mut com = Com::new(&dongle_state, com_reader, com_writer);
     let key_loop = async {
         loop {
+            check_in(Watched::Keys);
+            // Storage pauses the scan while it writes to flash. Keys are
+            // released on the host if the pause takes too long
+            let released = pause_exceeded().await;
+            #[cfg_attr(not(feature = "mouse"), allow(unused_variables))]
             let (key_rep, mouse_rep);
-            {
+            if released {
+                (key_rep, mouse_rep) = report.release_all();
+            } else {
                 (key_rep, mouse_rep) = report.generate_report(&KEYS).await;
             }
             let key_task = async {
-                if let Some(rep) = key_rep {
-                    info!("Writing key report!");
-                    key_writer.write_serialize(rep).await.unwrap();
+                match key_rep {
+                    Some(rep) => {
+                        info!("Writing key report!");
+                        key_writer.write_serialize(rep).await
+                    }
+                    None => Ok::<(), EndpointError>(()),
                 }
             };
+            #[cfg(feature = "mouse")]
             let mouse_task = async {
-                if let Some(rep) = mouse_rep {
-                    mouse_writer.write_serialize(rep).await.unwrap();
+                match mouse_rep {
+                    Some(rep) => mouse_writer.write_serialize(rep).await,
+                    None => Ok(()),
                 }
             };
-            join(key_task, mouse_task).await;
-            Timer::after_micros(5).await;
+            #[cfg(not(feature = "mouse"))]
+            let mouse_task = async { Ok::<(), EndpointError>(()) };
+            // A suspended host doesn't poll for reports
+            let (key_res, mouse_res) = idle(Watched::Keys, join(key_task, mouse_task)).await;
+            if key_res.is_err() || mouse_res.is_err() {
+                // The dropped reports are sent again once the host is back so
+                // it doesn't miss a release
+                record_endpoint_error(Endpoint::Report);
+                report.resend();
+                idle(Watched::Keys, key_writer.ready()).await;
+            }
+            if released {
+                idle(Watched::Keys, wait_for_scan()).await;
+            }
+            Timer::after(scan_interval()).await;
         }
     };
-    join3(usb_fut, key_loop, com.com_loop()).await;
+    join4(
+        usb_fut,
+        key_loop,
+        com.com_loop(),
+        join4(
+            link::run_search_indicator(),
+            join5(
+                link::run_host_leds_relay(),
+                link::run_host_switch_relay(),
+                link::run_power_state_relay(),
+                link::run_layer_relay(),
+                link::run_link_timing_relay(),
+            ),
+            detection_loop(),
+            join(
+                link::run_self_test_radio(),
+                link::run_battery_thresholds_relay(),
+            ),
+        ),
+    )
+    .await;
 }
 
 #[interrupt]
@@ -185,6 +297,7 @@ fn main() -> ! {
     let mut nrf_config = embassy_nrf::config::Config::default();
     nrf_config.hfclk_source = HfclkSource::ExternalXtal;
     let p = embassy_nrf::init(nrf_config);
+    load_boot_state(p.NVMC);
 
     embassy_nrf::interrupt::EGU1_SWI1.set_priority(embassy_nrf::interrupt::Priority::P1);
     embassy_nrf::interrupt::RADIO.set_priority(embassy_nrf::interrupt::Priority::P0);
@@ -195,13 +308,221 @@ fn main() -> ! {
     let exectuor = THREAD_EXECUTOR.init_with(Executor::new);
     exectuor.run(|spawner| {
         spawner.spawn(thread_task(p.USBD)).unwrap();
+        spawner.spawn(watchdog_task(p.WDT)).unwrap();
+        spawner.spawn(bootloader_task()).unwrap();
+        spawner.spawn(factory_reset_task()).unwrap();
     });
 }
 
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    error!("{}", Display2Format(info));
+    record_crash(&CrashRecord::panic(info))
+}
+
+#[exception]
+unsafe fn HardFault(frame: &ExceptionFrame) -> ! {
+    record_crash(&CrashRecord::hard_fault(frame.pc()))
+}
+
+/// Com state of the dongle. Answers link requests itself so the
+/// dongle can be checked without any halves connected
+struct DongleState {}
+
+impl KeyboardState for DongleState {
+    async fn handle_request<'d, T: embassy_usb::driver::Driver<'d>>(
+        &self,
+        request: HidRequest,
+        reader: &mut ContinuousReader<'d, T>,
+        writer: &mut ContinuousWriter<'d, T>,
+    ) -> Result<(), KeyLibError> {
+        match request {
+            HidRequest::LinkStatus => {
+                writer.write(&link::status_buffer()).await?;
+                writer.flush().await?;
+            }
+            HidRequest::LinkStats => {
+                writer.write(&link::stats_buffer()).await?;
+                writer.flush().await?;
+            }
+            HidRequest::Diagnostics => {
+                let kind = reader.pop().await?;
+                match DiagnosticKind::try_from(kind) {
+                    Ok(DiagnosticKind::Link) => {
+                        write_diagnostic(writer, &link::link_report()).await?
+                    }
+                    Ok(DiagnosticKind::Health) => {
+                        write_diagnostic(writer, &HealthReport::current()).await?
+                    }
+                    Ok(DiagnosticKind::Storage) => {
+                        write_diagnostic(writer, &storage_stats().await).await?
+                    }
+                    Ok(DiagnosticKind::Boot) => match boot_report() {
+                        Some(report) => write_diagnostic(writer, &report).await?,
+                        None => write_unsupported(writer, kind).await?,
+                    },
+                    Ok(DiagnosticKind::Crash) => write_diagnostic(writer, &last_crash()).await?,
+                    _ => write_unsupported(writer, kind).await?,
+                }
+            }
+            HidRequest::WatchLink => {
+                let interval = reader.pop().await?;
+                reader.flush();
+                watch_link(reader, writer, interval, link::half_link_states).await?;
+            }
+            HidRequest::ForwardToHalf => {
+                let addr = reader.pop().await?;
+                let len = reader.pop().await? as usize;
+                let Some(half) = Half::from_addr(addr).filter(|_| len <= MAX_FORWARD_LEN) else {
+                    writer.write(&[ForwardStatus::Invalid as u8, 0]).await?;
+                    writer.flush().await?;
+                    return Ok(());
+                };
+                let mut buf = [0u8; MAX_FORWARD_LEN];
+                reader.pop_slice(&mut buf[..len]).await?;
+                match link::forward(half, &buf[..len]).await {
+                    Some(response) => {
+                        writer
+                            .write(&[ForwardStatus::Ok as u8, response.len() as u8])
+                            .await?;
+                        writer.write(&response).await?;
+                    }
+                    None => {
+                        writer.write(&[ForwardStatus::Timeout as u8, 0]).await?;
+                    }
+                }
+                writer.flush().await?;
+            }
+            HidRequest::HalfLayer => {
+                let addr = reader.pop().await?;
+                let config_num = reader.pop().await?;
+                let layer = reader.pop().await?;
+                let result = match Half::from_addr(addr) {
+                    Some(half) => keymap::read_layer(half, config_num, layer).await,
+                    None => Err(ForwardStatus::Invalid),
+                };
+                match result {
+                    Ok(message) => {
+                        writer.write(&[ForwardStatus::Ok as u8]).await?;
+                        writer.write(&(message.len() as u16).to_le_bytes()).await?;
+                        writer.write(&message[..]).await?;
+                    }
+                    Err(status) => writer.write(&[status as u8, 0, 0]).await?,
+                }
+                writer.flush().await?;
+            }
+            HidRequest::UpdateHalfLayer => {
+                let addr = reader.pop().await?;
+                let config_num = reader.pop().await?;
+                let layer = reader.pop().await?;
+                let mut len = [0u8; 2];
+                reader.pop_slice(&mut len).await?;
+                let len = u16::from_le_bytes(len) as usize;
+                let Some(half) = Half::from_addr(addr).filter(|_| len <= MAX_MESSAGE_LEN) else {
+                    writer.write(&[ForwardStatus::Invalid as u8, 0]).await?;
+                    writer.flush().await?;
+                    return Ok(());
+                };
+                let mut buf = [0u8; MAX_MESSAGE_LEN];
+                reader.pop_slice(&mut buf[..len]).await?;
+                let status = keymap::write_layer(half, config_num, layer, &buf[..len]).await;
+                writer.write(&[status as u8, 0]).await?;
+                writer.flush().await?;
+            }
+            HidRequest::DfuStart => {
+                let addr = reader.pop().await?;
+                let mut buf = [0u8; 8];
+                reader.pop_slice(&mut buf).await?;
+                let len = u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]);
+                let crc = u32::from_le_bytes([buf[4], buf[5], buf[6], buf[7]]);
+                let status = match Half::from_addr(addr) {
+                    Some(half) => dfu::start(half, len, crc).await,
+                    None => ForwardStatus::Invalid,
+                };
+                writer.write(&[status as u8, 0]).await?;
+                writer.flush().await?;
+            }
+            HidRequest::DfuData => {
+                let len = reader.pop().await? as usize;
+                let mut buf = [0u8; 4 + MAX_DFU_CHUNK_LEN];
+                if len > MAX_DFU_CHUNK_LEN {
+                    error!("Received dfu chunk of len {}", len);
+                    return Ok(());
+                }
+                reader.pop_slice(&mut buf[..4 + len]).await?;
+                let offset = u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]);
+                dfu::send_data(offset, &buf[4..4 + len]).await;
+            }
+            HidRequest::DfuFinish => {
+                let addr = reader.pop().await?;
+                let (status, result) = match Half::from_addr(addr) {
+                    Some(half) => dfu::finish(half).await,
+                    None => (ForwardStatus::Invalid, 0),
+                };
+                writer.write(&[status as u8, result]).await?;
+                writer.flush().await?;
+            }
+            _ => return KEYS.handle_request(request, reader, writer).await,
+        }
+        Ok(())
+    }
+}
+
 struct Indicator {}
 
 impl ConfigIndicator for Indicator {
-    async fn indicate_config(&self, config_num: Indicate) {}
+    async fn indicate_config(&self, config_num: Indicate) {
+        // The dongle has no leds, so the host leds are shown on the halves
+        if let Indicate::HostLeds(leds) = config_num {
+            link::set_host_leds(leds);
+        }
+    }
+}
+
+/// Receives the lock key leds the host sets with the keyboard's output report
+struct KeyboardRequestHandler {}
+
+impl RequestHandler for KeyboardRequestHandler {
+    fn set_report(&mut self, id: ReportId, data: &[u8]) -> OutResponse {
+        match (id, data.first()) {
+            (ReportId::Out(_), Some(&leds)) => {
+                record_led_report();
+                link::set_host_leds(HostLeds(leds));
+                OutResponse::Accepted
+            }
+            _ => OutResponse::Rejected,
+        }
+    }
+
+    fn set_idle_ms(&mut self, _id: Option<ReportId>, _duration_ms: u32) {
+        record_set_idle();
+    }
+}
+
+/// Receives the scroll resolution the host sets with the mouse's feature
+/// report
+#[cfg(feature = "mouse")]
+struct MouseRequestHandler {}
+
+#[cfg(feature = "mouse")]
+impl RequestHandler for MouseRequestHandler {
+    fn set_report(&mut self, id: ReportId, data: &[u8]) -> OutResponse {
+        match id {
+            ReportId::Feature(_) if set_resolution_report(data) => OutResponse::Accepted,
+            _ => OutResponse::Rejected,
+        }
+    }
+
+    fn get_report(&mut self, id: ReportId, buf: &mut [u8]) -> Option<usize> {
+        match id {
+            ReportId::Feature(_) => {
+                let report = resolution_report();
+                buf.get_mut(..report.len())?.copy_from_slice(&report);
+                Some(report.len())
+            }
+            _ => None,
+        }
+    }
 }
 
 struct MyDeviceHandler {
@@ -228,6 +549,10 @@ impl Handler for MyDeviceHandler {
 
     fn reset(&mut self) {
         self.configured.store(false, Ordering::Relaxed);
+        reset_detection();
+        link::set_host_asleep(false);
+        #[cfg(feature = "mouse")]
+        reset_resolution();
         info!("Bus reset, the Vbus current limit is 100mA");
     }
 
@@ -239,6 +564,7 @@ impl Handler for MyDeviceHandler {
     fn configured(&mut self, configured: bool) {
         self.configured.store(configured, Ordering::Relaxed);
         if configured {
+            set_configured();
             info!(
                 "Device configured, it may now draw up to the configured current limit from Vbus."
             )
@@ -246,4 +572,14 @@ impl Handler for MyDeviceHandler {
             info!("Device is no longer configured, the Vbus current limit is 100mA.");
         }
     }
+
+    fn suspended(&mut self, suspended: bool) {
+        // Relayed to the halves so they sleep with the host
+        link::set_host_asleep(suspended);
+    }
+
+    fn get_string(&mut self, index: StringIndex, _lang_id: u16) -> Option<&str> {
+        record_string_request(index.into());
+        None
+    }
 }
@@ -79,6 +79,7 @@ async fn main(spawner: Spawner) {
     let key = storage::StorageKey::KeyScanCode {
         config_num: 0,
         layer: 0,
+        slot: 0,
     };
     // let codes = ScanCodeLayerStorage {
     //     codes: [ScanCodeBehavior::Single(key_lib::scan_codes::KeyCodes::Undefined); NUM_KEYS],
@@ -89,13 +90,16 @@ async fn main(spawner: Spawner) {
         let item = get_item(storage::StorageKey::KeyScanCode {
             config_num: 0,
             layer: 0,
+            slot: 0,
         })
         .await;
         match item {
-            Some(val) => {
-                let StorageItem::Key(key) = val;
+            Some(StorageItem::Key(key)) => {
                 log::info!("{:?}", key.codes);
             }
+            Some(_) => {
+                log::info!("Unexpected item stored at key scan code!");
+            }
             None => {
                 log::info!("No keys stored!???");
             }
@@ -5,25 +5,70 @@
 #![no_std]
 #![no_main]
 
+use core::panic::PanicInfo;
+
 use assign_resources::assign_resources;
-use bruh78::radio::{self, send_packet, Addresses, Packet, Radio};
-use bruh78::sensors::Matrix;
-use cortex_m_rt::entry;
+use bruh78::battery::{self, Battery};
+use bruh78::radio::{self, send_packet, Addresses, Radio};
+use bruh78::watchdog::{load_boot_state, record_crash, run_wdt};
+use bruh78::{dfu, key_config::set_keys, keymap, link, Switch, DFU_STAGING, HALF_STORAGE};
+use cortex_m_rt::{entry, exception, ExceptionFrame};
+use defmt::{error, Display2Format};
+use embassy_embedded_hal::adapter::BlockingAsync;
+use embassy_embedded_hal::flash::partition::Partition;
 use embassy_executor::{Executor, InterruptExecutor, Spawner};
+use embassy_futures::join::join;
 use embassy_nrf::config::HfclkSource;
 use embassy_nrf::gpio::{Input, Level, Output, OutputDrive, Pull};
 use embassy_nrf::interrupt::InterruptExt;
-use embassy_nrf::{bind_interrupts, interrupt, peripherals, Peri};
-use embassy_time::Timer;
+use embassy_nrf::nvmc::Nvmc;
+use embassy_nrf::usb::{self, vbus_detect::HardwareVbusDetect, Driver};
+use embassy_nrf::{bind_interrupts, interrupt, peripherals, saadc, Peri};
+use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
+use embassy_sync::mutex::Mutex;
+use embassy_time::{Duration, Instant, Timer};
+use embassy_usb::class::hid::{HidWriter, ReportId, RequestHandler, State};
+use embassy_usb::control::OutResponse;
+use embassy_usb::driver::EndpointError;
+use embassy_usb::Builder;
+use key_lib::crash::CrashRecord;
+use key_lib::descriptor::KeyboardReportNKRO;
+use key_lib::dfu::DfuWriter;
+use key_lib::display;
+use key_lib::error::{record_endpoint_error, Endpoint};
+use key_lib::keys::{ConfigIndicator, HostLeds, Indicate, Keys};
+use key_lib::position::{EagerDebouncer, KeyState, Matrix};
+use key_lib::report::{MouseOutput, Report, ReportSink};
+use key_lib::slave_com::set_peer_present;
+use key_lib::storage::Storage;
+use key_lib::watchdog::{check_in, idle, Watched};
+use key_lib::NUM_KEYS;
 use static_cell::StaticCell;
+use usbd_hid::descriptor::SerializedDescriptor;
 
-use {defmt_rtt as _, panic_probe as _};
+use defmt_rtt as _;
 
 static RADIO_EXECUTOR: InterruptExecutor = InterruptExecutor::new();
 static THREAD_EXECUTOR: StaticCell<Executor> = StaticCell::new();
 
+/// Keymap the half runs itself while it's plugged into a host without the
+/// dongle. Loaded from the half's storage, which the dongle can read and
+/// write, see [keymap]
+static KEYS: Mutex<ThreadModeRawMutex, Keys<Indicator>> = Mutex::new(Keys::default());
+
+type Flash = BlockingAsync<Nvmc<'static>>;
+
+/// Internal flash, shared by dfu staging and the half's storage
+static FLASH: StaticCell<Mutex<ThreadModeRawMutex, Flash>> = StaticCell::new();
+
+// Product id of the half's own usb keyboard. The dongle is 0xa44
+const USB_PID: u16 = 0xa46;
+
 bind_interrupts!(struct Irqs {
     RADIO => radio::InterruptHandler;
+    SAADC => saadc::InterruptHandler;
+    USBD => usb::InterruptHandler<peripherals::USBD>;
+    CLOCK_POWER => usb::vbus_detect::InterruptHandler;
 });
 
 assign_resources! {
@@ -41,6 +86,59 @@ assign_resources! {
     radio: RadioResources {
         rad: RADIO,
     }
+    flash: FlashResources {
+        nvmc: NVMC,
+    }
+    watchdog: WatchdogResources {
+        wdt: WDT,
+    }
+    battery: BatteryResources {
+        saadc: SAADC,
+        led: P0_15,
+    }
+    usb: UsbResources {
+        usbd: USBD,
+    }
+}
+
+struct Indicator {}
+
+impl ConfigIndicator for Indicator {
+    // Host leds are shown by the battery task
+    async fn indicate_config(&self, _config_num: Indicate) {}
+}
+
+struct KeyboardRequestHandler {}
+
+impl RequestHandler for KeyboardRequestHandler {
+    fn set_report(&mut self, id: ReportId, data: &[u8]) -> OutResponse {
+        match (id, data.first()) {
+            (ReportId::Out(_), Some(&leds)) => {
+                link::set_host_leds(HostLeds(leds));
+                OutResponse::Accepted
+            }
+            _ => OutResponse::Rejected,
+        }
+    }
+}
+
+/// Sends the reports of the half's own keymap over its usb keyboard. The
+/// half has no mouse interface, so mouse reports are dropped
+struct UsbSink<'a, 'd, D: embassy_usb::driver::Driver<'d>>(&'a mut HidWriter<'d, D, 32>);
+
+impl<'d, D: embassy_usb::driver::Driver<'d>> ReportSink for UsbSink<'_, 'd, D> {
+    type Error = EndpointError;
+
+    async fn send(
+        &mut self,
+        key_report: Option<&KeyboardReportNKRO>,
+        _: MouseOutput<'_>,
+    ) -> Result<(), EndpointError> {
+        match key_report {
+            Some(rep) => self.0.write_serialize(rep).await,
+            None => Ok(()),
+        }
+    }
 }
 
 #[embassy_executor::task]
@@ -48,14 +146,66 @@ async fn radio_task(r: RadioResources) {
     let addresses = Addresses::default();
     let mut radio = Radio::new(r.rad, Irqs, addresses);
     radio.set_tx_addresses(|w| w.set_txaddress(1));
+    radio.enable_auto_tx_power();
     radio.set_rx_addresses(|w| {
         w.set_addr0(true);
     });
     radio.run().await;
 }
 
+/// Scans the matrix and sends the key state to the dongle. While a host has
+/// the half's usb keyboard configured the half runs [KEYS] itself and sends
+/// its reports over usb instead, and the dongle is told to mute its keys.
+/// Unplugged halves fall back to the radio right away, see [link::is_wired]
 #[embassy_executor::task]
-async fn keyboard_task(k: KeyboardResources) {
+async fn keyboard_task(k: KeyboardResources, u: UsbResources) {
+    let driver = Driver::new(u.usbd, Irqs, HardwareVbusDetect::new(Irqs));
+    let mut config = embassy_usb::Config::new(0xa55, USB_PID);
+    config.manufacturer = Some("Tybeast Corp.");
+    config.product = Some("Tycho Left");
+    config.max_power = 500;
+    config.max_packet_size_0 = 64;
+
+    let mut config_descriptor = [0; 256];
+    let mut bos_descriptor = [0; 256];
+    let mut msos_descriptor = [0; 256];
+    let mut control_buf = [0; 64];
+    let mut key_state = State::new();
+    let mut key_handler = KeyboardRequestHandler {};
+    let mut wired_handler = link::WiredHandler;
+    let mut builder = Builder::new(
+        driver,
+        config,
+        &mut config_descriptor,
+        &mut bos_descriptor,
+        &mut msos_descriptor,
+        &mut control_buf,
+    );
+    builder.handler(&mut wired_handler);
+    // Only the keymap is kept in the half's storage, so the report rate
+    // isn't loaded
+    let key_config = embassy_usb::class::hid::Config {
+        report_descriptor: KeyboardReportNKRO::desc(),
+        request_handler: Some(&mut key_handler),
+        poll_ms: 1,
+        max_packet_size: 32,
+    };
+    let mut key_writer = HidWriter::<_, 32>::new(&mut builder, &mut key_state, key_config);
+    let mut usb = builder.build();
+
+    let mut keys = KEYS.lock().await;
+    keys.set_indicator(Indicator {});
+    // Halves whose keymap was never written from the dongle run the built-in
+    // one
+    if keys.load_keys_from_storage(0).await.is_err() {
+        set_keys(&mut keys);
+        keys.set_indicator(Indicator {});
+    }
+    drop(keys);
+    // The right half's keys can't reach a wired left half, so its keys stay
+    // released and the standalone layer is used
+    set_peer_present(false);
+
     let columns = [
         Output::new(k.out_0, Level::Low, OutputDrive::Standard),
         Output::new(k.out_1, Level::Low, OutputDrive::Standard),
@@ -71,20 +221,95 @@ async fn keyboard_task(k: KeyboardResources) {
         Input::new(k.in_3, Pull::Down),
     ];
 
-    let mut matrix = Matrix::new(columns, rows);
+    let mut matrix = Matrix::new(columns, rows, EagerDebouncer::new(Duration::from_millis(5)));
     matrix.disable_debouncer(15..17);
-    let mut rep = 0;
-    loop {
-        matrix.update().await;
-        let new_rep = matrix.get_state();
-        if new_rep != rep {
-            rep = new_rep;
-            let mut packet = Packet::default();
-            packet.copy_from_slice(&rep.to_le_bytes());
-            send_packet(&packet).await;
+    let mut positions = [Switch::DEFAULT; NUM_KEYS];
+    let mut report = Report::new();
+    let mut sink = UsbSink(&mut key_writer);
+    let scan_loop = async {
+        let mut rep = 0;
+        let mut wired = false;
+        let mut last_sent = Instant::now();
+        loop {
+            check_in(Watched::Keys);
+            matrix.update().await;
+            let state = matrix.get_state();
+            // Key states aren't sent over the radio while the half is wired
+            let new_wired = link::is_wired();
+            if new_wired {
+                // The host doesn't know the keys held before it configured
+                // the keyboard
+                if !wired {
+                    report.resend();
+                }
+                for (i, position) in positions[..NUM_KEYS / 2].iter_mut().enumerate() {
+                    position.update_buf((state >> i) & 1 != 0);
+                }
+                // A suspended host doesn't poll for reports
+                let sent = idle(
+                    Watched::Keys,
+                    report.send_report(&KEYS, &positions, &mut sink),
+                )
+                .await;
+                if sent.is_err() {
+                    record_endpoint_error(Endpoint::Report);
+                }
+            }
+            let new_rep = if new_wired { 0 } else { state };
+            // Idle halves resend their state so the dongle knows they're
+            // connected
+            if new_rep != rep
+                || new_wired != wired
+                || last_sent.elapsed() >= link::heartbeat_interval()
+            {
+                rep = new_rep;
+                wired = new_wired;
+                display::set_wired(wired);
+                last_sent = Instant::now();
+                send_packet(&link::key_state_packet(rep, wired)).await;
+            }
+            Timer::after(battery::scan_rate().scan_interval()).await;
         }
-        Timer::after_micros(5).await;
-    }
+    };
+    join(usb.run(), scan_loop).await;
+}
+
+#[embassy_executor::task]
+async fn config_task() {
+    link::run_config_handler().await;
+}
+
+#[embassy_executor::task]
+async fn arbitration_task() {
+    link::run_source_arbitration().await;
+}
+
+#[embassy_executor::task]
+async fn keymap_task() {
+    keymap::run_keymap_server(&KEYS).await;
+}
+
+#[embassy_executor::task]
+async fn storage_task(flash: Partition<'static, ThreadModeRawMutex, Flash>) {
+    let storage = Storage::init(flash, 0..HALF_STORAGE.end - HALF_STORAGE.start).await;
+    storage.run_storage().await;
+}
+
+#[embassy_executor::task]
+async fn dfu_task(flash: Partition<'static, ThreadModeRawMutex, Flash>) {
+    let range = 0..DFU_STAGING.end - DFU_STAGING.start;
+    dfu::run_dfu_receiver(DfuWriter::new(flash, range)).await;
+}
+
+#[embassy_executor::task]
+async fn watchdog_task(w: WatchdogResources) {
+    run_wdt(w.wdt, &[Watched::Keys, Watched::Radio]).await
+}
+
+#[embassy_executor::task]
+async fn battery_task(b: BatteryResources) {
+    let led = Output::new(b.led, Level::Low, OutputDrive::Standard);
+    Battery::new(b.saadc, Irqs, led).run().await;
 }
 
 #[interrupt]
@@ -96,7 +321,8 @@ unsafe fn EGU1_SWI1() {
 fn main() -> ! {
     let mut config = embassy_nrf::config::Config::default();
     config.hfclk_source = HfclkSource::ExternalXtal;
-    let p = embassy_nrf::init(config);
+    let mut p = embassy_nrf::init(config);
+    load_boot_state(p.NVMC.reborrow());
     let r = split_resources!(p);
 
     embassy_nrf::interrupt::EGU1_SWI1.set_priority(embassy_nrf::interrupt::Priority::P1);
@@ -105,8 +331,38 @@ fn main() -> ! {
     let spawner = RADIO_EXECUTOR.start(embassy_nrf::interrupt::EGU1_SWI1);
     spawner.spawn(radio_task(r.radio)).unwrap();
 
+    let flash = FLASH.init(Mutex::new(BlockingAsync::new(Nvmc::new(r.flash.nvmc))));
+    let storage_flash = Partition::new(
+        flash,
+        HALF_STORAGE.start,
+        HALF_STORAGE.end - HALF_STORAGE.start,
+    );
+    let dfu_flash = Partition::new(
+        flash,
+        DFU_STAGING.start,
+        DFU_STAGING.end - DFU_STAGING.start,
+    );
+
     let executor = THREAD_EXECUTOR.init_with(Executor::new);
     executor.run(|spawner| {
-        spawner.spawn(keyboard_task(r.keyboard)).unwrap();
+        spawner.spawn(keyboard_task(r.keyboard, r.usb)).unwrap();
+        spawner.spawn(battery_task(r.battery)).unwrap();
+        spawner.spawn(config_task()).unwrap();
+        spawner.spawn(arbitration_task()).unwrap();
+        spawner.spawn(keymap_task()).unwrap();
+        spawner.spawn(storage_task(storage_flash)).unwrap();
+        spawner.spawn(dfu_task(dfu_flash)).unwrap();
+        spawner.spawn(watchdog_task(r.watchdog)).unwrap();
     });
 }
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    error!("{}", Display2Format(info));
+    record_crash(&CrashRecord::panic(info))
+}
+
+#[exception]
+unsafe fn HardFault(frame: &ExceptionFrame) -> ! {
+    record_crash(&CrashRecord::hard_fault(frame.pc()))
+}
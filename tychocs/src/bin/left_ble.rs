@@ -0,0 +1,188 @@
+//! Left half paired straight to a host over BLE. The half runs the keymap
+//! itself and sends its reports without the dongle, while the keys of the
+//! right half are read as released so the standalone layer is used.
+//!
+//! Built with `cargo build --bin left_ble --features ble`
+
+#![no_std]
+#![no_main]
+
+use assign_resources::assign_resources;
+use bruh78::battery::{self, Battery};
+use bruh78::{
+    ble::{self, BleSink},
+    key_config::set_keys,
+    Switch,
+};
+use defmt::unwrap;
+use embassy_executor::Spawner;
+use embassy_nrf::config::HfclkSource;
+use embassy_nrf::gpio::{Input, Level, Output, OutputDrive, Pull};
+use embassy_nrf::peripherals::RNG;
+use embassy_nrf::{bind_interrupts, rng, saadc};
+use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
+use embassy_sync::mutex::Mutex;
+use embassy_time::{Duration, Timer};
+use key_lib::keys::{ConfigIndicator, Indicate, Keys};
+use key_lib::position::{EagerDebouncer, KeyState, Matrix};
+use key_lib::report::Report;
+use key_lib::slave_com::set_peer_present;
+use key_lib::NUM_KEYS;
+use nrf_sdc::mpsl::MultiprotocolServiceLayer;
+use nrf_sdc::{self as sdc, mpsl};
+use rand_chacha::{rand_core::SeedableRng, ChaCha12Rng};
+use static_cell::StaticCell;
+use trouble_host::prelude::DefaultPacketPool;
+
+use {defmt_rtt as _, panic_probe as _};
+
+static KEYS: Mutex<ThreadModeRawMutex, Keys<Indicator>> = Mutex::new(Keys::default());
+
+bind_interrupts!(struct Irqs {
+    RNG => rng::InterruptHandler<RNG>;
+    SAADC => saadc::InterruptHandler;
+    EGU0_SWI0 => mpsl::LowPrioInterruptHandler;
+    CLOCK_POWER => mpsl::ClockInterruptHandler;
+    RADIO => mpsl::HighPrioInterruptHandler;
+    TIMER0 => mpsl::HighPrioInterruptHandler;
+    RTC0 => mpsl::HighPrioInterruptHandler;
+});
+
+assign_resources! {
+    keyboard: KeyboardResources {
+        out_0: P1_00,
+        out_1: P0_11,
+        out_2: P1_04,
+        out_3: P1_06,
+        out_4: P0_09,
+        in_0: P0_02,
+        in_1: P1_15,
+        in_2: P1_11,
+        in_3: P0_10,
+    },
+    battery: BatteryResources {
+        saadc: SAADC,
+        led: P0_15,
+    }
+}
+
+// Memory the SoftDevice Controller needs for one peripheral connection
+const SDC_MEM_SIZE: usize = 3312;
+const L2CAP_TXQ: u8 = 3;
+const L2CAP_RXQ: u8 = 3;
+
+struct Indicator {}
+
+impl ConfigIndicator for Indicator {
+    // The host leds are written straight to the half over BLE
+    async fn indicate_config(&self, _config_num: Indicate) {}
+}
+
+#[embassy_executor::task]
+async fn mpsl_task(mpsl: &'static MultiprotocolServiceLayer<'static>) -> ! {
+    mpsl.run().await
+}
+
+#[embassy_executor::task]
+async fn keyboard_task(k: KeyboardResources) {
+    let columns = [
+        Output::new(k.out_0, Level::Low, OutputDrive::Standard),
+        Output::new(k.out_1, Level::Low, OutputDrive::Standard),
+        Output::new(k.out_2, Level::Low, OutputDrive::Standard),
+        Output::new(k.out_3, Level::Low, OutputDrive::Standard),
+        Output::new(k.out_4, Level::Low, OutputDrive::Standard),
+    ];
+
+    let rows = [
+        Input::new(k.in_0, Pull::Down),
+        Input::new(k.in_1, Pull::Down),
+        Input::new(k.in_2, Pull::Down),
+        Input::new(k.in_3, Pull::Down),
+    ];
+
+    let mut matrix = Matrix::new(columns, rows, EagerDebouncer::new(Duration::from_millis(5)));
+    matrix.disable_debouncer(15..17);
+
+    let mut keys = KEYS.lock().await;
+    set_keys(&mut keys);
+    keys.set_indicator(Indicator {});
+    drop(keys);
+    // The right half isn't connected, so its keys stay released
+    set_peer_present(false);
+
+    let mut positions = [Switch::DEFAULT; NUM_KEYS];
+    let mut report = Report::new();
+    loop {
+        matrix.update().await;
+        let state = matrix.get_state();
+        for (i, position) in positions[..NUM_KEYS / 2].iter_mut().enumerate() {
+            position.update_buf((state >> i) & 1 != 0);
+        }
+        // Reports are queued and never fail to send
+        let _ = report.send_report(&KEYS, &positions, &mut BleSink).await;
+        Timer::after(battery::scan_rate().scan_interval()).await;
+    }
+}
+
+#[embassy_executor::task]
+async fn battery_task(b: BatteryResources) {
+    let led = Output::new(b.led, Level::Low, OutputDrive::Standard);
+    Battery::new(b.saadc, Irqs, led).run().await;
+}
+
+fn build_sdc<'d, const N: usize>(
+    p: sdc::Peripherals<'d>,
+    rng: &'d mut rng::Rng<RNG>,
+    mpsl: &'d MultiprotocolServiceLayer,
+    mem: &'d mut sdc::Mem<N>,
+) -> Result<sdc::SoftdeviceController<'d>, sdc::Error> {
+    sdc::Builder::new()?
+        .support_adv()?
+        .support_peripheral()?
+        .peripheral_count(1)?
+        .buffer_cfg(
+            DefaultPacketPool::MTU as u16,
+            DefaultPacketPool::MTU as u16,
+            L2CAP_TXQ,
+            L2CAP_RXQ,
+        )?
+        .build(p, rng, mpsl, mem)
+}
+
+#[embassy_executor::main]
+async fn main(spawner: Spawner) {
+    let mut config = embassy_nrf::config::Config::default();
+    config.hfclk_source = HfclkSource::ExternalXtal;
+    let p = embassy_nrf::init(config);
+
+    let mpsl_p =
+        mpsl::Peripherals::new(p.RTC0, p.TIMER0, p.TEMP, p.PPI_CH19, p.PPI_CH30, p.PPI_CH31);
+    let lfclk_cfg = mpsl::raw::mpsl_clock_lfclk_cfg_t {
+        source: mpsl::raw::MPSL_CLOCK_LF_SRC_RC as u8,
+        rc_ctiv: mpsl::raw::MPSL_RECOMMENDED_RC_CTIV as u8,
+        rc_temp_ctiv: mpsl::raw::MPSL_RECOMMENDED_RC_TEMP_CTIV as u8,
+        accuracy_ppm: mpsl::raw::MPSL_DEFAULT_CLOCK_ACCURACY_PPM as u16,
+        skip_wait_lfclk_started: mpsl::raw::MPSL_DEFAULT_SKIP_WAIT_LFCLK_STARTED != 0,
+    };
+    static MPSL: StaticCell<MultiprotocolServiceLayer> = StaticCell::new();
+    let mpsl = MPSL.init(unwrap!(MultiprotocolServiceLayer::new(
+        mpsl_p, Irqs, lfclk_cfg
+    )));
+    spawner.must_spawn(mpsl_task(mpsl));
+
+    let r = split_resources!(p);
+    spawner.must_spawn(keyboard_task(r.keyboard));
+    spawner.must_spawn(battery_task(r.battery));
+
+    let sdc_p = sdc::Peripherals::new(
+        p.PPI_CH17, p.PPI_CH18, p.PPI_CH20, p.PPI_CH21, p.PPI_CH22, p.PPI_CH23, p.PPI_CH24,
+        p.PPI_CH25, p.PPI_CH26, p.PPI_CH27, p.PPI_CH28, p.PPI_CH29,
+    );
+    let mut rng = rng::Rng::new(p.RNG, Irqs);
+    // The controller holds on to the hardware rng, so the pairing keys come
+    // from a generator seeded by it
+    let mut pairing_rng = ChaCha12Rng::from_rng(&mut rng).unwrap();
+    let mut sdc_mem = sdc::Mem::<SDC_MEM_SIZE>::new();
+    let controller = unwrap!(build_sdc(sdc_p, &mut rng, mpsl, &mut sdc_mem));
+    ble::run(controller, &mut pairing_rng).await;
+}
@@ -5,23 +5,36 @@
 #![no_std]
 #![no_main]
 
+use core::panic::PanicInfo;
+
 use assign_resources::assign_resources;
-use bruh78::radio::{self, send_packet, Addresses, Packet, Radio};
-use bruh78::sensors::Matrix;
+use bruh78::battery::{self, Battery};
+use bruh78::radio::{self, send_packet, Addresses, Radio};
+use bruh78::watchdog::{load_boot_state, record_crash, run_wdt};
+use bruh78::{dfu, link, DFU_STAGING};
+use cortex_m_rt::{exception, ExceptionFrame};
 use defmt::*;
+use embassy_embedded_hal::adapter::BlockingAsync;
 use embassy_executor::{Executor, InterruptExecutor, Spawner};
 use embassy_nrf::config::HfclkSource;
 use embassy_nrf::gpio::{Input, Level, Output, OutputDrive, Pin, Pull};
 use embassy_nrf::interrupt;
 use embassy_nrf::interrupt::InterruptExt;
-use embassy_nrf::{bind_interrupts, peripherals, Peri};
-use embassy_time::Timer;
+use embassy_nrf::nvmc::Nvmc;
+use embassy_nrf::{bind_interrupts, peripherals, saadc, Peri};
+use embassy_time::{Duration, Instant, Timer};
+use key_lib::crash::CrashRecord;
+use key_lib::dfu::DfuWriter;
+use key_lib::display;
+use key_lib::position::{EagerDebouncer, Matrix};
+use key_lib::watchdog::{check_in, Watched};
 use static_cell::StaticCell;
 
-use {defmt_rtt as _, panic_probe as _};
+use defmt_rtt as _;
 
 bind_interrupts!(struct Irqs {
     RADIO => radio::InterruptHandler;
+    SAADC => saadc::InterruptHandler;
 });
 
 static RADIO_EXECUTOR: InterruptExecutor = InterruptExecutor::new();
@@ -42,6 +55,16 @@ assign_resources! {
     radio: RadioResources {
         rad: RADIO,
     }
+    dfu: DfuResources {
+        nvmc: NVMC,
+    }
+    watchdog: WatchdogResources {
+        wdt: WDT,
+    }
+    battery: BatteryResources {
+        saadc: SAADC,
+        led: P0_15,
+    }
 }
 
 #[embassy_executor::task]
@@ -49,6 +72,7 @@ async fn radio_task(r: RadioResources) {
     let addresses = Addresses::default();
     let mut radio = Radio::new(r.rad, Irqs, addresses);
     radio.set_tx_addresses(|w| w.set_txaddress(2));
+    radio.enable_auto_tx_power();
     radio.set_rx_addresses(|w| {
         w.set_addr0(true);
     });
@@ -86,27 +110,63 @@ async fn keyboard_task(k: KeyboardResources) {
         Input::new(k.in_3, Pull::Down),
     ];
 
-    let mut matrix = Matrix::new(columns, rows);
+    let mut matrix = Matrix::new(columns, rows, EagerDebouncer::new(Duration::from_millis(5)));
     matrix.disable_debouncer(18..20);
     let mut rep = 0;
+    let mut wired = false;
+    let mut last_sent = Instant::now();
     loop {
+        check_in(Watched::Keys);
         matrix.update().await;
-        let new_rep = matrix.get_state();
-        if new_rep != rep {
+        // Key states aren't sent over the radio while the half is wired
+        let new_wired = link::is_wired();
+        let new_rep = if new_wired { 0 } else { matrix.get_state() };
+        // Idle halves resend their state so the dongle knows they're connected
+        if new_rep != rep || new_wired != wired || last_sent.elapsed() >= link::heartbeat_interval()
+        {
             rep = new_rep;
-            let mut packet = Packet::default();
-            packet.copy_from_slice(&rep.to_le_bytes());
-            send_packet(&packet).await;
+            wired = new_wired;
+            display::set_wired(wired);
+            last_sent = Instant::now();
+            send_packet(&link::key_state_packet(rep, wired)).await;
         }
-        Timer::after_micros(5).await;
+        Timer::after(battery::scan_rate().scan_interval()).await;
     }
 }
 
+#[embassy_executor::task]
+async fn config_task() {
+    link::run_config_handler().await;
+}
+
+#[embassy_executor::task]
+async fn arbitration_task() {
+    link::run_source_arbitration().await;
+}
+
+#[embassy_executor::task]
+async fn dfu_task(d: DfuResources) {
+    let flash = BlockingAsync::new(Nvmc::new(d.nvmc));
+    dfu::run_dfu_receiver(DfuWriter::new(flash, DFU_STAGING)).await;
+}
+
+#[embassy_executor::task]
+async fn watchdog_task(w: WatchdogResources) {
+    run_wdt(w.wdt, &[Watched::Keys, Watched::Radio]).await
+}
+
+#[embassy_executor::task]
+async fn battery_task(b: BatteryResources) {
+    let led = Output::new(b.led, Level::Low, OutputDrive::Standard);
+    Battery::new(b.saadc, Irqs, led).run().await;
+}
+
 #[embassy_executor::main]
 async fn main(_spawner: Spawner) {
     let mut config = embassy_nrf::config::Config::default();
     config.hfclk_source = HfclkSource::ExternalXtal;
-    let p = embassy_nrf::init(config);
+    let mut p = embassy_nrf::init(config);
+    load_boot_state(p.NVMC.reborrow());
     let r = split_resources!(p);
 
     embassy_nrf::interrupt::EGU1_SWI1.set_priority(embassy_nrf::interrupt::Priority::P1);
@@ -118,6 +178,22 @@ async fn main(_spawner: Spawner) {
     let executor = THREAD_EXECUTOR.init_with(Executor::new);
     executor.run(|spawner| {
         spawner.spawn(keyboard_task(r.keyboard)).unwrap();
+        spawner.spawn(battery_task(r.battery)).unwrap();
+        spawner.spawn(config_task()).unwrap();
+        spawner.spawn(arbitration_task()).unwrap();
+        spawner.spawn(dfu_task(r.dfu)).unwrap();
+        spawner.spawn(watchdog_task(r.watchdog)).unwrap();
         // spawner.spawn(blinking_task(p.P0_15)).unwrap();
     });
 }
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    error!("{}", Display2Format(info));
+    record_crash(&CrashRecord::panic(info))
+}
+
+#[exception]
+unsafe fn HardFault(frame: &ExceptionFrame) -> ! {
+    record_crash(&CrashRecord::hard_fault(frame.pc()))
+}
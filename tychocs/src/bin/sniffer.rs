@@ -0,0 +1,100 @@
+//! Passively listens to every address used by the keyboard and logs each packet over usb
+//! to help debug connection drops. Packets aren't acked so the link isn't affected.
+
+#![no_std]
+#![no_main]
+
+use bruh78::radio::{self, Addresses, Packet, PacketType, Radio};
+use cortex_m_rt::entry;
+use embassy_executor::{Executor, InterruptExecutor};
+use embassy_nrf::{
+    bind_interrupts,
+    config::HfclkSource,
+    interrupt,
+    interrupt::InterruptExt,
+    peripherals::{self, USBD},
+    usb::{self, vbus_detect::HardwareVbusDetect, Driver},
+    Peri,
+};
+
+use defmt_rtt as _; // global logger
+use embassy_nrf as _;
+// time driver
+use panic_probe as _;
+use static_cell::StaticCell;
+
+static RADIO_EXECUTOR: InterruptExecutor = InterruptExecutor::new();
+static THREAD_EXECUTOR: StaticCell<Executor> = StaticCell::new();
+
+bind_interrupts!(struct Irqs {
+    USBD => usb::InterruptHandler<peripherals::USBD>;
+    CLOCK_POWER => usb::vbus_detect::InterruptHandler;
+    RADIO  => radio::InterruptHandler;
+});
+
+#[embassy_executor::task]
+async fn logger_task(usbd: Peri<'static, USBD>) {
+    let driver = Driver::new(usbd, Irqs, HardwareVbusDetect::new(Irqs));
+    embassy_usb_logger::run!(1024, log::LevelFilter::Info, driver);
+}
+
+#[embassy_executor::task]
+async fn radio_task(radio: Peri<'static, peripherals::RADIO>) {
+    let addresses = Addresses::default();
+    let mut radio = Radio::new(radio, Irqs, addresses);
+    // Listen on every logical address
+    radio.set_rx_addresses(|w| w.0 = 0xFF);
+    loop {
+        let mut packet = Packet::default();
+        let sniffed = radio.sniff(&mut packet).await;
+        if !sniffed.crc_ok {
+            log::info!(
+                "addr: {} crc failed, rssi: {}dBm",
+                packet.addr,
+                sniffed.rssi
+            );
+            continue;
+        }
+        let packet_type = match sniffed.packet_type {
+            Some(PacketType::Data) => "data",
+            Some(PacketType::SyncData) => "sync",
+            Some(PacketType::Config) => "config",
+            Some(PacketType::Dfu) => "dfu",
+            Some(PacketType::Ack) => "ack",
+            Some(PacketType::AnalogData) => "analog",
+            None => "unknown",
+        };
+        log::info!(
+            "addr: {}, type: {}, id: {}, rssi: {}dBm, payload: {:?}",
+            packet.addr,
+            packet_type,
+            packet.id(),
+            sniffed.rssi,
+            &packet[..],
+        );
+    }
+}
+
+#[interrupt]
+unsafe fn EGU1_SWI1() {
+    RADIO_EXECUTOR.on_interrupt()
+}
+
+#[entry]
+fn main() -> ! {
+    let mut nrf_config = embassy_nrf::config::Config::default();
+    nrf_config.hfclk_source = HfclkSource::ExternalXtal;
+    let p = embassy_nrf::init(nrf_config);
+
+    embassy_nrf::interrupt::EGU1_SWI1.set_priority(embassy_nrf::interrupt::Priority::P1);
+    embassy_nrf::interrupt::RADIO.set_priority(embassy_nrf::interrupt::Priority::P0);
+    embassy_nrf::interrupt::USBD.set_priority(embassy_nrf::interrupt::Priority::P2);
+    embassy_nrf::interrupt::CLOCK_POWER.set_priority(embassy_nrf::interrupt::Priority::P2);
+    let spawner = RADIO_EXECUTOR.start(embassy_nrf::interrupt::EGU1_SWI1);
+    spawner.spawn(radio_task(p.RADIO)).unwrap();
+
+    let executor = THREAD_EXECUTOR.init_with(Executor::new);
+    executor.run(|spawner| {
+        spawner.spawn(logger_task(p.USBD)).unwrap();
+    });
+}
@@ -4,10 +4,7 @@
 use core::{mem, ops::Deref};
 
 use assign_resources::assign_resources;
-use bruh78::{
-    radio::{self, Addresses, Packet, Radio},
-    sensors::Matrix,
-};
+use bruh78::radio::{self, Addresses, Packet, Radio};
 use cortex_m_rt::entry;
 use defmt::{info, *};
 use embassy_executor::{Executor, InterruptExecutor, Spawner};
@@ -22,6 +19,8 @@ use embassy_nrf::{
     usb::{self, vbus_detect::HardwareVbusDetect, Driver},
     Peri,
 };
+use embassy_time::Duration;
+use key_lib::position::{EagerDebouncer, Matrix};
 
 use defmt_rtt as _; // global logger
 use embassy_nrf as _;
@@ -92,7 +91,7 @@ async fn thread_task(k: KeyboardResources) {
         Input::new(k.in_3, Pull::Down),
     ];
 
-    let mut matrix = Matrix::new(columns, rows);
+    let mut matrix = Matrix::new(columns, rows, EagerDebouncer::new(Duration::from_millis(5)));
     matrix.disable_debouncer(15..17);
     let mut rep = 0;
     let radio = RadioClient {};
@@ -0,0 +1,218 @@
+//! HID over GATT for a half paired straight to a host without the dongle. The
+//! half runs the Keys/Report pipeline itself and queues its key reports with
+//! [BleSink], which are notified to the connected host as boot protocol
+//! reports. Only built with the `ble` feature, the proprietary radio in
+//! [crate::radio] stays the link for the dongle builds.
+//!
+//! Bonds aren't kept across restarts, so the host has to forget the half and
+//! pair again after it reboots
+
+use core::convert::Infallible;
+
+use defmt::{info, warn, Debug2Format};
+use embassy_futures::select::select;
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel};
+use key_lib::{
+    descriptor::{KeyboardReportNKRO, BOOT_REPORT_LEN},
+    keys::HostLeds,
+    report::{MouseOutput, ReportSink},
+};
+use rand_core::{CryptoRng, RngCore};
+use trouble_host::prelude::*;
+use usbd_hid::descriptor::{KeyboardReport, SerializedDescriptor};
+
+use crate::link;
+
+/// Name the half advertises with
+pub const DEVICE_NAME: &str = "Tycho";
+
+const CONNECTIONS_MAX: usize = 1;
+// Signalling and att channels
+const L2CAP_CHANNELS_MAX: usize = 2;
+
+// Length of the usbd-hid boot keyboard descriptor used as the report map
+const REPORT_MAP_LEN: usize = 67;
+
+// Reports waiting to be notified. Reports are dropped once it fills up, e.g.
+// while no host is connected
+static REPORTS: Channel<CriticalSectionRawMutex, [u8; BOOT_REPORT_LEN], 16> = Channel::new();
+
+#[gatt_server]
+struct Server {
+    hid: HidService,
+}
+
+#[gatt_service(uuid = service::HUMAN_INTERFACE_DEVICE)]
+struct HidService {
+    // HID 1.11, no country code, remote wake and normally connectable
+    #[characteristic(uuid = "2a4a", read, value = [0x11, 0x01, 0x00, 0x03])]
+    hid_info: [u8; 4],
+    #[characteristic(uuid = "2a4b", read, value = KeyboardReport::desc().try_into().unwrap())]
+    report_map: [u8; REPORT_MAP_LEN],
+    #[characteristic(uuid = "2a4c", write_without_response)]
+    control_point: u8,
+    // Report protocol
+    #[characteristic(uuid = "2a4e", read, write_without_response, value = 1)]
+    protocol_mode: u8,
+    #[descriptor(uuid = "2908", read, value = [0u8, 1u8])]
+    #[characteristic(uuid = "2a4d", read, notify)]
+    input_keyboard: [u8; BOOT_REPORT_LEN],
+    // Lock key leds set by the host
+    #[descriptor(uuid = "2908", read, value = [0u8, 2u8])]
+    #[characteristic(uuid = "2a4d", read, write, write_without_response)]
+    output_keyboard: [u8; 1],
+}
+
+/// Queues a key report for the connected host
+pub fn send_report(report: &KeyboardReportNKRO) {
+    if REPORTS.try_send(report.to_boot()).is_err() {
+        warn!("Dropped BLE key report");
+    }
+}
+
+/// Sink for [key_lib::report::Report] that queues the key reports with
+/// [send_report]. The boot protocol has no mouse report, so mouse reports
+/// are dropped
+pub struct BleSink;
+
+impl ReportSink for BleSink {
+    type Error = Infallible;
+
+    async fn send(
+        &mut self,
+        key_report: Option<&KeyboardReportNKRO>,
+        _: MouseOutput<'_>,
+    ) -> Result<(), Infallible> {
+        if let Some(rep) = key_report {
+            send_report(rep);
+        }
+        Ok(())
+    }
+}
+
+// Static random address derived from the chip's device address, so the host
+// sees the same half after a restart
+fn address() -> Address {
+    let ficr = embassy_nrf::pac::FICR;
+    let low = ficr.deviceaddr(0).read();
+    let high = ficr.deviceaddr(1).read() as u16 | 0xC000;
+    let mut bytes = [0u8; 6];
+    bytes[..4].copy_from_slice(&low.to_le_bytes());
+    bytes[4..].copy_from_slice(&high.to_le_bytes());
+    Address::random(bytes)
+}
+
+/// Runs the BLE host on the controller, advertising until a host connects and
+/// again once it disconnects
+pub async fn run<C: Controller>(controller: C, rng: &mut (impl RngCore + CryptoRng)) {
+    let mut resources: HostResources<DefaultPacketPool, CONNECTIONS_MAX, L2CAP_CHANNELS_MAX> =
+        HostResources::new();
+    let stack = trouble_host::new(controller, &mut resources)
+        .set_random_address(address())
+        .set_random_generator_seed(rng);
+    let Host {
+        mut peripheral,
+        mut runner,
+        ..
+    } = stack.build();
+    let server = Server::new_with_config(GapConfig::Peripheral(PeripheralConfig {
+        name: DEVICE_NAME,
+        appearance: &appearance::human_interface_device::KEYBOARD,
+    }))
+    .unwrap();
+
+    let host_loop = async {
+        loop {
+            if let Err(e) = runner.run().await {
+                warn!("BLE host stopped: {}", Debug2Format(&e));
+            }
+        }
+    };
+    let connection_loop = async {
+        loop {
+            let conn = match advertise(&mut peripheral, &server).await {
+                Ok(conn) => conn,
+                Err(e) => {
+                    warn!("Failed to advertise: {}", Debug2Format(&e));
+                    continue;
+                }
+            };
+            info!("BLE host connected");
+            // Reports queued while disconnected are stale
+            REPORTS.clear();
+            select(gatt_events(&server, &conn), notify_reports(&server, &conn)).await;
+            info!("BLE host disconnected");
+        }
+    };
+    select(host_loop, connection_loop).await;
+}
+
+async fn advertise<'values, 'server, C: Controller>(
+    peripheral: &mut Peripheral<'values, C, DefaultPacketPool>,
+    server: &'server Server<'values>,
+) -> Result<GattConnection<'values, 'server, DefaultPacketPool>, BleHostError<C::Error>> {
+    let mut adv_data = [0; 31];
+    let len = AdStructure::encode_slice(
+        &[
+            AdStructure::Flags(LE_GENERAL_DISCOVERABLE | BR_EDR_NOT_SUPPORTED),
+            AdStructure::ServiceUuids16(&[service::HUMAN_INTERFACE_DEVICE.to_le_bytes()]),
+            // Appearance, so hosts show the half as a keyboard before pairing
+            AdStructure::Unknown {
+                ty: 0x19,
+                data: &appearance::human_interface_device::KEYBOARD.to_le_bytes(),
+            },
+            AdStructure::CompleteLocalName(DEVICE_NAME.as_bytes()),
+        ],
+        &mut adv_data[..],
+    )?;
+    let advertiser = peripheral
+        .advertise(
+            &Default::default(),
+            Advertisement::ConnectableScannableUndirected {
+                adv_data: &adv_data[..len],
+                scan_data: &[],
+            },
+        )
+        .await?;
+    let conn = advertiser.accept().await?.with_attribute_server(server)?;
+    conn.raw().set_bondable(true)?;
+    Ok(conn)
+}
+
+// Answers the host's gatt requests until it disconnects. Writes to the output
+// report are shown with the host leds like on the dongle builds
+async fn gatt_events(server: &Server<'_>, conn: &GattConnection<'_, '_, DefaultPacketPool>) {
+    let output = server.hid.output_keyboard.handle;
+    loop {
+        match conn.next().await {
+            GattConnectionEvent::Disconnected { reason } => {
+                info!("Disconnected: {}", Debug2Format(&reason));
+                return;
+            }
+            GattConnectionEvent::Gatt { event } => {
+                if let GattEvent::Write(write) = &event {
+                    if write.handle() == output {
+                        if let Some(&leds) = write.data().first() {
+                            link::set_host_leds(HostLeds(leds));
+                        }
+                    }
+                }
+                match event.accept() {
+                    Ok(reply) => reply.send().await,
+                    Err(e) => warn!("Failed to reply to gatt event: {}", Debug2Format(&e)),
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+async fn notify_reports(server: &Server<'_>, conn: &GattConnection<'_, '_, DefaultPacketPool>) {
+    loop {
+        let report = REPORTS.receive().await;
+        if let Err(e) = server.hid.input_keyboard.notify(conn, &report).await {
+            warn!("Failed to notify key report: {}", Debug2Format(&e));
+            return;
+        }
+    }
+}
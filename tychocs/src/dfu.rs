@@ -0,0 +1,149 @@
+use defmt::{error, info};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+use embassy_time::{with_timeout, Duration};
+use embedded_storage_async::nor_flash::NorFlash;
+use key_lib::{
+    com::{ForwardStatus, HidRequest},
+    dfu::{DfuError, DfuWriter},
+};
+use num_enum::TryFromPrimitive;
+
+use crate::{
+    link::{self, Half},
+    radio::{self, receive_packet, Packet, PacketType},
+};
+
+// Time to wait for the half to verify the image after the last chunk
+const FINISH_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Value the bootloader checks GPREGRET for on boot to stay in uf2 mode
+const BOOTLOADER_DFU_MAGIC: u8 = 0x57;
+
+static DFU_REQUESTED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// First byte of every dfu packet. Start is followed by the image length and
+/// crc and Data by the offset of the chunk, all as le u32s
+#[repr(u8)]
+#[derive(Clone, Copy, PartialEq, Eq, TryFromPrimitive)]
+enum DfuCommand {
+    Start = 0,
+    Data = 1,
+    Finish = 2,
+}
+
+fn read_u32(buf: &[u8]) -> u32 {
+    u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]])
+}
+
+/// Resets into the bootloader, which shows up as a uf2 drive instead of
+/// starting the application
+pub fn reset_to_bootloader() -> ! {
+    embassy_nrf::pac::POWER
+        .gpregret()
+        .write(|w| w.set_gpregret(BOOTLOADER_DFU_MAGIC));
+    cortex_m::peripheral::SCB::sys_reset()
+}
+
+/// Puts the half in dfu mode so it starts listening for an image
+pub fn request_dfu() {
+    DFU_REQUESTED.signal(());
+}
+
+/// Puts the half in dfu mode and sends the start of an image
+pub async fn start(half: Half, len: u32, crc: u32) -> ForwardStatus {
+    if link::forward(half, &[HidRequest::DfuStart as u8])
+        .await
+        .is_none()
+    {
+        return ForwardStatus::Timeout;
+    }
+    let mut packet = Packet::default();
+    let mut buf = [0u8; 9];
+    buf[0] = DfuCommand::Start as u8;
+    buf[1..5].copy_from_slice(&len.to_le_bytes());
+    buf[5..9].copy_from_slice(&crc.to_le_bytes());
+    packet.copy_from_slice(&buf);
+    radio::send_dfu(&packet).await;
+    ForwardStatus::Ok
+}
+
+/// Sends a chunk of the image. The radio only sends the next packet once the
+/// previous one is acked, so chunks arrive in order
+pub async fn send_data(offset: u32, data: &[u8]) {
+    let mut packet = Packet::default();
+    let mut buf = [0u8; 5 + key_lib::com::MAX_DFU_CHUNK_LEN];
+    buf[0] = DfuCommand::Data as u8;
+    buf[1..5].copy_from_slice(&offset.to_le_bytes());
+    buf[5..][..data.len()].copy_from_slice(data);
+    packet.copy_from_slice(&buf[..5 + data.len()]);
+    radio::send_dfu(&packet).await;
+}
+
+/// Tells the half the image is complete and waits for it to verify the image.
+/// Returns the status of the relay and the dfu result, 0 if the image was staged
+pub async fn finish(half: Half) -> (ForwardStatus, u8) {
+    let addr = half as u8;
+    radio::clear_config();
+    let mut packet = Packet::default();
+    packet.copy_from_slice(&[DfuCommand::Finish as u8]);
+    radio::send_dfu(&packet).await;
+    let response = with_timeout(FINISH_TIMEOUT, async {
+        loop {
+            let packet = radio::receive_config().await;
+            if packet.addr == addr {
+                return packet;
+            }
+        }
+    })
+    .await;
+    match response {
+        Ok(packet) if !packet.is_empty() => (ForwardStatus::Ok, packet[0]),
+        Ok(_) => (ForwardStatus::Invalid, 0),
+        Err(_) => (ForwardStatus::Timeout, 0),
+    }
+}
+
+/// Waits for the dongle to put the half in dfu mode and writes the image it
+/// sends to the staging area. The first error is kept until the dongle
+/// finishes so the half keeps acking the rest of the image
+pub async fn run_dfu_receiver<F: NorFlash>(mut writer: DfuWriter<F>) -> ! {
+    loop {
+        DFU_REQUESTED.wait().await;
+        info!("Entered dfu mode");
+        let mut result: Result<(), DfuError> = Ok(());
+        loop {
+            let packet = receive_packet().await;
+            if !matches!(packet.packet_type(), Ok(PacketType::Dfu)) || packet.is_empty() {
+                continue;
+            }
+            let res = match DfuCommand::try_from(packet[0]) {
+                Ok(DfuCommand::Start) if packet.len() >= 9 => {
+                    writer
+                        .start(read_u32(&packet[1..5]), read_u32(&packet[5..9]))
+                        .await
+                }
+                Ok(DfuCommand::Data) if packet.len() >= 5 => {
+                    writer.write(read_u32(&packet[1..5]), &packet[5..]).await
+                }
+                Ok(DfuCommand::Finish) => {
+                    result = result.and(writer.finish().await);
+                    break;
+                }
+                _ => Ok(()),
+            };
+            if result.is_ok() {
+                result = res;
+            }
+        }
+        let status = match result {
+            Ok(()) => 0,
+            Err(e) => {
+                error!("Dfu failed: {}", e);
+                e as u8
+            }
+        };
+        let mut response = Packet::default();
+        response.copy_from_slice(&[status]);
+        radio::send_config(&response).await;
+    }
+}
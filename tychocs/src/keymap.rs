@@ -0,0 +1,202 @@
+//! Reading and writing the keymap a half keeps in its own flash for running
+//! on its own over usb. The dongle forwards HidRequest::HalfLayer and
+//! HidRequest::UpdateHalfLayer to the half like any com request, and the
+//! layer itself is sent as a message in fragments, so the host can keep the
+//! keymaps of the dongle and both halves in step from the dongle's com
+//! interface
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use defmt::{error, info};
+use embassy_sync::{
+    blocking_mutex::raw::{CriticalSectionRawMutex, ThreadModeRawMutex},
+    channel::Channel,
+    mutex::Mutex,
+};
+use embassy_time::{with_timeout, Duration};
+use key_lib::{
+    codes::{ScanCodeLayerStorage, MAX_SERIAL_LENGTH},
+    com::{ForwardStatus, HidRequest},
+    keys::{ConfigIndicator, Keys},
+    NUM_CONFIGS, NUM_KEYS, NUM_LAYERS,
+};
+use sequential_storage::map::Value;
+
+use crate::{
+    link::{self, Half},
+    radio::{self, Message, Packet, MAX_MESSAGE_LEN},
+};
+
+// Time to wait for the layer once its transfer was accepted. The fragments
+// are sent back to back right after
+const MESSAGE_TIMEOUT: Duration = Duration::from_secs(2);
+
+// Time to wait for the half to write the layer to flash. Every layer of the
+// config is rewritten to its other slot
+const STORE_TIMEOUT: Duration = Duration::from_secs(5);
+
+const _: () = assert!(NUM_KEYS * MAX_SERIAL_LENGTH <= MAX_MESSAGE_LEN);
+
+static REQUESTS: Channel<CriticalSectionRawMutex, Packet, 1> = Channel::new();
+
+// Set once the half runs [run_keymap_server]
+static SERVING: AtomicBool = AtomicBool::new(false);
+
+fn status(response: &[u8]) -> ForwardStatus {
+    response
+        .first()
+        .and_then(|&status| ForwardStatus::try_from(status).ok())
+        .unwrap_or(ForwardStatus::Invalid)
+}
+
+/// Reads a layer of the half's keymap. The message holds the serialized
+/// layer
+pub async fn read_layer(half: Half, config_num: u8, layer: u8) -> Result<Message, ForwardStatus> {
+    radio::clear_messages();
+    let request = [HidRequest::HalfLayer as u8, config_num, layer];
+    match link::forward(half, &request).await.map(|r| status(&r)) {
+        Some(ForwardStatus::Ok) => {}
+        Some(status) => return Err(status),
+        None => return Err(ForwardStatus::Timeout),
+    }
+    let addr = half as u8;
+    with_timeout(MESSAGE_TIMEOUT, async {
+        loop {
+            let message = radio::receive_message().await;
+            if message.addr == addr {
+                return message;
+            }
+        }
+    })
+    .await
+    .map_err(|_| ForwardStatus::Timeout)
+}
+
+/// Sends a serialized layer to the half and waits for it to be written to
+/// the half's flash
+pub async fn write_layer(half: Half, config_num: u8, layer: u8, data: &[u8]) -> ForwardStatus {
+    let request = [HidRequest::UpdateHalfLayer as u8, config_num, layer];
+    match link::forward(half, &request).await.map(|r| status(&r)) {
+        Some(ForwardStatus::Ok) => {}
+        Some(status) => return status,
+        None => return ForwardStatus::Timeout,
+    }
+    radio::send_message(data).await;
+    let addr = half as u8;
+    let response = with_timeout(STORE_TIMEOUT, async {
+        loop {
+            let packet = radio::receive_config().await;
+            if packet.addr == addr {
+                return packet;
+            }
+        }
+    })
+    .await;
+    match response {
+        Ok(packet) => status(&packet),
+        Err(_) => ForwardStatus::Timeout,
+    }
+}
+
+/// Passes a keymap request forwarded by the dongle to [run_keymap_server].
+/// Returns false if the half doesn't keep a keymap
+pub(crate) fn submit(request: Packet) -> bool {
+    SERVING.load(Ordering::Relaxed) && REQUESTS.try_send(request).is_ok()
+}
+
+async fn respond(status: ForwardStatus) {
+    let mut response = Packet::default();
+    response.copy_from_slice(&[status as u8]);
+    radio::send_config(&response).await;
+}
+
+// Returns the layer of the config. Configs that were never written read back
+// as empty
+async fn load_layer<I: ConfigIndicator>(
+    keys: &Mutex<ThreadModeRawMutex, Keys<I>>,
+    config_num: usize,
+    layer: usize,
+) -> ScanCodeLayerStorage<NUM_KEYS> {
+    let lock = keys.lock().await;
+    if lock.config_num == config_num {
+        return lock.layer(layer);
+    }
+    drop(lock);
+    let mut other = Keys::<I>::default();
+    let _ = other.load_keys_from_storage(config_num).await;
+    other.layer(layer)
+}
+
+// Replaces the layer of the config and writes the config to flash. The
+// running keys pick it up right away if the config is active
+async fn store_layer<I: ConfigIndicator>(
+    keys: &Mutex<ThreadModeRawMutex, Keys<I>>,
+    config_num: usize,
+    layer: usize,
+    codes: &ScanCodeLayerStorage<NUM_KEYS>,
+) {
+    let mut lock = keys.lock().await;
+    if lock.config_num == config_num {
+        lock.set_layer(layer, codes);
+        lock.write_keys_to_storage(config_num).await;
+        return;
+    }
+    drop(lock);
+    let mut other = Keys::<I>::default();
+    let _ = other.load_keys_from_storage(config_num).await;
+    other.set_layer(layer, codes);
+    other.write_keys_to_storage(config_num).await;
+}
+
+/// Serves the keymap requests the dongle forwards to the half from the keys
+/// and the half's storage. Reads answer with the layer as a message, writes
+/// take the layer as a message and answer once it's stored
+pub async fn run_keymap_server<I: ConfigIndicator>(keys: &Mutex<ThreadModeRawMutex, Keys<I>>) -> ! {
+    SERVING.store(true, Ordering::Relaxed);
+    loop {
+        let request = REQUESTS.receive().await;
+        let target = match request.get(1..3) {
+            Some(&[config_num, layer])
+                if (config_num as usize) < NUM_CONFIGS && (layer as usize) < NUM_LAYERS =>
+            {
+                Some((config_num as usize, layer as usize))
+            }
+            _ => None,
+        };
+        let Some((config_num, layer)) = target else {
+            error!("Received invalid keymap request");
+            respond(ForwardStatus::Invalid).await;
+            continue;
+        };
+        if request[0] == HidRequest::HalfLayer as u8 {
+            let codes = load_layer(keys, config_num, layer).await;
+            let mut buf = [0u8; MAX_MESSAGE_LEN];
+            match codes.serialize_into(&mut buf) {
+                Ok(len) => {
+                    respond(ForwardStatus::Ok).await;
+                    radio::send_message(&buf[..len]).await;
+                }
+                Err(_) => respond(ForwardStatus::Invalid).await,
+            }
+            continue;
+        }
+        radio::clear_messages();
+        respond(ForwardStatus::Ok).await;
+        let Some(message) = radio::listen_for_message(MESSAGE_TIMEOUT).await else {
+            error!("Dongle didn't send the layer");
+            respond(ForwardStatus::Timeout).await;
+            continue;
+        };
+        match ScanCodeLayerStorage::<NUM_KEYS>::deserialize_from(&message) {
+            Ok((codes, _)) => {
+                info!("Storing config {} | layer {}", config_num, layer);
+                store_layer(keys, config_num, layer, &codes).await;
+                respond(ForwardStatus::Ok).await;
+            }
+            Err(_) => {
+                error!("Received invalid layer");
+                respond(ForwardStatus::Invalid).await;
+            }
+        }
+    }
+}
@@ -1,11 +1,36 @@
 #![no_std]
 
+use core::ops::Range;
+
 pub const DONGLE_ADDRESS: u32 = 0x0A55_0A55;
 pub const DONGLE_PREFIX: u8 = 0x42;
 pub const KEYBOARD_ADDRESS: u32 = 0x0727_0727;
 pub const LEFT_PREFIX: u8 = 0x21;
 pub const RIGHT_PREFIX: u8 = 0x25;
 
+/// Flash range firmware images are staged in before the bootloader applies
+/// them. Starts after the crash page and runs up to the bootloader, so it
+/// holds an image as large as the application region in memory.x
+pub const DFU_STAGING: Range<u32> = 0x0009_0000..0x000F_4000;
+
+/// Flash range the left half keeps its own keymap in, between the
+/// application region in memory.x and the crash page
+pub const HALF_STORAGE: Range<u32> = 0x0008_7000..0x0008_F000;
+
+/// Switch state of the matrix keys. With the ramp-travel feature held keys
+/// ramp their travel up over time so analog behaviors work on choc switches
+#[cfg(not(feature = "ramp-travel"))]
+pub type Switch = key_lib::position::DefaultSwitch;
+#[cfg(feature = "ramp-travel")]
+pub type Switch = key_lib::position::RampSwitch;
+
+pub mod battery;
+#[cfg(feature = "ble")]
+pub mod ble;
+pub mod dfu;
 pub mod key_config;
+pub mod keymap;
+pub mod link;
 pub mod radio;
 pub mod sensors;
+pub mod watchdog;
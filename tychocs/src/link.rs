@@ -0,0 +1,934 @@
+use core::{
+    cell::Cell,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use defmt::{error, info, Format};
+use embassy_sync::{
+    blocking_mutex::{raw::CriticalSectionRawMutex, Mutex},
+    signal::Signal,
+};
+use embassy_time::{with_timeout, Duration, Instant, Timer};
+use key_lib::{
+    com::{ForwardStatus, HidRequest, LINK_STATS_LEN, LINK_STATUS_LEN},
+    diagnostics::{LinkReport, LINK_STATS_SERIAL_LENGTH},
+    display,
+    host_switch::{load_host_pairings, wait_host_switch, HostAddress},
+    keys::HostLeds,
+    link_watch::HalfLinkState,
+    power::{
+        wait_battery_thresholds, wait_link_timing, BatteryThresholds, LinkTiming,
+        BATTERY_THRESHOLDS, BATTERY_THRESHOLDS_SERIAL_LENGTH, LINK_TIMING_SERIAL_LENGTH,
+    },
+    self_test::{record_radio_check, wait_self_test_start, RadioCheck},
+    storage::{SettingId, StorageItem},
+    NUM_KEYS,
+};
+
+use crate::{
+    battery::{self, usb_powered},
+    radio::{self, Addresses, Packet},
+};
+
+// Time between searching messages while no half has been heard from
+const SEARCH_INTERVAL: Duration = Duration::from_secs(5);
+
+// Time to wait for a half to answer a forwarded request. Requests are only
+// delivered when the half sends a packet, so a key on it has to be pressed
+const FORWARD_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Lock key leds set by the host. Set by the dongle's usb request handler to
+/// be relayed to the halves and by the config handler on the halves
+static HOST_LEDS: Signal<CriticalSectionRawMutex, HostLeds> = Signal::new();
+
+/// Time between key states sent by an idle half while the host is asleep. The
+/// interval while it's awake is set by the [LinkTiming]
+pub const ASLEEP_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(60);
+
+// Sleeping halves that miss a few heartbeats are shown as disconnected
+const ASLEEP_CONNECTED_TIMEOUT: Duration = Duration::from_secs(150);
+
+/// Heartbeat interval and fastest scan rate of the halves. Loaded from storage
+/// on the dongle and sent by it to each half when the half connects, so both
+/// ends time out a half after the same number of missed heartbeats
+static LINK_TIMING: Mutex<CriticalSectionRawMutex, Cell<LinkTiming>> =
+    Mutex::new(Cell::new(LinkTiming::default()));
+
+/// Battery thresholds the dongle sends to each half when the half connects.
+/// Halves don't run storage, so they'd go back to the defaults on every boot
+/// otherwise
+static HALF_THRESHOLDS: Mutex<CriticalSectionRawMutex, Cell<BatteryThresholds>> =
+    Mutex::new(Cell::new(BatteryThresholds::default()));
+
+/// Time each half was last heard from by the dongle. Halves send packets when
+/// their state changes and every heartbeat interval while idle
+static LAST_SEEN: Mutex<CriticalSectionRawMutex, Cell<[Option<Instant>; 2]>> =
+    Mutex::new(Cell::new([None; 2]));
+
+/// Flag sent after the key state by a half that sends its reports over usb
+pub const KEY_STATE_WIRED: u8 = 1 << 0;
+/// Flag sent after the key state by a half whose battery is low
+pub const KEY_STATE_BATTERY_LOW: u8 = 1 << 1;
+/// Flag sent after the key state of an analog packet that holds the travel
+/// of every key instead of the changes since the last packet
+pub const ANALOG_KEYFRAME: u8 = 1 << 2;
+/// Flag sent after the key state by a half that was told the host is asleep
+pub const KEY_STATE_ASLEEP: u8 = 1 << 3;
+
+/// Keys on a half
+pub const HALF_KEYS: usize = NUM_KEYS / 2;
+
+// Analog packets start with the key state and its flags
+const ANALOG_HEADER_LEN: usize = 5;
+
+// Changes that fit in an analog packet, each the index of the key and its
+// change in travel
+const MAX_ANALOG_CHANGES: usize = (radio::MAX_PACKET_LEN - ANALOG_HEADER_LEN) / 2;
+
+// Time between keyframes, so a dongle that restarted or missed a packet
+// catches up
+const ANALOG_KEYFRAME_INTERVAL: Duration = Duration::from_secs(1);
+
+const _: () = assert!(ANALOG_HEADER_LEN + HALF_KEYS <= radio::MAX_PACKET_LEN);
+
+/// Set by a half while it sends its reports over its own usb connection
+static WIRED: AtomicBool = AtomicBool::new(false);
+
+/// Signaled when the usb handler of a half changes [WIRED]
+static WIRED_CHANGED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+// Time between vbus checks while arbitrating. Unplugging the half drops vbus
+// before its usb handler hears about it
+const VBUS_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Halves the dongle was told are wired. Their radio key states are ignored
+static WIRED_HALVES: Mutex<CriticalSectionRawMutex, Cell<[bool; 2]>> =
+    Mutex::new(Cell::new([false; 2]));
+
+/// Latest battery reading in millivolts and low flag sent by each half
+static HALF_BATTERIES: Mutex<CriticalSectionRawMutex, Cell<[(u16, bool); 2]>> =
+    Mutex::new(Cell::new([(0, false); 2]));
+
+/// Version of the packets exchanged between the dongle and the halves. Bump it
+/// whenever the format or meaning of a packet or config request changes, so
+/// halves and dongles flashed with other firmware report the mismatch
+pub const LINK_PROTOCOL_VERSION: u8 = 7;
+
+// Config request sent by the dongle with its LinkVersion. Outside the
+// HidRequest range since it never comes from the host
+const LINK_VERSION_REQUEST: u8 = 0xf0;
+
+// Config request sent by the dongle with 1 when its usb is suspended and 0
+// when it resumes
+const POWER_STATE_REQUEST: u8 = 0xf1;
+
+// Config request sent by the dongle with the active layer and config, so the
+// halves can show them
+const LAYER_REQUEST: u8 = 0xf2;
+
+// Config request sent by the dongle with the serialized [LinkTiming] when a
+// half connects and when the host changes it
+const LINK_TIMING_REQUEST: u8 = 0xf3;
+
+/// Set on the dongle while its usb is suspended and on a half while the dongle
+/// says so. Halves scan slowly, send fewer heartbeats and dim their indicators
+static HOST_ASLEEP: AtomicBool = AtomicBool::new(false);
+
+/// Signaled when [HOST_ASLEEP] changes. The dongle relays it to the halves and
+/// the halves update their indicators
+static POWER_STATE: Signal<CriticalSectionRawMutex, bool> = Signal::new();
+
+/// Power state each half sent with its key states
+static HALVES_ASLEEP: Mutex<CriticalSectionRawMutex, Cell<[bool; 2]>> =
+    Mutex::new(Cell::new([false; 2]));
+
+/// Link version sent by each half with its key states
+static HALF_VERSIONS: Mutex<CriticalSectionRawMutex, Cell<[Option<LinkVersion>; 2]>> =
+    Mutex::new(Cell::new([None; 2]));
+
+/// Set by a half once the dongle sent a link version that doesn't match its own
+static VERSION_MISMATCH: AtomicBool = AtomicBool::new(false);
+
+/// Link protocol and firmware version of a dongle or half
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Format)]
+pub struct LinkVersion {
+    pub protocol: u8,
+    pub firmware: [u8; 3],
+}
+
+impl LinkVersion {
+    pub const LEN: usize = 4;
+
+    /// Version of halves running firmware from before the version was sent
+    pub const UNKNOWN: Self = Self {
+        protocol: 0,
+        firmware: [0; 3],
+    };
+
+    /// Returns the version of this firmware
+    pub fn current() -> Self {
+        Self {
+            protocol: LINK_PROTOCOL_VERSION,
+            firmware: [
+                env!("CARGO_PKG_VERSION_MAJOR").parse().unwrap_or(0),
+                env!("CARGO_PKG_VERSION_MINOR").parse().unwrap_or(0),
+                env!("CARGO_PKG_VERSION_PATCH").parse().unwrap_or(0),
+            ],
+        }
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Option<Self> {
+        let buf = buf.get(..Self::LEN)?;
+        Some(Self {
+            protocol: buf[0],
+            firmware: [buf[1], buf[2], buf[3]],
+        })
+    }
+
+    pub fn to_bytes(&self) -> [u8; Self::LEN] {
+        [
+            self.protocol,
+            self.firmware[0],
+            self.firmware[1],
+            self.firmware[2],
+        ]
+    }
+
+    /// Returns true if both sides speak the same link protocol. The firmware
+    /// version is only reported
+    pub fn is_compatible(&self, other: &LinkVersion) -> bool {
+        self.protocol == other.protocol
+    }
+}
+
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Half {
+    Left = 1,
+    Right = 2,
+}
+
+impl Half {
+    pub fn from_addr(addr: u8) -> Option<Self> {
+        match addr {
+            1 => Some(Half::Left),
+            2 => Some(Half::Right),
+            _ => None,
+        }
+    }
+
+    /// Index of the half in per half state
+    pub fn index(&self) -> usize {
+        *self as usize - 1
+    }
+}
+
+/// Marks the half with the radio address as seen
+pub fn mark_seen(addr: u8) {
+    if let Some(half) = Half::from_addr(addr) {
+        LAST_SEEN.lock(|seen| {
+            let mut times = seen.get();
+            times[half.index()] = Some(Instant::now());
+            seen.set(times);
+        });
+    }
+}
+
+pub fn last_seen(half: Half) -> Option<Instant> {
+    LAST_SEEN.lock(|seen| seen.get()[half.index()])
+}
+
+/// Marks the half as sending its reports over usb. Should be set once the
+/// half's usb keyboard is configured by a host
+pub fn set_wired(wired: bool) {
+    WIRED.store(wired, Ordering::Release);
+    WIRED_CHANGED.signal(());
+}
+
+/// Returns true if the half should send its reports over usb instead of the
+/// radio. Wired always wins, but only while vbus is present so unplugging
+/// the half falls back to the radio right away
+pub fn is_wired() -> bool {
+    WIRED.load(Ordering::Acquire) && usb_powered()
+}
+
+/// Returns the packet a half sends to the dongle with its key state, followed
+/// by its flags, battery reading as a le u16 and its [LinkVersion]. Wired
+/// halves send an empty state so the dongle releases their keys
+pub fn key_state_packet(state: u32, wired: bool) -> Packet {
+    let mut packet = Packet::default();
+    let mut buf = [0u8; 7 + LinkVersion::LEN];
+    if wired {
+        buf[4] = KEY_STATE_WIRED;
+    } else {
+        buf[..4].copy_from_slice(&state.to_le_bytes());
+    }
+    if battery::is_low() {
+        buf[4] |= KEY_STATE_BATTERY_LOW;
+    }
+    if is_host_asleep() {
+        buf[4] |= KEY_STATE_ASLEEP;
+    }
+    buf[5..7].copy_from_slice(&battery::last_millivolts().to_le_bytes());
+    buf[7..].copy_from_slice(&LinkVersion::current().to_bytes());
+    packet.copy_from_slice(&buf);
+    packet
+}
+
+/// Returns the packet a half sends with [radio::send_source] when it starts
+/// or stops sending its reports over usb. Holds [KEY_STATE_WIRED] while wired
+pub fn source_packet(wired: bool) -> Packet {
+    let mut packet = Packet::default();
+    packet.copy_from_slice(&[if wired { KEY_STATE_WIRED } else { 0 }]);
+    packet
+}
+
+/// Tells the dongle which link the half's reports come from. The dongle mutes
+/// the half's keys as soon as its usb keyboard is configured by a host and
+/// forwards them again once it's unplugged, without waiting for the next key
+/// state. Key states keep carrying the wired flag so a dongle that restarted
+/// catches up
+pub async fn run_source_arbitration() -> ! {
+    let mut wired = false;
+    loop {
+        let _ = with_timeout(VBUS_POLL_INTERVAL, WIRED_CHANGED.wait()).await;
+        let new_wired = is_wired();
+        if new_wired != wired {
+            wired = new_wired;
+            info!(
+                "Sending reports over {}",
+                if wired { "usb" } else { "the dongle" }
+            );
+            radio::send_source(&source_packet(wired)).await;
+        }
+    }
+}
+
+/// Usb device handler of a half that marks it as wired while a host has its
+/// usb keyboard configured
+pub struct WiredHandler;
+
+impl embassy_usb::Handler for WiredHandler {
+    fn enabled(&mut self, enabled: bool) {
+        if !enabled {
+            set_wired(false);
+        }
+    }
+
+    fn reset(&mut self) {
+        set_wired(false);
+    }
+
+    fn configured(&mut self, configured: bool) {
+        set_wired(configured);
+    }
+}
+
+/// Encodes the travel of a half's keys into analog packets, sent with
+/// [radio::send_analog] in place of key state packets. Only the keys whose
+/// travel changed are sent, as their change since the last packet, with a
+/// keyframe of every key first, every [ANALOG_KEYFRAME_INTERVAL] and whenever
+/// too many keys changed at once. Wired halves send key state packets instead,
+/// and idle halves keep sending them as heartbeats
+pub struct AnalogEncoder {
+    last: [u8; HALF_KEYS],
+    last_keyframe: Option<Instant>,
+}
+
+impl AnalogEncoder {
+    pub const fn new() -> Self {
+        Self {
+            last: [0; HALF_KEYS],
+            last_keyframe: None,
+        }
+    }
+
+    /// Returns the packet with the key state and the travels from 0 to 100
+    /// that changed, or None if no travel changed
+    pub fn packet(&mut self, state: u32, travels: &[u8; HALF_KEYS]) -> Option<Packet> {
+        let mut buf = [0u8; radio::MAX_PACKET_LEN];
+        buf[..4].copy_from_slice(&state.to_le_bytes());
+        let changed = travels
+            .iter()
+            .zip(&self.last)
+            .filter(|(travel, last)| travel != last)
+            .count();
+        let keyframe_due = self
+            .last_keyframe
+            .is_none_or(|at| at.elapsed() >= ANALOG_KEYFRAME_INTERVAL);
+        let len = if keyframe_due || changed > MAX_ANALOG_CHANGES {
+            buf[4] |= ANALOG_KEYFRAME;
+            buf[ANALOG_HEADER_LEN..][..HALF_KEYS].copy_from_slice(travels);
+            self.last_keyframe = Some(Instant::now());
+            ANALOG_HEADER_LEN + HALF_KEYS
+        } else if changed == 0 {
+            return None;
+        } else {
+            let changes = travels
+                .iter()
+                .zip(&self.last)
+                .enumerate()
+                .filter(|(_, (travel, last))| travel != last);
+            for ((i, (travel, last)), pair) in
+                changes.zip(buf[ANALOG_HEADER_LEN..].chunks_exact_mut(2))
+            {
+                pair[0] = i as u8;
+                pair[1] = travel.wrapping_sub(*last);
+            }
+            ANALOG_HEADER_LEN + 2 * changed
+        };
+        self.last = *travels;
+        let mut packet = Packet::default();
+        packet.copy_from_slice(&buf[..len]);
+        Some(packet)
+    }
+}
+
+impl Default for AnalogEncoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Rebuilds the travel of a half's keys from its analog packets
+pub struct AnalogDecoder {
+    travels: [u8; HALF_KEYS],
+    // Cleared until the first keyframe, changes before it have nothing to
+    // apply to
+    synced: bool,
+}
+
+impl AnalogDecoder {
+    pub const fn new() -> Self {
+        Self {
+            travels: [0; HALF_KEYS],
+            synced: false,
+        }
+    }
+
+    /// Applies the analog packet and returns the travel of every key, or None
+    /// while no keyframe has arrived
+    pub fn decode(&mut self, packet: &[u8]) -> Option<[u8; HALF_KEYS]> {
+        let flags = *packet.get(4)?;
+        let payload = &packet[ANALOG_HEADER_LEN..];
+        if flags & ANALOG_KEYFRAME != 0 {
+            let travels = payload.get(..HALF_KEYS)?;
+            self.travels.copy_from_slice(travels);
+            self.synced = true;
+        } else if self.synced {
+            for pair in payload.chunks_exact(2) {
+                if let Some(travel) = self.travels.get_mut(pair[0] as usize) {
+                    *travel = travel.wrapping_add(pair[1]);
+                }
+            }
+        }
+        self.synced.then_some(self.travels)
+    }
+}
+
+impl Default for AnalogDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Records whether the half told the dongle it's wired
+pub fn set_half_wired(half: Half, wired: bool) {
+    WIRED_HALVES.lock(|halves| {
+        let mut states = halves.get();
+        if states[half.index()] != wired {
+            info!("Half {} wired: {}", half as u8, wired);
+        }
+        states[half.index()] = wired;
+        halves.set(states);
+    });
+}
+
+pub fn is_half_wired(half: Half) -> bool {
+    WIRED_HALVES.lock(|halves| halves.get()[half.index()])
+}
+
+/// Records the battery reading the half sent with its key state
+pub fn set_half_battery(half: Half, millivolts: u16, low: bool) {
+    HALF_BATTERIES.lock(|batteries| {
+        let mut readings = batteries.get();
+        if low && !readings[half.index()].1 {
+            info!("Half {} battery low at {}mV", half as u8, millivolts);
+        }
+        readings[half.index()] = (millivolts, low);
+        batteries.set(readings);
+    });
+}
+
+/// Records the link version the half sent with its key state. The dongle's
+/// version, link timing and battery thresholds are sent back when the half
+/// connects or its version changes, so the half can show a mismatch too and
+/// uses the dongle's settings
+pub fn record_half_version(half: Half, version: LinkVersion, reconnected: bool) {
+    let changed = HALF_VERSIONS.lock(|versions| {
+        let mut known = versions.get();
+        let changed = known[half.index()] != Some(version);
+        known[half.index()] = Some(version);
+        versions.set(known);
+        changed
+    });
+    if changed {
+        let own = LinkVersion::current();
+        if own.is_compatible(&version) {
+            info!("Half {} runs firmware {}", half as u8, version);
+        } else {
+            error!(
+                "Half {} runs link protocol {}, dongle runs {}",
+                half as u8, version.protocol, own.protocol
+            );
+        }
+    }
+    if changed || reconnected {
+        let mut request = [0u8; 1 + LinkVersion::LEN];
+        request[0] = LINK_VERSION_REQUEST;
+        request[1..].copy_from_slice(&LinkVersion::current().to_bytes());
+        radio::queue_config(half as u8, &request);
+        queue_link_timing(half);
+        queue_battery_thresholds(half);
+    }
+}
+
+/// Sets the link timing used by this device. The dongle sets it from storage
+/// at boot, halves when the dongle sends it
+pub fn set_link_timing(timing: LinkTiming) {
+    LINK_TIMING.lock(|link_timing| link_timing.set(timing));
+}
+
+pub fn link_timing() -> LinkTiming {
+    LINK_TIMING.lock(|link_timing| link_timing.get())
+}
+
+fn queue_link_timing(half: Half) {
+    let mut request = [0u8; 1 + LINK_TIMING_SERIAL_LENGTH];
+    request[0] = LINK_TIMING_REQUEST;
+    StorageItem::LinkTiming(link_timing())
+        .serialize_into(&mut request[1..])
+        .unwrap();
+    radio::queue_config(half as u8, &request);
+}
+
+/// Sets the battery thresholds the dongle sends to the halves. Loaded from
+/// storage at boot
+pub fn set_half_thresholds(thresholds: BatteryThresholds) {
+    HALF_THRESHOLDS.lock(|half_thresholds| half_thresholds.set(thresholds));
+}
+
+fn queue_battery_thresholds(half: Half) {
+    let mut request = [0u8; 1 + BATTERY_THRESHOLDS_SERIAL_LENGTH];
+    request[0] = HidRequest::UpdateBatteryThresholds as u8;
+    HALF_THRESHOLDS
+        .lock(|thresholds| thresholds.get())
+        .into_buffer(&mut request[1..])
+        .unwrap();
+    radio::queue_config(half as u8, &request);
+}
+
+/// Relays battery thresholds set by the host to both halves
+pub async fn run_battery_thresholds_relay() -> ! {
+    loop {
+        set_half_thresholds(wait_battery_thresholds().await);
+        for half in [Half::Left, Half::Right] {
+            queue_battery_thresholds(half);
+        }
+    }
+}
+
+/// Relays link timings set by the host to both halves. Each half gets it with
+/// the ack of the next packet it sends, so a half on a long heartbeat can
+/// take that long to pick up a shorter one
+pub async fn run_link_timing_relay() -> ! {
+    loop {
+        let timing = wait_link_timing().await;
+        set_link_timing(timing);
+        for half in [Half::Left, Half::Right] {
+            queue_link_timing(half);
+        }
+    }
+}
+
+/// Marks the host as asleep or awake. Called by the dongle's usb handler when
+/// the bus is suspended or resumed and by the halves when the dongle says so
+pub fn set_host_asleep(asleep: bool) {
+    if HOST_ASLEEP.swap(asleep, Ordering::Relaxed) != asleep {
+        info!("Host asleep: {}", asleep);
+        display::set_asleep(asleep);
+        POWER_STATE.signal(asleep);
+    }
+}
+
+pub fn is_host_asleep() -> bool {
+    HOST_ASLEEP.load(Ordering::Relaxed)
+}
+
+/// Waits for the host to fall asleep or wake up
+pub async fn wait_power_state() -> bool {
+    POWER_STATE.wait().await
+}
+
+/// Returns the time between key states sent by an idle half
+pub fn heartbeat_interval() -> Duration {
+    if is_host_asleep() {
+        ASLEEP_HEARTBEAT_INTERVAL
+    } else {
+        link_timing().heartbeat_interval()
+    }
+}
+
+/// Records the power state the half sent with its key state. A half that
+/// missed the last change, e.g. because other config took its place, is sent
+/// the current state again with the ack of its next packet
+pub fn record_half_power_state(half: Half, asleep: bool) {
+    HALVES_ASLEEP.lock(|halves| {
+        let mut states = halves.get();
+        states[half.index()] = asleep;
+        halves.set(states);
+    });
+    let addr = half as u8;
+    if asleep != is_host_asleep() && !radio::is_config_pending(addr, POWER_STATE_REQUEST) {
+        radio::queue_config(addr, &[POWER_STATE_REQUEST, is_host_asleep() as u8]);
+    }
+}
+
+/// Relays the host's power state to both halves. Each half gets it with the
+/// ack of the next packet it sends, so a sleeping half wakes up as soon as a
+/// key on it is pressed or its next heartbeat goes out
+pub async fn run_power_state_relay() -> ! {
+    loop {
+        let asleep = wait_power_state().await;
+        for half in [Half::Left, Half::Right] {
+            radio::queue_config(half as u8, &[POWER_STATE_REQUEST, asleep as u8]);
+        }
+    }
+}
+
+/// Returns true if the half sent a link version that doesn't match the dongle's
+pub fn is_half_mismatched(half: Half) -> bool {
+    HALF_VERSIONS.lock(|versions| {
+        versions.get()[half.index()]
+            .is_some_and(|version| !LinkVersion::current().is_compatible(&version))
+    })
+}
+
+/// Returns true on a half that was told by the dongle that their link
+/// versions don't match
+pub fn is_version_mismatched() -> bool {
+    VERSION_MISMATCH.load(Ordering::Relaxed)
+}
+
+/// Returns true if the half was heard from recently enough to be considered
+/// connected. Sleeping halves send fewer heartbeats
+pub fn is_connected(half: Half) -> bool {
+    let asleep = HALVES_ASLEEP.lock(|halves| halves.get()[half.index()]);
+    let timeout = if asleep {
+        ASLEEP_CONNECTED_TIMEOUT
+    } else {
+        link_timing().connected_timeout()
+    };
+    last_seen(half).is_some_and(|time| time.elapsed() < timeout)
+}
+
+/// Returns true if the dongle hasn't heard from either half since it started
+pub fn is_searching() -> bool {
+    LAST_SEEN.lock(|seen| seen.get().iter().all(|time| time.is_none()))
+}
+
+/// Returns the response for HidRequest::LinkStatus
+pub fn status_buffer() -> [u8; LINK_STATUS_LEN] {
+    let mut buf = [0u8; LINK_STATUS_LEN];
+    buf[0] = 1;
+    for (half, chunk) in [Half::Left, Half::Right]
+        .into_iter()
+        .zip(buf[1..].chunks_exact_mut(4))
+    {
+        let elapsed = match last_seen(half) {
+            Some(time) => time.elapsed().as_millis().min(u32::MAX as u64 - 1) as u32,
+            None => u32::MAX,
+        };
+        chunk.copy_from_slice(&elapsed.to_le_bytes());
+    }
+    buf
+}
+
+/// Returns the state of both halves for HidRequest::WatchLink
+pub fn half_link_states() -> [HalfLinkState; 2] {
+    let batteries = HALF_BATTERIES.lock(|batteries| batteries.get());
+    [Half::Left, Half::Right].map(|half| {
+        let elapsed = last_seen(half).map(|time| time.elapsed());
+        let (battery_mv, battery_low) = batteries[half.index()];
+        HalfLinkState {
+            connected: is_connected(half),
+            wired: is_half_wired(half),
+            battery_low,
+            version_mismatch: is_half_mismatched(half),
+            rssi: radio::link_stats(half as u8).rssi,
+            battery_mv,
+            last_seen_ms: elapsed.map(|elapsed| elapsed.as_millis().min(u32::MAX as u64) as u32),
+        }
+    })
+}
+
+/// Returns the link stats of both halves for the link diagnostic
+pub fn link_report() -> LinkReport {
+    LinkReport {
+        wireless: true,
+        halves: [
+            radio::link_stats(Half::Left as u8),
+            radio::link_stats(Half::Right as u8),
+        ],
+    }
+}
+
+/// Returns the response for HidRequest::LinkStats
+pub fn stats_buffer() -> [u8; LINK_STATS_LEN] {
+    let mut buf = [0u8; LINK_STATS_LEN];
+    buf[0] = 1;
+    for (half, chunk) in [Half::Left, Half::Right]
+        .into_iter()
+        .zip(buf[1..].chunks_exact_mut(LINK_STATS_SERIAL_LENGTH))
+    {
+        radio::link_stats(half as u8).into_buffer(chunk);
+    }
+    buf
+}
+
+/// Relays a com request to the half and waits for its response. Returns
+/// None if the half didn't respond in time
+pub async fn forward(half: Half, request: &[u8]) -> Option<Packet> {
+    let addr = half as u8;
+    radio::clear_config();
+    radio::queue_config(addr, request);
+    let response = with_timeout(FORWARD_TIMEOUT, async {
+        loop {
+            let packet = radio::receive_config().await;
+            if packet.addr == addr {
+                return packet;
+            }
+        }
+    })
+    .await
+    .ok();
+    if let (None, Some(&kind)) = (&response, request.first()) {
+        radio::cancel_config(addr, kind);
+    }
+    response
+}
+
+/// Checks the radio link to both halves whenever a self test starts. Each half
+/// is forwarded a battery thresholds request and passes once it responds, so
+/// the check covers sending, acks and config on both ends. A key on each half
+/// has to be pressed for the request to go out before the next heartbeat
+pub async fn run_self_test_radio() -> ! {
+    loop {
+        wait_self_test_start().await;
+        record_radio_check(RadioCheck::Running);
+        let mut passed = true;
+        for half in [Half::Left, Half::Right] {
+            if forward(half, &[HidRequest::BatteryThresholds as u8])
+                .await
+                .is_none()
+            {
+                error!("Half {} didn't respond to the self test", half as u8);
+                passed = false;
+            }
+        }
+        record_radio_check(if passed {
+            RadioCheck::Passed
+        } else {
+            RadioCheck::Failed
+        });
+    }
+}
+
+/// Updates the lock key leds set by the host. Doesn't wait so it can be
+/// called from the usb request handler
+pub fn set_host_leds(leds: HostLeds) {
+    HOST_LEDS.signal(leds);
+}
+
+/// Waits for the host to change the lock key leds
+pub async fn wait_host_leds() -> HostLeds {
+    HOST_LEDS.wait().await
+}
+
+/// Relays the lock key leds set by the host to both halves. The leds are
+/// delivered with the ack of the next packet each half sends
+pub async fn run_host_leds_relay() -> ! {
+    loop {
+        let leds = wait_host_leds().await;
+        info!("Relaying host leds {}", leds);
+        for half in [Half::Left, Half::Right] {
+            radio::queue_config(half as u8, &[HidRequest::HostLeds as u8, leds.0]);
+        }
+    }
+}
+
+/// Relays the active layer and config to both halves whenever either changes
+/// on the dongle. Only the latest is queued, so switching layers quickly
+/// doesn't hold up other config
+pub async fn run_layer_relay() -> ! {
+    let mut relayed = None;
+    loop {
+        let status = display::wait_status_change().await;
+        let indication = (status.layer, status.config);
+        if relayed == Some(indication) {
+            continue;
+        }
+        relayed = Some(indication);
+        for half in [Half::Left, Half::Right] {
+            radio::queue_config(half as u8, &[LAYER_REQUEST, status.layer, status.config]);
+        }
+    }
+}
+
+/// Relays host switches requested by keys to both halves. Each half gets the
+/// new host's addresses with the ack of the next packet it sends and hands
+/// itself over
+pub async fn run_host_switch_relay() -> ! {
+    loop {
+        let host = wait_host_switch().await;
+        let pairings = load_host_pairings().await;
+        let Some(address) = pairings.host(host as usize) else {
+            error!("Received switch to invalid host {}", host);
+            continue;
+        };
+        if Addresses::for_host(address).base == Addresses::for_host(pairings.own_address()).base {
+            info!("Already on host {}", host);
+            continue;
+        }
+        info!("Switching to host {}", host);
+        let mut request = [0u8; 10];
+        request[0] = HidRequest::SwitchHost as u8;
+        request[1] = host;
+        request[2..6].copy_from_slice(&address.dongle.to_le_bytes());
+        request[6..10].copy_from_slice(&address.keyboard.to_le_bytes());
+        for half in [Half::Left, Half::Right] {
+            radio::queue_config(half as u8, &request);
+        }
+    }
+}
+
+/// Handles com requests forwarded by the dongle. The battery thresholds, dfu
+/// mode, host leds and host switches can be set, and keymap requests are
+/// passed to the half's [crate::keymap::run_keymap_server] if it keeps its
+/// own keymap. The dongle resends its stored battery thresholds each time the
+/// half connects, since the half doesn't keep them. Also checks the link
+/// version and takes the link timing the dongle sends when the half connects,
+/// follows the host's power state and shows the layer
+pub async fn run_config_handler() -> ! {
+    loop {
+        let request = radio::receive_config().await;
+        let mut response = Packet::default();
+        match request.first() {
+            Some(&x) if x == HidRequest::BatteryThresholds as u8 => {
+                let mut buf = [0u8; 4];
+                let thresholds = *BATTERY_THRESHOLDS.lock().await;
+                thresholds.into_buffer(&mut buf).unwrap();
+                response.copy_from_slice(&buf);
+            }
+            Some(&x) if x == HidRequest::UpdateBatteryThresholds as u8 => {
+                match SettingId::BatteryThresholds.deserialize(&request[1..]) {
+                    Ok(StorageItem::BatteryThresholds(thresholds)) => {
+                        info!("Updating battery thresholds to {}", thresholds);
+                        *BATTERY_THRESHOLDS.lock().await = thresholds;
+                    }
+                    _ => error!("Received invalid battery thresholds"),
+                }
+            }
+            Some(&x) if x == HidRequest::DfuStart as u8 => crate::dfu::request_dfu(),
+            Some(&x)
+                if x == HidRequest::HalfLayer as u8 || x == HidRequest::UpdateHalfLayer as u8 =>
+            {
+                // Answered by the keymap server
+                if crate::keymap::submit(request) {
+                    continue;
+                }
+                response.copy_from_slice(&[ForwardStatus::Unsupported as u8]);
+            }
+            Some(&LINK_VERSION_REQUEST) => {
+                // Sent by the dongle without waiting for a response
+                match LinkVersion::from_bytes(&request[1..]) {
+                    Some(version) => {
+                        let mismatch = !LinkVersion::current().is_compatible(&version);
+                        if mismatch {
+                            error!("Dongle runs link protocol {}", version.protocol);
+                        }
+                        VERSION_MISMATCH.store(mismatch, Ordering::Relaxed);
+                    }
+                    None => error!("Received invalid link version"),
+                }
+                continue;
+            }
+            Some(&LINK_TIMING_REQUEST) => {
+                // Sent by the dongle without waiting for a response
+                match SettingId::LinkTiming.deserialize(&request[1..]) {
+                    Ok(StorageItem::LinkTiming(timing)) => {
+                        info!("Dongle set link timing to {}", timing);
+                        set_link_timing(timing);
+                    }
+                    _ => error!("Received invalid link timing"),
+                }
+                continue;
+            }
+            Some(&LAYER_REQUEST) => {
+                // Sent by the dongle without waiting for a response
+                match request.get(1..3) {
+                    Some(&[layer, config]) => {
+                        display::set_layer(layer as usize);
+                        display::set_config(config as usize);
+                    }
+                    _ => error!("Received invalid layer"),
+                }
+                continue;
+            }
+            Some(&POWER_STATE_REQUEST) => {
+                // Sent by the dongle without waiting for a response
+                match request.get(1) {
+                    Some(&asleep) => set_host_asleep(asleep != 0),
+                    None => error!("Received invalid power state"),
+                }
+                continue;
+            }
+            Some(&x) if x == HidRequest::HostLeds as u8 => {
+                // Relayed by the dongle without waiting for a response
+                if let Some(&leds) = request.get(1) {
+                    set_host_leds(HostLeds(leds));
+                }
+                continue;
+            }
+            Some(&x) if x == HidRequest::SwitchHost as u8 => {
+                // Relayed by the dongle, which won't hear from us again
+                match request.get(2..10) {
+                    Some(bytes) => {
+                        let address = HostAddress {
+                            dongle: u32::from_le_bytes(bytes[..4].try_into().unwrap()),
+                            keyboard: u32::from_le_bytes(bytes[4..].try_into().unwrap()),
+                        };
+                        info!("Switching to host {}", request[1]);
+                        radio::hand_over(
+                            Addresses::for_host(address),
+                            &key_state_packet(0, is_wired()),
+                        );
+                    }
+                    None => error!("Received invalid host switch"),
+                }
+                continue;
+            }
+            _ => error!("Received unsupported config request"),
+        }
+        radio::send_config(&response).await;
+    }
+}
+
+/// Periodically logs that the dongle is searching until a half is heard from
+pub async fn run_search_indicator() {
+    while is_searching() {
+        info!("Searching for halves");
+        Timer::after(SEARCH_INTERVAL).await;
+    }
+    info!("Found a half");
+}
@@ -1,11 +1,12 @@
 use core::{
+    cell::Cell,
     future::Future,
     sync::atomic::{compiler_fence, AtomicBool},
     task::Poll,
 };
 
-use defmt::info;
-use embassy_futures::select::select;
+use defmt::{error, info};
+use embassy_futures::select::{select, Either};
 use embassy_nrf::{
     interrupt::{
         self,
@@ -16,31 +17,107 @@ use embassy_nrf::{
     Peri,
 };
 use embassy_sync::{
-    blocking_mutex::raw::{CriticalSectionRawMutex, ThreadModeRawMutex},
+    blocking_mutex::{
+        self,
+        raw::{CriticalSectionRawMutex, ThreadModeRawMutex},
+    },
     channel::Channel,
     mutex::{Mutex, MutexGuard},
     signal::Signal,
     waitqueue::AtomicWaker,
 };
-use embassy_time::Timer;
+use embassy_time::{with_timeout, Duration, Instant, Timer};
+use key_lib::{
+    diagnostics::LinkStats,
+    display::{self, LinkStatus},
+    host_switch::HostAddress,
+    watchdog::{check_in, idle, Watched},
+};
 use num_enum::{TryFromPrimitive, TryFromPrimitiveError};
 
 use crate::{DONGLE_ADDRESS, DONGLE_PREFIX, KEYBOARD_ADDRESS, LEFT_PREFIX, RIGHT_PREFIX};
 
 const BUFFER_SIZE: usize = 32;
 const META_SIZE: usize = 3;
+// Esb frames start with their length and the byte holding the pid and ack flag
+const ESB_META_SIZE: usize = 2;
 
 static STATE: AtomicWaker = AtomicWaker::new();
 
 const NUM_PACKETS: usize = 20;
 
+// Acks missed in a row before the link is shown as lost on the display
+const LOST_LINK_ACKS: u32 = 10;
+
+/// Transmit power of the dongle. Halves with automatic power control work out
+/// how strong their own packets arrive from the strength of its acks
+pub const DONGLE_TX_POWER: TxPower = TxPower::POS8_DBM;
+const DONGLE_TX_DBM: i16 = 8;
+
+// Transmit powers automatic power control steps through, strongest first
+const TX_POWERS: [(TxPower, i16); 9] = [
+    (TxPower::POS8_DBM, 8),
+    (TxPower::POS4_DBM, 4),
+    (TxPower::_0_DBM, 0),
+    (TxPower::NEG4_DBM, -4),
+    (TxPower::NEG8_DBM, -8),
+    (TxPower::NEG12_DBM, -12),
+    (TxPower::NEG16_DBM, -16),
+    (TxPower::NEG20_DBM, -20),
+    (TxPower::NEG40_DBM, -40),
+];
+
+// Estimated strength the dongle receives packets at. The power is raised
+// below WEAK_RX_DBM and lowered once the next step down would still arrive
+// above STRONG_RX_DBM for STEP_DOWN_ACKS acks in a row
+const WEAK_RX_DBM: i16 = -80;
+const STRONG_RX_DBM: i16 = -70;
+const STEP_DOWN_ACKS: u8 = 32;
+
+// Strength above which the channel is taken as busy before a retry, and the
+// time to back off while it is
+const BUSY_CHANNEL_DBM: i8 = -75;
+const BUSY_BACKOFF: Duration = Duration::from_micros(250);
+
 static DATA: Mutex<CriticalSectionRawMutex, Packet> = Mutex::new(Packet::default());
 
 static REQUESTS: Channel<CriticalSectionRawMutex, Direction, NUM_PACKETS> = Channel::new();
 
+// Senders count under their tx address and receivers under the address
+// packets matched
+static LINK_STATS: blocking_mutex::Mutex<CriticalSectionRawMutex, Cell<[LinkStats; 8]>> =
+    blocking_mutex::Mutex::new(Cell::new([LinkStats::default(); 8]));
+
+/// Config payloads waiting to be attached to the next ack sent to each address
+static PENDING_CONFIG: blocking_mutex::Mutex<CriticalSectionRawMutex, Cell<[PendingConfig; 8]>> =
+    blocking_mutex::Mutex::new(Cell::new([PendingConfig::default(); 8]));
+
+static CONFIG_CHANNEL: Channel<CriticalSectionRawMutex, Packet, 4> = Channel::new();
+
 static RECV_CHANNEL: Channel<CriticalSectionRawMutex, Packet, NUM_PACKETS> = Channel::new();
 static SEND_CHANNEL: Channel<CriticalSectionRawMutex, Packet, NUM_PACKETS> = Channel::new();
 
+static HANDOVER: Signal<CriticalSectionRawMutex, Handover> = Signal::new();
+
+static MESSAGE_CHANNEL: Channel<CriticalSectionRawMutex, Message, 2> = Channel::new();
+
+// Held while a message is sent so fragments of two messages don't interleave
+static MESSAGE_SENDER: Mutex<CriticalSectionRawMutex, ()> = Mutex::new(());
+
+/// Longest message [send_message] sends in fragments
+pub const MAX_MESSAGE_LEN: usize = 512;
+
+// Data in a fragment after its header, which holds the fragment's sequence
+// number and FINAL_FRAGMENT on the last one
+const FRAGMENT_LEN: usize = BUFFER_SIZE - 1;
+const FINAL_FRAGMENT: u8 = 1 << 7;
+
+const _: () = assert!(MAX_MESSAGE_LEN.div_ceil(FRAGMENT_LEN) <= FINAL_FRAGMENT as usize);
+
+// Time to get the release packet through to the old host before switching
+// anyway, e.g. when its dongle was unplugged
+const HANDOVER_TIMEOUT: Duration = Duration::from_millis(50);
+
 pub struct InterruptHandler {}
 
 impl interrupt::typelevel::Handler<typelevel::RADIO> for InterruptHandler {
@@ -55,6 +132,9 @@ impl interrupt::typelevel::Handler<typelevel::RADIO> for InterruptHandler {
 pub struct Addresses {
     pub base: [u32; 2],
     pub prefix: [[u8; 4]; 2],
+    /// Bit per logical address that sends and receives
+    /// [PacketFormat::Esb] frames
+    pub esb: u8,
 }
 
 impl Default for Addresses {
@@ -62,6 +142,7 @@ impl Default for Addresses {
         let mut res = Self {
             base: Default::default(),
             prefix: Default::default(),
+            esb: 0,
         };
         res.base[0] = DONGLE_ADDRESS;
         res.base[1] = KEYBOARD_ADDRESS;
@@ -72,12 +153,412 @@ impl Default for Addresses {
     }
 }
 
+impl Addresses {
+    /// Returns the addresses of the host's dongle. Unpaired hosts use the
+    /// default addresses
+    pub fn for_host(host: HostAddress) -> Self {
+        let mut res = Self::default();
+        if host.is_paired() {
+            res.base[0] = host.dongle;
+            res.base[1] = host.keyboard;
+        }
+        res
+    }
+
+    /// Talks Enhanced ShockBurst on the logical address, e.g. to an nRF24
+    /// receiver
+    pub fn with_esb(mut self, addr: u8) -> Self {
+        self.esb |= 1 << addr;
+        self
+    }
+
+    /// Returns the base and prefix registers for an address given the way
+    /// Nordic's esb library takes it. Esb sends addresses msb first, so every
+    /// byte is bit reversed
+    pub fn esb_address(base: [u8; 4], prefix: u8) -> (u32, u8) {
+        (
+            u32::from_be_bytes(base.map(u8::reverse_bits)),
+            prefix.reverse_bits(),
+        )
+    }
+}
+
+/// Layout of packets on air. The radio can only use one at a time, so the
+/// format is switched for each transfer with an esb address
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum PacketFormat {
+    /// 8 bit length followed by the id and [PacketType] of the packet
+    Native,
+    /// Enhanced ShockBurst with dynamic payloads as sent by nRF24 radios and
+    /// Nordic's esb library: a 6 bit length, a 2 bit pid that tells
+    /// retransmissions apart and a flag asking for an ack, sent msb first
+    Esb,
+}
+
+impl PacketFormat {
+    fn apply(&self) {
+        let r = embassy_nrf::pac::RADIO;
+        let (lflen, s1len, endian) = match self {
+            PacketFormat::Native => (8, 0, embassy_nrf::pac::radio::vals::Endian::LITTLE),
+            PacketFormat::Esb => (6, 3, embassy_nrf::pac::radio::vals::Endian::BIG),
+        };
+        r.pcnf0().write(|w| {
+            w.set_lflen(lflen);
+            w.set_s0len(false);
+            w.set_s1len(s1len);
+            w.set_s1incl(embassy_nrf::pac::radio::vals::S1incl::AUTOMATIC);
+            w.set_plen(embassy_nrf::pac::radio::vals::Plen::_8BIT);
+        });
+        r.pcnf1().write(|w| {
+            w.set_maxlen(BUFFER_SIZE as u8);
+            w.set_statlen(0);
+            w.set_balen(4);
+            w.set_endian(endian);
+        });
+    }
+}
+
+/// Data rate of the radio. Both ends of a link have to use the same rate.
+/// 2Mbit spends half the time on air, 1Mbit reaches further
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum DataRate {
+    Nrf1Mbit,
+    Nrf2Mbit,
+}
+
+impl DataRate {
+    fn mode(&self) -> embassy_nrf::pac::radio::vals::Mode {
+        match self {
+            DataRate::Nrf1Mbit => embassy_nrf::pac::radio::vals::Mode::NRF_1MBIT,
+            DataRate::Nrf2Mbit => embassy_nrf::pac::radio::vals::Mode::NRF_2MBIT,
+        }
+    }
+}
+
+/// Lowers the transmit power of a half while the dongle receives it well, to
+/// save battery. The dongle's acks are sent at [DONGLE_TX_POWER], so their
+/// strength tells how much is lost on the way
+struct TxPowerControl {
+    // Index into TX_POWERS
+    level: usize,
+    // Acks in a row that would still be strong enough a step down
+    strong_acks: u8,
+}
+
+impl TxPowerControl {
+    const fn new() -> Self {
+        Self {
+            level: 0,
+            strong_acks: 0,
+        }
+    }
+
+    // Estimated strength the dongle receives packets sent at the level with
+    fn estimate(&self, level: usize, ack_rssi: i8) -> i16 {
+        TX_POWERS[level].1 + ack_rssi as i16 - DONGLE_TX_DBM
+    }
+
+    /// Returns the new power if it should change after an ack
+    fn acked(&mut self, ack_rssi: i8) -> Option<TxPower> {
+        if self.level > 0 && self.estimate(self.level, ack_rssi) < WEAK_RX_DBM {
+            self.strong_acks = 0;
+            self.level -= 1;
+            return Some(TX_POWERS[self.level].0);
+        }
+        if self.level + 1 < TX_POWERS.len()
+            && self.estimate(self.level + 1, ack_rssi) >= STRONG_RX_DBM
+        {
+            self.strong_acks += 1;
+            if self.strong_acks >= STEP_DOWN_ACKS {
+                self.strong_acks = 0;
+                self.level += 1;
+                return Some(TX_POWERS[self.level].0);
+            }
+        } else {
+            self.strong_acks = 0;
+        }
+        None
+    }
+
+    /// Returns to full power after a missed ack, so the retry gets through
+    fn missed(&mut self) -> Option<TxPower> {
+        self.strong_acks = 0;
+        if self.level == 0 {
+            return None;
+        }
+        self.level = 0;
+        Some(TX_POWERS[0].0)
+    }
+}
+
+/// Switch of the link to another host's dongle
+#[derive(Clone, Copy)]
+struct Handover {
+    addresses: Addresses,
+    // Sent to the old host first so it doesn't keep keys held
+    release: Packet,
+}
+
 pub struct Radio<'d> {
     _radio: Peri<'d, embassy_nrf::peripherals::RADIO>,
     tx_addreses: u8,
     rx_addresses: u32,
-    rx_id: [u8; 8],
+    // Id of the last accepted packet from each address. None until a packet
+    // is received so a restarted receiver accepts whatever id the sender is at
+    rx_id: [Option<u8>; 8],
+    // Session of the last SyncData packet from each address
+    rx_session: [Option<u8>; 8],
     tx_id: u8,
+    session: u8,
+    // Set once the first packet is acked. The first packet resyncs the
+    // receiver's id in case the sender restarted
+    tx_synced: bool,
+    hfclk_started: bool,
+    rx: RxBuffers,
+    // Set on halves that pick their transmit power from the acks
+    tx_power_control: Option<TxPowerControl>,
+    // Logical addresses that use esb, see Addresses::esb
+    esb: u8,
+    esb_tx_pid: u8,
+    // Pid and crc of the last esb frame accepted from each address. A frame
+    // with both the same is a retransmission
+    esb_rx: [Option<(u8, u32)>; 8],
+    // Message being put back together from the fragments of each address
+    reassembly: [Reassembly; 8],
+}
+
+/// Largest config payload that can be sent in a packet. Acks use the first
+/// byte of their payload for the address being acked
+pub const MAX_CONFIG_LEN: usize = BUFFER_SIZE - 1;
+
+/// Longest payload a packet can carry
+pub const MAX_PACKET_LEN: usize = BUFFER_SIZE;
+
+// Config that can wait for an address at once. Each ack carries the oldest
+const CONFIG_QUEUE_LEN: usize = 4;
+
+/// Config waiting to be attached to the acks of an address, oldest first
+#[derive(Clone, Copy)]
+struct PendingConfig {
+    packets: [Option<Packet>; CONFIG_QUEUE_LEN],
+    // Id of the packet whose ack carried the oldest config
+    sent_with: Option<u8>,
+}
+
+impl PendingConfig {
+    const fn default() -> Self {
+        Self {
+            packets: [None; CONFIG_QUEUE_LEN],
+            sent_with: None,
+        }
+    }
+
+    fn front(&self) -> Option<Packet> {
+        self.packets[0]
+    }
+
+    fn pop(&mut self) {
+        self.packets.rotate_left(1);
+        self.packets[CONFIG_QUEUE_LEN - 1] = None;
+        self.sent_with = None;
+    }
+
+    // Config of the same kind, its first byte, replaces the queued one so
+    // only the latest state is sent. Config already sent with an ack may have
+    // arrived, so it isn't replaced
+    fn push(&mut self, packet: Packet) {
+        let first_unsent = self.sent_with.is_some() as usize;
+        let kind = packet.first();
+        let slot = self.packets[first_unsent..]
+            .iter()
+            .position(|queued| queued.is_none_or(|queued| queued.first() == kind));
+        match slot {
+            Some(i) => self.packets[first_unsent + i] = Some(packet),
+            None => {
+                error!("Config queue full, dropping the oldest");
+                self.packets[first_unsent..].rotate_left(1);
+                self.packets[CONFIG_QUEUE_LEN - 1] = Some(packet);
+            }
+        }
+    }
+
+    fn cancel(&mut self, kind: u8) {
+        let first_unsent = self.sent_with.is_some() as usize;
+        if let Some(i) = self.packets[first_unsent..]
+            .iter()
+            .position(|queued| queued.is_some_and(|queued| queued.first() == Some(&kind)))
+        {
+            self.packets[first_unsent + i..].rotate_left(1);
+            self.packets[CONFIG_QUEUE_LEN - 1] = None;
+        }
+    }
+}
+
+/// Returns the link statistics of the logical address
+pub fn link_stats(addr: u8) -> LinkStats {
+    LINK_STATS.lock(|stats| stats.get()[addr as usize % 8])
+}
+
+fn update_stats(addr: u8, f: impl FnOnce(&mut LinkStats)) {
+    LINK_STATS.lock(|stats| {
+        let mut all = stats.get();
+        f(&mut all[addr as usize % 8]);
+        stats.set(all);
+    });
+}
+
+// Reads the strength sampled when the address of the last packet matched.
+// The sample is done long before the packet ends
+fn last_rssi() -> i8 {
+    let r = embassy_nrf::pac::RADIO;
+    // The sample is the magnitude of the received signal strength in -dBm
+    -(r.rssisample().read().rssisample() as i8)
+}
+
+/// Radio events a task can wait for. Waiting enables the event's interrupt,
+/// which wakes the task instead of it spinning on the register
+#[derive(Clone, Copy)]
+enum Event {
+    Ready,
+    End,
+    Disabled,
+    RssiEnd,
+}
+
+impl Event {
+    fn is_set(&self) -> bool {
+        let r = embassy_nrf::pac::RADIO;
+        match self {
+            Event::Ready => r.events_ready().read() != 0,
+            Event::End => r.events_end().read() != 0,
+            Event::Disabled => r.events_disabled().read() != 0,
+            Event::RssiEnd => r.events_rssiend().read() != 0,
+        }
+    }
+
+    fn clear(&self) {
+        let r = embassy_nrf::pac::RADIO;
+        match self {
+            Event::Ready => r.events_ready().write_value(0),
+            Event::End => r.events_end().write_value(0),
+            Event::Disabled => r.events_disabled().write_value(0),
+            Event::RssiEnd => r.events_rssiend().write_value(0),
+        }
+    }
+
+    fn enable_interrupt(&self) {
+        let r = embassy_nrf::pac::RADIO;
+        r.intenset().write(|w| match self {
+            Event::Ready => w.set_ready(true),
+            Event::End => w.set_end(true),
+            Event::Disabled => w.set_disabled(true),
+            Event::RssiEnd => w.set_rssiend(true),
+        });
+    }
+
+    /// Waits for the event. The event is left set for the caller to read the
+    /// results and clear it
+    async fn wait(self) {
+        core::future::poll_fn(|cx| {
+            STATE.register(cx.waker());
+            if self.is_set() {
+                Poll::Ready(())
+            } else {
+                // The interrupt fires right away if the event was set since
+                // the check
+                self.enable_interrupt();
+                Poll::Pending
+            }
+        })
+        .await;
+        compiler_fence(core::sync::atomic::Ordering::Acquire);
+    }
+}
+
+/// Disables the radio and waits until it's off. Futures dropped mid transfer
+/// only start disabling the radio, so the radio is disabled with this before
+/// it's started again
+async fn disable() {
+    let r = embassy_nrf::pac::RADIO;
+    r.tasks_disable().write_value(1);
+    core::future::poll_fn(|cx| {
+        STATE.register(cx.waker());
+        // A stale event would wake us before the radio is off, so it's
+        // cleared and the state checked after
+        Event::Disabled.clear();
+        if r.state().read().state() == RadioState::DISABLED {
+            Poll::Ready(())
+        } else {
+            Event::Disabled.enable_interrupt();
+            Poll::Pending
+        }
+    })
+    .await;
+    compiler_fence(core::sync::atomic::Ordering::Acquire);
+}
+
+/// Metadata of a packet received through [`Radio::sniff`]
+#[derive(Clone, Copy, defmt::Format)]
+pub struct Sniffed {
+    pub crc_ok: bool,
+    pub packet_type: Option<PacketType>,
+    pub rssi: i8,
+}
+
+fn set_addresses(addresses: &Addresses) {
+    let r = embassy_nrf::pac::RADIO;
+    r.base0().write_value(addresses.base[0]);
+    r.base1().write_value(addresses.base[1]);
+    r.prefix0()
+        .write(|w| w.0 = u32::from_le_bytes(addresses.prefix[0]));
+    r.prefix1()
+        .write(|w| w.0 = u32::from_le_bytes(addresses.prefix[1]));
+}
+
+// Starts a new session for the packets sent until the next restart. The last
+// session is kept in GPREGRET2, which survives System OFF and soft resets, so
+// a half waking from sleep always starts a session the dongle hasn't seen and
+// its first packet gets through without being taken for a retransmission
+fn next_session() -> u8 {
+    let power = embassy_nrf::pac::POWER;
+    let session = power.gpregret2().read().gpregret().wrapping_add(1);
+    power.gpregret2().write(|w| w.set_gpregret(session));
+    session
+}
+
+// Returns the config queued for the address to attach to the ack of packet id.
+// The sender only moves on to a new packet once it receives an ack, so a
+// fresh packet means the config was delivered and the next one is attached
+fn take_pending_config(addr: u8, id: u8, fresh: bool) -> Option<Packet> {
+    PENDING_CONFIG.lock(|pending| {
+        let mut all = pending.get();
+        let entry = &mut all[addr as usize % 8];
+        if fresh && entry.sent_with.is_some() {
+            entry.pop();
+        }
+        let config = match entry.sent_with {
+            Some(sent_id) if sent_id != id => None,
+            _ => entry.front(),
+        };
+        if config.is_some() {
+            entry.sent_with = Some(id);
+        }
+        pending.set(all);
+        config
+    })
+}
+
+fn write_tx_power(power: TxPower) {
+    let r = embassy_nrf::pac::RADIO;
+    r.txpower().write(|w| w.set_txpower(power));
+}
+
+fn start_hfclk() {
+    let c = embassy_nrf::pac::CLOCK;
+    c.events_hfclkstarted().write_value(0);
+    c.tasks_hfclkstart().write_value(1);
+    while c.events_hfclkstarted().read() == 0 {}
+    c.events_hfclkstarted().write_value(0);
 }
 
 impl<'d> Radio<'d> {
@@ -96,30 +577,11 @@ impl<'d> Radio<'d> {
         r.power().write(|w| w.set_power(false));
         r.power().write(|w| w.set_power(true));
 
-        r.mode()
-            .write(|w| w.set_mode(embassy_nrf::pac::radio::vals::Mode::NRF_1MBIT));
+        r.mode().write(|w| w.set_mode(DataRate::Nrf1Mbit.mode()));
 
-        r.pcnf0().write(|w| {
-            w.set_lflen(8);
-            w.set_s0len(false);
-            w.set_s1len(0);
-            w.set_s1incl(embassy_nrf::pac::radio::vals::S1incl::AUTOMATIC);
-            w.set_plen(embassy_nrf::pac::radio::vals::Plen::_8BIT);
-        });
-
-        r.pcnf1().write(|w| {
-            w.set_maxlen(BUFFER_SIZE as u8);
-            w.set_statlen(0);
-            w.set_balen(4);
-            w.set_endian(embassy_nrf::pac::radio::vals::Endian::LITTLE);
-        });
+        PacketFormat::Native.apply();
 
-        r.base0().write_value(addresses.base[0]);
-        r.base1().write_value(addresses.base[1]);
-        r.prefix0()
-            .write(|w| w.0 = u32::from_le_bytes(addresses.prefix[0]));
-        r.prefix1()
-            .write(|w| w.0 = u32::from_le_bytes(addresses.prefix[1]));
+        set_addresses(&addresses);
 
         r.crccnf().write(|w| {
             w.set_len(embassy_nrf::pac::radio::vals::Len::TWO);
@@ -148,17 +610,31 @@ impl<'d> Radio<'d> {
             _radio,
             rx_addresses: 0,
             tx_addreses: 0,
-            rx_id: [0u8; 8],
+            rx_id: [None; 8],
+            rx_session: [None; 8],
             tx_id: 0u8,
+            session: next_session(),
+            tx_synced: false,
+            hfclk_started: false,
+            rx: RxBuffers::new(),
+            tx_power_control: None,
+            esb: addresses.esb,
+            esb_tx_pid: 0,
+            esb_rx: [None; 8],
+            reassembly: [const { Reassembly::new() }; 8],
         }
     }
 
-    async fn transmit_ack(&mut self, id: u8, addr: u8) {
+    async fn transmit_ack(&mut self, id: u8, addr: u8, fresh: bool) {
         Timer::after_micros(40).await;
         let mut packet = Packet::default();
         packet.set_type(PacketType::Ack);
         packet.set_len(1);
         packet.set_id(id);
+        if let Some(config) = take_pending_config(addr, id, fresh) {
+            packet.set_len(1 + config.len());
+            packet[1..].copy_from_slice(&config);
+        }
         packet[0] = addr;
         info!("Ack sent for {}", id);
         self.send_inner(&mut packet).await;
@@ -175,6 +651,11 @@ impl<'d> Radio<'d> {
                     && packet.id() == id
                     && packet[0] == addr
                 {
+                    if packet.len() > 1 {
+                        let mut config = Packet::default();
+                        config.copy_from_slice(&packet[1..]);
+                        let _ = CONFIG_CHANNEL.try_send(config);
+                    }
                     break;
                 };
             }
@@ -186,32 +667,275 @@ impl<'d> Radio<'d> {
     }
 
     async fn send(&mut self, packet: &mut Packet) {
+        if self.esb & (1 << self.tx_addreses) != 0 {
+            return self.send_esb(packet).await;
+        }
         self.tx_id = self.tx_id.wrapping_add(1);
         packet.set_id(self.tx_id);
-        packet.set_type(PacketType::Data);
+        // Config, dfu, analog, source and fragment packets keep their type
+        if !matches!(
+            packet.packet_type(),
+            Ok(PacketType::Config)
+                | Ok(PacketType::Dfu)
+                | Ok(PacketType::AnalogData)
+                | Ok(PacketType::Source)
+                | Ok(PacketType::Fragment)
+        ) {
+            if self.tx_synced {
+                packet.set_type(PacketType::Data);
+            } else {
+                packet.set_type(PacketType::SyncData);
+                let len = packet.len();
+                packet.set_len(len + 1);
+                packet[len] = self.session;
+            }
+        }
+        let addr = self.tx_addreses;
+        let mut missed = 0;
         loop {
             self.send_inner(packet).await;
+            update_stats(addr, |stats| {
+                stats.packets_sent = stats.packets_sent.wrapping_add(1)
+            });
             if self.await_ack(packet.id()).await.is_ok() {
+                let rssi = last_rssi();
+                update_stats(addr, |stats| stats.rssi = rssi);
+                if let Some(power) = self.tx_power_control.as_mut().and_then(|c| c.acked(rssi)) {
+                    info!("Tx power stepped to {}", power);
+                    write_tx_power(power);
+                }
+                self.tx_synced = true;
+                display::set_link(LinkStatus::Connected);
                 return;
             }
+            update_stats(addr, |stats| {
+                stats.acks_missed = stats.acks_missed.wrapping_add(1)
+            });
+            if let Some(power) = self.tx_power_control.as_mut().and_then(|c| c.missed()) {
+                write_tx_power(power);
+            }
+            missed += 1;
+            if missed == LOST_LINK_ACKS {
+                display::set_link(LinkStatus::Lost);
+            }
+            // The ack may have collided with someone else on the channel, so
+            // the retry waits for them to finish
+            if !self.channel_clear(BUSY_CHANNEL_DBM).await {
+                Timer::after(BUSY_BACKOFF).await;
+            }
+        }
+    }
+
+    /// Clear channel assessment. Samples the strength of whatever is on the
+    /// channel and returns true if it's below the threshold in dBm. The radio
+    /// only does cca in 802.15.4 mode, so the strength is sampled by hand
+    pub async fn channel_clear(&mut self, threshold: i8) -> bool {
+        let r = embassy_nrf::pac::RADIO;
+        self.rx.stop().await;
+        disable().await;
+        r.shorts().write(|_| {});
+        Event::Ready.clear();
+        r.tasks_rxen().write_value(1);
+        Event::Ready.wait().await;
+        Event::Ready.clear();
+        Event::RssiEnd.clear();
+        r.tasks_rssistart().write_value(1);
+        Event::RssiEnd.wait().await;
+        Event::RssiEnd.clear();
+        let rssi = last_rssi();
+        disable().await;
+        rssi < threshold
+    }
+
+    /// Sends the payload of the packet as an esb frame and waits for the ack.
+    /// An ack payload is received like config
+    async fn send_esb(&mut self, packet: &Packet) {
+        let r = embassy_nrf::pac::RADIO;
+        self.esb_tx_pid = (self.esb_tx_pid + 1) & 0x3;
+        let pid = self.esb_tx_pid;
+        let mut frame = packet.esb_frame(pid, true);
+        let addr = self.tx_addreses;
+        self.rx.stop().await;
+        PacketFormat::Esb.apply();
+        // Acks come back on the address the frame was sent to
+        r.rxaddresses().write(|w| w.0 = 1 << addr);
+        let mut missed = 0;
+        loop {
+            self.send_inner(&mut frame).await;
+            update_stats(addr, |stats| {
+                stats.packets_sent = stats.packets_sent.wrapping_add(1)
+            });
+            if self.await_esb_ack(pid).await.is_ok() {
+                let rssi = last_rssi();
+                update_stats(addr, |stats| stats.rssi = rssi);
+                display::set_link(LinkStatus::Connected);
+                break;
+            }
+            update_stats(addr, |stats| {
+                stats.acks_missed = stats.acks_missed.wrapping_add(1)
+            });
+            missed += 1;
+            if missed == LOST_LINK_ACKS {
+                display::set_link(LinkStatus::Lost);
+            }
+        }
+        disable().await;
+        r.rxaddresses().write(|w| w.0 = self.rx_addresses);
+        PacketFormat::Native.apply();
+    }
+
+    async fn await_esb_ack(&mut self, pid: u8) -> Result<(), ()> {
+        let mut frame = Packet::default();
+        let receive_task = async {
+            loop {
+                if ReceiveFuture::new(&mut frame).await.is_ok() && frame.esb_pid() == pid {
+                    frame.unpack_esb();
+                    if !frame.is_empty() {
+                        let _ = CONFIG_CHANNEL.try_send(frame);
+                    }
+                    break;
+                }
+            }
+        };
+        match select(Timer::after_micros(500), receive_task).await {
+            Either::First(_) => Err(()),
+            Either::Second(_) => Ok(()),
         }
     }
 
-    async fn receive(&mut self, packet: &mut Packet) {
+    /// Receives the next esb frame and acks it if the sender asked for an
+    /// ack, with the config queued for the address as the ack payload.
+    /// Returns an error without receiving anything if a packet is queued to
+    /// be sent while waiting
+    async fn receive_esb(&mut self, packet: &mut Packet) -> Result<(), ()> {
+        self.rx.stop().await;
+        PacketFormat::Esb.apply();
+        let res = loop {
+            disable().await;
+            let received = match select(
+                ReceiveFuture::new(&mut *packet),
+                SEND_CHANNEL.ready_to_receive(),
+            )
+            .await
+            {
+                Either::First(res) => res,
+                Either::Second(_) => break Err(()),
+            };
+            let addr = packet.addr;
+            if received.is_err() {
+                update_stats(addr, |stats| {
+                    stats.crc_errors = stats.crc_errors.wrapping_add(1)
+                });
+                continue;
+            }
+            let crc = embassy_nrf::pac::RADIO.rxcrc().read().rxcrc();
+            let rssi = last_rssi();
+            update_stats(addr, |stats| {
+                stats.packets_received = stats.packets_received.wrapping_add(1);
+                stats.rssi = rssi;
+            });
+            let (pid, ack) = packet.unpack_esb();
+            let fresh = self.esb_rx[addr as usize] != Some((pid, crc));
+            if ack {
+                self.transmit_esb_ack(pid, addr, fresh).await;
+            }
+            if fresh {
+                self.esb_rx[addr as usize] = Some((pid, crc));
+                packet.addr = addr;
+                break Ok(());
+            }
+            info!("Discarded retransmitted esb frame {} from {}", pid, addr);
+        };
+        disable().await;
+        PacketFormat::Native.apply();
+        res
+    }
+
+    async fn transmit_esb_ack(&mut self, pid: u8, addr: u8, fresh: bool) {
         let r = embassy_nrf::pac::RADIO;
+        Timer::after_micros(40).await;
+        let payload = take_pending_config(addr, pid, fresh).unwrap_or(Packet::default());
+        let mut frame = payload.esb_frame(pid, false);
+        // Acks go out on the address the frame came in on
+        let tx_address = r.txaddress().read().txaddress();
+        r.txaddress().write(|w| w.set_txaddress(addr));
+        self.send_inner(&mut frame).await;
+        r.txaddress().write(|w| w.set_txaddress(tx_address));
+    }
+
+    /// Receives the next packet. Returns an error without receiving anything
+    /// if a packet is queued to be sent while waiting
+    async fn receive(&mut self, packet: &mut Packet) -> Result<(), ()> {
+        // The format can't change with the address that matched, so esb is
+        // only received while every rx address uses it
+        if self.rx_addresses != 0 && self.rx_addresses & !(self.esb as u32) == 0 {
+            return self.receive_esb(packet).await;
+        }
         loop {
-            let res = ReceiveFuture::new(packet).await;
-            if res.is_ok() && packet.packet_type().is_ok_and(|x| x == PacketType::Data) {
-                let addr = r.rxmatch().read().rxmatch();
-                self.transmit_ack(packet.id(), addr).await;
+            let res = match select(self.rx.next(packet), SEND_CHANNEL.ready_to_receive()).await {
+                Either::First(res) => res,
+                Either::Second(_) => {
+                    self.rx.stop().await;
+                    return Err(());
+                }
+            };
+            let packet_type = packet.packet_type();
+            let rssi = last_rssi();
+            if res.is_err() {
+                update_stats(packet.addr, |stats| {
+                    stats.crc_errors = stats.crc_errors.wrapping_add(1)
+                });
+            }
+            if res.is_ok() && packet_type.is_ok_and(|x| x != PacketType::Ack) {
+                // Read when the packet ended, the radio may be receiving the next one
+                let addr = packet.addr;
+                update_stats(addr, |stats| {
+                    stats.packets_received = stats.packets_received.wrapping_add(1);
+                    stats.rssi = rssi;
+                });
 
                 // If packet_id is the same as the previous id, it must mean that the ack hasn't
                 // gone through so we'll discard the packet on the receiving end but send another
-                // ack to make sure the tx side knows the packet was already received
-                if packet.id() != self.rx_id[addr as usize] {
-                    self.rx_id[addr as usize] = packet.id();
+                // ack to make sure the tx side knows the packet was already received. Packets
+                // with an id behind the previous id are stale retransmissions that arrived after
+                // newer state, so they're discarded as well
+                // A SyncData packet from a new session comes from a restarted
+                // sender, so it's fresh whatever id it has
+                let session = match packet_type {
+                    Ok(PacketType::SyncData) if !packet.is_empty() => {
+                        let len = packet.len() - 1;
+                        packet.set_len(len);
+                        Some(packet.buffer[META_SIZE + len])
+                    }
+                    _ => None,
+                };
+                let new_session = session.is_some() && session != self.rx_session[addr as usize];
+                let fresh = match self.rx_id[addr as usize] {
+                    Some(id) => match session {
+                        Some(_) => new_session || packet.id() != id,
+                        None => (packet.id().wrapping_sub(id) as i8) > 0,
+                    },
+                    None => true,
+                };
+                if new_session {
+                    // Config sent with an ack to the old session may not have
+                    // made it before the restart
+                    PENDING_CONFIG.lock(|pending| {
+                        let mut all = pending.get();
+                        all[addr as usize % 8].sent_with = None;
+                        pending.set(all);
+                    });
+                }
+                self.transmit_ack(packet.id(), addr, fresh).await;
+                if fresh {
+                    self.rx_id[addr as usize] = Some(packet.id());
+                    if session.is_some() {
+                        self.rx_session[addr as usize] = session;
+                    }
                     packet.addr = addr;
-                    return;
+                    return Ok(());
+                } else {
+                    info!("Discarded stale packet {} from {}", packet.id(), addr);
                 }
             }
         }
@@ -219,6 +943,8 @@ impl<'d> Radio<'d> {
 
     async fn send_inner(&mut self, packet: &mut Packet) {
         let r = embassy_nrf::pac::RADIO;
+        self.rx.stop().await;
+        disable().await;
 
         r.packetptr().write_value(packet.buffer.as_ptr() as u32);
         r.shorts().write(|w| {
@@ -229,19 +955,8 @@ impl<'d> Radio<'d> {
         compiler_fence(core::sync::atomic::Ordering::Release);
         r.tasks_txen().write_value(1);
         r.intenclr().write(|w| w.0 = 0xFFFF_FFFF);
-        core::future::poll_fn(|cx| {
-            STATE.register(cx.waker());
-            if r.events_disabled().read() != 0 {
-                r.events_disabled().write_value(0);
-                Poll::Ready(())
-            } else {
-                r.intenset().write(|w| w.set_disabled(true));
-                Poll::Pending
-            }
-        })
-        .await;
-
-        compiler_fence(core::sync::atomic::Ordering::Acquire);
+        Event::Disabled.wait().await;
+        Event::Disabled.clear();
     }
 
     pub fn set_tx_addresses(&mut self, f: impl FnOnce(&mut Txaddress)) {
@@ -256,45 +971,332 @@ impl<'d> Radio<'d> {
         self.rx_addresses = r.rxaddresses().read().0;
     }
 
+    /// Transmits at a fixed power from +8 to -40 dBm. Turns off automatic
+    /// power control
     pub fn set_tx_power(&mut self, val: TxPower) {
+        self.tx_power_control = None;
+        write_tx_power(val);
+    }
+
+    /// Starts at full power and steps the power down while the acks show the
+    /// other end receives packets well. Meant for halves talking to a dongle
+    /// transmitting at [DONGLE_TX_POWER]
+    pub fn enable_auto_tx_power(&mut self) {
+        self.tx_power_control = Some(TxPowerControl::new());
+        write_tx_power(TX_POWERS[0].0);
+    }
+
+    /// Sets the data rate, 1Mbit by default. Every board on the link has to
+    /// be set to the same rate
+    pub fn set_data_rate(&mut self, rate: DataRate) {
         let r = embassy_nrf::pac::RADIO;
-        r.txpower().write(|w| {
-            w.set_txpower(val);
-        });
+        r.mode().write(|w| w.set_mode(rate.mode()));
+    }
+
+    /// Receives the next packet on any enabled rx address without acking it or
+    /// filtering duplicates, so other devices on the link aren't disturbed.
+    /// Packets that fail the crc are returned as well with `crc_ok` unset
+    pub async fn sniff(&mut self, packet: &mut Packet) -> Sniffed {
+        if !self.hfclk_started {
+            start_hfclk();
+            self.hfclk_started = true;
+        }
+        self.rx.stop().await;
+        disable().await;
+        let crc_ok = ReceiveFuture::new(packet).await.is_ok();
+        Sniffed {
+            crc_ok,
+            packet_type: packet.packet_type().ok(),
+            rssi: last_rssi(),
+        }
+    }
+
+    /// Releases the keys held on the current host and moves the link over to
+    /// the dongle at the new addresses
+    async fn hand_over(&mut self, mut handover: Handover) {
+        let c = embassy_nrf::pac::CLOCK;
+        if !self.hfclk_started {
+            start_hfclk();
+        }
+        if with_timeout(HANDOVER_TIMEOUT, self.send(&mut handover.release))
+            .await
+            .is_err()
+        {
+            info!("Old host didn't ack the release");
+            // The send may have been dropped mid transfer
+            disable().await;
+        }
+        if !self.hfclk_started {
+            c.tasks_hfclkstop().write_value(1);
+        }
+        // Packets received from the old host before the switch are dropped
+        self.rx.stop().await;
+        self.rx.ready = None;
+        set_addresses(&handover.addresses);
+        self.esb = handover.addresses.esb;
+        self.esb_rx = [None; 8];
+        self.reassembly = [const { Reassembly::new() }; 8];
+        // The new dongle hasn't seen our ids, so the next packet resyncs it
+        // and its packets are accepted whatever id they start at
+        self.tx_synced = false;
+        self.rx_id = [None; 8];
+        self.rx_session = [None; 8];
+        PENDING_CONFIG.lock(|pending| pending.set([PendingConfig::default(); 8]));
+        CONFIG_CHANNEL.clear();
+        info!("Handed over to a new host");
     }
 
     pub async fn run(mut self) {
         let c = embassy_nrf::pac::CLOCK;
-        let mut wrote = false;
         loop {
-            let dir = REQUESTS.receive().await;
+            check_in(Watched::Radio);
+            let request = select(REQUESTS.receive(), HANDOVER.wait());
+            let dir = match idle(Watched::Radio, request).await {
+                Either::First(dir) => dir,
+                Either::Second(handover) => {
+                    self.hand_over(handover).await;
+                    continue;
+                }
+            };
             match dir {
                 Direction::Tx => {
-                    let mut packet = SEND_CHANNEL.receive().await;
-                    c.events_hfclkstarted().write_value(0);
-                    c.tasks_hfclkstart().write_value(1);
-                    while c.events_hfclkstarted().read() == 0 {}
-                    c.events_hfclkstarted().write_value(0);
-                    self.send(&mut packet).await;
-                    c.tasks_hfclkstop().write_value(1);
+                    // The packet may have already been sent while receiving
+                    if let Ok(mut packet) = SEND_CHANNEL.try_receive() {
+                        if self.hfclk_started {
+                            self.send(&mut packet).await;
+                        } else {
+                            start_hfclk();
+                            self.send(&mut packet).await;
+                            c.tasks_hfclkstop().write_value(1);
+                        }
+                    }
                 }
-                Direction::Rx => {
-                    if !wrote {
-                        c.events_hfclkstarted().write_value(0);
-                        c.tasks_hfclkstart().write_value(1);
-                        while c.events_hfclkstarted().read() == 0 {}
-                        c.events_hfclkstarted().write_value(0);
-                        wrote = true;
+                Direction::Rx | Direction::Message(_) => {
+                    if !self.hfclk_started {
+                        start_hfclk();
+                        self.hfclk_started = true;
+                    }
+                    // Config packets don't answer the rx request so keep receiving
+                    // until a data packet arrives
+                    loop {
+                        let mut packet = Packet::default();
+                        // Nothing arrives for as long as the other side is idle
+                        let receive = idle(Watched::Radio, self.receive(&mut packet));
+                        let res = match dir {
+                            Direction::Message(deadline) => {
+                                match select(receive, Timer::at(deadline)).await {
+                                    Either::First(res) => res,
+                                    Either::Second(_) => {
+                                        self.rx.stop().await;
+                                        break;
+                                    }
+                                }
+                            }
+                            _ => receive.await,
+                        };
+                        if res.is_err() {
+                            // Send queued packets so receivers can also transmit
+                            if let Ok(mut packet) = SEND_CHANNEL.try_receive() {
+                                self.send(&mut packet).await;
+                            }
+                            continue;
+                        }
+                        if matches!(packet.packet_type(), Ok(PacketType::Config)) {
+                            let _ = CONFIG_CHANNEL.try_send(packet);
+                        } else if matches!(packet.packet_type(), Ok(PacketType::Fragment)) {
+                            let addr = packet.addr;
+                            let reassembly = &mut self.reassembly[addr as usize % 8];
+                            if let Some(message) = reassembly.push(addr, &packet) {
+                                if MESSAGE_CHANNEL.try_send(message).is_err() {
+                                    error!("Dropped a message from {}", addr);
+                                }
+                                if matches!(dir, Direction::Message(_)) {
+                                    break;
+                                }
+                            }
+                        } else if matches!(dir, Direction::Message(_)) {
+                            // Nothing waits for data packets while listening
+                            // for a message
+                            continue;
+                        } else {
+                            RECV_CHANNEL.send(packet).await;
+                            break;
+                        }
                     }
-                    let mut packet = Packet::default();
-                    self.receive(&mut packet).await;
-                    RECV_CHANNEL.send(packet).await;
                 }
             }
         }
     }
 }
 
+/// Message put back together from the fragments sent with [send_message]
+#[derive(Clone, Copy)]
+pub struct Message {
+    pub addr: u8,
+    len: usize,
+    data: [u8; MAX_MESSAGE_LEN],
+}
+
+impl core::ops::Deref for Message {
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        &self.data[..self.len]
+    }
+}
+
+/// Fragments of a message received so far
+struct Reassembly {
+    data: [u8; MAX_MESSAGE_LEN],
+    len: usize,
+    // Sequence number of the next fragment. Set to FINAL_FRAGMENT, which no
+    // fragment has, after one was missed so the rest of the message is dropped
+    next_seq: u8,
+}
+
+impl Reassembly {
+    const fn new() -> Self {
+        Self {
+            data: [0; MAX_MESSAGE_LEN],
+            len: 0,
+            next_seq: 0,
+        }
+    }
+
+    /// Adds the fragment. Returns the message once its last fragment arrived
+    fn push(&mut self, addr: u8, fragment: &[u8]) -> Option<Message> {
+        let (&header, data) = fragment.split_first()?;
+        let seq = header & !FINAL_FRAGMENT;
+        // A new message starts over whatever was left of the last one
+        if seq == 0 {
+            self.len = 0;
+            self.next_seq = 0;
+        }
+        if seq != self.next_seq || self.len + data.len() > MAX_MESSAGE_LEN {
+            error!("Dropped fragment {} of a message from {}", seq, addr);
+            self.next_seq = FINAL_FRAGMENT;
+            return None;
+        }
+        self.data[self.len..][..data.len()].copy_from_slice(data);
+        self.len += data.len();
+        self.next_seq += 1;
+        if header & FINAL_FRAGMENT == 0 {
+            return None;
+        }
+        let message = Message {
+            addr,
+            len: self.len,
+            data: self.data,
+        };
+        self.len = 0;
+        self.next_seq = 0;
+        Some(message)
+    }
+}
+
+/// Two buffers the radio receives into in turn. The radio restarts into the
+/// other buffer as soon as a packet ends, so a packet arriving back to back
+/// with the last one isn't dropped while the last one is handled
+struct RxBuffers {
+    buffers: [Packet; 2],
+    // Buffer the radio is receiving into
+    filling: usize,
+    // Set while the radio is receiving into the buffers
+    active: bool,
+    // Packet that ended before the radio was stopped, with its crc result
+    ready: Option<(Packet, bool)>,
+}
+
+impl RxBuffers {
+    const fn new() -> Self {
+        Self {
+            buffers: [Packet::default(); 2],
+            filling: 0,
+            active: false,
+            ready: None,
+        }
+    }
+
+    async fn start(&mut self) {
+        if self.active {
+            return;
+        }
+        disable().await;
+        let r = embassy_nrf::pac::RADIO;
+        r.shorts().write(|w| {
+            w.set_ready_start(true);
+            w.set_end_start(true);
+            w.set_address_rssistart(true);
+        });
+        r.packetptr()
+            .write_value(self.buffers[self.filling].buffer.as_ptr() as u32);
+        r.events_ready().write_value(0);
+        r.events_end().write_value(0);
+        r.events_crcok().write_value(0);
+
+        compiler_fence(core::sync::atomic::Ordering::Release);
+        r.tasks_rxen().write_value(1);
+        // Set before waiting so a dropped receive still stops the radio
+        self.active = true;
+        // The packet pointer is latched on start, so the buffer after this one
+        // can be set once the radio is ready
+        Event::Ready.wait().await;
+        Event::Ready.clear();
+        r.packetptr()
+            .write_value(self.buffers[self.filling ^ 1].buffer.as_ptr() as u32);
+    }
+
+    /// Stops receiving so the radio can transmit. A packet that ended before
+    /// the radio stopped is returned by the next call to [Self::next]
+    async fn stop(&mut self) {
+        if !self.active {
+            return;
+        }
+        let r = embassy_nrf::pac::RADIO;
+        r.shorts().write(|_| {});
+        disable().await;
+        if r.events_end().read() != 0 && self.ready.is_none() {
+            self.ready = Some(self.take_end());
+        }
+        r.events_end().write_value(0);
+        self.active = false;
+    }
+
+    // Takes the packet that just ended and points the radio at its buffer
+    // for the packet after the one being received
+    fn take_end(&mut self) -> (Packet, bool) {
+        let r = embassy_nrf::pac::RADIO;
+        r.events_end().write_value(0);
+        let crc_ok = r.events_crcok().read() != 0;
+        r.events_crcok().write_value(0);
+        let mut packet = self.buffers[self.filling];
+        packet.addr = r.rxmatch().read().rxmatch();
+        self.filling ^= 1;
+        r.packetptr()
+            .write_value(self.buffers[self.filling ^ 1].buffer.as_ptr() as u32);
+        (packet, crc_ok)
+    }
+
+    /// Receives the next packet, leaving the radio receiving into the other
+    /// buffer. Returns an error if the packet failed the crc
+    async fn next(&mut self, packet: &mut Packet) -> Result<(), ()> {
+        let (received, crc_ok) = match self.ready.take() {
+            Some(ready) => ready,
+            None => {
+                self.start().await;
+                Event::End.wait().await;
+                self.take_end()
+            }
+        };
+        *packet = received;
+        if crc_ok {
+            Ok(())
+        } else {
+            Err(())
+        }
+    }
+}
+
 struct ReceiveFuture<'a> {
     complete: bool,
     packet: &'a mut Packet,
@@ -306,6 +1308,7 @@ impl<'a> ReceiveFuture<'a> {
         r.shorts().write(|w| {
             w.set_ready_start(true);
             w.set_end_disable(true);
+            w.set_address_rssistart(true);
         });
         r.packetptr().write_value(packet.buffer.as_ptr() as u32);
 
@@ -347,19 +1350,23 @@ impl<'a> Future for ReceiveFuture<'a> {
 }
 
 impl<'a> Drop for ReceiveFuture<'a> {
+    // Only starts disabling the radio, whatever starts it next waits for
+    // it with [disable]
     fn drop(&mut self) {
         if !self.complete {
             let r = embassy_nrf::pac::RADIO;
             r.tasks_disable().write_value(1);
-            while r.state().read().state() != RadioState::DISABLED {}
-            r.events_disabled().write_value(0);
         }
     }
 }
 
+#[derive(Clone, Copy)]
 enum Direction {
     Tx,
     Rx,
+    // Receives until a message arrives or the deadline passes, see
+    // listen_for_message
+    Message(Instant),
 }
 
 pub async fn send_packet(packet: &Packet) {
@@ -372,11 +1379,159 @@ pub async fn receive_packet() -> Packet {
     RECV_CHANNEL.receive().await
 }
 
+/// Sends data longer than a packet in fragments, which the other end puts
+/// back together for [receive_message]. The fragments go out in order with
+/// the other packets, so only the sending side needs to be in tx
+pub async fn send_message(data: &[u8]) {
+    let data = &data[..data.len().min(MAX_MESSAGE_LEN)];
+    let count = data.len().div_ceil(FRAGMENT_LEN).max(1);
+    let mut chunks = data.chunks(FRAGMENT_LEN);
+    let _sender = MESSAGE_SENDER.lock().await;
+    // An empty message is sent as a single empty fragment
+    for seq in 0..count {
+        let chunk = chunks.next().unwrap_or(&[]);
+        let mut buf = [0u8; BUFFER_SIZE];
+        buf[0] = seq as u8;
+        if seq + 1 == count {
+            buf[0] |= FINAL_FRAGMENT;
+        }
+        buf[1..][..chunk.len()].copy_from_slice(chunk);
+        let mut packet = Packet::default();
+        packet.copy_from_slice(&buf[..1 + chunk.len()]);
+        packet.set_type(PacketType::Fragment);
+        send_packet(&packet).await;
+    }
+}
+
+/// Receives the next message sent with [send_message]. Messages are only
+/// received while the radio is receiving for [receive_packet]
+pub async fn receive_message() -> Message {
+    MESSAGE_CHANNEL.receive().await
+}
+
+/// Drops messages that weren't received
+pub fn clear_messages() {
+    MESSAGE_CHANNEL.clear();
+}
+
+/// Listens for a message sent with [send_message] until it arrives or the
+/// timeout passes. Halves only receive with the acks of their own packets
+/// otherwise, and the radio stops listening once this returns
+pub async fn listen_for_message(timeout: Duration) -> Option<Message> {
+    REQUESTS
+        .send(Direction::Message(Instant::now() + timeout))
+        .await;
+    with_timeout(timeout, MESSAGE_CHANNEL.receive()).await.ok()
+}
+
+/// Moves the link to the dongle at the addresses. release is sent to the
+/// current dongle first, which is given a short time to ack it
+pub fn hand_over(addresses: Addresses, release: &Packet) {
+    HANDOVER.signal(Handover {
+        addresses,
+        release: *release,
+    });
+}
+
+/// Sends a config packet. Used by halves to respond to config received
+/// through [receive_config]
+pub async fn send_config(packet: &Packet) {
+    let mut packet = *packet;
+    packet.set_type(PacketType::Config);
+    send_packet(&packet).await;
+}
+
+/// Sends a packet of a firmware image to a half in dfu mode
+pub async fn send_dfu(packet: &Packet) {
+    let mut packet = *packet;
+    packet.set_type(PacketType::Dfu);
+    send_packet(&packet).await;
+}
+
+/// Sends a packet of key travels from a half built by
+/// [crate::link::AnalogEncoder]
+pub async fn send_analog(packet: &Packet) {
+    let mut packet = *packet;
+    packet.set_type(PacketType::AnalogData);
+    send_packet(&packet).await;
+}
+
+/// Sends a packet built by [crate::link::source_packet] telling the dongle
+/// whether to forward the half's keys
+pub async fn send_source(packet: &Packet) {
+    let mut packet = *packet;
+    packet.set_type(PacketType::Source);
+    send_packet(&packet).await;
+}
+
+/// Queues config to be sent to the address. Receivers can't start a transfer
+/// so the config rides on the ack of the next packet from the address, one
+/// config per ack. Queued config of the same kind, its first byte, is
+/// replaced so a burst of changes only sends the latest
+pub fn queue_config(addr: u8, payload: &[u8]) {
+    let mut packet = Packet::default();
+    packet.copy_from_slice(&payload[..payload.len().min(MAX_CONFIG_LEN)]);
+    PENDING_CONFIG.lock(|pending| {
+        let mut all = pending.get();
+        all[addr as usize % 8].push(packet);
+        pending.set(all);
+    });
+}
+
+/// Returns true if config of the kind queued for the address hasn't been
+/// delivered yet
+pub fn is_config_pending(addr: u8, kind: u8) -> bool {
+    PENDING_CONFIG.lock(|pending| {
+        pending.get()[addr as usize % 8]
+            .packets
+            .iter()
+            .flatten()
+            .any(|packet| packet.first() == Some(&kind))
+    })
+}
+
+/// Drops config of the kind queued for the address if it hasn't been sent yet
+pub fn cancel_config(addr: u8, kind: u8) {
+    PENDING_CONFIG.lock(|pending| {
+        let mut all = pending.get();
+        all[addr as usize % 8].cancel(kind);
+        pending.set(all);
+    });
+}
+
+/// Receives the next config packet. Halves receive config queued by the
+/// dongle and the dongle receives the responses sent with [send_config]
+pub async fn receive_config() -> Packet {
+    CONFIG_CHANNEL.receive().await
+}
+
+/// Drops config packets that weren't received
+pub fn clear_config() {
+    CONFIG_CHANNEL.clear();
+}
+
 #[repr(u8)]
-#[derive(Clone, Copy, PartialEq, Eq, TryFromPrimitive)]
-enum PacketType {
+#[derive(Clone, Copy, PartialEq, Eq, TryFromPrimitive, defmt::Format)]
+pub enum PacketType {
     Data,
     Ack,
+    // First data packet sent since the sender started. Resets the id the
+    // receiver compares against to find stale packets. The last byte is the
+    // sender's session, which the receiver strips
+    SyncData,
+    // Com responses relayed from a half to the dongle
+    Config,
+    // Firmware image sent from the dongle to a half in dfu mode
+    Dfu,
+    // Key state of a half followed by the travel of its keys that changed,
+    // see crate::link::AnalogEncoder
+    AnalogData,
+    // Sent by a half when it starts or stops sending its reports over its own
+    // usb connection, see crate::link::run_source_arbitration
+    Source,
+    // Part of a message longer than a packet, see send_message. The first
+    // byte holds the sequence number and FINAL_FRAGMENT on the last part
+    Fragment,
 }
 
 #[derive(Clone, Copy, PartialEq, Eq)]
@@ -418,7 +1573,7 @@ impl Packet {
         self.buffer[Self::ID_INDEX] = id;
     }
 
-    fn packet_type(&self) -> Result<PacketType, TryFromPrimitiveError<PacketType>> {
+    pub fn packet_type(&self) -> Result<PacketType, TryFromPrimitiveError<PacketType>> {
         self.buffer[Self::TYPE_INDEX].try_into()
     }
 
@@ -431,6 +1586,34 @@ impl Packet {
         self.buffer[META_SIZE..][..src.len()].copy_from_slice(src);
         self.set_len(src.len());
     }
+
+    // Returns the payload laid out as an esb frame
+    fn esb_frame(&self, pid: u8, ack: bool) -> Packet {
+        let mut frame = Packet::default();
+        frame.buffer[0] = self.len() as u8;
+        frame.buffer[1] = (pid << 1) | ack as u8;
+        frame.buffer[ESB_META_SIZE..][..self.len()].copy_from_slice(self);
+        frame
+    }
+
+    // Pid of an esb frame received into the packet
+    fn esb_pid(&self) -> u8 {
+        (self.buffer[1] >> 1) & 0x3
+    }
+
+    // Turns an esb frame received into the packet into a data packet with
+    // the pid as its id. Returns the pid and whether the sender asked for an
+    // ack
+    fn unpack_esb(&mut self) -> (u8, bool) {
+        let len = (self.buffer[0] as usize).min(BUFFER_SIZE);
+        let (pid, ack) = (self.esb_pid(), self.buffer[1] & 1 != 0);
+        self.buffer
+            .copy_within(ESB_META_SIZE..ESB_META_SIZE + len, META_SIZE);
+        self.set_len(len);
+        self.set_id(pid);
+        self.set_type(PacketType::Data);
+        (pid, ack)
+    }
 }
 
 impl core::ops::Deref for Packet {
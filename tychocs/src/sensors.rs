@@ -1,158 +1,121 @@
-use core::{future::Future, marker::PhantomData, ops::Range};
-
-use embassy_futures::select::{select4, select_array, select_slice};
-use embassy_nrf::{
-    gpio::{AnyPin, Input, Output},
-    gpiote::{AnyChannel, InputChannel},
+use key_lib::{
+    position::KeySensors,
+    watchdog::{idle, Watched},
+    NUM_KEYS,
 };
-use embassy_time::{Duration, Instant};
-use heapless::Vec;
-use key_lib::{position::KeySensors, NUM_KEYS};
 
-use crate::radio::receive_packet;
+use crate::{
+    link::{self, AnalogDecoder, Half, LinkVersion, HALF_KEYS},
+    radio::{receive_packet, PacketType},
+};
 
-const DEBOUNCE_TIME: u64 = 5;
-#[derive(Copy, Clone, Debug)]
-struct Debouncer {
-    state: bool,
-    debounced: Option<Instant>,
+/// Key states sent by a half
+#[derive(Clone, Copy)]
+pub struct HalfState {
+    pub half: Half,
+    pub keys: u32,
+    // Set while the half sends its reports over its own usb connection
+    pub wired: bool,
+    /// Travel of the half's keys, for halves that send analog packets
+    pub depths: Option<[u8; HALF_KEYS]>,
 }
 
-impl Debouncer {
-    const fn default() -> Debouncer {
-        Self {
-            state: false,
-            debounced: None,
-        }
-    }
-    /// Returns the pressed status of the position
-    fn is_pressed(&self) -> bool {
-        self.state
-    }
-
-    /// Updates the buf of the key. Updating the buf will also update
-    /// the value returned from the is_pressed function
-    fn update_buf(&mut self, buf: bool) {
-        match self.debounced {
-            Some(time) => {
-                if time.elapsed() > Duration::from_millis(DEBOUNCE_TIME) {
-                    self.state = buf;
-                    self.debounced = None;
-                }
-            }
-            None => {
-                if buf != self.state {
-                    self.debounced = Some(Instant::now());
-                }
-            }
-        }
-    }
+/// Link the dongle receives key states from. The radio is the only link for
+/// now, a half plugged into the dongle would add a wired one
+pub trait HalfTransport {
+    /// Waits for the next key state. Returns None for packets that didn't come
+    /// from a half
+    fn receive(&mut self) -> impl core::future::Future<Output = Option<HalfState>>;
 }
 
-pub struct Matrix<'a, const INPUT_SIZE: usize, const OUTPUT_SIZE: usize> {
-    out: [Output<'a>; OUTPUT_SIZE],
-    input: [Input<'a>; INPUT_SIZE],
-    valid_input: [[bool; OUTPUT_SIZE]; INPUT_SIZE],
-    debouncers: [[Debouncer; OUTPUT_SIZE]; INPUT_SIZE],
-    pressed: Option<Instant>,
+/// Key states received over the radio
+pub struct RadioTransport {
+    analog: [AnalogDecoder; 2],
 }
 
-impl<'a, const INPUT_SIZE: usize, const OUTPUT_SIZE: usize> Matrix<'a, INPUT_SIZE, OUTPUT_SIZE> {
-    pub fn disable_debouncer(&mut self, range: Range<usize>) {
-        let res = self.valid_input.iter_mut().flatten().skip(range.start);
-        for input in res.take(range.len()) {
-            *input = false;
-        }
-    }
-    pub fn new(out: [Output<'a>; OUTPUT_SIZE], input: [Input<'a>; INPUT_SIZE]) -> Self {
+impl RadioTransport {
+    pub const fn new() -> Self {
         Self {
-            out,
-            input,
-            valid_input: [[true; OUTPUT_SIZE]; INPUT_SIZE],
-            debouncers: [[Debouncer::default(); OUTPUT_SIZE]; INPUT_SIZE],
-            pressed: None,
+            analog: [AnalogDecoder::new(), AnalogDecoder::new()],
         }
     }
+}
 
-    pub async fn update(&mut self) {
-        // If no keys were pressed in the previous scan,
-        // we'll set all the output pins high and await
-        // for one of the channels to go high to save battery
-        if let Some(time) = self.pressed {
-            if time.elapsed() >= Duration::from_millis(DEBOUNCE_TIME) {
-                for power in &mut self.out {
-                    power.set_high();
-                }
-
-                // let mut high = false;
-                // for row in &mut self.input {
-                //     high = high || row.is_high()
-                // }
-
-                let futures: Vec<_, INPUT_SIZE> = self
-                    .input
-                    .iter_mut()
-                    .map(|pin| pin.wait_for_high())
-                    .collect();
-                unsafe {
-                    select_array(futures.into_array::<INPUT_SIZE>().unwrap_unchecked()).await;
-                }
+impl Default for RadioTransport {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
-                for power in &mut self.out {
-                    power.set_low();
-                }
-            }
+impl HalfTransport for RadioTransport {
+    async fn receive(&mut self) -> Option<HalfState> {
+        // Idle halves only send a heartbeat every few seconds
+        let states = idle(Watched::Keys, receive_packet()).await;
+        let addr = states.addr;
+        let reconnected = Half::from_addr(addr).is_some_and(|half| !link::is_connected(half));
+        link::mark_seen(addr);
+        let half = Half::from_addr(addr)?;
+        // Halves tell the dongle right away when a host configures their usb
+        // keyboard or they're unplugged
+        if let Ok(PacketType::Source) = states.packet_type() {
+            let flags = states.first().copied().unwrap_or(0);
+            return Some(HalfState {
+                half,
+                keys: 0,
+                wired: flags & link::KEY_STATE_WIRED != 0,
+                depths: None,
+            });
         }
-
-        let mut pressed = false;
-        for i in 0..OUTPUT_SIZE {
-            self.out[i].set_high();
-            for j in 0..INPUT_SIZE {
-                self.debouncers[j][i].update_buf(self.input[j].is_high());
-                pressed = pressed || self.debouncers[j][i].is_pressed();
-            }
-            self.out[i].set_low();
+        let flags = states.get(4).copied().unwrap_or(0);
+        let wired = flags & link::KEY_STATE_WIRED != 0;
+        let keys = match states.get(0..4) {
+            Some(bytes) => u32::from_le_bytes(bytes.try_into().unwrap()),
+            None => 0,
+        };
+        // Analog packets carry travels in place of the battery and version,
+        // which come with the key state packets halves send as heartbeats
+        if let Ok(PacketType::AnalogData) = states.packet_type() {
+            let depths = self.analog[half.index()].decode(&states);
+            return Some(HalfState {
+                half,
+                keys,
+                wired,
+                depths,
+            });
         }
-        if pressed {
-            self.pressed = None;
-        } else {
-            match self.pressed {
-                Some(_) => {}
-                None => {
-                    self.pressed = Some(Instant::now());
-                }
-            }
+        // Halves running older firmware don't send their battery
+        if let Some(bytes) = states.get(5..7) {
+            let millivolts = u16::from_le_bytes(bytes.try_into().unwrap());
+            link::set_half_battery(half, millivolts, flags & link::KEY_STATE_BATTERY_LOW != 0);
         }
-    }
-
-    pub fn get_state(&self) -> u32 {
-        let mut index = 0;
-        let mut state = 0u32;
-        self.debouncers
-            .iter()
-            .flatten()
-            .zip(self.valid_input.iter().flatten())
-            .for_each(|(deb, valid)| {
-                if *valid {
-                    if deb.is_pressed() {
-                        state |= 1 << index;
-                    }
-                    index += 1;
-                }
-            });
-        state
+        // Halves running firmware from before the version was sent are
+        // reported as mismatched
+        let version = states
+            .get(7..)
+            .and_then(LinkVersion::from_bytes)
+            .unwrap_or(LinkVersion::UNKNOWN);
+        link::record_half_version(half, version, reconnected);
+        link::record_half_power_state(half, flags & link::KEY_STATE_ASLEEP != 0);
+        Some(HalfState {
+            half,
+            keys,
+            wired,
+            depths: None,
+        })
     }
 }
 
-pub struct DongleSensors {}
+pub struct DongleSensors<T: HalfTransport> {
+    transport: T,
+}
 
-impl DongleSensors {
-    pub fn new() -> Self {
-        Self {}
+impl<T: HalfTransport> DongleSensors<T> {
+    pub fn new(transport: T) -> Self {
+        Self { transport }
     }
 }
 
-impl KeySensors for DongleSensors {
+impl<T: HalfTransport> KeySensors for DongleSensors<T> {
     type Item = bool;
 
     async fn update_positions<K: key_lib::position::KeyState<Item = Self::Item>>(
@@ -160,25 +123,27 @@ impl KeySensors for DongleSensors {
         positions: &mut [K],
     ) {
         const OFFSET: usize = NUM_KEYS / 2;
-        let states = receive_packet().await;
-        let key_states = u32::from_le_bytes(states[0..4].try_into().unwrap());
-        let addr = states.addr;
-        if addr == 1 {
-            positions[..OFFSET]
-                .iter_mut()
-                .enumerate()
-                .for_each(|(i, k)| {
-                    let state = (key_states >> i) & 1 != 0;
-                    k.update_buf(state);
-                });
-        } else if addr == 2 {
-            positions[OFFSET..]
-                .iter_mut()
-                .enumerate()
-                .for_each(|(i, k)| {
-                    let state = (key_states >> i) & 1 != 0;
-                    k.update_buf(state);
-                });
+        let Some(state) = self.transport.receive().await else {
+            return;
+        };
+        // Halves that send their reports over usb have their keys released so
+        // the same key presses aren't sent by the dongle too
+        link::set_half_wired(state.half, state.wired);
+        let key_states = if state.wired { 0 } else { state.keys };
+        let keys = match state.half {
+            Half::Left => &mut positions[..OFFSET],
+            Half::Right => &mut positions[OFFSET..],
+        };
+        keys.iter_mut().enumerate().for_each(|(i, k)| {
+            let state = (key_states >> i) & 1 != 0;
+            k.update_buf(state);
+        });
+        // Switches that keep the travel press by it, so rapid trigger works
+        // through the dongle
+        if let Some(depths) = state.depths.filter(|_| !state.wired) {
+            keys.iter_mut()
+                .zip(depths)
+                .for_each(|(k, travel)| k.update_depth(travel));
         }
     }
 }
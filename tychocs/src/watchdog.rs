@@ -0,0 +1,94 @@
+//! Hardware watchdog and crash records. The bins feed the WDT from
+//! key_lib::watchdog and record panics and hard faults to [CRASH_PAGE] before
+//! resetting, so a locked up board restarts on its own and the dongle can
+//! tell the host why it did
+
+use core::ops::Range;
+
+use defmt::{error, info};
+use embassy_nrf::nvmc::Nvmc;
+use embassy_nrf::peripherals::{NVMC, WDT};
+use embassy_nrf::wdt::{self, Watchdog};
+use embassy_nrf::Peri;
+use embedded_storage::nor_flash::{NorFlash, ReadNorFlash};
+use key_lib::crash::{set_last_crash, CrashRecord, CRASH_RECORD_LEN};
+use key_lib::diagnostics::{set_boot_report, BootReport, ResetReason};
+use key_lib::watchdog::{run_watchdog, Watched, WATCHDOG_TIMEOUT};
+
+use crate::link::LinkVersion;
+
+/// Flash page crash records are written to. Sits between
+/// [crate::HALF_STORAGE] and [crate::DFU_STAGING]
+pub const CRASH_PAGE: Range<u32> = 0x0008_F000..0x0009_0000;
+
+/// Starts the WDT and feeds it while the watched loops keep checking in. The
+/// WDT keeps running through soft resets, so it's only stopped by the reset
+/// it causes or a power cycle
+pub async fn run_wdt(wdt: Peri<'static, WDT>, watched: &[Watched]) -> ! {
+    let mut config = wdt::Config::default();
+    config.timeout_ticks = (32768 * WATCHDOG_TIMEOUT.as_millis() / 1000) as u32;
+    // Halting on a breakpoint shouldn't reset the board
+    config.action_during_debug_halt = wdt::HaltConfig::PAUSE;
+    let mut handle = match Watchdog::try_new::<1>(wdt, config) {
+        Ok((_, [handle])) => handle,
+        Err(_) => {
+            // Started with another config before a soft reset. It can't be
+            // changed, so the reset it causes will restart it with this one
+            error!("Watchdog is already running with another config");
+            core::future::pending().await
+        }
+    };
+    run_watchdog(watched, || handle.pet()).await
+}
+
+/// Reads why the board booted and the crash record written before it, and
+/// erases the record so it's only reported once
+pub fn load_boot_state(nvmc: Peri<'_, NVMC>) {
+    let power = embassy_nrf::pac::POWER;
+    let reasons = power.resetreas().read();
+    // The register accumulates reasons until cleared
+    power.resetreas().write_value(reasons);
+    let reset_reason = if reasons.dog() {
+        ResetReason::Watchdog
+    } else if reasons.lockup() {
+        ResetReason::Lockup
+    } else if reasons.sreq() {
+        ResetReason::Software
+    } else if reasons.resetpin() {
+        ResetReason::Pin
+    } else if reasons.0 == 0 {
+        ResetReason::PowerOn
+    } else {
+        ResetReason::Unknown
+    };
+    set_boot_report(BootReport {
+        version: LinkVersion::current().firmware,
+        reset_reason,
+    });
+
+    let mut flash = Nvmc::new(nvmc);
+    let mut buf = [0u8; CRASH_RECORD_LEN];
+    if flash.read(CRASH_PAGE.start, &mut buf).is_err() {
+        return;
+    }
+    if let Some(record) = CrashRecord::from_bytes(&buf) {
+        info!("Crashed before the last reset: {}", record.message());
+        set_last_crash(record);
+    }
+    if buf.iter().any(|byte| *byte != 0xff) {
+        let _ = flash.erase(CRASH_PAGE.start, CRASH_PAGE.end);
+    }
+}
+
+/// Writes the record to [CRASH_PAGE] and resets. Called from the panic and
+/// HardFault handlers of the bins
+pub fn record_crash(record: &CrashRecord) -> ! {
+    cortex_m::interrupt::disable();
+    // Whatever owned the nvmc won't run again before the reset
+    let mut flash = Nvmc::new(unsafe { NVMC::steal() });
+    // Nothing can be done about a failed write while crashing
+    if flash.erase(CRASH_PAGE.start, CRASH_PAGE.end).is_ok() {
+        let _ = flash.write(CRASH_PAGE.start, &record.to_bytes());
+    }
+    cortex_m::peripheral::SCB::sys_reset()
+}